@@ -0,0 +1,59 @@
+//! Longest prefix match against a country sized CIDR list
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tache::ip_trie::IpTrie;
+
+/// Networks spread over the whole IPv4 space, like a GEOIP export
+fn networks(n: u32) -> Vec<(IpAddr, u8)> {
+    (0..n)
+        .map(|i| {
+            let ip = Ipv4Addr::from(i.wrapping_mul(2_654_435_761));
+            (IpAddr::V4(ip), 16 + (i % 9) as u8)
+        })
+        .collect()
+}
+
+fn lookup(c: &mut Criterion) {
+    let networks = networks(8000);
+    let mut trie = IpTrie::new();
+    for &(ip, len) in &networks {
+        trie.insert(ip, len, ());
+    }
+    let probes = (0..1024u32)
+        .map(|i| IpAddr::V4(Ipv4Addr::from(i.wrapping_mul(40_503) << 8)))
+        .collect::<Vec<_>>();
+
+    c.bench_function("ip_trie 8000 networks", |b| {
+        b.iter(|| {
+            probes
+                .iter()
+                .filter(|&&ip| trie.contains(black_box(ip)))
+                .count()
+        })
+    });
+
+    // What the trie replaced
+    c.bench_function("linear scan 8000 networks", |b| {
+        b.iter(|| {
+            probes
+                .iter()
+                .filter(|&&ip| {
+                    networks
+                        .iter()
+                        .any(|&(net, len)| match (net, black_box(ip)) {
+                            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                                let mask = !0u32 << (32 - len);
+                                u32::from(net) & mask == u32::from(ip) & mask
+                            }
+                            _ => false,
+                        })
+                })
+                .count()
+        })
+    });
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tache::protocol::Http;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = Http::new();
+    let mut src = BytesMut::from(data);
+    // Pipelined requests and their bodies are decoded one after another
+    while let Ok(Some(_)) = codec.decode(&mut src) {}
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tache::protocol::socks::read_addr;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = read_addr(&mut &data[..]);
+});
@@ -0,0 +1,58 @@
+#![no_main]
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::executor::block_on;
+use libfuzzer_sys::fuzz_target;
+use tache::outbound::socks5_handshake;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Replays `data` as the server side, swallows everything written
+struct Replay<'a> {
+    data: &'a [u8],
+}
+
+impl AsyncRead for Replay<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = buf.len().min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Replay<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let target = "example.com:443".parse().unwrap();
+    let mut stream = Replay { data };
+    let _ = block_on(socks5_handshake(
+        &mut stream,
+        &target,
+        Some(("user", "pass")),
+    ));
+});
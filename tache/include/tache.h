@@ -0,0 +1,35 @@
+/* C API of tache, build the library with `--features ffi` */
+
+#ifndef TACHE_H
+#define TACHE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define TACHE_OK 0
+#define TACHE_ERR_INVALID_ARGUMENT -1
+#define TACHE_ERR_CONFIG -2
+#define TACHE_ERR_STATE -3
+#define TACHE_ERR_IO -4
+
+typedef void (*tache_traffic_callback)(uint64_t up, uint64_t down, void *user_data);
+
+/* Start serving with a JSON (or YAML) config */
+int tache_start(const char *config);
+/* Shut down, closing every connection */
+int tache_stop(void);
+/* Replace the running config */
+int tache_reload(const char *config);
+/* "rule", "global" or "direct" */
+int tache_set_mode(const char *mode);
+/* Called every second with the bytes of the last second, NULL unregisters */
+int tache_set_traffic_callback(tache_traffic_callback callback, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TACHE_H */
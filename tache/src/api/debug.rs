@@ -0,0 +1,217 @@
+//! `/debug`, what a long-running instance holds on to
+//!
+//! `GET /debug/status` is always there, CPU profiles at
+//! `GET /debug/pprof/profile?seconds=N` need the `pprof` feature. Captures
+//! are started with `POST /debug/capture`, looked at with `GET` and stopped
+//! early with `DELETE`.
+
+use http::{Method, Response, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+
+use super::{error_response, json_response, ApiRequest};
+use crate::{crypto, engine::capture::Filter, protocol::vmess::clock, rt, tls};
+
+pub async fn route(req: &ApiRequest<'_>, segments: &[&str]) -> Response<String> {
+    match (req.request.method(), segments) {
+        (&Method::GET, ["status"]) => status(req),
+        (&Method::GET, ["pprof", "profile"]) => profile(req).await,
+        (&Method::POST, ["capture"]) => start_capture(req),
+        (&Method::GET, ["capture"]) => match req.context.capture().status() {
+            Some(status) => json_response(StatusCode::OK, &status),
+            None => error_response(StatusCode::NOT_FOUND, "no capture"),
+        },
+        (&Method::DELETE, ["capture"]) => match req.context.capture().stop() {
+            Some(status) => json_response(StatusCode::OK, &status),
+            None => error_response(StatusCode::NOT_FOUND, "no capture"),
+        },
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Bytes mapped by the process, from the OS as there is no allocator hook
+#[derive(Serialize)]
+struct Memory {
+    resident: u64,
+    #[serde(rename = "virtual")]
+    virtual_: u64,
+}
+
+fn status(req: &ApiRequest<'_>) -> Response<String> {
+    let pool = req.context.buffer_pool();
+    json_response(
+        StatusCode::OK,
+        &json!({
+            "uptime": rt::uptime().as_secs(),
+            "tasks": rt::tasks(),
+            "open_fds": open_fds(),
+            "memory": memory(),
+            "load": req.context.load_shedder().stats(),
+            "crypto": {
+                "backend": crypto::BACKEND,
+                "hardware_aes": crypto::hardware_aes(),
+                "preferred": req.context.preferred_cipher().name(),
+            },
+            "clock_offset": clock::offset(),
+            "tls_sessions": tls::session_stats(),
+            "buffers": {
+                "size": pool.buffer_size(),
+                "in_use": pool.in_use(),
+                "idle": pool.idle(),
+            },
+        }),
+    )
+}
+
+/// Capture what the JSON body selects, everything for an empty body
+fn start_capture(req: &ApiRequest<'_>) -> Response<String> {
+    let filter = if req.body.is_empty() {
+        Filter::default()
+    } else {
+        match serde_json::from_slice::<Filter>(req.body) {
+            Ok(filter) => filter,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+        }
+    };
+    match req.context.capture().start(filter) {
+        Ok(status) => json_response(StatusCode::CREATED, &status),
+        Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            error_response(StatusCode::CONFLICT, &e.to_string())
+        }
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn open_fds() -> Option<usize> {
+    #[cfg(target_os = "macos")]
+    const FD_DIR: &str = "/dev/fd";
+    #[cfg(not(target_os = "macos"))]
+    const FD_DIR: &str = "/proc/self/fd";
+    // Listing the directory holds one more
+    let count = std::fs::read_dir(FD_DIR).ok()?.count();
+    Some(count.saturating_sub(1))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn open_fds() -> Option<usize> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn memory() -> Option<Memory> {
+    // Sizes in pages: total, resident, then others
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let mut pages = statm.split_whitespace().map(|n| n.parse::<u64>());
+    let (total, resident) = (pages.next()?.ok()?, pages.next()?.ok()?);
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    Some(Memory {
+        resident: resident * page_size,
+        virtual_: total * page_size,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn memory() -> Option<Memory> {
+    None
+}
+
+#[cfg(feature = "pprof")]
+async fn profile(req: &ApiRequest<'_>) -> Response<String> {
+    use std::time::Duration;
+
+    use http::header;
+    use serde::Deserialize;
+
+    /// Samples per second
+    const FREQUENCY: i32 = 99;
+    const DEFAULT_SECONDS: u64 = 10;
+    const MAX_SECONDS: u64 = 300;
+
+    #[derive(Deserialize)]
+    struct ProfileQuery {
+        seconds: Option<u64>,
+    }
+
+    let query = req.request.uri().query().unwrap_or("");
+    let seconds = match serde_urlencoded::from_str::<ProfileQuery>(query) {
+        Ok(query) => query.seconds.unwrap_or(DEFAULT_SECONDS).min(MAX_SECONDS),
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    // Fails while another profile runs
+    let guard = match pprof::ProfilerGuard::new(FREQUENCY) {
+        Ok(guard) => guard,
+        Err(e) => return error_response(StatusCode::CONFLICT, &e.to_string()),
+    };
+    rt::delay_for(Duration::from_secs(seconds)).await;
+    let mut svg = Vec::new();
+    let built = guard.report().build().and_then(|r| r.flamegraph(&mut svg));
+    if let Err(e) = built {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+    }
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/svg+xml")
+        .body(String::from_utf8_lossy(&svg).into_owned())
+        .unwrap()
+}
+
+#[cfg(not(feature = "pprof"))]
+async fn profile(_req: &ApiRequest<'_>) -> Response<String> {
+    error_response(StatusCode::NOT_FOUND, "built without the pprof feature")
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use http::Request;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        context::{Context, SharedContext},
+    };
+
+    #[test]
+    fn reports_status() {
+        let context: SharedContext = Arc::new(Context::new(Config::new()).unwrap());
+        let request = Request::get("/debug/status").body(()).unwrap();
+        let req = ApiRequest {
+            context: &context,
+            request: &request,
+            body: &[],
+            peer: None,
+            local: true,
+        };
+        let pool = context.buffer_pool();
+        let held = pool.get();
+        let resp = rt::Runtime::new().unwrap().block_on(async {
+            // Counted until the runtime goes
+            rt::spawn(futures::future::pending());
+            route(&req, &["status"]).await
+        });
+        drop(held);
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let status: Value = serde_json::from_str(resp.body()).unwrap();
+        assert_eq!(status["buffers"]["in_use"], 1);
+        assert_eq!(status["buffers"]["size"], pool.buffer_size());
+        assert!(status["tasks"].as_u64().unwrap() >= 1);
+        assert!(status["uptime"].is_u64());
+        #[cfg(target_os = "linux")]
+        {
+            assert!(status["open_fds"].as_u64().unwrap() > 0);
+            assert!(status["memory"]["resident"].as_u64().unwrap() > 0);
+        }
+
+        #[cfg(not(feature = "pprof"))]
+        {
+            let resp = rt::Runtime::new()
+                .unwrap()
+                .block_on(route(&req, &["pprof", "profile"]));
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+    }
+}
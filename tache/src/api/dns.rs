@@ -0,0 +1,73 @@
+//! `/dns/queries`, the query log of the built-in resolver as it grows
+//!
+//! Queries are streamed as chunked JSON lines, one per query answered after
+//! the request, until the client goes away. The response is written
+//! straight to the connection since the API's codec only sends whole
+//! bodies.
+
+use std::{io, sync::Arc};
+
+use futures::StreamExt;
+use http::{header::HeaderValue, Method, Request, Response, StatusCode};
+use log::debug;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::{error_response, ApiRequest};
+use crate::{context::SharedContext, dns::QueryLog};
+
+/// Write and flush, TLS buffers otherwise
+async fn send<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> io::Result<()> {
+    stream.write_all(data).await?;
+    stream.flush().await
+}
+
+/// Log to stream for a `GET /dns/queries` while `query-log` is set
+pub fn streamed(context: &SharedContext, request: &Request<()>) -> Option<Arc<QueryLog>> {
+    if request.method() != Method::GET
+        || request.uri().path().trim_end_matches('/') != "/dns/queries"
+    {
+        return None;
+    }
+    context.dns()?.query_log()
+}
+
+pub async fn stream_queries<S>(stream: &mut S, log: &QueryLog, origin: Option<HeaderValue>)
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut queries = log.subscribe();
+    let mut head = "HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/x-ndjson\r\n\
+                    Cache-Control: no-cache\r\n\
+                    Transfer-Encoding: chunked\r\n"
+        .to_owned();
+    if let Some(origin) = origin.as_ref().and_then(|o| o.to_str().ok()) {
+        head.push_str(&format!(
+            "Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n",
+            origin
+        ));
+    }
+    head.push_str("\r\n");
+    if send(stream, head.as_bytes()).await.is_err() {
+        return;
+    }
+
+    while let Some(query) = queries.next().await {
+        let line = serde_json::to_string(&query).unwrap_or_default();
+        let chunk = format!("{:x}\r\n{}\n\r\n", line.len() + 1, line);
+        if let Err(e) = send(stream, chunk.as_bytes()).await {
+            debug!("API stopped streaming DNS queries, err: {}", e);
+            return;
+        }
+    }
+}
+
+pub fn route(req: &ApiRequest<'_>, segments: &[&str]) -> Response<String> {
+    match (req.request.method(), segments) {
+        // Streamed before routing while the log is enabled
+        (&Method::GET, ["queries"]) => {
+            error_response(StatusCode::NOT_FOUND, "dns query-log is not enabled")
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
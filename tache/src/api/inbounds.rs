@@ -0,0 +1,52 @@
+//! `/inbounds`, listeners added and removed without a reload
+//!
+//! Added inbounds last until the next reload or profile switch, the
+//! configured ones can't be removed here.
+
+use std::io;
+
+use http::{Method, Response, StatusCode};
+use serde_json::json;
+
+use super::{empty_response, error_response, json_response, ApiRequest};
+use crate::config::InboundConfig;
+
+pub fn route(req: &ApiRequest<'_>, segments: &[&str]) -> Response<String> {
+    let added = req.context.added_inbounds();
+    match (req.request.method(), segments) {
+        (&Method::GET, []) => json_response(
+            StatusCode::OK,
+            &json!({ "configured": req.context.config().inbounds, "added": added.list() }),
+        ),
+        (&Method::POST, []) => {
+            let inbound = match serde_json::from_slice::<InboundConfig>(req.body) {
+                Ok(inbound) => inbound,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+            };
+            match added.add(req.context, inbound) {
+                Ok(()) => empty_response(StatusCode::CREATED),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::AlreadyExists
+                        || e.kind() == io::ErrorKind::AddrInUse =>
+                {
+                    error_response(StatusCode::CONFLICT, &e.to_string())
+                }
+                Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        (&Method::DELETE, [name]) => {
+            let configured = req.context.config().inbounds.iter();
+            if added.remove(name) {
+                empty_response(StatusCode::NO_CONTENT)
+            } else if configured.map(InboundConfig::name).any(|n| n == *name) {
+                error_response(
+                    StatusCode::BAD_REQUEST,
+                    "configured inbounds are removed by editing the config",
+                )
+            } else {
+                error_response(StatusCode::NOT_FOUND, "inbound not found")
+            }
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
@@ -0,0 +1,650 @@
+//! RESTful controller API
+
+use std::{error::Error as StdError, io, net::SocketAddr};
+
+use futures::{SinkExt, StreamExt};
+use http::{
+    header::{self, HeaderValue},
+    Method, Request, Response, StatusCode,
+};
+use log::{debug, error, info};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+use crate::{
+    config::ApiConfig,
+    context::SharedContext,
+    engine::usage::Period,
+    listener,
+    outbound::{history, probe, speedtest::Refused},
+    protocol::{self, Message},
+    rt, tls,
+};
+
+mod debug;
+mod dns;
+mod inbounds;
+mod profiles;
+mod providers;
+mod rules;
+
+/// Largest request body accepted
+const MAX_BODY_LEN: usize = 1024 * 1024;
+
+/// Per-request data available to route handlers
+pub struct ApiRequest<'a> {
+    pub context: &'a SharedContext,
+    pub request: &'a Request<()>,
+    pub body: &'a [u8],
+    /// `None` on a Unix socket
+    pub peer: Option<SocketAddr>,
+    /// Client on this host, over loopback or a Unix socket
+    pub local: bool,
+}
+
+impl<'a> ApiRequest<'a> {
+    /// Path split into non-empty, percent-decoded segments
+    pub fn segments(&self) -> Vec<String> {
+        self.request
+            .uri()
+            .path()
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned())
+            .collect()
+    }
+}
+
+pub fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(body).unwrap_or_default())
+        .unwrap()
+}
+
+pub fn error_response(status: StatusCode, message: &str) -> Response<String> {
+    json_response(status, &json!({ "message": message }))
+}
+
+pub fn empty_response(status: StatusCode) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .body(String::new())
+        .unwrap()
+}
+
+/// Access control applied before any route
+struct Guard {
+    secret: Option<String>,
+    allowed_origins: Vec<String>,
+    allow_unauthenticated_localhost: bool,
+}
+
+impl Guard {
+    fn new(config: &ApiConfig) -> Guard {
+        Guard {
+            secret: config.secret.clone().filter(|s| !s.is_empty()),
+            allowed_origins: config.cors_allowed_origins.clone(),
+            allow_unauthenticated_localhost: config
+                .allow_unauthenticated_localhost
+                .unwrap_or(false),
+        }
+    }
+
+    fn authorized(&self, request: &Request<()>, local: bool) -> bool {
+        let secret = match self.secret {
+            Some(ref secret) => secret,
+            None => return true,
+        };
+        if self.allow_unauthenticated_localhost && local {
+            return true;
+        }
+
+        let bearer = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| v.starts_with("Bearer "))
+            .map(|v| &v["Bearer ".len()..]);
+        // Browsers can't set headers on websocket upgrades, accept `?token=` as well
+        let token = request.uri().query().and_then(|q| {
+            q.split('&')
+                .filter(|kv| kv.starts_with("token="))
+                .map(|kv| &kv["token=".len()..])
+                .next()
+        });
+        bearer.or(token).map(|t| t == secret).unwrap_or(false)
+    }
+
+    /// Value for `Access-Control-Allow-Origin` if the request origin is allowed
+    fn allowed_origin(&self, request: &Request<()>) -> Option<HeaderValue> {
+        let origin = request.headers().get(header::ORIGIN)?;
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let value = origin.to_str().ok()?;
+        if self
+            .allowed_origins
+            .iter()
+            .any(|o| o.trim_end_matches('/') == value)
+        {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+
+    async fn handle(&self, req: ApiRequest<'_>) -> Response<String> {
+        let origin = self.allowed_origin(req.request);
+
+        let mut response = if req.request.method() == Method::OPTIONS {
+            // CORS preflight never carries credentials
+            if origin.is_some() {
+                let mut response = empty_response(StatusCode::NO_CONTENT);
+                let headers = response.headers_mut();
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_METHODS,
+                    HeaderValue::from_static("GET, POST, PUT, PATCH, DELETE"),
+                );
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_HEADERS,
+                    HeaderValue::from_static("Authorization, Content-Type"),
+                );
+                headers.insert(
+                    header::ACCESS_CONTROL_MAX_AGE,
+                    HeaderValue::from_static("600"),
+                );
+                response
+            } else {
+                error_response(StatusCode::FORBIDDEN, "origin not allowed")
+            }
+        } else if !self.authorized(req.request, req.local) {
+            error_response(StatusCode::UNAUTHORIZED, "unauthorized")
+        } else {
+            route(req).await
+        };
+
+        if let Some(origin) = origin {
+            let headers = response.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        response
+    }
+}
+
+async fn route(req: ApiRequest<'_>) -> Response<String> {
+    let segments = req.segments();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    match (req.request.method(), &segments[..]) {
+        (&Method::GET, []) => json_response(StatusCode::OK, &json!({ "hello": "tache" })),
+        (&Method::GET, ["version"]) => {
+            json_response(StatusCode::OK, &json!({ "version": crate::VERSION }))
+        }
+        (&Method::GET, ["connections"]) => connections(&req),
+        (&Method::GET, ["stats"]) => stats(&req),
+        (&Method::POST, ["gc"]) => gc(&req),
+        (&Method::GET, ["configs", "last-reload"]) => last_reload(&req),
+        (&Method::GET, ["geo-db"]) => geo_db(&req, false).await,
+        (&Method::POST, ["geo-db", "update"]) => geo_db(&req, true).await,
+        (&Method::GET, ["proxies"]) => proxies(&req),
+        (&Method::GET, ["proxies", "test"]) => test_proxies(&req).await,
+        (&Method::GET, ["proxies", name, "history"]) => proxy_history(&req, name),
+        (&Method::GET, ["proxies", name, "info"]) => proxy_info(&req, name).await,
+        (&Method::POST, ["proxies", name, "speedtest"]) => speedtest(&req, name).await,
+        (_, ["debug", ..]) => debug::route(&req, &segments[1..]).await,
+        (_, ["dns", ..]) => dns::route(&req, &segments[1..]),
+        (_, ["inbounds", ..]) => inbounds::route(&req, &segments[1..]),
+        (_, ["providers", ..]) => providers::route(&req, &segments[1..]).await,
+        (_, ["profiles", ..]) => profiles::route(&req, &segments[1..]),
+        (_, ["rules", ..]) => rules::route(&req, &segments[1..]),
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Closed connections by reason and the latest of them
+fn connections(req: &ApiRequest<'_>) -> Response<String> {
+    let stats = req.context.close_stats();
+    let closed = stats
+        .counts()
+        .into_iter()
+        .map(|(reason, count)| (reason.to_string(), json!(count)))
+        .collect::<serde_json::Map<_, _>>();
+    json_response(
+        StatusCode::OK,
+        &json!({ "closed": closed, "recent": stats.recent() }),
+    )
+}
+
+/// Drop spare upstream connections and idle buffers, cached DNS answers and
+/// the latest closed connections, for long running instances
+fn gc(req: &ApiRequest<'_>) -> Response<String> {
+    let context = req.context;
+    let connections = context.outbound_pool().clear();
+    let buffers = context.buffer_pool().clear();
+    let closed = context.close_stats().clear_recent();
+    if let Err(e) = context.flush_dns_cache() {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+    }
+    info!(
+        "Freed {} spare connections, {} buffers and {} closed connection records",
+        connections, buffers, closed
+    );
+    json_response(
+        StatusCode::OK,
+        &json!({ "connections": connections, "buffers": buffers, "closed": closed }),
+    )
+}
+
+/// What the latest reload or profile switch changed
+fn last_reload(req: &ApiRequest<'_>) -> Response<String> {
+    match req.context.carryover().last_reload() {
+        Some(diff) => json_response(StatusCode::OK, &diff),
+        None => error_response(StatusCode::NOT_FOUND, "not reloaded yet"),
+    }
+}
+
+/// Downloaded databases, downloading them all again first when `update`
+async fn geo_db(req: &ApiRequest<'_>, update: bool) -> Response<String> {
+    let geo_db = match req.context.geo_db() {
+        Some(geo_db) => geo_db,
+        None => return error_response(StatusCode::NOT_FOUND, "geo-db is not configured"),
+    };
+    let databases = if update {
+        geo_db.update().await
+    } else {
+        geo_db.statuses()
+    };
+    json_response(StatusCode::OK, &json!({ "databases": databases }))
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    /// `day` unless given, or `month`
+    period: Option<String>,
+}
+
+/// Bytes by outbound and client of every day or month kept, newest first
+fn stats(req: &ApiRequest<'_>) -> Response<String> {
+    let query = req.request.uri().query().unwrap_or("");
+    let query = match serde_urlencoded::from_str::<StatsQuery>(query) {
+        Ok(query) => query,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let period = match query.period.as_deref() {
+        None | Some("day") => Period::Day,
+        Some("month") => Period::Month,
+        Some(other) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("unknown period {}, expected day or month", other),
+            )
+        }
+    };
+    let usage = req.context.usage().report(period);
+    json_response(StatusCode::OK, &json!({ "usage": usage }))
+}
+
+/// Proxies and groups with whether they are up and the circuit of proxies
+fn proxies(req: &ApiRequest<'_>) -> Response<String> {
+    let config = req.context.config();
+    let outbounds = req.context.outbounds();
+    let entry = |name: &str, kind: &str| {
+        let outbound = outbounds.get(name);
+        json!({
+            "name": name,
+            "type": kind,
+            "alive": outbound.map(|o| o.alive()),
+            "circuit": outbound.and_then(|o| o.circuit()),
+            "history": history::stats(name),
+        })
+    };
+    let mut proxies: Vec<_> = config
+        .proxies
+        .iter()
+        .filter(|proxy| !proxy.hidden())
+        .map(|proxy| entry(proxy.name(), proxy.kind()))
+        .collect();
+    proxies.extend(
+        config
+            .proxy_groups
+            .iter()
+            .map(|group| entry(&group.name, &group.kind)),
+    );
+    json_response(StatusCode::OK, &json!({ "proxies": proxies }))
+}
+
+#[derive(Deserialize)]
+struct TestQuery {
+    url: Option<String>,
+    /// Measure throughput downloading this url as well
+    download: Option<String>,
+}
+
+/// Timings of every proxy, fastest first
+async fn test_proxies(req: &ApiRequest<'_>) -> Response<String> {
+    let query = req.request.uri().query().unwrap_or("");
+    let query = match serde_urlencoded::from_str::<TestQuery>(query) {
+        Ok(query) => query,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let outbounds = probe::proxy_outbounds(&req.context.config().proxies, &req.context.outbounds());
+    let url = query
+        .url
+        .as_ref()
+        .map_or(probe::DEFAULT_TEST_URL, String::as_str);
+    let reports = probe::measure_all(&outbounds, url, query.download.as_deref()).await;
+    json_response(StatusCode::OK, &json!({ "proxies": reports }))
+}
+
+/// Recent health check delays of proxy `name`, oldest first
+fn proxy_history(req: &ApiRequest<'_>, name: &str) -> Response<String> {
+    if req.context.outbound(name).is_none() && history::stats(name).is_none() {
+        return error_response(StatusCode::NOT_FOUND, "proxy not found");
+    }
+    json_response(
+        StatusCode::OK,
+        &json!({
+            "name": name,
+            "stats": history::stats(name),
+            "samples": history::samples(name),
+        }),
+    )
+}
+
+/// Address and country traffic of proxy or group `name` leaves from
+async fn proxy_info(req: &ApiRequest<'_>, name: &str) -> Response<String> {
+    let outbound = match req.context.outbound(name) {
+        Some(outbound) => outbound,
+        None => return error_response(StatusCode::NOT_FOUND, "proxy not found"),
+    };
+    let geoip = req.context.geoip();
+    let exit = req
+        .context
+        .exit_checker()
+        .exit(name, &*outbound, geoip.as_deref())
+        .await;
+    json_response(StatusCode::OK, &json!({ "name": name, "exit": exit }))
+}
+
+/// Latency, jitter and throughput of proxy or group `name`, 429 while
+/// another test runs or within the cooldown
+async fn speedtest(req: &ApiRequest<'_>, name: &str) -> Response<String> {
+    let query = req.request.uri().query().unwrap_or("");
+    let query = match serde_urlencoded::from_str::<TestQuery>(query) {
+        Ok(query) => query,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let outbound = match req.context.outbound(name) {
+        Some(outbound) => outbound,
+        None => return error_response(StatusCode::NOT_FOUND, "proxy not found"),
+    };
+    let url = query
+        .url
+        .as_ref()
+        .map_or(probe::DEFAULT_TEST_URL, String::as_str);
+    let tester = req.context.speed_tester();
+    let download = query.download.as_deref();
+    match tester.test(name, &*outbound, url, download).await {
+        Ok(test) => json_response(StatusCode::OK, &test),
+        Err(refused) => {
+            let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, &refused.to_string());
+            if let Refused::Cooldown(secs) = refused {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            }
+            response
+        }
+    }
+}
+
+async fn serve_connection<S>(
+    context: SharedContext,
+    guard: &Guard,
+    stream: S,
+    peer: Option<SocketAddr>,
+    local: bool,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut transport = Framed::new(stream, protocol::Http::new());
+    while let Some(request) = read_request(&mut transport).await {
+        let (request, body) = match request {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("API failed to read request, err: {}", e);
+                return;
+            }
+        };
+        if let Some(log) = dns::streamed(&context, &request) {
+            if guard.authorized(&request, local) {
+                let origin = guard.allowed_origin(&request);
+                dns::stream_queries(transport.get_mut(), &log, origin).await;
+                return;
+            }
+        }
+        let response = match body {
+            Some(body) => {
+                guard
+                    .handle(ApiRequest {
+                        context: &context,
+                        request: &request,
+                        body: &body,
+                        peer,
+                        local,
+                    })
+                    .await
+            }
+            None => error_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large"),
+        };
+        if let Err(e) = transport.send(response).await {
+            debug!("API failed to write response, err: {}", e);
+            return;
+        }
+    }
+}
+
+/// Read a request and its whole body, `None` as body when it exceeds
+/// `MAX_BODY_LEN`
+async fn read_request<S>(
+    transport: &mut Framed<S, protocol::Http>,
+) -> Option<io::Result<(Request<()>, Option<Vec<u8>>)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = match transport.next().await? {
+        Ok(Message::Request(request)) => request,
+        Ok(_) => {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a request",
+            )))
+        }
+        Err(e) => return Some(Err(e)),
+    };
+    if protocol::expects_continue(&request) {
+        if let Err(e) = transport.send(protocol::continue_response()).await {
+            return Some(Err(e));
+        }
+    }
+    let mut body = Some(Vec::new());
+    loop {
+        match transport.next().await? {
+            Ok(Message::Body(data)) => {
+                // Keep reading so the next request is framed correctly
+                body = body
+                    .filter(|b| b.len() + data.len() <= MAX_BODY_LEN)
+                    .map(|mut b| {
+                        b.extend_from_slice(&data);
+                        b
+                    });
+            }
+            Ok(Message::End) => return Some(Ok((request, body))),
+            Ok(Message::Request(..)) => {
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected request",
+                )))
+            }
+            Err(e) => return Some(Err(e)),
+        }
+    }
+}
+
+/// Run the API server until the listener fails
+pub async fn run(context: SharedContext, config: ApiConfig) -> Result<(), Box<dyn StdError>> {
+    let acceptor = match config.tls {
+        Some(ref config) => Some(tls::server_acceptor(config)?),
+        None => None,
+    };
+    let guard = std::sync::Arc::new(Guard::new(&config));
+    if guard.secret.is_none() && !config.cors_allowed_origins.is_empty() {
+        error!("API allows cross origin requests without a secret, any website may control tache");
+    }
+
+    let mut incoming = listener::bind(&config.listen).await?;
+    info!("API listening on: {}", config.listen);
+
+    while let Some(Ok(stream)) = incoming.next().await {
+        let context = context.clone();
+        let guard = guard.clone();
+        let acceptor = acceptor.clone();
+        let peer = stream.peer_addr();
+        let local = stream.is_local();
+        rt::spawn(async move {
+            match acceptor {
+                Some(acceptor) => match tls::accept(&acceptor, stream).await {
+                    Ok(stream) => serve_connection(context, &guard, stream, peer, local).await,
+                    Err(e) => debug!("API TLS handshake failed, err: {}", e),
+                },
+                None => serve_connection(context, &guard, stream, peer, local).await,
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        context::Context,
+        engine::{tracker::ConnectionTracker, ConnectionMeta},
+    };
+
+    fn guard_of(config: &str) -> Guard {
+        let config: ApiConfig = serde_yaml::from_str(config).unwrap();
+        Guard::new(&config)
+    }
+
+    fn request(uri: &str, headers: &[(header::HeaderName, &str)]) -> Request<()> {
+        let mut builder = Request::builder().uri(uri);
+        for &(ref name, value) in headers {
+            builder = builder.header(name, value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn checks_the_secret() {
+        let guard = guard_of("listen: 127.0.0.1:9090\nsecret: s3cret");
+        assert!(!guard.authorized(&request("/proxies", &[]), false));
+        assert!(!guard.authorized(&request("/proxies", &[]), true));
+        let bearer = [(header::AUTHORIZATION, "Bearer s3cret")];
+        assert!(guard.authorized(&request("/proxies", &bearer), false));
+        let wrong = [(header::AUTHORIZATION, "Bearer wrong")];
+        assert!(!guard.authorized(&request("/proxies", &wrong), false));
+        assert!(guard.authorized(&request("/traffic?a=1&token=s3cret", &[]), false));
+
+        let guard = guard_of(
+            "listen: 127.0.0.1:9090\nsecret: s3cret\nallow-unauthenticated-localhost: true",
+        );
+        assert!(guard.authorized(&request("/proxies", &[]), true));
+        assert!(!guard.authorized(&request("/proxies", &[]), false));
+
+        let guard = guard_of("listen: 127.0.0.1:9090");
+        assert!(guard.authorized(&request("/proxies", &[]), false));
+    }
+
+    #[test]
+    fn allows_listed_origins() {
+        let guard =
+            guard_of("listen: 127.0.0.1:9090\ncors-allowed-origins: [\"https://ui.example.com/\"]");
+        let allowed = [(header::ORIGIN, "https://ui.example.com")];
+        assert_eq!(
+            guard.allowed_origin(&request("/", &allowed)).unwrap(),
+            "https://ui.example.com"
+        );
+        let other = [(header::ORIGIN, "https://evil.example.com")];
+        assert!(guard.allowed_origin(&request("/", &other)).is_none());
+        assert!(guard.allowed_origin(&request("/", &[])).is_none());
+
+        let guard = guard_of("listen: 127.0.0.1:9090\ncors-allowed-origins: [\"*\"]");
+        assert_eq!(guard.allowed_origin(&request("/", &other)).unwrap(), "*");
+    }
+
+    fn call(context: &SharedContext, method: Method, uri: &str) -> Response<String> {
+        let request = Request::builder().method(method).uri(uri).body(()).unwrap();
+        let req = ApiRequest {
+            context,
+            request: &request,
+            body: &[],
+            peer: None,
+            local: true,
+        };
+        rt::Runtime::new().unwrap().block_on(route(req))
+    }
+
+    #[test]
+    fn gc_frees_idle_resources() {
+        let context: SharedContext = Arc::new(Context::new(Config::new()).unwrap());
+        drop(context.buffer_pool().get());
+        let meta = ConnectionMeta {
+            udp: false,
+            inbound: "HTTP".to_owned(),
+            user: None,
+            uid: None,
+            host: "example.com".to_owned(),
+            dst_port: 443,
+            src_addr: None,
+            dst_addr: None,
+            protocol: None,
+            sni: None,
+            tls_version: None,
+            fingerprint: None,
+        };
+        ConnectionTracker::open(&context, "HTTP", &meta);
+
+        let resp = call(&context, Method::POST, "/gc");
+        assert_eq!(resp.status(), StatusCode::OK);
+        let freed: serde_json::Value = serde_json::from_str(resp.body()).unwrap();
+        assert_eq!(freed["connections"], 0);
+        assert_eq!(freed["buffers"], 1);
+        assert_eq!(freed["closed"], 1);
+        assert_eq!(context.buffer_pool().idle(), 0);
+        assert!(context.close_stats().recent().is_empty());
+
+        let resp = call(&context, Method::GET, "/gc");
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn post_refreshes_providers() {
+        let context: SharedContext = Arc::new(Context::new(Config::new()).unwrap());
+        for uri in &["/providers/proxies/missing", "/providers/rules/missing"] {
+            let resp = call(&context, Method::POST, uri);
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+            assert!(resp.body().contains("resource not found"));
+        }
+        let resp = call(&context, Method::POST, "/providers/proxies");
+        assert!(resp.body().contains("\"message\":\"not found\""));
+    }
+}
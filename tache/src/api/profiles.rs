@@ -0,0 +1,32 @@
+//! `/profiles`, switching between the configs of the profiles directory
+
+use std::io;
+
+use http::{Method, Response, StatusCode};
+use serde_json::json;
+
+use super::{empty_response, error_response, json_response, ApiRequest};
+
+pub fn route(req: &ApiRequest<'_>, segments: &[&str]) -> Response<String> {
+    let profiles = match req.context.profiles() {
+        Some(profiles) => profiles,
+        None => return error_response(StatusCode::NOT_FOUND, "not serving profiles"),
+    };
+    match (req.request.method(), segments) {
+        (&Method::GET, []) => match profiles.list() {
+            Ok(list) => json_response(
+                StatusCode::OK,
+                &json!({ "profiles": list, "active": profiles.active() }),
+            ),
+            Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        },
+        (&Method::POST, [name, "activate"]) => match profiles.activate(name) {
+            Ok(()) => empty_response(StatusCode::NO_CONTENT),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                error_response(StatusCode::NOT_FOUND, &e.to_string())
+            }
+            Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+        },
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
@@ -0,0 +1,64 @@
+//! `/providers/proxies` and `/providers/rules`
+//!
+//! PUT and POST on a provider both download it again.
+
+use http::{Method, Response, StatusCode};
+use serde_json::{json, Map, Value};
+
+use super::{empty_response, error_response, json_response, ApiRequest};
+
+pub async fn route(req: &ApiRequest<'_>, segments: &[&str]) -> Response<String> {
+    let providers = req.context.providers();
+    match (req.request.method(), segments) {
+        (&Method::GET, ["proxies"]) => {
+            let list: Map<String, Value> = providers
+                .proxies
+                .iter()
+                .map(|(name, p)| (name.clone(), p.to_json()))
+                .collect();
+            json_response(StatusCode::OK, &json!({ "providers": list }))
+        }
+        (&Method::GET, ["proxies", name]) => match providers.proxies.get(*name) {
+            Some(p) => json_response(StatusCode::OK, &p.to_json()),
+            None => error_response(StatusCode::NOT_FOUND, "resource not found"),
+        },
+        (&Method::PUT, ["proxies", name]) | (&Method::POST, ["proxies", name]) => {
+            match providers.proxies.get(*name) {
+                Some(p) => match p.update().await {
+                    Ok(()) => empty_response(StatusCode::NO_CONTENT),
+                    Err(e) => error_response(StatusCode::SERVICE_UNAVAILABLE, &e.to_string()),
+                },
+                None => error_response(StatusCode::NOT_FOUND, "resource not found"),
+            }
+        }
+        (&Method::PUT, ["proxies", name, "healthcheck"]) => match providers.proxies.get(*name) {
+            Some(p) => {
+                p.health_check(&req.context.events()).await;
+                empty_response(StatusCode::NO_CONTENT)
+            }
+            None => error_response(StatusCode::NOT_FOUND, "resource not found"),
+        },
+        (&Method::GET, ["rules"]) => {
+            let list: Map<String, Value> = providers
+                .rules
+                .iter()
+                .map(|(name, p)| (name.clone(), p.to_json()))
+                .collect();
+            json_response(StatusCode::OK, &json!({ "providers": list }))
+        }
+        (&Method::GET, ["rules", name]) => match providers.rules.get(*name) {
+            Some(p) => json_response(StatusCode::OK, &p.to_json()),
+            None => error_response(StatusCode::NOT_FOUND, "resource not found"),
+        },
+        (&Method::PUT, ["rules", name]) | (&Method::POST, ["rules", name]) => {
+            match providers.rules.get(*name) {
+                Some(p) => match p.update().await {
+                    Ok(()) => empty_response(StatusCode::NO_CONTENT),
+                    Err(e) => error_response(StatusCode::SERVICE_UNAVAILABLE, &e.to_string()),
+                },
+                None => error_response(StatusCode::NOT_FOUND, "resource not found"),
+            }
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
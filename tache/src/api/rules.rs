@@ -0,0 +1,57 @@
+//! `/rules`, editing the rule list of the running engine
+//!
+//! Rules are listed with the connections they matched since tache started.
+
+use std::collections::HashMap;
+
+use http::{Method, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{empty_response, error_response, json_response, ApiRequest};
+use crate::{config::RuleConfig, engine::rules::hits};
+
+#[derive(Deserialize)]
+struct RuleList {
+    rules: Vec<RuleConfig>,
+}
+
+pub fn route(req: &ApiRequest<'_>, segments: &[&str]) -> Response<String> {
+    match (req.request.method(), segments) {
+        (&Method::GET, []) => {
+            let sub_rules = req
+                .context
+                .config()
+                .sub_rules
+                .iter()
+                .map(|(name, rules)| (name.clone(), hits::list(Some(name), rules)))
+                .collect::<HashMap<_, _>>();
+            json_response(
+                StatusCode::OK,
+                &json!({
+                    "rules": hits::list(None, &req.context.rule_configs()),
+                    "sub-rules": sub_rules,
+                }),
+            )
+        }
+        // Replace the whole list
+        (&Method::PUT, []) => edit(req, |rules, new| *rules = new),
+        // Append after the current rules
+        (&Method::POST, []) => edit(req, |rules, new| rules.extend(new)),
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+fn edit<F>(req: &ApiRequest<'_>, apply: F) -> Response<String>
+where
+    F: FnOnce(&mut Vec<RuleConfig>, Vec<RuleConfig>),
+{
+    let list = match serde_json::from_slice::<RuleList>(req.body) {
+        Ok(list) => list,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    match req.context.edit_rules(|rules| apply(rules, list.rules)) {
+        Ok(()) => empty_response(StatusCode::NO_CONTENT),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e),
+    }
+}
@@ -4,21 +4,43 @@
 //! or you could specify a configuration file. The format of configuration file is defined
 //! in mod `config`.
 
-use std::{io::Result as IoResult, net::SocketAddr, process};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Result as IoResult, Write},
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    pin::Pin,
+    process,
+    sync::Arc,
+};
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use futures::{
+    channel::mpsc::UnboundedReceiver,
     future::{select, Either},
-    prelude::*,
     Future,
 };
 use log::{debug, error, info};
-use tokio::net::signal;
-use tokio::runtime::Runtime;
+use serde::Deserialize;
+use tokio::signal;
 
-use tache::{run, Config, Mode};
+use tache::{
+    config::{ApiConfig, InboundConfig, RuleConfig, RuleSetFormat},
+    convert::{self, Format},
+    crypto,
+    engine::rules::{Metadata, RuleSet},
+    geoip::{self, Databases, GeoIP},
+    outbound::{build_outbounds, probe, speedtest},
+    profile::Profiles,
+    provider::{self, Providers},
+    run, run_profile,
+    state::Dirs,
+    Config, ListenAddress, Mode,
+};
 
 mod logging;
+#[cfg(windows)]
+mod service;
 
 fn main() {
     let matches = App::new("tache")
@@ -35,30 +57,298 @@ fn main() {
                 .short("c")
                 .long("config")
                 .takes_value(true)
-                .help("Specify config file"),
+                .help("Specify config file, - reads it from stdin"),
+        )
+        .arg(
+            Arg::with_name("PROFILE")
+                .short("p")
+                .long("profile")
+                .takes_value(true)
+                .conflicts_with("CONFIG")
+                .help("Serve a profile of the profiles directory, switchable through the API"),
+        )
+        .arg(
+            Arg::with_name("PROFILES_DIR")
+                .long("profiles-dir")
+                .takes_value(true)
+                .default_value("profiles")
+                .help("Directory of profiles, named config files"),
+        )
+        .subcommand(
+            SubCommand::with_name("test-proxies")
+                .about("Measure every proxy of the config and print them fastest first")
+                .arg(
+                    Arg::with_name("URL")
+                        .long("url")
+                        .takes_value(true)
+                        .default_value(tache::outbound::probe::DEFAULT_TEST_URL)
+                        .help("Url fetched to measure handshake and first byte"),
+                )
+                .arg(
+                    Arg::with_name("DOWNLOAD")
+                        .long("download")
+                        .takes_value(true)
+                        .help("Url downloaded to measure throughput"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("speedtest")
+                .about("Measure latency, jitter and throughput of one proxy or group")
+                .arg(
+                    Arg::with_name("NAME")
+                        .required(true)
+                        .help("Proxy or group to test"),
+                )
+                .arg(
+                    Arg::with_name("URL")
+                        .long("url")
+                        .takes_value(true)
+                        .default_value(tache::outbound::probe::DEFAULT_TEST_URL)
+                        .help("Url fetched to measure latency and jitter"),
+                )
+                .arg(
+                    Arg::with_name("DOWNLOAD")
+                        .long("download")
+                        .takes_value(true)
+                        .help("Url downloaded to measure throughput, speedtest.url of the config unless set"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("explain")
+                .about("Run the rules against a connection and print which one matched and why")
+                .arg(
+                    Arg::with_name("HOST")
+                        .long("host")
+                        .takes_value(true)
+                        .help("Destination domain"),
+                )
+                .arg(
+                    Arg::with_name("DST")
+                        .long("dst")
+                        .takes_value(true)
+                        .help("Destination address, ip or ip:port"),
+                )
+                .arg(
+                    Arg::with_name("PORT")
+                        .long("port")
+                        .takes_value(true)
+                        .help("Destination port when --dst has none, 80 by default"),
+                )
+                .arg(
+                    Arg::with_name("SRC")
+                        .long("src")
+                        .takes_value(true)
+                        .help("Source address, ip or ip:port"),
+                )
+                .arg(
+                    Arg::with_name("SNI")
+                        .long("sni")
+                        .takes_value(true)
+                        .help("Server name of the TLS ClientHello, as sniffed"),
+                )
+                .arg(
+                    Arg::with_name("PROTOCOL")
+                        .long("protocol")
+                        .takes_value(true)
+                        .possible_values(&["http", "tls", "quic"])
+                        .help("Protocol the client speaks, as sniffed"),
+                )
+                .arg(
+                    Arg::with_name("TLS_VERSION")
+                        .long("tls-version")
+                        .takes_value(true)
+                        .possible_values(&["1.0", "1.1", "1.2", "1.3"])
+                        .help("Highest TLS version the client offers"),
+                )
+                .arg(
+                    Arg::with_name("UDP")
+                        .long("udp")
+                        .help("Match as a UDP connection"),
+                )
+                .arg(
+                    Arg::with_name("INBOUND")
+                        .long("inbound")
+                        .takes_value(true)
+                        .default_value("")
+                        .help("Name of the inbound the connection came in on"),
+                )
+                .arg(
+                    Arg::with_name("USER")
+                        .long("user")
+                        .takes_value(true)
+                        .help("User authenticated on the inbound"),
+                )
+                .arg(
+                    Arg::with_name("UID")
+                        .long("uid")
+                        .takes_value(true)
+                        .help("Local user running the client"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("analyze-rules")
+                .about("List the rules that never matched since the tache serving the config started, asking its API"),
+        )
+        .subcommand(
+            SubCommand::with_name("compile-ruleset")
+                .about("Compile a yaml or text rule set to the binary format, which loads faster")
+                .arg(
+                    Arg::with_name("FROM")
+                        .long("from")
+                        .takes_value(true)
+                        .possible_values(&["yaml", "text"])
+                        .help("Format of the input, detected unless set"),
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .required(true)
+                        .help("Rule set to compile"),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .required(true)
+                        .help("File the compiled set is written to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("service")
+                .about("Run as a Windows service, started at boot with the config given by -c")
+                .subcommand(SubCommand::with_name("install").about("Register the service"))
+                .subcommand(SubCommand::with_name("uninstall").about("Stop and remove the service"))
+                .subcommand(
+                    SubCommand::with_name("run").about("Serve under the service control manager, as it launches the service"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("schema")
+                .about("Print the JSON Schema of the config file, for editor completion"),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Print a Clash or Surge config as a tache config")
+                .arg(
+                    Arg::with_name("FROM")
+                        .long("from")
+                        .takes_value(true)
+                        .possible_values(&["clash", "surge"])
+                        .help("Format of the input, surge for .conf files and clash otherwise"),
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .required(true)
+                        .help("Config file to convert"),
+                ),
         )
         .get_matches();
 
+    if matches.subcommand_matches("schema").is_some() {
+        let schema = tache::config::schema();
+        println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+        return;
+    }
+
+    if let Some(args) = matches.subcommand_matches("convert") {
+        if let Err(err) = convert_config(args) {
+            eprintln!("Converting failed: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(args) = matches.subcommand_matches("compile-ruleset") {
+        if let Err(err) = compile_ruleset(args) {
+            eprintln!("Compiling failed: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
     let debug_level = matches.occurrences_of("VERBOSE");
 
     logging::init(true, debug_level, "tachelocal");
 
-    let mut config = match matches.value_of("CONFIG") {
-        Some(config_path) => match Config::load_from_file(config_path) {
+    if let Some(args) = matches.subcommand_matches("service") {
+        if args.subcommand_matches("run").is_none() {
+            if let Err(err) = manage_service(matches.value_of("CONFIG"), args) {
+                error!("Managing the service failed: {}", err);
+                process::exit(1);
+            }
+            return;
+        }
+    }
+
+    let mut profiles = None;
+    let config = if let Some(name) = matches.value_of("PROFILE") {
+        let (p, switches) = Profiles::new(matches.value_of("PROFILES_DIR").unwrap());
+        let config = match p.load(name) {
             Ok(cfg) => cfg,
             Err(err) => {
-                error!("{:?}", err);
+                error!("{}", err);
                 return;
             }
-        },
-        None => Config::new(),
+        };
+        p.set_active(name);
+        profiles = Some((p, switches));
+        config
+    } else {
+        match matches.value_of("CONFIG") {
+            Some(config_path) => match Config::load_from_file(config_path) {
+                Ok(cfg) => cfg,
+                Err(err) => {
+                    error!("{:?}", err);
+                    return;
+                }
+            },
+            None => Config::new(),
+        }
     };
 
+    if let Some(test) = matches.subcommand_matches("test-proxies") {
+        let url = test.value_of("URL").unwrap();
+        if let Err(err) = test_proxies(&config, url, test.value_of("DOWNLOAD")) {
+            error!("Testing proxies failed: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(args) = matches.subcommand_matches("speedtest") {
+        if let Err(err) = speed_test(&config, args) {
+            error!("Speed test failed: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(args) = matches.subcommand_matches("explain") {
+        if let Err(err) = explain(&config, args) {
+            error!("Explaining failed: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("analyze-rules").is_some() {
+        if let Err(err) = analyze_rules(&config) {
+            error!("Analyzing rules failed: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("service").is_some() {
+        if let Err(err) = run_service(config) {
+            error!("Service exited with error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
     info!("Tache {}", tache::VERSION);
 
     debug!("Config: {:?}", config);
 
-    match launch_server(config) {
+    match launch_server(config, profiles) {
         Ok(()) => {}
         Err(err) => {
             error!("Server exited unexpectly with error: {}", err);
@@ -67,17 +357,370 @@ fn main() {
     }
 }
 
-fn launch_server(config: Config) -> IoResult<()> {
-    let runtime = Runtime::new().expect("Creating runtime");
+#[cfg(windows)]
+fn manage_service(config: Option<&str>, args: &clap::ArgMatches) -> IoResult<()> {
+    match args.subcommand_name() {
+        Some("install") => match config {
+            Some(path) if path != "-" => {
+                service::install(std::path::Path::new(path))?;
+                info!("Installed service {}", service::SERVICE_NAME);
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the service needs a config file, give it with -c",
+            )),
+        },
+        Some("uninstall") => {
+            service::uninstall()?;
+            info!("Removed service {}", service::SERVICE_NAME);
+            Ok(())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "expected install, uninstall or run",
+        )),
+    }
+}
+
+#[cfg(windows)]
+fn run_service(config: Config) -> IoResult<()> {
+    service::run(config)
+}
+
+#[cfg(not(windows))]
+fn manage_service(_config: Option<&str>, _args: &clap::ArgMatches) -> IoResult<()> {
+    Err(not_windows())
+}
+
+#[cfg(not(windows))]
+fn run_service(_config: Config) -> IoResult<()> {
+    Err(not_windows())
+}
+
+#[cfg(not(windows))]
+fn not_windows() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "services are only supported on Windows, use systemd or launchd here",
+    )
+}
+
+fn test_proxies(config: &Config, url: &str, download: Option<&str>) -> IoResult<()> {
+    let mut runtime = tache::rt::runtime(config.runtime.as_ref())?;
+    let outbounds = build_outbounds(
+        &config.proxies,
+        &config.proxy_groups,
+        config.circuit_breaker.as_ref(),
+        crypto::preferred(config.cipher_preference.unwrap_or_default()),
+    );
+    let outbounds = probe::proxy_outbounds(&config.proxies, &outbounds);
+    let reports = runtime.block_on(probe::measure_all(&outbounds, url, download));
+    print!("{}", probe::table(&reports));
+    Ok(())
+}
+
+fn speed_test(config: &Config, args: &clap::ArgMatches) -> IoResult<()> {
+    let name = args.value_of("NAME").unwrap();
+    let mut runtime = tache::rt::runtime(config.runtime.as_ref())?;
+    let outbounds = build_outbounds(
+        &config.proxies,
+        &config.proxy_groups,
+        config.circuit_breaker.as_ref(),
+        crypto::preferred(config.cipher_preference.unwrap_or_default()),
+    );
+    let outbound = outbounds.get(name).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no proxy or group named {}", name),
+        )
+    })?;
+    let download = args
+        .value_of("DOWNLOAD")
+        .map(str::to_owned)
+        .or_else(|| config.speedtest.as_ref().and_then(|s| s.url.clone()))
+        .unwrap_or_else(|| speedtest::DEFAULT_URL.to_owned());
+    let url = args.value_of("URL").unwrap();
+    let test = runtime.block_on(speedtest::run(name, &**outbound, url, &download));
+    let ms = |v: Option<u64>| v.map_or_else(|| "-".to_owned(), |v| format!("{}ms", v));
+    println!("latency     {}", ms(test.latency));
+    println!("jitter      {}", ms(test.jitter));
+    println!(
+        "throughput  {}",
+        test.throughput.map_or_else(
+            || "-".to_owned(),
+            |t| format!("{}KiB/s ({} bytes)", t / 1024, test.bytes)
+        )
+    );
+    if let Some(error) = test.error {
+        println!("error       {}", error);
+    }
+    Ok(())
+}
+
+fn convert_config(args: &clap::ArgMatches) -> Result<(), String> {
+    let input = args.value_of("INPUT").unwrap();
+    let format = match args.value_of("FROM") {
+        Some(from) => from.parse()?,
+        None if input.ends_with(".conf") => Format::Surge,
+        None => Format::Clash,
+    };
+    let text = fs::read_to_string(input).map_err(|e| format!("{}: {}", input, e))?;
+    let converted = convert::convert(format, &text)?;
+    for warning in converted.warnings.iter() {
+        eprintln!("warning: {}", warning);
+    }
+    print!("{}", converted.yaml);
+    Ok(())
+}
+
+fn compile_ruleset(args: &clap::ArgMatches) -> Result<(), String> {
+    let input = args.value_of("INPUT").unwrap();
+    let output = args.value_of("OUTPUT").unwrap();
+    let format = match args.value_of("FROM") {
+        Some("yaml") => Some(RuleSetFormat::Yaml),
+        Some(_) => Some(RuleSetFormat::Text),
+        None => None,
+    };
+    let content = fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+    let compiled = provider::compile(&content, format).map_err(|e| format!("{}: {}", input, e))?;
+    tache::state::write_atomic(output, &compiled).map_err(|e| format!("{}: {}", output, e))?;
+    eprintln!("{} bytes to {} bytes", content.len(), compiled.len());
+    Ok(())
+}
+
+/// `ip` or `ip:port`
+fn parse_addr(value: &str) -> Result<(IpAddr, Option<u16>), String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok((addr.ip(), Some(addr.port())));
+    }
+    value
+        .parse::<IpAddr>()
+        .map(|ip| (ip, None))
+        .map_err(|_| format!("invalid address \"{}\"", value))
+}
+
+#[derive(Deserialize)]
+struct ListedRule {
+    #[serde(flatten)]
+    rule: RuleConfig,
+    hits: u64,
+}
+
+#[derive(Deserialize)]
+struct ListedRules {
+    rules: Vec<ListedRule>,
+    #[serde(rename = "sub-rules", default)]
+    sub_rules: HashMap<String, Vec<ListedRule>>,
+}
+
+/// GET `path` of the API, the body of a 200 response
+fn api_get(api: &ApiConfig, path: &str) -> Result<Vec<u8>, String> {
+    if api.tls.is_some() {
+        return Err("the API is served over TLS, which this command doesn't speak".to_owned());
+    }
+    let secret = api.secret.as_deref().unwrap_or("");
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: tache\r\nAuthorization: Bearer {}\r\n\r\n",
+        path, secret
+    );
+    let exchange = |stream: &mut dyn ReadWrite| -> io::Result<Vec<u8>> {
+        stream.write_all(request.as_bytes())?;
+        stream.shutdown_write()?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        Ok(response)
+    };
+    let response = match api.listen {
+        ListenAddress::Tcp(ref addr) => {
+            let mut addr = addr
+                .to_socket_addrs()
+                .map_err(|e| e.to_string())?
+                .next()
+                .ok_or_else(|| format!("{} resolved to no address", addr))?;
+            // Listening on all interfaces, reach it over loopback
+            if addr.ip().is_unspecified() {
+                addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            }
+            exchange(&mut TcpStream::connect(addr).map_err(|e| format!("{}: {}", addr, e))?)
+        }
+        #[cfg(unix)]
+        ListenAddress::Unix(ref path) => exchange(
+            &mut std::os::unix::net::UnixStream::connect(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?,
+        ),
+        #[cfg(not(unix))]
+        ListenAddress::Unix(..) => return Err("unix sockets need a unix system".to_owned()),
+    }
+    .map_err(|e| e.to_string())?;
+
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("malformed response from the API")?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        return Err(format!(
+            "the API answered {}: {}",
+            status,
+            String::from_utf8_lossy(&response[split + 4..])
+        ));
+    }
+    Ok(response[split + 4..].to_vec())
+}
+
+/// Streams `api_get` talks to the API over
+trait ReadWrite: Read + Write {
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl ReadWrite for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl ReadWrite for std::os::unix::net::UnixStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+fn analyze_rules(config: &Config) -> Result<(), String> {
+    let api = config
+        .api
+        .as_ref()
+        .ok_or("the config has no api to ask the running tache")?;
+    let body = api_get(api, "/rules")?;
+    let listed: ListedRules = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+
+    let mut lists = vec![(String::new(), listed.rules)];
+    let mut sub_rules = listed.sub_rules.into_iter().collect::<Vec<_>>();
+    sub_rules.sort_by(|a, b| a.0.cmp(&b.0));
+    lists.extend(
+        sub_rules
+            .into_iter()
+            .map(|(name, rules)| (format!("{}/", name), rules)),
+    );
 
-    let abort_signal = signal::ctrl_c()?;
+    let total = lists.iter().map(|(_, rules)| rules.len()).sum::<usize>();
+    let mut unused = 0;
+    for (prefix, rules) in lists.iter() {
+        for (index, listed) in rules.iter().enumerate() {
+            if listed.hits == 0 {
+                unused += 1;
+                println!("{}#{:<3} {}", prefix, index + 1, listed.rule.line());
+            }
+        }
+    }
+    println!("{} of {} rules never matched", unused, total);
+    Ok(())
+}
 
-    let result = runtime.block_on(select(
-        Box::pin(run(config)),
-        Box::pin(abort_signal.into_future()),
-    ));
+fn explain(config: &Config, args: &clap::ArgMatches) -> Result<(), String> {
+    let dst = args.value_of("DST").map(parse_addr).transpose()?;
+    let src = args.value_of("SRC").map(parse_addr).transpose()?;
+    let dst_port = match (dst.and_then(|(_, port)| port), args.value_of("PORT")) {
+        (Some(port), _) => port,
+        (None, Some(port)) => port
+            .parse()
+            .map_err(|_| format!("invalid port \"{}\"", port))?,
+        (None, None) => 80,
+    };
+    let uid = match args.value_of("UID") {
+        Some(uid) => Some(
+            uid.parse()
+                .map_err(|_| format!("invalid uid \"{}\"", uid))?,
+        ),
+        None => None,
+    };
+    let tls_version = args.value_of("TLS_VERSION").map(|version| match version {
+        "1.0" => 0x0301,
+        "1.1" => 0x0302,
+        "1.2" => 0x0303,
+        _ => 0x0304,
+    });
+    if args.value_of("HOST").is_none() && dst.is_none() {
+        return Err("give --host, --dst or both".to_owned());
+    }
+
+    // With both the address is what the domain resolved to
+    let host = args.value_of("HOST").unwrap_or("");
+    let dst_ip = dst.map(|(ip, _)| ip);
+    let meta = Metadata {
+        inbound: args.value_of("INBOUND").unwrap(),
+        user: args.value_of("USER"),
+        uid,
+        host,
+        dst_ip: dst_ip.filter(|_| host.is_empty()),
+        resolved_ip: dst_ip.filter(|_| !host.is_empty()),
+        dst_port,
+        src_ip: src.map(|(ip, _)| ip),
+        src_port: src.and_then(|(_, port)| port),
+        udp: args.is_present("UDP"),
+        protocol: args.value_of("PROTOCOL"),
+        sni: args.value_of("SNI"),
+        tls_version,
+    };
+    let country = geoip::load(config.geoip_database.as_deref())
+        .map_err(|e| e.to_string())?;
+    let asn = match config.asn_database {
+        Some(ref path) => Some(Arc::new(GeoIP::open(path).map_err(|e| e.to_string())?)),
+        None => None,
+    };
+    // RULE-SET rules read the providers' copies on disk
+    let providers = Providers::new(
+        &config.proxy_providers,
+        &config.rule_providers,
+        &Dirs::new(config.state_dir.as_deref()),
+    );
+    let rules = RuleSet::new(config, Databases { country, asn }, &providers);
+    let (matched, steps) = rules.explain(&meta);
+
+    for step in &steps {
+        let origin = step
+            .origin
+            .as_ref()
+            .map_or_else(String::new, |o| format!(" (from {})", o));
+        println!(
+            "#{:<3} {}: {}{}",
+            step.index, step.rule, step.outcome, origin
+        );
+    }
+    match matched {
+        Some(matched) => println!("=> {} via {}", matched.target, matched.rule),
+        None => println!("=> no rule matched, connection goes DIRECT"),
+    }
+    let mode = config
+        .inbounds
+        .iter()
+        .find(|inbound| inbound.name() == meta.inbound)
+        .and_then(InboundConfig::mode)
+        .unwrap_or(&config.mode);
+    match mode {
+        Mode::Rule => {}
+        mode => println!("note: mode is {}, rules are not used", mode),
+    }
+    Ok(())
+}
+
+fn launch_server(
+    config: Config,
+    profiles: Option<(Profiles, UnboundedReceiver<Config>)>,
+) -> IoResult<()> {
+    let mut runtime = tache::rt::runtime(config.runtime.as_ref()).expect("Creating runtime");
+
+    let abort_signal = signal::ctrl_c();
+
+    let server: Pin<Box<dyn Future<Output = IoResult<()>>>> = match profiles {
+        Some((profiles, switches)) => Box::pin(run_profile(profiles, switches, config)),
+        None => Box::pin(run(config)),
+    };
+    let result = runtime.block_on(select(server, Box::pin(abort_signal)));
 
-    runtime.shutdown_now();
+    tache::rt::shutdown(runtime);
 
     match result {
         // Server future resolved without an error. This should never happen.
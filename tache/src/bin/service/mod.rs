@@ -0,0 +1,159 @@
+//! Running as a Windows service
+//!
+//! `service install` registers this binary with the service control manager
+//! to start at boot with the given config, SCM then launches it as
+//! `service run`. Stopping the service, or Windows shutting down, shuts the
+//! engine down like ctrl-c does in a console.
+
+use std::{
+    ffi::OsString,
+    io,
+    path::Path,
+    sync::{mpsc, Mutex},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use log::{error, info};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use tache::{Config, Engine};
+
+pub const SERVICE_NAME: &str = "tache";
+const DISPLAY_NAME: &str = "Tache";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+lazy_static! {
+    /// Config loaded by `main`, taken by the service main SCM calls
+    static ref CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn to_io(e: windows_service::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Register the service, started at boot with the config at `config`
+pub fn install(config: &Path) -> io::Result<()> {
+    let config = config.canonicalize()?;
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(to_io)?;
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(DISPLAY_NAME),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: vec![
+            OsString::from("-c"),
+            config.into_os_string(),
+            OsString::from("service"),
+            OsString::from("run"),
+        ],
+        account_name: None,
+        account_password: None,
+    };
+    manager
+        .create_service(info, ServiceAccess::empty())
+        .map_err(to_io)?;
+    Ok(())
+}
+
+/// Stop the service if running and remove it
+pub fn uninstall() -> io::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(to_io)?;
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(to_io)?;
+    if service.query_status().map_err(to_io)?.current_state != ServiceState::Stopped {
+        service.stop().map_err(to_io)?;
+    }
+    // Removed by SCM once stopped
+    service.delete().map_err(to_io)
+}
+
+/// Hand the process over to SCM, returns once the service stopped
+pub fn run(config: Config) -> io::Result<()> {
+    *CONFIG.lock().unwrap() = Some(config);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(to_io)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(err) = serve() {
+        error!("Service failed: {}", err);
+    }
+}
+
+fn serve() -> io::Result<()> {
+    let config = CONFIG
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "service started twice"))?;
+
+    let (stop, stopped) = mpsc::channel();
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            let _ = stop.send(());
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status = service_control_handler::register(SERVICE_NAME, handler).map_err(to_io)?;
+    let set_status = |state, exit_code| {
+        let controls_accepted = match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+        status
+            .set_service_status(ServiceStatus {
+                service_type: SERVICE_TYPE,
+                current_state: state,
+                controls_accepted,
+                exit_code,
+                checkpoint: 0,
+                wait_hint: Duration::default(),
+            })
+            .map_err(to_io)
+    };
+
+    set_status(ServiceState::StartPending, ServiceExitCode::Win32(0))?;
+    let engine = match Engine::builder().config(config).build() {
+        Ok(engine) => engine,
+        Err(err) => {
+            set_status(ServiceState::Stopped, ServiceExitCode::ServiceSpecific(1))?;
+            return Err(io::Error::new(io::ErrorKind::Other, err.to_string()));
+        }
+    };
+    if let Err(err) = engine.start() {
+        set_status(ServiceState::Stopped, ServiceExitCode::ServiceSpecific(1))?;
+        return Err(io::Error::new(io::ErrorKind::Other, err.to_string()));
+    }
+    set_status(ServiceState::Running, ServiceExitCode::Win32(0))?;
+    info!("Service {} running", SERVICE_NAME);
+
+    // A dropped handler ends the service as well
+    let _ = stopped.recv();
+    set_status(ServiceState::StopPending, ServiceExitCode::Win32(0))?;
+    let _ = engine.shutdown();
+    set_status(ServiceState::Stopped, ServiceExitCode::Win32(0))
+}
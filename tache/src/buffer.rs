@@ -0,0 +1,180 @@
+//! Reusable I/O buffers
+//!
+//! Relaying tens of thousands of connections with a fresh allocation per read
+//! path puts the allocator under pressure, buffers are recycled here instead.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::config::BufferConfig;
+
+/// Default bytes of one buffer
+pub const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+/// Default number of idle buffers kept
+pub const DEFAULT_POOL_SIZE: usize = 1024;
+/// Default bytes of buffers one relay direction may hold
+pub const DEFAULT_MAX_PER_CONNECTION: usize = 64 * 1024;
+
+pub struct BufferPool {
+    size: usize,
+    pool_size: usize,
+    max_per_connection: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+    /// Buffers borrowed and not yet returned
+    in_use: AtomicUsize,
+}
+
+impl BufferPool {
+    /// `max_per_connection` is raised to `size` when below it
+    pub fn new(size: usize, pool_size: usize, max_per_connection: usize) -> BufferPool {
+        BufferPool {
+            size,
+            pool_size,
+            max_per_connection: max_per_connection.max(size),
+            free: Mutex::new(Vec::new()),
+            in_use: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn from_config(config: Option<&BufferConfig>) -> BufferPool {
+        let size = config
+            .and_then(|c| c.size)
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_BUFFER_SIZE);
+        let pool_size = config
+            .and_then(|c| c.pool_size)
+            .unwrap_or(DEFAULT_POOL_SIZE);
+        let max_per_connection = config
+            .and_then(|c| c.max_per_connection)
+            .unwrap_or(DEFAULT_MAX_PER_CONNECTION);
+        BufferPool::new(size, pool_size, max_per_connection)
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.size
+    }
+
+    /// Buffers one relay direction may hold at once, at least one
+    pub fn max_buffers_per_connection(&self) -> usize {
+        self.max_per_connection / self.size
+    }
+
+    /// Take an idle buffer or allocate one, it goes back to the pool on drop
+    pub fn get(self: &Arc<Self>) -> Buffer {
+        let buf = self
+            .free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0u8; self.size]);
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+        Buffer {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    /// Number of idle buffers
+    pub fn idle(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    /// Free the idle buffers, returns how many
+    pub fn clear(&self) -> usize {
+        let mut free = self.free.lock().unwrap();
+        let freed = free.len();
+        *free = Vec::new();
+        freed
+    }
+
+    /// Number of buffers held by connections
+    pub fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::Relaxed)
+    }
+
+    fn put(&self, buf: Vec<u8>) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.pool_size {
+            free.push(buf);
+        }
+    }
+}
+
+/// A full sized buffer borrowed from a [`BufferPool`]
+///
+/// Contents are left over from the previous user, only read back what was written.
+pub struct Buffer {
+    buf: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        let buf = std::mem::take(&mut self.buf);
+        self.pool.put(buf);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recycles_buffers() {
+        let pool = Arc::new(BufferPool::new(4096, 1, 1024));
+        assert_eq!(pool.max_buffers_per_connection(), 1);
+
+        let mut a = pool.get();
+        a[0] = 7;
+        let b = pool.get();
+        assert_eq!((a.len(), pool.in_use(), pool.idle()), (4096, 2, 0));
+        let first = a.as_ptr();
+        drop(a);
+        // Only one idle buffer is kept
+        drop(b);
+        assert_eq!((pool.in_use(), pool.idle()), (0, 1));
+
+        let c = pool.get();
+        assert_eq!((c.as_ptr(), c[0]), (first, 7));
+        drop(c);
+        assert_eq!(pool.clear(), 1);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn sizes_from_config() {
+        let pool = BufferPool::from_config(None);
+        assert_eq!(pool.buffer_size(), DEFAULT_BUFFER_SIZE);
+        assert_eq!(pool.max_buffers_per_connection(), 4);
+
+        let config: BufferConfig =
+            serde_yaml::from_str("size: 8192\nmax-per-connection: 65536").unwrap();
+        let pool = BufferPool::from_config(Some(&config));
+        assert_eq!(pool.buffer_size(), 8192);
+        assert_eq!(pool.max_buffers_per_connection(), 8);
+
+        let config: BufferConfig = serde_yaml::from_str("size: 0").unwrap();
+        let pool = BufferPool::from_config(Some(&config));
+        assert_eq!(pool.buffer_size(), DEFAULT_BUFFER_SIZE);
+    }
+}
@@ -3,22 +3,84 @@
 use std::{
     io,
     net::SocketAddr,
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, RwLock,
+    },
     time::Instant,
 };
 
 use lru_cache::LruCache;
 use trust_dns_resolver::Resolver;
 
-use crate::{config::Config, dns_resolver::create_resolver};
+use crate::{
+    buffer::BufferPool,
+    config::{Config, Mode, RuleConfig},
+    crypto::{self, CipherKind},
+    dns,
+    dns_resolver::create_resolver,
+    engine::{
+        cache::HttpCache, capture::Capture, carryover::Carryover, handshake::HandshakeGuard,
+        hotplug::AddedInbounds, limiter::ConnectionLimiter, mitm::Mitm, qos::Qos,
+        rewrite::Rewrites, rules::RuleSet, shed::LoadShedder, tracker::CloseStats,
+        traffic::Traffic, usage::Usage,
+    },
+    event::{Event, EventBus},
+    geodb::GeoDb,
+    geoip::{self, Databases, GeoIP},
+    outbound::{
+        build_outbounds, exit::ExitChecker, speedtest::SpeedTester, Outbound, Outbounds, Pool,
+    },
+    profile::Profiles,
+    provider::Providers,
+    state::Dirs,
+};
 
 type DnsQueryCache = LruCache<u16, (SocketAddr, Instant)>;
 
+/// Rules in use with the list they were compiled from
+struct LiveRules {
+    set: Arc<RuleSet>,
+    configs: Vec<RuleConfig>,
+}
+
 #[derive(Clone)]
 pub struct Context {
     config: Config,
-    dns_resolver: Arc<Resolver>,
+    /// Switched in place, starts as the configured one
+    mode: Arc<RwLock<Mode>>,
+    /// Replaced to flush its cache
+    dns_resolver: Arc<RwLock<Arc<Resolver>>>,
     dns_query_cache: Option<Arc<Mutex<DnsQueryCache>>>,
+    outbound_pool: Arc<Pool>,
+    outbounds: Arc<Outbounds>,
+    connection_limiter: Arc<ConnectionLimiter>,
+    load_shedder: Arc<LoadShedder>,
+    handshake_guard: Arc<HandshakeGuard>,
+    providers: Arc<Providers>,
+    rules: Arc<RwLock<LiveRules>>,
+    geoip: Option<Arc<GeoIP>>,
+    asn: Option<Arc<GeoIP>>,
+    geo_db: Option<Arc<GeoDb>>,
+    dns: Option<Arc<dns::Resolver>>,
+    traffic: Arc<Traffic>,
+    close_stats: Arc<CloseStats>,
+    usage: Arc<Usage>,
+    events: Arc<EventBus>,
+    connection_id: Arc<AtomicU64>,
+    buffer_pool: Arc<BufferPool>,
+    qos: Option<Arc<Qos>>,
+    http_cache: Option<Arc<HttpCache>>,
+    mitm: Option<Arc<Mitm>>,
+    rewrites: Arc<Rewrites>,
+    profiles: Option<Arc<Profiles>>,
+    capture: Arc<Capture>,
+    preferred_cipher: CipherKind,
+    exit_checker: Arc<ExitChecker>,
+    speed_tester: Arc<SpeedTester>,
+    added_inbounds: Arc<AddedInbounds>,
+    carryover: Arc<Carryover>,
+    dirs: Arc<Dirs>,
 }
 
 pub type SharedContext = Arc<Context>;
@@ -26,20 +88,105 @@ pub type SharedContext = Arc<Context>;
 impl Context {
     pub fn new(config: Config) -> io::Result<Context> {
         let resolver = create_resolver(config.get_dns_config())?;
+        let preferred_cipher = crypto::preferred(config.cipher_preference.unwrap_or_default());
+        let outbound_pool = Arc::new(Pool::from_config(
+            config.keep_alive.as_ref(),
+            &config.proxies,
+        ));
+        let outbounds = Arc::new(build_outbounds(
+            &config.proxies,
+            &config.proxy_groups,
+            config.circuit_breaker.as_ref(),
+            preferred_cipher,
+        ));
+        let buffer_pool = Arc::new(BufferPool::from_config(config.buffer.as_ref()));
+        let qos = config.qos.as_ref().map(|c| Arc::new(Qos::new(c)));
+        let exit_checker = Arc::new(ExitChecker::new(config.exit_check.as_ref()));
+        let speed_tester = Arc::new(SpeedTester::new(config.speedtest.as_ref()));
+        let http_cache = config
+            .http_cache
+            .as_ref()
+            .map(|c| Arc::new(HttpCache::new(c)));
+        let mitm = match config.mitm.as_ref() {
+            Some(c) => Some(Arc::new(Mitm::new(c)?)),
+            None => None,
+        };
+        let rewrites = Arc::new(Rewrites::new(&config.rewrites)?);
+        let connection_limiter = Arc::new(ConnectionLimiter::new(config.connection_limit.as_ref()));
+        let load_shedder = Arc::new(LoadShedder::new(config.connection_limit.as_ref()));
+        let handshake_guard = Arc::new(HandshakeGuard::new(config.handshake.as_ref()));
+        let dirs = Dirs::new(config.state_dir.as_deref());
+        let providers = Arc::new(Providers::new(
+            &config.proxy_providers,
+            &config.rule_providers,
+            &dirs,
+        ));
+        let usage = Arc::new(Usage::load(config.stats.as_ref(), &dirs)?);
+        let geo_db = GeoDb::new(&config, &dirs).map(Arc::new);
+        let geoip = match geo_db.as_ref().and_then(|db| db.geoip()) {
+            Some(geoip) => Some(geoip),
+            None => geoip::load(config.geoip_database.as_deref())?,
+        };
+        let asn = match geo_db.as_ref().and_then(|db| db.asn()) {
+            Some(asn) => Some(asn),
+            None => match config.asn_database {
+                Some(ref path) => Some(Arc::new(GeoIP::open(path)?)),
+                None => None,
+            },
+        };
+        let databases = Databases {
+            country: geoip.clone(),
+            asn: asn.clone(),
+        };
+        let rules = Arc::new(RwLock::new(LiveRules {
+            set: Arc::new(RuleSet::new(&config, databases, &providers)),
+            configs: config.rules.clone(),
+        }));
+        let dns = config
+            .dns
+            .as_ref()
+            .map(|dns| Arc::new(dns::Resolver::new(dns, geoip.clone(), &outbounds)));
         Ok(Context {
+            mode: Arc::new(RwLock::new(config.mode.clone())),
             config,
-            dns_resolver: Arc::new(resolver),
+            dns_resolver: Arc::new(RwLock::new(Arc::new(resolver))),
             dns_query_cache: None,
+            outbound_pool,
+            outbounds,
+            connection_limiter,
+            load_shedder,
+            handshake_guard,
+            providers,
+            rules,
+            geoip,
+            asn,
+            geo_db,
+            dns,
+            traffic: Arc::new(Traffic::new()),
+            close_stats: Arc::new(CloseStats::new()),
+            usage,
+            events: Arc::new(EventBus::new()),
+            connection_id: Arc::new(AtomicU64::new(0)),
+            buffer_pool,
+            qos,
+            http_cache,
+            mitm,
+            rewrites,
+            profiles: None,
+            capture: Arc::new(Capture::new()),
+            preferred_cipher,
+            exit_checker,
+            speed_tester,
+            added_inbounds: Arc::new(AddedInbounds::new()),
+            carryover: Arc::new(Carryover::new()),
+            dirs: Arc::new(dirs),
         })
     }
 
     pub fn new_dns(config: Config) -> io::Result<Context> {
-        let resolver = create_resolver(config.get_dns_config())?;
-        Ok(Context {
-            config,
-            dns_resolver: Arc::new(resolver),
-            dns_query_cache: Some(Arc::new(Mutex::new(LruCache::new(1024)))),
-        })
+        let mut context = Context::new(config)?;
+        context.dns_query_cache = Some(Arc::new(Mutex::new(LruCache::new(1024))));
+        Ok(context)
     }
 
     pub fn config(&self) -> &Config {
@@ -50,11 +197,322 @@ impl Context {
         &mut self.config
     }
 
-    pub fn dns_resolver(&self) -> &Resolver {
-        &*self.dns_resolver
+    /// Mode of inbounds without their own
+    pub fn mode(&self) -> Mode {
+        self.mode.read().unwrap().clone()
+    }
+
+    pub fn set_mode(&self, mode: Mode) {
+        *self.mode.write().unwrap() = mode;
+    }
+
+    pub fn dns_resolver(&self) -> Arc<Resolver> {
+        self.dns_resolver.read().unwrap().clone()
+    }
+
+    /// Drop the answers the resolver cached by replacing it
+    pub fn flush_dns_cache(&self) -> io::Result<()> {
+        let resolver = create_resolver(self.config.get_dns_config())?;
+        *self.dns_resolver.write().unwrap() = Arc::new(resolver);
+        Ok(())
+    }
+
+    /// Spare connections to proxy servers, dials go through [`Pooled`]
+    ///
+    /// [`Pooled`]: crate::outbound::Pooled
+    pub fn outbound_pool(&self) -> Arc<Pool> {
+        self.outbound_pool.clone()
+    }
+
+    /// Outbound registered under `name`, `DIRECT` included
+    pub fn outbound(&self, name: &str) -> Option<Arc<dyn Outbound>> {
+        self.outbounds.get(name).cloned()
+    }
+
+    pub fn outbounds(&self) -> Arc<Outbounds> {
+        self.outbounds.clone()
+    }
+
+    pub fn connection_limiter(&self) -> Arc<ConnectionLimiter> {
+        self.connection_limiter.clone()
+    }
+
+    pub fn load_shedder(&self) -> Arc<LoadShedder> {
+        self.load_shedder.clone()
+    }
+
+    pub fn handshake_guard(&self) -> Arc<HandshakeGuard> {
+        self.handshake_guard.clone()
+    }
+
+    pub fn providers(&self) -> Arc<Providers> {
+        self.providers.clone()
+    }
+
+    pub fn rules(&self) -> Arc<RuleSet> {
+        self.rules.read().unwrap().set.clone()
+    }
+
+    /// Rule list in use, as last set by the config or the API
+    pub fn rule_configs(&self) -> Vec<RuleConfig> {
+        self.rules.read().unwrap().configs.clone()
+    }
+
+    /// Replace the rules with the list `edit` makes of the current one
+    ///
+    /// The new list is checked and compiled before it is swapped in,
+    /// connections matched from then on use it. On error the current rules
+    /// stay.
+    pub fn edit_rules<F>(&self, edit: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Vec<RuleConfig>),
+    {
+        let mut live = self.rules.write().unwrap();
+        let mut config = self.config.clone();
+        config.rules = live.configs.clone();
+        edit(&mut config.rules);
+
+        config.check_rules().map_err(|e| format!("{:?}", e))?;
+        let targets = config
+            .rules
+            .iter()
+            .chain(config.sub_rules.values().flatten());
+        for rule in targets.filter(|rule| rule.sub_rule.is_none()) {
+            if !rule.target.eq_ignore_ascii_case("REJECT") && self.outbound(&rule.target).is_none()
+            {
+                return Err(format!(
+                    "unknown target {} of rule {}",
+                    rule.target, rule.kind
+                ));
+            }
+        }
+        let set = RuleSet::try_new(&config, self.databases(), &self.providers)?;
+
+        let count = config.rules.len();
+        *live = LiveRules {
+            set: Arc::new(set),
+            configs: config.rules,
+        };
+        self.events.publish(Event::RulesReplaced { count });
+        Ok(())
+    }
+
+    pub fn geoip(&self) -> Option<Arc<GeoIP>> {
+        self.geoip.clone()
+    }
+
+    /// ASN database, when configured
+    pub fn asn(&self) -> Option<Arc<GeoIP>> {
+        self.asn.clone()
+    }
+
+    pub fn databases(&self) -> Databases {
+        Databases {
+            country: self.geoip.clone(),
+            asn: self.asn.clone(),
+        }
+    }
+
+    /// Downloaded databases, when `geo-db` is set
+    pub fn geo_db(&self) -> Option<Arc<GeoDb>> {
+        self.geo_db.clone()
+    }
+
+    /// Resolver of the built-in DNS server, present when `dns` is configured
+    pub fn dns(&self) -> Option<Arc<dns::Resolver>> {
+        self.dns.clone()
+    }
+
+    pub fn traffic(&self) -> Arc<Traffic> {
+        self.traffic.clone()
+    }
+
+    /// Count into `traffic` so totals outlive this context
+    pub fn set_traffic(&mut self, traffic: Arc<Traffic>) {
+        self.traffic = traffic;
+    }
+
+    pub fn close_stats(&self) -> Arc<CloseStats> {
+        self.close_stats.clone()
+    }
+
+    /// Count into `close_stats` so totals outlive this context
+    pub fn set_close_stats(&mut self, close_stats: Arc<CloseStats>) {
+        self.close_stats = close_stats;
+    }
+
+    /// Take over fake addresses and UDP sessions of the contexts
+    /// `carryover` was set on before
+    pub fn set_carryover(&mut self, carryover: Arc<Carryover>) {
+        let fake_ip = self.dns.as_ref().and_then(|dns| dns.fake_ip());
+        carryover.hand_over(&self.config, fake_ip);
+        self.carryover = carryover;
+    }
+
+    pub fn carryover(&self) -> Arc<Carryover> {
+        self.carryover.clone()
+    }
+
+    /// Bytes by day, outbound and client, loaded from `stats` when set
+    pub fn usage(&self) -> Arc<Usage> {
+        self.usage.clone()
+    }
+
+    pub fn buffer_pool(&self) -> Arc<BufferPool> {
+        self.buffer_pool.clone()
+    }
+
+    /// Scheduler of relays by traffic class, with `qos`
+    pub fn qos(&self) -> Option<Arc<Qos>> {
+        self.qos.clone()
+    }
+
+    /// Cache of direct HTTP responses, present when `http-cache` is configured
+    pub fn http_cache(&self) -> Option<Arc<HttpCache>> {
+        self.http_cache.clone()
+    }
+
+    /// Interception of HTTPS tunnels, present when `mitm` is configured
+    pub fn mitm(&self) -> Option<Arc<Mitm>> {
+        self.mitm.clone()
+    }
+
+    pub fn rewrites(&self) -> Arc<Rewrites> {
+        self.rewrites.clone()
+    }
+
+    pub fn events(&self) -> Arc<EventBus> {
+        self.events.clone()
+    }
+
+    /// Publish into `events` so subscribers outlive this context
+    pub fn set_events(&mut self, events: Arc<EventBus>) {
+        self.events = events;
+    }
+
+    /// Profiles the API may switch between, only when serving one of them
+    pub fn profiles(&self) -> Option<Arc<Profiles>> {
+        self.profiles.clone()
+    }
+
+    pub fn set_profiles(&mut self, profiles: Arc<Profiles>) {
+        self.profiles = Some(profiles);
+    }
+
+    /// Debug capture started from the API
+    pub fn capture(&self) -> Arc<Capture> {
+        self.capture.clone()
+    }
+
+    pub fn exit_checker(&self) -> Arc<ExitChecker> {
+        self.exit_checker.clone()
+    }
+
+    pub fn speed_tester(&self) -> Arc<SpeedTester> {
+        self.speed_tester.clone()
+    }
+
+    /// State and cache directories of this config
+    pub fn dirs(&self) -> &Dirs {
+        &self.dirs
+    }
+
+    /// Inbounds added through the API
+    pub fn added_inbounds(&self) -> Arc<AddedInbounds> {
+        self.added_inbounds.clone()
+    }
+
+    /// Cipher of proxies set to `auto`
+    pub fn preferred_cipher(&self) -> CipherKind {
+        self.preferred_cipher
+    }
+
+    /// Unique id for a new connection
+    pub fn next_connection_id(&self) -> u64 {
+        self.connection_id.fetch_add(1, Ordering::Relaxed)
     }
 
-    pub fn dns_query_cache(&self) -> MutexGuard<DnsQueryCache> {
+    pub fn dns_query_cache(&self) -> MutexGuard<'_, DnsQueryCache> {
         self.dns_query_cache.as_ref().unwrap().lock().unwrap()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::engine::rules::Metadata;
+
+    fn rule(kind: &str, param: &str, target: &str) -> RuleConfig {
+        serde_yaml::from_str(&format!(
+            "{{ kind: {}, params: [\"{}\"], target: {} }}",
+            kind, param, target
+        ))
+        .unwrap()
+    }
+
+    fn target(context: &Context, host: &str) -> Option<String> {
+        let meta = Metadata {
+            inbound: "http",
+            user: None,
+            uid: None,
+            host,
+            dst_ip: None,
+            resolved_ip: None,
+            dst_port: 443,
+            src_ip: None,
+            src_port: None,
+            udp: false,
+            protocol: None,
+            sni: None,
+            tls_version: None,
+        };
+        let rules = context.rules();
+        let target = rules.matched(&meta).map(|m| m.target.to_owned());
+        target
+    }
+
+    #[test]
+    fn edits_rules_live() {
+        let config = "mode: rule\nlog-level: silent\ninbounds: []\nproxies: []\n\
+                      proxy-groups: []\nrules:\n\
+                      \x20 - { kind: DOMAIN-SUFFIX, params: [old.example], target: DIRECT }\n";
+        let context = Context::new(Config::load_from_str(config).unwrap()).unwrap();
+        let mut events = context.events().subscribe();
+        assert_eq!(
+            target(&context, "www.old.example").as_deref(),
+            Some("DIRECT")
+        );
+
+        // Replaced
+        let new = rule("DOMAIN-SUFFIX", "new.example", "REJECT");
+        context.edit_rules(|rules| *rules = vec![new]).unwrap();
+        assert_eq!(target(&context, "www.old.example"), None);
+        assert_eq!(
+            target(&context, "www.new.example").as_deref(),
+            Some("REJECT")
+        );
+
+        // Appended
+        let more = rule("DOMAIN", "more.example", "DIRECT");
+        context.edit_rules(|rules| rules.push(more)).unwrap();
+        assert_eq!(context.rule_configs().len(), 2);
+        assert_eq!(target(&context, "more.example").as_deref(), Some("DIRECT"));
+
+        for count in [1, 2] {
+            match futures::executor::block_on(events.next()) {
+                Some(Event::RulesReplaced { count: replaced }) => assert_eq!(replaced, count),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+
+        // Rejected lists leave the current rules in place
+        let unknown = rule("DOMAIN", "a.example", "nowhere");
+        assert!(context.edit_rules(|rules| rules.push(unknown)).is_err());
+        let invalid = rule("IP-CIDR", "not an ip", "DIRECT");
+        assert!(context.edit_rules(|rules| rules.push(invalid)).is_err());
+        assert_eq!(context.rule_configs().len(), 2);
+        assert_eq!(target(&context, "more.example").as_deref(), Some("DIRECT"));
+    }
+}
@@ -0,0 +1,863 @@
+//! Conversion of Clash and Surge configs into tache's
+//!
+//! Listeners, proxies, groups and rules are mapped to their tache
+//! counterparts, anything without one is left out with a warning. Groups of
+//! every kind become `smart` ones, the only kind built, which use the
+//! fastest healthy member like `url-test` and `fallback` do.
+
+use std::{fmt, str::FromStr};
+
+use serde_yaml::{Mapping, Value};
+
+use crate::{
+    config::{Config, ProxyConfig},
+    engine::rules,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// YAML of Clash and its forks
+    Clash,
+    /// Surge profile, an INI like `.conf`
+    Surge,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "clash" => Ok(Format::Clash),
+            "surge" => Ok(Format::Surge),
+            _ => Err(format!("unknown config format {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Format::Clash => f.write_str("clash"),
+            Format::Surge => f.write_str("surge"),
+        }
+    }
+}
+
+pub struct Converted {
+    /// The tache config, as YAML
+    pub yaml: String,
+    /// What was left out or changed on the way
+    pub warnings: Vec<String>,
+}
+
+/// Convert `text` written in `format`
+///
+/// Fails only when `text` can't be read at all, a converted config that
+/// doesn't load is reported among the warnings.
+pub fn convert(format: Format, text: &str) -> Result<Converted, String> {
+    let mut out = Output::default();
+    match format {
+        Format::Clash => clash(text, &mut out)?,
+        Format::Surge => surge(text, &mut out),
+    }
+    out.finish()
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_owned())
+}
+
+fn strings<'a, I: IntoIterator<Item = &'a str>>(items: I) -> Value {
+    Value::Sequence(items.into_iter().map(string).collect())
+}
+
+/// Mapping of the entries that aren't null
+fn map(entries: Vec<(&str, Value)>) -> Value {
+    let mut map = Mapping::new();
+    for (key, value) in entries {
+        if !value.is_null() {
+            map.insert(string(key), value);
+        }
+    }
+    Value::Mapping(map)
+}
+
+/// `host:port`, brackets around IPv6 addresses
+fn address(host: &str, port: &str) -> Value {
+    if host.contains(':') {
+        Value::String(format!("[{}]:{}", host, port))
+    } else {
+        Value::String(format!("{}:{}", host, port))
+    }
+}
+
+#[derive(Default)]
+struct Output {
+    mode: Option<String>,
+    log_level: Option<String>,
+    api: Option<Value>,
+    inbounds: Vec<Value>,
+    proxies: Vec<Value>,
+    groups: Vec<Value>,
+    rules: Vec<Value>,
+    warnings: Vec<String>,
+}
+
+impl Output {
+    fn warn<S: Into<String>>(&mut self, warning: S) {
+        self.warnings.push(warning.into());
+    }
+
+    fn inbound(&mut self, name: &str, kind: &str, listen: String, auth: &[String]) {
+        let auth = if auth.is_empty() || kind == "redir" {
+            Value::Null
+        } else {
+            strings(auth.iter().map(String::as_str))
+        };
+        self.inbounds.push(map(vec![
+            ("name", string(name)),
+            ("kind", string(kind)),
+            ("listen", Value::String(listen)),
+            ("authentication", auth),
+        ]));
+    }
+
+    /// Keep `proxy` if it makes a valid tache proxy
+    fn proxy(&mut self, name: &str, proxy: Value) {
+        match serde_yaml::from_value::<ProxyConfig>(proxy.clone()) {
+            Ok(_) => self.proxies.push(proxy),
+            Err(e) => self.warn(format!("proxy {} left out: {}", name, e)),
+        }
+    }
+
+    fn group(
+        &mut self,
+        name: &str,
+        kind: &str,
+        members: Vec<String>,
+        url: Option<&str>,
+        interval: Option<u64>,
+    ) {
+        match kind {
+            "url-test" | "fallback" => {}
+            "select" | "load-balance" => self.warn(format!(
+                "group {} is a {} group, converted to smart which picks the fastest member",
+                name, kind
+            )),
+            _ => {
+                self.warn(format!("group {} of kind {} left out", name, kind));
+                return;
+            }
+        }
+        if members.is_empty() {
+            self.warn(format!("group {} has no members, left out", name));
+            return;
+        }
+        self.groups.push(map(vec![
+            ("name", string(name)),
+            ("kind", string("smart")),
+            ("proxies", strings(members.iter().map(String::as_str))),
+            ("url", url.map_or(Value::Null, string)),
+            (
+                "interval",
+                interval.map_or(Value::Null, |i| Value::Number(i.into())),
+            ),
+        ]));
+    }
+
+    /// `KIND,param,target` with trailing flags, `MATCH,target` or
+    /// `FINAL,target`, as both Clash and Surge write them
+    fn rule(&mut self, line: &str) {
+        let parts: Vec<&str> = line.split(',').map(str::trim).collect();
+        let kind = parts[0].to_ascii_uppercase();
+        if !rules::KINDS.contains(&&kind[..]) {
+            self.warn(format!(
+                "rule {} left out, {} rules are not supported",
+                line, kind
+            ));
+            return;
+        }
+        let (params, target, flags) = match (&kind[..], parts.len()) {
+            ("MATCH", n) | ("FINAL", n) if n >= 2 => (vec![], parts[1], &parts[2..]),
+            (_, n) if n >= 3 => (vec![parts[1]], parts[2], &parts[3..]),
+            _ => {
+                self.warn(format!("rule {} left out, it has no target", line));
+                return;
+            }
+        };
+        let mut params = params;
+        for flag in flags {
+            if flag.eq_ignore_ascii_case("no-resolve") {
+                params.push("no-resolve");
+            } else {
+                self.warn(format!("rule {}: option {} dropped", line, flag));
+            }
+        }
+        self.rules.push(map(vec![
+            (
+                "kind",
+                string(if kind == "FINAL" { "MATCH" } else { &kind }),
+            ),
+            (
+                "params",
+                if params.is_empty() {
+                    Value::Null
+                } else {
+                    strings(params)
+                },
+            ),
+            ("target", string(target)),
+        ]));
+    }
+
+    fn finish(mut self) -> Result<Converted, String> {
+        let mut config = Mapping::new();
+        config.insert(
+            string("mode"),
+            string(self.mode.as_ref().map_or("rule", String::as_str)),
+        );
+        config.insert(
+            string("log-level"),
+            string(self.log_level.as_ref().map_or("info", String::as_str)),
+        );
+        if let Some(api) = self.api.take() {
+            config.insert(string("api"), api);
+        }
+        config.insert(string("inbounds"), Value::Sequence(self.inbounds));
+        config.insert(string("proxies"), Value::Sequence(self.proxies));
+        config.insert(string("proxy-groups"), Value::Sequence(self.groups));
+        config.insert(string("rules"), Value::Sequence(self.rules));
+        let yaml = serde_yaml::to_string(&Value::Mapping(config)).map_err(|e| e.to_string())?;
+        if let Err(e) = Config::load_from_str(&yaml) {
+            self.warnings
+                .push(format!("the converted config does not load: {:?}", e));
+        }
+        Ok(Converted {
+            yaml,
+            warnings: self.warnings,
+        })
+    }
+}
+
+fn clash(text: &str, out: &mut Output) -> Result<(), String> {
+    let config: Value = serde_yaml::from_str(text).map_err(|e| e.to_string())?;
+    let config = config
+        .as_mapping()
+        .ok_or_else(|| "a Clash config is a mapping".to_owned())?;
+    let get = |key: &str| config.get(&string(key));
+
+    let mut ignored = Vec::new();
+    for key in config.iter().filter_map(|(k, _)| k.as_str()) {
+        match key {
+            "port"
+            | "socks-port"
+            | "mixed-port"
+            | "redir-port"
+            | "allow-lan"
+            | "bind-address"
+            | "authentication"
+            | "mode"
+            | "log-level"
+            | "external-controller"
+            | "secret"
+            | "proxies"
+            | "proxy-groups"
+            | "rules" => {}
+            "tproxy-port" | "dns" | "tun" | "proxy-providers" | "rule-providers" | "hosts" => {
+                out.warn(format!("{} is not converted", key))
+            }
+            key => ignored.push(key),
+        }
+    }
+    if !ignored.is_empty() {
+        out.warn(format!("ignored settings: {}", ignored.join(", ")));
+    }
+
+    match get("mode")
+        .and_then(Value::as_str)
+        .map(str::to_ascii_lowercase)
+    {
+        Some(ref mode) if mode == "global" || mode == "direct" => out.mode = Some(mode.clone()),
+        Some(ref mode) if mode != "rule" => out.warn(format!("mode {} converted to rule", mode)),
+        _ => {}
+    }
+    if let Some(level) = get("log-level").and_then(Value::as_str) {
+        out.log_level = Some(level.to_owned());
+    }
+    if let Some(listen) = get("external-controller").and_then(Value::as_str) {
+        let listen = if listen.starts_with(':') {
+            format!("127.0.0.1{}", listen)
+        } else {
+            listen.to_owned()
+        };
+        out.api = Some(map(vec![
+            ("listen", Value::String(listen)),
+            ("secret", get("secret").cloned().unwrap_or(Value::Null)),
+        ]));
+    }
+
+    let lan = get("allow-lan").and_then(Value::as_bool).unwrap_or(false);
+    let bind = match get("bind-address").and_then(Value::as_str) {
+        _ if !lan => "127.0.0.1",
+        None | Some("*") => "0.0.0.0",
+        Some(bind) => bind,
+    };
+    let auth: Vec<String> = get("authentication")
+        .and_then(Value::as_sequence)
+        .map(|a| {
+            a.iter()
+                .filter_map(Value::as_str)
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default();
+    let port = |key: &str| get(key).and_then(Value::as_u64).filter(|&p| p != 0);
+    let listen = |port: u64| {
+        address(bind, &port.to_string())
+            .as_str()
+            .unwrap()
+            .to_owned()
+    };
+    if let Some(port) = port("port") {
+        out.inbound("http", "http", listen(port), &auth);
+    }
+    if let Some(port) = port("socks-port") {
+        out.inbound("socks", "socks5", listen(port), &auth);
+    }
+    if let Some(port) = port("mixed-port") {
+        out.warn(format!(
+            "mixed-port {} serves HTTP only, SOCKS5 needs socks-port",
+            port
+        ));
+        out.inbound("mixed", "http", listen(port), &auth);
+    }
+    if let Some(port) = port("redir-port") {
+        out.inbound("redir", "redir", listen(port), &auth);
+    }
+
+    for proxy in get("proxies")
+        .and_then(Value::as_sequence)
+        .into_iter()
+        .flatten()
+    {
+        clash_proxy(proxy, out);
+    }
+    for group in get("proxy-groups")
+        .and_then(Value::as_sequence)
+        .into_iter()
+        .flatten()
+    {
+        let field = |key: &str| group.get(key).and_then(Value::as_str);
+        let name = field("name").unwrap_or("");
+        if group.get("use").is_some() {
+            out.warn(format!(
+                "group {}: providers in use are not converted",
+                name
+            ));
+        }
+        let members = group
+            .get("proxies")
+            .and_then(Value::as_sequence)
+            .map(|p| {
+                p.iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let interval = group.get("interval").and_then(Value::as_u64);
+        out.group(
+            name,
+            field("type").unwrap_or(""),
+            members,
+            field("url"),
+            interval,
+        );
+    }
+    for rule in get("rules")
+        .and_then(Value::as_sequence)
+        .into_iter()
+        .flatten()
+    {
+        match rule.as_str() {
+            Some(rule) => out.rule(rule),
+            None => out.warn(format!("rule {:?} is not a string, left out", rule)),
+        }
+    }
+    Ok(())
+}
+
+/// Keys of Clash proxies that map to tache ones, by proxy type
+fn clash_keys(kind: &str) -> &'static [&'static str] {
+    match kind {
+        "ss" => &["cipher", "password", "udp", "plugin", "plugin-opts"],
+        "vmess" => &[
+            "uuid",
+            "alterId",
+            "cipher",
+            "tls",
+            "network",
+            "h2-opts",
+            "grpc-opts",
+        ],
+        "trojan" => &[
+            "password",
+            "sni",
+            "skip-cert-verify",
+            "network",
+            "h2-opts",
+            "grpc-opts",
+        ],
+        "socks5" | "http" => &["username", "password", "tls", "skip-cert-verify"],
+        _ => &[],
+    }
+}
+
+fn clash_proxy(proxy: &Value, out: &mut Output) {
+    let field = |key: &str| proxy.get(key).cloned().unwrap_or(Value::Null);
+    let text = |key: &str| proxy.get(key).and_then(Value::as_str);
+    let name = text("name").unwrap_or("").to_owned();
+    let kind = text("type").unwrap_or("");
+    let port = match proxy.get("port") {
+        Some(Value::Number(port)) => port.to_string(),
+        Some(Value::String(port)) => port.clone(),
+        _ => String::new(),
+    };
+
+    let known = clash_keys(kind);
+    if known.is_empty() {
+        out.warn(format!("proxy {} of type {} left out", name, kind));
+        return;
+    }
+    let ignored: Vec<&str> = proxy
+        .as_mapping()
+        .into_iter()
+        .flat_map(Mapping::iter)
+        .filter_map(|(k, _)| k.as_str())
+        .filter(|k| {
+            let common = [
+                "name",
+                "type",
+                "server",
+                "port",
+                "client-fingerprint",
+                "dialer-proxy",
+                "udp-over-tcp",
+                "ip-version",
+            ];
+            !known.contains(k) && !common.contains(k)
+        })
+        .collect();
+    if !ignored.is_empty() {
+        out.warn(format!("proxy {}: ignored {}", name, ignored.join(", ")));
+    }
+
+    let mut entries = vec![
+        ("name", string(&name)),
+        ("address", address(text("server").unwrap_or(""), &port)),
+    ];
+    match kind {
+        "ss" => {
+            let opts = |key: &str| proxy.get("plugin-opts").and_then(|o| o.get(key)).cloned();
+            match text("plugin") {
+                None => {}
+                Some("shadow-tls") if opts("version").and_then(|v| v.as_u64()) == Some(3) => {
+                    entries.push((
+                        "shadow-tls",
+                        map(vec![
+                            ("host", opts("host").unwrap_or(Value::Null)),
+                            ("password", opts("password").unwrap_or(Value::Null)),
+                        ]),
+                    ));
+                }
+                Some(plugin) => {
+                    out.warn(format!(
+                        "proxy {} left out, plugin {} is not supported",
+                        name, plugin
+                    ));
+                    return;
+                }
+            }
+            entries.push(("kind", string("shadowsocks")));
+            entries.push(("cipher", field("cipher")));
+            entries.push(("password", field("password")));
+            entries.push(("udp", Value::Bool(field("udp").as_bool().unwrap_or(false))));
+        }
+        "vmess" | "trojan" => {
+            if text("network") == Some("ws") {
+                out.warn(format!(
+                    "proxy {} left out, ws transport is not supported",
+                    name
+                ));
+                return;
+            }
+            entries.push(("kind", string(kind)));
+            if kind == "vmess" {
+                entries.push(("uuid", field("uuid")));
+                entries.push((
+                    "alterId",
+                    proxy
+                        .get("alterId")
+                        .cloned()
+                        .unwrap_or_else(|| Value::Number(0u64.into())),
+                ));
+                entries.push((
+                    "cipher",
+                    proxy
+                        .get("cipher")
+                        .cloned()
+                        .unwrap_or_else(|| string("auto")),
+                ));
+                entries.push(("tls", field("tls")));
+            } else {
+                entries.push(("password", field("password")));
+                entries.push(("sni", field("sni")));
+                entries.push(("skip_cert_verify", field("skip-cert-verify")));
+            }
+            entries.push(("network", field("network")));
+            entries.push(("h2-opts", field("h2-opts")));
+            entries.push(("grpc-opts", field("grpc-opts")));
+        }
+        _ => {
+            entries.push(("kind", string(kind)));
+            entries.push(("username", field("username")));
+            entries.push(("password", field("password")));
+            entries.push(("tls", field("tls")));
+            entries.push(("skip_cert_verify", field("skip-cert-verify")));
+        }
+    }
+    match text("client-fingerprint") {
+        None => {}
+        Some(fp @ "chrome") | Some(fp @ "firefox") | Some(fp @ "safari") | Some(fp @ "random") => {
+            entries.push(("client-fingerprint", string(fp)))
+        }
+        Some(fp) => out.warn(format!("proxy {}: client-fingerprint {} dropped", name, fp)),
+    }
+    let version = match text("ip-version") {
+        None | Some("dual") => None,
+        Some("ipv4") => Some("v4-only"),
+        Some("ipv6") => Some("v6-only"),
+        Some("ipv4-prefer") => Some("prefer-v4"),
+        Some("ipv6-prefer") => Some("prefer-v6"),
+        Some(other) => {
+            out.warn(format!("proxy {}: ip-version {} dropped", name, other));
+            None
+        }
+    };
+    if let Some(version) = version {
+        entries.push(("ip-version", string(version)));
+    }
+    entries.push(("dialer-proxy", field("dialer-proxy")));
+    entries.push(("udp-over-tcp", field("udp-over-tcp")));
+    out.proxy(&name, map(entries));
+}
+
+/// Sections of a Surge profile, `key = value` lines by section name
+fn surge_sections(text: &str) -> Vec<(String, Vec<(String, String)>)> {
+    let mut sections: Vec<(String, Vec<(String, String)>)> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with(';')
+            || line.starts_with("//")
+        {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            sections.push((line[1..line.len() - 1].trim().to_owned(), Vec::new()));
+            continue;
+        }
+        let section = match sections.last_mut() {
+            Some((_, lines)) => lines,
+            None => continue,
+        };
+        // Rules have no key, they are kept whole
+        match line.find('=') {
+            Some(pos) if !line[..pos].contains(',') => section.push((
+                line[..pos].trim().to_owned(),
+                line[pos + 1..].trim().to_owned(),
+            )),
+            _ => section.push((String::new(), line.to_owned())),
+        }
+    }
+    sections
+}
+
+/// Positional items and `key=value` options of a comma separated list
+fn surge_list(value: &str) -> (Vec<&str>, Vec<(&str, &str)>) {
+    let mut items = Vec::new();
+    let mut options = Vec::new();
+    for part in value.split(',').map(str::trim) {
+        match part.find('=') {
+            Some(pos) => options.push((part[..pos].trim(), part[pos + 1..].trim())),
+            None => items.push(part),
+        }
+    }
+    (items, options)
+}
+
+fn surge(text: &str, out: &mut Output) {
+    for (section, lines) in surge_sections(text) {
+        match &section[..] {
+            "General" => surge_general(&lines, out),
+            "Proxy" => {
+                for (name, value) in lines.iter() {
+                    surge_proxy(name, value, out);
+                }
+            }
+            "Proxy Group" => {
+                for (name, value) in lines.iter() {
+                    let (items, options) = surge_list(value);
+                    let option =
+                        |key: &str| options.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+                    for (key, _) in options.iter() {
+                        if *key != "url" && *key != "interval" {
+                            out.warn(format!("group {}: option {} dropped", name, key));
+                        }
+                    }
+                    let members = items.iter().skip(1).map(|m| m.to_string()).collect();
+                    let interval = option("interval").and_then(|i| i.parse().ok());
+                    out.group(name, items[0], members, option("url"), interval);
+                }
+            }
+            "Rule" => {
+                for (_, line) in lines.iter() {
+                    out.rule(line);
+                }
+            }
+            section => out.warn(format!("section [{}] is not converted", section)),
+        }
+    }
+}
+
+fn surge_general(lines: &[(String, String)], out: &mut Output) {
+    let mut ignored = Vec::new();
+    for (key, value) in lines {
+        match &key[..] {
+            "loglevel" => {
+                out.log_level = Some(
+                    match &value[..] {
+                        "verbose" => "debug",
+                        "warning" => "warning",
+                        _ => "info",
+                    }
+                    .to_owned(),
+                )
+            }
+            "http-listen" | "socks5-listen" => {
+                let listen = if value.contains(':') {
+                    value.clone()
+                } else {
+                    format!("127.0.0.1:{}", value)
+                };
+                if key == "http-listen" {
+                    out.inbound("http", "http", listen, &[]);
+                } else {
+                    out.inbound("socks", "socks5", listen, &[]);
+                }
+            }
+            _ => ignored.push(&key[..]),
+        }
+    }
+    if !ignored.is_empty() {
+        out.warn(format!("ignored settings: {}", ignored.join(", ")));
+    }
+}
+
+fn surge_proxy(name: &str, value: &str, out: &mut Output) {
+    let (items, options) = surge_list(value);
+    let kind = items[0];
+    if kind == "direct" || kind == "reject" {
+        out.warn(format!(
+            "proxy {} stands for {}, use that instead",
+            name,
+            kind.to_uppercase()
+        ));
+        return;
+    }
+    if items.len() < 3 {
+        out.warn(format!("proxy {} of type {} left out", name, kind));
+        return;
+    }
+    let option = |key: &str| options.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+    let flag = |key: &str| option(key).map_or(Value::Null, |v| Value::Bool(v == "true"));
+    let text = |key: &str| option(key).map_or(Value::Null, string);
+
+    let (kind, known): (&str, &[&str]) = match kind {
+        "ss" => (
+            "shadowsocks",
+            &[
+                "encrypt-method",
+                "password",
+                "udp-relay",
+                "shadow-tls-password",
+                "shadow-tls-sni",
+                "shadow-tls-version",
+            ],
+        ),
+        "vmess" => ("vmess", &["username", "tls", "skip-cert-verify"]),
+        "trojan" => ("trojan", &["password", "sni", "skip-cert-verify"]),
+        "http" | "https" => ("http", &["username", "password", "skip-cert-verify"]),
+        "socks5" | "socks5-tls" => ("socks5", &["username", "password", "skip-cert-verify"]),
+        kind => {
+            out.warn(format!("proxy {} of type {} left out", name, kind));
+            return;
+        }
+    };
+    let ignored: Vec<&str> = options
+        .iter()
+        .map(|(k, _)| *k)
+        .filter(|k| !known.contains(k))
+        .collect();
+    if !ignored.is_empty() {
+        out.warn(format!("proxy {}: ignored {}", name, ignored.join(", ")));
+    }
+
+    let mut entries = vec![
+        ("name", string(name)),
+        ("kind", string(kind)),
+        ("address", address(items[1], items[2])),
+    ];
+    match kind {
+        "shadowsocks" => {
+            if option("shadow-tls-password").is_some() {
+                if option("shadow-tls-version") != Some("3") {
+                    out.warn(format!(
+                        "proxy {} left out, only shadow-tls v3 is supported",
+                        name
+                    ));
+                    return;
+                }
+                entries.push((
+                    "shadow-tls",
+                    map(vec![
+                        ("host", text("shadow-tls-sni")),
+                        ("password", text("shadow-tls-password")),
+                    ]),
+                ));
+            }
+            entries.push(("cipher", text("encrypt-method")));
+            entries.push(("password", text("password")));
+            entries.push(("udp", Value::Bool(option("udp-relay") == Some("true"))));
+        }
+        "vmess" => {
+            entries.push(("uuid", text("username")));
+            entries.push(("alterId", Value::Number(0u64.into())));
+            entries.push(("cipher", string("auto")));
+            entries.push(("tls", flag("tls")));
+        }
+        "trojan" => {
+            entries.push(("password", text("password")));
+            entries.push(("sni", text("sni")));
+            entries.push(("skip_cert_verify", flag("skip-cert-verify")));
+        }
+        _ => {
+            // Credentials may also follow the port
+            let username = items.get(3).map_or_else(|| text("username"), |u| string(u));
+            let password = items.get(4).map_or_else(|| text("password"), |p| string(p));
+            let tls = items[0] == "https" || items[0] == "socks5-tls";
+            entries.push(("username", username));
+            entries.push(("password", password));
+            entries.push(("tls", if tls { Value::Bool(true) } else { Value::Null }));
+            entries.push(("skip_cert_verify", flag("skip-cert-verify")));
+        }
+    }
+    out.proxy(name, map(entries));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::IpVersion;
+
+    #[test]
+    fn converts_clash() {
+        let clash = "\
+mixed-port: 7890
+allow-lan: false
+mode: Rule
+log-level: info
+external-controller: :9090
+proxies:
+  - { name: ss1, type: ss, server: 1.2.3.4, port: 8388, cipher: aes-256-gcm, password: pw, udp: true, ip-version: ipv4-prefer }
+  - { name: tj, type: trojan, server: t.example.com, port: 443, password: pw, sni: t.example.com, client-fingerprint: chrome }
+  - { name: v, type: vless, server: v.example.com, port: 443, uuid: x }
+proxy-groups:
+  - { name: auto, type: url-test, proxies: [ss1, tj], url: 'http://www.gstatic.com/generate_204', interval: 300 }
+  - { name: pick, type: select, proxies: [auto, DIRECT] }
+rules:
+  - DOMAIN-SUFFIX,google.com,auto
+  - IP-CIDR,10.0.0.0/8,DIRECT,no-resolve
+  - PROCESS-NAME,curl,DIRECT
+  - MATCH,pick
+";
+        let converted = convert(Format::Clash, clash).unwrap();
+        let config = Config::load_from_str(&converted.yaml).unwrap();
+        assert_eq!(config.inbounds.len(), 1);
+        assert_eq!(config.inbounds[0].listen()[0].to_string(), "127.0.0.1:7890");
+        assert_eq!(config.api.unwrap().listen.to_string(), "127.0.0.1:9090");
+        let names: Vec<_> = config.proxies.iter().map(ProxyConfig::name).collect();
+        assert_eq!(names, ["ss1", "tj"]);
+        assert_eq!(
+            config.proxies[0].options().ip_version,
+            Some(IpVersion::PreferV4)
+        );
+        assert_eq!(config.proxy_groups.len(), 2);
+        assert!(config.proxy_groups.iter().all(|g| g.kind == "smart"));
+        assert_eq!(config.rules.len(), 3);
+        assert_eq!(
+            config.rules[1].params,
+            Some(vec!["10.0.0.0/8".to_owned(), "no-resolve".to_owned()])
+        );
+        let warned = |s: &str| converted.warnings.iter().any(|w| w.contains(s));
+        assert!(warned("vless"));
+        assert!(warned("PROCESS-NAME"));
+        assert!(warned("select"));
+        assert!(warned("mixed-port"));
+    }
+
+    #[test]
+    fn converts_surge() {
+        let surge = "\
+[General]
+loglevel = notify
+http-listen = 0.0.0.0:6152
+dns-server = system
+
+[Proxy]
+On = direct
+ss1 = ss, 1.2.3.4, 8388, encrypt-method=chacha20-ietf-poly1305, password=pw, udp-relay=true
+web = https, proxy.example.com, 443, user, pass
+wg = wireguard, section-name=x
+
+[Proxy Group]
+auto = url-test, ss1, web, url=http://www.gstatic.com/generate_204, interval=600
+
+[Rule]
+DOMAIN-KEYWORD,google,auto
+GEOIP,CN,DIRECT
+FINAL,auto,dns-failed
+";
+        let converted = convert(Format::Surge, surge).unwrap();
+        let config = Config::load_from_str(&converted.yaml).unwrap();
+        assert_eq!(config.inbounds[0].listen()[0].to_string(), "0.0.0.0:6152");
+        let names: Vec<_> = config.proxies.iter().map(ProxyConfig::name).collect();
+        assert_eq!(names, ["ss1", "web"]);
+        match config.proxies[1] {
+            ProxyConfig::HTTP {
+                ref username, tls, ..
+            } => {
+                assert_eq!(username.as_ref().map(String::as_str), Some("user"));
+                assert_eq!(tls, Some(true));
+            }
+            ref other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(config.proxy_groups[0].interval, Some(600));
+        assert_eq!(config.rules.len(), 3);
+        assert_eq!(config.rules[2].kind, "MATCH");
+        let warned = |s: &str| converted.warnings.iter().any(|w| w.contains(s));
+        assert!(warned("dns-server"));
+        assert!(warned("wireguard"));
+        assert!(warned("dns-failed"));
+    }
+}
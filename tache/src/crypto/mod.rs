@@ -0,0 +1,284 @@
+//! AEAD ciphers of Shadowsocks and VMess
+//!
+//! The implementation comes from a backend picked with cargo features, the
+//! first enabled of `openssl-crypto`, `rust-crypto` and `ring-crypto`, ring
+//! by default. Other primitives, like the HMACs and randomness of the
+//! protocols, don't depend on the backend.
+
+use std::io;
+
+use hmac::Hmac;
+use sha1::Sha1;
+
+use crate::config::CipherPreference;
+
+#[cfg(feature = "openssl-crypto")]
+mod openssl_backend;
+#[cfg(feature = "openssl-crypto")]
+use self::openssl_backend as backend;
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl-crypto")))]
+mod rustcrypto_backend;
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl-crypto")))]
+use self::rustcrypto_backend as backend;
+
+#[cfg(all(
+    feature = "ring-crypto",
+    not(any(feature = "openssl-crypto", feature = "rust-crypto"))
+))]
+mod ring_backend;
+#[cfg(all(
+    feature = "ring-crypto",
+    not(any(feature = "openssl-crypto", feature = "rust-crypto"))
+))]
+use self::ring_backend as backend;
+
+#[cfg(not(any(
+    feature = "openssl-crypto",
+    feature = "rust-crypto",
+    feature = "ring-crypto"
+)))]
+compile_error!("enable one of the openssl-crypto, rust-crypto or ring-crypto features");
+
+/// Name of the backend built in, for logs
+pub const BACKEND: &str = backend::NAME;
+
+/// HMAC-SHA1 of legacy protocols, the same whichever backend is built in
+pub type HmacSha1 = Hmac<Sha1>;
+
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherKind {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl CipherKind {
+    /// Shadowsocks names, also as go-shadowsocks2 spells them, and the
+    /// shorter one VMess uses for ChaCha20
+    pub fn from_name(name: &str) -> Option<CipherKind> {
+        match name {
+            "aes-128-gcm" | "AEAD_AES_128_GCM" => Some(CipherKind::Aes128Gcm),
+            "aes-256-gcm" | "AEAD_AES_256_GCM" => Some(CipherKind::Aes256Gcm),
+            "chacha20-ietf-poly1305" | "chacha20-poly1305" | "AEAD_CHACHA20_POLY1305" => {
+                Some(CipherKind::ChaCha20Poly1305)
+            }
+            _ => None,
+        }
+    }
+
+    /// Like `from_name`, with `auto` standing for `preferred`
+    pub fn select(name: &str, preferred: CipherKind) -> Option<CipherKind> {
+        match name {
+            "auto" => Some(preferred),
+            _ => CipherKind::from_name(name),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherKind::Aes128Gcm => "aes-128-gcm",
+            CipherKind::Aes256Gcm => "aes-256-gcm",
+            CipherKind::ChaCha20Poly1305 => "chacha20-ietf-poly1305",
+        }
+    }
+
+    pub fn key_len(self) -> usize {
+        match self {
+            CipherKind::Aes128Gcm => 16,
+            CipherKind::Aes256Gcm | CipherKind::ChaCha20Poly1305 => 32,
+        }
+    }
+}
+
+/// Cipher `auto` stands for
+pub fn preferred(preference: CipherPreference) -> CipherKind {
+    match preference {
+        CipherPreference::Auto if hardware_aes() => CipherKind::Aes128Gcm,
+        CipherPreference::Auto => CipherKind::ChaCha20Poly1305,
+        CipherPreference::AesGcm => CipherKind::Aes128Gcm,
+        CipherPreference::Chacha20Poly1305 => CipherKind::ChaCha20Poly1305,
+    }
+}
+
+/// Whether the CPU has AES and carry-less multiply instructions, without them
+/// AES-GCM is several times slower than ChaCha20-Poly1305
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn hardware_aes() -> bool {
+    is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq")
+}
+
+/// Whether the CPU has the ARMv8 crypto extension, from the ELF auxiliary vector
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    target_arch = "aarch64"
+))]
+pub fn hardware_aes() -> bool {
+    const HWCAP_AES: libc::c_ulong = 1 << 3;
+    const HWCAP_PMULL: libc::c_ulong = 1 << 4;
+    let hwcap = unsafe { libc::getauxval(libc::AT_HWCAP) };
+    hwcap & HWCAP_AES != 0 && hwcap & HWCAP_PMULL != 0
+}
+
+/// Whether the CPU has the ARMv8 crypto extension, 32-bit ARM reports it in
+/// the second auxiliary vector word
+#[cfg(all(any(target_os = "linux", target_os = "android"), target_arch = "arm"))]
+pub fn hardware_aes() -> bool {
+    const AT_HWCAP2: libc::c_ulong = 26;
+    const HWCAP2_AES: libc::c_ulong = 1 << 0;
+    const HWCAP2_PMULL: libc::c_ulong = 1 << 1;
+    let hwcap = unsafe { libc::getauxval(AT_HWCAP2) };
+    hwcap & HWCAP2_AES != 0 && hwcap & HWCAP2_PMULL != 0
+}
+
+/// Apple's ARM chips all have the crypto extension
+#[cfg(all(target_os = "ios", target_arch = "aarch64"))]
+pub fn hardware_aes() -> bool {
+    true
+}
+
+#[cfg(not(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    all(
+        any(target_os = "linux", target_os = "android"),
+        any(target_arch = "aarch64", target_arch = "arm")
+    ),
+    all(target_os = "ios", target_arch = "aarch64")
+)))]
+pub fn hardware_aes() -> bool {
+    false
+}
+
+/// A cipher with its key, sealing and opening whole messages in place
+pub struct Cipher {
+    kind: CipherKind,
+    key: backend::Key,
+}
+
+impl Cipher {
+    pub fn new(kind: CipherKind, key: &[u8]) -> io::Result<Cipher> {
+        if key.len() != kind.key_len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} takes a {} byte key", kind.name(), kind.key_len()),
+            ));
+        }
+        Ok(Cipher {
+            kind,
+            key: backend::Key::new(kind, key)?,
+        })
+    }
+
+    pub fn kind(&self) -> CipherKind {
+        self.kind
+    }
+
+    /// Encrypt `data` and append its tag
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], data: &mut Vec<u8>) -> io::Result<()> {
+        self.seal_with(nonce, &[], data)
+    }
+
+    /// Like `seal`, the tag also covers `aad`
+    pub fn seal_with(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        data: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        self.key.seal(nonce, aad, data)
+    }
+
+    /// Check the tag at the end of `data`, decrypt and drop the tag
+    pub fn open(&self, nonce: &[u8; NONCE_LEN], data: &mut Vec<u8>) -> io::Result<()> {
+        self.open_with(nonce, &[], data)
+    }
+
+    /// Like `open`, the tag also covers `aad`
+    pub fn open_with(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        data: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        if data.len() < TAG_LEN {
+            return Err(invalid_tag());
+        }
+        self.key.open(nonce, aad, data)?;
+        data.truncate(data.len() - TAG_LEN);
+        Ok(())
+    }
+}
+
+fn invalid_tag() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "message authentication failed")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seals_and_opens() {
+        // Test case 1 of the GCM specification, only a tag
+        let cipher = Cipher::new(CipherKind::Aes128Gcm, &[0; 16]).unwrap();
+        let mut data = Vec::new();
+        cipher.seal(&[0; NONCE_LEN], &mut data).unwrap();
+        assert_eq!(
+            data,
+            [
+                0x58, 0xe2, 0xfc, 0xce, 0xfa, 0x7e, 0x30, 0x61, 0x36, 0x7f, 0x1d, 0x57, 0xa4, 0xe7,
+                0x45, 0x5a
+            ]
+        );
+
+        for &kind in &[CipherKind::Aes256Gcm, CipherKind::ChaCha20Poly1305] {
+            let cipher = Cipher::new(kind, &[7; 32]).unwrap();
+            let nonce = [1; NONCE_LEN];
+            let mut data = b"hello".to_vec();
+            cipher.seal(&nonce, &mut data).unwrap();
+            assert_eq!(data.len(), 5 + TAG_LEN);
+            let mut tampered = data.clone();
+            tampered[0] ^= 1;
+            assert!(cipher.open(&nonce, &mut tampered).is_err());
+            cipher.open(&nonce, &mut data).unwrap();
+            assert_eq!(data, b"hello");
+        }
+        assert!(Cipher::new(CipherKind::Aes256Gcm, &[0; 16]).is_err());
+
+        // The tag covers the associated data
+        let cipher = Cipher::new(CipherKind::Aes128Gcm, &[3; 16]).unwrap();
+        let mut data = b"header".to_vec();
+        cipher
+            .seal_with(&[2; NONCE_LEN], b"auth id", &mut data)
+            .unwrap();
+        let mut other = data.clone();
+        assert!(cipher
+            .open_with(&[2; NONCE_LEN], b"auth ix", &mut other)
+            .is_err());
+        assert!(cipher.open(&[2; NONCE_LEN], &mut other).is_err());
+        cipher
+            .open_with(&[2; NONCE_LEN], b"auth id", &mut data)
+            .unwrap();
+        assert_eq!(data, b"header");
+    }
+
+    #[test]
+    fn selects_preferred_for_auto() {
+        let aes = preferred(CipherPreference::AesGcm);
+        assert_eq!(aes, CipherKind::Aes128Gcm);
+        assert_eq!(CipherKind::select("auto", aes), Some(aes));
+        assert_eq!(
+            CipherKind::select("chacha20-poly1305", aes),
+            Some(CipherKind::ChaCha20Poly1305)
+        );
+        assert_eq!(CipherKind::select("rc4-md5", aes), None);
+        assert_eq!(
+            preferred(CipherPreference::Auto) == CipherKind::Aes128Gcm,
+            hardware_aes()
+        );
+    }
+}
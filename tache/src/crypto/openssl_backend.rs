@@ -0,0 +1,45 @@
+use std::io;
+
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+use super::{invalid_tag, CipherKind, NONCE_LEN, TAG_LEN};
+
+pub const NAME: &str = "openssl";
+
+pub struct Key {
+    cipher: Cipher,
+    key: Vec<u8>,
+}
+
+impl Key {
+    pub fn new(kind: CipherKind, key: &[u8]) -> io::Result<Key> {
+        let cipher = match kind {
+            CipherKind::Aes128Gcm => Cipher::aes_128_gcm(),
+            CipherKind::Aes256Gcm => Cipher::aes_256_gcm(),
+            CipherKind::ChaCha20Poly1305 => Cipher::chacha20_poly1305(),
+        };
+        Ok(Key {
+            cipher,
+            key: key.to_vec(),
+        })
+    }
+
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], data: &mut Vec<u8>) -> io::Result<()> {
+        let mut tag = [0; TAG_LEN];
+        let sealed = encrypt_aead(self.cipher, &self.key, Some(nonce), aad, data, &mut tag)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        *data = sealed;
+        data.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Leaves the tag in place
+    pub fn open(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], data: &mut Vec<u8>) -> io::Result<()> {
+        let (message, tag) = data.split_at(data.len() - TAG_LEN);
+        let mut opened = decrypt_aead(self.cipher, &self.key, Some(nonce), aad, message, tag)
+            .map_err(|_| invalid_tag())?;
+        opened.extend_from_slice(tag);
+        *data = opened;
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+use std::io;
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+
+use super::{invalid_tag, CipherKind, NONCE_LEN};
+
+pub const NAME: &str = "ring";
+
+pub struct Key(LessSafeKey);
+
+impl Key {
+    pub fn new(kind: CipherKind, key: &[u8]) -> io::Result<Key> {
+        let algorithm = match kind {
+            CipherKind::Aes128Gcm => &aead::AES_128_GCM,
+            CipherKind::Aes256Gcm => &aead::AES_256_GCM,
+            CipherKind::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        };
+        let key = UnboundKey::new(algorithm, key)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))?;
+        Ok(Key(LessSafeKey::new(key)))
+    }
+
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], data: &mut Vec<u8>) -> io::Result<()> {
+        self.0
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(*nonce), Aad::from(aad), data)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))
+    }
+
+    /// Leaves the tag in place
+    #[allow(clippy::ptr_arg)] // Same as the other backends, openssl replaces `data`
+    pub fn open(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], data: &mut Vec<u8>) -> io::Result<()> {
+        self.0
+            .open_in_place(Nonce::assume_unique_for_key(*nonce), Aad::from(aad), data)
+            .map(|_| ())
+            .map_err(|_| invalid_tag())
+    }
+}
@@ -0,0 +1,82 @@
+use std::io;
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, AeadInPlace, NewAead},
+    Aes128Gcm, Aes256Gcm,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use super::{invalid_tag, CipherKind, NONCE_LEN, TAG_LEN};
+
+pub const NAME: &str = "rust-crypto";
+
+/// The AES ciphers hold their expanded round keys
+pub enum Key {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl Key {
+    pub fn new(kind: CipherKind, key: &[u8]) -> io::Result<Key> {
+        Ok(match kind {
+            CipherKind::Aes128Gcm => {
+                Key::Aes128Gcm(Box::new(Aes128Gcm::new(GenericArray::from_slice(key))))
+            }
+            CipherKind::Aes256Gcm => {
+                Key::Aes256Gcm(Box::new(Aes256Gcm::new(GenericArray::from_slice(key))))
+            }
+            CipherKind::ChaCha20Poly1305 => {
+                Key::ChaCha20Poly1305(ChaCha20Poly1305::new(GenericArray::from_slice(key)))
+            }
+        })
+    }
+
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], data: &mut Vec<u8>) -> io::Result<()> {
+        match *self {
+            Key::Aes128Gcm(ref c) => seal(&**c, nonce, aad, data),
+            Key::Aes256Gcm(ref c) => seal(&**c, nonce, aad, data),
+            Key::ChaCha20Poly1305(ref c) => seal(c, nonce, aad, data),
+        }
+    }
+
+    /// Leaves the tag in place
+    #[allow(clippy::ptr_arg)] // Same as the other backends, openssl replaces `data`
+    pub fn open(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], data: &mut Vec<u8>) -> io::Result<()> {
+        match *self {
+            Key::Aes128Gcm(ref c) => open(&**c, nonce, aad, data),
+            Key::Aes256Gcm(ref c) => open(&**c, nonce, aad, data),
+            Key::ChaCha20Poly1305(ref c) => open(c, nonce, aad, data),
+        }
+    }
+}
+
+fn seal<A: AeadInPlace>(
+    cipher: &A,
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    data: &mut Vec<u8>,
+) -> io::Result<()> {
+    let tag = cipher
+        .encrypt_in_place_detached(GenericArray::from_slice(nonce), aad, data)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+    data.extend_from_slice(&tag);
+    Ok(())
+}
+
+fn open<A: AeadInPlace>(
+    cipher: &A,
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    data: &mut [u8],
+) -> io::Result<()> {
+    let (message, tag) = data.split_at_mut(data.len() - TAG_LEN);
+    cipher
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(nonce),
+            aad,
+            message,
+            GenericArray::from_slice(tag),
+        )
+        .map_err(|_| invalid_tag())
+}
@@ -0,0 +1,148 @@
+//! EDNS Client Subnet (RFC 7871) handling for upstream queries
+
+use std::{io, net::IpAddr};
+
+use trust_dns_proto::{
+    op::{Edns, Message},
+    rr::rdata::opt::{EdnsCode, EdnsOption},
+};
+
+use crate::ip_trie::parse_cidr;
+
+/// What to do with the client subnet option of forwarded queries
+#[derive(Clone, Debug)]
+pub enum EcsPolicy {
+    /// Replace any client supplied subnet with this one
+    Override(IpAddr, u8),
+    /// Remove the option so upstreams only see the resolver address
+    Strip,
+}
+
+impl EcsPolicy {
+    /// Parse `strip` or a subnet like `1.2.3.0/24`
+    pub fn parse(s: &str) -> Option<EcsPolicy> {
+        if s.eq_ignore_ascii_case("strip") {
+            return Some(EcsPolicy::Strip);
+        }
+        parse_cidr(s).map(|(ip, len)| EcsPolicy::Override(ip, len))
+    }
+
+    /// Rewrite a wire format query according to the policy
+    pub fn apply(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut msg = Message::from_vec(query)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        let mut edns = Edns::new();
+        if let Some(old) = msg.edns() {
+            edns.set_max_payload(old.max_payload());
+            edns.set_version(old.version());
+            edns.set_dnssec_ok(old.dnssec_ok());
+            for (code, option) in old.options().options() {
+                if *code != EdnsCode::Subnet {
+                    edns.set_option(option.clone());
+                }
+            }
+        } else if let EcsPolicy::Strip = *self {
+            // Nothing to strip
+            return Ok(query.to_vec());
+        } else {
+            edns.set_max_payload(1232);
+        }
+        if let EcsPolicy::Override(ip, len) = *self {
+            edns.set_option(EdnsOption::Unknown(
+                u16::from(EdnsCode::Subnet),
+                encode_subnet(ip, len),
+            ));
+        }
+        msg.set_edns(edns);
+        msg.to_vec()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+/// FAMILY, SOURCE PREFIX-LENGTH, SCOPE PREFIX-LENGTH and the truncated address
+fn encode_subnet(ip: IpAddr, len: u8) -> Vec<u8> {
+    let (family, octets) = match ip {
+        IpAddr::V4(ip) => (1u16, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2u16, ip.octets().to_vec()),
+    };
+    let bytes = (len as usize).div_ceil(8);
+    let mut data = Vec::with_capacity(4 + bytes);
+    data.push((family >> 8) as u8);
+    data.push(family as u8);
+    data.push(len);
+    data.push(0);
+    data.extend_from_slice(&octets[..bytes]);
+    // Bits past the prefix must be zero
+    if !len.is_multiple_of(8) {
+        if let Some(last) = data.last_mut() {
+            *last &= 0xffu8 << (8 - len % 8);
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use trust_dns_proto::{
+        op::Query,
+        rr::{Name, RecordType},
+    };
+
+    use super::*;
+
+    fn query(subnet: Option<(IpAddr, u8)>) -> Vec<u8> {
+        let mut msg = Message::new();
+        msg.add_query(Query::query(
+            Name::from_ascii("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        if let Some((ip, len)) = subnet {
+            let mut edns = Edns::new();
+            edns.set_max_payload(4096);
+            edns.set_option(EdnsOption::Unknown(
+                u16::from(EdnsCode::Subnet),
+                encode_subnet(ip, len),
+            ));
+            msg.set_edns(edns);
+        }
+        msg.to_vec().unwrap()
+    }
+
+    fn subnet_of(wire: &[u8]) -> Option<Vec<u8>> {
+        let msg = Message::from_vec(wire).unwrap();
+        msg.edns()
+            .and_then(|edns| edns.option(EdnsCode::Subnet))
+            .map(Vec::<u8>::from)
+    }
+
+    #[test]
+    fn overrides_the_client_subnet() {
+        let policy = EcsPolicy::parse("1.2.3.0/20").unwrap();
+        let rewritten = policy.apply(&query(None)).unwrap();
+        assert_eq!(subnet_of(&rewritten).unwrap(), vec![0, 1, 20, 0, 1, 2, 0]);
+
+        let client = Some(("2001:db8::1".parse().unwrap(), 56));
+        let rewritten = policy.apply(&query(client)).unwrap();
+        assert_eq!(subnet_of(&rewritten).unwrap(), vec![0, 1, 20, 0, 1, 2, 0]);
+        let msg = Message::from_vec(&rewritten).unwrap();
+        assert_eq!(msg.edns().unwrap().max_payload(), 4096);
+    }
+
+    #[test]
+    fn strips_the_client_subnet() {
+        let policy = EcsPolicy::parse("STRIP").unwrap();
+        let plain = query(None);
+        assert_eq!(policy.apply(&plain).unwrap(), plain);
+
+        let client = Some(("10.1.2.3".parse().unwrap(), 24));
+        assert_eq!(
+            subnet_of(&query(client)).unwrap(),
+            vec![0, 1, 24, 0, 10, 1, 2]
+        );
+        let stripped = policy.apply(&query(client)).unwrap();
+        assert!(Message::from_vec(&stripped).unwrap().edns().is_some());
+        assert_eq!(subnet_of(&stripped), None);
+        assert!(EcsPolicy::parse("not a subnet").is_none());
+    }
+}
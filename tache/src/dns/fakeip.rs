@@ -0,0 +1,226 @@
+//! Made up addresses of the `fake-ip` DNS mode
+//!
+//! Every queried domain gets an address from a reserved range, connections
+//! to it are mapped back to the domain so rules and outbounds see the name.
+//! Addresses are handed out in turn and reused once the range wraps around.
+//! On reloads the new pools take over the addresses of the old ones they
+//! cover, so answers clients still cache keep mapping to their domains.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+};
+
+use crate::ip_trie::parse_cidr;
+
+/// Benchmarking range of RFC 2544, never routed on the internet
+pub const DEFAULT_RANGE: &str = "198.18.0.0/16";
+
+/// Domains remembered per family, IPv6 ranges are far larger than needed
+const MAX_ENTRIES: u128 = 65536;
+
+/// Offsets skipped at the start of a range, the network address and the
+/// address of the TUN device
+const RESERVED: u128 = 2;
+
+struct Pool {
+    base: u128,
+    v6: bool,
+    capacity: u128,
+    /// Offset handed out next, from 0 to `capacity`
+    next: u128,
+    hosts: HashMap<u128, String>,
+    offsets: HashMap<String, u128>,
+}
+
+impl Pool {
+    fn new(cidr: &str) -> Result<Pool, String> {
+        let (ip, prefix) = parse_cidr(cidr).ok_or_else(|| format!("invalid range {}", cidr))?;
+        let (base, bits, v6) = match ip {
+            IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32, false),
+            IpAddr::V6(ip) => (u128::from(ip), 128, true),
+        };
+        let size = 1u128
+            .checked_shl(bits - u32::from(prefix))
+            .unwrap_or(u128::MAX);
+        // And the broadcast address of IPv4
+        let reserved = if v6 { RESERVED } else { RESERVED + 1 };
+        if size <= reserved {
+            return Err(format!("range {} is too small", cidr));
+        }
+        let mask = !(size - 1);
+        Ok(Pool {
+            base: (base & mask) + RESERVED,
+            v6,
+            capacity: (size - reserved).min(MAX_ENTRIES),
+            next: 0,
+            hosts: HashMap::new(),
+            offsets: HashMap::new(),
+        })
+    }
+
+    fn ip(&self, offset: u128) -> IpAddr {
+        let value = self.base + offset;
+        if self.v6 {
+            IpAddr::V6(Ipv6Addr::from(value))
+        } else {
+            IpAddr::V4(Ipv4Addr::from(value as u32))
+        }
+    }
+
+    fn offset(&self, ip: IpAddr) -> Option<u128> {
+        let value = match ip {
+            IpAddr::V4(ip) if !self.v6 => u128::from(u32::from(ip)),
+            IpAddr::V6(ip) if self.v6 => u128::from(ip),
+            _ => return None,
+        };
+        value
+            .checked_sub(self.base)
+            .filter(|offset| *offset < self.capacity)
+    }
+
+    fn allocate(&mut self, host: &str) -> IpAddr {
+        if let Some(&offset) = self.offsets.get(host) {
+            return self.ip(offset);
+        }
+        let offset = self.next;
+        self.next = (self.next + 1) % self.capacity;
+        if let Some(previous) = self.hosts.insert(offset, host.to_owned()) {
+            self.offsets.remove(&previous);
+        }
+        self.offsets.insert(host.to_owned(), offset);
+        self.ip(offset)
+    }
+
+    /// Take over the addresses of `old` inside this range, returns how many
+    fn restore(&mut self, old: &Pool) -> usize {
+        let mut restored = 0;
+        for (&offset, host) in old.hosts.iter() {
+            let offset = match self.offset(old.ip(offset)) {
+                Some(offset) => offset,
+                None => continue,
+            };
+            if let Some(previous) = self.hosts.insert(offset, host.clone()) {
+                self.offsets.remove(&previous);
+            }
+            self.offsets.insert(host.clone(), offset);
+            restored += 1;
+        }
+        if old.base == self.base && old.capacity == self.capacity {
+            self.next = old.next;
+        } else if let Some(last) = self.hosts.keys().max() {
+            self.next = (last + 1) % self.capacity;
+        }
+        restored
+    }
+}
+
+pub struct FakeIp {
+    v4: Mutex<Pool>,
+    v6: Option<Mutex<Pool>>,
+}
+
+impl FakeIp {
+    /// Pools of `range`, and of `range6` for AAAA queries when given
+    pub fn new(range: Option<&str>, range6: Option<&str>) -> Result<FakeIp, String> {
+        let v4 = Pool::new(range.unwrap_or(DEFAULT_RANGE))?;
+        if v4.v6 {
+            return Err("fake-ip-range has to be IPv4".to_owned());
+        }
+        let v6 = match range6 {
+            Some(range6) => {
+                let pool = Pool::new(range6)?;
+                // Unique local addresses, fc00::/7
+                match pool.ip(0) {
+                    IpAddr::V6(ip) if ip.segments()[0] & 0xfe00 == 0xfc00 => {}
+                    _ => return Err("fake-ip6-range has to be an IPv6 ULA prefix".to_owned()),
+                }
+                Some(Mutex::new(pool))
+            }
+            None => None,
+        };
+        Ok(FakeIp {
+            v4: Mutex::new(v4),
+            v6,
+        })
+    }
+
+    /// Address standing for `host`, IPv6 only with `fake-ip6-range`
+    pub fn allocate(&self, host: &str, v6: bool) -> Option<IpAddr> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let pool = if v6 { self.v6.as_ref()? } else { &self.v4 };
+        Some(pool.lock().unwrap().allocate(&host))
+    }
+
+    /// Domain `ip` was handed out for
+    pub fn host(&self, ip: IpAddr) -> Option<String> {
+        let pool = match ip {
+            IpAddr::V4(..) => &self.v4,
+            IpAddr::V6(..) => self.v6.as_ref()?,
+        };
+        let pool = pool.lock().unwrap();
+        let offset = pool.offset(ip)?;
+        pool.hosts.get(&offset).cloned()
+    }
+
+    /// Take over the addresses `old` handed out that the ranges of this one
+    /// cover, returns how many
+    pub fn restore(&self, old: &FakeIp) -> usize {
+        let mut restored = self.v4.lock().unwrap().restore(&old.v4.lock().unwrap());
+        if let (Some(v6), Some(old)) = (self.v6.as_ref(), old.v6.as_ref()) {
+            restored += v6.lock().unwrap().restore(&old.lock().unwrap());
+        }
+        restored
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allocates_and_maps_back() {
+        let fake = FakeIp::new(Some("198.18.0.0/30"), Some("fd00:7461:6368::/120")).unwrap();
+        let a = fake.allocate("a.example.com.", false).unwrap();
+        assert_eq!(a, "198.18.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(fake.allocate("A.example.com", false), Some(a));
+        assert_eq!(fake.host(a).as_deref(), Some("a.example.com"));
+
+        // A /30 holds one address, the next domain takes it over
+        assert_eq!(fake.allocate("b.example.com", false), Some(a));
+        assert_eq!(fake.host(a).as_deref(), Some("b.example.com"));
+
+        let a6 = fake.allocate("a.example.com", true).unwrap();
+        assert_eq!(a6, "fd00:7461:6368::2".parse::<IpAddr>().unwrap());
+        assert_eq!(fake.host(a6).as_deref(), Some("a.example.com"));
+        assert!(fake.host("198.18.1.2".parse().unwrap()).is_none());
+
+        assert!(FakeIp::new(None, Some("2001:db8::/64")).is_err());
+        assert!(FakeIp::new(Some("fd00::/64"), None).is_err());
+        assert!(FakeIp::new(None, None)
+            .unwrap()
+            .allocate("a", true)
+            .is_none());
+    }
+
+    #[test]
+    fn restores_covered_addresses() {
+        let old = FakeIp::new(Some("198.18.0.0/24"), Some("fd00:7461:6368::/120")).unwrap();
+        let a = old.allocate("a.example.com", false).unwrap();
+        let a6 = old.allocate("a.example.com", true).unwrap();
+        let b = old.allocate("b.example.com", false).unwrap();
+
+        // Same v4 range, a smaller one without b and no v6 range
+        let new = FakeIp::new(Some("198.18.0.0/24"), Some("fd00:7461:6368::/120")).unwrap();
+        assert_eq!(new.restore(&old), 3);
+        assert_eq!(new.host(a6).as_deref(), Some("a.example.com"));
+        assert_ne!(new.allocate("c.example.com", false), Some(a));
+        assert_eq!(new.allocate("b.example.com", false), Some(b));
+
+        let smaller = FakeIp::new(Some("198.18.0.0/30"), None).unwrap();
+        assert_eq!(smaller.restore(&old), 1);
+        assert_eq!(smaller.host(a).as_deref(), Some("a.example.com"));
+        assert!(smaller.host(b).is_none());
+    }
+}
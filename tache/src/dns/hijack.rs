@@ -0,0 +1,81 @@
+//! DNS servers whose queries the built-in resolver answers instead
+//!
+//! Entries are `ip:port` or `any:port`, optionally behind `udp://` or
+//! `tcp://` like Clash writes them; both transports are taken either way.
+
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Clone, PartialEq)]
+struct Target {
+    /// `None` for any address
+    ip: Option<IpAddr>,
+    port: u16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DnsHijack {
+    targets: Vec<Target>,
+}
+
+impl DnsHijack {
+    pub fn new(entries: &[String]) -> Result<DnsHijack, String> {
+        let targets = entries
+            .iter()
+            .map(|entry| parse(entry).ok_or_else(|| format!("invalid dns-hijack entry {}", entry)))
+            .collect::<Result<_, _>>()?;
+        Ok(DnsHijack { targets })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Whether queries sent to `dst` are answered locally
+    pub fn matches(&self, dst: SocketAddr) -> bool {
+        self.targets
+            .iter()
+            .any(|t| t.port == dst.port() && t.ip.is_none_or(|ip| ip == dst.ip()))
+    }
+}
+
+fn parse(entry: &str) -> Option<Target> {
+    let entry = entry
+        .trim_start_matches("udp://")
+        .trim_start_matches("tcp://");
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        return Some(Target {
+            ip: Some(addr.ip()),
+            port: addr.port(),
+        });
+    }
+    let (host, port) = entry.split_at(entry.rfind(':')?);
+    if host != "any" {
+        return None;
+    }
+    Some(Target {
+        ip: None,
+        port: port[1..].parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_servers_and_ports() {
+        let entries = ["any:53", "udp://8.8.8.8:5353", "[2001:db8::53]:853"];
+        let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        let hijack = DnsHijack::new(&entries).unwrap();
+        assert!(hijack.matches("1.1.1.1:53".parse().unwrap()));
+        assert!(hijack.matches("[::1]:53".parse().unwrap()));
+        assert!(hijack.matches("8.8.8.8:5353".parse().unwrap()));
+        assert!(!hijack.matches("8.8.4.4:5353".parse().unwrap()));
+        assert!(hijack.matches("[2001:db8::53]:853".parse().unwrap()));
+        assert!(!hijack.matches("1.1.1.1:853".parse().unwrap()));
+
+        for bad in &["53", "dns.google:53", "any:dns", "any:70000"] {
+            assert!(DnsHijack::new(&[bad.to_string()]).is_err(), "{}", bad);
+        }
+    }
+}
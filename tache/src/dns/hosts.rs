@@ -0,0 +1,129 @@
+//! Static answers of the built-in DNS server
+
+use std::{collections::HashMap, net::IpAddr};
+
+use log::error;
+use trust_dns_proto::{
+    op::{Message, MessageType, Query, ResponseCode},
+    rr::{Name, RData, Record, RecordType},
+};
+
+use crate::domain_trie::{normalize, DomainTrie};
+
+/// TTL of static answers, short so edits apply soon after a reload
+const TTL: u32 = 60;
+
+/// Aliases followed before giving up on a loop
+pub const MAX_ALIASES: usize = 8;
+
+pub enum Host {
+    Ip(IpAddr),
+    /// Answered with a CNAME, the alias is resolved in turn
+    Alias(Name),
+}
+
+/// Domain patterns mapped to an address or another domain
+#[derive(Default)]
+pub struct Hosts(DomainTrie<Host>);
+
+impl Hosts {
+    pub fn new(hosts: &HashMap<String, String>) -> Hosts {
+        let mut trie = DomainTrie::new();
+        for (pattern, value) in hosts.iter() {
+            let host = match value.parse::<IpAddr>() {
+                Ok(ip) => Host::Ip(ip),
+                Err(_) => match normalize(value).and_then(|v| Name::from_ascii(v + ".").ok()) {
+                    Some(name) => Host::Alias(name),
+                    None => {
+                        error!("Invalid DNS host \"{}\" of \"{}\"", value, pattern);
+                        continue;
+                    }
+                },
+            };
+            if let Err(e) = trie.insert(pattern, host) {
+                error!("Skip DNS host, err: {}", e);
+            }
+        }
+        Hosts(trie)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, name: &Name) -> Option<&Host> {
+        self.0.get(&name.to_ascii())
+    }
+}
+
+/// Record answering a query of `query_type` for `name` with `ip`, none when
+/// the address family doesn't match
+pub fn address(name: Name, query_type: RecordType, ip: IpAddr) -> Option<Record> {
+    match (query_type, ip) {
+        (RecordType::A, IpAddr::V4(ip)) => Some(Record::from_rdata(name, TTL, RData::A(ip))),
+        (RecordType::AAAA, IpAddr::V6(ip)) => Some(Record::from_rdata(name, TTL, RData::AAAA(ip))),
+        _ => None,
+    }
+}
+
+pub fn alias(name: Name, alias: Name) -> Record {
+    Record::from_rdata(name, TTL, RData::CNAME(alias))
+}
+
+/// Query like `query` asking for `name` instead
+pub fn requery(query: &Message, name: Name, query_type: RecordType) -> Message {
+    let mut requery = Message::new();
+    requery
+        .set_id(query.id())
+        .set_message_type(MessageType::Query)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .add_query(Query::query(name, query_type));
+    requery
+}
+
+/// Response to `query` carrying `answers`
+pub fn reply(query: &Message, answers: Vec<Record>, code: ResponseCode) -> Message {
+    let mut resp = Message::new();
+    resp.set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(code);
+    resp.add_queries(query.queries().to_vec());
+    resp.add_answers(answers);
+    resp
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn addresses_and_aliases() {
+        let mut config = HashMap::new();
+        config.insert("+.telemetry.example.com".to_owned(), "127.0.0.1".to_owned());
+        config.insert(
+            "api.example.com".to_owned(),
+            "internal.example.net".to_owned(),
+        );
+        let hosts = Hosts::new(&config);
+
+        let name = Name::from_ascii("a.telemetry.example.com.").unwrap();
+        match hosts.get(&name) {
+            Some(Host::Ip(ip)) => {
+                assert!(address(name.clone(), RecordType::A, *ip).is_some());
+                assert!(address(name, RecordType::AAAA, *ip).is_none());
+            }
+            _ => panic!("expected an address"),
+        }
+        match hosts.get(&Name::from_ascii("API.example.com.").unwrap()) {
+            Some(Host::Alias(alias)) => assert_eq!(alias.to_ascii(), "internal.example.net."),
+            _ => panic!("expected an alias"),
+        }
+        assert!(hosts
+            .get(&Name::from_ascii("example.com.").unwrap())
+            .is_none());
+    }
+}
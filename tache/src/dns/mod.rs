@@ -0,0 +1,417 @@
+//! Built-in DNS server and upstream resolution
+
+use std::{io, net::IpAddr, sync::Arc};
+
+use futures::future::{join, select_ok};
+use log::{debug, error};
+use trust_dns_proto::{
+    op::{Message, MessageType, Query, ResponseCode},
+    rr::{Name, RData, Record, RecordType},
+};
+
+use crate::{
+    config::{DNSConfig, DNSMode, FallbackFilterConfig},
+    geoip::GeoIP,
+    ip_trie::IpSet,
+    outbound::{Outbound, Outbounds},
+};
+
+mod ecs;
+mod fakeip;
+mod hijack;
+mod hosts;
+pub mod query_log;
+mod server;
+mod upstream;
+
+pub use self::{
+    ecs::EcsPolicy,
+    fakeip::FakeIp,
+    hijack::DnsHijack,
+    query_log::QueryLog,
+    server::{answer, run},
+    upstream::Upstream,
+};
+
+use self::hosts::{Host, Hosts};
+
+/// Decides when an answer from the main servers is considered poisoned
+pub struct FallbackFilter {
+    geoip: Option<(Arc<GeoIP>, String)>,
+    ipcidr: IpSet,
+}
+
+impl FallbackFilter {
+    fn new(config: Option<&FallbackFilterConfig>, geoip: Option<Arc<GeoIP>>) -> FallbackFilter {
+        let geoip_enabled = config.map(|c| c.geoip).unwrap_or(true);
+        let code = config
+            .map(|c| c.geoip_code.clone())
+            .unwrap_or_else(|| "CN".to_owned());
+        let ipcidr = config
+            .map(|c| {
+                IpSet::from_cidrs(&c.ipcidr, |s| {
+                    error!("Invalid fallback-filter ipcidr \"{}\"", s)
+                })
+            })
+            .unwrap_or_default();
+        FallbackFilter {
+            geoip: if geoip_enabled {
+                geoip.map(|g| (g, code))
+            } else {
+                None
+            },
+            ipcidr,
+        }
+    }
+
+    /// An answer outside the expected country or inside a bogus range needs the fallback
+    fn should_fallback(&self, ip: IpAddr) -> bool {
+        if let Some((ref geoip, ref code)) = self.geoip {
+            match geoip.country(ip) {
+                Some(ref country) if country.eq_ignore_ascii_case(code) => {}
+                _ => return true,
+            }
+        }
+        self.ipcidr.contains(ip)
+    }
+}
+
+pub(crate) fn answer_ips(msg: &Message) -> Vec<IpAddr> {
+    msg.answers()
+        .iter()
+        .filter_map(|r: &Record| match *r.rdata() {
+            RData::A(ip) => Some(IpAddr::V4(ip)),
+            RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// An upstream together with the outbound carrying its queries
+pub struct Server {
+    upstream: Upstream,
+    via: Option<Arc<dyn Outbound>>,
+}
+
+/// Query every server of a group concurrently and take the first answer,
+/// with the upstream it came from
+async fn exchange_group(servers: &[Server], query: &[u8]) -> io::Result<(Message, String)> {
+    if servers.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other, "no DNS server"));
+    }
+    let futs = servers.iter().map(|s| {
+        Box::pin(async move {
+            let resp = s.upstream.exchange(query, s.via.as_deref()).await?;
+            let msg = Message::from_vec(&resp)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            Ok::<_, io::Error>((msg, s.upstream.to_string()))
+        })
+    });
+    let (answered, _) = select_ok(futs).await?;
+    Ok(answered)
+}
+
+pub struct Resolver {
+    main: Vec<Server>,
+    fallback: Vec<Server>,
+    filter: FallbackFilter,
+    ecs: Option<EcsPolicy>,
+    hosts: Hosts,
+    /// Present in `fake-ip` mode
+    fake_ip: Option<Arc<FakeIp>>,
+    query_log: Option<Arc<QueryLog>>,
+}
+
+/// Parse an upstream optionally suffixed with `#outbound`
+fn parse_server(s: &str, outbounds: &Outbounds) -> io::Result<Server> {
+    let (s, via) = match s.rfind('#') {
+        Some(pos) => (&s[..pos], Some(&s[pos + 1..])),
+        None => (s, None),
+    };
+    let via = match via {
+        Some(name) => Some(outbounds.get(name).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("outbound \"{}\" of DNS server \"{}\" not found", name, s),
+            )
+        })?),
+        None => None,
+    };
+    Ok(Server {
+        upstream: Upstream::parse(s)?,
+        via,
+    })
+}
+
+fn parse_servers(servers: &[String], outbounds: &Outbounds) -> Vec<Server> {
+    servers
+        .iter()
+        .filter_map(|s| match parse_server(s, outbounds) {
+            Ok(server) => Some(server),
+            Err(e) => {
+                error!("Skip DNS server, err: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+impl Resolver {
+    pub fn new(config: &DNSConfig, geoip: Option<Arc<GeoIP>>, outbounds: &Outbounds) -> Resolver {
+        Resolver {
+            main: parse_servers(&config.servers, outbounds),
+            fallback: parse_servers(&config.fallback, outbounds),
+            filter: FallbackFilter::new(config.fallback_filter.as_ref(), geoip),
+            ecs: config
+                .client_subnet
+                .as_ref()
+                .and_then(|s| match EcsPolicy::parse(s) {
+                    Some(policy) => Some(policy),
+                    None => {
+                        error!(
+                            "Invalid client-subnet \"{}\", expect `strip` or a subnet",
+                            s
+                        );
+                        None
+                    }
+                }),
+            hosts: Hosts::new(&config.hosts),
+            fake_ip: match config.mode {
+                DNSMode::FakeIP => match FakeIp::new(
+                    config.fake_ip_range.as_deref(),
+                    config.fake_ip6_range.as_deref(),
+                ) {
+                    Ok(fake_ip) => Some(Arc::new(fake_ip)),
+                    Err(e) => {
+                        error!("Fake-ip disabled, err: {}", e);
+                        None
+                    }
+                },
+                DNSMode::RedirHost => None,
+            },
+            query_log: config
+                .query_log
+                .as_ref()
+                .map(|c| Arc::new(QueryLog::new(c))),
+        }
+    }
+
+    /// Log of answered queries, when `query-log` is set
+    pub fn query_log(&self) -> Option<Arc<QueryLog>> {
+        self.query_log.clone()
+    }
+
+    /// Fake addresses of `fake-ip` mode
+    pub fn fake_ip(&self) -> Option<Arc<FakeIp>> {
+        self.fake_ip.clone()
+    }
+
+    /// Domain a connection to the fake address `ip` is meant for
+    pub fn fake_host(&self, ip: IpAddr) -> Option<String> {
+        self.fake_ip.as_ref()?.host(ip)
+    }
+
+    /// Resolve a wire format query, static hosts first, then fake addresses
+    pub async fn exchange(&self, query: &[u8]) -> io::Result<Message> {
+        self.resolve(query).await.map(|(resp, _)| resp)
+    }
+
+    /// Like `exchange`, with where the answer came from, an upstream,
+    /// `hosts` or `fake-ip`
+    pub async fn resolve(&self, query: &[u8]) -> io::Result<(Message, String)> {
+        if !self.hosts.is_empty() || self.fake_ip.is_some() {
+            let msg = Message::from_vec(query)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            if let Some(resp) = self.lookup_hosts(&msg).await? {
+                return Ok((resp, "hosts".to_owned()));
+            }
+            if let Some(resp) = self.lookup_fake(&msg) {
+                return Ok((resp, "fake-ip".to_owned()));
+            }
+        }
+        self.forward(query).await
+    }
+
+    /// First address of `host`, from the hosts or the upstreams, never a fake
+    /// one, for rules on destination addresses
+    pub async fn lookup_ip(&self, host: &str) -> io::Result<Option<IpAddr>> {
+        let name = Name::from_ascii(host)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let mut query = Message::new();
+        query
+            .set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name, RecordType::A));
+        let resp = match self.lookup_hosts(&query).await? {
+            Some(resp) => resp,
+            None => {
+                let query = query
+                    .to_vec()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                self.forward(&query).await?.0
+            }
+        };
+        Ok(answer_ips(&resp).into_iter().next())
+    }
+
+    /// Answer address queries with fake addresses, AAAA ones without an
+    /// IPv6 range with none
+    fn lookup_fake(&self, query: &Message) -> Option<Message> {
+        let fake_ip = self.fake_ip.as_ref()?;
+        let (name, query_type) = match query.queries() {
+            [q] => (q.name().clone(), q.query_type()),
+            _ => return None,
+        };
+        let v6 = match query_type {
+            RecordType::A => false,
+            RecordType::AAAA => true,
+            _ => return None,
+        };
+        let answers = fake_ip
+            .allocate(&name.to_ascii(), v6)
+            .and_then(|ip| hosts::address(name, query_type, ip));
+        Some(hosts::reply(
+            query,
+            answers.into_iter().collect(),
+            ResponseCode::NoError,
+        ))
+    }
+
+    /// Answer `query` from the hosts, following aliases and forwarding the
+    /// last one that isn't a host
+    async fn lookup_hosts(&self, query: &Message) -> io::Result<Option<Message>> {
+        let (mut name, query_type) = match query.queries() {
+            [q] => (q.name().clone(), q.query_type()),
+            _ => return Ok(None),
+        };
+        let mut answers = Vec::new();
+        for _ in 0..hosts::MAX_ALIASES {
+            match self.hosts.get(&name) {
+                None if answers.is_empty() => return Ok(None),
+                None => {
+                    let requery = hosts::requery(query, name, query_type);
+                    let requery = requery
+                        .to_vec()
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                    let (mut resp, _) = self.forward(&requery).await?;
+                    answers.extend(resp.take_answers());
+                    return Ok(Some(hosts::reply(query, answers, resp.response_code())));
+                }
+                Some(Host::Ip(ip)) => {
+                    answers.extend(hosts::address(name, query_type, *ip));
+                    return Ok(Some(hosts::reply(query, answers, ResponseCode::NoError)));
+                }
+                Some(Host::Alias(alias)) => {
+                    answers.push(hosts::alias(name, alias.clone()));
+                    name = alias.clone();
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too many aliases in DNS hosts",
+        ))
+    }
+
+    /// Resolve a wire format query with the upstreams
+    ///
+    /// With fallback servers configured both groups are asked at once and the
+    /// fallback answer replaces the main one when the latter looks poisoned.
+    async fn forward(&self, query: &[u8]) -> io::Result<(Message, String)> {
+        let rewritten;
+        let query = match self.ecs {
+            Some(ref ecs) => {
+                rewritten = ecs.apply(query)?;
+                &rewritten[..]
+            }
+            None => query,
+        };
+
+        if self.fallback.is_empty() {
+            return exchange_group(&self.main, query).await;
+        }
+
+        let (main, fallback) = join(
+            exchange_group(&self.main, query),
+            exchange_group(&self.fallback, query),
+        )
+        .await;
+        match (main, fallback) {
+            (Ok(main), Ok(fallback)) => {
+                if answer_ips(&main.0)
+                    .into_iter()
+                    .any(|ip| self.filter.should_fallback(ip))
+                {
+                    debug!("DNS answer from main servers filtered, using fallback");
+                    Ok(fallback)
+                } else {
+                    Ok(main)
+                }
+            }
+            (Ok(main), Err(..)) => Ok(main),
+            (Err(..), Ok(fallback)) => Ok(fallback),
+            (Err(e), Err(..)) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, UdpSocket},
+        thread,
+        time::Duration,
+    };
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    /// Upstream answering A queries with `answer` of the queried name
+    fn upstream(answer: fn(&str) -> Ipv4Addr) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                let query = Message::from_vec(&buf[..len]).unwrap();
+                let name = query.queries()[0].name().clone();
+                let ip = answer(&name.to_ascii());
+                let record = hosts::address(name, RecordType::A, IpAddr::V4(ip));
+                let resp =
+                    hosts::reply(&query, record.into_iter().collect(), ResponseCode::NoError);
+                socket.send_to(&resp.to_vec().unwrap(), peer).unwrap();
+            }
+        });
+        format!("udp://{}", addr)
+    }
+
+    #[test]
+    fn fallback_replaces_poisoned_answers() {
+        let main = upstream(|name| match name {
+            "poisoned.example." => Ipv4Addr::new(240, 0, 0, 1),
+            _ => Ipv4Addr::new(192, 0, 2, 1),
+        });
+        let fallback = upstream(|_| Ipv4Addr::new(198, 51, 100, 1));
+        let config: DNSConfig = serde_yaml::from_str(&format!(
+            "{{ listen: 127.0.0.1:0, mode: redir-host, servers: [{}], fallback: [{}], \
+             fallback-filter: {{ geoip: false, ipcidr: [240.0.0.0/4] }} }}",
+            main, fallback
+        ))
+        .unwrap();
+        let resolver = Resolver::new(&config, None, &HashMap::new());
+
+        let mut rt = Runtime::new().unwrap();
+        let poisoned = rt
+            .block_on(resolver.lookup_ip("poisoned.example."))
+            .unwrap();
+        assert_eq!(poisoned, Some("198.51.100.1".parse().unwrap()));
+        let clean = rt.block_on(resolver.lookup_ip("clean.example.")).unwrap();
+        assert_eq!(clean, Some("192.0.2.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,183 @@
+//! Queries the built-in resolver answered, `query-log` of `dns`
+//!
+//! Each query is attributed to the client that sent it, with the answer,
+//! where it came from (an upstream, `hosts` or `fake-ip`) and how long it
+//! took. Entries are appended as JSON lines to `path` when set and streamed
+//! to subscribers, the API's `/dns/queries`.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use log::error;
+use serde::Serialize;
+use trust_dns_proto::{op::Message, rr::RData};
+
+use crate::{
+    config::DnsQueryLogConfig,
+    provider::{format_time, unix_now},
+};
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Query {
+    pub at: String,
+    /// `None` when the query didn't come over the network, like from tests
+    pub client: Option<IpAddr>,
+    pub qname: String,
+    pub qtype: String,
+    /// Response code, `SERVFAIL` as well when resolving failed
+    pub rcode: String,
+    /// Addresses and aliases answered
+    pub answer: Vec<String>,
+    /// Upstream, `hosts` or `fake-ip`, `None` when resolving failed
+    pub upstream: Option<String>,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub struct QueryLog {
+    file: Option<Mutex<File>>,
+    subscribers: Mutex<Vec<UnboundedSender<Query>>>,
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Addresses and aliases in the answer section of `msg`
+fn answers(msg: &Message) -> Vec<String> {
+    msg.answers()
+        .iter()
+        .filter_map(|r| match *r.rdata() {
+            RData::A(ip) => Some(ip.to_string()),
+            RData::AAAA(ip) => Some(ip.to_string()),
+            RData::CNAME(ref name) => Some(name.to_ascii()),
+            _ => None,
+        })
+        .collect()
+}
+
+impl QueryLog {
+    /// A log without a file still streams, one whose file can't be opened
+    /// as well
+    pub fn new(config: &DnsQueryLogConfig) -> QueryLog {
+        let file = config
+            .path
+            .as_ref()
+            .and_then(|path| match open(Path::new(path)) {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(e) => {
+                    error!("Not writing DNS queries to {}, err: {}", path, e);
+                    None
+                }
+            });
+        QueryLog {
+            file,
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Queries recorded from now on
+    pub fn subscribe(&self) -> UnboundedReceiver<Query> {
+        let (tx, rx) = unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Record the wire format `query` of `client` and how it was resolved
+    pub fn record(
+        &self,
+        client: Option<IpAddr>,
+        query: &[u8],
+        result: Result<(&Message, &str), &io::Error>,
+        latency: Duration,
+    ) {
+        let (qname, qtype) = match Message::from_vec(query) {
+            Ok(msg) => match msg.queries().first() {
+                Some(q) => (q.name().to_ascii(), q.query_type().to_string()),
+                None => return,
+            },
+            Err(_) => return,
+        };
+        let entry = match result {
+            Ok((resp, upstream)) => Query {
+                at: format_time(unix_now()),
+                client,
+                qname,
+                qtype,
+                rcode: resp.response_code().to_string(),
+                answer: answers(resp),
+                upstream: Some(upstream.to_owned()),
+                latency_ms: latency.as_millis() as u64,
+                error: None,
+            },
+            Err(e) => Query {
+                at: format_time(unix_now()),
+                client,
+                qname,
+                qtype,
+                rcode: "SERVFAIL".to_owned(),
+                answer: Vec::new(),
+                upstream: None,
+                latency_ms: latency.as_millis() as u64,
+                error: Some(e.to_string()),
+            },
+        };
+
+        if let Some(ref file) = self.file {
+            let mut line = serde_json::to_string(&entry).unwrap_or_default();
+            line.push('\n');
+            if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+                error!("Failed to write DNS query log, err: {}", e);
+            }
+        }
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(entry.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+    use trust_dns_proto::{
+        op::{Message, Query as Question},
+        rr::{Name, RecordType},
+    };
+
+    #[test]
+    fn streams_attributed_queries() {
+        let log = QueryLog::new(&DnsQueryLogConfig { path: None });
+        let mut queries = log.subscribe();
+
+        let mut query = Message::new();
+        query.add_query(Question::query(
+            Name::from_ascii("example.com.").unwrap(),
+            RecordType::AAAA,
+        ));
+        let query = query.to_vec().unwrap();
+        let client = "192.0.2.7".parse().ok();
+        let failed = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        log.record(client, &query, Err(&failed), Duration::from_millis(5));
+
+        let entry = futures::executor::block_on(queries.next()).unwrap();
+        assert_eq!(entry.client, client);
+        assert_eq!(entry.qname, "example.com.");
+        assert_eq!(entry.qtype, "AAAA");
+        assert_eq!(entry.rcode, "SERVFAIL");
+        assert_eq!(entry.latency_ms, 5);
+        assert!(entry.upstream.is_none());
+    }
+}
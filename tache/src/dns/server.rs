@@ -0,0 +1,80 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Instant,
+};
+
+use futures::{channel::mpsc, StreamExt};
+use log::{debug, info};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+
+use super::Resolver;
+use crate::rt::{self, UdpSocket};
+
+/// Reply with SERVFAIL so clients fail fast instead of timing out
+fn servfail(query: &[u8]) -> Option<Vec<u8>> {
+    let query = Message::from_vec(query).ok()?;
+    let mut resp = Message::new();
+    resp.set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::ServFail);
+    resp.add_queries(query.queries().to_vec());
+    resp.to_vec().ok()
+}
+
+/// Response to the wire format `query` of `client`, SERVFAIL when resolving
+/// fails
+pub async fn answer(resolver: &Resolver, query: &[u8], client: Option<IpAddr>) -> Option<Vec<u8>> {
+    let start = Instant::now();
+    let result = resolver.resolve(query).await;
+    if let Some(log) = resolver.query_log() {
+        let result = result
+            .as_ref()
+            .map(|(resp, upstream)| (resp, upstream.as_str()));
+        log.record(client, query, result, start.elapsed());
+    }
+    match result {
+        Ok((resp, _)) => resp.to_vec().ok(),
+        Err(e) => {
+            debug!("DNS query failed, err: {}", e);
+            servfail(query)
+        }
+    }
+}
+
+/// Serve DNS over UDP until the socket fails
+pub async fn run(resolver: Arc<Resolver>, listen: impl ToSocketAddrs) -> io::Result<()> {
+    let addr = listen
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid DNS listen address"))?;
+    let socket = UdpSocket::bind(&addr).await?;
+    info!("DNS listening on: {}", addr);
+
+    let (mut recv, mut send) = socket.split();
+    let (tx, mut rx) = mpsc::unbounded::<(Vec<u8>, SocketAddr)>();
+    rt::spawn(async move {
+        while let Some((resp, peer)) = rx.next().await {
+            if let Err(e) = send.send_to(&resp, &peer).await {
+                debug!("Failed to send DNS response to {}, err: {}", peer, e);
+            }
+        }
+    });
+
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let (n, peer) = recv.recv_from(&mut buf).await?;
+        let query = buf[..n].to_vec();
+        let resolver = resolver.clone();
+        let tx = tx.clone();
+        rt::spawn(async move {
+            if let Some(resp) = answer(&resolver, &query, Some(peer.ip())).await {
+                let _ = tx.unbounded_send((resp, peer));
+            }
+        });
+    }
+}
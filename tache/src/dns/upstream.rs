@@ -0,0 +1,279 @@
+//! DNS upstream servers
+
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::lookup_host,
+};
+use url::Url;
+
+use crate::{
+    http_client,
+    outbound::{BoxStream, Outbound, TcpDialer},
+    rt::{self, TcpStream, UdpSocket},
+    tls,
+    utils::{Address, DomainName},
+};
+
+/// Time allowed for one exchange with an upstream
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Host names of servers are looked up on each exchange
+#[derive(Clone, Debug)]
+pub enum Upstream {
+    Udp(Address),
+    Tcp(Address),
+    /// DNS over TLS, address and certificate name
+    Tls(Address, String),
+    /// DNS over HTTPS (RFC 8484 GET)
+    Https(String),
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Upstream::Udp(ref addr) => write!(f, "udp://{}", addr),
+            Upstream::Tcp(ref addr) => write!(f, "tcp://{}", addr),
+            Upstream::Tls(ref addr, ref name) => write!(f, "tls://{}({})", name, addr),
+            Upstream::Https(ref url) => f.write_str(url),
+        }
+    }
+}
+
+fn invalid(s: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid DNS server \"{}\"", s),
+    )
+}
+
+fn address(host: &str, port: u16) -> Address {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Address::SocketAddr(SocketAddr::new(ip, port)),
+        Err(..) => Address::DomainName(DomainName(host.to_owned(), port)),
+    }
+}
+
+/// First address of `addr`, without blocking the runtime on a lookup
+async fn resolve(addr: &Address) -> io::Result<SocketAddr> {
+    match *addr {
+        Address::SocketAddr(addr) => Ok(addr),
+        Address::DomainName(DomainName(ref host, port)) => lookup_host((host.as_str(), port))
+            .await?
+            .next()
+            .ok_or_else(|| invalid(host)),
+    }
+}
+
+impl Upstream {
+    /// Parse `1.1.1.1`, `udp://1.1.1.1:53`, `tcp://1.1.1.1`, `tls://dns.example:853`
+    /// or `https://1.1.1.1/dns-query`
+    pub fn parse(s: &str) -> io::Result<Upstream> {
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Upstream::Udp(Address::SocketAddr(SocketAddr::new(ip, 53))));
+        }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Upstream::Udp(Address::SocketAddr(addr)));
+        }
+        let url = Url::parse(s).map_err(|_| invalid(s))?;
+        let host = url.host_str().ok_or_else(|| invalid(s))?;
+        // Url keeps brackets around IPv6 hosts
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match url.scheme() {
+            "udp" => Ok(Upstream::Udp(address(host, url.port().unwrap_or(53)))),
+            "tcp" => Ok(Upstream::Tcp(address(host, url.port().unwrap_or(53)))),
+            "tls" => Ok(Upstream::Tls(
+                address(host, url.port().unwrap_or(853)),
+                host.to_owned(),
+            )),
+            "https" => Ok(Upstream::Https(s.to_owned())),
+            _ => Err(invalid(s)),
+        }
+    }
+
+    /// Send a wire format query and return the wire format response
+    ///
+    /// With `via` the exchange is carried by that outbound; plain UDP
+    /// servers are then queried over TCP since outbounds only relay streams.
+    pub async fn exchange(&self, query: &[u8], via: Option<&dyn Outbound>) -> io::Result<Vec<u8>> {
+        match rt::timeout(UPSTREAM_TIMEOUT, self.exchange_inner(query, via)).await {
+            Ok(res) => res,
+            Err(..) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("DNS upstream {} timed out", self),
+            )),
+        }
+    }
+
+    async fn exchange_inner(
+        &self,
+        query: &[u8],
+        via: Option<&dyn Outbound>,
+    ) -> io::Result<Vec<u8>> {
+        match *self {
+            Upstream::Udp(ref addr) if via.is_none() => {
+                let addr = resolve(addr).await?;
+                let local: SocketAddr = if addr.is_ipv4() {
+                    "0.0.0.0:0".parse().unwrap()
+                } else {
+                    "[::]:0".parse().unwrap()
+                };
+                let mut socket = UdpSocket::bind(&local).await?;
+                socket.send_to(query, &addr).await?;
+                let mut buf = vec![0u8; 4096];
+                loop {
+                    let (n, from) = socket.recv_from(&mut buf).await?;
+                    // Drop stray packets from anyone but the upstream
+                    if from == addr {
+                        buf.truncate(n);
+                        return Ok(buf);
+                    }
+                }
+            }
+            Upstream::Udp(ref addr) | Upstream::Tcp(ref addr) => {
+                exchange_stream(connect(addr, via).await?, query).await
+            }
+            Upstream::Tls(ref addr, ref name) => {
+                let stream = connect(addr, via).await?;
+                let stream = tls::connect(&http_client::tls_connector(), name, stream).await?;
+                exchange_stream(stream, query).await
+            }
+            Upstream::Https(ref url) => {
+                let url = format!(
+                    "{}{}dns={}",
+                    url,
+                    if url.contains('?') { '&' } else { '?' },
+                    base64::encode_config(query, base64::URL_SAFE_NO_PAD)
+                );
+                let resp = http_client::get_via(&url, UPSTREAM_TIMEOUT, via).await?;
+                if resp.status != 200 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("DNS over HTTPS returned status {}", resp.status),
+                    ));
+                }
+                Ok(resp.body)
+            }
+        }
+    }
+}
+
+/// Through `via` the proxy resolves the server's name
+async fn connect(addr: &Address, via: Option<&dyn Outbound>) -> io::Result<BoxStream> {
+    match via {
+        Some(via) => via.dial(addr, &TcpDialer).await,
+        None => Ok(Box::new(TcpStream::connect(&resolve(addr).await?).await?)),
+    }
+}
+
+/// Stream transports prefix every message with its length
+async fn exchange_stream<S>(mut stream: S, query: &[u8]) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut len = [0u8; 2];
+    BigEndian::write_u16(&mut len, query.len() as u16);
+    stream.write_all(&len).await?;
+    stream.write_all(query).await?;
+    stream.read_exact(&mut len).await?;
+    let mut buf = vec![0u8; BigEndian::read_u16(&len) as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use futures::future::BoxFuture;
+
+    use super::*;
+    use crate::{
+        outbound::Dialer,
+        rt::{Runtime, TcpListener},
+    };
+
+    /// Stands in for a proxy, noting what it was asked to reach
+    struct Relay {
+        server: SocketAddr,
+        dialed: Mutex<Vec<String>>,
+    }
+
+    impl Outbound for Relay {
+        fn name(&self) -> String {
+            "relay".to_owned()
+        }
+
+        fn udp(&self) -> bool {
+            false
+        }
+
+        fn dial<'a>(
+            &'a self,
+            target: &'a Address,
+            _: &'a dyn Dialer,
+        ) -> BoxFuture<'a, io::Result<BoxStream>> {
+            self.dialed.lock().unwrap().push(target.to_string());
+            Box::pin(async move {
+                let stream: BoxStream = Box::new(TcpStream::connect(&self.server).await?);
+                Ok(stream)
+            })
+        }
+
+        fn alive(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn parse_leaves_names_unresolved() {
+        // Nothing resolves under .invalid, parsing must not try
+        match Upstream::parse("tls://dns.invalid:8853").unwrap() {
+            Upstream::Tls(Address::DomainName(DomainName(host, port)), name) => {
+                assert_eq!(
+                    (host.as_str(), port, name.as_str()),
+                    ("dns.invalid", 8853, "dns.invalid")
+                );
+            }
+            upstream => panic!("unexpected upstream {}", upstream),
+        }
+        match Upstream::parse("tcp://[::1]").unwrap() {
+            Upstream::Tcp(Address::SocketAddr(addr)) => {
+                assert_eq!(addr, "[::1]:53".parse().unwrap())
+            }
+            upstream => panic!("unexpected upstream {}", upstream),
+        }
+    }
+
+    #[test]
+    fn exchanges_through_the_outbound() {
+        Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let relay = Relay {
+                server: listener.local_addr().unwrap(),
+                dialed: Mutex::new(Vec::new()),
+            };
+            // A UDP server goes over TCP through the proxy, which resolves it
+            let upstream = Upstream::parse("udp://dns.invalid:5353").unwrap();
+            let serve = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut query = [0; 2 + 5];
+                stream.read_exact(&mut query).await.unwrap();
+                assert_eq!(&query, b"\x00\x05query");
+                stream.write_all(b"\x00\x06answer").await.unwrap();
+            };
+            let client = async {
+                let answer = upstream.exchange(b"query", Some(&relay)).await.unwrap();
+                assert_eq!(answer, b"answer");
+            };
+            futures::join!(serve, client);
+            assert_eq!(*relay.dialed.lock().unwrap(), vec!["dns.invalid:5353"]);
+        });
+    }
+}
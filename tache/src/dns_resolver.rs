@@ -5,13 +5,12 @@ use std::{
     net::SocketAddr,
 };
 
-use tokio::{self, future::Future};
 use trust_dns_resolver::{config::ResolverConfig, Resolver};
 
 use crate::context::SharedContext;
 
 pub fn create_resolver(dns: Option<ResolverConfig>) -> io::Result<Resolver> {
-    let resolver = {
+    {
         // To make this independent, if targeting macOS, BSD, Linux, or Windows, we can use the system's configuration:
         #[cfg(any(unix, windows))]
         {
@@ -39,9 +38,7 @@ pub fn create_resolver(dns: Option<ResolverConfig>) -> io::Result<Resolver> {
                 Resolver::new(ResolverConfig::google(), ResolverOpts::default())
             }
         }
-    };
-
-    resolver
+    }
 }
 
 async fn inner_resolve(
@@ -0,0 +1,226 @@
+//! Domain lookups by most specific pattern
+//!
+//! Names are normalized before they are stored or looked up: internationalized
+//! labels become punycode, letters lowercase and a trailing dot is dropped, so
+//! `Bücher.Example.` and `xn--bcher-kva.example` are the same name everywhere.
+//!
+//! Patterns follow the usual proxy rule syntax:
+//!
+//! * `example.com` the name itself
+//! * `*.example.com` names one label below it
+//! * `.example.com` every name below it
+//! * `+.example.com` the name and every name below it
+
+use std::{collections::HashMap, error::Error, fmt};
+
+/// Pattern whose domain is not a valid name
+#[derive(Debug)]
+pub struct InvalidDomain(pub String);
+
+impl fmt::Display for InvalidDomain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid domain pattern \"{}\"", self.0)
+    }
+}
+
+impl Error for InvalidDomain {}
+
+/// Canonical form of `domain`, `None` when it is not a valid name
+pub fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.trim_end_matches('.');
+    if domain.is_empty() {
+        return None;
+    }
+    let ascii = if domain.is_ascii() {
+        domain.to_ascii_lowercase()
+    } else {
+        idna::domain_to_ascii(domain).ok()?
+    };
+    if ascii.split('.').any(str::is_empty) {
+        return None;
+    }
+    Some(ascii)
+}
+
+struct Node<T> {
+    children: HashMap<String, Node<T>>,
+    exact: Option<T>,
+    /// Names exactly one label below
+    wildcard: Option<T>,
+    /// Names any number of labels below
+    subdomains: Option<T>,
+    /// The name and names below
+    suffix: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            children: HashMap::new(),
+            exact: None,
+            wildcard: None,
+            subdomains: None,
+            suffix: None,
+        }
+    }
+}
+
+/// Domain patterns mapped to values
+pub struct DomainTrie<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+impl<T> Default for DomainTrie<T> {
+    fn default() -> DomainTrie<T> {
+        DomainTrie::new()
+    }
+}
+
+impl<T> DomainTrie<T> {
+    pub fn new() -> DomainTrie<T> {
+        DomainTrie {
+            root: Node::new(),
+            len: 0,
+        }
+    }
+
+    /// Patterns stored
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Map `pattern` to `value`, returning the value it replaced
+    ///
+    /// Fails when the domain of the pattern is invalid.
+    pub fn insert(&mut self, pattern: &str, value: T) -> Result<Option<T>, InvalidDomain> {
+        let (domain, kind) = if let Some(domain) = pattern.strip_prefix("*.") {
+            (domain, Kind::Wildcard)
+        } else if let Some(domain) = pattern.strip_prefix("+.") {
+            (domain, Kind::Suffix)
+        } else if let Some(domain) = pattern.strip_prefix('.') {
+            (domain, Kind::Subdomains)
+        } else {
+            (pattern, Kind::Exact)
+        };
+        let domain = normalize(domain).ok_or_else(|| InvalidDomain(pattern.to_owned()))?;
+
+        let mut node = &mut self.root;
+        for label in domain.rsplit('.') {
+            node = node
+                .children
+                .entry(label.to_owned())
+                .or_insert_with(Node::new);
+        }
+        let slot = match kind {
+            Kind::Exact => &mut node.exact,
+            Kind::Wildcard => &mut node.wildcard,
+            Kind::Subdomains => &mut node.subdomains,
+            Kind::Suffix => &mut node.suffix,
+        };
+        let old = slot.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        Ok(old)
+    }
+
+    /// `domain` itself, same as a pattern without prefix
+    pub fn insert_exact(&mut self, domain: &str, value: T) -> Result<Option<T>, InvalidDomain> {
+        self.insert(domain.trim_start_matches('.'), value)
+    }
+
+    /// `domain` and every name below, as DOMAIN-SUFFIX rules match
+    pub fn insert_suffix(&mut self, domain: &str, value: T) -> Result<Option<T>, InvalidDomain> {
+        self.insert(&format!("+.{}", domain.trim_start_matches('.')), value)
+    }
+
+    /// Value of the most specific pattern matching `domain`
+    ///
+    /// Deeper patterns win, on the same level the name itself beats its
+    /// suffix and a wildcard beats broader subdomain patterns.
+    pub fn get(&self, domain: &str) -> Option<&T> {
+        let domain = normalize(domain)?;
+        let labels = domain.rsplit('.').collect::<Vec<_>>();
+
+        let mut node = &self.root;
+        let mut best = None;
+        for depth in 0..=labels.len() {
+            let remaining = labels.len() - depth;
+            let found = match remaining {
+                0 => node.exact.as_ref().or(node.suffix.as_ref()),
+                1 => node
+                    .wildcard
+                    .as_ref()
+                    .or(node.subdomains.as_ref())
+                    .or(node.suffix.as_ref()),
+                _ => node.subdomains.as_ref().or(node.suffix.as_ref()),
+            };
+            if found.is_some() {
+                best = found;
+            }
+            node = match labels.get(depth).and_then(|l| node.children.get(*l)) {
+                Some(child) => child,
+                None => break,
+            };
+        }
+        best
+    }
+
+    pub fn contains(&self, domain: &str) -> bool {
+        self.get(domain).is_some()
+    }
+}
+
+enum Kind {
+    Exact,
+    Wildcard,
+    Subdomains,
+    Suffix,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_names() {
+        assert_eq!(
+            normalize("WWW.Example.COM."),
+            Some("www.example.com".to_owned())
+        );
+        assert_eq!(
+            normalize("Bücher.example"),
+            Some("xn--bcher-kva.example".to_owned())
+        );
+        assert_eq!(normalize("."), None);
+        assert_eq!(normalize("a..b"), None);
+    }
+
+    #[test]
+    fn most_specific_pattern_wins() {
+        let mut trie = DomainTrie::new();
+        trie.insert("+.example.com", "suffix").unwrap();
+        trie.insert("*.cdn.example.com", "wildcard").unwrap();
+        trie.insert(".static.example.com", "subdomains").unwrap();
+        trie.insert("www.example.com", "exact").unwrap();
+        trie.insert_exact("bücher.de", "idn").unwrap();
+
+        assert_eq!(trie.get("example.com"), Some(&"suffix"));
+        assert_eq!(trie.get("WWW.example.com."), Some(&"exact"));
+        assert_eq!(trie.get("a.www.example.com"), Some(&"suffix"));
+        assert_eq!(trie.get("img.cdn.example.com"), Some(&"wildcard"));
+        assert_eq!(trie.get("a.img.cdn.example.com"), Some(&"suffix"));
+        assert_eq!(trie.get("static.example.com"), Some(&"suffix"));
+        assert_eq!(trie.get("a.b.static.example.com"), Some(&"subdomains"));
+        assert_eq!(trie.get("xn--bcher-kva.de"), Some(&"idn"));
+        assert_eq!(trie.get("example.org"), None);
+        assert_eq!(trie.get("notexample.com"), None);
+        assert!(trie.insert("*.", "invalid").is_err());
+        assert_eq!(trie.len(), 5);
+    }
+}
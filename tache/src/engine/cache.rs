@@ -0,0 +1,577 @@
+//! Cache of GET responses for the HTTP inbound
+//!
+//! A small shared cache after RFC 7234: only responses with explicit
+//! freshness are stored, and none marked `no-store` or `private`, carrying
+//! `Vary` or setting cookies. Stale entries with an `ETag` or
+//! `Last-Modified` are revalidated with a conditional request. Entries are
+//! kept in memory up to `memory-size` bytes, the least recently used ones
+//! move to `disk-path` when it is set.
+
+use std::{
+    fs,
+    hash::Hasher,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    Method, Request, Response,
+};
+use log::{debug, warn};
+use lru_cache::LruCache;
+use siphasher::sip::SipHasher;
+
+use crate::{config::HttpCacheConfig, state};
+
+const DEFAULT_MEMORY_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_DISK_SIZE: u64 = 256 * 1024 * 1024;
+const DEFAULT_MAX_OBJECT_SIZE: usize = 4 * 1024 * 1024;
+
+/// First line of entries on disk, bumped when the format changes
+const DISK_MAGIC: &str = "tache-cache-1";
+
+/// Statuses cacheable by default, RFC 7231 section 6.1
+const CACHEABLE_STATUS: [u16; 10] = [200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+
+/// Headers describing the connection rather than the response
+const HOP_BY_HOP: [HeaderName; 7] = [
+    header::CONNECTION,
+    header::TRANSFER_ENCODING,
+    header::TE,
+    header::TRAILER,
+    header::UPGRADE,
+    header::PROXY_AUTHENTICATE,
+    header::CONTENT_LENGTH,
+];
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `Sun, 06 Nov 1994 08:49:37 GMT` as unix time
+fn http_date(value: &HeaderValue) -> Option<u64> {
+    let tm = time::strptime(value.to_str().ok()?, "%a, %d %b %Y %H:%M:%S GMT").ok()?;
+    let sec = tm.to_timespec().sec;
+    if sec < 0 {
+        None
+    } else {
+        Some(sec as u64)
+    }
+}
+
+/// Whether `Cache-Control` lists `directive`, returning its argument if any
+fn directive<'h>(headers: &'h HeaderMap, directive: &str) -> Option<Option<&'h str>> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find_map(|d| {
+            let mut sp = d.trim().splitn(2, '=');
+            let name = sp.next()?;
+            if name.eq_ignore_ascii_case(directive) {
+                Some(sp.next().map(|arg| arg.trim_matches('"')))
+            } else {
+                None
+            }
+        })
+}
+
+fn seconds(headers: &HeaderMap, name: &str) -> Option<u64> {
+    directive(headers, name)?.and_then(|arg| arg.parse().ok())
+}
+
+/// Seconds a response stays fresh from `now`, `None` when it can't be stored
+fn freshness_lifetime(headers: &HeaderMap, now: u64) -> Option<u64> {
+    if directive(headers, "no-store").is_some() || directive(headers, "private").is_some() {
+        return None;
+    }
+    let lifetime = if directive(headers, "no-cache").is_some() {
+        0
+    } else if let Some(max_age) =
+        seconds(headers, "s-maxage").or_else(|| seconds(headers, "max-age"))
+    {
+        max_age
+    } else if let Some(expires) = headers.get(header::EXPIRES) {
+        let date = headers.get(header::DATE).and_then(http_date).unwrap_or(now);
+        // Invalid dates, like `0`, mean already expired
+        http_date(expires).map_or(0, |expires| expires.saturating_sub(date))
+    } else {
+        return None;
+    };
+    let age = headers
+        .get(header::AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    Some(lifetime.saturating_sub(age))
+}
+
+/// A stored response
+struct Entry {
+    /// Status line and headers, without the blank line ending them
+    head: Bytes,
+    body: Bytes,
+    /// Unix time the response was received or last revalidated
+    stored: u64,
+    /// Seconds it stays fresh after `stored`
+    lifetime: u64,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Entry {
+    fn new(response: &Response<()>, body: Bytes, lifetime: u64, now: u64) -> Entry {
+        let status = response.status();
+        let mut head = BytesMut::new();
+        head.extend_from_slice(
+            format!(
+                "HTTP/1.1 {} {}\r\n",
+                status.as_str(),
+                status.canonical_reason().unwrap_or("")
+            )
+            .as_bytes(),
+        );
+        for (name, value) in response.headers() {
+            if HOP_BY_HOP.contains(name) || *name == header::AGE {
+                continue;
+            }
+            head.extend_from_slice(name.as_str().as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+
+        let text = |name: HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        Entry {
+            head: head.freeze(),
+            body,
+            stored: now,
+            lifetime,
+            etag: text(header::ETAG),
+            last_modified: text(header::LAST_MODIFIED),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.head.len() + self.body.len()
+    }
+
+    fn is_fresh(&self, now: u64) -> bool {
+        now.saturating_sub(self.stored) < self.lifetime
+    }
+
+    /// The response as sent to the client, with its current `Age`
+    fn response(&self, now: u64) -> Bytes {
+        let age = format!("Age: {}\r\n\r\n", now.saturating_sub(self.stored));
+        let mut buf = BytesMut::with_capacity(self.size() + age.len());
+        buf.put_slice(&self.head);
+        buf.put_slice(age.as_bytes());
+        buf.put_slice(&self.body);
+        buf.freeze()
+    }
+
+    /// Conditional headers asking the server whether the entry is still valid
+    fn validators(&self) -> Option<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = self.etag.as_ref().and_then(|v| v.parse().ok()) {
+            headers.insert(header::IF_NONE_MATCH, value);
+        }
+        if let Some(value) = self.last_modified.as_ref().and_then(|v| v.parse().ok()) {
+            headers.insert(header::IF_MODIFIED_SINCE, value);
+        }
+        if headers.is_empty() {
+            None
+        } else {
+            Some(headers)
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}\n",
+            DISK_MAGIC,
+            self.stored,
+            self.lifetime,
+            self.head.len(),
+            self.etag.as_ref().map_or("", String::as_str),
+            self.last_modified.as_ref().map_or("", String::as_str),
+        )
+        .into_bytes();
+        buf.extend_from_slice(&self.head);
+        buf.extend_from_slice(&self.body);
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Option<Entry> {
+        let mut lines = Vec::with_capacity(6);
+        let mut rest = data;
+        for _ in 0..6 {
+            let end = rest.iter().position(|b| *b == b'\n')?;
+            lines.push(std::str::from_utf8(&rest[..end]).ok()?);
+            rest = &rest[end + 1..];
+        }
+        if lines[0] != DISK_MAGIC {
+            return None;
+        }
+        let head_len = lines[3]
+            .parse::<usize>()
+            .ok()
+            .filter(|l| *l <= rest.len())?;
+        let text = |s: &str| {
+            if s.is_empty() {
+                None
+            } else {
+                Some(s.to_owned())
+            }
+        };
+        Some(Entry {
+            head: Bytes::copy_from_slice(&rest[..head_len]),
+            body: Bytes::copy_from_slice(&rest[head_len..]),
+            stored: lines[1].parse().ok()?,
+            lifetime: lines[2].parse().ok()?,
+            etag: text(lines[4]),
+            last_modified: text(lines[5]),
+        })
+    }
+}
+
+struct Memory {
+    entries: LruCache<String, Arc<Entry>>,
+    size: usize,
+}
+
+/// Entries evicted from memory, one file each
+struct Disk {
+    path: PathBuf,
+    limit: u64,
+}
+
+impl Disk {
+    fn file(&self, key: &str) -> PathBuf {
+        let mut hasher = SipHasher::new();
+        hasher.write(key.as_bytes());
+        self.path.join(format!("{:016x}", hasher.finish()))
+    }
+
+    fn get(&self, key: &str) -> Option<Entry> {
+        fs::read(self.file(key))
+            .ok()
+            .and_then(|data| Entry::decode(&data))
+    }
+
+    fn put(&self, key: &str, entry: &Entry) {
+        if let Err(e) = state::write_atomic(self.file(key), &entry.encode()) {
+            warn!("Failed to write HTTP cache entry, err: {}", e);
+            return;
+        }
+        if let Err(e) = self.trim() {
+            warn!("Failed to trim HTTP cache, err: {}", e);
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let _ = fs::remove_file(self.file(key));
+    }
+
+    /// Remove the oldest files until the directory fits its limit
+    fn trim(&self) -> io::Result<()> {
+        let mut files = Vec::new();
+        let mut total = 0;
+        for file in fs::read_dir(&self.path)? {
+            let file = file?;
+            let meta = file.metadata()?;
+            total += meta.len();
+            files.push((meta.modified()?, meta.len(), file.path()));
+        }
+        if total <= self.limit {
+            return Ok(());
+        }
+        files.sort();
+        for (_, len, path) in files {
+            if total <= self.limit {
+                break;
+            }
+            fs::remove_file(path)?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of looking up a request
+pub enum Lookup {
+    /// Serialized response to send to the client as is
+    Fresh(Bytes),
+    /// Stale entry, forward the request with these conditional headers and
+    /// hand a `304 Not Modified` to [`HttpCache::revalidated`]
+    Stale(HeaderMap),
+    Miss,
+}
+
+pub struct HttpCache {
+    memory: Mutex<Memory>,
+    memory_limit: usize,
+    disk: Option<Disk>,
+    max_object_size: usize,
+}
+
+impl HttpCache {
+    pub fn new(config: &HttpCacheConfig) -> HttpCache {
+        let disk = config.disk_path.as_ref().and_then(|path| {
+            if let Err(e) = fs::create_dir_all(path) {
+                warn!("HTTP cache stays in memory, can't use {}, err: {}", path, e);
+                return None;
+            }
+            Some(Disk {
+                path: PathBuf::from(path),
+                limit: config.disk_size.unwrap_or(DEFAULT_DISK_SIZE),
+            })
+        });
+        HttpCache {
+            memory: Mutex::new(Memory {
+                entries: LruCache::new(usize::MAX),
+                size: 0,
+            }),
+            memory_limit: config.memory_size.unwrap_or(DEFAULT_MEMORY_SIZE),
+            disk,
+            max_object_size: config.max_object_size.unwrap_or(DEFAULT_MAX_OBJECT_SIZE),
+        }
+    }
+
+    /// Key of cacheable requests, GETs in absolute form without credentials
+    fn key(request: &Request<()>) -> Option<String> {
+        if request.method() != Method::GET
+            || request.uri().scheme_str().is_none()
+            || request.headers().contains_key(header::AUTHORIZATION)
+            || directive(request.headers(), "no-store").is_some()
+        {
+            return None;
+        }
+        Some(request.uri().to_string())
+    }
+
+    fn get(&self, key: &str) -> Option<Arc<Entry>> {
+        if let Some(entry) = self.memory.lock().unwrap().entries.get_mut(key) {
+            return Some(entry.clone());
+        }
+        let entry = Arc::new(self.disk.as_ref()?.get(key)?);
+        self.insert(key.to_owned(), entry.clone());
+        Some(entry)
+    }
+
+    fn insert(&self, key: String, entry: Arc<Entry>) {
+        let mut evicted = Vec::new();
+        {
+            let mut memory = self.memory.lock().unwrap();
+            memory.size += entry.size();
+            if let Some(old) = memory.entries.insert(key, entry) {
+                memory.size -= old.size();
+            }
+            while memory.size > self.memory_limit {
+                match memory.entries.remove_lru() {
+                    Some((key, entry)) => {
+                        memory.size -= entry.size();
+                        evicted.push((key, entry));
+                    }
+                    None => break,
+                }
+            }
+        }
+        if let Some(ref disk) = self.disk {
+            for (key, entry) in evicted {
+                disk.put(&key, &entry);
+            }
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        {
+            let mut memory = self.memory.lock().unwrap();
+            if let Some(old) = memory.entries.remove(key) {
+                memory.size -= old.size();
+            }
+        }
+        if let Some(ref disk) = self.disk {
+            disk.remove(key);
+        }
+    }
+
+    /// Largest body kept
+    pub fn max_object_size(&self) -> usize {
+        self.max_object_size
+    }
+
+    pub fn lookup(&self, request: &Request<()>) -> Lookup {
+        self.lookup_at(request, now())
+    }
+
+    fn lookup_at(&self, request: &Request<()>, now: u64) -> Lookup {
+        let entry = match Self::key(request).and_then(|key| self.get(&key)) {
+            Some(entry) => entry,
+            None => return Lookup::Miss,
+        };
+        let revalidate = directive(request.headers(), "no-cache").is_some()
+            || request
+                .headers()
+                .get(header::PRAGMA)
+                .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"no-cache"));
+        if entry.is_fresh(now) && !revalidate {
+            debug!("HTTP cache hit {}", request.uri());
+            return Lookup::Fresh(entry.response(now));
+        }
+        match entry.validators() {
+            Some(validators) => Lookup::Stale(validators),
+            None => Lookup::Miss,
+        }
+    }
+
+    /// Keep the response to `request` if it may be reused
+    pub fn store(&self, request: &Request<()>, response: &Response<()>, body: Bytes) {
+        self.store_at(request, response, body, now())
+    }
+
+    fn store_at(&self, request: &Request<()>, response: &Response<()>, body: Bytes, now: u64) {
+        let key = match Self::key(request) {
+            Some(key) => key,
+            None => return,
+        };
+        let headers = response.headers();
+        if !CACHEABLE_STATUS.contains(&response.status().as_u16())
+            || headers.contains_key(header::VARY)
+            || headers.contains_key(header::SET_COOKIE)
+            || body.len() > self.max_object_size
+        {
+            return;
+        }
+        let lifetime = match freshness_lifetime(headers, now) {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+        let entry = Entry::new(response, body, lifetime, now);
+        if lifetime == 0 && entry.validators().is_none() {
+            return;
+        }
+        self.insert(key, Arc::new(entry));
+    }
+
+    /// The stored response, fresh again after the server answered the
+    /// conditional request with `response`
+    pub fn revalidated(&self, request: &Request<()>, response: &Response<()>) -> Option<Bytes> {
+        let now = now();
+        let key = Self::key(request)?;
+        let entry = self.get(&key)?;
+        let entry = Entry {
+            head: entry.head.clone(),
+            body: entry.body.clone(),
+            stored: now,
+            lifetime: freshness_lifetime(response.headers(), now).unwrap_or(entry.lifetime),
+            etag: entry.etag.clone(),
+            last_modified: entry.last_modified.clone(),
+        };
+        let bytes = entry.response(now);
+        self.insert(key, Arc::new(entry));
+        Some(bytes)
+    }
+
+    /// Drop the entry of a URI changed by an unsafe request, RFC 7234 section 4.4
+    pub fn invalidate(&self, request: &Request<()>) {
+        let method = request.method();
+        if method == Method::POST
+            || method == Method::PUT
+            || method == Method::PATCH
+            || method == Method::DELETE
+        {
+            self.remove(&request.uri().to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cache() -> HttpCache {
+        HttpCache::new(&HttpCacheConfig {
+            memory_size: Some(1024),
+            disk_path: None,
+            disk_size: None,
+            max_object_size: None,
+        })
+    }
+
+    fn get(uri: &str) -> Request<()> {
+        Request::get(uri).body(()).unwrap()
+    }
+
+    fn response(cache_control: &str) -> Response<()> {
+        Response::builder()
+            .header(header::CACHE_CONTROL, cache_control)
+            .header(header::ETAG, "\"v1\"")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn fresh_stale_and_uncacheable() {
+        let cache = cache();
+        let request = get("http://example.com/a");
+        cache.store_at(
+            &request,
+            &response("max-age=60"),
+            Bytes::from("hello"),
+            1000,
+        );
+
+        match cache.lookup_at(&request, 1030) {
+            Lookup::Fresh(bytes) => {
+                let text = String::from_utf8(bytes.to_vec()).unwrap();
+                assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+                assert!(text.contains("Age: 30\r\n"));
+                assert!(text.ends_with("\r\n\r\nhello"));
+            }
+            _ => panic!("expected a fresh entry"),
+        }
+        match cache.lookup_at(&request, 1060) {
+            Lookup::Stale(validators) => assert_eq!(validators[header::IF_NONE_MATCH], "\"v1\""),
+            _ => panic!("expected a stale entry"),
+        }
+
+        let private = get("http://example.com/b");
+        cache.store_at(
+            &private,
+            &response("private, max-age=60"),
+            Bytes::new(),
+            1000,
+        );
+        assert!(matches!(cache.lookup_at(&private, 1000), Lookup::Miss));
+
+        cache.invalidate(&Request::post("http://example.com/a").body(()).unwrap());
+        assert!(matches!(cache.lookup_at(&request, 1030), Lookup::Miss));
+    }
+
+    #[test]
+    fn disk_format_round_trips() {
+        let entry = Entry::new(&response("max-age=5"), Bytes::from("body"), 5, 42);
+        let decoded = Entry::decode(&entry.encode()).unwrap();
+        assert_eq!(decoded.head, entry.head);
+        assert_eq!(decoded.body, entry.body);
+        assert_eq!((decoded.stored, decoded.lifetime), (42, 5));
+        assert_eq!(decoded.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(decoded.last_modified, None);
+    }
+}
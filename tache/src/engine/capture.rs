@@ -0,0 +1,440 @@
+//! Packet captures for debugging, written as pcap files Wireshark opens
+//!
+//! A capture is started from the API and runs for a limited time. Streams it
+//! selects are written as made up TCP segments between the client and the
+//! destination, only the first bytes of each direction. Packets of TUN
+//! inbounds are written as read, cut to the same length.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::ready;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::ConnectionMeta;
+use crate::{inbounds::tun::packet::checksum, outbound::BoxStream};
+
+/// Raw IP packets, the version is in the first nibble
+const LINKTYPE_RAW: u32 = 101;
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 600;
+/// Kept of each direction of a stream, or of each packet
+const DEFAULT_BYTES: usize = 4096;
+/// Size at which the file stops growing
+const MAX_FILE_LEN: u64 = 64 * 1024 * 1024;
+/// Largest payload of a made up segment
+const SEGMENT_LEN: usize = 1460;
+
+const TCP: u8 = 6;
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+/// What to capture, everything when empty
+#[derive(Deserialize, Debug, Default)]
+pub struct Filter {
+    /// How long to run, 30 unless given and at most 600
+    pub seconds: Option<u64>,
+    /// Bytes kept of each direction of a stream or of each packet
+    pub bytes: Option<usize>,
+    /// Destination domain with its subdomains, or address
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub inbound: Option<String>,
+    /// Capture packets of TUN inbounds too
+    #[serde(default)]
+    pub tun: bool,
+}
+
+impl Filter {
+    fn matches(&self, meta: &ConnectionMeta) -> bool {
+        if self.inbound.as_ref().is_some_and(|i| *i != meta.inbound) {
+            return false;
+        }
+        if self.port.is_some_and(|p| p != meta.dst_port) {
+            return false;
+        }
+        self.host.as_ref().is_none_or(|host| {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            let name = meta.host.to_ascii_lowercase();
+            name == host
+                || name.ends_with(&format!(".{}", host))
+                || meta.dst_addr.is_some_and(|a| a.ip().to_string() == host)
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub path: PathBuf,
+    pub running: bool,
+    /// Seconds until the capture stops
+    pub remaining: u64,
+    pub packets: u64,
+    /// Size of the file
+    pub bytes: u64,
+}
+
+struct Output {
+    /// Closed once stopped, expired or full
+    file: Option<File>,
+    len: u64,
+    packets: u64,
+}
+
+struct Session {
+    filter: Filter,
+    snap_len: usize,
+    path: PathBuf,
+    deadline: Instant,
+    output: Mutex<Output>,
+}
+
+impl Session {
+    fn running(&self) -> bool {
+        self.output.lock().unwrap().file.is_some() && Instant::now() < self.deadline
+    }
+
+    /// Append one record of `data`, from a packet of `orig_len` bytes
+    fn write(&self, data: &[u8], orig_len: usize) {
+        let mut output = self.output.lock().unwrap();
+        if Instant::now() >= self.deadline || output.len >= MAX_FILE_LEN {
+            output.file = None;
+        }
+        let file = match output.file {
+            Some(ref mut file) => file,
+            None => return,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + data.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(orig_len as u32).to_le_bytes());
+        record.extend_from_slice(data);
+        if file.write_all(&record).is_err() {
+            output.file = None;
+            return;
+        }
+        output.len += record.len() as u64;
+        output.packets += 1;
+    }
+
+    fn status(&self) -> Status {
+        let output = self.output.lock().unwrap();
+        let now = Instant::now();
+        Status {
+            path: self.path.clone(),
+            running: output.file.is_some() && now < self.deadline,
+            remaining: if now < self.deadline {
+                (self.deadline - now).as_secs()
+            } else {
+                0
+            },
+            packets: output.packets,
+            bytes: output.len,
+        }
+    }
+}
+
+/// The capture running or last run, one at a time
+#[derive(Default)]
+pub struct Capture {
+    session: Mutex<Option<Arc<Session>>>,
+}
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture::default()
+    }
+
+    /// Start capturing into a new file in the temporary directory
+    pub fn start(&self, filter: Filter) -> io::Result<Status> {
+        let mut session = self.session.lock().unwrap();
+        if session.as_ref().is_some_and(|s| s.running()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a capture is running",
+            ));
+        }
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let path = std::env::temp_dir().join(format!("tache-{}.pcap", started.as_millis()));
+        let snap_len = filter.bytes.unwrap_or(DEFAULT_BYTES).max(1);
+        let mut file = File::create(&path)?;
+        // Microsecond timestamps, version 2.4
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&0xffffu32.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+
+        let seconds = filter.seconds.unwrap_or(DEFAULT_SECONDS).min(MAX_SECONDS);
+        let started = Arc::new(Session {
+            filter,
+            snap_len,
+            path,
+            deadline: Instant::now() + Duration::from_secs(seconds),
+            output: Mutex::new(Output {
+                file: Some(file),
+                len: header.len() as u64,
+                packets: 0,
+            }),
+        });
+        let status = started.status();
+        *session = Some(started);
+        Ok(status)
+    }
+
+    /// Stop the running capture early, keeping what it wrote
+    pub fn stop(&self) -> Option<Status> {
+        let session = self.session.lock().unwrap();
+        let session = session.as_ref()?;
+        session.output.lock().unwrap().file = None;
+        Some(session.status())
+    }
+
+    pub fn status(&self) -> Option<Status> {
+        let session = self.session.lock().unwrap();
+        session.as_ref().map(|s| s.status())
+    }
+
+    fn running(&self) -> Option<Arc<Session>> {
+        let session = self.session.lock().unwrap();
+        session.as_ref().filter(|s| s.running()).cloned()
+    }
+
+    /// Record the outbound stream of `meta` when the capture selects it
+    pub fn wrap(&self, meta: &ConnectionMeta, stream: BoxStream) -> BoxStream {
+        let session = match self.running().filter(|s| s.filter.matches(meta)) {
+            Some(session) => session,
+            None => return stream,
+        };
+        let client = meta
+            .src_addr
+            .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+        // Domains are resolved by the outbound, their address isn't known
+        let server = meta
+            .dst_addr
+            .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), meta.dst_port));
+        let mut flow = Flow {
+            session,
+            ends: [client, server],
+            seq: [0, 0],
+            left: [0, 0],
+        };
+        flow.open();
+        Box::new(Captured { stream, flow })
+    }
+
+    /// Record a packet read from or written to the TUN inbound `inbound`
+    pub fn packet(&self, inbound: &str, packet: &[u8]) {
+        let session = match self.running() {
+            Some(session) => session,
+            None => return,
+        };
+        let filter = &session.filter;
+        if !filter.tun || filter.inbound.as_ref().is_some_and(|i| i != inbound) {
+            return;
+        }
+        let len = packet.len().min(session.snap_len);
+        session.write(&packet[..len], packet.len());
+    }
+}
+
+/// Made up TCP connection carrying what a stream sent, 0 is the client
+struct Flow {
+    session: Arc<Session>,
+    ends: [SocketAddr; 2],
+    seq: [u32; 2],
+    /// Bytes each direction may still write
+    left: [usize; 2],
+}
+
+impl Flow {
+    fn open(&mut self) {
+        self.segment(0, TCP_SYN, &[]);
+        self.seq[0] = 1;
+        self.segment(1, TCP_SYN | TCP_ACK, &[]);
+        self.seq[1] = 1;
+        self.segment(0, TCP_ACK, &[]);
+        self.left = [self.session.snap_len; 2];
+    }
+
+    fn record(&mut self, from: usize, data: &[u8]) {
+        let len = data.len().min(self.left[from]);
+        if len == 0 || !self.session.running() {
+            return;
+        }
+        self.left[from] -= len;
+        for chunk in data[..len].chunks(SEGMENT_LEN) {
+            self.segment(from, TCP_ACK, chunk);
+            self.seq[from] = self.seq[from].wrapping_add(chunk.len() as u32);
+        }
+    }
+
+    fn segment(&self, from: usize, flags: u8, payload: &[u8]) {
+        let (src, dst) = (self.ends[from], self.ends[1 - from]);
+        let ack = if flags & TCP_ACK != 0 {
+            self.seq[1 - from]
+        } else {
+            0
+        };
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend_from_slice(&src.port().to_be_bytes());
+        tcp.extend_from_slice(&dst.port().to_be_bytes());
+        tcp.extend_from_slice(&self.seq[from].to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+
+        let packet = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let mut pseudo = Vec::with_capacity(12);
+                pseudo.extend_from_slice(&src.octets());
+                pseudo.extend_from_slice(&dst.octets());
+                pseudo.extend_from_slice(&[0, TCP]);
+                pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+                let check = checksum(&[&pseudo, &tcp]);
+                tcp[16..18].copy_from_slice(&check.to_be_bytes());
+
+                let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, TCP, 0, 0];
+                packet[2..4].copy_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+                packet.extend_from_slice(&src.octets());
+                packet.extend_from_slice(&dst.octets());
+                let check = checksum(&[&packet]);
+                packet[10..12].copy_from_slice(&check.to_be_bytes());
+                packet.extend_from_slice(&tcp);
+                packet
+            }
+            (src, dst) => {
+                let v6 = |ip: IpAddr| match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                let (src, dst) = (v6(src).octets(), v6(dst).octets());
+                let mut pseudo = Vec::with_capacity(40);
+                pseudo.extend_from_slice(&src);
+                pseudo.extend_from_slice(&dst);
+                pseudo.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+                pseudo.extend_from_slice(&[0, 0, 0, TCP]);
+                let check = checksum(&[&pseudo, &tcp]);
+                tcp[16..18].copy_from_slice(&check.to_be_bytes());
+
+                let mut packet = vec![0x60, 0, 0, 0, 0, 0, TCP, 64];
+                packet[4..6].copy_from_slice(&(tcp.len() as u16).to_be_bytes());
+                packet.extend_from_slice(&src);
+                packet.extend_from_slice(&dst);
+                packet.extend_from_slice(&tcp);
+                packet
+            }
+        };
+        self.session.write(&packet, packet.len());
+    }
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        if self.session.running() {
+            self.segment(0, TCP_FIN | TCP_ACK, &[]);
+        }
+    }
+}
+
+/// Outbound stream recording what goes through it
+struct Captured {
+    stream: BoxStream,
+    flow: Flow,
+}
+
+impl AsyncRead for Captured {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.stream).poll_read(cx, buf))?;
+        self.flow.record(1, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Captured {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.stream).poll_write(cx, buf))?;
+        self.flow.record(0, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn writes_made_up_segments() {
+        let session = Arc::new(Session {
+            filter: Filter::default(),
+            snap_len: 3,
+            path: PathBuf::new(),
+            deadline: Instant::now() + Duration::from_secs(60),
+            output: Mutex::new(Output {
+                file: Some(tempfile()),
+                len: 0,
+                packets: 0,
+            }),
+        });
+        let mut flow = Flow {
+            session: session.clone(),
+            ends: [
+                "10.0.0.2:50000".parse().unwrap(),
+                "[2001:db8::1]:443".parse().unwrap(),
+            ],
+            seq: [0, 0],
+            left: [0, 0],
+        };
+        flow.open();
+        flow.record(0, b"hello");
+        flow.record(0, b"again");
+        assert_eq!(flow.seq, [4, 1]);
+        drop(flow);
+        let status = session.status();
+        // Handshake, 3 bytes of data and the FIN, IPv6 with mapped addresses
+        assert_eq!(status.packets, 5);
+        assert_eq!(status.bytes, 5 * (16 + 60) + 3);
+    }
+
+    fn tempfile() -> File {
+        let path = std::env::temp_dir().join(format!("tache-test-{}.pcap", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        file
+    }
+}
@@ -0,0 +1,58 @@
+//! State handed from a context to the one replacing it on reloads and
+//! profile switches
+//!
+//! Fake addresses handed out keep mapping to their domains, so clients
+//! don't connect to addresses nobody knows until their DNS caches expire.
+//! UDP sessions of tunnels keep their upstream and are taken over by the new
+//! listener. The config is kept to tell what the next one changed.
+
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use super::{diff::ConfigDiff, tunnel::UdpSessions};
+use crate::{config::Config, dns::FakeIp};
+
+#[derive(Default)]
+pub struct Carryover {
+    /// Fake addresses of the context serving last
+    fake_ip: Mutex<Option<Arc<FakeIp>>>,
+    udp_sessions: Arc<UdpSessions>,
+    config: Mutex<Option<Config>>,
+    last_reload: Mutex<Option<ConfigDiff>>,
+}
+
+impl Carryover {
+    pub fn new() -> Carryover {
+        Carryover::default()
+    }
+
+    /// Pass the state on to a context of `config` with `fake_ip`
+    pub(crate) fn hand_over(&self, config: &Config, fake_ip: Option<Arc<FakeIp>>) {
+        let mut last = self.fake_ip.lock().unwrap();
+        if let (Some(old), Some(new)) = (last.as_ref(), fake_ip.as_ref()) {
+            info!("Kept {} fake addresses", new.restore(old));
+        }
+        if fake_ip.is_some() {
+            *last = fake_ip;
+        }
+        self.udp_sessions.keep(&config.tunnels);
+
+        let mut last_config = self.config.lock().unwrap();
+        if let Some(ref old) = *last_config {
+            let diff = ConfigDiff::new(old, config);
+            info!("Reloaded config, {}", diff);
+            *self.last_reload.lock().unwrap() = Some(diff);
+        }
+        *last_config = Some(config.clone());
+    }
+
+    /// Changes of the latest reload, `None` before the first one
+    pub fn last_reload(&self) -> Option<ConfigDiff> {
+        self.last_reload.lock().unwrap().clone()
+    }
+
+    pub(crate) fn udp_sessions(&self) -> Arc<UdpSessions> {
+        self.udp_sessions.clone()
+    }
+}
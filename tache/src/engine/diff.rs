@@ -0,0 +1,256 @@
+//! What a reload changed, logged and kept for `/configs/last-reload`
+//!
+//! Proxies, groups, inbounds and tunnels are told apart by name, rules by
+//! their `KIND,params,target` line. Inbounds and tunnels listening on other
+//! addresses are rebound, other top-level sections are listed by key when
+//! anything in them changed.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    config::{Config, InboundConfig, ProxyConfig, RuleConfig},
+    provider::{format_time, unix_now},
+};
+
+/// Keys of the sections compared item by item
+const ITEMIZED: &[&str] = &["proxies", "proxy-groups", "rules", "inbounds", "tunnels"];
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Changes {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changed: Vec<String>,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ConfigDiff {
+    /// Time the reload was applied
+    pub at: String,
+    pub proxies: Changes,
+    pub proxy_groups: Changes,
+    /// Added and removed rules, a moved rule is neither
+    pub rules: Changes,
+    pub inbounds: Changes,
+    pub tunnels: Changes,
+    /// Inbounds and tunnels whose listen addresses changed
+    pub rebound: Vec<String>,
+    /// Other top-level sections that changed, like `dns`
+    pub sections: Vec<String>,
+}
+
+fn value<T: Serialize>(item: &T) -> Value {
+    serde_json::to_value(item).unwrap_or(Value::Null)
+}
+
+/// Items of `old` and `new` matched up by `name`
+fn by_name<T, F>(old: &[T], new: &[T], name: F) -> Changes
+where
+    T: Serialize,
+    F: Fn(&T) -> &str,
+{
+    let old = old
+        .iter()
+        .map(|item| (name(item), item))
+        .collect::<HashMap<_, _>>();
+    let mut changes = Changes::default();
+    for item in new {
+        match old.get(name(item)) {
+            None => changes.added.push(name(item).to_owned()),
+            Some(before) if value(*before) != value(item) => {
+                changes.changed.push(name(item).to_owned())
+            }
+            Some(_) => {}
+        }
+    }
+    let new = new.iter().map(name).collect::<BTreeSet<_>>();
+    let mut removed = old
+        .keys()
+        .filter(|n| !new.contains(*n))
+        .map(|n| (*n).to_owned())
+        .collect::<Vec<_>>();
+    removed.sort();
+    changes.removed = removed;
+    changes
+}
+
+/// Rule lines only in one of the lists, counting duplicates
+fn rules(old: &[RuleConfig], new: &[RuleConfig]) -> Changes {
+    let mut count = HashMap::new();
+    for rule in new {
+        *count.entry(rule.line()).or_insert(0i64) += 1;
+    }
+    let mut changes = Changes::default();
+    for rule in old {
+        let line = rule.line();
+        match count.get_mut(&line) {
+            Some(n) if *n > 0 => *n -= 1,
+            _ => changes.removed.push(line),
+        }
+    }
+    for rule in new {
+        let line = rule.line();
+        if let Some(n) = count.get_mut(&line) {
+            if *n > 0 {
+                *n -= 1;
+                changes.added.push(line);
+            }
+        }
+    }
+    changes
+}
+
+impl ConfigDiff {
+    pub fn new(old: &Config, new: &Config) -> ConfigDiff {
+        let inbounds = by_name(&old.inbounds, &new.inbounds, InboundConfig::name);
+        let tunnels = by_name(&old.tunnels, &new.tunnels, |t| &t.name);
+        let mut rebound = Vec::new();
+        for name in inbounds.changed.iter() {
+            let listen = |config: &Config| {
+                config
+                    .inbounds
+                    .iter()
+                    .find(|i| i.name() == name)
+                    .map(|i| value(&i.listen()))
+            };
+            if listen(old) != listen(new) {
+                rebound.push(name.clone());
+            }
+        }
+        for name in tunnels.changed.iter() {
+            let listen = |config: &Config| {
+                config
+                    .tunnels
+                    .iter()
+                    .find(|t| t.name == *name)
+                    .map(|t| t.listen.to_string())
+            };
+            if listen(old) != listen(new) {
+                rebound.push(name.clone());
+            }
+        }
+
+        let mut sections = Vec::new();
+        if let (Value::Object(old), Value::Object(new)) = (value(old), value(new)) {
+            let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+            for key in keys {
+                if !ITEMIZED.contains(&key.as_str()) && old.get(key) != new.get(key) {
+                    sections.push(key.clone());
+                }
+            }
+        }
+
+        ConfigDiff {
+            at: format_time(unix_now()),
+            proxies: by_name(&old.proxies, &new.proxies, ProxyConfig::name),
+            proxy_groups: by_name(&old.proxy_groups, &new.proxy_groups, |g| &g.name),
+            rules: rules(&old.rules, &new.rules),
+            inbounds,
+            tunnels,
+            rebound,
+            sections,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proxies.is_empty()
+            && self.proxy_groups.is_empty()
+            && self.rules.is_empty()
+            && self.inbounds.is_empty()
+            && self.tunnels.is_empty()
+            && self.rebound.is_empty()
+            && self.sections.is_empty()
+    }
+}
+
+/// One line like `proxies +hk -us ~jp; rules +1 -2`
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("nothing changed");
+        }
+        let mut parts = Vec::new();
+        let mut named = |section: &str, changes: &Changes| {
+            if changes.is_empty() {
+                return;
+            }
+            let items = changes
+                .added
+                .iter()
+                .map(|n| format!("+{}", n))
+                .chain(changes.removed.iter().map(|n| format!("-{}", n)))
+                .chain(changes.changed.iter().map(|n| format!("~{}", n)))
+                .collect::<Vec<_>>();
+            parts.push(format!("{} {}", section, items.join(" ")));
+        };
+        named("proxies", &self.proxies);
+        named("proxy-groups", &self.proxy_groups);
+        named("inbounds", &self.inbounds);
+        named("tunnels", &self.tunnels);
+        if !self.rules.is_empty() {
+            parts.push(format!(
+                "rules +{} -{}",
+                self.rules.added.len(),
+                self.rules.removed.len()
+            ));
+        }
+        if !self.rebound.is_empty() {
+            parts.push(format!("rebound {}", self.rebound.join(" ")));
+        }
+        if !self.sections.is_empty() {
+            parts.push(format!("sections {}", self.sections.join(" ")));
+        }
+        f.write_str(&parts.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(s: &str) -> Config {
+        let base = "mode: rule\nlog-level: silent\nproxy-groups: []\n";
+        Config::load_from_str(&format!("{}{}", base, s)).unwrap()
+    }
+
+    #[test]
+    fn diffs_reloaded_config() {
+        let old = config(
+            "inbounds:\n  - { name: http, kind: http, listen: 127.0.0.1:7890 }\n\
+             proxies:\n  - { name: a, kind: socks5, address: a:1080 }\n  \
+             - { name: b, kind: socks5, address: b:1080 }\n\
+             rules:\n  - { kind: DOMAIN, params: [a.com], target: a }\n  \
+             - { kind: MATCH, target: DIRECT }\n",
+        );
+        let new = config(
+            "inbounds:\n  - { name: http, kind: http, listen: 127.0.0.1:7891 }\n\
+             proxies:\n  - { name: b, kind: socks5, address: b:1081 }\n  \
+             - { name: c, kind: socks5, address: c:1080 }\n\
+             rules:\n  - { kind: MATCH, target: DIRECT }\n\
+             strict: true\n",
+        );
+        let diff = ConfigDiff::new(&old, &new);
+        assert_eq!(diff.proxies.added, vec!["c"]);
+        assert_eq!(diff.proxies.removed, vec!["a"]);
+        assert_eq!(diff.proxies.changed, vec!["b"]);
+        assert_eq!(diff.rules.removed, vec!["DOMAIN,a.com,a"]);
+        assert!(diff.rules.added.is_empty());
+        assert_eq!(diff.rebound, vec!["http"]);
+        assert_eq!(diff.sections, vec!["strict"]);
+        assert!(ConfigDiff::new(&new, &new).is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+//! JA3 and JA4 fingerprints of TLS ClientHellos
+//!
+//! Both hash what a TLS library offers rather than where it connects, so
+//! flows of one application look alike across destinations. That tells apps
+//! apart where the process owning a connection can't be looked up, e.g. for
+//! clients on other hosts. GREASE values are left out, JA4 also sorts
+//! ciphers and extensions so their order, shuffled by some clients, doesn't
+//! matter.
+
+use md5::{Digest, Md5};
+use serde::Serialize;
+use sha2::Sha256;
+
+const SERVER_NAME: u16 = 0x0000;
+const ALPN: u16 = 0x0010;
+
+/// Fields of a ClientHello the fingerprints are made of
+#[derive(Debug, Default)]
+pub struct HelloFields {
+    pub legacy_version: u16,
+    /// Highest version offered, supported_versions included
+    pub version: u16,
+    pub ciphers: Vec<u16>,
+    /// Extension types in the order sent
+    pub extensions: Vec<u16>,
+    pub groups: Vec<u16>,
+    pub point_formats: Vec<u8>,
+    /// First protocol offered by ALPN
+    pub alpn: Option<Vec<u8>>,
+    pub signature_algorithms: Vec<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Fingerprint {
+    /// MD5 of the JA3 string, in hex
+    pub ja3: String,
+    pub ja4: String,
+}
+
+impl Fingerprint {
+    pub fn of(hello: &HelloFields) -> Fingerprint {
+        Fingerprint {
+            ja3: ja3(hello),
+            ja4: ja4(hello),
+        }
+    }
+}
+
+/// Reserved values clients sprinkle in to keep servers tolerant
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn no_grease(values: &[u16]) -> Vec<u16> {
+    values.iter().cloned().filter(|&v| !is_grease(v)).collect()
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `version,ciphers,extensions,groups,point formats` in decimal, hashed
+fn ja3(hello: &HelloFields) -> String {
+    let text = format!(
+        "{},{},{},{},{}",
+        hello.legacy_version,
+        join(&no_grease(&hello.ciphers)),
+        join(&no_grease(&hello.extensions)),
+        join(&no_grease(&hello.groups)),
+        join(&hello.point_formats),
+    );
+    hex(&Md5::digest(text.as_bytes()))
+}
+
+/// `t13d1516h2_<ciphers>_<extensions>`, see
+/// https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md
+fn ja4(hello: &HelloFields) -> String {
+    let ciphers = no_grease(&hello.ciphers);
+    let extensions = no_grease(&hello.extensions);
+    let version = match hello.version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        _ => "00",
+    };
+    let sni = if extensions.contains(&SERVER_NAME) {
+        'd'
+    } else {
+        'i'
+    };
+    let alpn = match hello.alpn {
+        Some(ref alpn) if !alpn.is_empty() => {
+            let (first, last) = (alpn[0], alpn[alpn.len() - 1]);
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                format!("{}{}", first as char, last as char)
+            } else {
+                let hex = hex(alpn);
+                format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+            }
+        }
+        _ => "00".to_owned(),
+    };
+    let a = format!(
+        "t{}{}{:02}{:02}{}",
+        version,
+        sni,
+        ciphers.len().min(99),
+        extensions.len().min(99),
+        alpn
+    );
+
+    let mut ciphers: Vec<_> = ciphers.iter().map(|c| format!("{:04x}", c)).collect();
+    ciphers.sort();
+    let mut extensions: Vec<_> = extensions
+        .iter()
+        .filter(|&&e| e != SERVER_NAME && e != ALPN)
+        .map(|e| format!("{:04x}", e))
+        .collect();
+    extensions.sort();
+    let mut c = extensions.join(",");
+    let algorithms = no_grease(&hello.signature_algorithms);
+    if !algorithms.is_empty() {
+        let algorithms: Vec<_> = algorithms.iter().map(|a| format!("{:04x}", a)).collect();
+        c.push('_');
+        c.push_str(&algorithms.join(","));
+    }
+    format!(
+        "{}_{}_{}",
+        a,
+        truncated_hash(&ciphers.join(",")),
+        truncated_hash(&c)
+    )
+}
+
+/// First 12 hex digits of the SHA-256 of `text`, zeros for nothing
+fn truncated_hash(text: &str) -> String {
+    if text.is_empty() {
+        return "0".repeat(12);
+    }
+    let mut hash = hex(&Sha256::digest(text.as_bytes()));
+    hash.truncate(12);
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprints_hellos() {
+        let hello = HelloFields {
+            legacy_version: 0x0303,
+            version: 0x0304,
+            ciphers: vec![0x1a1a, 0x1301, 0x1302, 0xc02b],
+            extensions: vec![0x2a2a, 0x0000, 0x0010, 0x000a, 0x000b, 0x000d, 0x002b],
+            groups: vec![0x3a3a, 0x001d, 0x0017],
+            point_formats: vec![0],
+            alpn: Some(b"h2".to_vec()),
+            signature_algorithms: vec![0x0403, 0x0804],
+        };
+        assert_eq!(
+            Fingerprint::of(&hello),
+            Fingerprint {
+                ja3: "3736761f91e3f9597a641ce4c92f256c".to_owned(),
+                ja4: "t13d0306h2_5559582ccdc4_fb71836bce29".to_owned(),
+            }
+        );
+
+        // No SNI, an ALPN that isn't printable, nothing hashed
+        let hello = HelloFields {
+            legacy_version: 0x0301,
+            version: 0x0301,
+            alpn: Some(vec![0xab, 0x01]),
+            ..HelloFields::default()
+        };
+        assert_eq!(
+            Fingerprint::of(&hello).ja4,
+            "t10i0000a1_000000000000_000000000000"
+        );
+    }
+}
@@ -0,0 +1,532 @@
+//! Plain HTTP requests of the HTTP inbound sent on to their origin
+//!
+//! Each request goes out in origin form on a connection of its own, its body
+//! framed the way the client framed it, and asks the origin to close once it
+//! answered. Clients waiting for `100 Continue` get it from here. The
+//! response is passed back as it arrives; the client connection takes the
+//! next request unless the client asked to close it or the response body
+//! only ends with the origin connection.
+//!
+//! Response headers are edited by the rewrites matching the URL first. With
+//! a cache, responses are kept while passed back and stale entries confirmed
+//! by a `304 Not Modified` go to the client in its place.
+
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use futures::{SinkExt, StreamExt};
+use http::{
+    header::{self, HeaderMap, HeaderName},
+    Method, Request, Response, StatusCode, Version,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::Framed;
+
+use super::{cache::HttpCache, rewrite::Rewrites};
+use crate::{
+    listener::InboundStream,
+    protocol::{self, Message, MAX_HEAD_LEN},
+};
+
+/// Most headers accepted in a response head
+const MAX_HEADERS: usize = 96;
+/// Longest chunk size or trailer line of a response
+const MAX_LINE_LEN: usize = 4096;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Values of the comma separated list headers `name`
+fn tokens<'a>(headers: &'a HeaderMap, name: &HeaderName) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+/// Whether the sender of `headers` closes the connection after the message
+fn closes(headers: &HeaderMap) -> bool {
+    tokens(headers, &header::CONNECTION).any(|v| v.eq_ignore_ascii_case("close"))
+}
+
+fn chunked(headers: &HeaderMap) -> bool {
+    tokens(headers, &header::TRANSFER_ENCODING)
+        .last()
+        .is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
+}
+
+/// Headers of one hop, the fixed ones and those `Connection` lists
+///
+/// `Transfer-Encoding` stays, bodies are passed on in their framing.
+fn hop_by_hop(headers: &HeaderMap) -> Vec<HeaderName> {
+    let mut names = vec![
+        header::CONNECTION,
+        HeaderName::from_static("keep-alive"),
+        HeaderName::from_static("proxy-connection"),
+        header::PROXY_AUTHENTICATE,
+        header::PROXY_AUTHORIZATION,
+        header::TE,
+        header::TRAILER,
+        header::UPGRADE,
+    ];
+    names.extend(
+        tokens(headers, &header::CONNECTION)
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok()),
+    );
+    names
+}
+
+fn write_headers(head: &mut Vec<u8>, headers: &HeaderMap, skip: &[HeaderName]) {
+    for (name, value) in headers.iter().filter(|(name, _)| !skip.contains(name)) {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Head of `request` as sent to the origin
+fn request_head(request: &Request<()>) -> Vec<u8> {
+    let uri = request.uri();
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method(), path).into_bytes();
+    let headers = request.headers();
+    if !headers.contains_key(header::HOST) {
+        if let Some(authority) = uri.authority() {
+            head.extend_from_slice(format!("Host: {}\r\n", authority).as_bytes());
+        }
+    }
+    let mut skip = hop_by_hop(headers);
+    // Answered here before the body is read
+    skip.push(header::EXPECT);
+    write_headers(&mut head, headers, &skip);
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+    head
+}
+
+/// Head of `response` as sent to the client
+fn response_head(response: &Response<()>, keep_alive: bool) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {}\r\n", response.status()).into_bytes();
+    write_headers(
+        &mut head,
+        response.headers(),
+        &hop_by_hop(response.headers()),
+    );
+    if !keep_alive {
+        head.extend_from_slice(b"Connection: close\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Edit the headers of `response` to a request for `url` by `rewrites`,
+/// the framing of the body stays the one of the origin
+fn rewrite(rewrites: &Rewrites, url: &str, response: &mut Response<()>) {
+    let headers = response.headers_mut();
+    let mut framing = HeaderMap::new();
+    for name in &[header::CONTENT_LENGTH, header::TRANSFER_ENCODING] {
+        for value in headers.get_all(name) {
+            framing.append(name.clone(), value.clone());
+        }
+    }
+    rewrites.response(url, headers);
+    headers.remove(header::CONTENT_LENGTH);
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.extend(framing);
+}
+
+/// Response head at the start of `buf`, removed from it once complete
+fn parse_head(buf: &mut BytesMut) -> io::Result<Option<Response<()>>> {
+    let (response, len) = {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let len = match parsed.parse(buf) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) if buf.len() >= MAX_HEAD_LEN => {
+                return Err(invalid("origin response head too large"))
+            }
+            Ok(httparse::Status::Partial) => return Ok(None),
+            Err(e) => return Err(invalid(&format!("invalid origin response: {:?}", e))),
+        };
+        let mut response = Response::builder()
+            .status(parsed.code.unwrap_or_default())
+            .version(Version::HTTP_11);
+        for h in parsed.headers.iter() {
+            response = response.header(h.name, h.value);
+        }
+        let response = response
+            .body(())
+            .map_err(|_| invalid("invalid origin response head"))?;
+        (response, len)
+    };
+    buf.advance(len);
+    Ok(Some(response))
+}
+
+/// Framing of a response body, RFC 7230 section 3.3.3
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Body {
+    Empty,
+    Length(u64),
+    Chunked,
+    /// Ends with the origin connection
+    Close,
+}
+
+fn response_body(request: &Request<()>, response: &Response<()>) -> io::Result<Body> {
+    let status = response.status();
+    if request.method() == Method::HEAD
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return Ok(Body::Empty);
+    }
+    let headers = response.headers();
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        return Ok(if chunked(headers) {
+            Body::Chunked
+        } else {
+            Body::Close
+        });
+    }
+    match headers.get(header::CONTENT_LENGTH) {
+        Some(len) => len
+            .to_str()
+            .ok()
+            .and_then(|len| len.trim().parse().ok())
+            .map(Body::Length)
+            .ok_or_else(|| invalid("invalid Content-Length from origin")),
+        None => Ok(Body::Close),
+    }
+}
+
+/// Body of a response being cached, dropped once over `limit`
+struct Kept {
+    body: Option<BytesMut>,
+    limit: usize,
+}
+
+impl Kept {
+    fn push(&mut self, data: &[u8]) {
+        if let Some(ref mut body) = self.body {
+            if body.len() + data.len() > self.limit {
+                self.body = None;
+            } else {
+                body.extend_from_slice(data);
+            }
+        }
+    }
+}
+
+/// Reads of the origin connection, with what was read ahead
+struct Origin<'a, S> {
+    stream: &'a mut S,
+    buf: BytesMut,
+    read: u64,
+    kept: Kept,
+}
+
+impl<S: AsyncRead + Unpin> Origin<'_, S> {
+    /// Read more into `buf`, `false` at EOF
+    async fn fill(&mut self) -> io::Result<bool> {
+        self.buf.reserve(4096);
+        let n = self.stream.read_buf(&mut self.buf).await?;
+        self.read += n as u64;
+        Ok(n > 0)
+    }
+
+    async fn fill_more(&mut self) -> io::Result<()> {
+        if self.fill().await? {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "origin closed within the response",
+            ))
+        }
+    }
+
+    async fn head(&mut self) -> io::Result<Response<()>> {
+        loop {
+            if let Some(response) = parse_head(&mut self.buf)? {
+                return Ok(response);
+            }
+            self.fill_more().await?;
+        }
+    }
+
+    /// Next line, with its CRLF
+    async fn line(&mut self) -> io::Result<BytesMut> {
+        loop {
+            if let Some(pos) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                return Ok(self.buf.split_to(pos + 2));
+            }
+            if self.buf.len() > MAX_LINE_LEN {
+                return Err(invalid("origin response line too long"));
+            }
+            self.fill_more().await?;
+        }
+    }
+
+    /// Pass `len` bytes on to `client`, kept as body data with `keep`
+    async fn copy<W>(&mut self, client: &mut W, mut len: u64, keep: bool) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        loop {
+            let n = len.min(self.buf.len() as u64) as usize;
+            client.write_all(&self.buf[..n]).await?;
+            if keep {
+                self.kept.push(&self.buf[..n]);
+            }
+            self.buf.advance(n);
+            len -= n as u64;
+            if len == 0 {
+                return Ok(());
+            }
+            self.fill_more().await?;
+        }
+    }
+
+    async fn copy_chunked<W>(&mut self, client: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        loop {
+            let line = self.line().await?;
+            client.write_all(&line).await?;
+            let size = line[..line.len() - 2]
+                .split(|b| *b == b';')
+                .next()
+                .and_then(|size| std::str::from_utf8(size).ok())
+                .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+                .ok_or_else(|| invalid("invalid chunk size from origin"))?;
+            if size == 0 {
+                break;
+            }
+            self.copy(client, size, true).await?;
+            self.copy(client, 2, false).await?;
+        }
+        // Trailer fields up to the empty line
+        loop {
+            let line = self.line().await?;
+            client.write_all(&line).await?;
+            if &line[..] == b"\r\n" {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn copy_to_end<W>(&mut self, client: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        loop {
+            client.write_all(&self.buf).await?;
+            self.kept.push(&self.buf);
+            self.buf.clear();
+            if !self.fill().await? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A request passed on to its origin
+pub struct Forwarded {
+    /// The client may send its next request on the connection
+    pub keep_alive: bool,
+    /// Bytes sent to and received from the origin
+    pub up: u64,
+    pub down: u64,
+}
+
+/// Send `request` read from `transport` and its body on to the origin over
+/// `origin`, and its response back to the client
+///
+/// Conditional headers of a stale entry in `cache` are sent along already.
+pub async fn forward<S>(
+    transport: &mut Framed<InboundStream, protocol::Http>,
+    request: &Request<()>,
+    origin: &mut S,
+    rewrites: &Rewrites,
+    cache: Option<&HttpCache>,
+) -> io::Result<Forwarded>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let head = request_head(request);
+    origin.write_all(&head).await?;
+    let mut up = head.len() as u64;
+    if protocol::expects_continue(request) {
+        transport.send(protocol::continue_response()).await?;
+    }
+    let chunks = chunked(request.headers());
+    loop {
+        match transport.next().await {
+            Some(Ok(Message::Body(data))) if chunks => {
+                let size = format!("{:x}\r\n", data.len());
+                origin.write_all(size.as_bytes()).await?;
+                origin.write_all(&data).await?;
+                origin.write_all(b"\r\n").await?;
+                up += (size.len() + data.len() + 2) as u64;
+            }
+            Some(Ok(Message::Body(data))) => {
+                origin.write_all(&data).await?;
+                up += data.len() as u64;
+            }
+            Some(Ok(Message::End)) => break,
+            Some(Ok(Message::Request(..))) => return Err(invalid("request within a body")),
+            Some(Err(e)) => return Err(e),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "client closed within the request body",
+                ))
+            }
+        }
+    }
+    if chunks {
+        origin.write_all(b"0\r\n\r\n").await?;
+        up += 5;
+    }
+    origin.flush().await?;
+
+    let mut origin = Origin {
+        stream: origin,
+        buf: BytesMut::new(),
+        read: 0,
+        kept: Kept {
+            body: cache.map(|_| BytesMut::new()),
+            limit: cache.map_or(0, HttpCache::max_object_size),
+        },
+    };
+    let client = transport.get_mut();
+    let mut response = loop {
+        let response = origin.head().await?;
+        // Interim responses go through as they are, the final one follows
+        if response.status().is_informational()
+            && response.status() != StatusCode::SWITCHING_PROTOCOLS
+        {
+            client.write_all(&response_head(&response, true)).await?;
+            continue;
+        }
+        break response;
+    };
+    let body = response_body(request, &response)?;
+    rewrite(rewrites, &request.uri().to_string(), &mut response);
+    let keep_alive = body != Body::Close && !closes(request.headers());
+    let revalidated = match cache {
+        Some(cache) if response.status() == StatusCode::NOT_MODIFIED => {
+            cache.revalidated(request, &response)
+        }
+        _ => None,
+    };
+    if let Some(cached) = revalidated {
+        client.write_all(&cached).await?;
+        client.flush().await?;
+        return Ok(Forwarded {
+            keep_alive,
+            up,
+            down: origin.read,
+        });
+    }
+    client
+        .write_all(&response_head(&response, keep_alive))
+        .await?;
+    match body {
+        Body::Empty => {}
+        Body::Length(len) => origin.copy(client, len, true).await?,
+        Body::Chunked => origin.copy_chunked(client).await?,
+        Body::Close => origin.copy_to_end(client).await?,
+    }
+    client.flush().await?;
+    if let (Some(cache), Some(kept)) = (cache, origin.kept.body.take()) {
+        cache.store(request, &response, kept.freeze());
+    }
+    Ok(Forwarded {
+        keep_alive,
+        up,
+        down: origin.read,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request_goes_out_in_origin_form() {
+        let request = Request::post("http://example.com:8080/a?b=1")
+            .header(header::CONNECTION, "keep-alive, x-hop")
+            .header("x-hop", "1")
+            .header(header::PROXY_AUTHORIZATION, "Basic Zm9vOmJhcg==")
+            .header(header::EXPECT, "100-continue")
+            .header(header::TRANSFER_ENCODING, "chunked")
+            .body(())
+            .unwrap();
+        let head = String::from_utf8(request_head(&request)).unwrap();
+        assert_eq!(
+            head,
+            "POST /a?b=1 HTTP/1.1\r\n\
+             Host: example.com:8080\r\n\
+             transfer-encoding: chunked\r\n\
+             Connection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn rewrites_leave_framing_alone() {
+        let configs: Vec<crate::config::RewriteConfig> = serde_yaml::from_str(
+            r#"
+- url: ^http://example\.com/
+  response-headers:
+    add: {X-Rewritten: "1"}
+    remove: [Content-Length, Set-Cookie]
+"#,
+        )
+        .unwrap();
+        let rewrites = Rewrites::new(&configs).unwrap();
+        let mut response = Response::builder()
+            .header(header::CONTENT_LENGTH, "5")
+            .header(header::SET_COOKIE, "a=b")
+            .body(())
+            .unwrap();
+        rewrite(&rewrites, "http://example.com/", &mut response);
+        let headers = response.headers();
+        assert_eq!(headers[header::CONTENT_LENGTH], "5");
+        assert_eq!(headers["x-rewritten"], "1");
+        assert!(!headers.contains_key(header::SET_COOKIE));
+    }
+
+    #[test]
+    fn response_framing() {
+        let get = Request::get("http://example.com/").body(()).unwrap();
+        let head = Request::head("http://example.com/").body(()).unwrap();
+        let mut buf = BytesMut::from(
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello"[..],
+        );
+        let response = parse_head(&mut buf).unwrap().unwrap();
+        assert_eq!(&buf[..], b"hello");
+        assert_eq!(response_body(&get, &response).unwrap(), Body::Length(5));
+        assert_eq!(response_body(&head, &response).unwrap(), Body::Empty);
+        assert_eq!(
+            response_head(&response, true),
+            b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n".to_vec()
+        );
+
+        let mut buf = BytesMut::from(&b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n"[..]);
+        assert!(parse_head(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"\r\n");
+        let response = parse_head(&mut buf).unwrap().unwrap();
+        assert_eq!(response_body(&get, &response).unwrap(), Body::Chunked);
+
+        let mut buf = BytesMut::from(&b"HTTP/1.1 200 OK\r\n\r\n"[..]);
+        let response = parse_head(&mut buf).unwrap().unwrap();
+        assert_eq!(response_body(&get, &response).unwrap(), Body::Close);
+    }
+}
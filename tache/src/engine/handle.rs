@@ -0,0 +1,314 @@
+//! Embedding API, drive the engine from another Rust application
+
+use std::{
+    error::Error as StdError,
+    fmt, io,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{
+    channel::{mpsc::UnboundedReceiver, oneshot},
+    future::{select, Either},
+};
+use log::error;
+
+use super::{carryover::Carryover, serve, tracker::CloseStats, traffic::Traffic};
+use crate::{
+    config::{self, Config, Mode},
+    context::Context,
+    event::{Event, EventBus},
+    rt::{self, Runtime},
+};
+
+/// How long `stop` waits for listeners to be closed
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a reload waits for the new listeners before keeping them
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Control of the serving task
+struct Serving {
+    stop: oneshot::Sender<()>,
+    /// Disconnects once the task ended and its listeners are closed
+    done: mpsc::Receiver<()>,
+    /// Why the task ended on its own
+    failure: Arc<Mutex<Option<String>>>,
+    context: Arc<Context>,
+}
+
+#[derive(Debug)]
+pub enum EngineError {
+    /// `build` was called without a config
+    MissingConfig,
+    AlreadyRunning,
+    NotRunning,
+    /// The engine was shut down and can't be used any more
+    ShutDown,
+    Config(config::Error),
+    /// The serving task ended right after it was started
+    Failed(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EngineError::MissingConfig => f.write_str("engine built without config"),
+            EngineError::AlreadyRunning => f.write_str("engine is already running"),
+            EngineError::NotRunning => f.write_str("engine is not running"),
+            EngineError::ShutDown => f.write_str("engine was shut down"),
+            EngineError::Config(ref e) => write!(f, "invalid config: {:?}", e),
+            EngineError::Failed(ref e) => write!(f, "engine failed to serve: {}", e),
+            EngineError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for EngineError {}
+
+impl From<io::Error> for EngineError {
+    fn from(e: io::Error) -> EngineError {
+        EngineError::Io(e)
+    }
+}
+
+#[derive(Default)]
+pub struct EngineBuilder {
+    config: Option<Config>,
+    runtime: Option<Runtime>,
+}
+
+impl EngineBuilder {
+    pub fn config(mut self, config: Config) -> EngineBuilder {
+        self.config = Some(config);
+        self
+    }
+
+    /// Serve on the given runtime instead of a new one built from the
+    /// `runtime` section of the config
+    pub fn runtime(mut self, runtime: Runtime) -> EngineBuilder {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    pub fn build(self) -> Result<Engine, EngineError> {
+        let config = self.config.ok_or(EngineError::MissingConfig)?;
+        let runtime = match self.runtime {
+            Some(runtime) => runtime,
+            None => rt::runtime(config.runtime.as_ref())?,
+        };
+        Ok(Engine {
+            config: Mutex::new(config),
+            runtime: Mutex::new(Some(runtime)),
+            serving: Mutex::new(None),
+            events: Arc::new(EventBus::new()),
+            traffic: Arc::new(Traffic::new()),
+            close_stats: Arc::new(CloseStats::new()),
+            carryover: Arc::new(Carryover::new()),
+        })
+    }
+}
+
+/// A tache instance serving on its own runtime
+///
+/// ```no_run
+/// use tache::{Config, Engine};
+///
+/// let engine = Engine::builder().config(Config::new()).build().unwrap();
+/// engine.start().unwrap();
+/// // ...
+/// engine.shutdown().unwrap();
+/// ```
+pub struct Engine {
+    config: Mutex<Config>,
+    runtime: Mutex<Option<Runtime>>,
+    /// Present while running
+    serving: Mutex<Option<Serving>>,
+    events: Arc<EventBus>,
+    /// Kept across reloads so totals keep growing
+    traffic: Arc<Traffic>,
+    close_stats: Arc<CloseStats>,
+    carryover: Arc<Carryover>,
+}
+
+impl Engine {
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder::default()
+    }
+
+    /// Set up inbounds from the current config and serve in the background
+    ///
+    /// Errors of the config itself are returned, failures while serving are
+    /// published as [`Event::Failed`].
+    pub fn start(&self) -> Result<(), EngineError> {
+        let config = self.config.lock().unwrap().clone();
+        let context = self.context(config)?;
+        self.serve(context)
+    }
+
+    /// Replace the config, restarting inbounds when running
+    ///
+    /// Established connections are kept, only new ones follow the new config.
+    /// Fake addresses and UDP sessions of unchanged tunnels carry over. A
+    /// config that is invalid or fails to serve is returned as an error and
+    /// the engine goes on with the previous one.
+    pub fn reload(&self, config: Config) -> Result<(), EngineError> {
+        let context = self.context(config.clone())?;
+        let previous = std::mem::replace(&mut *self.config.lock().unwrap(), config);
+        if self.stop_serving().is_ok() {
+            if let Err(e) = self.serve(context).and_then(|()| self.settle()) {
+                error!(
+                    "Reload failed, going back to the previous config, err: {}",
+                    e
+                );
+                *self.config.lock().unwrap() = previous;
+                let _ = self.stop_serving();
+                self.start()?;
+                return Err(e);
+            }
+        }
+        self.events.publish(Event::Reloaded);
+        Ok(())
+    }
+
+    /// Switch the mode of inbounds without their own, without restarting
+    /// anything
+    pub fn set_mode(&self, mode: Mode) {
+        self.config.lock().unwrap().mode = mode.clone();
+        if let Some(ref serving) = *self.serving.lock().unwrap() {
+            serving.context.set_mode(mode);
+        }
+    }
+
+    /// Stop serving, the engine can be started again later
+    pub fn stop(&self) -> Result<(), EngineError> {
+        self.stop_serving()
+    }
+
+    /// Stop serving and tear down the runtime with all its connections
+    pub fn shutdown(&self) -> Result<(), EngineError> {
+        let _ = self.stop_serving();
+        let runtime = self
+            .runtime
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(EngineError::ShutDown)?;
+        rt::shutdown(runtime);
+        self.events.publish(Event::Stopped);
+        Ok(())
+    }
+
+    /// The serving task drops its receiver once it failed
+    pub fn is_running(&self) -> bool {
+        self.serving
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|s| !s.stop.is_canceled())
+    }
+
+    pub fn config(&self) -> Config {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Total bytes sent and received since the engine was built
+    pub fn traffic(&self) -> (u64, u64) {
+        self.traffic.snapshot()
+    }
+
+    /// Receive engine events until the engine is dropped
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Validate the config and build what serving it needs
+    fn context(&self, config: Config) -> Result<Arc<Context>, EngineError> {
+        config.check_valid().map_err(EngineError::Config)?;
+        let mut context = Context::new(config)?;
+        context.set_traffic(self.traffic.clone());
+        context.set_close_stats(self.close_stats.clone());
+        context.set_carryover(self.carryover.clone());
+        context.set_events(self.events.clone());
+        Ok(Arc::new(context))
+    }
+
+    /// Spawn the serving task for `context`
+    fn serve(&self, context: Arc<Context>) -> Result<(), EngineError> {
+        let runtime = self.runtime.lock().unwrap();
+        let runtime = runtime.as_ref().ok_or(EngineError::ShutDown)?;
+        let mut serving = self.serving.lock().unwrap();
+        if serving.as_ref().is_some_and(|s| !s.stop.is_canceled()) {
+            return Err(EngineError::AlreadyRunning);
+        }
+
+        let (stop, stopped) = oneshot::channel();
+        let (done_tx, done) = mpsc::channel();
+        let failure = Arc::new(Mutex::new(None));
+        let events = self.events.clone();
+        let failed = failure.clone();
+        let serving_context = context.clone();
+        runtime.spawn(async move {
+            let _done = done_tx;
+            if let Either::Left((Err(e), _)) =
+                select(Box::pin(serve(serving_context)), stopped).await
+            {
+                error!("Engine stopped serving, err: {}", e);
+                *failed.lock().unwrap() = Some(e.to_string());
+                events.publish(Event::Failed {
+                    error: e.to_string(),
+                });
+            }
+        });
+        *serving = Some(Serving {
+            stop,
+            done,
+            failure,
+            context,
+        });
+        drop(serving);
+
+        self.events.publish(Event::Started);
+        Ok(())
+    }
+
+    /// Give the serving task a moment to bind its listeners, returning why
+    /// it ended when it did
+    fn settle(&self) -> Result<(), EngineError> {
+        let serving = self.serving.lock().unwrap();
+        let serving = serving.as_ref().ok_or(EngineError::NotRunning)?;
+        match serving.done.recv_timeout(SETTLE_TIME) {
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            _ => {
+                let failure = serving.failure.lock().unwrap().take();
+                Err(EngineError::Failed(failure.unwrap_or_default()))
+            }
+        }
+    }
+
+    /// Stop the serving task and wait until its listeners are closed, so
+    /// a restart can bind the same addresses
+    fn stop_serving(&self) -> Result<(), EngineError> {
+        let serving = self
+            .serving
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(EngineError::NotRunning)?;
+        // The task may already have ended on its own
+        let _ = serving.stop.send(());
+        let _ = serving.done.recv_timeout(STOP_TIMEOUT);
+        Ok(())
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.lock().unwrap().take() {
+            rt::shutdown(runtime);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Protection of inbounds against clients that connect but never finish
+//! their request, slow-loris style
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::debug;
+
+use crate::{config::HandshakeConfig, protocol, rt};
+
+/// Default seconds a new connection has to send its request
+const DEFAULT_TIMEOUT: u64 = 10;
+/// Default connections from one source ip waiting for their request
+const DEFAULT_MAX_HALF_OPEN: usize = 64;
+
+pub struct HandshakeGuard {
+    timeout: Duration,
+    max_header_size: usize,
+    max_half_open: usize,
+    half_open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// A connection that has not sent its request yet, counted until dropped
+pub struct HalfOpen {
+    guard: Arc<HandshakeGuard>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for HalfOpen {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            let mut half_open = self.guard.half_open.lock().unwrap();
+            let remove = match half_open.get_mut(&ip) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+            if remove {
+                half_open.remove(&ip);
+            }
+        }
+    }
+}
+
+impl HalfOpen {
+    /// Run `fut` unless the handshake timeout passes first
+    pub async fn timeout<F: Future>(&self, fut: F) -> io::Result<F::Output> {
+        rt::timeout(self.guard.timeout, fut)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))
+    }
+}
+
+impl HandshakeGuard {
+    pub fn new(config: Option<&HandshakeConfig>) -> HandshakeGuard {
+        HandshakeGuard {
+            timeout: Duration::from_secs(config.and_then(|c| c.timeout).unwrap_or(DEFAULT_TIMEOUT)),
+            max_header_size: config
+                .and_then(|c| c.max_header_size)
+                .unwrap_or(protocol::MAX_HEAD_LEN),
+            max_half_open: config
+                .and_then(|c| c.max_half_open_per_client)
+                .unwrap_or(DEFAULT_MAX_HALF_OPEN),
+            half_open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Request codec honoring the header size limit
+    pub fn codec(&self) -> protocol::Http {
+        protocol::Http::with_max_head_len(self.max_header_size)
+    }
+
+    /// Count a new connection from `ip`, `None` when the client already has
+    /// too many connections without a request and this one should be dropped
+    ///
+    /// Unix socket clients have no address and are not counted.
+    pub fn enter(self: &Arc<Self>, ip: Option<IpAddr>) -> Option<HalfOpen> {
+        if let Some(ip) = ip {
+            let mut half_open = self.half_open.lock().unwrap();
+            let count = half_open.entry(ip).or_insert(0);
+            if *count >= self.max_half_open {
+                debug!("Dropped connection, {} has {} half-open", ip, count);
+                return None;
+            }
+            *count += 1;
+        }
+        Some(HalfOpen {
+            guard: self.clone(),
+            ip,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn half_open_per_client() {
+        let guard = Arc::new(HandshakeGuard::new(Some(&HandshakeConfig {
+            timeout: None,
+            max_header_size: None,
+            max_half_open_per_client: Some(2),
+        })));
+        let ip = Some("192.168.1.2".parse().unwrap());
+
+        let first = guard.enter(ip).unwrap();
+        let _second = guard.enter(ip).unwrap();
+        assert!(guard.enter(ip).is_none());
+        assert!(guard.enter(Some("192.168.1.3".parse().unwrap())).is_some());
+        assert!(guard.enter(None).is_some());
+
+        drop(first);
+        assert!(guard.enter(ip).is_some());
+    }
+}
@@ -0,0 +1,258 @@
+//! Inbounds added and removed through the API while serving
+//!
+//! They are polled by `serve` like the configured ones, so reloads and
+//! profile switches drop them with the rest. Listen addresses are checked
+//! against every listener of the config and the added inbounds, then TCP
+//! ports are bound once on trial so one taken by another process fails the
+//! request rather than the listener later on.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{pending, select, select_all, BoxFuture, Either},
+    StreamExt,
+};
+use log::{error, info};
+
+use super::inbound_futures;
+use crate::{config::InboundConfig, context::SharedContext, utils::ListenAddress};
+
+struct Added {
+    id: u64,
+    config: InboundConfig,
+    /// Dropping it stops the listeners
+    _stop: oneshot::Sender<()>,
+}
+
+type Listener = BoxFuture<'static, ()>;
+
+pub struct AddedInbounds {
+    inbounds: Arc<Mutex<HashMap<String, Added>>>,
+    next_id: AtomicU64,
+    started: mpsc::UnboundedSender<Listener>,
+    /// Taken by `run`
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<Listener>>>,
+}
+
+impl Default for AddedInbounds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AddedInbounds {
+    pub fn new() -> AddedInbounds {
+        let (started, receiver) = mpsc::unbounded();
+        AddedInbounds {
+            inbounds: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicU64::new(0),
+            started,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+
+    pub fn list(&self) -> Vec<InboundConfig> {
+        let inbounds = self.inbounds.lock().unwrap();
+        let mut list: Vec<_> = inbounds.values().map(|a| a.config.clone()).collect();
+        list.sort_by(|a, b| a.name().cmp(b.name()));
+        list
+    }
+
+    pub fn get(&self, name: &str) -> Option<InboundConfig> {
+        let inbounds = self.inbounds.lock().unwrap();
+        inbounds.get(name).map(|a| a.config.clone())
+    }
+
+    /// Start listening for `inbound`
+    ///
+    /// Fails with `InvalidInput` for options the config wouldn't take,
+    /// `AlreadyExists` for names in use and `AddrInUse` for taken addresses.
+    pub fn add(&self, context: &SharedContext, inbound: InboundConfig) -> io::Result<()> {
+        let config = context.config();
+        config
+            .check_inbound(&inbound)
+            .map_err(|e| invalid(format!("{:?}", e)))?;
+        let listens = inbound.listen().to_vec();
+        if listens.is_empty() {
+            return Err(invalid("tun inbounds can't be added while serving"));
+        }
+        if let Some(proxy) = inbound.default_outbound() {
+            if context.outbound(proxy).is_none() {
+                return Err(invalid(format!("no outbound named {}", proxy)));
+            }
+        }
+
+        let mut inbounds = self.inbounds.lock().unwrap();
+        let name = inbound.name().to_owned();
+        if inbounds.contains_key(&name) || config.inbounds.iter().any(|i| i.name() == name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("inbound {} already exists", name),
+            ));
+        }
+        let mut taken: Vec<(String, ListenAddress)> = config
+            .inbounds
+            .iter()
+            .chain(inbounds.values().map(|a| &a.config))
+            .flat_map(|i| {
+                let owner = format!("inbound {}", i.name());
+                i.listen().iter().map(move |l| (owner.clone(), l.clone()))
+            })
+            .collect();
+        taken.extend(
+            config
+                .tunnels
+                .iter()
+                .map(|t| (format!("tunnel {}", t.name), t.listen.clone())),
+        );
+        if let Some(ref api) = config.api {
+            taken.push(("api".to_owned(), api.listen.clone()));
+        }
+        if let Some(ref dns) = config.dns {
+            taken.push(("dns".to_owned(), ListenAddress::Tcp(dns.listen.clone())));
+        }
+        for listen in listens.iter() {
+            for (owner, other) in taken.iter() {
+                if conflicts(listen, other) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} already listens on {}", owner, other),
+                    ));
+                }
+            }
+            try_bind(listen)?;
+        }
+
+        let listeners = inbound_futures(context.clone(), &inbound)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (stop, stopped) = oneshot::channel::<()>();
+        let registry = self.inbounds.clone();
+        let task_name = name.clone();
+        let task = async move {
+            match select(select_all(listeners), stopped).await {
+                Either::Left(((res, ..), _)) => {
+                    error!("Added inbound {} exited, result: {:?}", task_name, res);
+                    let mut inbounds = registry.lock().unwrap();
+                    if inbounds.get(&task_name).is_some_and(|a| a.id == id) {
+                        inbounds.remove(&task_name);
+                    }
+                }
+                Either::Right(..) => info!("Stopped inbound {}", task_name),
+            }
+        };
+        self.started
+            .unbounded_send(Box::pin(task))
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "not serving"))?;
+        info!("Added inbound {}", name);
+        inbounds.insert(
+            name,
+            Added {
+                id,
+                config: inbound,
+                _stop: stop,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stop the listeners of added inbound `name`, connections already
+    /// accepted keep running
+    pub fn remove(&self, name: &str) -> bool {
+        self.inbounds.lock().unwrap().remove(name).is_some()
+    }
+
+    /// Poll the listeners of added inbounds, never returns
+    pub async fn run(&self) {
+        let receiver = self.receiver.lock().unwrap().take();
+        match receiver {
+            Some(receiver) => {
+                receiver
+                    .for_each_concurrent(None, |listener| listener)
+                    .await
+            }
+            None => pending().await,
+        }
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+fn socket_addrs(listen: &ListenAddress) -> Vec<SocketAddr> {
+    match *listen {
+        ListenAddress::Tcp(ref addr) => addr
+            .to_socket_addrs()
+            .map(Iterator::collect)
+            .unwrap_or_default(),
+        ListenAddress::Unix(..) => Vec::new(),
+    }
+}
+
+/// Whether listening on `a` and `b` at once fails
+fn conflicts(a: &ListenAddress, b: &ListenAddress) -> bool {
+    if let (ListenAddress::Unix(a), ListenAddress::Unix(b)) = (a, b) {
+        return a == b;
+    }
+    let others = socket_addrs(b);
+    socket_addrs(a).iter().any(|a| {
+        others.iter().any(|b| {
+            a.port() == b.port()
+                && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+        })
+    })
+}
+
+fn try_bind(listen: &ListenAddress) -> io::Result<()> {
+    match *listen {
+        ListenAddress::Tcp(..) => {
+            let addrs = socket_addrs(listen);
+            if addrs.is_empty() {
+                return Err(invalid(format!("{} resolved to no address", listen)));
+            }
+            for addr in addrs {
+                TcpListener::bind(addr)?;
+            }
+            Ok(())
+        }
+        // Stale sockets are replaced when listening
+        ListenAddress::Unix(..) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_listen_conflicts() {
+        let listen = |s: &str| s.parse::<ListenAddress>().unwrap();
+        assert!(conflicts(
+            &listen("127.0.0.1:7890"),
+            &listen("0.0.0.0:7890")
+        ));
+        assert!(conflicts(&listen("[::]:7890"), &listen("127.0.0.1:7890")));
+        assert!(!conflicts(
+            &listen("127.0.0.1:7890"),
+            &listen("127.0.0.2:7890")
+        ));
+        assert!(!conflicts(&listen("0.0.0.0:7890"), &listen("0.0.0.0:7891")));
+        assert!(conflicts(
+            &listen("unix:///tmp/a.sock"),
+            &listen("unix:///tmp/a.sock")
+        ));
+        assert!(!conflicts(
+            &listen("unix:///tmp/a.sock"),
+            &listen("0.0.0.0:7890")
+        ));
+    }
+}
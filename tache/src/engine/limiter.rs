@@ -0,0 +1,234 @@
+//! Concurrent connection limits per destination host and per source client
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{
+    channel::oneshot,
+    future::{select, Either},
+};
+use log::debug;
+
+use crate::{
+    config::{LimitPolicy, LimiterConfig},
+    rt::delay_for,
+};
+
+/// Default seconds a queued connection waits for a free slot
+const DEFAULT_QUEUE_TIMEOUT: u64 = 10;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+enum Key {
+    Host(String),
+    Client(IpAddr),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Key::Host(ref host) => write!(f, "host {}", host),
+            Key::Client(ref ip) => write!(f, "client {}", ip),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Slots {
+    active: usize,
+    waiters: VecDeque<oneshot::Sender<()>>,
+}
+
+/// Connection refused by the limiter
+#[derive(Debug)]
+pub struct LimitExceeded(String);
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "too many concurrent connections for {}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+pub struct ConnectionLimiter {
+    per_host: Option<usize>,
+    per_client: Option<usize>,
+    policy: LimitPolicy,
+    queue_timeout: Duration,
+    slots: Mutex<HashMap<Key, Slots>>,
+}
+
+/// Held for the lifetime of a connection, releases its slots on drop
+pub struct Permit {
+    limiter: Arc<ConnectionLimiter>,
+    keys: Vec<Key>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        for key in self.keys.drain(..) {
+            self.limiter.release(key);
+        }
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: Option<&LimiterConfig>) -> ConnectionLimiter {
+        ConnectionLimiter {
+            per_host: config.and_then(|c| c.per_host),
+            per_client: config.and_then(|c| c.per_client),
+            policy: config.map(|c| c.policy.clone()).unwrap_or_default(),
+            queue_timeout: Duration::from_secs(
+                config
+                    .and_then(|c| c.queue_timeout)
+                    .unwrap_or(DEFAULT_QUEUE_TIMEOUT),
+            ),
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a slot for the source client and for the destination host
+    ///
+    /// Depending on the policy a full key either rejects right away or waits up to
+    /// the queue timeout for another connection to finish.
+    pub async fn acquire(
+        self: Arc<Self>,
+        host: &str,
+        client: Option<IpAddr>,
+    ) -> Result<Permit, LimitExceeded> {
+        let mut permit = Permit {
+            limiter: self.clone(),
+            keys: Vec::with_capacity(2),
+        };
+        if let (Some(limit), Some(ip)) = (self.per_client, client) {
+            let key = Key::Client(ip);
+            self.acquire_key(key.clone(), limit).await?;
+            permit.keys.push(key);
+        }
+        if let (Some(limit), false) = (self.per_host, host.is_empty()) {
+            let key = Key::Host(host.to_owned());
+            self.acquire_key(key.clone(), limit).await?;
+            permit.keys.push(key);
+        }
+        Ok(permit)
+    }
+
+    async fn acquire_key(&self, key: Key, limit: usize) -> Result<(), LimitExceeded> {
+        let rx = {
+            let mut slots = self.slots.lock().unwrap();
+            let entry = slots.entry(key.clone()).or_default();
+            if entry.active < limit {
+                entry.active += 1;
+                return Ok(());
+            }
+            if let LimitPolicy::Reject = self.policy {
+                debug!("Rejected connection, {} is at its limit of {}", key, limit);
+                return Err(LimitExceeded(key.to_string()));
+            }
+            let (tx, rx) = oneshot::channel();
+            entry.waiters.push_back(tx);
+            rx
+        };
+
+        // A released slot is handed over directly, `active` already accounts for us
+        match select(rx, delay_for(self.queue_timeout)).await {
+            Either::Left((Ok(()), _)) => Ok(()),
+            Either::Left((Err(_), _)) => Err(LimitExceeded(key.to_string())),
+            Either::Right((_, mut rx)) => {
+                rx.close();
+                match rx.try_recv() {
+                    // Handed over while timing out, keep it
+                    Ok(Some(())) => Ok(()),
+                    _ => {
+                        debug!("Timed out waiting for a slot of {}", key);
+                        Err(LimitExceeded(key.to_string()))
+                    }
+                }
+            }
+        }
+    }
+
+    fn release(&self, key: Key) {
+        let mut slots = self.slots.lock().unwrap();
+        let remove = match slots.get_mut(&key) {
+            Some(entry) => {
+                // Hand the slot to the first waiter still listening
+                let mut handed = false;
+                while let Some(tx) = entry.waiters.pop_front() {
+                    if tx.send(()).is_ok() {
+                        handed = true;
+                        break;
+                    }
+                }
+                if !handed {
+                    entry.active -= 1;
+                }
+                entry.active == 0 && entry.waiters.is_empty()
+            }
+            None => false,
+        };
+        if remove {
+            slots.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rt::Runtime;
+
+    fn limiter(config: &str) -> Arc<ConnectionLimiter> {
+        let config: LimiterConfig = serde_yaml::from_str(config).unwrap();
+        Arc::new(ConnectionLimiter::new(Some(&config)))
+    }
+
+    #[test]
+    fn rejects_over_the_limit() {
+        let limiter = limiter("per-host: 1\nper-client: 2\npolicy: reject");
+        let client = Some(IpAddr::from([10, 0, 0, 1]));
+        Runtime::new().unwrap().block_on(async {
+            let first = limiter.clone().acquire("a.com", client).await.unwrap();
+            assert!(limiter.clone().acquire("a.com", client).await.is_err());
+            // The refused connection gave its client slot back
+            let second = limiter.clone().acquire("b.com", client).await.unwrap();
+            assert!(limiter.clone().acquire("c.com", client).await.is_err());
+            // Other clients have slots of their own
+            let other = Some(IpAddr::from([10, 0, 0, 2]));
+            let _third = limiter.clone().acquire("c.com", other).await.unwrap();
+
+            drop(first);
+            let _fourth = limiter.clone().acquire("a.com", client).await.unwrap();
+            drop(second);
+            assert!(limiter
+                .slots
+                .lock()
+                .unwrap()
+                .get(&Key::Host("b.com".to_owned()))
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn queues_until_a_slot_frees() {
+        let limiter = limiter("per-host: 1\nqueue-timeout: 1");
+        Runtime::new().unwrap().block_on(async {
+            let first = limiter.clone().acquire("a.com", None).await.unwrap();
+            let queued = limiter.clone().acquire("a.com", None);
+            let release = async {
+                delay_for(Duration::from_millis(50)).await;
+                drop(first);
+            };
+            let (queued, ()) = futures::join!(queued, release);
+            let _second = queued.unwrap();
+
+            // Nobody releases this time, so it times out
+            assert!(limiter.clone().acquire("a.com", None).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,156 @@
+//! HTTPS interception for the HTTP inbound
+//!
+//! CONNECT tunnels to the configured hosts are terminated with a leaf
+//! certificate minted for the host and signed by a local CA, so the
+//! requests inside go through the same rules as plain HTTP ones and are
+//! encrypted again towards the origin. Clients have to trust the CA, it is
+//! generated on first start when neither `ca-cert` nor `ca-key` exists.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use http::{Request, Uri};
+use log::info;
+use lru_cache::LruCache;
+
+use crate::{
+    config::MitmConfig,
+    domain_trie::DomainTrie,
+    tls::{self, Authority, TlsAcceptor, TlsConnector},
+};
+
+const CA_NAME: &str = "tache MITM CA";
+
+/// Leaf certificates kept, one per intercepted host
+const LEAF_CACHE_SIZE: usize = 256;
+
+pub struct Mitm {
+    hosts: DomainTrie<()>,
+    ca: Authority,
+    leaves: Mutex<LruCache<String, TlsAcceptor>>,
+    /// Verifies origins of decrypted tunnels
+    origins: TlsConnector,
+}
+
+impl Mitm {
+    /// Load the CA of `config`, generating it when both files are missing
+    pub fn new(config: &MitmConfig) -> io::Result<Mitm> {
+        let mut hosts = DomainTrie::new();
+        for pattern in &config.hosts {
+            hosts
+                .insert(pattern, ())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+
+        let (cert_path, key_path) = (Path::new(&config.ca_cert), Path::new(&config.ca_key));
+        if !cert_path.exists() && !key_path.exists() {
+            let (cert, key) = Authority::generate(CA_NAME)?;
+            fs::write(cert_path, cert)?;
+            write_private(key_path, key.as_bytes())?;
+            info!(
+                "generated MITM CA {}, clients have to trust it",
+                config.ca_cert
+            );
+        }
+        // Leaves chain up to the certificate on disk, not a re-serialization
+        let ca = Authority::from_pem(
+            &fs::read_to_string(cert_path)?,
+            &fs::read_to_string(key_path)?,
+        )?;
+
+        let origins = match config.origin_ca {
+            Some(ref path) => tls::client_connector_trusting(&["http/1.1"], path)?,
+            None => tls::client_connector(&["http/1.1"]),
+        };
+
+        Ok(Mitm {
+            hosts,
+            ca,
+            leaves: Mutex::new(LruCache::new(LEAF_CACHE_SIZE)),
+            origins,
+        })
+    }
+
+    /// Whether tunnels to `host` are decrypted
+    pub fn intercepts(&self, host: &str) -> bool {
+        self.hosts.contains(host)
+    }
+
+    /// Connector encrypting the requests of decrypted tunnels again
+    pub fn origin_connector(&self) -> TlsConnector {
+        self.origins.clone()
+    }
+
+    /// Acceptor presenting a certificate for `host`
+    pub fn acceptor(&self, host: &str) -> io::Result<TlsAcceptor> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Some(acceptor) = self.leaves.lock().unwrap().get_mut(host) {
+            return Ok(acceptor.clone());
+        }
+
+        let acceptor = self.ca.acceptor(host, &["http/1.1"])?;
+        self.leaves
+            .lock()
+            .unwrap()
+            .insert(host.to_owned(), acceptor.clone());
+        Ok(acceptor)
+    }
+}
+
+/// `request` read from a decrypted tunnel to `authority`, given the absolute
+/// URI of proxied requests
+pub fn absolute(mut request: Request<()>, authority: &str) -> io::Result<Request<()>> {
+    let path = request.uri().path_and_query().map_or("/", |p| p.as_str());
+    let uri = format!("https://{}{}", authority, path)
+        .parse::<Uri>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    *request.uri_mut() = uri;
+    Ok(request)
+}
+
+/// Write `contents` readable by the owner only
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+// Minting takes TLS, which comes with ring only
+#[cfg(all(test, feature = "ring-crypto"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generates_ca_and_mints_leaves() {
+        let dir = std::env::temp_dir().join(format!("tache-mitm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = MitmConfig {
+            ca_cert: dir.join("ca.pem").to_string_lossy().into_owned(),
+            ca_key: dir.join("ca.key").to_string_lossy().into_owned(),
+            hosts: vec!["+.example.com".to_owned()],
+            origin_ca: None,
+        };
+
+        let mitm = Mitm::new(&config).unwrap();
+        assert!(mitm.intercepts("www.example.com"));
+        assert!(!mitm.intercepts("example.org"));
+        assert!(mitm.acceptor("www.example.com").is_ok());
+        assert!(mitm.acceptor("127.0.0.1").is_ok());
+
+        // Second start loads the CA written by the first
+        let reloaded = Mitm::new(&config).unwrap();
+        assert_eq!(reloaded.ca.der(), mitm.ca.der());
+        assert!(reloaded.acceptor("example.com").is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
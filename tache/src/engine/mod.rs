@@ -1,34 +1,74 @@
-use log::error;
+use log::{debug, error, info, warn};
 use bytes::BytesMut;
 use futures::{
     SinkExt,
     StreamExt,
-    future::{select_all, BoxFuture},
-};
-use http::{header::HeaderValue, Request, Response, StatusCode};
-use serde::Serialize;
-use std::{env, error::Error as StdError, fmt::{self, Display}, io};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use tokio::{
-    prelude::*,
-    codec::{Decoder, Encoder, Framed},
-    net::{TcpListener, TcpStream},
+    channel::mpsc::UnboundedReceiver,
+    future::{select, select_all, BoxFuture, Either},
+    pin_mut,
 };
+use http::{header, Method, Request, Response, StatusCode};
+use std::{error::Error as StdError, fmt::{self, Display}, io, time::Duration};
+use std::sync::Arc;
+use tokio::prelude::*;
+use tokio_util::codec::Framed;
 
 use crate::{
-    config::{Config, InboundConfig},
+    config::{Config, InboundConfig, Mode},
     context::{Context, SharedContext},
+    crypto,
+    event::CloseReason,
+    inbounds::tun,
+    listener::{self, InboundStream},
+    rt::{self, TcpListener},
+    socket_owner,
+    tls::{self, TlsAcceptor},
+    utils::{Address, DomainName, ListenAddress, ListenAddresses},
 };
 
-mod rules;
+mod forward;
+mod handle;
+pub mod cache;
+pub mod capture;
+pub mod carryover;
+pub mod diff;
+pub mod fingerprint;
+pub mod handshake;
+pub mod hotplug;
+pub mod limiter;
+pub mod mitm;
+pub mod qos;
+pub mod relay;
+pub mod rewrite;
+pub mod rules;
+pub mod shed;
+pub mod sniff;
+pub mod tracker;
+pub mod traffic;
+pub mod usage;
+mod tunnel;
+
+pub use self::handle::{Engine, EngineBuilder, EngineError};
 
-use crate::outbound::Outbound;
-use std::net::{ToSocketAddrs, SocketAddr};
-use crate::config::ProxyConfig;
-use crate::protocol;
+use self::{
+    carryover::Carryover,
+    handshake::{HalfOpen, HandshakeGuard},
+    mitm::Mitm,
+    qos::Flow,
+    tracker::{CloseStats, ConnectionTracker},
+    traffic::Traffic,
+};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use crate::config::{TlsServerConfig, TrafficClass};
+use crate::protocol::{self, socks, Message};
+use crate::profile::Profiles;
+use crate::provider::Providers;
+use crate::outbound::{BoxStream, Dialer, MarkedDialer, Outbound, Pool, Pooled, DIRECT};
+use crate::outbound::{exit::ExitChecker, probe};
+use crate::protocol::vmess::clock;
 
-type MODE = Vec<Box<dyn rules::Rule + Send + Sync>>;
+/// Accept loop of an inbound, the engine stops when one returns
+type InboundFuture = BoxFuture<'static, Result<(), Box<dyn StdError>>>;
 
 #[derive(Debug)]
 struct Error {
@@ -39,10 +79,6 @@ impl Error {
     fn from(v: &str) -> Box<dyn StdError> {
         Box::new(Error { v: From::from(v) })
     }
-
-    fn change_message(&mut self, new_message: &str) {
-        self.v = new_message.to_string();
-    }
 }
 
 impl StdError for Error {
@@ -57,255 +93,951 @@ impl Display for Error {
 
 pub struct ConnectionMeta {
     pub udp: bool,
+    /// Name of the inbound the connection came in on
+    pub inbound: String,
+    /// User the client authenticated as
+    pub user: Option<String>,
+    /// Local user running the client, looked up only when rules need it
+    pub uid: Option<u32>,
     pub host: String,
+    pub dst_port: u16,
     pub src_addr: Option<std::net::SocketAddr>,
     pub dst_addr: Option<std::net::SocketAddr>,
+    /// `http`, `tls` or `quic`, for tunnels only once sniffed
+    pub protocol: Option<&'static str>,
+    /// Server name the client sent inside a sniffed tunnel
+    pub sni: Option<String>,
+    pub tls_version: Option<u16>,
+    /// JA3 and JA4 of a sniffed ClientHello
+    pub fingerprint: Option<fingerprint::Fingerprint>,
 }
 
 impl ConnectionMeta {
     pub fn is_host(&self) -> bool {
         !self.host.is_empty()
     }
+
+    /// Destination for the outbound, domains are left to it to resolve
+    pub fn target(&self) -> Address {
+        match self.dst_addr {
+            Some(addr) => Address::SocketAddr(addr),
+            None => Address::DomainName(DomainName(self.host.clone(), self.dst_port)),
+        }
+    }
+}
+
+async fn build_connection_meta(context: &Context, stream: &InboundStream, inbound: &str,
+                               user: Option<String>, request: &Request<()>)
+                               -> Result<ConnectionMeta, Box<dyn StdError>> {
+    let host = match request.uri().host() {
+        Some(host) => host,
+        None => {
+            return Err(Error::from("not have host"));
+        }
+    };
+
+    let dst_port = match request.uri().port() {
+        Some(port) => port.as_u16(),
+        None if request.uri().scheme_str() == Some("https") => 443,
+        None => 80,
+    };
+
+    // Requests from decrypted tunnels are absolute https URIs
+    let protocol = match (request.method(), request.uri().scheme_str()) {
+        (&Method::CONNECT, _) => None,
+        (_, Some("https")) => Some(sniff::TLS),
+        _ => Some(sniff::HTTP),
+    };
+
+    Ok(connection_meta(context, stream, inbound, user, host, dst_port, protocol))
 }
 
-pub struct Engine {
-    outbounds: Vec<Box<dyn Outbound>>,
-    modes: Arc<HashMap<String, MODE>>,
+/// Meta of a connection from `stream` to `host` at `dst_port`, `protocol`
+/// when known already
+fn connection_meta(context: &Context, stream: &InboundStream, inbound: &str,
+                   user: Option<String>, host: &str, dst_port: u16,
+                   protocol: Option<&'static str>) -> ConnectionMeta {
+    // Domains are resolved by the outbound, rules see an address only when
+    // IP rules need one, see `run_rule`
+    let dst_addr = host.trim_start_matches('[').trim_end_matches(']')
+        .parse::<IpAddr>().ok()
+        .map(|ip| SocketAddr::new(ip, dst_port));
+
+    // Addresses made up by fake-ip DNS stand for the domain they were given to
+    let fake_host = dst_addr.and_then(|addr| context.dns()?.fake_host(addr.ip()));
+    let (host, dst_addr) = match fake_host {
+        Some(ref fake_host) => (fake_host.as_str(), None),
+        None => (host, dst_addr),
+    };
+
+    // Unix socket clients have no address
+    let src_addr = stream.peer_addr();
+    let uid = if context.rules().matches_uid() { stream.owner_uid() } else { None };
+
+    ConnectionMeta {
+        udp: false,
+        inbound: inbound.to_owned(),
+        user,
+        uid,
+        host: String::from(host),
+        dst_port,
+        dst_addr,
+        src_addr,
+        protocol,
+        sni: None,
+        tls_version: None,
+        fingerprint: None,
+    }
 }
 
-impl Engine {
-    #[inline]
-    pub fn new() -> Engine {
-        let modes = Arc::new(HashMap::new());
+/// Config of inbound `name`, configured or added while serving
+fn inbound_config(context: &Context, name: &str) -> Option<InboundConfig> {
+    context
+        .config()
+        .inbounds
+        .iter()
+        .find(|inbound| inbound.name() == name)
+        .cloned()
+        .or_else(|| context.added_inbounds().get(name))
+}
 
-        Engine {
-            outbounds: vec![],
-            modes,
+/// User of the `Proxy-Authorization` credentials in `request`
+///
+/// Fails with `PermissionDenied` when `credentials` are required and the
+/// request carries none of them.
+fn proxy_user(credentials: Option<&[String]>, request: &Request<()>) -> io::Result<Option<String>> {
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+    let given = request.headers().get(header::PROXY_AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.get(..6).is_some_and(|s| s.eq_ignore_ascii_case("basic ")))
+        .and_then(|v| base64::decode(v[6..].trim()).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+    match given {
+        Some(ref given) if credentials.contains(given) => {
+            Ok(given.split(':').next().map(str::to_owned))
         }
+        _ => Err(io::Error::new(io::ErrorKind::PermissionDenied, "proxy authentication required")),
     }
+}
 
-    pub fn get_modes(&self) -> Vec<&str> {
-        self.modes.keys().map(|key| key.as_ref()).collect()
-    }
+fn proxy_auth_required() -> Response<String> {
+    Response::builder()
+        .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        .header(header::PROXY_AUTHENTICATE, "Basic realm=\"tache\"")
+        .body(String::new())
+        .unwrap()
+}
 
-    pub fn update_config(config: &Config) -> Result<(), &'static str> {
-        Err("not implement")
+/// Reason for `e` ending a connection, `timeout` when it timed out
+fn close_reason(e: &(dyn StdError + 'static), timeout: CloseReason) -> CloseReason {
+    match e.downcast_ref::<io::Error>() {
+        Some(e) if e.kind() == io::ErrorKind::TimedOut => timeout,
+        Some(e) if e.kind() == io::ErrorKind::PermissionDenied => CloseReason::Reject,
+        Some(e) if e.kind() == io::ErrorKind::ConnectionAborted => CloseReason::ClientEof,
+        _ => CloseReason::Error,
     }
+}
 
-    pub fn lookup(&self) {}
+/// Outcome of the rule engine for one connection
+pub(crate) struct Matched {
+    pub(crate) outbound: Arc<dyn Outbound>,
+    pub(crate) rule: String,
+    pub(crate) proxy: String,
+    pub(crate) dscp: Option<u8>,
+    /// Relay scheduling class, with `qos`
+    pub(crate) class: Option<TrafficClass>,
+    /// Probe both sides with TCP keepalives
+    pub(crate) keepalive: bool,
+    /// Destination address, when known or resolved for IP rules
+    pub(crate) dst_ip: Option<IpAddr>,
+}
 
-    async fn respond<T>(req: Request<T>) -> Result<Response<String>, Box<dyn StdError>> {
-        let mut response = Response::builder();
-        let body = match req.uri().path() {
-            "/plaintext" => {
-                response.header("Content-Type", "text/plain");
-                "Hello, World!".to_string()
+/// Pick the outbound for `meta` by the default outbound of its inbound or
+/// the mode of its inbound, the engine's current one unless set, `REJECT` fails
+/// with `PermissionDenied`
+pub(crate) async fn run_rule(context: &Context, meta: &ConnectionMeta)
+                  -> Result<Matched, Box<dyn StdError>> {
+    let inbound = inbound_config(context, &meta.inbound);
+    let default_outbound = inbound.as_ref().and_then(InboundConfig::default_outbound);
+    let mode = inbound.as_ref().and_then(InboundConfig::mode).cloned().unwrap_or_else(|| context.mode());
+    let mut dst_ip = meta.dst_addr.map(|addr| addr.ip());
+    // Set by the matching rule only
+    let (mut dscp, mut class, mut keepalive) = (None, None, false);
+    let (rule, proxy) = match (default_outbound, mode) {
+        (Some(proxy), _) => (format!("IN-NAME,{}", meta.inbound), proxy.to_owned()),
+        (None, Mode::Direct) => (String::from("DIRECT"), String::from("DIRECT")),
+        (None, Mode::Global) => (String::from("GLOBAL"), String::from("GLOBAL")),
+        (None, Mode::Rule) => {
+            let rules = context.rules();
+            let mut metadata = rules::Metadata {
+                inbound: &meta.inbound,
+                user: meta.user.as_deref(),
+                uid: meta.uid,
+                host: if meta.dst_addr.is_some() { "" } else { &meta.host },
+                dst_ip: meta.dst_addr.map(|addr| addr.ip()),
+                resolved_ip: None,
+                dst_port: meta.dst_port,
+                src_ip: meta.src_addr.map(|addr| addr.ip()),
+                src_port: meta.src_addr.map(|addr| addr.port()),
+                udp: meta.udp,
+                protocol: meta.protocol,
+                sni: meta.sni.as_deref(),
+                tls_version: meta.tls_version,
+            };
+            // Domains go to the outbound as they are, resolved here only
+            // when an IP rule without no-resolve is reached before a match
+            if rules.needs_ip(&metadata) {
+                metadata.resolved_ip = resolve_for_rules(context, &meta.host).await;
+                dst_ip = metadata.resolved_ip;
             }
-            "/json" => {
-                response.header("Content-Type", "application/json");
-
-                #[derive(Serialize)]
-                struct Message {
-                    message: &'static str,
+            match rules.matched(&metadata) {
+                Some(m) => {
+                    dscp = m.dscp;
+                    class = m.class;
+                    keepalive = m.keepalive;
+                    (m.rule, m.target.to_owned())
                 }
-                serde_json::to_string(&Message {
-                    message: "Hello, World!",
-                })?
-            }
-            _ => {
-                response.status(StatusCode::NOT_FOUND);
-                String::new()
+                None => (String::from("DIRECT"), String::from("DIRECT")),
             }
-        };
-        let response = response
-            .body(body)
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        }
+    };
 
-        Ok(response)
+    if proxy.eq_ignore_ascii_case("REJECT") {
+        let e = io::Error::new(io::ErrorKind::PermissionDenied, format!("rejected by {}", rule));
+        return Err(e.into());
     }
+    let outbound = match context.outbound(&proxy) {
+        Some(outbound) => outbound,
+        None => return Err(Error::from(&format!("no outbound named {}", proxy))),
+    };
+    let class = context.qos().map(|qos| qos.classify(meta.dst_port, class));
+    Ok(Matched { outbound, rule, proxy, dscp, class, keepalive, dst_ip })
+}
 
-    fn delete_hop_by_hop_headers() {}
+/// First address of `host` for IP rules to match on, from the built-in
+/// resolver when `dns` is set
+async fn resolve_for_rules(context: &Context, host: &str) -> Option<IpAddr> {
+    let resolved = match context.dns() {
+        Some(dns) => dns.lookup_ip(host).await,
+        None => rt::lookup_host((host, 0)).await.map(|mut addrs| addrs.next().map(|a| a.ip())),
+    };
+    match resolved {
+        Ok(ip) => ip,
+        Err(e) => {
+            debug!("Failed to resolve {} for rules, err: {}", host, e);
+            None
+        }
+    }
 }
 
-async fn build_connection_meta(stream: &TcpStream, request: &Request<()>)
-                               -> Result<ConnectionMeta, Box<dyn StdError>> {
-    let host = match request.uri().host() {
-        Some(host) => host,
-        None => {
-            return Err(Error::from("not have host"));
+/// Dial `target` through `outbound`, given up as soon as the client hangs up
+///
+/// Dropping the dial stops its handshake and frees whatever it holds.
+/// Connections to the proxy server, or to `target` itself for `DIRECT`, are
+/// marked with `dscp` and send segments no larger than `mss` when given, and
+/// are probed with keepalives for `keepalive`, which the client is as well.
+/// Unmarked ones are checked out of `pool` when it has a spare.
+async fn dial(inbound: &mut InboundStream, outbound: &dyn Outbound, target: &Address,
+              pool: Arc<Pool>, dscp: Option<u8>, mss: Option<u16>, keepalive: bool)
+              -> io::Result<BoxStream> {
+    if keepalive {
+        if let Err(e) = inbound.set_keepalive() {
+            warn!("Failed to set keepalive towards the client, err: {}", e);
+        }
+    }
+    let pooled;
+    let marked;
+    let dialer: &dyn Dialer = match (dscp, mss, keepalive) {
+        (None, None, false) => {
+            pooled = Pooled(pool);
+            &pooled
+        }
+        (dscp, mss, keepalive) => {
+            marked = MarkedDialer { dscp, mss, keepalive };
+            &marked
         }
     };
+    let closed = inbound.closed();
+    pin_mut!(closed);
+    let dialed = select(outbound.dial(target, dialer), closed).await;
+    match dialed {
+        Either::Left((stream, _)) => stream,
+        Either::Right(..) => {
+            Err(io::Error::new(io::ErrorKind::ConnectionAborted, "client closed while dialing"))
+        }
+    }
+}
+
+/// Carry `request` read from `transport` to `outbound`, a CONNECT tunnel
+/// takes the connection over while other requests give it back for the next
+/// one unless it is closing
+async fn pipe(
+    context: &SharedContext,
+    tracker: &mut ConnectionTracker,
+    mut transport: Framed<InboundStream, protocol::Http>,
+    request: Request<()>,
+    mut outbound: BoxStream,
+    established: bool,
+    matched: &Matched,
+) -> io::Result<Option<Framed<InboundStream, protocol::Http>>> {
+    if request.method() != Method::CONNECT {
+        let cache = context.http_cache().filter(|_| matched.proxy == DIRECT);
+        let forwarded = forward::forward(
+            &mut transport, &request, &mut outbound, &context.rewrites(), cache.as_deref()).await?;
+        tracker.transferred(forwarded.up, forwarded.down);
+        return Ok(if forwarded.keep_alive { Some(transport) } else { None });
+    }
+    let parts = transport.into_parts();
+    let mut inbound = parts.io;
+    if !established {
+        inbound.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await?;
+    }
+    // Sniffed bytes go first
+    outbound.write_all(&parts.read_buf).await?;
+    tracker.transferred(parts.read_buf.len() as u64, 0);
+    let dst_port = request.uri().port_u16().unwrap_or(443);
+    relay_matched(context, tracker, &mut inbound, &mut outbound, matched, dst_port).await?;
+    Ok(None)
+}
+
+/// Relay between the client and `outbound` until both are done, scheduled
+/// by the class of `matched` with `qos`
+///
+/// Clients of keepalive connections are reset when the target is lost, so
+/// they reconnect right away.
+async fn relay_matched(
+    context: &SharedContext,
+    tracker: &mut ConnectionTracker,
+    inbound: &mut InboundStream,
+    outbound: &mut BoxStream,
+    matched: &Matched,
+    dst_port: u16,
+) -> io::Result<()> {
+    let flow = context.qos().map(|qos| Flow::new(qos, dst_port, matched.class));
+    tracker.classified(flow.as_ref().map(Flow::class));
+    match relay::relay(inbound, outbound, &context.buffer_pool(), flow.as_ref()).await {
+        Ok((up, down)) => {
+            tracker.transferred(up, down);
+            Ok(())
+        }
+        Err(e) => {
+            if matched.keepalive && relay::is_remote(&e) {
+                if let Err(e) = inbound.reset_on_close() {
+                    debug!("Failed to reset the client, err: {}", e);
+                }
+                tracker.close(CloseReason::UpstreamLost);
+            }
+            Err(e)
+        }
+    }
+}
 
-    let dst_addr = match host.to_socket_addrs() {
-        Ok(mut addrs) => addrs.next(),
-        Err(e) => None
+/// Acceptor for an inbound serving `tls`, offering `alpn` unless the
+/// certificate config lists its own protocols
+fn tls_acceptor(tls: Option<&TlsServerConfig>, alpn: &[&str]) -> io::Result<Option<TlsAcceptor>> {
+    let mut tls = match tls {
+        Some(tls) => tls.clone(),
+        None => return Ok(None),
     };
+    if tls.alpn.is_empty() {
+        tls.alpn = alpn.iter().map(|p| (*p).to_owned()).collect();
+    }
+    Ok(Some(tls::server_acceptor(&tls)?))
+}
 
-    let src_addr = match stream.peer_addr() {
-        Ok(addr) => Some(addr),
-        Err(e) => None
+/// Finish the TLS handshake of `inbound` within the handshake timeout
+async fn accept_tls(
+    acceptor: Option<TlsAcceptor>,
+    inbound: InboundStream,
+    half_open: &Option<HalfOpen>,
+) -> io::Result<InboundStream> {
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => return Ok(inbound),
+    };
+    let stream = match half_open {
+        Some(half_open) => half_open.timeout(tls::accept(&acceptor, inbound)).await??,
+        None => tls::accept(&acceptor, inbound).await?,
     };
+    Ok(InboundStream::Tls(Box::new(stream)))
+}
 
-    Ok(ConnectionMeta {
-        udp: false,
-        host: String::from(host),
-        dst_addr,
-        src_addr,
-    })
+/// Client addresses from the PROXY protocol header of `inbound` when
+/// `enabled`, within the handshake timeout
+async fn accept_proxy_protocol(
+    enabled: bool,
+    inbound: InboundStream,
+    half_open: &Option<HalfOpen>,
+) -> io::Result<InboundStream> {
+    if !enabled {
+        return Ok(inbound);
+    }
+    match half_open {
+        Some(half_open) => half_open.timeout(inbound.accept_proxy_protocol()).await?,
+        None => inbound.accept_proxy_protocol().await,
+    }
 }
 
-async fn run_rule(stream: &TcpStream, meta: ConnectionMeta)
-                  -> Result<&TcpStream, Box<dyn StdError>> {
-    Err(Error::from("not implement"))
+/// Answer the CONNECT read by `transport` and decrypt the tunnel with a
+/// certificate for `host`, within the handshake timeout
+async fn intercept(
+    mitm: &Mitm,
+    guard: &Arc<HandshakeGuard>,
+    transport: Framed<InboundStream, protocol::Http>,
+    host: &str,
+) -> io::Result<Framed<InboundStream, protocol::Http>> {
+    let parts = transport.into_parts();
+    if !parts.read_buf.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "client sent data before the tunnel was established"));
+    }
+    let mut inbound = parts.io;
+    let half_open = guard.enter(inbound.peer_addr().map(|a| a.ip()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "too many handshakes"))?;
+    inbound.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await?;
+    let inbound = accept_tls(Some(mitm.acceptor(host)?), inbound, &Some(half_open)).await?;
+    Ok(Framed::new(inbound, parts.codec))
 }
 
-async fn pipe(request: Request<()>, inbound: &TcpStream, outbound: &TcpStream)
-              -> Result<(), Box<dyn StdError>> {
-    Ok(())
+/// Time a tunnel client gets to send its first bytes, clients of protocols
+/// where the server speaks first wait this long for nothing
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+/// Largest TLS record, ClientHellos fit well within
+const SNIFF_LIMIT: usize = 5 + 16 * 1024;
+
+/// Answer the CONNECT read by `transport` and sniff the start of the
+/// tunnel, what was read stays buffered in the transport
+async fn sniff_tunnel(transport: Framed<InboundStream, protocol::Http>)
+                      -> io::Result<(Framed<InboundStream, protocol::Http>, sniff::Sniffed)> {
+    let mut parts = transport.into_parts();
+    parts.io.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await?;
+    let sniffed = rt::timeout(SNIFF_TIMEOUT, read_sniffed(&mut parts.io, &mut parts.read_buf));
+    let sniffed = match sniffed.await {
+        Ok(sniffed) => sniffed?,
+        Err(_) => sniff::Sniffed::default(),
+    };
+    Ok((Framed::from_parts(parts), sniffed))
+}
+
+/// Read into `buf` until its protocol is known or can't be told
+async fn read_sniffed(inbound: &mut InboundStream, buf: &mut BytesMut)
+                      -> io::Result<sniff::Sniffed> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        match sniff::sniff(buf) {
+            sniff::Sniff::Found(sniffed) => return Ok(sniffed),
+            sniff::Sniff::Incomplete if buf.len() < SNIFF_LIMIT => {}
+            _ => return Ok(sniff::Sniffed::default()),
+        }
+        let n = inbound.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(sniff::Sniffed::default());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// TLS to the origin of a decrypted tunnel, verified against the web PKI
+/// and the origin CAs of `mitm`
+async fn encrypt(mitm: &Mitm, stream: BoxStream, host: &str) -> io::Result<BoxStream> {
+    let stream = tls::connect(&mitm.origin_connector(), host, stream).await?;
+    Ok(Box::new(stream))
+}
+
+/// Next message from the client, the first one has to arrive within the
+/// handshake timeout while the connection counts as half-open
+async fn next_message(
+    transport: &mut Framed<InboundStream, protocol::Http>,
+    half_open: &mut Option<HalfOpen>,
+) -> Option<io::Result<Message>> {
+    match half_open.take() {
+        Some(half_open) => match half_open.timeout(transport.next()).await {
+            Ok(message) => message,
+            Err(e) => Some(Err(e)),
+        },
+        None => transport.next().await,
+    }
 }
 
-async fn single_run_http(listen_address: SocketAddr) -> Result<(), Box<dyn StdError>> {
-    let mut incoming = TcpListener::bind(&listen_address).await?.incoming();
-    println!("Listening on: {}", &listen_address);
+async fn single_run_http(context: SharedContext, name: String, listen: ListenAddresses,
+                         tls: Option<TlsServerConfig>, proxy_protocol: bool)
+                         -> Result<(), Box<dyn StdError>> {
+    let acceptor = tls_acceptor(tls.as_ref(), &["http/1.1"])?;
+    let mut incoming = listener::bind_all(&listen).await?;
 
     while let Some(Ok(inbound)) = incoming.next().await {
-        tokio::spawn(async move {
-            let mut transport = Framed::new(inbound, protocol::Http);
+        let admission = match context.load_shedder().admit() {
+            Ok(a) => a,
+            Err(e) => {
+                debug!("[{}] refused connection, {:?}", name, e);
+                if acceptor.is_none() {
+                    rt::spawn(shed::refuse(inbound, shed::HTTP_REPLY));
+                }
+                continue;
+            }
+        };
+        let guard = context.handshake_guard();
+        let mut half_open = match guard.enter(inbound.peer_addr().map(|a| a.ip())) {
+            Some(h) => Some(h),
+            None => continue,
+        };
+        let context = context.clone();
+        let name = name.clone();
+        let acceptor = acceptor.clone();
+        rt::spawn(async move {
+            let _admission = admission;
+            let inbound = match accept_proxy_protocol(proxy_protocol, inbound, &half_open).await {
+                Ok(s) => s,
+                Err(e) => {
+                    debug!("[{}] failed to process request, err: {}", name, e);
+                    return;
+                }
+            };
+            let inbound = match accept_tls(acceptor, inbound, &half_open).await {
+                Ok(s) => s,
+                Err(e) => {
+                    debug!("[{}] failed to process request, err: {}", name, e);
+                    return;
+                }
+            };
+            let mut transport = Framed::new(inbound, guard.codec());
+            // Authority and user of the CONNECT this connection was decrypted from
+            let mut intercepted: Option<(String, Option<String>)> = None;
 
-            while let Some(request) = transport.next().await {
-                let request = match request {
-                    Ok(r) => r,
-                    Err(e) => {
-                        println!("failed to process request {}", e);
+            while let Some(request) = next_message(&mut transport, &mut half_open).await {
+                let mut request = match (request, &intercepted) {
+                    (Ok(Message::Request(r)), None) => r,
+                    (Ok(Message::Request(r)), Some((authority, _))) => {
+                        match mitm::absolute(r, authority) {
+                            Ok(r) => r,
+                            Err(e) => {
+                                debug!("[{}] failed to process request, err: {}", name, e);
+                                return;
+                            }
+                        }
+                    }
+                    // Bodies of requests answered here, `pipe` reads the others
+                    (Ok(_), _) => continue,
+                    (Err(e), _) => {
+                        debug!("[{}] failed to process request, err: {}", name, e);
                         return;
                     }
                 };
 
-                let connection_meta = match build_connection_meta(
-                    transport.get_ref(), &request).await {
-                    Ok(r) => r,
-                    Err(e) => {
-                        println!("failed to process request {}", e);
-                        return;
+                let user = match &intercepted {
+                    // Authenticated with the CONNECT already
+                    Some((_, user)) => user.clone(),
+                    None => {
+                        let inbound = inbound_config(&context, &name);
+                        let credentials = inbound.as_ref().and_then(InboundConfig::authentication);
+                        match proxy_user(credentials, &request) {
+                            Ok(user) => user,
+                            Err(e) => {
+                                debug!("[{}] failed to process request, err: {}", name, e);
+                                let _ = transport.send(proxy_auth_required()).await;
+                                return;
+                            }
+                        }
                     }
                 };
 
-                let outbound = match run_rule(
-                    transport.get_ref(), connection_meta).await {
-                    Ok(r) => r,
-                    Err(e) => {
-                        println!("failed to process request {}", e);
+                if let Some(response) = context.rewrites().request(&mut request) {
+                    if let Err(e) = transport.send(response).await {
+                        debug!("[{}] failed to process request, err: {}", name, e);
                         return;
                     }
-                };
-
-                if let Err(e) = pipe(
-                    request, transport.get_ref(), outbound).await {
-                    println!("failed to process request {}", e);
-                    return;
+                    continue;
                 }
-            }
-        });
-    }
-    Ok(())
-}
-
-async fn single_run_socks(listen_address: SocketAddr) -> Result<(), Box<dyn StdError>> {
-    let mut incoming = TcpListener::bind(&listen_address).await?.incoming();
-    println!("Listening on: {}", &listen_address);
 
-    while let Some(Ok(inbound)) = incoming.next().await {
-        tokio::spawn(async move {
-            let mut transport = Framed::new(inbound, protocol::Http);
-
-            while let Some(request) = transport.next().await {
-                let request = match request {
+                let mut connection_meta = match build_connection_meta(
+                    &context, transport.get_ref(), &name, user, &request).await {
                     Ok(r) => r,
                     Err(e) => {
-                        println!("failed to process request {}", e);
+                        debug!("[{}] failed to process request, err: {}", name, e);
                         return;
                     }
                 };
 
-                let connection_meta = match build_connection_meta(
-                    transport.get_ref(), &request).await {
-                    Ok(r) => r,
+                let mut tracker = ConnectionTracker::open(&context, "HTTP", &connection_meta);
+
+                let _permit = match context.connection_limiter().acquire(
+                    &connection_meta.host,
+                    connection_meta.src_addr.map(|addr| addr.ip()),
+                ).await {
+                    Ok(p) => p,
                     Err(e) => {
-                        println!("failed to process request {}", e);
+                        debug!("[{}] failed to process request, err: {}", name, e);
+                        tracker.close(CloseReason::Reject);
                         return;
                     }
                 };
 
-                let outbound = match run_rule(
-                    transport.get_ref(), connection_meta).await {
+                // Decrypted tunnels show their host in every request already
+                let sniff = request.method() == Method::CONNECT
+                    && (context.rules().sniffs() || context.config().tls_fingerprint.unwrap_or(false))
+                    && !context.mitm().is_some_and(|mitm| mitm.intercepts(&connection_meta.host));
+                if sniff {
+                    let (t, sniffed) = match sniff_tunnel(transport).await {
+                        Ok(r) => r,
+                        Err(e) => {
+                            debug!("[{}] failed to process request, err: {}", name, e);
+                            tracker.close(close_reason(&e, CloseReason::HandshakeFailure));
+                            return;
+                        }
+                    };
+                    transport = t;
+                    connection_meta.protocol = sniffed.protocol;
+                    connection_meta.sni = sniffed.sni;
+                    connection_meta.tls_version = sniffed.tls_version;
+                    if let Some(ref fingerprint) = sniffed.fingerprint {
+                        tracker.fingerprinted(fingerprint);
+                    }
+                    connection_meta.fingerprint = sniffed.fingerprint;
+                }
+
+                let matched = match run_rule(
+                    &context, &connection_meta).await {
                     Ok(r) => r,
                     Err(e) => {
-                        println!("failed to process request {}", e);
+                        debug!("[{}] failed to process request, err: {}", name, e);
+                        tracker.close(close_reason(&*e, CloseReason::DialTimeout));
                         return;
                     }
                 };
+                tracker.rule_matched(&matched.rule, &matched.proxy);
+                tracker.destination(matched.dst_ip);
+                tracker.classified(matched.class);
 
-                if let Err(e) = pipe(
-                    request, transport.get_ref(), outbound).await {
-                    println!("failed to process request {}", e);
-                    return;
+                // CONNECT tunnels are opaque, only plain requests sent direct are cached
+                if let (Some(cache), true) = (context.http_cache(), matched.proxy == DIRECT) {
+                    cache.invalidate(&request);
+                    match cache.lookup(&request) {
+                        cache::Lookup::Fresh(response) => {
+                            if let Err(e) = transport.get_mut().write_all(&response).await {
+                                debug!("[{}] failed to process request, err: {}", name, e);
+                                tracker.close(CloseReason::Error);
+                                return;
+                            }
+                            tracker.close(CloseReason::ClientEof);
+                            continue;
+                        }
+                        // The origin confirms the entry or sends a new response
+                        cache::Lookup::Stale(validators) => request.headers_mut().extend(validators),
+                        cache::Lookup::Miss => {}
+                    }
                 }
-            }
-        });
-    }
-    Ok(())
-}
-
-async fn single_run_redir(listen_address: SocketAddr) -> Result<(), Box<dyn StdError>> {
-    let mut incoming = TcpListener::bind(&listen_address).await?.incoming();
-    println!("Listening on: {}", &listen_address);
 
-    while let Some(Ok(inbound)) = incoming.next().await {
-        tokio::spawn(async move {
-            let mut transport = Framed::new(inbound, protocol::Http);
+                if request.method() == Method::CONNECT {
+                    let host = &connection_meta.host;
+                    if let Some(mitm) = context.mitm().filter(|mitm| mitm.intercepts(host)) {
+                        transport = match intercept(&mitm, &guard, transport, host).await {
+                            Ok(t) => t,
+                            Err(e) => {
+                                debug!("[{}] failed to process request, err: {}", name, e);
+                                tracker.close(close_reason(&e, CloseReason::HandshakeFailure));
+                                return;
+                            }
+                        };
+                        intercepted = Some((request.uri().to_string(), connection_meta.user.clone()));
+                        tracker.close(CloseReason::ClientEof);
+                        continue;
+                    }
+                }
 
-            while let Some(request) = transport.next().await {
-                let request = match request {
-                    Ok(r) => r,
+                let outbound = match dial(
+                    transport.get_mut(), &*matched.outbound, &connection_meta.target(),
+                    context.outbound_pool(), matched.dscp, None, matched.keepalive).await {
+                    Ok(s) => s,
                     Err(e) => {
-                        println!("failed to process request {}", e);
+                        debug!("[{}] failed to process request, err: {}", name, e);
+                        tracker.close(close_reason(&e, CloseReason::DialTimeout));
                         return;
                     }
                 };
+                let outbound = context.capture().wrap(&connection_meta, outbound);
+                let outbound = match (&intercepted, context.mitm()) {
+                    (Some(_), Some(mitm)) => match encrypt(&mitm, outbound, &connection_meta.host).await {
+                        Ok(s) => s,
+                        Err(e) => {
+                            debug!("[{}] failed to process request, err: {}", name, e);
+                            tracker.close(close_reason(&e, CloseReason::HandshakeFailure));
+                            return;
+                        }
+                    },
+                    _ => outbound,
+                };
 
-                let connection_meta = match build_connection_meta(
-                    transport.get_ref(), &request).await {
-                    Ok(r) => r,
-                    Err(e) => {
-                        println!("failed to process request {}", e);
+                transport = match pipe(
+                    &context, &mut tracker, transport, request, outbound, sniff, &matched).await {
+                    Ok(Some(t)) => t,
+                    Ok(None) => {
+                        tracker.close(CloseReason::ClientEof);
                         return;
                     }
-                };
-
-                let outbound = match run_rule(
-                    transport.get_ref(), connection_meta).await {
-                    Ok(r) => r,
                     Err(e) => {
-                        println!("failed to process request {}", e);
+                        debug!("[{}] failed to process request, err: {}", name, e);
+                        tracker.close(close_reason(&e, CloseReason::IdleTimeout));
                         return;
                     }
                 };
+                tracker.close(CloseReason::ClientEof);
+            }
+        });
+    }
+    Ok(())
+}
 
-                if let Err(e) = pipe(
-                    request, transport.get_ref(), outbound).await {
-                    println!("failed to process request {}", e);
-                    return;
+async fn single_run_socks(context: SharedContext, name: String, listen: ListenAddresses,
+                          tls: Option<TlsServerConfig>, proxy_protocol: bool)
+                          -> Result<(), Box<dyn StdError>> {
+    let acceptor = tls_acceptor(tls.as_ref(), &[])?;
+    let mut incoming = listener::bind_all(&listen).await?;
+
+    while let Some(Ok(inbound)) = incoming.next().await {
+        let admission = match context.load_shedder().admit() {
+            Ok(a) => a,
+            Err(e) => {
+                debug!("[{}] refused connection, {:?}", name, e);
+                if acceptor.is_none() {
+                    rt::spawn(shed::refuse(inbound, shed::SOCKS_REPLY));
                 }
+                continue;
             }
+        };
+        let guard = context.handshake_guard();
+        let half_open = match guard.enter(inbound.peer_addr().map(|a| a.ip())) {
+            Some(h) => Some(h),
+            None => continue,
+        };
+        let context = context.clone();
+        let name = name.clone();
+        let acceptor = acceptor.clone();
+        rt::spawn(async move {
+            let _admission = admission;
+            let inbound = match accept_proxy_protocol(proxy_protocol, inbound, &half_open).await {
+                Ok(s) => s,
+                Err(e) => {
+                    debug!("[{}] failed to process request, err: {}", name, e);
+                    return;
+                }
+            };
+            let mut inbound = match accept_tls(acceptor, inbound, &half_open).await {
+                Ok(s) => s,
+                Err(e) => {
+                    debug!("[{}] failed to process request, err: {}", name, e);
+                    return;
+                }
+            };
+
+            let config = inbound_config(&context, &name);
+            let credentials = config.as_ref().and_then(InboundConfig::authentication);
+            let accept = socks::accept(&mut inbound, credentials);
+            let accepted = match half_open {
+                Some(half_open) => half_open.timeout(accept).await.and_then(|r| r),
+                None => accept.await,
+            };
+            let (target, user) = match accepted {
+                Ok(r) => r,
+                Err(e) => {
+                    debug!("[{}] failed to process request, err: {}", name, e);
+                    return;
+                }
+            };
+
+            let connection_meta = connection_meta(
+                &context, &inbound, &name, user, &target.host(), target.port(), None);
+            serve_stream(&context, "Socks5", inbound, connection_meta, true).await;
         });
     }
     Ok(())
 }
 
-async fn single_run_tun() -> Result<(), Box<dyn StdError>> {
+async fn single_run_redir(context: SharedContext, name: String, listen_address: SocketAddr) -> Result<(), Box<dyn StdError>> {
+    let mut incoming = TcpListener::bind(&listen_address).await?;
+    info!("Listening on: {}", listen_address);
+
+    while let Some(Ok(inbound)) = incoming.next().await {
+        // Nothing to answer a redirected connection with, it is just closed
+        let admission = match context.load_shedder().admit() {
+            Ok(a) => a,
+            Err(e) => {
+                debug!("[{}] refused connection, {:?}", name, e);
+                continue;
+            }
+        };
+        let context = context.clone();
+        let name = name.clone();
+        rt::spawn(async move {
+            let _admission = admission;
+            let target = match listener::original_dst(&inbound) {
+                // Connected to the listener itself, dialing it would loop
+                Ok(target) if inbound.local_addr().ok() == Some(target) => {
+                    debug!("[{}] connection to {} was not redirected", name, target);
+                    return;
+                }
+                Ok(target) => target,
+                Err(e) => {
+                    debug!("[{}] failed to process request, err: {}", name, e);
+                    return;
+                }
+            };
+            let inbound = InboundStream::Tcp(inbound);
+            let connection_meta = connection_meta(
+                &context, &inbound, &name, None, &target.ip().to_string(), target.port(), None);
+            serve_stream(&context, "Redir", inbound, connection_meta, false).await;
+        });
+    }
     Ok(())
 }
 
+/// Dial the target of a SOCKS5, redirected or TUN connection through the
+/// outbound the rules pick and relay to it, answering `socks` clients once
+/// dialed
+async fn serve_stream(context: &SharedContext, kind: &str, mut inbound: InboundStream,
+                      connection_meta: ConnectionMeta, socks: bool) {
+    let mut tracker = ConnectionTracker::open(context, kind, &connection_meta);
+
+    let _permit = match context.connection_limiter().acquire(
+        &connection_meta.host,
+        connection_meta.src_addr.map(|addr| addr.ip()),
+    ).await {
+        Ok(p) => p,
+        Err(e) => {
+            debug!("[{}] failed to process request, err: {}", connection_meta.inbound, e);
+            if socks {
+                let _ = socks::reply(&mut inbound, socks::REPLY_NOT_ALLOWED).await;
+            }
+            tracker.close(CloseReason::Reject);
+            return;
+        }
+    };
+
+    let matched = match run_rule(
+        context, &connection_meta).await {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            debug!("[{}] failed to process request, err: {}", connection_meta.inbound, e);
+            tracker.close(close_reason(&*e, CloseReason::DialTimeout));
+            Err(e.downcast_ref::<io::Error>().map_or(socks::REPLY_GENERAL_FAILURE, socks::reply_code))
+        }
+    };
+    let matched = match matched {
+        Ok(r) => r,
+        Err(code) => {
+            if socks {
+                let _ = socks::reply(&mut inbound, code).await;
+            }
+            return;
+        }
+    };
+    tracker.rule_matched(&matched.rule, &matched.proxy);
+    tracker.destination(matched.dst_ip);
+    tracker.classified(matched.class);
+
+    // Segments of TUN clients are clamped on the device, those to the
+    // destination here
+    let mss = inbound_config(context, &connection_meta.inbound).and_then(|i| i.mss_clamp());
+    let outbound = match dial(
+        &mut inbound, &*matched.outbound, &connection_meta.target(),
+        context.outbound_pool(), matched.dscp, mss, matched.keepalive).await {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("[{}] failed to process request, err: {}", connection_meta.inbound, e);
+            if socks {
+                let _ = socks::reply(&mut inbound, socks::reply_code(&e)).await;
+            }
+            tracker.close(close_reason(&e, CloseReason::DialTimeout));
+            return;
+        }
+    };
+    let mut outbound = context.capture().wrap(&connection_meta, outbound);
+
+    if socks {
+        if let Err(e) = socks::reply(&mut inbound, socks::REPLY_SUCCEEDED).await {
+            debug!("[{}] failed to process request, err: {}", connection_meta.inbound, e);
+            tracker.close(close_reason(&e, CloseReason::HandshakeFailure));
+            return;
+        }
+    }
+    match relay_matched(context, &mut tracker, &mut inbound, &mut outbound, &matched,
+                        connection_meta.dst_port).await {
+        Ok(()) => tracker.close(CloseReason::ClientEof),
+        Err(e) => {
+            debug!("[{}] failed to process request, err: {}", connection_meta.inbound, e);
+            tracker.close(close_reason(&e, CloseReason::IdleTimeout));
+        }
+    }
+}
+
+/// Serve the connections coming in through the device of TUN inbound
+/// `inbound` for as long as it works
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn single_run_tun(context: SharedContext, inbound: InboundConfig) -> Result<(), Box<dyn StdError>> {
+    let (device, listeners) = tun::device::open(&inbound).await?;
+    info!("Listening on: {}", device.name());
+
+    let running = device.run(context.clone());
+    let accepting = select_all(listeners.into_iter().map(|listener| {
+        Box::pin(accept_tun(context.clone(), inbound.name().to_owned(), listener))
+    }));
+    pin_mut!(running);
+    match select(running, accepting).await {
+        Either::Left((result, _)) | Either::Right(((result, ..), _)) => Ok(result?),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+async fn single_run_tun(_context: SharedContext, _inbound: InboundConfig) -> Result<(), Box<dyn StdError>> {
+    Err(Error::from("TUN inbounds run on Linux and macOS only"))
+}
+
+/// Relay the connections `listener` accepts from the device of TUN inbound
+/// `name`
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn accept_tun(context: SharedContext, name: String, mut listener: tun::device::Listener)
+                    -> io::Result<()> {
+    loop {
+        let (stream, entry) = listener.accept().await?;
+        // Nothing to answer with, the connection is just closed
+        let admission = match context.load_shedder().admit() {
+            Ok(a) => a,
+            Err(e) => {
+                debug!("[{}] refused connection, {:?}", name, e);
+                continue;
+            }
+        };
+        let context = context.clone();
+        let name = name.clone();
+        if listener.hijacks(entry.dst) {
+            rt::spawn(async move {
+                let _admission = admission;
+                if let Err(e) = tun::dns::serve(&context, entry.client.ip(), stream).await {
+                    debug!("[{}] hijacked DNS connection of {} failed, err: {}", name, entry.client, e);
+                }
+            });
+            continue;
+        }
+        rt::spawn(async move {
+            let _admission = admission;
+            let inbound = InboundStream::Tcp(stream);
+            let mut connection_meta = connection_meta(
+                &context, &inbound, &name, None, &entry.dst.ip().to_string(), entry.dst.port(), None);
+            // The listener's peer is made up, the client is behind the device
+            connection_meta.src_addr = Some(entry.client);
+            connection_meta.uid = if context.rules().matches_uid() {
+                socket_owner::tcp_uid(entry.client)
+            } else {
+                None
+            };
+            serve_stream(&context, "Tun", inbound, connection_meta, false).await;
+            drop(entry);
+        });
+    }
+}
+
 pub async fn run(config: Config) -> io::Result<()> {
 //    let mut proxies = Arc::new(HashMap::new());
 //    // setup proxies
@@ -331,38 +1063,407 @@ pub async fn run(config: Config) -> io::Result<()> {
 
     // setup rules
 
-    let mut vf = Vec::new();
-    // setup inbounds
-    for inbound in config.inbounds.iter() {
-        match inbound {
-            InboundConfig::HTTP { name: _, listen, authentication: _ } => {
-                for addr in listen.to_socket_addrs()? {
-                    let fut = single_run_http(addr);
-                    vf.push(Box::pin(fut) as BoxFuture<Result<(), Box<dyn StdError>>>);
-                }
-            }
-            InboundConfig::Socks5 { name: _, listen, authentication: _ } => {
-                for addr in listen.to_socket_addrs()? {
-                    let fut = single_run_socks(addr);
-                    vf.push(Box::pin(fut) as BoxFuture<Result<(), Box<dyn StdError>>>);
-                }
+    let context: SharedContext = Arc::new(Context::new(config)?);
+    serve(context).await
+}
+
+/// Serve `config`, switching to each profile activated later
+///
+/// Connections established before a switch keep running, traffic totals,
+/// fake addresses and UDP sessions of tunnels carry over.
+pub async fn run_profile(
+    profiles: Profiles,
+    mut switches: UnboundedReceiver<Config>,
+    mut config: Config,
+) -> io::Result<()> {
+    let profiles = Arc::new(profiles);
+    let traffic = Arc::new(Traffic::new());
+    let close_stats = Arc::new(CloseStats::new());
+    let carryover = Arc::new(Carryover::new());
+    loop {
+        let mut context = Context::new(config)?;
+        context.set_traffic(traffic.clone());
+        context.set_close_stats(close_stats.clone());
+        context.set_carryover(carryover.clone());
+        context.set_profiles(profiles.clone());
+        match select(Box::pin(serve(Arc::new(context))), switches.next()).await {
+            Either::Left((result, _)) => return result,
+            Either::Right((Some(next), _)) => {
+                info!("Switching to profile {}", profiles.active().unwrap_or_default());
+                config = next;
             }
-            InboundConfig::Redir { name: _, listen, authentication: _ } => {
-                for addr in listen.to_socket_addrs()? {
-                    let fut = single_run_redir(addr);
+            Either::Right((None, serving)) => return serving.await,
+        }
+    }
+}
+
+/// Listeners of `inbound`, one for each address of redir inbounds, the
+/// others accept on all their addresses at once
+fn inbound_futures(context: SharedContext, inbound: &InboundConfig)
+                   -> io::Result<Vec<InboundFuture>> {
+    let mut vf = Vec::new();
+    match inbound {
+        InboundConfig::HTTP { name, listen, tls, .. } => {
+            let fut = single_run_http(context, name.clone(), listen.clone(), tls.clone(),
+                                      inbound.proxy_protocol());
+            vf.push(Box::pin(fut) as BoxFuture<Result<(), Box<dyn StdError>>>);
+        }
+        InboundConfig::Socks5 { name, listen, tls, .. } => {
+            let fut = single_run_socks(context, name.clone(), listen.clone(), tls.clone(),
+                                       inbound.proxy_protocol());
+            vf.push(Box::pin(fut) as BoxFuture<Result<(), Box<dyn StdError>>>);
+        }
+        InboundConfig::Redir { name, listen, .. } => {
+            for listen in listen.iter() {
+                let addr = match listen {
+                    ListenAddress::Tcp(addr) => addr,
+                    ListenAddress::Unix(..) => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  "redir inbounds listen on TCP only"));
+                    }
+                };
+                for addr in addr.to_socket_addrs()? {
+                    let fut = single_run_redir(context.clone(), name.clone(), addr);
                     vf.push(Box::pin(fut) as BoxFuture<Result<(), Box<dyn StdError>>>);
                 }
             }
-            InboundConfig::TUN { name: _ } => {
-                let fut = single_run_tun();
-                vf.push(Box::pin(fut) as BoxFuture<Result<(), Box<dyn StdError>>>);
-            }
-        };
+        }
+        InboundConfig::TUN { .. } => {
+            let fut = single_run_tun(context, inbound.clone());
+            vf.push(Box::pin(fut) as BoxFuture<Result<(), Box<dyn StdError>>>);
+        }
+    };
+    Ok(vf)
+}
+
+/// Serve every configured inbound until one of them fails
+///
+/// Dropping the future stops the listeners and background tasks, connections
+/// already handed to their own task keep running.
+pub(crate) async fn serve(context: SharedContext) -> io::Result<()> {
+    rt::mark_started();
+    let config = context.config().clone();
+    info!(
+        "Crypto backend {}, hardware AES {}, auto ciphers use {}",
+        crypto::BACKEND,
+        crypto::hardware_aes(),
+        context.preferred_cipher().name()
+    );
+    let mut vf = Vec::new();
+
+    let pool = context.outbound_pool();
+    vf.push(Box::pin(async move {
+        pool.run_reaper().await;
+        Ok(())
+    }) as BoxFuture<Result<(), Box<dyn StdError>>>);
+
+    if config.stats.is_some() {
+        let usage = context.usage();
+        vf.push(Box::pin(async move {
+            usage.run_flusher().await;
+            Ok(())
+        }) as BoxFuture<Result<(), Box<dyn StdError>>>);
+    }
+
+    if config.exit_check.is_some() {
+        let proxies = probe::proxy_outbounds(&config.proxies, &context.outbounds());
+        let checker = ExitChecker::run(context.exit_checker(), proxies, context.geoip());
+        vf.push(Box::pin(async move {
+            checker.await;
+            Ok(())
+        }) as BoxFuture<Result<(), Box<dyn StdError>>>);
+    }
+
+    if let Some(clock_check) = config.clock_check.clone() {
+        vf.push(Box::pin(async move {
+            clock::run(clock_check).await;
+            Ok(())
+        }) as BoxFuture<Result<(), Box<dyn StdError>>>);
+    }
+
+    if let Some(geo_db) = context.geo_db() {
+        vf.push(Box::pin(async move {
+            geo_db.run().await;
+            Ok(())
+        }) as BoxFuture<Result<(), Box<dyn StdError>>>);
     }
 
-    let (res, ..) = select_all(vf.into_iter()).await;
+    let providers = context.providers();
+    vf.push(Box::pin(async move {
+        providers.initialize().await;
+        Providers::run_updater(providers).await;
+        Ok(())
+    }) as BoxFuture<Result<(), Box<dyn StdError>>>);
+
+    if let Some(api) = config.api.clone() {
+        let fut = crate::api::run(context.clone(), api);
+        vf.push(Box::pin(fut) as BoxFuture<Result<(), Box<dyn StdError>>>);
+    }
+
+    if let (Some(resolver), Some(dns)) = (context.dns(), config.dns.as_ref()) {
+        let listen = dns.listen.clone();
+        let fut = async move { crate::dns::run(resolver, listen).await.map_err(Into::into) };
+        vf.push(Box::pin(fut) as BoxFuture<Result<(), Box<dyn StdError>>>);
+    }
+
+    let added = context.added_inbounds();
+    vf.push(Box::pin(async move {
+        added.run().await;
+        Ok(())
+    }) as BoxFuture<Result<(), Box<dyn StdError>>>);
+
+    // setup inbounds
+    for inbound in config.inbounds.iter() {
+        vf.extend(inbound_futures(context.clone(), inbound)?);
+    }
+
+    for tunnel in config.tunnels.iter() {
+        let fut = tunnel::run(context.clone(), tunnel.clone());
+        vf.push(Box::pin(fut) as BoxFuture<Result<(), Box<dyn StdError>>>);
+    }
+
+    let (res, ..) = select_all(vf).await;
     error!("One of inbound exited unexpectedly, result: {:?}", res);
-    Err(io::Error::new(io::ErrorKind::Other, "server exited unexpectedly"))
+    let message = match res {
+        Err(e) => e.to_string(),
+        Ok(()) => "server exited unexpectedly".to_owned(),
+    };
+    Err(io::Error::new(io::ErrorKind::Other, message))
 }
 
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    fn context(inbounds: &str, rules: &str) -> Context {
+        let config = format!(
+            "mode: rule\nlog-level: silent\nproxies: []\nproxy-groups: []\n\
+             inbounds:\n{}rules:\n{}",
+            inbounds, rules
+        );
+        Context::new(Config::load_from_str(&config).unwrap()).unwrap()
+    }
+
+    fn meta(inbound: &str, user: Option<&str>) -> ConnectionMeta {
+        ConnectionMeta {
+            udp: false,
+            inbound: inbound.to_owned(),
+            user: user.map(str::to_owned),
+            uid: None,
+            host: "example.com".to_owned(),
+            dst_port: 443,
+            src_addr: None,
+            dst_addr: None,
+            protocol: None,
+            sni: None,
+            tls_version: None,
+            fingerprint: None,
+        }
+    }
+
+    fn matched(context: &Context, meta: &ConnectionMeta) -> Result<(String, String), String> {
+        rt::Runtime::new()
+            .unwrap()
+            .block_on(run_rule(context, meta))
+            .map(|m| (m.rule, m.proxy))
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn routes_by_inbound() {
+        let context = context(
+            "  - { name: socks1, kind: socks5, listen: 127.0.0.1:1080, default-outbound: DIRECT }\n\
+             \x20 - { name: http1, kind: http, listen: 127.0.0.1:8080 }\n\
+             \x20 - { name: http2, kind: http, listen: 127.0.0.1:8081 }\n",
+            "  - { kind: IN-NAME, params: [http2], target: DIRECT }\n\
+             \x20 - { kind: MATCH, target: REJECT }\n",
+        );
+        // The default outbound goes before any rule
+        assert_eq!(
+            matched(&context, &meta("socks1", None)),
+            Ok(("IN-NAME,socks1".to_owned(), "DIRECT".to_owned()))
+        );
+        assert_eq!(
+            matched(&context, &meta("http2", None)),
+            Ok(("IN-NAME,http2".to_owned(), "DIRECT".to_owned()))
+        );
+        assert_eq!(
+            matched(&context, &meta("http1", None)),
+            Err("rejected by MATCH".to_owned())
+        );
+    }
+
+    #[test]
+    fn routes_by_authenticated_user() {
+        let credentials = ["alice:secret".to_owned(), "bob:hunter2".to_owned()];
+        let request = |auth: Option<&str>| {
+            let mut request = Request::builder().method(Method::CONNECT).uri("example.com:443");
+            if let Some(auth) = auth {
+                request = request.header(header::PROXY_AUTHORIZATION, auth);
+            }
+            request.body(()).unwrap()
+        };
+        // alice:secret
+        let alice = request(Some("Basic YWxpY2U6c2VjcmV0"));
+        assert_eq!(proxy_user(None, &alice).unwrap(), None);
+        assert_eq!(
+            proxy_user(Some(&credentials), &alice).unwrap().as_deref(),
+            Some("alice")
+        );
+        // alice:wrong
+        let wrong = request(Some("basic YWxpY2U6d3Jvbmc="));
+        for request in &[wrong, request(None)] {
+            let e = proxy_user(Some(&credentials), request).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        }
 
+        let context = context(
+            "  - { name: http1, kind: http, listen: 127.0.0.1:8080,\
+             \x20     authentication: [\"alice:secret\"] }\n",
+            "  - { kind: AUTH-USER, params: [alice], target: DIRECT }\n\
+             \x20 - { kind: MATCH, target: REJECT }\n",
+        );
+        assert_eq!(
+            matched(&context, &meta("http1", Some("alice"))),
+            Ok(("AUTH-USER,alice".to_owned(), "DIRECT".to_owned()))
+        );
+        assert!(matched(&context, &meta("http1", Some("bob"))).is_err());
+        assert!(matched(&context, &meta("http1", None)).is_err());
+    }
+
+    /// Never finishes its handshake, noting when it was given up
+    struct Stalled(Arc<AtomicBool>);
+
+    struct Abandoned(Arc<AtomicBool>);
+
+    impl Drop for Abandoned {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl Outbound for Stalled {
+        fn name(&self) -> String {
+            "stalled".to_owned()
+        }
+
+        fn udp(&self) -> bool {
+            false
+        }
+
+        fn dial<'a>(&'a self, _: &'a Address, _: &'a dyn Dialer)
+                    -> BoxFuture<'a, io::Result<BoxStream>> {
+            let abandoned = Abandoned(self.0.clone());
+            Box::pin(async move {
+                let _abandoned = abandoned;
+                futures::future::pending().await
+            })
+        }
+
+        fn alive(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn client_hangup_cancels_the_dial() {
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let outbound = Stalled(abandoned.clone());
+        let pool = Arc::new(Pool::from_config(None, &[]));
+        let target = Address::DomainName(DomainName("example.com".to_owned(), 443));
+        rt::Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = rt::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let mut inbound = InboundStream::Tcp(listener.accept().await.unwrap().0);
+            let hangup = async {
+                rt::delay_for(Duration::from_millis(50)).await;
+                drop(client);
+            };
+            let (dialed, ()) = futures::join!(
+                dial(&mut inbound, &outbound, &target, pool, None, None, false),
+                hangup
+            );
+            assert_eq!(dialed.err().unwrap().kind(), io::ErrorKind::ConnectionAborted);
+        });
+        assert!(abandoned.load(Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "ring-crypto")]
+    #[test]
+    fn terminates_tls_on_the_inbound() {
+        use tokio_rustls::{
+            rustls::{self, Session},
+            webpki::DNSNameRef,
+            TlsConnector,
+        };
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let dir = std::env::temp_dir();
+        let pid = std::process::id();
+        let config = TlsServerConfig {
+            cert: dir.join(format!("tache-inbound-{}.crt", pid)).to_string_lossy().into_owned(),
+            key: dir.join(format!("tache-inbound-{}.key", pid)).to_string_lossy().into_owned(),
+            alpn: vec![],
+        };
+        std::fs::write(&config.cert, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&config.key, cert.serialize_private_key_pem()).unwrap();
+        let acceptor = tls_acceptor(Some(&config), &["http/1.1"]).unwrap();
+        std::fs::remove_file(&config.cert).unwrap();
+        std::fs::remove_file(&config.key).unwrap();
+        assert!(tls_acceptor(None, &["http/1.1"]).unwrap().is_none());
+
+        let mut client = rustls::ClientConfig::new();
+        client.root_store.add(&rustls::Certificate(cert.serialize_der().unwrap())).unwrap();
+        client.set_protocols(&[b"http/1.1".to_vec()]);
+        let connector = TlsConnector::from(Arc::new(client));
+        rt::Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let serve = async {
+                let inbound = InboundStream::Tcp(listener.accept().await.unwrap().0);
+                let mut inbound = accept_tls(acceptor, inbound, &None).await.unwrap();
+                assert!(matches!(inbound, InboundStream::Tls(..)));
+                assert!(inbound.peer_addr().is_some());
+                let mut ping = [0; 4];
+                inbound.read_exact(&mut ping).await.unwrap();
+                assert_eq!(&ping, b"ping");
+                inbound.write_all(b"pong").await.unwrap();
+                inbound.flush().await.unwrap();
+            };
+            let client = async {
+                let stream = rt::TcpStream::connect(addr).await.unwrap();
+                let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+                let mut stream = connector.connect(name, stream).await.unwrap();
+                assert_eq!(stream.get_ref().1.get_alpn_protocol(), Some(&b"http/1.1"[..]));
+                stream.write_all(b"ping").await.unwrap();
+                let mut pong = [0; 4];
+                stream.read_exact(&mut pong).await.unwrap();
+                assert_eq!(&pong, b"pong");
+            };
+            futures::join!(serve, client);
+        });
+    }
+
+    #[test]
+    fn mode_of_the_inbound_overrides() {
+        let context = context(
+            "  - { name: lan, kind: http, listen: 127.0.0.1:8080, mode: direct }\n\
+             \x20 - { name: all, kind: socks5, listen: 127.0.0.1:1080, mode: global }\n\
+             \x20 - { name: http1, kind: http, listen: 127.0.0.1:8081 }\n",
+            "  - { kind: MATCH, target: REJECT }\n",
+        );
+        assert_eq!(
+            matched(&context, &meta("lan", None)),
+            Ok(("DIRECT".to_owned(), "DIRECT".to_owned()))
+        );
+        // Global mode goes to the GLOBAL group, not configured here
+        let global = matched(&context, &meta("all", None)).unwrap_err();
+        assert!(global.ends_with("no outbound named GLOBAL"), "{}", global);
+        assert_eq!(
+            matched(&context, &meta("http1", None)),
+            Err("rejected by MATCH".to_owned())
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Interactive connections first when the link is saturated
+//!
+//! Connections are interactive or bulk by the `class` of their rule or
+//! tunnel, else by destination port. Relays report the bytes they move here
+//! and the link counts as saturated once the rate over the last second
+//! reaches 90% of `bandwidth`. While it is and an interactive connection
+//! moved data within the last second, bulk relays read ahead one buffer at
+//! most and are paced to `bulk-share` of the bandwidth together, leaving
+//! the rest of the link and the buffers to interactive ones.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::config::{QosConfig, TrafficClass};
+
+/// SSH, telnet, DNS, RDP, VNC and IRC
+pub const DEFAULT_INTERACTIVE_PORTS: &[u16] = &[22, 23, 53, 3389, 5900, 6667, 6697];
+/// Percent of the bandwidth bulk connections share while contended
+pub const DEFAULT_BULK_SHARE: u8 = 50;
+
+/// Rates are measured over, and interactive connections count as active
+/// for, this long
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Bytes per second, measured over consecutive windows
+struct Meter {
+    start: Instant,
+    bytes: u64,
+    /// Rate of the last full window
+    rate: u64,
+}
+
+/// Budget of bulk connections, negative once they read ahead of it
+struct Bucket {
+    tokens: i64,
+    updated: Instant,
+}
+
+pub struct Qos {
+    bandwidth: u64,
+    interactive_ports: Vec<u16>,
+    bulk_rate: u64,
+    started: Instant,
+    /// Milliseconds after `started` plus one interactive data last moved,
+    /// 0 for never
+    interactive_at: AtomicU64,
+    meter: Mutex<Meter>,
+    bucket: Mutex<Bucket>,
+}
+
+impl Qos {
+    pub fn new(config: &QosConfig) -> Qos {
+        let share = config.bulk_share.unwrap_or(DEFAULT_BULK_SHARE).min(100);
+        let now = Instant::now();
+        Qos {
+            bandwidth: config.bandwidth,
+            interactive_ports: config
+                .interactive_ports
+                .clone()
+                .unwrap_or_else(|| DEFAULT_INTERACTIVE_PORTS.to_vec()),
+            bulk_rate: (config.bandwidth * u64::from(share) / 100).max(1),
+            started: now,
+            interactive_at: AtomicU64::new(0),
+            meter: Mutex::new(Meter {
+                start: now,
+                bytes: 0,
+                rate: 0,
+            }),
+            bucket: Mutex::new(Bucket {
+                tokens: 0,
+                updated: now,
+            }),
+        }
+    }
+
+    /// `class` when set, else interactive for the interactive ports
+    pub fn classify(&self, dst_port: u16, class: Option<TrafficClass>) -> TrafficClass {
+        class.unwrap_or_else(|| {
+            if self.interactive_ports.contains(&dst_port) {
+                TrafficClass::Interactive
+            } else {
+                TrafficClass::Bulk
+            }
+        })
+    }
+
+    fn millis(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64 + 1
+    }
+
+    /// Count `n` bytes relayed for a connection of `class`
+    pub fn record(&self, class: TrafficClass, n: usize) {
+        if class == TrafficClass::Interactive {
+            self.interactive_at.store(self.millis(), Ordering::Relaxed);
+        }
+        let mut meter = self.meter.lock().unwrap();
+        let elapsed = meter.start.elapsed();
+        if elapsed >= WINDOW {
+            meter.rate = meter.bytes * 1000 / (elapsed.as_millis() as u64);
+            meter.start = Instant::now();
+            meter.bytes = 0;
+        }
+        meter.bytes += n as u64;
+    }
+
+    fn saturated(&self) -> bool {
+        let threshold = self.bandwidth / 10 * 9;
+        let meter = self.meter.lock().unwrap();
+        meter.rate >= threshold || meter.bytes >= threshold
+    }
+
+    /// Whether bulk connections give way to interactive ones right now
+    pub fn contended(&self) -> bool {
+        let at = self.interactive_at.load(Ordering::Relaxed);
+        at != 0 && self.millis() - at < WINDOW.as_millis() as u64 && self.saturated()
+    }
+
+    /// Time a bulk connection waits after reading `n` bytes while contended,
+    /// `None` while within the bulk rate
+    pub fn pace(&self, n: usize) -> Option<Duration> {
+        let rate = self.bulk_rate as i64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = rate * now.duration_since(bucket.updated).as_millis() as i64 / 1000;
+        bucket.tokens = (bucket.tokens + refill).min(rate);
+        bucket.updated = now;
+        bucket.tokens -= n as i64;
+        if bucket.tokens >= 0 {
+            return None;
+        }
+        Some(Duration::from_millis(
+            (-bucket.tokens * 1000 / rate) as u64 + 1,
+        ))
+    }
+}
+
+/// Class of one relayed connection
+#[derive(Clone)]
+pub struct Flow {
+    qos: Arc<Qos>,
+    class: TrafficClass,
+}
+
+impl Flow {
+    pub fn new(qos: Arc<Qos>, dst_port: u16, class: Option<TrafficClass>) -> Flow {
+        let class = qos.classify(dst_port, class);
+        Flow { qos, class }
+    }
+
+    pub fn class(&self) -> TrafficClass {
+        self.class
+    }
+
+    /// Bulk while the link is contended
+    pub fn yields(&self) -> bool {
+        self.class == TrafficClass::Bulk && self.qos.contended()
+    }
+
+    pub fn record(&self, n: usize) {
+        self.qos.record(self.class, n);
+    }
+
+    pub fn pace(&self, n: usize) -> Option<Duration> {
+        self.qos.pace(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bulk_yields_to_interactive() {
+        let qos = Arc::new(Qos::new(&QosConfig {
+            bandwidth: 1000,
+            interactive_ports: None,
+            bulk_share: None,
+        }));
+        let ssh = Flow::new(qos.clone(), 22, None);
+        let download = Flow::new(qos.clone(), 443, None);
+        assert_eq!(ssh.class(), TrafficClass::Interactive);
+        assert_eq!(download.class(), TrafficClass::Bulk);
+        assert_eq!(
+            Flow::new(qos.clone(), 22, Some(TrafficClass::Bulk)).class(),
+            TrafficClass::Bulk
+        );
+
+        download.record(2000);
+        assert!(!download.yields());
+        ssh.record(10);
+        assert!(download.yields());
+        assert!(!ssh.yields());
+
+        // 500 bytes per second for bulk, a second ahead waits about that long
+        assert!(download.pace(400).is_some());
+        let wait = download.pace(500).unwrap();
+        assert!(wait > Duration::from_millis(1500) && wait < Duration::from_millis(2000));
+    }
+}
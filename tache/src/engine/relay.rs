@@ -0,0 +1,424 @@
+//! Copy data between the two sides of a proxied connection
+//!
+//! Each direction reads ahead into pooled buffers while its writer is busy,
+//! up to the per connection ceiling of the pool. At the ceiling reading
+//! pauses, so a slow peer pushes back on the sender through TCP flow control
+//! instead of growing memory.
+//!
+//! With `qos`, a bulk connection reads ahead one buffer at most and waits
+//! out its share of the bandwidth while interactive ones are contending for
+//! the link.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::{future::poll_fn, ready};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::qos::Flow;
+use crate::{
+    buffer::{Buffer, BufferPool},
+    rt::delay_for,
+};
+
+/// Data read but not yet written
+struct Chunk {
+    buf: Buffer,
+    pos: usize,
+    cap: usize,
+}
+
+type Pause = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// One direction of a relay
+struct Copy {
+    pool: Arc<BufferPool>,
+    max_buffers: usize,
+    flow: Option<Flow>,
+    /// Wait of a bulk connection over its share before reading again
+    pause: Option<Pause>,
+    queue: VecDeque<Chunk>,
+    read_done: bool,
+    done: bool,
+    amount: u64,
+}
+
+impl Copy {
+    fn new(pool: &Arc<BufferPool>, flow: Option<&Flow>) -> Copy {
+        Copy {
+            pool: pool.clone(),
+            max_buffers: pool.max_buffers_per_connection(),
+            flow: flow.cloned(),
+            pause: None,
+            queue: VecDeque::new(),
+            read_done: false,
+            done: false,
+            amount: 0,
+        }
+    }
+
+    /// Read into the free tail of the last chunk or into a new buffer if
+    /// the ceiling allows one, `None` when reading has to wait for the writer
+    fn poll_fill<R>(
+        &mut self,
+        cx: &mut Context<'_>,
+        reader: Pin<&mut R>,
+    ) -> Option<Poll<io::Result<()>>>
+    where
+        R: AsyncRead + ?Sized,
+    {
+        if let Some(ref mut pause) = self.pause {
+            if pause.as_mut().poll(cx).is_pending() {
+                return Some(Poll::Pending);
+            }
+            self.pause = None;
+        }
+        let yields = self.flow.as_ref().is_some_and(Flow::yields);
+        let max_buffers = if yields { 1 } else { self.max_buffers };
+        let has_room = self.queue.back().is_some_and(|c| c.cap < c.buf.len());
+        if !has_room {
+            if self.queue.len() >= max_buffers {
+                return None;
+            }
+            let buf = self.pool.get();
+            self.queue.push_back(Chunk {
+                buf,
+                pos: 0,
+                cap: 0,
+            });
+        }
+        let chunk = self.queue.back_mut().unwrap();
+        let res = match reader.poll_read(cx, &mut chunk.buf[chunk.cap..]) {
+            Poll::Ready(Ok(0)) => {
+                self.read_done = true;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Ok(n)) => {
+                chunk.cap += n;
+                if let Some(ref flow) = self.flow {
+                    flow.record(n);
+                    if yields {
+                        self.pause = flow.pace(n).map(|wait| Box::pin(delay_for(wait)) as Pause);
+                    }
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        };
+        // Hand an unused buffer straight back to the pool
+        if self.queue.back().is_some_and(|c| c.cap == 0) {
+            self.queue.pop_back();
+        }
+        Some(res)
+    }
+
+    /// Drive until the reader hit EOF and everything was written
+    fn poll_copy<R, W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut reader: Pin<&mut R>,
+        mut writer: Pin<&mut W>,
+    ) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + ?Sized,
+        W: AsyncWrite + ?Sized,
+    {
+        if self.done {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            let mut progressed = false;
+
+            while !self.read_done {
+                match self.poll_fill(cx, reader.as_mut()) {
+                    Some(Poll::Ready(res)) => {
+                        res?;
+                        progressed = true;
+                    }
+                    Some(Poll::Pending) | None => break,
+                }
+            }
+
+            loop {
+                let more = self.queue.len() > 1;
+                let chunk = match self.queue.front_mut() {
+                    Some(chunk) => chunk,
+                    None => break,
+                };
+                if chunk.pos == chunk.cap {
+                    break;
+                }
+                match writer
+                    .as_mut()
+                    .poll_write(cx, &chunk.buf[chunk.pos..chunk.cap])?
+                {
+                    Poll::Ready(0) => {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "write zero byte into writer",
+                        )));
+                    }
+                    Poll::Ready(n) => {
+                        chunk.pos += n;
+                        self.amount += n as u64;
+                        progressed = true;
+                        // Keep the last buffer for further reads while it has room
+                        if chunk.pos == chunk.cap && (more || chunk.cap == chunk.buf.len()) {
+                            self.queue.pop_front();
+                        }
+                    }
+                    Poll::Pending => break,
+                }
+            }
+
+            let drained = self.queue.iter().all(|c| c.pos == c.cap);
+            if drained && self.read_done {
+                ready!(writer.as_mut().poll_flush(cx))?;
+                ready!(writer.as_mut().poll_shutdown(cx))?;
+                self.queue.clear();
+                self.done = true;
+                return Poll::Ready(Ok(()));
+            }
+            if !progressed {
+                if drained {
+                    // Nothing more to send right now, push out what was written
+                    ready!(writer.as_mut().poll_flush(cx))?;
+                }
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+/// Failure on the remote side of a relay, wrapped in an `io::Error` of the
+/// same kind
+#[derive(Debug)]
+pub struct RemoteError(io::Error);
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "remote: {}", self.0)
+    }
+}
+
+impl Error for RemoteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Whether `e` of a relay came from the remote
+pub fn is_remote(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<RemoteError>())
+}
+
+fn remote_error(e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), RemoteError(e))
+}
+
+/// The remote, its errors tagged as `RemoteError`
+struct Remote<'r, R: ?Sized>(&'r mut R);
+
+impl<R: AsyncRead + Unpin + ?Sized> AsyncRead for Remote<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0)
+            .poll_read(cx, buf)
+            .map_err(remote_error)
+    }
+}
+
+impl<R: AsyncWrite + Unpin + ?Sized> AsyncWrite for Remote<'_, R> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0)
+            .poll_write(cx, buf)
+            .map_err(remote_error)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0).poll_flush(cx).map_err(remote_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0)
+            .poll_shutdown(cx)
+            .map_err(remote_error)
+    }
+}
+
+/// Relay between `local` and `remote` until both sides are closed
+///
+/// Returns the bytes sent to and received from the remote. Both directions
+/// are scheduled as `flow` when given, errors of `remote` are told apart by
+/// `is_remote`.
+pub async fn relay<L, R>(
+    local: &mut L,
+    remote: &mut R,
+    pool: &Arc<BufferPool>,
+    flow: Option<&Flow>,
+) -> io::Result<(u64, u64)>
+where
+    L: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    R: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut remote = Remote(remote);
+    let mut up = Copy::new(pool, flow);
+    let mut down = Copy::new(pool, flow);
+    poll_fn(|cx| {
+        let up_done = up.poll_copy(cx, Pin::new(&mut *local), Pin::new(&mut remote))?;
+        let down_done = down.poll_copy(cx, Pin::new(&mut remote), Pin::new(&mut *local))?;
+        match (up_done, down_done) {
+            (Poll::Ready(()), Poll::Ready(())) => Poll::Ready(Ok::<_, io::Error>(())),
+            _ => Poll::Pending,
+        }
+    })
+    .await?;
+    Ok((up.amount, down.amount))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::task::noop_waker_ref;
+
+    use super::*;
+
+    /// Counting bytes up to `len`
+    struct Source {
+        sent: usize,
+        len: usize,
+    }
+
+    impl AsyncRead for Source {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(self.len - self.sent);
+            for (i, b) in buf[..n].iter_mut().enumerate() {
+                *b = (self.sent + i) as u8;
+            }
+            self.sent += n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    /// Takes nothing until opened
+    struct Sink {
+        open: bool,
+        written: Vec<u8>,
+    }
+
+    impl AsyncWrite for Sink {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if !self.open {
+                return Poll::Pending;
+            }
+            self.written.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn reads_ahead_up_to_the_ceiling() {
+        let pool = Arc::new(BufferPool::new(1024, 16, 4096));
+        let mut source = Source {
+            sent: 0,
+            len: 10_000,
+        };
+        let mut sink = Sink {
+            open: false,
+            written: Vec::new(),
+        };
+        let mut copy = Copy::new(&pool, None);
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let poll = copy.poll_copy(&mut cx, Pin::new(&mut source), Pin::new(&mut sink));
+        assert!(poll.is_pending());
+        // A stalled writer holds the reader at four buffers
+        assert_eq!((source.sent, copy.queue.len(), pool.in_use()), (4096, 4, 4));
+
+        sink.open = true;
+        let poll = copy.poll_copy(&mut cx, Pin::new(&mut source), Pin::new(&mut sink));
+        assert!(poll.is_ready());
+        assert_eq!(copy.amount, 10_000);
+        assert_eq!(sink.written.len(), 10_000);
+        assert!(sink.written.iter().enumerate().all(|(i, &b)| b == i as u8));
+        assert_eq!(pool.in_use(), 0);
+    }
+
+    /// Fails to read, like a connection reset by its peer
+    struct Reset;
+
+    impl AsyncRead for Reset {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    impl AsyncWrite for Reset {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn tells_remote_errors_apart() {
+        let pool = Arc::new(BufferPool::new(1024, 16, 4096));
+        let mut local = io::Cursor::new(Vec::new());
+        let e =
+            futures::executor::block_on(relay(&mut local, &mut Reset, &pool, None)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert!(is_remote(&e));
+
+        let mut remote = io::Cursor::new(Vec::new());
+        let e =
+            futures::executor::block_on(relay(&mut Reset, &mut remote, &pool, None)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert!(!is_remote(&e));
+    }
+}
@@ -0,0 +1,187 @@
+//! URL rewrites of the HTTP inbound
+//!
+//! Rules match the absolute URL of plain HTTP requests and of requests in
+//! decrypted HTTPS tunnels. Header edits of every matching rule apply in
+//! order, the first matching redirect or reject answers the client without
+//! contacting the origin.
+
+use std::io;
+
+use http::{
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    Method, Request, Response, StatusCode,
+};
+use regex::Regex;
+
+use crate::config::{HeaderEditConfig, RewriteConfig};
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+#[derive(Default)]
+struct HeaderEdit {
+    add: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+impl HeaderEdit {
+    fn new(config: Option<&HeaderEditConfig>) -> io::Result<HeaderEdit> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(HeaderEdit::default()),
+        };
+        let mut add = Vec::with_capacity(config.add.len());
+        for (name, value) in config.add.iter() {
+            add.push((
+                HeaderName::from_bytes(name.as_bytes()).map_err(invalid)?,
+                HeaderValue::from_str(value).map_err(invalid)?,
+            ));
+        }
+        let remove = config
+            .remove
+            .iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(invalid))
+            .collect::<io::Result<_>>()?;
+        Ok(HeaderEdit { add, remove })
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        for name in self.remove.iter() {
+            headers.remove(name);
+        }
+        for (name, value) in self.add.iter() {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+enum Answer {
+    Redirect(String),
+    Reject,
+}
+
+struct Rewrite {
+    url: Regex,
+    answer: Option<Answer>,
+    request: HeaderEdit,
+    response: HeaderEdit,
+}
+
+pub struct Rewrites {
+    rules: Vec<Rewrite>,
+}
+
+impl Rewrites {
+    pub fn new(configs: &[RewriteConfig]) -> io::Result<Rewrites> {
+        let mut rules = Vec::with_capacity(configs.len());
+        for config in configs {
+            let answer = match (&config.redirect, config.reject.unwrap_or(false)) {
+                (Some(to), _) => Some(Answer::Redirect(to.clone())),
+                (None, true) => Some(Answer::Reject),
+                (None, false) => None,
+            };
+            rules.push(Rewrite {
+                url: Regex::new(&config.url).map_err(invalid)?,
+                answer,
+                request: HeaderEdit::new(config.request_headers.as_ref())?,
+                response: HeaderEdit::new(config.response_headers.as_ref())?,
+            });
+        }
+        Ok(Rewrites { rules })
+    }
+
+    /// Edit the headers of `request`, returning the answer for the client
+    /// when a rule redirects or rejects it
+    pub fn request(&self, request: &mut Request<()>) -> Option<Response<String>> {
+        // Tunnels are rewritten once decrypted
+        if request.method() == Method::CONNECT {
+            return None;
+        }
+        let url = request.uri().to_string();
+        for rule in self.rules.iter().filter(|rule| rule.url.is_match(&url)) {
+            rule.request.apply(request.headers_mut());
+            match rule.answer {
+                Some(Answer::Redirect(ref to)) => {
+                    let location = rule.url.replace(&url, to.as_str());
+                    return Some(
+                        Response::builder()
+                            .status(StatusCode::FOUND)
+                            .header(header::LOCATION, location.as_ref())
+                            .body(String::new())
+                            .unwrap_or_else(|_| not_found()),
+                    );
+                }
+                Some(Answer::Reject) => return Some(not_found()),
+                None => {}
+            }
+        }
+        None
+    }
+
+    /// Edit the headers of the response to a request for `url`
+    pub fn response(&self, url: &str, headers: &mut HeaderMap) {
+        for rule in self.rules.iter().filter(|rule| rule.url.is_match(url)) {
+            rule.response.apply(headers);
+        }
+    }
+}
+
+fn not_found() -> Response<String> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(String::new())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redirects_rejects_and_edits() {
+        let configs: Vec<RewriteConfig> = serde_yaml::from_str(
+            r#"
+- url: ^http://example\.com/(.*)$
+  redirect: https://example.com/$1
+- url: ^http://ads\.
+  reject: true
+- url: ^http://api\.
+  request-headers:
+    add: {User-Agent: tache}
+    remove: [Cookie]
+  response-headers:
+    remove: [Set-Cookie]
+"#,
+        )
+        .unwrap();
+        let rewrites = Rewrites::new(&configs).unwrap();
+
+        let mut request = Request::get("http://example.com/a?b=1").body(()).unwrap();
+        let response = rewrites.request(&mut request).unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com/a?b=1"
+        );
+
+        let mut request = Request::get("http://ads.example.org/").body(()).unwrap();
+        assert_eq!(
+            rewrites.request(&mut request).unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let mut request = Request::get("http://api.example.org/")
+            .header(header::COOKIE, "a=b")
+            .body(())
+            .unwrap();
+        assert!(rewrites.request(&mut request).is_none());
+        assert!(request.headers().get(header::COOKIE).is_none());
+        assert_eq!(request.headers()[header::USER_AGENT], "tache");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::SET_COOKIE, HeaderValue::from_static("a=b"));
+        rewrites.response("http://api.example.org/", &mut headers);
+        assert!(headers.is_empty());
+    }
+}
@@ -0,0 +1,69 @@
+//! DOMAIN, DOMAIN-SUFFIX, DOMAIN-KEYWORD and DST-SNI rules
+
+use super::{Matcher, Metadata};
+use crate::domain_trie::DomainTrie;
+
+/// Any of the listed domains, alone or with their subdomains
+pub struct Domain(DomainTrie<()>);
+
+impl Domain {
+    pub fn exact(params: &[String]) -> Result<Domain, String> {
+        let mut trie = DomainTrie::new();
+        for domain in params {
+            trie.insert_exact(domain, ()).map_err(|e| e.to_string())?;
+        }
+        Ok(Domain(trie))
+    }
+
+    pub fn suffix(params: &[String]) -> Result<Domain, String> {
+        let mut trie = DomainTrie::new();
+        for domain in params {
+            trie.insert_suffix(domain, ()).map_err(|e| e.to_string())?;
+        }
+        Ok(Domain(trie))
+    }
+}
+
+impl Matcher for Domain {
+    fn matches(&self, meta: &Metadata) -> bool {
+        !meta.host.is_empty() && self.0.contains(meta.host)
+    }
+}
+
+/// Server names of sniffed TLS connections, patterns like `+.example.com`
+///
+/// Unlike the domain rules this ignores the host the client asked for, so
+/// tunnels opened to bare IPs still match by the name inside.
+pub struct Sni(DomainTrie<()>);
+
+impl Sni {
+    pub fn new(params: &[String]) -> Result<Sni, String> {
+        let mut trie = DomainTrie::new();
+        for pattern in params {
+            trie.insert(pattern, ()).map_err(|e| e.to_string())?;
+        }
+        Ok(Sni(trie))
+    }
+}
+
+impl Matcher for Sni {
+    fn matches(&self, meta: &Metadata) -> bool {
+        meta.sni.is_some_and(|sni| self.0.contains(sni))
+    }
+}
+
+/// Domains containing any of the keywords
+pub struct Keyword(Vec<String>);
+
+impl Keyword {
+    pub fn new(params: &[String]) -> Keyword {
+        Keyword(params.iter().map(|k| k.to_ascii_lowercase()).collect())
+    }
+}
+
+impl Matcher for Keyword {
+    fn matches(&self, meta: &Metadata) -> bool {
+        let host = meta.host.to_ascii_lowercase();
+        self.0.iter().any(|k| host.contains(k.as_str()))
+    }
+}
@@ -0,0 +1,55 @@
+//! IP-CIDR and DST-PORT rules, on the destination
+
+use super::{Matcher, Metadata};
+use crate::ip_trie::IpSet;
+
+/// Destination addresses in any of the networks
+pub struct IpCidr(IpSet);
+
+impl IpCidr {
+    pub fn new(params: &[String]) -> Result<IpCidr, String> {
+        Ok(IpCidr(parse_networks(params)?))
+    }
+}
+
+impl Matcher for IpCidr {
+    fn matches(&self, meta: &Metadata) -> bool {
+        meta.dst_ip.is_some_and(|ip| self.0.contains(ip))
+    }
+}
+
+/// Destination ports in the list
+pub struct Port(Vec<u16>);
+
+impl Port {
+    pub fn new(params: &[String]) -> Result<Port, String> {
+        Ok(Port(parse_ports(params)?))
+    }
+}
+
+impl Matcher for Port {
+    fn matches(&self, meta: &Metadata) -> bool {
+        self.0.contains(&meta.dst_port)
+    }
+}
+
+/// Networks of a rule, every one has to parse
+pub(super) fn parse_networks(params: &[String]) -> Result<IpSet, String> {
+    let mut invalid = None;
+    let set = IpSet::from_cidrs(params, |s| invalid = Some(s.to_owned()));
+    match invalid {
+        Some(s) => Err(format!("invalid network \"{}\"", s)),
+        None => Ok(set),
+    }
+}
+
+pub(super) fn parse_ports(params: &[String]) -> Result<Vec<u16>, String> {
+    params
+        .iter()
+        .map(|p| {
+            p.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port \"{}\"", p))
+        })
+        .collect()
+}
@@ -0,0 +1,154 @@
+//! GEOIP and IP-ASN rules
+
+use std::sync::Arc;
+
+use super::{Matcher, Metadata};
+use crate::geoip::GeoIP;
+
+/// Destination addresses located in the country
+pub struct Country {
+    geoip: Arc<GeoIP>,
+    code: String,
+}
+
+impl Country {
+    pub fn new(params: &[String], geoip: Option<Arc<GeoIP>>) -> Result<Country, String> {
+        let code = params
+            .first()
+            .ok_or_else(|| "GEOIP needs a country code".to_owned())?;
+        let geoip = geoip.ok_or_else(|| "no GeoIP database loaded".to_owned())?;
+        Ok(Country {
+            geoip,
+            code: code.to_ascii_uppercase(),
+        })
+    }
+}
+
+impl Matcher for Country {
+    fn matches(&self, meta: &Metadata) -> bool {
+        meta.dst_ip
+            .and_then(|ip| self.geoip.country(ip))
+            .is_some_and(|c| c.eq_ignore_ascii_case(&self.code))
+    }
+}
+
+/// Destination addresses announced by the autonomous system
+pub struct Asn {
+    asn: Arc<GeoIP>,
+    number: u32,
+}
+
+impl Asn {
+    pub fn new(params: &[String], asn: Option<Arc<GeoIP>>) -> Result<Asn, String> {
+        let param = params
+            .first()
+            .ok_or_else(|| "IP-ASN needs an AS number".to_owned())?;
+        // `AS15169` as well as `15169`
+        let digits = match param.get(..2) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("AS") => &param[2..],
+            _ => &param[..],
+        };
+        let number = digits
+            .parse()
+            .map_err(|_| format!("invalid AS number {}", param))?;
+        let asn = asn.ok_or_else(|| "no ASN database loaded".to_owned())?;
+        Ok(Asn { asn, number })
+    }
+}
+
+impl Matcher for Asn {
+    fn matches(&self, meta: &Metadata) -> bool {
+        meta.dst_ip
+            .and_then(|ip| self.asn.asn(ip))
+            .is_some_and(|asn| asn.number == self.number)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        if s.len() < 29 {
+            out.push(0x40 | s.len() as u8);
+        } else {
+            out.extend_from_slice(&[0x40 | 29, (s.len() - 29) as u8]);
+        }
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    /// A MaxMind database putting every address in AS `number`
+    fn asn_database(number: u16, organization: &str) -> Vec<u8> {
+        // One node both branches of which lead to the first record
+        let mut db = vec![0, 0, 17, 0, 0, 17];
+        db.extend_from_slice(&[0; 16]);
+        db.push(0xE2);
+        string(&mut db, "autonomous_system_number");
+        db.push(0xC2);
+        db.extend_from_slice(&number.to_be_bytes());
+        string(&mut db, "autonomous_system_organization");
+        string(&mut db, organization);
+
+        db.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+        db.push(0xE9);
+        string(&mut db, "node_count");
+        db.extend_from_slice(&[0xC1, 1]);
+        string(&mut db, "record_size");
+        db.extend_from_slice(&[0xA1, 24]);
+        string(&mut db, "ip_version");
+        db.extend_from_slice(&[0xA1, 4]);
+        string(&mut db, "database_type");
+        string(&mut db, "GeoLite2-ASN");
+        string(&mut db, "languages");
+        db.extend_from_slice(&[0x00, 0x04]);
+        string(&mut db, "binary_format_major_version");
+        db.extend_from_slice(&[0xA1, 2]);
+        string(&mut db, "binary_format_minor_version");
+        db.extend_from_slice(&[0xA1, 0]);
+        string(&mut db, "build_epoch");
+        db.extend_from_slice(&[0x01, 0x02, 0]);
+        string(&mut db, "description");
+        db.push(0xE0);
+        db
+    }
+
+    fn meta(dst_ip: Option<&str>) -> Metadata<'static> {
+        Metadata {
+            inbound: "http",
+            user: None,
+            uid: None,
+            host: "",
+            dst_ip: dst_ip.map(|ip| ip.parse().unwrap()),
+            resolved_ip: None,
+            dst_port: 443,
+            src_ip: None,
+            src_port: None,
+            udp: false,
+            protocol: None,
+            sni: None,
+            tls_version: None,
+        }
+    }
+
+    #[test]
+    fn matches_the_as_number() {
+        let db = Arc::new(GeoIP::empty());
+        db.replace(asn_database(15169, "GOOGLE")).unwrap();
+        let asn = db.asn("8.8.8.8".parse().unwrap()).unwrap();
+        assert_eq!(asn.number, 15169);
+        assert_eq!(asn.organization.as_deref(), Some("GOOGLE"));
+
+        let params = |p: &str| vec![p.to_owned()];
+        let google = Asn::new(&params("AS15169"), Some(db.clone())).unwrap();
+        assert!(google.matches(&meta(Some("8.8.8.8"))));
+        assert!(!google.matches(&meta(None)));
+        let bare = Asn::new(&params("15169"), Some(db.clone())).unwrap();
+        assert!(bare.matches(&meta(Some("8.8.4.4"))));
+        let other = Asn::new(&params("as13335"), Some(db.clone())).unwrap();
+        assert!(!other.matches(&meta(Some("8.8.8.8"))));
+
+        assert!(Asn::new(&params("ASN"), Some(db.clone())).is_err());
+        assert!(Asn::new(&[], Some(db)).is_err());
+        assert!(Asn::new(&params("AS15169"), None).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//! Connections each rule matched over the process lifetime
+//!
+//! Counters are keyed by the list a rule is in and its `KIND,params,target`
+//! line, so they survive reloads and rule edits for as long as the rule
+//! stays. Sub-rule jumps count the connections they were entered for.
+//!
+//! Compiled rules own their counters, the shared map only finds them again
+//! for the next compile and the API. Counters of rules no compiled set has
+//! any more are dropped from it as it grows.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::config::RuleConfig;
+
+/// Fewest counters pruned for, so small rule lists aren't scanned on
+/// every compile
+const MIN_PRUNE: usize = 64;
+
+#[derive(Default)]
+struct Counters {
+    map: HashMap<String, Weak<AtomicU64>>,
+    /// Size after the last pruning, pruned again once doubled so compiling
+    /// stays linear in the rules
+    pruned: usize,
+}
+
+lazy_static! {
+    static ref HITS: Mutex<Counters> = Mutex::new(Counters::default());
+}
+
+fn key(list: Option<&str>, rule: &RuleConfig) -> String {
+    format!("{}/{}", list.unwrap_or(""), rule.line())
+}
+
+/// Counter of `rule` in the sub-rule `list`, or in the main list for `None`
+pub fn counter(list: Option<&str>, rule: &RuleConfig) -> Arc<AtomicU64> {
+    let key = key(list, rule);
+    let mut hits = HITS.lock().unwrap();
+    if let Some(counter) = hits.map.get(&key).and_then(Weak::upgrade) {
+        return counter;
+    }
+    if hits.map.len() >= 2 * hits.pruned.max(MIN_PRUNE) {
+        hits.map.retain(|_, counter| counter.strong_count() > 0);
+        hits.pruned = hits.map.len();
+    }
+    let counter = Arc::new(AtomicU64::new(0));
+    hits.map.insert(key, Arc::downgrade(&counter));
+    counter
+}
+
+pub fn count(list: Option<&str>, rule: &RuleConfig) -> u64 {
+    HITS.lock()
+        .unwrap()
+        .map
+        .get(&key(list, rule))
+        .and_then(Weak::upgrade)
+        .map_or(0, |hits| hits.load(Ordering::Relaxed))
+}
+
+/// Counters in the map, dead ones included
+#[cfg(test)]
+fn len() -> usize {
+    HITS.lock().unwrap().map.len()
+}
+
+/// A rule with its matches, as listed by the API
+#[derive(Serialize, Debug)]
+pub struct RuleHits {
+    #[serde(flatten)]
+    pub rule: RuleConfig,
+    pub hits: u64,
+}
+
+pub fn list(list: Option<&str>, rules: &[RuleConfig]) -> Vec<RuleHits> {
+    rules
+        .iter()
+        .map(|rule| RuleHits {
+            rule: rule.clone(),
+            hits: count(list, rule),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(domain: &str) -> RuleConfig {
+        serde_yaml::from_str(&format!(
+            "{{ kind: DOMAIN, params: [{}], target: DIRECT }}",
+            domain
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn drops_counters_of_removed_rules() {
+        let kept = counter(Some("hits-test"), &rule("kept.example"));
+        kept.fetch_add(1, Ordering::Relaxed);
+        // Rules of many reloads, each gone with its compiled set
+        for reload in 0..100 {
+            for i in 0..10 {
+                let removed = rule(&format!("{}.{}.example", i, reload));
+                counter(Some("hits-test"), &removed).fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        // Other tests compile rules meanwhile, far fewer than the 1000 dropped
+        assert!(len() < 500);
+        assert_eq!(count(Some("hits-test"), &rule("kept.example")), 1);
+        assert_eq!(count(Some("hits-test"), &rule("0.0.example")), 0);
+        // Found again by the next compile
+        assert!(Arc::ptr_eq(
+            &kept,
+            &counter(Some("hits-test"), &rule("kept.example"))
+        ));
+    }
+}
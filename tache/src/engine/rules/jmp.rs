@@ -0,0 +1,82 @@
+//! Evaluation of rule lists and jumps into sub-rules
+
+use std::collections::HashMap;
+
+use log::error;
+
+use super::{schedule::Schedule, Action, Entry, Matched, Metadata, Outcome};
+
+/// Sees each rule looked at with its position and the lists it's in
+type Trace<'a> = dyn FnMut(&Entry, usize, &[&str], Outcome) + 'a;
+
+/// `display` prefixed by the sub-rule lists in `path`
+pub(super) fn qualified(path: &[&str], display: &str) -> String {
+    let mut rule = path.join("/");
+    if !rule.is_empty() {
+        rule.push('/');
+    }
+    rule.push_str(display);
+    rule
+}
+
+/// First match in `rules`, following jumps into `sub_rules`
+///
+/// `path` holds the lists being evaluated. The config refuses loops, the
+/// check here keeps a bad list from recursing forever anyway. `trace` sees
+/// every rule looked at with its position in its list.
+pub(super) fn evaluate<'r>(
+    rules: &'r [Entry],
+    sub_rules: &'r HashMap<String, Vec<Entry>>,
+    meta: &Metadata,
+    path: &mut Vec<&'r str>,
+    trace: &mut Trace<'_>,
+) -> Option<Matched<'r>> {
+    for (index, entry) in rules.iter().enumerate() {
+        if !entry.sources.is_empty() && !entry.sources.iter().any(|s| s == meta.inbound) {
+            trace(entry, index, path, Outcome::OtherInbound);
+            continue;
+        }
+        if !entry.matches(meta) {
+            trace(entry, index, path, Outcome::NoMatch);
+            continue;
+        }
+        if !entry.schedule.as_ref().is_none_or(Schedule::active) {
+            trace(entry, index, path, Outcome::Inactive);
+            continue;
+        }
+        match entry.action {
+            Action::Target(ref target) => {
+                trace(entry, index, path, Outcome::Matched);
+                return Some(Matched {
+                    rule: qualified(path, &entry.display),
+                    target: target.as_str(),
+                    dscp: entry.dscp,
+                    class: entry.class,
+                    keepalive: entry.keepalive,
+                });
+            }
+            Action::Jump(ref name) => {
+                if path.contains(&name.as_str()) {
+                    error!("Sub-rule {} reaches itself", name);
+                    trace(entry, index, path, Outcome::Loop);
+                    continue;
+                }
+                let list = match sub_rules.get(name) {
+                    Some(list) => list,
+                    None => {
+                        trace(entry, index, path, Outcome::Missing);
+                        continue;
+                    }
+                };
+                trace(entry, index, path, Outcome::Entered);
+                path.push(name);
+                let matched = evaluate(list, sub_rules, meta, path, trace);
+                path.pop();
+                if matched.is_some() {
+                    return matched;
+                }
+            }
+        }
+    }
+    None
+}
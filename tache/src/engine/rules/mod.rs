@@ -1,8 +1,677 @@
+//! Rule matching, deciding the outbound of each connection
+//!
+//! Rules are compiled once from the config and evaluated top to bottom, the
+//! first match wins. A rule with `sub-rule` evaluates a named list instead,
+//! falling through to the next rule when nothing in the list matched.
+
 pub mod direct;
 pub mod global;
+pub mod hits;
+
+mod domain;
+mod dst;
+mod geoip;
+mod jmp;
+mod protocol;
+mod provider;
+mod schedule;
+mod src;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use log::error;
 
-use crate::outbound;
+use crate::{
+    config::{Config, RuleConfig, TrafficClass},
+    geoip::Databases,
+    outbound,
+    provider::Providers,
+};
 
 pub trait Rule {
     fn run(&self) -> Option<Box<dyn outbound::Outbound>>;
 }
+
+/// What rules look at
+#[derive(Clone, Copy)]
+pub struct Metadata<'a> {
+    /// Name of the inbound the connection came in on
+    pub inbound: &'a str,
+    /// User the client authenticated as on the inbound
+    pub user: Option<&'a str>,
+    /// Local user running the client
+    pub uid: Option<u32>,
+    /// Destination domain, empty for IP destinations
+    pub host: &'a str,
+    pub dst_ip: Option<IpAddr>,
+    /// Address `host` resolved to, seen by rules on destination addresses
+    /// without `no-resolve` only
+    pub resolved_ip: Option<IpAddr>,
+    pub dst_port: u16,
+    pub src_ip: Option<IpAddr>,
+    pub src_port: Option<u16>,
+    pub udp: bool,
+    /// `http`, `tls` or `quic`, when known from the request or by sniffing
+    pub protocol: Option<&'a str>,
+    /// Server name of the TLS ClientHello, when the connection was sniffed
+    pub sni: Option<&'a str>,
+    /// Highest TLS version the client offered, e.g. `0x0303` for 1.2
+    pub tls_version: Option<u16>,
+}
+
+/// Condition of one rule
+trait Matcher: Send + Sync {
+    fn matches(&self, meta: &Metadata) -> bool;
+}
+
+/// Rules of kind MATCH
+struct Any;
+
+impl Matcher for Any {
+    fn matches(&self, _meta: &Metadata) -> bool {
+        true
+    }
+}
+
+enum Action {
+    Target(String),
+    Jump(String),
+}
+
+struct Entry {
+    /// `KIND,params` as shown in logs and the API
+    display: String,
+    sources: Vec<String>,
+    matcher: Box<dyn Matcher>,
+    action: Action,
+    schedule: Option<schedule::Schedule>,
+    dscp: Option<u8>,
+    class: Option<TrafficClass>,
+    keepalive: bool,
+    origin: Option<String>,
+    /// Rule on destination addresses that domains are resolved for
+    resolves: bool,
+    hits: Arc<AtomicU64>,
+}
+
+impl Entry {
+    fn matches(&self, meta: &Metadata) -> bool {
+        match (self.resolves, meta.dst_ip, meta.resolved_ip) {
+            (true, None, Some(ip)) => self.matcher.matches(&Metadata {
+                dst_ip: Some(ip),
+                ..*meta
+            }),
+            _ => self.matcher.matches(meta),
+        }
+    }
+}
+
+/// First rule matching a connection
+#[derive(Debug, PartialEq)]
+pub struct Matched<'r> {
+    /// The rule, prefixed by the sub-rule lists it was reached through
+    pub rule: String,
+    pub target: &'r str,
+    pub dscp: Option<u8>,
+    pub class: Option<TrafficClass>,
+    pub keepalive: bool,
+}
+
+/// How evaluation went past one rule
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// Limited to other inbounds
+    OtherInbound,
+    NoMatch,
+    /// Matched outside its schedule
+    Inactive,
+    Matched,
+    /// Matched and evaluated its sub-rule list
+    Entered,
+    /// Jumps into a sub-rule list already being evaluated
+    Loop,
+    /// Jumps into a sub-rule list that doesn't exist
+    Missing,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::OtherInbound => "skipped, other inbound",
+            Outcome::NoMatch => "no match",
+            Outcome::Inactive => "matched, outside its schedule",
+            Outcome::Matched => "matched",
+            Outcome::Entered => "matched, entering sub-rule",
+            Outcome::Loop => "matched, sub-rule loop",
+            Outcome::Missing => "matched, sub-rule not found",
+        })
+    }
+}
+
+/// One rule looked at while explaining a match
+#[derive(Debug)]
+pub struct Step {
+    /// The rule, prefixed by the sub-rule lists it was reached through
+    pub rule: String,
+    /// Position in its list, from 1
+    pub index: usize,
+    /// Include file or proxy the rule came from, `None` for the main config
+    pub origin: Option<String>,
+    pub outcome: Outcome,
+}
+
+/// Rule kinds `compile` understands
+pub const KINDS: &[&str] = &[
+    "DOMAIN",
+    "DOMAIN-SUFFIX",
+    "DOMAIN-KEYWORD",
+    "DST-SNI",
+    "IP-CIDR",
+    "IP-CIDR6",
+    "DST-PORT",
+    "SRC-IP-CIDR",
+    "SOURCE-IP-CIDR",
+    "SRC-PORT",
+    "IN-NAME",
+    "AUTH-USER",
+    "UID",
+    "GEOIP",
+    "IP-ASN",
+    "NETWORK",
+    "PROTOCOL",
+    "TLS-VERSION",
+    "RULE-SET",
+    "MATCH",
+    "FINAL",
+];
+
+/// `config` of the sub-rule `list`, or of the main list for `None`
+fn compile(
+    list: Option<&str>,
+    config: &RuleConfig,
+    databases: &Databases,
+    providers: &Providers,
+) -> Result<Entry, String> {
+    let raw = config.params.clone().unwrap_or_default();
+    // Clash's flag for IP rules to skip domain destinations
+    let no_resolve = |p: &String| p.eq_ignore_ascii_case("no-resolve");
+    let params: Vec<String> = raw.iter().filter(|p| !no_resolve(p)).cloned().collect();
+    let resolves = ["IP-CIDR", "IP-CIDR6", "GEOIP", "IP-ASN"]
+        .iter()
+        .any(|kind| config.kind.eq_ignore_ascii_case(kind))
+        && !raw.iter().any(no_resolve);
+    let matcher: Box<dyn Matcher> = match &config.kind.to_ascii_uppercase()[..] {
+        "DOMAIN" => Box::new(domain::Domain::exact(&params)?),
+        "DOMAIN-SUFFIX" => Box::new(domain::Domain::suffix(&params)?),
+        "DOMAIN-KEYWORD" => Box::new(domain::Keyword::new(&params)),
+        "DST-SNI" => Box::new(domain::Sni::new(&params)?),
+        "IP-CIDR" | "IP-CIDR6" => Box::new(dst::IpCidr::new(&params)?),
+        "DST-PORT" => Box::new(dst::Port::new(&params)?),
+        "SRC-IP-CIDR" | "SOURCE-IP-CIDR" => Box::new(src::IpCidr::new(&params)?),
+        "SRC-PORT" => Box::new(src::Port::new(&params)?),
+        "IN-NAME" => Box::new(src::InName::new(&params)),
+        "AUTH-USER" => Box::new(src::User::new(&params)),
+        "UID" => Box::new(src::Uid::new(&params)?),
+        "GEOIP" => Box::new(geoip::Country::new(&params, databases.country.clone())?),
+        "IP-ASN" => Box::new(geoip::Asn::new(&params, databases.asn.clone())?),
+        "NETWORK" => Box::new(protocol::Network::new(&params)?),
+        "PROTOCOL" => Box::new(protocol::Protocol::new(&params)?),
+        "TLS-VERSION" => Box::new(protocol::TlsVersion::new(&params)?),
+        "RULE-SET" => Box::new(provider::Provider::new(&params, providers)?),
+        "MATCH" | "FINAL" => Box::new(Any),
+        kind => return Err(format!("rule kind {} not supported yet", kind)),
+    };
+    let action = match config.sub_rule {
+        Some(ref name) => Action::Jump(name.clone()),
+        None => Action::Target(config.target.clone()),
+    };
+    let schedule = match config.schedule {
+        Some(ref schedule) => Some(schedule::Schedule::new(schedule)?),
+        None => None,
+    };
+    let display = if raw.is_empty() {
+        config.kind.clone()
+    } else {
+        format!("{},{}", config.kind, raw.join(","))
+    };
+    Ok(Entry {
+        display,
+        sources: config.source.clone(),
+        matcher,
+        action,
+        schedule,
+        dscp: config.dscp,
+        class: config.class,
+        keepalive: config.keepalive.unwrap_or(false),
+        origin: config.origin.clone(),
+        resolves,
+        hits: hits::counter(list, config),
+    })
+}
+
+fn compile_list(
+    list: Option<&str>,
+    rules: &[RuleConfig],
+    databases: &Databases,
+    providers: &Providers,
+) -> Vec<Entry> {
+    rules
+        .iter()
+        .filter_map(|rule| match compile(list, rule, databases, providers) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                error!("Skip rule {}, err: {}", rule.kind, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Like `compile_list`, failing on the first rule that doesn't compile
+fn compile_strict(
+    list: Option<&str>,
+    rules: &[RuleConfig],
+    databases: &Databases,
+    providers: &Providers,
+) -> Result<Vec<Entry>, String> {
+    rules
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| {
+            compile(list, rule, databases, providers)
+                .map_err(|e| format!("rule {} ({}): {}", i + 1, rule.kind, e))
+        })
+        .collect()
+}
+
+/// Compiled rules and sub-rule lists of a config
+pub struct RuleSet {
+    rules: Vec<Entry>,
+    sub_rules: HashMap<String, Vec<Entry>>,
+    /// Any UID rule, finding the socket owner is too costly otherwise
+    matches_uid: bool,
+    /// Any rule on sniffed details, sniffing delays the dial otherwise for
+    /// nothing
+    sniffs: bool,
+    /// Any rule domains are resolved for
+    resolves: bool,
+}
+
+impl RuleSet {
+    /// Rules of `config`, skipping the ones that don't compile, RULE-SET
+    /// rules match the entries of `providers`
+    pub fn new(config: &Config, databases: Databases, providers: &Providers) -> RuleSet {
+        let compile = |list: Option<&str>, rules: &[RuleConfig]| {
+            Ok(compile_list(list, rules, &databases, providers))
+        };
+        RuleSet::build(config, compile).unwrap()
+    }
+
+    /// Rules of `config`, failing when any of them doesn't compile
+    pub fn try_new(
+        config: &Config,
+        databases: Databases,
+        providers: &Providers,
+    ) -> Result<RuleSet, String> {
+        RuleSet::build(config, |list, rules| {
+            compile_strict(list, rules, &databases, providers)
+        })
+    }
+
+    fn build<F>(config: &Config, compile: F) -> Result<RuleSet, String>
+    where
+        F: Fn(Option<&str>, &[RuleConfig]) -> Result<Vec<Entry>, String>,
+    {
+        let any_kind = |kind: &str| {
+            config
+                .rules
+                .iter()
+                .chain(config.sub_rules.values().flatten())
+                .any(|rule| rule.kind.eq_ignore_ascii_case(kind))
+        };
+        let mut sub_rules = HashMap::with_capacity(config.sub_rules.len());
+        for (name, rules) in config.sub_rules.iter() {
+            let list =
+                compile(Some(name), rules).map_err(|e| format!("sub-rule {}: {}", name, e))?;
+            sub_rules.insert(name.clone(), list);
+        }
+        let rules = compile(None, &config.rules)?;
+        let resolves = rules
+            .iter()
+            .chain(sub_rules.values().flatten())
+            .any(|entry| entry.resolves);
+        Ok(RuleSet {
+            rules,
+            sub_rules,
+            matches_uid: any_kind("UID"),
+            sniffs: ["DST-SNI", "PROTOCOL", "TLS-VERSION"]
+                .iter()
+                .any(|kind| any_kind(kind)),
+            resolves,
+        })
+    }
+
+    /// Whether rules look at `Metadata::uid`
+    pub fn matches_uid(&self) -> bool {
+        self.matches_uid
+    }
+
+    /// Whether rules look at what sniffing the connection tells
+    pub fn sniffs(&self) -> bool {
+        self.sniffs
+    }
+
+    /// Whether a rule on destination addresses is reached before the one the
+    /// domain destination of `meta` matches
+    ///
+    /// Only then the domain has to be resolved locally, for the rules, the
+    /// outbound is still handed the domain. Rules before the first such one
+    /// don't look at `Metadata::resolved_ip`, evaluating again with it set
+    /// is like resolving at that rule.
+    pub fn needs_ip(&self, meta: &Metadata) -> bool {
+        if !self.resolves
+            || meta.dst_ip.is_some()
+            || meta.resolved_ip.is_some()
+            || meta.host.is_empty()
+        {
+            return false;
+        }
+        let mut needs = false;
+        jmp::evaluate(
+            &self.rules,
+            &self.sub_rules,
+            meta,
+            &mut Vec::new(),
+            &mut |entry, _, _, outcome| needs |= entry.resolves && outcome == Outcome::NoMatch,
+        );
+        needs
+    }
+
+    /// First rule matching `meta`, `None` when no rule does, counting the
+    /// hit
+    pub fn matched(&self, meta: &Metadata) -> Option<Matched<'_>> {
+        jmp::evaluate(
+            &self.rules,
+            &self.sub_rules,
+            meta,
+            &mut Vec::new(),
+            &mut |entry, _, _, outcome| {
+                if outcome == Outcome::Matched || outcome == Outcome::Entered {
+                    entry.hits.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+        )
+    }
+
+    /// Like `matched`, along with every rule looked at on the way
+    pub fn explain(&self, meta: &Metadata) -> (Option<Matched<'_>>, Vec<Step>) {
+        let mut steps = Vec::new();
+        let matched = jmp::evaluate(
+            &self.rules,
+            &self.sub_rules,
+            meta,
+            &mut Vec::new(),
+            &mut |entry, index, path, outcome| {
+                steps.push(Step {
+                    rule: jmp::qualified(path, &entry.display),
+                    index: index + 1,
+                    origin: entry.origin.clone(),
+                    outcome,
+                })
+            },
+        );
+        (matched, steps)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::ProviderConfig, state::Dirs};
+
+    fn rule(kind: &str, params: &[&str], target: &str, sub_rule: Option<&str>) -> RuleConfig {
+        RuleConfig {
+            kind: kind.to_owned(),
+            source: vec![],
+            params: Some(params.iter().map(|p| (*p).to_owned()).collect()),
+            target: target.to_owned(),
+            sub_rule: sub_rule.map(str::to_owned),
+            timeout: None,
+            schedule: None,
+            dscp: None,
+            class: None,
+            keepalive: None,
+            origin: None,
+        }
+    }
+
+    fn meta<'a>(host: &'a str, dst_port: u16) -> Metadata<'a> {
+        Metadata {
+            inbound: "http",
+            user: None,
+            uid: None,
+            host,
+            dst_ip: None,
+            resolved_ip: None,
+            dst_port,
+            src_ip: None,
+            src_port: None,
+            udp: false,
+            protocol: None,
+            sni: None,
+            tls_version: None,
+        }
+    }
+
+    #[test]
+    fn sub_rules_fall_through() {
+        let mut config = Config::new();
+        config.rules = vec![
+            rule("DST-PORT", &["443"], "", Some("tls")),
+            rule("DOMAIN-SUFFIX", &["example.com"], "plain", None),
+            rule("MATCH", &[], "DIRECT", None),
+        ];
+        config.sub_rules.insert(
+            "tls".to_owned(),
+            vec![rule("DOMAIN-SUFFIX", &["example.com"], "tls-proxy", None)],
+        );
+        let rules = RuleSet::new(&config, Databases::default(), &Providers::default());
+
+        assert_eq!(
+            rules.matched(&meta("www.example.com", 443)),
+            Some(Matched {
+                rule: "tls/DOMAIN-SUFFIX,example.com".to_owned(),
+                target: "tls-proxy",
+                dscp: None,
+                class: None,
+                keepalive: false,
+            })
+        );
+        assert_eq!(
+            rules.matched(&meta("www.example.com", 80)).unwrap().target,
+            "plain"
+        );
+        assert_eq!(
+            rules.matched(&meta("other.org", 443)).unwrap().target,
+            "DIRECT"
+        );
+
+        let (matched, steps) = rules.explain(&meta("www.example.com", 80));
+        assert_eq!(matched.unwrap().target, "plain");
+        let outcomes = steps.iter().map(|s| s.outcome).collect::<Vec<_>>();
+        assert_eq!(outcomes, vec![Outcome::NoMatch, Outcome::Matched]);
+        assert_eq!(steps[1].index, 2);
+    }
+
+    #[test]
+    fn protocol_rules() {
+        let mut config = Config::new();
+        config.rules = vec![
+            rule("TLS-VERSION", &["<1.2"], "REJECT", None),
+            rule("PROTOCOL", &["http"], "plain", None),
+            rule("NETWORK", &["udp"], "udp", None),
+            rule("MATCH", &[], "DIRECT", None),
+        ];
+        let rules = RuleSet::new(&config, Databases::default(), &Providers::default());
+        assert!(rules.sniffs());
+
+        let mut old_tls = meta("example.com", 443);
+        old_tls.protocol = Some("tls");
+        old_tls.tls_version = Some(0x0302);
+        assert_eq!(rules.matched(&old_tls).unwrap().target, "REJECT");
+        old_tls.tls_version = Some(0x0304);
+        assert_eq!(rules.matched(&old_tls).unwrap().target, "DIRECT");
+
+        let mut plain = meta("example.com", 80);
+        plain.protocol = Some("http");
+        assert_eq!(rules.matched(&plain).unwrap().target, "plain");
+
+        let mut udp = meta("example.com", 53);
+        udp.udp = true;
+        assert_eq!(rules.matched(&udp).unwrap().target, "udp");
+    }
+
+    #[test]
+    fn resolves_only_before_ip_rules() {
+        let mut config = Config::new();
+        config.rules = vec![
+            rule("DOMAIN-SUFFIX", &["example.com"], "proxy", None),
+            rule("IP-CIDR", &["10.0.0.0/8", "no-resolve"], "unresolved", None),
+            rule("DOMAIN", &["lan.example.org"], "DIRECT", None),
+            rule("IP-CIDR", &["192.168.0.0/16"], "DIRECT", None),
+            rule("MATCH", &[], "proxy", None),
+        ];
+        let rules = RuleSet::new(&config, Databases::default(), &Providers::default());
+
+        assert!(!rules.needs_ip(&meta("www.example.com", 443)));
+        assert!(!rules.needs_ip(&meta("lan.example.org", 443)));
+        let mut other = meta("other.org", 443);
+        assert!(rules.needs_ip(&other));
+        other.resolved_ip = Some("192.168.1.1".parse().unwrap());
+        assert!(!rules.needs_ip(&other));
+        assert_eq!(rules.matched(&other).unwrap().target, "DIRECT");
+
+        // A domain resolving into the range of a no-resolve rule skips it
+        let mut private = meta("private.org", 443);
+        private.resolved_ip = Some("10.1.1.1".parse().unwrap());
+        assert_eq!(rules.matched(&private).unwrap().target, "proxy");
+
+        let mut literal = meta("", 443);
+        literal.dst_ip = Some("10.1.1.1".parse().unwrap());
+        assert_eq!(rules.matched(&literal).unwrap().target, "unresolved");
+    }
+
+    #[test]
+    fn rule_sets_match_provider_entries() {
+        let path = std::env::temp_dir().join(format!("tache-rule-set-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "+.ads.example\n.sub.example\n*.one.example\nexact.example\n\
+             10.0.0.0/8\nDOMAIN-SUFFIX,tracker.example\nIP-CIDR6,2001:db8::/32\n",
+        )
+        .unwrap();
+        let provider = ProviderConfig::File {
+            name: "blocked".to_owned(),
+            path: path.to_string_lossy().into_owned(),
+            format: None,
+        };
+        let providers = Providers::new(&[], &[provider], &Dirs::new(None));
+        let mut config = Config::new();
+        config.rules = vec![
+            rule("RULE-SET", &["blocked"], "REJECT", None),
+            rule("MATCH", &[], "DIRECT", None),
+        ];
+        let rules = RuleSet::new(&config, Databases::default(), &providers);
+        let target = |host: &str, ip: Option<&str>| {
+            let mut meta = meta(host, 443);
+            meta.dst_ip = ip.map(|ip| ip.parse().unwrap());
+            rules.matched(&meta).unwrap().target
+        };
+
+        assert_eq!(target("ads.example", None), "REJECT");
+        assert_eq!(target("x.y.ads.example", None), "REJECT");
+        assert_eq!(target("sub.example", None), "DIRECT");
+        assert_eq!(target("a.sub.example", None), "REJECT");
+        assert_eq!(target("a.one.example", None), "REJECT");
+        assert_eq!(target("a.b.one.example", None), "DIRECT");
+        assert_eq!(target("exact.example", None), "REJECT");
+        assert_eq!(target("www.exact.example", None), "DIRECT");
+        assert_eq!(target("cdn.tracker.example", None), "REJECT");
+        assert_eq!(target("", Some("10.20.30.40")), "REJECT");
+        assert_eq!(target("", Some("11.0.0.1")), "DIRECT");
+        assert_eq!(target("", Some("2001:db8::1")), "REJECT");
+        // Domains aren't resolved for sets
+        let mut resolved = meta("resolves.example", 443);
+        resolved.resolved_ip = Some("10.0.0.1".parse().unwrap());
+        assert!(!rules.needs_ip(&resolved));
+        assert_eq!(rules.matched(&resolved).unwrap().target, "DIRECT");
+
+        let mut missing = config.clone();
+        missing.rules[0] = rule("RULE-SET", &["unknown"], "REJECT", None);
+        assert!(RuleSet::try_new(&missing, Databases::default(), &providers).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn counts_hits() {
+        let mut config = Config::new();
+        config.rules = vec![
+            rule("DOMAIN-SUFFIX", &["hits.example"], "counted", None),
+            rule("DOMAIN-SUFFIX", &["never.example"], "counted", None),
+        ];
+        let rules = RuleSet::new(&config, Databases::default(), &Providers::default());
+        assert!(rules.matched(&meta("www.hits.example", 443)).is_some());
+        rules.explain(&meta("www.hits.example", 443));
+        assert_eq!(hits::count(None, &config.rules[0]), 1);
+        assert_eq!(hits::count(None, &config.rules[1]), 0);
+    }
+
+    #[test]
+    fn explains_every_step() {
+        let mut config = Config::new();
+        let mut socks_only = rule("DOMAIN-SUFFIX", &["example.com"], "socks", None);
+        socks_only.source = vec!["socks".to_owned()];
+        let mut included = rule("DST-PORT", &["443"], "", Some("tls"));
+        included.origin = Some("rules.yaml".to_owned());
+        config.rules = vec![
+            socks_only,
+            rule("DST-PORT", &["443"], "", Some("missing")),
+            included,
+            rule("MATCH", &[], "DIRECT", None),
+        ];
+        config.sub_rules.insert(
+            "tls".to_owned(),
+            vec![
+                rule("DOMAIN", &["other.org"], "other", None),
+                rule("DOMAIN-SUFFIX", &["example.com"], "proxy", None),
+            ],
+        );
+        let rules = RuleSet::new(&config, Databases::default(), &Providers::default());
+
+        let (matched, steps) = rules.explain(&meta("www.example.com", 443));
+        assert_eq!(matched.unwrap().rule, "tls/DOMAIN-SUFFIX,example.com");
+        let steps = steps
+            .iter()
+            .map(|s| (s.rule.as_str(), s.index, s.origin.as_deref(), s.outcome))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            vec![
+                ("DOMAIN-SUFFIX,example.com", 1, None, Outcome::OtherInbound),
+                ("DST-PORT,443", 2, None, Outcome::Missing),
+                ("DST-PORT,443", 3, Some("rules.yaml"), Outcome::Entered),
+                ("tls/DOMAIN,other.org", 1, None, Outcome::NoMatch),
+                ("tls/DOMAIN-SUFFIX,example.com", 2, None, Outcome::Matched),
+            ]
+        );
+        assert_eq!(Outcome::Missing.to_string(), "matched, sub-rule not found");
+    }
+}
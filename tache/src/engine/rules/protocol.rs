@@ -0,0 +1,114 @@
+//! NETWORK, PROTOCOL and TLS-VERSION rules, on what the client speaks
+
+use super::{Matcher, Metadata};
+
+/// `tcp` or `udp` connections
+pub struct Network {
+    udp: bool,
+}
+
+impl Network {
+    pub fn new(params: &[String]) -> Result<Network, String> {
+        match params {
+            [network] if network.eq_ignore_ascii_case("tcp") => Ok(Network { udp: false }),
+            [network] if network.eq_ignore_ascii_case("udp") => Ok(Network { udp: true }),
+            _ => Err("expected tcp or udp".to_owned()),
+        }
+    }
+}
+
+impl Matcher for Network {
+    fn matches(&self, meta: &Metadata) -> bool {
+        meta.udp == self.udp
+    }
+}
+
+/// Connections of any of the sniffed protocols, `http`, `tls` or `quic`
+pub struct Protocol(Vec<String>);
+
+impl Protocol {
+    pub fn new(params: &[String]) -> Result<Protocol, String> {
+        let protocols = params
+            .iter()
+            .map(|p| p.to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        match protocols
+            .iter()
+            .find(|p| !["http", "tls", "quic"].contains(&p.as_str()))
+        {
+            Some(p) => Err(format!(
+                "unknown protocol {}, expected http, tls or quic",
+                p
+            )),
+            None => Ok(Protocol(protocols)),
+        }
+    }
+}
+
+impl Matcher for Protocol {
+    fn matches(&self, meta: &Metadata) -> bool {
+        meta.protocol
+            .is_some_and(|protocol| self.0.iter().any(|p| p == protocol))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Compare {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+/// TLS connections offering at most, at least or exactly a version, like
+/// `<1.2` or `>=1.3`
+pub struct TlsVersion {
+    compare: Compare,
+    version: u16,
+}
+
+impl TlsVersion {
+    pub fn new(params: &[String]) -> Result<TlsVersion, String> {
+        let param = match params {
+            [param] => param.trim(),
+            _ => return Err("expected one version like <1.2".to_owned()),
+        };
+        let (compare, version) = [
+            ("<=", Compare::LessOrEqual),
+            (">=", Compare::GreaterOrEqual),
+            ("<", Compare::Less),
+            (">", Compare::Greater),
+            ("=", Compare::Equal),
+        ]
+        .iter()
+        .find(|(prefix, _)| param.starts_with(prefix))
+        .map_or((Compare::Equal, param), |(prefix, compare)| {
+            (*compare, param[prefix.len()..].trim())
+        });
+        let version = match version {
+            "1.0" => 0x0301,
+            "1.1" => 0x0302,
+            "1.2" => 0x0303,
+            "1.3" => 0x0304,
+            _ => return Err(format!("unknown TLS version {}", version)),
+        };
+        Ok(TlsVersion { compare, version })
+    }
+}
+
+impl Matcher for TlsVersion {
+    fn matches(&self, meta: &Metadata) -> bool {
+        let offered = match meta.tls_version {
+            Some(offered) => offered,
+            None => return false,
+        };
+        match self.compare {
+            Compare::Less => offered < self.version,
+            Compare::LessOrEqual => offered <= self.version,
+            Compare::Equal => offered == self.version,
+            Compare::GreaterOrEqual => offered >= self.version,
+            Compare::Greater => offered > self.version,
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! RULE-SET rules, on the entries of a rule provider
+//!
+//! Entries are looked up as they would be written for the destination, so a
+//! set of any size costs a lookup per label of the domain or per prefix
+//! length of the address. Domain sets may hold `example.com`, `+.example.com`
+//! for the domain and its subdomains, `.example.com` for subdomains only and
+//! `*.example.com` for one label more. Address sets hold networks like
+//! `10.0.0.0/8`. Classical sets may hold `DOMAIN`, `DOMAIN-SUFFIX`,
+//! `IP-CIDR` and `IP-CIDR6` lines. Domains aren't resolved for a set.
+
+use std::{net::IpAddr, sync::Arc};
+
+use log::debug;
+
+use super::{Matcher, Metadata};
+use crate::provider::{CompactList, Providers, RuleProvider};
+
+/// Destinations listed by the provider
+pub struct Provider(Arc<RuleProvider>);
+
+impl Provider {
+    pub fn new(params: &[String], providers: &Providers) -> Result<Provider, String> {
+        let name = params
+            .first()
+            .ok_or_else(|| "RULE-SET needs a provider name".to_owned())?;
+        let provider = providers
+            .rules
+            .get(name)
+            .ok_or_else(|| format!("no rule provider named {}", name))?;
+        Ok(Provider(provider.clone()))
+    }
+}
+
+impl Matcher for Provider {
+    fn matches(&self, meta: &Metadata) -> bool {
+        // Not downloaded yet, or the copy on disk is gone
+        let rules = match self.0.rules() {
+            Ok(rules) => rules,
+            Err(e) => {
+                debug!("Rule provider {} has no rules, err: {}", self.0.name(), e);
+                return false;
+            }
+        };
+        (!meta.host.is_empty() && lists_domain(&rules, meta.host))
+            || meta.dst_ip.is_some_and(|ip| lists_ip(&rules, ip))
+    }
+}
+
+fn lists_domain(rules: &CompactList, host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if rules.contains(&host) || rules.contains(&format!("DOMAIN,{}", host)) {
+        return true;
+    }
+    let mut suffix = &host[..];
+    let mut subdomain = false;
+    loop {
+        if rules.contains(&format!("+.{}", suffix))
+            || rules.contains(&format!("DOMAIN-SUFFIX,{}", suffix))
+            || (subdomain && rules.contains(&format!(".{}", suffix)))
+        {
+            return true;
+        }
+        suffix = match suffix.find('.') {
+            Some(pos) => &suffix[pos + 1..],
+            None => return false,
+        };
+        // One label less than the host
+        if !subdomain && rules.contains(&format!("*.{}", suffix)) {
+            return true;
+        }
+        subdomain = true;
+    }
+}
+
+fn lists_ip(rules: &CompactList, ip: IpAddr) -> bool {
+    let bits = if ip.is_ipv4() { 32 } else { 128 };
+    (0..=bits).any(|prefix| {
+        let network = format!("{}/{}", mask(ip, prefix), prefix);
+        rules.contains(&network)
+            // Clash lists IPv6 networks under either kind
+            || rules.contains(&format!("IP-CIDR,{}", network))
+            || (ip.is_ipv6() && rules.contains(&format!("IP-CIDR6,{}", network)))
+    })
+}
+
+/// First address of the network of `ip` with `prefix` bits
+fn mask(ip: IpAddr, prefix: u32) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            IpAddr::from((u32::from(ip) & mask).to_be_bytes())
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            IpAddr::from((u128::from(ip) & mask).to_be_bytes())
+        }
+    }
+}
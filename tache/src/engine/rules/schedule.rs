@@ -0,0 +1,136 @@
+//! Time windows limiting when a rule applies
+
+use crate::config::ScheduleConfig;
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Days of the week and minutes of the day a rule is active, in local time
+pub struct Schedule {
+    /// Bit per day, Sunday first
+    days: u8,
+    /// Start and end minute, the window wraps past midnight when end < start
+    window: Option<(u16, u16)>,
+}
+
+fn parse_day(s: &str) -> Result<u8, String> {
+    let s = s.trim().to_ascii_lowercase();
+    DAYS.iter()
+        .position(|d| s.starts_with(d))
+        .map(|d| d as u8)
+        .ok_or_else(|| format!("invalid day \"{}\"", s))
+}
+
+fn parse_minute(s: &str) -> Result<u16, String> {
+    let invalid = || format!("invalid time \"{}\"", s);
+    let mut sp = s.trim().splitn(2, ':');
+    let hour = sp.next().and_then(|h| h.parse::<u16>().ok());
+    let minute = sp.next().and_then(|m| m.parse::<u16>().ok());
+    match (hour, minute) {
+        (Some(h), Some(m)) if h < 24 && m < 60 => Ok(h * 60 + m),
+        // 24:00 ends a window at midnight
+        (Some(24), Some(0)) => Ok(24 * 60),
+        _ => Err(invalid()),
+    }
+}
+
+impl Schedule {
+    pub fn new(config: &ScheduleConfig) -> Result<Schedule, String> {
+        let days = match config.days {
+            Some(ref days) => {
+                let mut bits = 0u8;
+                for day in days {
+                    let mut sp = day.splitn(2, '-');
+                    let first = parse_day(sp.next().unwrap_or(""))?;
+                    let last = match sp.next() {
+                        Some(last) => parse_day(last)?,
+                        None => first,
+                    };
+                    // mon-fri, or fri-mon across the weekend
+                    let mut d = first;
+                    loop {
+                        bits |= 1 << d;
+                        if d == last {
+                            break;
+                        }
+                        d = (d + 1) % 7;
+                    }
+                }
+                bits
+            }
+            None => 0x7f,
+        };
+        let window = match config.time {
+            Some(ref time) => {
+                let mut sp = time.splitn(2, '-');
+                let start = parse_minute(sp.next().unwrap_or(""))?;
+                let end = parse_minute(sp.next().ok_or("time needs a start and an end")?)?;
+                Some((start, end))
+            }
+            None => None,
+        };
+        Ok(Schedule { days, window })
+    }
+
+    /// Whether the schedule covers `minute` of `day`, Sunday being day 0
+    ///
+    /// A window past midnight belongs to the day it starts on.
+    pub fn active_at(&self, day: u8, minute: u16) -> bool {
+        let has_day = |d: u8| self.days & (1 << (d % 7)) != 0;
+        match self.window {
+            None => has_day(day),
+            Some((start, end)) if start <= end => has_day(day) && start <= minute && minute < end,
+            Some((start, end)) => {
+                (has_day(day) && minute >= start) || (has_day(day + 6) && minute < end)
+            }
+        }
+    }
+
+    /// Whether the schedule covers the current local time
+    pub fn active(&self) -> bool {
+        let now = time::now();
+        self.active_at(now.tm_wday as u8, (now.tm_hour * 60 + now.tm_min) as u16)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn schedule(time: Option<&str>, days: &[&str]) -> Schedule {
+        Schedule::new(&ScheduleConfig {
+            time: time.map(str::to_owned),
+            days: if days.is_empty() {
+                None
+            } else {
+                Some(days.iter().map(|d| (*d).to_owned()).collect())
+            },
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn working_hours_and_nights() {
+        let work = schedule(Some("09:00-18:00"), &["mon-fri"]);
+        assert!(work.active_at(1, 9 * 60));
+        assert!(!work.active_at(1, 18 * 60));
+        assert!(!work.active_at(0, 12 * 60));
+        assert!(work.active_at(5, 17 * 60 + 59));
+
+        // Friday night runs into Saturday morning, Sunday night does not
+        let night = schedule(Some("22:00-06:00"), &["fri", "sat"]);
+        assert!(night.active_at(5, 23 * 60));
+        assert!(night.active_at(6, 5 * 60));
+        assert!(night.active_at(0, 60));
+        assert!(!night.active_at(1, 60));
+
+        let weekend = schedule(None, &["sat-sun"]);
+        assert!(weekend.active_at(6, 0) && weekend.active_at(0, 0));
+        assert!(!weekend.active_at(1, 0));
+
+        assert!(Schedule::new(&ScheduleConfig {
+            time: Some("25:00-26:00".to_owned()),
+            days: None,
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! SRC-IP-CIDR, SRC-PORT, IN-NAME, AUTH-USER and UID rules, on the client
+
+use super::{
+    dst::{parse_networks, parse_ports},
+    Matcher, Metadata,
+};
+use crate::ip_trie::IpSet;
+
+/// Clients in any of the networks
+pub struct IpCidr(IpSet);
+
+impl IpCidr {
+    pub fn new(params: &[String]) -> Result<IpCidr, String> {
+        Ok(IpCidr(parse_networks(params)?))
+    }
+}
+
+impl Matcher for IpCidr {
+    fn matches(&self, meta: &Metadata) -> bool {
+        meta.src_ip.is_some_and(|ip| self.0.contains(ip))
+    }
+}
+
+/// Client ports in the list
+pub struct Port(Vec<u16>);
+
+impl Port {
+    pub fn new(params: &[String]) -> Result<Port, String> {
+        Ok(Port(parse_ports(params)?))
+    }
+}
+
+impl Matcher for Port {
+    fn matches(&self, meta: &Metadata) -> bool {
+        meta.src_port.is_some_and(|port| self.0.contains(&port))
+    }
+}
+
+/// Connections from any of the named inbounds
+pub struct InName(Vec<String>);
+
+impl InName {
+    pub fn new(params: &[String]) -> InName {
+        InName(params.to_vec())
+    }
+}
+
+impl Matcher for InName {
+    fn matches(&self, meta: &Metadata) -> bool {
+        self.0.iter().any(|name| name == meta.inbound)
+    }
+}
+
+/// Clients authenticated as any of the users
+pub struct User(Vec<String>);
+
+impl User {
+    pub fn new(params: &[String]) -> User {
+        User(params.to_vec())
+    }
+}
+
+impl Matcher for User {
+    fn matches(&self, meta: &Metadata) -> bool {
+        meta.user
+            .is_some_and(|user| self.0.iter().any(|u| u == user))
+    }
+}
+
+/// Local clients run by any of the users, by numeric uid
+pub struct Uid(Vec<u32>);
+
+impl Uid {
+    pub fn new(params: &[String]) -> Result<Uid, String> {
+        params
+            .iter()
+            .map(|p| {
+                p.trim()
+                    .parse::<u32>()
+                    .map_err(|_| format!("invalid uid \"{}\"", p))
+            })
+            .collect::<Result<_, _>>()
+            .map(Uid)
+    }
+}
+
+impl Matcher for Uid {
+    fn matches(&self, meta: &Metadata) -> bool {
+        meta.uid.is_some_and(|uid| self.0.contains(&uid))
+    }
+}
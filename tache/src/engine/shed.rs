@@ -0,0 +1,146 @@
+//! Global caps on connections and on how fast they are accepted
+//!
+//! Connections over a cap are refused at accept, before any work is spent on
+//! them, so a small device degrades predictably instead of running out of
+//! memory. Inbounds answer them with the cheapest error their protocol has.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::config::LimiterConfig;
+
+/// Sent to HTTP clients refused at accept
+pub const HTTP_REPLY: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+/// SOCKS5 method selection accepting none, clients fail before their request
+pub const SOCKS_REPLY: &[u8] = &[0x05, 0xff];
+
+/// Why a connection was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shed {
+    /// `max-connections` are open
+    Full,
+    /// `max-accepts-per-second` were accepted this second
+    Rate,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ShedStats {
+    pub active: usize,
+    /// Refused since start as too many were open
+    pub full: u64,
+    /// Refused since start as too many came in one second
+    pub rate: u64,
+}
+
+pub struct LoadShedder {
+    max_connections: Option<usize>,
+    max_accepts: Option<u32>,
+    started: Instant,
+    active: AtomicUsize,
+    /// Second of the accept window and the connections accepted in it
+    window: Mutex<(u64, u32)>,
+    shed_full: AtomicU64,
+    shed_rate: AtomicU64,
+}
+
+/// Held for the lifetime of an accepted connection
+pub struct Admission {
+    shedder: Arc<LoadShedder>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.shedder.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LoadShedder {
+    pub fn new(config: Option<&LimiterConfig>) -> LoadShedder {
+        LoadShedder {
+            max_connections: config.and_then(|c| c.max_connections),
+            max_accepts: config.and_then(|c| c.max_accepts_per_second),
+            started: Instant::now(),
+            active: AtomicUsize::new(0),
+            window: Mutex::new((0, 0)),
+            shed_full: AtomicU64::new(0),
+            shed_rate: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a connection just accepted, `Err` when it has to be refused
+    pub fn admit(self: &Arc<Self>) -> Result<Admission, Shed> {
+        self.admit_at(self.started.elapsed().as_secs())
+    }
+
+    fn admit_at(self: &Arc<Self>, second: u64) -> Result<Admission, Shed> {
+        let mut window = self.window.lock().unwrap();
+        if window.0 != second {
+            *window = (second, 0);
+        }
+        if self.max_accepts.is_some_and(|max| window.1 >= max) {
+            self.shed_rate.fetch_add(1, Ordering::Relaxed);
+            return Err(Shed::Rate);
+        }
+        let active = self.active.fetch_add(1, Ordering::Relaxed);
+        let admission = Admission {
+            shedder: self.clone(),
+        };
+        if self.max_connections.is_some_and(|max| active >= max) {
+            self.shed_full.fetch_add(1, Ordering::Relaxed);
+            return Err(Shed::Full);
+        }
+        window.1 += 1;
+        Ok(admission)
+    }
+
+    pub fn stats(&self) -> ShedStats {
+        ShedStats {
+            active: self.active.load(Ordering::Relaxed),
+            full: self.shed_full.load(Ordering::Relaxed),
+            rate: self.shed_rate.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Send `reply` to a refused client, closing once `stream` is dropped
+pub async fn refuse<S: AsyncWrite + Unpin>(mut stream: S, reply: &[u8]) {
+    let _ = stream.write_all(reply).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::LimitPolicy;
+
+    #[test]
+    fn sheds_over_limits() {
+        let shedder = Arc::new(LoadShedder::new(Some(&LimiterConfig {
+            per_host: None,
+            per_client: None,
+            policy: LimitPolicy::Reject,
+            queue_timeout: None,
+            max_connections: Some(2),
+            max_accepts_per_second: Some(3),
+        })));
+
+        let first = shedder.admit_at(0).unwrap();
+        let _second = shedder.admit_at(0).unwrap();
+        assert_eq!(shedder.admit_at(0).err(), Some(Shed::Full));
+        drop(first);
+        let _third = shedder.admit_at(0).unwrap();
+        assert_eq!(shedder.admit_at(0).err(), Some(Shed::Rate));
+        assert_eq!(shedder.admit_at(1).err(), Some(Shed::Full));
+
+        let stats = shedder.stats();
+        assert_eq!((stats.active, stats.full, stats.rate), (2, 2, 1));
+    }
+}
@@ -0,0 +1,319 @@
+//! Protocol details read from the first bytes a client sends
+//!
+//! Rules may route by what the client is about to say rather than where it
+//! says it goes, e.g. the SNI of a tunnel opened to a bare IP because the
+//! client resolved the name over encrypted DNS.
+
+use super::fingerprint::{is_grease, Fingerprint, HelloFields};
+
+/// Outcome of looking at the bytes received so far
+#[derive(Debug, PartialEq)]
+pub enum Sniff<T> {
+    Found(T),
+    /// More bytes are needed to tell
+    Incomplete,
+    /// The bytes are not of this protocol
+    NotMatched,
+}
+
+pub const TLS: &str = "tls";
+pub const HTTP: &str = "http";
+pub const QUIC: &str = "quic";
+
+const HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME: u16 = 0x0000;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+const SUPPORTED_GROUPS: u16 = 0x000a;
+const EC_POINT_FORMATS: u16 = 0x000b;
+const SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const ALPN: u16 = 0x0010;
+
+/// What the start of a connection tells about it
+#[derive(Debug, Default, PartialEq)]
+pub struct Sniffed {
+    /// `TLS` or `HTTP`
+    pub protocol: Option<&'static str>,
+    pub sni: Option<String>,
+    /// Highest TLS version offered, e.g. `0x0303` for TLS 1.2
+    pub tls_version: Option<u16>,
+    pub fingerprint: Option<Fingerprint>,
+}
+
+/// Protocol of the first bytes of a connection and what it tells
+pub fn sniff(buf: &[u8]) -> Sniff<Sniffed> {
+    match client_hello(buf) {
+        Sniff::Found(hello) => {
+            return Sniff::Found(Sniffed {
+                protocol: Some(TLS),
+                sni: hello.sni,
+                tls_version: Some(hello.version),
+                fingerprint: Some(hello.fingerprint),
+            })
+        }
+        Sniff::Incomplete => return Sniff::Incomplete,
+        Sniff::NotMatched => {}
+    }
+    match http_request(buf) {
+        Sniff::Found(()) => Sniff::Found(Sniffed {
+            protocol: Some(HTTP),
+            ..Sniffed::default()
+        }),
+        Sniff::Incomplete => Sniff::Incomplete,
+        Sniff::NotMatched => Sniff::NotMatched,
+    }
+}
+
+/// What a TLS ClientHello tells about the connection
+#[derive(Debug, PartialEq)]
+pub struct ClientHello {
+    /// Host name of the server_name extension, lowercase
+    pub sni: Option<String>,
+    /// Highest version offered
+    pub version: u16,
+    pub fingerprint: Fingerprint,
+}
+
+/// Bounds-checked reads, `None` once past the end
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from(b[0]) << 8 | u16::from(b[1]))
+    }
+
+    /// What's left as big-endian `u16`s
+    fn u16s(mut self) -> Vec<u16> {
+        let mut values = Vec::with_capacity(self.0.len() / 2);
+        while let Some(value) = self.u16() {
+            values.push(value);
+        }
+        values
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    /// Bytes prefixed by a length of `width` bytes
+    fn vec(&mut self, width: usize) -> Option<Reader<'a>> {
+        let len = match width {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => self.u24()?,
+        };
+        self.take(len).map(Reader)
+    }
+}
+
+/// ClientHello at the start of `buf`, which holds the first bytes of a
+/// connection
+pub fn client_hello(buf: &[u8]) -> Sniff<ClientHello> {
+    let mut record = Reader(buf);
+    match record.u8() {
+        Some(HANDSHAKE) => {}
+        Some(_) => return Sniff::NotMatched,
+        None => return Sniff::Incomplete,
+    }
+    match record.u8() {
+        Some(3) => {}
+        Some(_) => return Sniff::NotMatched,
+        None => return Sniff::Incomplete,
+    }
+    let payload = match (record.u8(), record.vec(2)) {
+        (Some(_), Some(payload)) => payload,
+        _ => return Sniff::Incomplete,
+    };
+    // A ClientHello split over records is rare enough to leave unparsed
+    match parse_client_hello(payload) {
+        Some(hello) => Sniff::Found(hello),
+        None => Sniff::NotMatched,
+    }
+}
+
+fn parse_client_hello(mut payload: Reader) -> Option<ClientHello> {
+    if payload.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    let mut body = payload.vec(3)?;
+    let legacy_version = body.u16()?;
+    body.take(32)?; // random
+    body.vec(1)?; // legacy_session_id
+    let ciphers = body.vec(2)?.u16s();
+    body.vec(1)?; // legacy_compression_methods
+
+    let mut sni = None;
+    let mut fields = HelloFields {
+        legacy_version,
+        version: legacy_version,
+        ciphers,
+        ..HelloFields::default()
+    };
+    // Extensions are optional before TLS 1.3
+    let mut extensions = body.vec(2).unwrap_or(Reader(&[]));
+    while let (Some(kind), Some(mut data)) = (extensions.u16(), extensions.vec(2)) {
+        fields.extensions.push(kind);
+        if kind == SERVER_NAME {
+            let mut names = data.vec(2)?;
+            while let (Some(name_type), Some(name)) = (names.u8(), names.vec(2)) {
+                // Only host_name(0) is defined
+                if name_type == 0 {
+                    let name = std::str::from_utf8(name.0).ok()?;
+                    sni = Some(name.to_ascii_lowercase());
+                    break;
+                }
+            }
+        } else if kind == SUPPORTED_VERSIONS {
+            // TLS 1.3 offers its versions here, legacy_version stays 1.2
+            let mut versions = data.vec(1)?;
+            while let Some(version) = versions.u16() {
+                if !is_grease(version) && version > fields.version {
+                    fields.version = version;
+                }
+            }
+        } else if kind == SUPPORTED_GROUPS {
+            fields.groups = data.vec(2)?.u16s();
+        } else if kind == EC_POINT_FORMATS {
+            fields.point_formats = data.vec(1)?.0.to_vec();
+        } else if kind == SIGNATURE_ALGORITHMS {
+            fields.signature_algorithms = data.vec(2)?.u16s();
+        } else if kind == ALPN {
+            fields.alpn = data.vec(2)?.vec(1).map(|protocol| protocol.0.to_vec());
+        }
+    }
+    Some(ClientHello {
+        sni,
+        version: fields.version,
+        fingerprint: Fingerprint::of(&fields),
+    })
+}
+
+/// Whether `datagram` opens a QUIC connection, an Initial packet of QUIC
+/// version 1 or 2
+pub fn quic_initial(datagram: &[u8]) -> bool {
+    // Long header with the fixed bit, then the version
+    if datagram.len() < 5 || datagram[0] & 0xc0 != 0xc0 {
+        return false;
+    }
+    let packet_type = (datagram[0] >> 4) & 0x03;
+    match datagram[1..5] {
+        [0x00, 0x00, 0x00, 0x01] => packet_type == 0,
+        [0x6b, 0x33, 0x43, 0xcf] => packet_type == 1,
+        _ => false,
+    }
+}
+
+/// Request line start of HTTP/1, an uppercase method and a space
+pub fn http_request(buf: &[u8]) -> Sniff<()> {
+    // Longest standard method is OPTIONS
+    const MAX_METHOD: usize = 7;
+    for (i, &b) in buf.iter().take(MAX_METHOD + 1).enumerate() {
+        match b {
+            b'A'..=b'Z' => {}
+            b' ' if i >= 3 => return Sniff::Found(()),
+            _ => return Sniff::NotMatched,
+        }
+    }
+    if buf.len() > MAX_METHOD {
+        Sniff::NotMatched
+    } else {
+        Sniff::Incomplete
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+        let mut message = vec![CLIENT_HELLO, 0];
+        message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        message.extend_from_slice(&body);
+        let mut record = vec![HANDSHAKE, 3, 1];
+        record.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        record.extend_from_slice(&message);
+        record
+    }
+
+    #[test]
+    fn reads_sni() {
+        let host = b"Example.com";
+        let mut ext = vec![0, 0, 0, host.len() as u8 + 5, 0, host.len() as u8 + 3, 0, 0];
+        ext.push(host.len() as u8);
+        ext.extend_from_slice(host);
+        let hello = record(&ext);
+
+        assert_eq!(
+            client_hello(&hello),
+            Sniff::Found(ClientHello {
+                sni: Some("example.com".to_owned()),
+                version: 0x0303,
+                fingerprint: Fingerprint {
+                    ja3: "1e7c622032b0cb79401b0f7be3793a1a".to_owned(),
+                    ja4: "t12d010100_0f2cb44170f4_000000000000".to_owned(),
+                },
+            })
+        );
+        assert_eq!(client_hello(&hello[..20]), Sniff::Incomplete);
+        assert_eq!(
+            client_hello(&record(&[])),
+            Sniff::Found(ClientHello {
+                sni: None,
+                version: 0x0303,
+                fingerprint: Fingerprint {
+                    ja3: "ea1e247991e541e39bf918cb7cfa5139".to_owned(),
+                    ja4: "t12i010000_0f2cb44170f4_000000000000".to_owned(),
+                },
+            })
+        );
+        assert_eq!(client_hello(b"GET / HTTP/1.1\r\n"), Sniff::NotMatched);
+    }
+
+    #[test]
+    fn tells_protocols_apart() {
+        // supported_versions with GREASE, TLS 1.3 and 1.2
+        let hello = record(&[0, 0x2b, 0, 7, 6, 0x3a, 0x3a, 3, 4, 3, 3]);
+        match sniff(&hello) {
+            Sniff::Found(sniffed) => {
+                assert_eq!(sniffed.protocol, Some(TLS));
+                assert_eq!(sniffed.tls_version, Some(0x0304));
+            }
+            other => panic!("expected a ClientHello, got {:?}", other),
+        }
+
+        assert_eq!(
+            sniff(b"POST /a HTTP/1.1\r\n"),
+            Sniff::Found(Sniffed {
+                protocol: Some(HTTP),
+                ..Sniffed::default()
+            })
+        );
+        assert_eq!(sniff(b"GE"), Sniff::Incomplete);
+        assert_eq!(sniff(b"SSH-2.0-OpenSSH"), Sniff::NotMatched);
+        assert_eq!(sniff(b""), Sniff::Incomplete);
+
+        assert!(quic_initial(&[0xc3, 0, 0, 0, 1, 8]));
+        // Handshake packet, and a short header one
+        assert!(!quic_initial(&[0xe3, 0, 0, 0, 1, 8]));
+        assert!(!quic_initial(&[0x43, 0, 0, 0, 1, 8]));
+    }
+}
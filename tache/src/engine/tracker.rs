@@ -0,0 +1,353 @@
+//! Lifecycle events of one proxied connection
+
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use log::info;
+use serde::Serialize;
+
+use super::{fingerprint::Fingerprint, ConnectionMeta};
+use crate::{
+    config::TrafficClass,
+    context::SharedContext,
+    event::{CloseReason, Event},
+    geoip::Asn,
+};
+
+/// Closed connections kept for the API
+const MAX_RECENT: usize = 256;
+
+/// A connection that ended, as listed by `/connections`
+#[derive(Clone, Debug, Serialize)]
+pub struct ClosedConnection {
+    pub id: u64,
+    pub inbound: String,
+    pub host: String,
+    pub rule: Option<String>,
+    pub proxy: Option<String>,
+    pub up: u64,
+    pub down: u64,
+    pub duration_ms: u64,
+    pub reason: CloseReason,
+    /// JA3 and JA4 of a sniffed ClientHello
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<Fingerprint>,
+    /// Network of the destination, with an ASN database
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<Asn>,
+    /// Relay scheduling class, with `qos`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class: Option<TrafficClass>,
+}
+
+/// Closed connections counted by reason, and the latest of them
+#[derive(Default)]
+pub struct CloseStats {
+    counts: [AtomicU64; 9],
+    recent: Mutex<VecDeque<ClosedConnection>>,
+}
+
+impl CloseStats {
+    pub fn new() -> CloseStats {
+        CloseStats::default()
+    }
+
+    fn add(&self, closed: ClosedConnection) {
+        let index = CloseReason::ALL
+            .iter()
+            .position(|&r| r == closed.reason)
+            .unwrap_or(0);
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == MAX_RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(closed);
+    }
+
+    /// Connections closed so far for every reason
+    pub fn counts(&self) -> Vec<(CloseReason, u64)> {
+        CloseReason::ALL
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(&reason, count)| (reason, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Latest closed connections, oldest first
+    pub fn recent(&self) -> Vec<ClosedConnection> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Forget the latest closed connections, counts are kept
+    pub fn clear_recent(&self) -> usize {
+        let mut recent = self.recent.lock().unwrap();
+        let cleared = recent.len();
+        *recent = VecDeque::new();
+        cleared
+    }
+}
+
+/// Publishes `ConnectionOpened` when created and `ConnectionClosed` when
+/// dropped, so every exit path of a connection task is reported.
+///
+/// A tracker dropped without a recorded reason went down with its task.
+pub struct ConnectionTracker {
+    context: SharedContext,
+    id: u64,
+    inbound: String,
+    host: String,
+    /// Client address without the port, for usage by client
+    source: String,
+    rule: Option<String>,
+    proxy: Option<String>,
+    fingerprint: Option<Fingerprint>,
+    asn: Option<Asn>,
+    class: Option<TrafficClass>,
+    started: Instant,
+    up: u64,
+    down: u64,
+    reason: Option<CloseReason>,
+}
+
+impl ConnectionTracker {
+    pub fn open(
+        context: &SharedContext,
+        inbound: &str,
+        meta: &ConnectionMeta,
+    ) -> ConnectionTracker {
+        let id = context.next_connection_id();
+        context.events().publish(Event::ConnectionOpened {
+            id,
+            inbound: inbound.to_owned(),
+            source: meta.src_addr.map(|addr| addr.to_string()),
+            host: meta.host.clone(),
+        });
+        ConnectionTracker {
+            context: context.clone(),
+            id,
+            inbound: inbound.to_owned(),
+            host: meta.host.clone(),
+            source: meta
+                .src_addr
+                .map_or_else(|| "-".to_owned(), |addr| addr.ip().to_string()),
+            rule: None,
+            proxy: None,
+            fingerprint: None,
+            asn: None,
+            class: None,
+            started: Instant::now(),
+            up: 0,
+            down: 0,
+            reason: None,
+        }
+    }
+
+    pub fn rule_matched(&mut self, rule: &str, proxy: &str) {
+        self.context.events().publish(Event::RuleMatched {
+            id: self.id,
+            rule: rule.to_owned(),
+            proxy: proxy.to_owned(),
+        });
+        self.rule = Some(rule.to_owned());
+        self.proxy = Some(proxy.to_owned());
+    }
+
+    /// Look up the network of the destination address
+    pub fn destination(&mut self, ip: Option<IpAddr>) {
+        if let (Some(ip), Some(asn)) = (ip, self.context.asn()) {
+            self.asn = asn.asn(ip);
+        }
+    }
+
+    pub fn classified(&mut self, class: Option<TrafficClass>) {
+        self.class = class;
+    }
+
+    /// Record the ClientHello fingerprint of a sniffed tunnel
+    pub fn fingerprinted(&mut self, fingerprint: &Fingerprint) {
+        self.fingerprint = Some(fingerprint.clone());
+    }
+
+    /// Count relayed bytes, sent to and received from the remote
+    pub fn transferred(&mut self, up: u64, down: u64) {
+        self.up += up;
+        self.down += down;
+        let traffic = self.context.traffic();
+        traffic.add_up(up);
+        traffic.add_down(down);
+        let proxy = self.proxy.as_ref().map_or("-", String::as_str);
+        self.context.usage().add(proxy, &self.source, up, down);
+    }
+
+    /// Record why the connection ends, the first reason sticks
+    pub fn close(&mut self, reason: CloseReason) {
+        self.reason.get_or_insert(reason);
+    }
+}
+
+impl Drop for ConnectionTracker {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        let duration_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        let reason = self.reason.unwrap_or(CloseReason::Reload);
+        let fingerprint = self
+            .fingerprint
+            .as_ref()
+            .map_or_else(String::new, |f| format!(", ja3 {} ja4 {}", f.ja3, f.ja4));
+        info!(
+            "[{}] {} -> {} via {} closed: {}, up {} down {} in {}ms{}",
+            self.inbound,
+            self.id,
+            self.host,
+            self.proxy.as_ref().map_or("-", String::as_str),
+            reason,
+            self.up,
+            self.down,
+            duration_ms,
+            fingerprint
+        );
+        self.context.events().publish(Event::ConnectionClosed {
+            id: self.id,
+            up: self.up,
+            down: self.down,
+            duration_ms,
+            reason,
+        });
+        self.context.close_stats().add(ClosedConnection {
+            id: self.id,
+            inbound: self.inbound.clone(),
+            host: self.host.clone(),
+            rule: self.rule.take(),
+            proxy: self.proxy.take(),
+            up: self.up,
+            down: self.down,
+            duration_ms,
+            reason,
+            fingerprint: self.fingerprint.take(),
+            asn: self.asn.take(),
+            class: self.class,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{config::Config, context::Context};
+
+    fn closed(id: u64, reason: CloseReason) -> ClosedConnection {
+        ClosedConnection {
+            id,
+            inbound: "HTTP".to_owned(),
+            host: "example.com".to_owned(),
+            rule: None,
+            proxy: None,
+            up: 0,
+            down: 0,
+            duration_ms: 0,
+            reason,
+            fingerprint: None,
+            asn: None,
+            class: None,
+        }
+    }
+
+    #[test]
+    fn close_stats_count_by_reason() {
+        let stats = CloseStats::new();
+        for id in 0..MAX_RECENT as u64 + 2 {
+            stats.add(closed(id, CloseReason::ClientEof));
+        }
+        stats.add(closed(1000, CloseReason::DialTimeout));
+
+        let counts = stats.counts();
+        assert!(counts.contains(&(CloseReason::ClientEof, MAX_RECENT as u64 + 2)));
+        assert!(counts.contains(&(CloseReason::DialTimeout, 1)));
+        assert!(counts.contains(&(CloseReason::Reject, 0)));
+
+        let recent = stats.recent();
+        assert_eq!(recent.len(), MAX_RECENT);
+        assert_eq!(recent.last().unwrap().reason, CloseReason::DialTimeout);
+        assert_eq!(recent[0].id, 3);
+    }
+
+    #[test]
+    fn publishes_the_lifecycle() {
+        let context: SharedContext = Arc::new(Context::new(Config::new()).unwrap());
+        let mut events = context.events().subscribe();
+        let meta = ConnectionMeta {
+            udp: false,
+            inbound: "HTTP".to_owned(),
+            user: None,
+            uid: None,
+            host: "example.com".to_owned(),
+            dst_port: 443,
+            src_addr: "192.0.2.7:50000".parse().ok(),
+            dst_addr: None,
+            protocol: None,
+            sni: None,
+            tls_version: None,
+            fingerprint: None,
+        };
+        let mut tracker = ConnectionTracker::open(&context, "HTTP", &meta);
+        tracker.rule_matched("DOMAIN-SUFFIX,example.com", "PROXY");
+        tracker.transferred(10, 20);
+        tracker.transferred(1, 2);
+        tracker.close(CloseReason::UpstreamEof);
+        tracker.close(CloseReason::Error);
+        drop(tracker);
+
+        let mut next = || futures::executor::block_on(events.next()).unwrap();
+        let id = match next() {
+            Event::ConnectionOpened {
+                id,
+                inbound,
+                source,
+                host,
+            } => {
+                assert_eq!(inbound, "HTTP");
+                assert_eq!(source.as_deref(), Some("192.0.2.7:50000"));
+                assert_eq!(host, "example.com");
+                id
+            }
+            event => panic!("unexpected event {:?}", event),
+        };
+        match next() {
+            Event::RuleMatched {
+                id: matched, proxy, ..
+            } => {
+                assert_eq!((matched, proxy.as_str()), (id, "PROXY"))
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        match next() {
+            Event::ConnectionClosed {
+                id: closed,
+                up,
+                down,
+                reason,
+                ..
+            } => assert_eq!(
+                (closed, up, down, reason),
+                (id, 11, 22, CloseReason::UpstreamEof)
+            ),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let recent = context.close_stats().recent();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].proxy.as_deref(), Some("PROXY"));
+    }
+}
@@ -0,0 +1,709 @@
+//! Fixed port forwards, bypassing rules
+//!
+//! Every connection to a tunnel goes to its target through its outbound.
+//! UDP is relayed directly, with one upstream socket per client that lives
+//! until the target stays quiet for `UDP_IDLE_TIMEOUT`. Outbounds with
+//! `udp-over-tcp` carry it instead, one stream per client, and outbounds
+//! with a UDP relay of their own one relay per client. Sessions outlive
+//! reloads, the new listener of a tunnel with the same target and outbound
+//! takes them over so clients keep their upstream port.
+//!
+//! Rules apply to one thing only, QUIC: when they reject `PROTOCOL,quic`
+//! for a UDP tunnel its QUIC handshakes are dropped, so browsers fall back
+//! to TCP where rewrites and interception work.
+
+use std::{
+    collections::HashMap,
+    error::Error as StdError,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{
+    channel::mpsc,
+    future::{pending, select, Either},
+    StreamExt,
+};
+use log::{debug, error, info, warn};
+use tokio::io::AsyncWriteExt;
+
+use super::{
+    close_reason, dial,
+    qos::Flow,
+    relay::{self, relay},
+    tracker::ConnectionTracker,
+    ConnectionMeta,
+};
+use crate::{
+    config::TunnelConfig,
+    context::SharedContext,
+    dns_resolver,
+    engine::{rules::Metadata, sniff},
+    event::CloseReason,
+    listener::{self, InboundStream},
+    outbound::{uot, Datagrams, Outbound, TcpDialer, DIRECT},
+    protocol::proxy_protocol,
+    rt::{self, UdpSocket},
+    utils::{Address, ListenAddress},
+};
+
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn meta(tunnel: &TunnelConfig, src_addr: Option<SocketAddr>, udp: bool) -> ConnectionMeta {
+    let (host, dst_addr) = match tunnel.target {
+        Address::SocketAddr(addr) => (addr.ip().to_string(), Some(addr)),
+        Address::DomainName(ref dn) => (dn.0.clone(), None),
+    };
+    ConnectionMeta {
+        udp,
+        inbound: tunnel.name.clone(),
+        user: None,
+        uid: None,
+        host,
+        dst_port: tunnel.target.port(),
+        dst_addr,
+        src_addr,
+        protocol: None,
+        sni: None,
+        tls_version: None,
+        fingerprint: None,
+    }
+}
+
+/// Forward `tunnel` until its listener fails
+pub async fn run(context: SharedContext, tunnel: TunnelConfig) -> Result<(), Box<dyn StdError>> {
+    if !tunnel.udp.unwrap_or(false) {
+        return run_tcp(context, &tunnel).await;
+    }
+    // Polled together so a reload stops both
+    let udp = async {
+        if let Err(e) = run_udp(context.clone(), &tunnel).await {
+            error!("UDP tunnel {} stopped, err: {}", tunnel.name, e);
+        }
+        pending::<()>().await
+    };
+    match select(Box::pin(run_tcp(context.clone(), &tunnel)), Box::pin(udp)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(..) => Ok(()),
+    }
+}
+
+async fn run_tcp(context: SharedContext, tunnel: &TunnelConfig) -> Result<(), Box<dyn StdError>> {
+    let mut incoming = listener::bind(&tunnel.listen).await?;
+    info!("Tunnel {} forwards to {}", tunnel.name, tunnel.target);
+    while let Some(Ok(inbound)) = incoming.next().await {
+        let admission = match context.load_shedder().admit() {
+            Ok(a) => a,
+            Err(e) => {
+                debug!("Tunnel {} refused connection, {:?}", tunnel.name, e);
+                continue;
+            }
+        };
+        let context = context.clone();
+        let tunnel = tunnel.clone();
+        rt::spawn(async move {
+            let _admission = admission;
+            forward(&context, &tunnel, inbound).await
+        });
+    }
+    Ok(())
+}
+
+async fn forward(context: &SharedContext, tunnel: &TunnelConfig, mut inbound: InboundStream) {
+    let proxy = tunnel.outbound.as_ref().map_or(DIRECT, String::as_str);
+    let mut tracker = ConnectionTracker::open(
+        context,
+        &tunnel.name,
+        &meta(tunnel, inbound.peer_addr(), false),
+    );
+    tracker.rule_matched("TUNNEL", proxy);
+    let flow = context
+        .qos()
+        .map(|qos| Flow::new(qos, tunnel.target.port(), tunnel.class));
+    tracker.classified(flow.as_ref().map(Flow::class));
+
+    let outbound = match context.outbound(proxy) {
+        Some(outbound) => outbound,
+        None => {
+            debug!("Tunnel {} has no outbound named {}", tunnel.name, proxy);
+            tracker.close(CloseReason::Error);
+            return;
+        }
+    };
+    let keepalive = tunnel.keepalive.unwrap_or(false);
+    let pool = context.outbound_pool();
+    let dialing = dial(
+        &mut inbound,
+        &*outbound,
+        &tunnel.target,
+        pool,
+        None,
+        None,
+        keepalive,
+    );
+    let mut remote = match dialing.await {
+        Ok(remote) => remote,
+        Err(e) => {
+            debug!("Tunnel {} failed to dial, err: {}", tunnel.name, e);
+            tracker.close(close_reason(&e, CloseReason::DialTimeout));
+            return;
+        }
+    };
+    if let Some(version) = tunnel.send_proxy_protocol {
+        let addresses = inbound
+            .peer_addr()
+            .and_then(|source| Some((source, inbound.local_addr()?)));
+        let header = proxy_protocol::encode(version, addresses);
+        if let Err(e) = remote.write_all(&header).await {
+            debug!(
+                "Tunnel {} failed to send PROXY header, err: {}",
+                tunnel.name, e
+            );
+            tracker.close(CloseReason::Error);
+            return;
+        }
+    }
+    let pool = context.buffer_pool();
+    match relay(&mut inbound, &mut remote, &pool, flow.as_ref()).await {
+        Ok((up, down)) => {
+            tracker.transferred(up, down);
+            tracker.close(CloseReason::ClientEof);
+        }
+        Err(ref e) if keepalive && relay::is_remote(e) => {
+            debug!("Tunnel {} lost its target, err: {}", tunnel.name, e);
+            if let Err(e) = inbound.reset_on_close() {
+                debug!(
+                    "Tunnel {} failed to reset its client, err: {}",
+                    tunnel.name, e
+                );
+            }
+            tracker.close(CloseReason::UpstreamLost);
+        }
+        Err(e) => tracker.close(close_reason(&e, CloseReason::IdleTimeout)),
+    }
+}
+
+/// Way UDP of a tunnel leaves
+enum UdpRoute {
+    Direct,
+    /// Framed on a stream of the outbound
+    Stream(Arc<dyn Outbound>),
+    /// Through the UDP relay of the outbound
+    Relay(Arc<dyn Outbound>),
+}
+
+fn udp_route(context: &SharedContext, tunnel: &TunnelConfig) -> UdpRoute {
+    let name = match tunnel.outbound.as_ref().filter(|name| *name != DIRECT) {
+        Some(name) => name,
+        None => return UdpRoute::Direct,
+    };
+    let over_tcp = context
+        .config()
+        .proxies
+        .iter()
+        .find(|p| p.name() == name || p.options().alias.contains(name))
+        .is_some_and(|p| p.options().udp_over_tcp);
+    match context.outbound(name) {
+        Some(outbound) if over_tcp => UdpRoute::Stream(outbound),
+        Some(ref outbound) if outbound.udp() => UdpRoute::Relay(outbound.clone()),
+        _ => {
+            warn!(
+                "UDP of tunnel {} goes direct, outbound {} has no UDP relay or udp-over-tcp",
+                tunnel.name, name
+            );
+            UdpRoute::Direct
+        }
+    }
+}
+
+/// Whether rules reject `datagram` of `client`, checked only when it opens
+/// a QUIC connection
+fn rejects_quic(
+    context: &SharedContext,
+    tunnel: &TunnelConfig,
+    client: SocketAddr,
+    datagram: &[u8],
+) -> bool {
+    let rules = context.rules();
+    if !rules.sniffs() || !sniff::quic_initial(datagram) {
+        return false;
+    }
+    let (host, dst_ip) = match tunnel.target {
+        Address::SocketAddr(addr) => ("", Some(addr.ip())),
+        Address::DomainName(ref dn) => (dn.0.as_str(), None),
+    };
+    let meta = Metadata {
+        inbound: &tunnel.name,
+        user: None,
+        uid: None,
+        host,
+        dst_ip,
+        resolved_ip: None,
+        dst_port: tunnel.target.port(),
+        src_ip: Some(client.ip()),
+        src_port: Some(client.port()),
+        udp: true,
+        protocol: Some(sniff::QUIC),
+        sni: None,
+        tls_version: None,
+    };
+    rules
+        .matched(&meta)
+        .is_some_and(|m| m.target.eq_ignore_ascii_case("REJECT"))
+}
+
+type Reply = mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>;
+
+/// Listener a session hands replies to, replaced when a reload's listener
+/// takes the session over and cleared when none does
+type ReplyTo = Arc<Mutex<Option<Reply>>>;
+
+struct Session {
+    datagrams: mpsc::UnboundedSender<Vec<u8>>,
+    alive: Arc<AtomicBool>,
+    reply: ReplyTo,
+}
+
+/// Hand a reply to the listener, false once no listener takes the session
+fn send_reply(reply: &ReplyTo, datagram: Vec<u8>, client: SocketAddr) -> bool {
+    match *reply.lock().unwrap() {
+        // Replies while a reload swaps listeners are lost
+        Some(ref reply) => {
+            let _ = reply.unbounded_send((datagram, client));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Target and outbound of a tunnel, sessions are taken over by tunnels
+/// of the same route only
+fn route_key(tunnel: &TunnelConfig) -> String {
+    format!(
+        "{} via {}",
+        tunnel.target,
+        tunnel.outbound.as_ref().map_or(DIRECT, String::as_str)
+    )
+}
+
+type Sessions = HashMap<SocketAddr, Session>;
+
+/// UDP sessions of every tunnel by tunnel name and client, kept across
+/// reloads
+#[derive(Default)]
+pub struct UdpSessions {
+    tunnels: Mutex<HashMap<String, (String, Sessions)>>,
+}
+
+impl UdpSessions {
+    /// Close the sessions of tunnels `tunnels` has no UDP tunnel of the same
+    /// route for
+    pub fn keep(&self, tunnels: &[TunnelConfig]) {
+        let mut sessions = self.tunnels.lock().unwrap();
+        sessions.retain(|name, (route, sessions)| {
+            let kept = tunnels
+                .iter()
+                .any(|t| t.name == *name && t.udp.unwrap_or(false) && route_key(t) == *route);
+            if !kept {
+                for session in sessions.values() {
+                    *session.reply.lock().unwrap() = None;
+                }
+            }
+            kept
+        });
+    }
+
+    /// Send the replies of the sessions of `tunnel` to `reply`, returns how
+    /// many there are
+    fn adopt(&self, tunnel: &TunnelConfig, reply: &Reply) -> usize {
+        let mut sessions = self.tunnels.lock().unwrap();
+        let (_, sessions) = sessions
+            .entry(tunnel.name.clone())
+            .or_insert_with(|| (route_key(tunnel), HashMap::new()));
+        sessions.retain(|_, s| s.alive.load(Ordering::Relaxed));
+        for session in sessions.values() {
+            *session.reply.lock().unwrap() = Some(reply.clone());
+        }
+        sessions.len()
+    }
+
+    /// Pass `datagram` to the session of `client`, false without one
+    fn send(&self, tunnel: &str, client: SocketAddr, datagram: &[u8]) -> bool {
+        let mut sessions = self.tunnels.lock().unwrap();
+        let sessions = match sessions.get_mut(tunnel) {
+            Some((_, sessions)) => sessions,
+            None => return false,
+        };
+        let alive = match sessions.get(&client) {
+            Some(session) => session.alive.load(Ordering::Relaxed),
+            None => return false,
+        };
+        if !alive {
+            sessions.remove(&client);
+            return false;
+        }
+        let _ = sessions[&client]
+            .datagrams
+            .unbounded_send(datagram.to_vec());
+        true
+    }
+
+    fn insert(&self, tunnel: &TunnelConfig, client: SocketAddr, session: Session) {
+        let mut sessions = self.tunnels.lock().unwrap();
+        let (_, sessions) = sessions
+            .entry(tunnel.name.clone())
+            .or_insert_with(|| (route_key(tunnel), HashMap::new()));
+        sessions.insert(client, session);
+    }
+}
+
+async fn run_udp(context: SharedContext, tunnel: &TunnelConfig) -> io::Result<()> {
+    let addr = match tunnel.listen {
+        ListenAddress::Tcp(ref addr) => addr.to_socket_addrs()?.next(),
+        ListenAddress::Unix(..) => None,
+    };
+    let addr = addr
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid UDP listen address"))?;
+    let (mut recv, mut send) = UdpSocket::bind(&addr).await?.split();
+
+    // Replies of every session leave through the tunnel port
+    let (reply, mut replies) = mpsc::unbounded::<(Vec<u8>, SocketAddr)>();
+    let sending = async move {
+        while let Some((datagram, client)) = replies.next().await {
+            if let Err(e) = send.send_to(&datagram, &client).await {
+                debug!("Failed to send UDP reply to {}, err: {}", client, e);
+            }
+        }
+    };
+
+    let sessions = context.carryover().udp_sessions();
+    let adopted = sessions.adopt(tunnel, &reply);
+    if adopted > 0 {
+        info!("Tunnel {} took over {} UDP sessions", tunnel.name, adopted);
+    }
+    let route = udp_route(&context, tunnel);
+    let receiving = async move {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (n, client) = recv.recv_from(&mut buf).await?;
+            if sessions.send(&tunnel.name, client, &buf[..n]) {
+                continue;
+            }
+            if rejects_quic(&context, tunnel, client, &buf[..n]) {
+                debug!("Tunnel {} dropped QUIC from {}", tunnel.name, client);
+                continue;
+            }
+            let reply = Arc::new(Mutex::new(Some(reply.clone())));
+            let session = match route {
+                UdpRoute::Direct => open_session(&context, tunnel, client, reply).await,
+                UdpRoute::Stream(ref outbound) => {
+                    open_stream_session(&context, tunnel, &**outbound, client, reply).await
+                }
+                UdpRoute::Relay(ref outbound) => {
+                    open_relay_session(&context, tunnel, &**outbound, client, reply).await
+                }
+            };
+            match session {
+                Ok(session) => {
+                    let _ = session.datagrams.unbounded_send(buf[..n].to_vec());
+                    sessions.insert(tunnel, client, session);
+                }
+                Err(e) => debug!(
+                    "Tunnel {} failed to open UDP session, err: {}",
+                    tunnel.name, e
+                ),
+            }
+        }
+    };
+    // Dropping both on reloads closes the socket for the next listener
+    match select(Box::pin(receiving), Box::pin(sending)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(..) => Ok(()),
+    }
+}
+
+/// Socket of `client` towards the target, alive until the target goes quiet
+async fn open_session(
+    context: &SharedContext,
+    tunnel: &TunnelConfig,
+    client: SocketAddr,
+    reply: ReplyTo,
+) -> io::Result<Session> {
+    let target =
+        dns_resolver::resolve(context.clone(), &tunnel.target.host(), tunnel.target.port())
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "target has no address"))?;
+    let local: SocketAddr = if target.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let (mut recv, mut send) = UdpSocket::bind(&local).await?.split();
+
+    let (datagrams, mut rx) = mpsc::unbounded::<Vec<u8>>();
+    let sent = Arc::new(AtomicU64::new(0));
+    let counter = sent.clone();
+    rt::spawn(async move {
+        while let Some(datagram) = rx.next().await {
+            match send.send_to(&datagram, &target).await {
+                Ok(n) => counter.fetch_add(n as u64, Ordering::Relaxed),
+                Err(..) => break,
+            };
+        }
+    });
+
+    let alive = Arc::new(AtomicBool::new(true));
+    let mut tracker =
+        ConnectionTracker::open(context, &tunnel.name, &meta(tunnel, Some(client), true));
+    tracker.rule_matched("TUNNEL", DIRECT);
+    let flag = alive.clone();
+    let replies = reply.clone();
+    rt::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        let reason = loop {
+            let (n, from) = match rt::timeout(UDP_IDLE_TIMEOUT, recv.recv_from(&mut buf)).await {
+                Ok(Ok(received)) => received,
+                Ok(Err(..)) => break CloseReason::Error,
+                Err(..) => break CloseReason::IdleTimeout,
+            };
+            // Drop stray packets from anyone but the target
+            if from != target {
+                continue;
+            }
+            tracker.transferred(0, n as u64);
+            if !send_reply(&replies, buf[..n].to_vec(), client) {
+                break CloseReason::Reload;
+            }
+        };
+        flag.store(false, Ordering::Relaxed);
+        tracker.transferred(sent.load(Ordering::Relaxed), 0);
+        tracker.close(reason);
+    });
+    Ok(Session {
+        datagrams,
+        alive,
+        reply,
+    })
+}
+
+/// Datagrams of `client` framed on a stream through `outbound`, alive until
+/// the target goes quiet
+async fn open_stream_session(
+    context: &SharedContext,
+    tunnel: &TunnelConfig,
+    outbound: &dyn Outbound,
+    client: SocketAddr,
+    reply: ReplyTo,
+) -> io::Result<Session> {
+    let stream = outbound.dial(&uot::target(), &TcpDialer).await?;
+    let (mut read, mut write) = tokio::io::split(stream);
+
+    let (datagrams, mut rx) = mpsc::unbounded::<Vec<u8>>();
+    let sent = Arc::new(AtomicU64::new(0));
+    let counter = sent.clone();
+    let target = tunnel.target.clone();
+    rt::spawn(async move {
+        while let Some(datagram) = rx.next().await {
+            let frame = match uot::encode(&target, &datagram) {
+                Ok(frame) => frame,
+                Err(..) => continue,
+            };
+            if write.write_all(&frame).await.is_err() {
+                break;
+            }
+            counter.fetch_add(datagram.len() as u64, Ordering::Relaxed);
+        }
+    });
+
+    let alive = Arc::new(AtomicBool::new(true));
+    let proxy = outbound.name();
+    let mut tracker =
+        ConnectionTracker::open(context, &tunnel.name, &meta(tunnel, Some(client), true));
+    tracker.rule_matched("TUNNEL", &proxy);
+    let flag = alive.clone();
+    let replies = reply.clone();
+    rt::spawn(async move {
+        let reason = loop {
+            let payload = match rt::timeout(UDP_IDLE_TIMEOUT, uot::read_datagram(&mut read)).await {
+                Ok(Ok((_, payload))) => payload,
+                Ok(Err(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    break CloseReason::UpstreamEof
+                }
+                Ok(Err(..)) => break CloseReason::Error,
+                Err(..) => break CloseReason::IdleTimeout,
+            };
+            tracker.transferred(0, payload.len() as u64);
+            if !send_reply(&replies, payload, client) {
+                break CloseReason::Reload;
+            }
+        };
+        flag.store(false, Ordering::Relaxed);
+        tracker.transferred(sent.load(Ordering::Relaxed), 0);
+        tracker.close(reason);
+    });
+    Ok(Session {
+        datagrams,
+        alive,
+        reply,
+    })
+}
+
+/// Datagrams of `client` through the UDP relay of `outbound`, alive until
+/// the target goes quiet
+async fn open_relay_session(
+    context: &SharedContext,
+    tunnel: &TunnelConfig,
+    outbound: &dyn Outbound,
+    client: SocketAddr,
+    reply: ReplyTo,
+) -> io::Result<Session> {
+    let Datagrams { send, mut recv } = outbound.bind().await?;
+
+    let (datagrams, mut rx) = mpsc::unbounded::<Vec<u8>>();
+    let sent = Arc::new(AtomicU64::new(0));
+    let counter = sent.clone();
+    let target = tunnel.target.clone();
+    rt::spawn(async move {
+        while let Some(datagram) = rx.next().await {
+            let len = datagram.len() as u64;
+            if send.unbounded_send((target.clone(), datagram)).is_err() {
+                break;
+            }
+            counter.fetch_add(len, Ordering::Relaxed);
+        }
+    });
+
+    let alive = Arc::new(AtomicBool::new(true));
+    let proxy = outbound.name();
+    let mut tracker =
+        ConnectionTracker::open(context, &tunnel.name, &meta(tunnel, Some(client), true));
+    tracker.rule_matched("TUNNEL", &proxy);
+    let flag = alive.clone();
+    let replies = reply.clone();
+    rt::spawn(async move {
+        let reason = loop {
+            let payload = match rt::timeout(UDP_IDLE_TIMEOUT, recv.next()).await {
+                Ok(Some((_, payload))) => payload,
+                // Relays close on their own once quiet
+                Ok(None) | Err(..) => break CloseReason::IdleTimeout,
+            };
+            tracker.transferred(0, payload.len() as u64);
+            if !send_reply(&replies, payload, client) {
+                break CloseReason::Reload;
+            }
+        };
+        flag.store(false, Ordering::Relaxed);
+        tracker.transferred(sent.load(Ordering::Relaxed), 0);
+        tracker.close(reason);
+    });
+    Ok(Session {
+        datagrams,
+        alive,
+        reply,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        context::Context,
+        rt::{Runtime, TcpListener, TcpStream},
+    };
+
+    #[test]
+    fn forwards_to_the_target() {
+        let context: SharedContext = Arc::new(Context::new(Config::new()).unwrap());
+        Runtime::new().unwrap().block_on(async {
+            let mut target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let tunnel: TunnelConfig = serde_yaml::from_str(&format!(
+                "{{ name: db, listen: 127.0.0.1:0, target: \"{}\" }}",
+                target.local_addr().unwrap()
+            ))
+            .unwrap();
+            let addr = listener.local_addr().unwrap();
+            let serve = async {
+                let (mut stream, _) = target.accept().await.unwrap();
+                let mut ping = [0; 4];
+                stream.read_exact(&mut ping).await.unwrap();
+                assert_eq!(&ping, b"ping");
+                stream.write_all(b"pong").await.unwrap();
+                let mut rest = Vec::new();
+                stream.read_to_end(&mut rest).await.unwrap();
+            };
+            let forwarding = async {
+                let (inbound, _) = listener.accept().await.unwrap();
+                forward(&context, &tunnel, InboundStream::Tcp(inbound)).await
+            };
+            let client = async {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream.write_all(b"ping").await.unwrap();
+                stream.shutdown(std::net::Shutdown::Write).unwrap();
+                let mut pong = Vec::new();
+                stream.read_to_end(&mut pong).await.unwrap();
+                assert_eq!(pong, b"pong");
+            };
+            futures::join!(serve, forwarding, client);
+        });
+
+        let recent = context.close_stats().recent();
+        assert_eq!(recent.len(), 1);
+        let closed = &recent[0];
+        assert_eq!(
+            (
+                closed.inbound.as_str(),
+                closed.rule.as_deref(),
+                closed.proxy.as_deref()
+            ),
+            ("db", Some("TUNNEL"), Some(DIRECT))
+        );
+        assert_eq!((closed.up, closed.down), (4, 4));
+    }
+
+    #[test]
+    fn drops_quic_when_rules_reject_it() {
+        let context = |rules: &str| -> SharedContext {
+            let config = format!(
+                "mode: rule\nlog-level: silent\ninbounds: []\nproxies: []\n\
+                 proxy-groups: []\nrules:\n{}",
+                rules
+            );
+            Arc::new(Context::new(Config::load_from_str(&config).unwrap()).unwrap())
+        };
+        let tunnel: TunnelConfig =
+            serde_yaml::from_str("{ name: h3, listen: 127.0.0.1:0, target: 192.0.2.1:443 }")
+                .unwrap();
+        let client = "127.0.0.1:50000".parse().unwrap();
+        let v1 = [0xc3, 0x00, 0x00, 0x00, 0x01, 0x08];
+        let v2 = [0xd3, 0x6b, 0x33, 0x43, 0xcf, 0x08];
+
+        let rejecting = context(
+            "  - { kind: PROTOCOL, params: [quic], target: REJECT }\n\
+             \x20 - { kind: MATCH, target: DIRECT }\n",
+        );
+        assert!(rejects_quic(&rejecting, &tunnel, client, &v1));
+        assert!(rejects_quic(&rejecting, &tunnel, client, &v2));
+        // Other datagrams, and a QUIC handshake packet past the initial
+        assert!(!rejects_quic(&rejecting, &tunnel, client, b"\x12\x34 dns"));
+        assert!(!rejects_quic(
+            &rejecting,
+            &tunnel,
+            client,
+            &[0xe3, 0, 0, 0, 1, 8]
+        ));
+
+        let allowing = context("  - { kind: MATCH, target: DIRECT }\n");
+        assert!(!rejects_quic(&allowing, &tunnel, client, &v1));
+    }
+}
@@ -0,0 +1,308 @@
+//! Bytes relayed by day, outbound and client, kept across restarts
+//!
+//! Counters are appended to the `stats` file as JSON lines of deltas once a
+//! minute, so contexts replaced by a reload never overwrite each other. The
+//! file is compacted to one line per day, outbound and client when loaded,
+//! days past `retain-days` are dropped then.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::StatsConfig,
+    rt::interval,
+    state::{self, Dirs},
+};
+
+/// A year of months to compare unless configured
+const DEFAULT_RETAIN_DAYS: u32 = 400;
+/// In the state directory unless configured
+const DEFAULT_FILE: &str = "stats.jsonl";
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Bytes {
+    pub up: u64,
+    pub down: u64,
+}
+
+impl Bytes {
+    fn add(&mut self, other: Bytes) {
+        self.up += other.up;
+        self.down += other.down;
+    }
+}
+
+/// Counter of one outbound and client on one day
+type Key = (String, String, String);
+
+/// One line of the stats file
+#[derive(Serialize, Deserialize)]
+struct Record {
+    day: String,
+    outbound: String,
+    source: String,
+    #[serde(flatten)]
+    bytes: Bytes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    /// Key of the period `day` (`YYYY-MM-DD`) falls in
+    fn of(self, day: &str) -> &str {
+        match self {
+            Period::Day => day,
+            Period::Month => &day[..7.min(day.len())],
+        }
+    }
+}
+
+/// Usage of one day or month
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub period: String,
+    #[serde(flatten)]
+    pub total: Bytes,
+    pub outbounds: BTreeMap<String, Bytes>,
+    pub sources: BTreeMap<String, Bytes>,
+}
+
+#[derive(Default)]
+struct State {
+    totals: HashMap<Key, Bytes>,
+    /// Counted since the last flush
+    pending: HashMap<Key, Bytes>,
+}
+
+pub struct Usage {
+    path: Option<PathBuf>,
+    retain_days: u32,
+    state: Mutex<State>,
+}
+
+impl Usage {
+    /// Counters in memory only
+    fn new() -> Usage {
+        Usage {
+            path: None,
+            retain_days: DEFAULT_RETAIN_DAYS,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Counters persisted as configured, loading what the file holds
+    pub fn load(config: Option<&StatsConfig>, dirs: &Dirs) -> io::Result<Usage> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(Usage::new()),
+        };
+        let path = config
+            .path
+            .as_ref()
+            .map_or_else(|| dirs.state().join(DEFAULT_FILE), PathBuf::from);
+        let usage = Usage {
+            path: Some(path.clone()),
+            retain_days: config.retain_days.unwrap_or(DEFAULT_RETAIN_DAYS),
+            state: Mutex::new(State::default()),
+        };
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                return Ok(usage);
+            }
+            Err(e) => return Err(e),
+        };
+        let cutoff = usage.cutoff();
+        let mut state = usage.state.lock().unwrap();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) if record.day >= cutoff => {
+                    let key = (record.day, record.outbound, record.source);
+                    state.totals.entry(key).or_default().add(record.bytes);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Skip stats line \"{}\", err: {}", line, e),
+            }
+        }
+        compact(&path, &state.totals)?;
+        drop(state);
+        Ok(usage)
+    }
+
+    /// Count bytes relayed for `source` through `outbound` today
+    pub fn add(&self, outbound: &str, source: &str, up: u64, down: u64) {
+        let key = (today(), outbound.to_owned(), source.to_owned());
+        let bytes = Bytes { up, down };
+        let mut state = self.state.lock().unwrap();
+        state.totals.entry(key.clone()).or_default().add(bytes);
+        if self.path.is_some() {
+            state.pending.entry(key).or_default().add(bytes);
+        }
+    }
+
+    /// Usage by `period`, newest first
+    pub fn report(&self, period: Period) -> Vec<Report> {
+        let state = self.state.lock().unwrap();
+        let mut reports = BTreeMap::new();
+        for ((day, outbound, source), bytes) in state.totals.iter() {
+            let report = reports
+                .entry(period.of(day).to_owned())
+                .or_insert_with(|| Report {
+                    period: period.of(day).to_owned(),
+                    total: Bytes::default(),
+                    outbounds: BTreeMap::new(),
+                    sources: BTreeMap::new(),
+                });
+            report.total.add(*bytes);
+            report
+                .outbounds
+                .entry(outbound.clone())
+                .or_default()
+                .add(*bytes);
+            report
+                .sources
+                .entry(source.clone())
+                .or_default()
+                .add(*bytes);
+        }
+        reports
+            .into_iter()
+            .rev()
+            .map(|(_, report)| report)
+            .collect()
+    }
+
+    /// Append the counts since the last flush and forget expired days
+    pub fn flush(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let cutoff = self.cutoff();
+        let mut state = self.state.lock().unwrap();
+        state.totals.retain(|(day, ..), _| *day >= cutoff);
+        if state.pending.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(&mut state.pending);
+        if let Err(e) = append(path, &pending) {
+            error!("Failed to save stats to {}, err: {}", path.display(), e);
+            // Try again with the next flush
+            state.pending = pending;
+        }
+    }
+
+    /// Flush every minute, never returns
+    pub async fn run_flusher(&self) {
+        let mut interval = interval(FLUSH_INTERVAL);
+        while interval.next().await.is_some() {
+            self.flush();
+        }
+    }
+
+    /// Oldest day kept
+    fn cutoff(&self) -> String {
+        let tm = time::now() - time::Duration::days(i64::from(self.retain_days));
+        day_of(&tm)
+    }
+}
+
+impl Drop for Usage {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+fn day_of(tm: &time::Tm) -> String {
+    format!(
+        "{:04}-{:02}-{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday
+    )
+}
+
+/// Local date, periods follow the user's calendar
+fn today() -> String {
+    day_of(&time::now())
+}
+
+fn write_records<W: Write>(out: &mut W, counts: &HashMap<Key, Bytes>) -> io::Result<()> {
+    for ((day, outbound, source), bytes) in counts.iter() {
+        let record = Record {
+            day: day.clone(),
+            outbound: outbound.clone(),
+            source: source.clone(),
+            bytes: *bytes,
+        };
+        serde_json::to_writer(&mut *out, &record)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+fn append(path: &Path, counts: &HashMap<Key, Bytes>) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    write_records(&mut io::BufWriter::new(file), counts)
+}
+
+/// Replace the file with `totals`, renamed into place so a crash keeps the
+/// old one
+fn compact(path: &Path, totals: &HashMap<Key, Bytes>) -> io::Result<()> {
+    let mut records = Vec::new();
+    write_records(&mut records, totals)?;
+    state::write_atomic(path, &records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reports_by_day_and_month() {
+        let usage = Usage::new();
+        {
+            let mut state = usage.state.lock().unwrap();
+            let mut put = |day: &str, outbound: &str, source: &str, up, down| {
+                let key = (day.to_owned(), outbound.to_owned(), source.to_owned());
+                state.totals.insert(key, Bytes { up, down });
+            };
+            put("2019-10-01", "auto", "10.0.0.2", 10, 100);
+            put("2019-10-01", "DIRECT", "10.0.0.3", 1, 2);
+            put("2019-10-02", "auto", "10.0.0.2", 5, 50);
+            put("2019-11-01", "auto", "10.0.0.3", 7, 70);
+        }
+
+        let days = usage.report(Period::Day);
+        assert_eq!(days.len(), 3);
+        assert_eq!(days[0].period, "2019-11-01");
+        assert_eq!(days[2].total, Bytes { up: 11, down: 102 });
+        assert_eq!(days[2].outbounds["DIRECT"], Bytes { up: 1, down: 2 });
+
+        let months = usage.report(Period::Month);
+        assert_eq!(months.len(), 2);
+        assert_eq!(months[1].period, "2019-10");
+        assert_eq!(months[1].total, Bytes { up: 16, down: 152 });
+        assert_eq!(months[1].sources["10.0.0.2"], Bytes { up: 15, down: 150 });
+    }
+}
@@ -0,0 +1,130 @@
+//! Notifications about the engine and its connections
+//!
+//! Publishers on the relay path never wait on subscribers, events are queued
+//! in unbounded channels and consumed at the subscriber's pace.
+
+use std::{fmt, sync::Mutex};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use serde::Serialize;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    /// Inbounds were set up from the current config
+    Started,
+    /// A new config replaced the running one
+    Reloaded,
+    /// The rule list was replaced through the API
+    RulesReplaced { count: usize },
+    /// The engine stopped serving, it will not start again
+    Stopped,
+    /// Serving ended with an error, e.g. a listen address already in use
+    Failed { error: String },
+    ConnectionOpened {
+        id: u64,
+        inbound: String,
+        source: Option<String>,
+        host: String,
+    },
+    RuleMatched {
+        id: u64,
+        rule: String,
+        proxy: String,
+    },
+    ConnectionClosed {
+        id: u64,
+        up: u64,
+        down: u64,
+        duration_ms: u64,
+        reason: CloseReason,
+    },
+    /// A health check could not reach the proxy
+    ProxyUnhealthy { provider: String, proxy: String },
+}
+
+/// Why a connection ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The client closed its side first
+    ClientEof,
+    /// The remote closed its side first
+    UpstreamEof,
+    /// The remote of a `keepalive` connection failed, e.g. stopped answering
+    /// keepalive probes, the client was reset to reconnect
+    UpstreamLost,
+    /// Connecting to the remote or the proxy server timed out
+    DialTimeout,
+    /// The inbound request or the outbound protocol handshake failed
+    HandshakeFailure,
+    /// Refused by a REJECT rule or a connection limit
+    Reject,
+    /// Nothing was relayed for too long
+    IdleTimeout,
+    /// Torn down with the serving task, on reload, stop or shutdown
+    Reload,
+    /// Any other error
+    Error,
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 9] = [
+        CloseReason::ClientEof,
+        CloseReason::UpstreamEof,
+        CloseReason::UpstreamLost,
+        CloseReason::DialTimeout,
+        CloseReason::HandshakeFailure,
+        CloseReason::Reject,
+        CloseReason::IdleTimeout,
+        CloseReason::Reload,
+        CloseReason::Error,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::UpstreamEof => "upstream_eof",
+            CloseReason::UpstreamLost => "upstream_lost",
+            CloseReason::DialTimeout => "dial_timeout",
+            CloseReason::HandshakeFailure => "handshake_failure",
+            CloseReason::Reject => "reject",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::Reload => "reload",
+            CloseReason::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Fan out events to every subscriber
+///
+/// Subscribers that dropped their receiver are forgotten on the next publish.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<UnboundedSender<Event>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        let (tx, rx) = unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,297 @@
+//! C API for driving the engine from mobile and desktop GUI clients
+//!
+//! Functions return `TACHE_OK` or one of the negative `TACHE_ERR_*` codes.
+//! Strings are NUL terminated UTF-8 and stay owned by the caller. Only one
+//! engine exists per process.
+
+use std::{
+    ffi::CStr,
+    os::raw::{c_char, c_int, c_void},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use log::error;
+
+use crate::{Config, Engine, EngineError, Mode};
+
+pub const TACHE_OK: c_int = 0;
+pub const TACHE_ERR_INVALID_ARGUMENT: c_int = -1;
+pub const TACHE_ERR_CONFIG: c_int = -2;
+pub const TACHE_ERR_STATE: c_int = -3;
+pub const TACHE_ERR_IO: c_int = -4;
+/// The call panicked, or an earlier one did while holding the engine
+pub const TACHE_ERR_PANIC: c_int = -5;
+
+/// Receives the bytes sent and received during the last second
+pub type TrafficCallback = extern "C" fn(up: u64, down: u64, user_data: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct Callback {
+    func: TrafficCallback,
+    // Raw pointers aren't Send, the caller guarantees it may cross threads
+    user_data: usize,
+}
+
+lazy_static! {
+    /// Shared out so slow calls like reloads don't hold the lock
+    static ref ENGINE: Mutex<Option<Arc<Engine>>> = Mutex::new(None);
+    static ref TRAFFIC_CALLBACK: Mutex<Option<Callback>> = Mutex::new(None);
+}
+
+static REPORTER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+const TRAFFIC_INTERVAL: Duration = Duration::from_secs(1);
+
+fn error_code(e: &EngineError) -> c_int {
+    match *e {
+        EngineError::Io(..) => TACHE_ERR_IO,
+        EngineError::Config(..) => TACHE_ERR_CONFIG,
+        _ => TACHE_ERR_STATE,
+    }
+}
+
+/// Run `f` without letting a panic unwind into the caller
+fn guarded<F>(f: F) -> c_int
+where
+    F: FnOnce() -> c_int,
+{
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        error!("C API call panicked");
+        TACHE_ERR_PANIC
+    })
+}
+
+/// The running engine, the lock is released again before it's used
+fn engine() -> Result<Arc<Engine>, c_int> {
+    match ENGINE.lock() {
+        Ok(engine) => engine.clone().ok_or(TACHE_ERR_STATE),
+        Err(_) => Err(TACHE_ERR_PANIC),
+    }
+}
+
+unsafe fn read_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// Accepts JSON, any YAML config works as well
+unsafe fn read_config(s: *const c_char) -> Result<Config, c_int> {
+    let s = read_str(s).ok_or(TACHE_ERR_INVALID_ARGUMENT)?;
+    Config::load_from_str(s).map_err(|e| {
+        error!("Invalid config, err: {:?}", e);
+        TACHE_ERR_CONFIG
+    })
+}
+
+/// Build an engine from `config` and start serving
+///
+/// # Safety
+///
+/// `config` must be NULL or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn tache_start(config: *const c_char) -> c_int {
+    guarded(|| {
+        let config = match read_config(config) {
+            Ok(config) => config,
+            Err(code) => return code,
+        };
+        // Held while starting, so a concurrent start can't build a second one
+        let mut engine = match ENGINE.lock() {
+            Ok(engine) => engine,
+            Err(_) => return TACHE_ERR_PANIC,
+        };
+        if engine.is_some() {
+            return TACHE_ERR_STATE;
+        }
+        let started = Engine::builder()
+            .config(config)
+            .build()
+            .and_then(|e| e.start().map(|_| e));
+        match started {
+            Ok(e) => {
+                *engine = Some(Arc::new(e));
+                TACHE_OK
+            }
+            Err(e) => {
+                error!("Failed to start engine, err: {}", e);
+                error_code(&e)
+            }
+        }
+    })
+}
+
+/// Shut the engine down, closing every connection
+#[no_mangle]
+pub extern "C" fn tache_stop() -> c_int {
+    guarded(|| {
+        let engine = match ENGINE.lock() {
+            Ok(mut engine) => engine.take(),
+            Err(_) => return TACHE_ERR_PANIC,
+        };
+        match engine {
+            Some(engine) => match engine.shutdown() {
+                Ok(()) => TACHE_OK,
+                Err(e) => error_code(&e),
+            },
+            None => TACHE_ERR_STATE,
+        }
+    })
+}
+
+/// Replace the running config, the running one is kept when it fails
+///
+/// # Safety
+///
+/// `config` must be NULL or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn tache_reload(config: *const c_char) -> c_int {
+    guarded(|| {
+        let config = match read_config(config) {
+            Ok(config) => config,
+            Err(code) => return code,
+        };
+        with_engine(|engine| engine.reload(config))
+    })
+}
+
+/// Switch between `rule`, `global` and `direct`, connections already
+/// established and the inbounds are kept
+///
+/// # Safety
+///
+/// `mode` must be NULL or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn tache_set_mode(mode: *const c_char) -> c_int {
+    guarded(|| {
+        let mode = match read_str(mode).and_then(|s| s.parse::<Mode>().ok()) {
+            Some(mode) => mode,
+            None => return TACHE_ERR_INVALID_ARGUMENT,
+        };
+        with_engine(|engine| {
+            engine.set_mode(mode);
+            Ok(())
+        })
+    })
+}
+
+/// Register `callback` to be called every second, NULL unregisters it
+///
+/// The callback runs on a background thread with `user_data` passed back.
+#[no_mangle]
+pub extern "C" fn tache_set_traffic_callback(
+    callback: Option<TrafficCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    guarded(|| {
+        let mut registered = match TRAFFIC_CALLBACK.lock() {
+            Ok(registered) => registered,
+            Err(_) => return TACHE_ERR_PANIC,
+        };
+        *registered = callback.map(|func| Callback {
+            func,
+            user_data: user_data as usize,
+        });
+        // Under the lock the reporter decides to exit under, so a reporter
+        // seeing no callback has stopped running before this checks
+        if callback.is_some() && !REPORTER_RUNNING.swap(true, Ordering::SeqCst) {
+            thread::spawn(report_traffic);
+        }
+        TACHE_OK
+    })
+}
+
+fn with_engine<F>(f: F) -> c_int
+where
+    F: FnOnce(&Engine) -> Result<(), EngineError>,
+{
+    let engine = match engine() {
+        Ok(engine) => engine,
+        Err(code) => return code,
+    };
+    match f(&engine) {
+        Ok(()) => TACHE_OK,
+        Err(e) => {
+            error!("Engine call failed, err: {}", e);
+            error_code(&e)
+        }
+    }
+}
+
+/// Report per second deltas until the callback is unregistered
+fn report_traffic() {
+    let mut last = (0, 0);
+    loop {
+        thread::sleep(TRAFFIC_INTERVAL);
+        let total = engine().map_or((0, 0), |engine| engine.traffic());
+        // A new engine starts counting from zero again
+        let up = total.0.saturating_sub(last.0);
+        let down = total.1.saturating_sub(last.1);
+        last = total;
+
+        // Called unlocked, the callback may register another one
+        let callback = {
+            // Nothing can be registered any more once poisoned
+            let registered = TRAFFIC_CALLBACK.lock();
+            match registered.as_deref() {
+                Ok(Some(cb)) => *cb,
+                _ => {
+                    REPORTER_RUNNING.store(false, Ordering::SeqCst);
+                    return;
+                }
+            }
+        };
+        (callback.func)(up, down, callback.user_data as *mut c_void);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CString;
+
+    use super::*;
+
+    // One test, the engine is global to the process
+    #[test]
+    fn drives_one_engine() {
+        let config = CString::new(
+            "mode: rule\nlog-level: silent\ninbounds: []\nproxies: []\nproxy-groups: []\nrules: []\n",
+        )
+        .unwrap();
+        let global = CString::new("global").unwrap();
+        unsafe {
+            assert_eq!(tache_set_mode(global.as_ptr()), TACHE_ERR_STATE);
+            assert_eq!(tache_start(config.as_ptr()), TACHE_OK);
+            assert_eq!(tache_start(config.as_ptr()), TACHE_ERR_STATE);
+
+            let mut events = engine().unwrap().subscribe();
+            assert_eq!(tache_set_mode(global.as_ptr()), TACHE_OK);
+            assert!(matches!(engine().unwrap().config().mode, Mode::Global));
+            // Switched in place, nothing was restarted
+            assert!(events.try_recv().is_err());
+            assert!(engine().unwrap().is_running());
+
+            let bogus = CString::new("bogus").unwrap();
+            assert_eq!(tache_set_mode(bogus.as_ptr()), TACHE_ERR_INVALID_ARGUMENT);
+            assert_eq!(tache_reload(bogus.as_ptr()), TACHE_ERR_CONFIG);
+            assert_eq!(tache_stop(), TACHE_OK);
+            assert_eq!(tache_stop(), TACHE_ERR_STATE);
+        }
+
+        assert_eq!(guarded(|| panic!("in a call")), TACHE_ERR_PANIC);
+        let _ = thread::spawn(|| {
+            let _engine = ENGINE.lock().unwrap();
+            panic!("while holding the engine");
+        })
+        .join();
+        assert_eq!(tache_stop(), TACHE_ERR_PANIC);
+    }
+}
@@ -0,0 +1,265 @@
+//! Databases downloaded and kept up to date, `geo-db` of the config
+//!
+//! A database missing on start is downloaded right away, then again once its
+//! file is older than `interval` or when an update is forced through the
+//! API. A download has to match its checksum when one is configured and
+//! parse before it's saved, so a broken mirror never replaces a working
+//! database. Rules and resolvers holding the database see the new one.
+
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use futures::StreamExt;
+use log::{error, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{Config, GeoDbSource},
+    geoip::{self, GeoIP},
+    http_client,
+    provider::{format_time, unix_now},
+    rt::interval,
+    state::{self, Dirs},
+};
+
+/// A week, the usual release cycle of free databases
+pub const DEFAULT_INTERVAL: u64 = 7 * 24 * 3600;
+
+/// Databases are tens of megabytes
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+struct Database {
+    name: &'static str,
+    source: GeoDbSource,
+    path: PathBuf,
+    geoip: Arc<GeoIP>,
+}
+
+/// A database as shown by the API
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub name: &'static str,
+    pub url: String,
+    pub path: String,
+    pub loaded: bool,
+    /// Time the file was saved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Database {
+    /// Load the copy on disk, a missing or broken one is downloaded later
+    fn open(name: &'static str, source: &GeoDbSource, path: PathBuf) -> Database {
+        let geoip = match GeoIP::open(&path.to_string_lossy()) {
+            Ok(geoip) => geoip,
+            Err(e) => {
+                if path.exists() {
+                    warn!("Downloading {} again, err: {}", name, e);
+                }
+                GeoIP::empty()
+            }
+        };
+        Database {
+            name,
+            source: source.clone(),
+            path,
+            geoip: Arc::new(geoip),
+        }
+    }
+
+    /// Unix seconds the file was saved
+    fn saved_at(&self) -> Option<u64> {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok()?;
+        modified
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs())
+    }
+
+    fn due(&self, interval: u64, now: u64) -> bool {
+        if !self.geoip.is_loaded() {
+            return true;
+        }
+        match self.saved_at() {
+            Some(saved) => interval > 0 && now >= saved + interval,
+            None => true,
+        }
+    }
+
+    async fn download(&self) -> io::Result<()> {
+        let data = fetch(&self.source.url).await?;
+        if let Some(expected) = self.expected_sha256().await? {
+            let actual = hex(&Sha256::digest(&data));
+            if !actual.eq_ignore_ascii_case(&expected) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("SHA-256 is {}, expected {}", actual, expected),
+                ));
+            }
+        }
+        self.geoip.replace(data.clone())?;
+        state::write_atomic(&self.path, &data)
+    }
+
+    async fn expected_sha256(&self) -> io::Result<Option<String>> {
+        if let Some(ref sha256) = self.source.sha256 {
+            return Ok(Some(sha256.clone()));
+        }
+        let url = match self.source.sha256_url {
+            Some(ref url) => url,
+            None => return Ok(None),
+        };
+        let data = fetch(url).await?;
+        let text = String::from_utf8_lossy(&data);
+        match text.split_whitespace().next() {
+            Some(sha256) => Ok(Some(sha256.to_owned())),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is empty", url),
+            )),
+        }
+    }
+
+    fn status(&self, error: Option<String>) -> Status {
+        Status {
+            name: self.name,
+            url: self.source.url.clone(),
+            path: self.path.to_string_lossy().into_owned(),
+            loaded: self.geoip.is_loaded(),
+            updated_at: self.saved_at().map(format_time),
+            error,
+        }
+    }
+}
+
+async fn fetch(url: &str) -> io::Result<Vec<u8>> {
+    let resp = http_client::get(url, DOWNLOAD_TIMEOUT).await?;
+    if resp.status != 200 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("fetching {} returned status {}", url, resp.status),
+        ));
+    }
+    Ok(resp.body)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub struct GeoDb {
+    interval: u64,
+    databases: Vec<Database>,
+}
+
+impl GeoDb {
+    /// Databases of `geo-db`, saved to the paths of `geoip-database` and
+    /// `asn-database` when set. `None` without `geo-db`.
+    pub fn new(config: &Config, dirs: &Dirs) -> Option<GeoDb> {
+        let geo_db = config.geo_db.as_ref()?;
+        let path = |configured: &Option<String>, default: &str| {
+            configured
+                .as_ref()
+                .map_or_else(|| dirs.state().join(default), PathBuf::from)
+        };
+        let mut databases = Vec::new();
+        if let Some(ref source) = geo_db.geoip {
+            let path = path(&config.geoip_database, geoip::DEFAULT_DATABASE);
+            databases.push(Database::open("geoip", source, path));
+        }
+        if let Some(ref source) = geo_db.asn {
+            let path = path(&config.asn_database, geoip::ASN_DATABASE);
+            databases.push(Database::open("asn", source, path));
+        }
+        Some(GeoDb {
+            interval: geo_db.interval.unwrap_or(DEFAULT_INTERVAL),
+            databases,
+        })
+    }
+
+    fn find(&self, name: &str) -> Option<Arc<GeoIP>> {
+        self.databases
+            .iter()
+            .find(|db| db.name == name)
+            .map(|db| db.geoip.clone())
+    }
+
+    /// Country database, empty until the first download
+    pub fn geoip(&self) -> Option<Arc<GeoIP>> {
+        self.find("geoip")
+    }
+
+    pub fn asn(&self) -> Option<Arc<GeoIP>> {
+        self.find("asn")
+    }
+
+    pub fn statuses(&self) -> Vec<Status> {
+        self.databases.iter().map(|db| db.status(None)).collect()
+    }
+
+    /// Download every database now, with the error of each that failed
+    pub async fn update(&self) -> Vec<Status> {
+        let mut statuses = Vec::new();
+        for db in &self.databases {
+            let error = self.update_one(db).await.err();
+            statuses.push(db.status(error.map(|e| e.to_string())));
+        }
+        statuses
+    }
+
+    async fn update_one(&self, db: &Database) -> io::Result<()> {
+        match db.download().await {
+            Ok(()) => {
+                info!("Database {} updated from {}", db.name, db.source.url);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to update database {}, err: {}", db.name, e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Download missing databases, then check every minute for outdated
+    /// ones, never returns
+    pub async fn run(&self) {
+        let mut interval = interval(Duration::from_secs(60));
+        loop {
+            let now = unix_now();
+            for db in &self.databases {
+                if db.due(self.interval, now) {
+                    let _ = self.update_one(db).await;
+                }
+            }
+            if interval.next().await.is_none() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_database_is_due() {
+        let source = GeoDbSource {
+            url: "https://example.com/Country.mmdb".to_owned(),
+            sha256: None,
+            sha256_url: None,
+        };
+        let path = std::env::temp_dir().join("tache-geodb-missing.mmdb");
+        let db = Database::open("geoip", &source, path);
+        assert!(!db.geoip.is_loaded());
+        assert!(db.due(0, unix_now()));
+        assert!(db.status(None).updated_at.is_none());
+    }
+}
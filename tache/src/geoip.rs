@@ -0,0 +1,98 @@
+//! GeoIP country and ASN lookups backed by MaxMind databases
+
+use std::{
+    io,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+
+use maxminddb::{geoip2, Reader};
+use serde::Serialize;
+
+/// Default database path, relative to the working directory
+pub const DEFAULT_DATABASE: &str = "Country.mmdb";
+/// File name of a downloaded ASN database
+pub const ASN_DATABASE: &str = "GeoLite2-ASN.mmdb";
+
+/// Databases rules look addresses up in
+#[derive(Clone, Default)]
+pub struct Databases {
+    pub country: Option<Arc<GeoIP>>,
+    pub asn: Option<Arc<GeoIP>>,
+}
+
+/// Network operator an address belongs to
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Asn {
+    pub number: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+}
+
+/// A configured database must load, the default one is optional
+pub fn load(path: Option<&str>) -> io::Result<Option<Arc<GeoIP>>> {
+    match path {
+        Some(path) => GeoIP::open(path).map(|g| Some(Arc::new(g))),
+        None => Ok(GeoIP::open(DEFAULT_DATABASE).ok().map(Arc::new)),
+    }
+}
+
+/// A database that can be replaced in place, rules and resolvers holding it
+/// see the new one
+pub struct GeoIP {
+    reader: RwLock<Option<Reader<Vec<u8>>>>,
+}
+
+impl GeoIP {
+    pub fn open(path: &str) -> io::Result<GeoIP> {
+        let reader = Reader::open_readfile(path).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed to open GeoIP database {}: {}", path, e),
+            )
+        })?;
+        Ok(GeoIP {
+            reader: RwLock::new(Some(reader)),
+        })
+    }
+
+    /// No database yet, every lookup misses until one is set
+    pub fn empty() -> GeoIP {
+        GeoIP {
+            reader: RwLock::new(None),
+        }
+    }
+
+    /// Check `data` is a database and use it from now on
+    pub fn replace(&self, data: Vec<u8>) -> io::Result<()> {
+        let reader = Reader::from_source(data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("not a MaxMind database: {}", e),
+            )
+        })?;
+        *self.reader.write().unwrap() = Some(reader);
+        Ok(())
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.reader.read().unwrap().is_some()
+    }
+
+    /// ISO 3166 country code of `ip`, `None` for private or unknown addresses
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.read().unwrap();
+        let country: geoip2::Country = reader.as_ref()?.lookup(ip).ok()?;
+        country.country?.iso_code
+    }
+
+    /// Autonomous system of `ip`, for ASN databases
+    pub fn asn(&self, ip: IpAddr) -> Option<Asn> {
+        let reader = self.reader.read().unwrap();
+        let asn: geoip2::Asn = reader.as_ref()?.lookup(ip).ok()?;
+        Some(Asn {
+            number: asn.autonomous_system_number?,
+            organization: asn.autonomous_system_organization,
+        })
+    }
+}
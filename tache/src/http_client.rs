@@ -0,0 +1,215 @@
+//! Minimal HTTP/1.1 client for fetching providers and probing endpoints
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::lookup_host,
+};
+use url::Url;
+
+use crate::{
+    outbound::{BoxStream, Outbound, TcpDialer},
+    rt::{self, TcpStream},
+    tls::{self, TlsConnector},
+    utils::{Address, DomainName},
+};
+
+/// Default time allowed for a whole fetch
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Time until the first byte of the response arrived
+    pub ttfb: Duration,
+    /// Time to establish the stream the request was sent on, zero when it
+    /// was handed to [`request`]
+    pub handshake: Duration,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn other<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+pub(crate) fn tls_connector() -> TlsConnector {
+    tls::client_connector(&[])
+}
+
+/// GET `url` directly, following no redirects
+pub async fn get(url: &str, timeout: Duration) -> io::Result<HttpResponse> {
+    get_via(url, timeout, None).await
+}
+
+/// GET `url` through `via`, or directly without an outbound
+pub async fn get_via(
+    url: &str,
+    timeout: Duration,
+    via: Option<&dyn Outbound>,
+) -> io::Result<HttpResponse> {
+    match rt::timeout(timeout, get_inner(url, via)).await {
+        Ok(res) => res,
+        Err(..) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("fetching {} timed out", url),
+        )),
+    }
+}
+
+async fn get_inner(url: &str, via: Option<&dyn Outbound>) -> io::Result<HttpResponse> {
+    let parsed = Url::parse(url).map_err(other)?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| other("url without host"))?
+        .to_owned();
+    let port = parsed
+        .port_or_known_default()
+        .ok_or_else(|| other("url without port"))?;
+    let start = Instant::now();
+    let stream: BoxStream = match via {
+        // Leave name resolution to the proxy
+        Some(via) => {
+            via.dial(
+                &Address::DomainName(DomainName(host.clone(), port)),
+                &TcpDialer,
+            )
+            .await?
+        }
+        None => {
+            let addr = lookup_host((host.as_str(), port))
+                .await?
+                .next()
+                .ok_or_else(|| other("host resolved to no address"))?;
+            Box::new(TcpStream::connect(&addr).await?)
+        }
+    };
+
+    let resp = match parsed.scheme() {
+        "http" => {
+            let handshake = start.elapsed();
+            HttpResponse {
+                handshake,
+                ..request(stream, &parsed, &host).await?
+            }
+        }
+        "https" => {
+            let stream = tls::connect(&tls_connector(), &host, stream).await?;
+            let handshake = start.elapsed();
+            HttpResponse {
+                handshake,
+                ..request(stream, &parsed, &host).await?
+            }
+        }
+        scheme => return Err(other(format!("unsupported scheme {}", scheme))),
+    };
+    Ok(resp)
+}
+
+/// Send a GET on an established stream and read the whole response
+pub async fn request<S>(mut stream: S, url: &Url, host: &str) -> io::Result<HttpResponse>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    };
+    let req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: tache/{}\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        crate::VERSION
+    );
+    let start = Instant::now();
+    stream.write_all(req.as_bytes()).await?;
+
+    let mut buf = Vec::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+    let mut ttfb = None;
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if ttfb.is_none() {
+            ttfb = Some(start.elapsed());
+        }
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let head_len = match response.parse(&buf).map_err(other)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => return Err(other("truncated http response")),
+    };
+    let headers: Vec<(String, String)> = response
+        .headers
+        .iter()
+        .map(|h| {
+            (
+                h.name.to_owned(),
+                String::from_utf8_lossy(h.value).into_owned(),
+            )
+        })
+        .collect();
+    let status = response.code.unwrap_or(0);
+
+    let mut resp = HttpResponse {
+        status,
+        headers,
+        body: Vec::new(),
+        ttfb: ttfb.unwrap_or_default(),
+        handshake: Duration::default(),
+    };
+    let raw = &buf[head_len..];
+    resp.body = if resp
+        .header("transfer-encoding")
+        .map(|v| v.eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false)
+    {
+        decode_chunked(raw)?
+    } else {
+        match resp
+            .header("content-length")
+            .and_then(|v| v.parse::<usize>().ok())
+        {
+            Some(len) if len <= raw.len() => raw[..len].to_vec(),
+            Some(_) => return Err(other("truncated http body")),
+            None => raw.to_vec(),
+        }
+    };
+    Ok(resp)
+}
+
+fn decode_chunked(mut raw: &[u8]) -> io::Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(raw.len());
+    loop {
+        match httparse::parse_chunk_size(raw).map_err(|_| other("invalid chunk size"))? {
+            httparse::Status::Complete((_, 0)) => return Ok(body),
+            httparse::Status::Complete((pos, size)) => {
+                let size = size as usize;
+                // chunk data is followed by CRLF
+                if raw.len() < pos + size + 2 {
+                    return Err(other("truncated chunk"));
+                }
+                body.extend_from_slice(&raw[pos..pos + size]);
+                raw = &raw[pos + size + 2..];
+            }
+            httparse::Status::Partial => return Err(other("truncated chunk")),
+        }
+    }
+}
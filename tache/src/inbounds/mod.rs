@@ -1,4 +1,4 @@
 mod http;
 mod redir;
 mod socks;
-mod tun;
+pub mod tun;
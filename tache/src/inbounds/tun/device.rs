@@ -0,0 +1,452 @@
+//! The device of a TUN inbound and the listener its TCP connections end in
+//!
+//! Packets are written back readdressed by `nat`, the system's TCP stack
+//! terminates the connections and the engine relays what the listener
+//! accepts, IPv6 connections those of a listener and table of their own when
+//! the device has an IPv6 address. Pings are answered by `icmp` and UDP
+//! queries to hijacked DNS servers by `dns`, in tasks of their own, TCP ones
+//! are left to the engine. Packets of other kinds are dropped. Captures of the debug API
+//! asking for TUN packets record them as read and as written.
+//!
+//! Every queue of the device is read by a worker, which hands packets to the
+//! worker `packet::shard` picks for their flow, so the packets of a flow keep
+//! their order whichever queue they come in on.
+
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{select, Either},
+    pin_mut, StreamExt,
+};
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tuntap::{platform::posix::Fd, AsyncDevice, Configuration, Device, Tuntap};
+
+use super::{
+    dns, icmp,
+    nat::{Entry, Nat},
+    packet,
+};
+use crate::{
+    config::{IcmpMode, InboundConfig},
+    context::SharedContext,
+    dns::DnsHijack,
+    engine::capture::Capture,
+    ip_trie::parse_cidr,
+    rt::{self, TcpListener, TcpStream},
+};
+
+/// Address of the device without `inet4-address`, the route of its prefix
+/// brings the default fake-ip range in
+const DEFAULT_INET4_ADDRESS: &str = "198.18.0.1/16";
+/// Smallest IP packet, a bare IPv4 header
+const MIN_PACKET_LEN: usize = 20;
+/// Largest IP packet
+const MAX_PACKET_LEN: usize = 65535;
+/// Address family in front of the packets of utun devices
+#[cfg(target_os = "macos")]
+const HEADER_LEN: usize = 4;
+#[cfg(not(target_os = "macos"))]
+const HEADER_LEN: usize = 0;
+/// Waits for an address to leave the tentative state, IPv6 ones are in it
+/// for a moment after being set
+const BIND_TRIES: u32 = 50;
+const BIND_RETRY: Duration = Duration::from_millis(100);
+
+pub struct Tun {
+    device: AsyncDevice,
+    /// Queues beyond the first
+    queues: Vec<AsyncDevice<Fd>>,
+    nat: Arc<Nat>,
+    /// Present with `inet6-address`
+    nat6: Option<Arc<Nat>>,
+    inbound: String,
+    mss: Option<u16>,
+    icmp: IcmpMode,
+    hijack: Arc<DnsHijack>,
+}
+
+/// Listener of one address family
+pub struct Listener {
+    listener: TcpListener,
+    nat: Arc<Nat>,
+    hijack: Arc<DnsHijack>,
+}
+
+/// Create the device of TUN inbound `inbound` and listen on its addresses,
+/// the IPv4 one first
+pub async fn open(inbound: &InboundConfig) -> io::Result<(Tun, Vec<Listener>)> {
+    let (name, inet4_address, inet6_address, queues, mss, icmp, hijack) = match *inbound {
+        InboundConfig::TUN {
+            ref device,
+            ref inet4_address,
+            ref inet6_address,
+            queues,
+            mss_clamp,
+            icmp,
+            ref dns_hijack,
+            ..
+        } => (
+            device,
+            inet4_address,
+            inet6_address,
+            queues.unwrap_or(1),
+            mss_clamp,
+            icmp.unwrap_or_default(),
+            dns_hijack,
+        ),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a TUN inbound",
+            ))
+        }
+    };
+    let cidr = inet4_address.as_deref().unwrap_or(DEFAULT_INET4_ADDRESS);
+    let (address, prefix) = match parse_cidr(cidr) {
+        Some((IpAddr::V4(address), prefix)) => (address, prefix),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "inet4-address isn't an IPv4 CIDR",
+            ))
+        }
+    };
+    let hijack = DnsHijack::new(hijack)
+        .map(Arc::new)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut config = Configuration::default();
+    if let Some(ref name) = *name {
+        config.name(name);
+    }
+    let netmask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+    config
+        .address(address)
+        .netmask(Ipv4Addr::from(netmask))
+        .up();
+    let address6 = match inet6_address.as_deref().map(parse_cidr) {
+        None => None,
+        Some(Some((IpAddr::V6(address), prefix))) => Some((address, prefix)),
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "inet6-address isn't an IPv6 CIDR",
+            ))
+        }
+    };
+    if let Some((address, prefix)) = address6 {
+        // Set by the Linux devices of `tuntap` only
+        if cfg!(not(target_os = "linux")) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "inet6-address is set on Linux devices only",
+            ));
+        }
+        config.address6(address, prefix);
+    }
+    set_queues(&mut config, queues)?;
+    let mut device = tuntap::create(&config)?;
+    let queues = take_queues(&mut device)
+        .into_iter()
+        .map(AsyncDevice::new)
+        .collect::<io::Result<_>>()?;
+    let device = AsyncDevice::new(device)?;
+
+    // Connections come in from made up addresses of the device's prefixes
+    let listener = listen(address.into(), &hijack).await?;
+    let nat = listener.nat.clone();
+    let mut listeners = vec![listener];
+    if let Some((address, _)) = address6 {
+        listeners.push(listen(address.into(), &hijack).await?);
+    }
+    let nat6 = listeners.get(1).map(|listener| listener.nat.clone());
+    Ok((
+        Tun {
+            device,
+            queues,
+            nat,
+            nat6,
+            inbound: inbound.name().to_owned(),
+            mss,
+            icmp,
+            hijack,
+        },
+        listeners,
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_queues(config: &mut Configuration, queues: usize) -> io::Result<()> {
+    config.platform(|platform| {
+        platform.queues(queues);
+    });
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_queues(_config: &mut Configuration, queues: usize) -> io::Result<()> {
+    if queues > 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "queues are set on Linux devices only",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn take_queues(device: &mut Device) -> Vec<Fd> {
+    device.take_queues()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn take_queues(_device: &mut Device) -> Vec<Fd> {
+    Vec::new()
+}
+
+/// Listen on `address` of the device, a port of its own
+async fn listen(address: IpAddr, hijack: &Arc<DnsHijack>) -> io::Result<Listener> {
+    let mut tries = 1;
+    let listener = loop {
+        match TcpListener::bind(SocketAddr::new(address, 0)).await {
+            Err(ref e) if e.kind() == io::ErrorKind::AddrNotAvailable && tries < BIND_TRIES => {
+                tries += 1;
+                rt::delay_for(BIND_RETRY).await;
+            }
+            result => break result?,
+        }
+    };
+    let nat = Arc::new(Nat::new(listener.local_addr()?));
+    Ok(Listener {
+        listener,
+        nat,
+        hijack: hijack.clone(),
+    })
+}
+
+impl Tun {
+    pub fn name(&self) -> &str {
+        self.device.get_ref().name()
+    }
+
+    /// Read packets until a queue fails, writing back the readdressed ones
+    /// and the answers of tasks. Workers of the queues beyond the first run
+    /// as tasks of their own and stop with this one.
+    pub async fn run(self, context: SharedContext) -> io::Result<()> {
+        let name = self.name().to_owned();
+        let Tun {
+            device,
+            queues,
+            nat,
+            nat6,
+            inbound,
+            mss,
+            icmp,
+            hijack,
+        } = self;
+        let (workers, mut inboxes): (Vec<_>, Vec<_>) =
+            (0..=queues.len()).map(|_| mpsc::unbounded()).unzip();
+        let shared = Arc::new(Shared {
+            name,
+            capture: context.capture(),
+            context,
+            nat,
+            nat6,
+            inbound,
+            mss,
+            icmp,
+            hijack,
+            workers,
+        });
+
+        let (failed, mut failures) = mpsc::unbounded();
+        let mut stops = Vec::with_capacity(queues.len());
+        for (index, (queue, jobs)) in queues.into_iter().zip(inboxes.drain(1..)).enumerate() {
+            let (stop, stopped) = oneshot::channel::<()>();
+            stops.push(stop);
+            let working = work(shared.clone(), index + 1, queue, jobs);
+            let failed = failed.clone();
+            rt::spawn(async move {
+                pin_mut!(working);
+                if let Either::Left((Err(e), _)) = select(working, stopped).await {
+                    let _ = failed.unbounded_send(e);
+                }
+            });
+        }
+        let working = work(shared, 0, device, inboxes.remove(0));
+        pin_mut!(working);
+        match select(working, failures.next()).await {
+            Either::Left((result, _)) => result,
+            Either::Right((Some(e), _)) => Err(e),
+            // `failed` is held until here
+            Either::Right((None, _)) => Ok(()),
+        }
+    }
+}
+
+/// What the workers of a device share
+struct Shared {
+    name: String,
+    /// Packets are recorded as read and as written
+    capture: Arc<Capture>,
+    context: SharedContext,
+    nat: Arc<Nat>,
+    nat6: Option<Arc<Nat>>,
+    inbound: String,
+    mss: Option<u16>,
+    icmp: IcmpMode,
+    hijack: Arc<DnsHijack>,
+    /// Inboxes of the workers by queue
+    workers: Vec<mpsc::UnboundedSender<Job>>,
+}
+
+/// Frame handed to the worker of a queue
+enum Job {
+    /// Read by another worker, of a flow of this one
+    Read(Vec<u8>),
+    /// Answering one this worker read, written as is
+    Answer(Vec<u8>),
+}
+
+/// Read `queue` until it fails, handing packets to the worker of their flow
+/// and doing the jobs handed to worker `index`
+async fn work<Q>(
+    shared: Arc<Shared>,
+    index: usize,
+    mut queue: Q,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+) -> io::Result<()>
+where
+    Q: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; HEADER_LEN + MAX_PACKET_LEN];
+    loop {
+        let event = match select(queue.read(&mut buf), jobs.next()).await {
+            Either::Left((len, _)) => Either::Left(len?),
+            Either::Right((job, _)) => Either::Right(job),
+        };
+        match event {
+            Either::Left(len) => {
+                let frame = &mut buf[..len];
+                shared.record(frame);
+                let owner = frame
+                    .get(HEADER_LEN..)
+                    .map_or(index, |packet| packet::shard(packet, shared.workers.len()));
+                if owner != index {
+                    let _ = shared.workers[owner].unbounded_send(Job::Read(frame.to_vec()));
+                } else if shared.handle(frame, index) {
+                    shared.write(&mut queue, frame).await;
+                }
+            }
+            Either::Right(Some(Job::Read(mut frame))) => {
+                if shared.handle(&mut frame, index) {
+                    shared.write(&mut queue, &frame).await;
+                }
+            }
+            Either::Right(Some(Job::Answer(frame))) => shared.write(&mut queue, &frame).await,
+            // `shared` holds a sender of every inbox
+            Either::Right(None) => {}
+        }
+    }
+}
+
+impl Shared {
+    /// Handle `frame` for worker `index`, true to write it back
+    fn handle(&self, frame: &mut [u8], index: usize) -> bool {
+        if frame.len() < HEADER_LEN + MIN_PACKET_LEN {
+            return false;
+        }
+        let (header, packet) = frame.split_at_mut(HEADER_LEN);
+        if packet::echo_request(packet).is_some() {
+            let (context, inbound, mode) = (self.context.clone(), self.inbound.clone(), self.icmp);
+            let packet = packet.to_vec();
+            self.answer(header, index, async move {
+                icmp::answer(&context, &inbound, mode, packet).await
+            });
+            return false;
+        }
+        if dns::hijacked(&self.hijack, packet) {
+            let (context, packet) = (self.context.clone(), packet.to_vec());
+            self.answer(header, index, async move {
+                dns::answer(&context, &packet).await
+            });
+            return false;
+        }
+        self.readdress(packet)
+    }
+
+    /// Hand the packet `answering` comes up with to worker `index`, in a
+    /// task as checking the destination or resolving takes a while
+    fn answer<F>(&self, header: &[u8], index: usize, answering: F)
+    where
+        F: Future<Output = Option<Vec<u8>>> + Send + 'static,
+    {
+        let mut frame = header.to_vec();
+        let worker = self.workers[index].clone();
+        rt::spawn(async move {
+            if let Some(reply) = answering.await {
+                frame.extend_from_slice(&reply);
+                let _ = worker.unbounded_send(Job::Answer(frame));
+            }
+        });
+    }
+
+    /// Write `frame` to `queue`
+    async fn write<Q: AsyncWrite + Unpin>(&self, queue: &mut Q, frame: &[u8]) {
+        self.record(frame);
+        // Lost like any packet, the peers retransmit
+        if let Err(e) = queue.write(frame).await {
+            debug!("[{}] failed to write a packet, err: {}", self.name, e);
+        }
+    }
+
+    /// Hand the packet of `frame` to the capture, if one runs
+    fn record(&self, frame: &[u8]) {
+        if let Some(packet) = frame.get(HEADER_LEN..) {
+            self.capture.packet(&self.inbound, packet);
+        }
+    }
+
+    /// Turn `packet` into the one going the other way of the system's stack,
+    /// false to drop it
+    fn readdress(&self, packet: &mut [u8]) -> bool {
+        let nat = if packet::is_ipv6(packet) {
+            match self.nat6 {
+                Some(ref nat) => nat,
+                None => return false,
+            }
+        } else {
+            &self.nat
+        };
+        if let Some(mss) = self.mss {
+            packet::clamp_mss(packet, mss);
+        }
+        nat.translate(packet)
+    }
+}
+
+impl Listener {
+    /// Whether connections to `dst` are DNS ones answered by `dns::serve`
+    pub fn hijacks(&self, dst: SocketAddr) -> bool {
+        self.hijack.matches(dst)
+    }
+
+    /// Next connection of the device, with the client and destination of
+    /// its session
+    pub async fn accept(&mut self) -> io::Result<(TcpStream, Entry)> {
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            match self.nat.accept(peer) {
+                Some(entry) => return Ok((stream, entry)),
+                None => debug!("Dropped connection from {} not through the device", peer),
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! DNS queries routed into the TUN device towards hijacked servers
+//!
+//! Clients with a resolver of their own still get answers of the built-in
+//! one, so fake-ip and the DNS rules apply to them as well. UDP queries are
+//! answered from the device loop, TCP connections once the listener accepts
+//! them.
+
+use std::{convert::TryFrom, io, net::IpAddr, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::packet;
+use crate::{context::Context, dns::DnsHijack, rt};
+
+/// Time a TCP client may keep its connection open between queries
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether `packet` is a UDP query to a server in `hijack`, answered by
+/// `answer` instead of being relayed
+pub fn hijacked(hijack: &DnsHijack, packet: &[u8]) -> bool {
+    packet::udp_datagram(packet).is_some_and(|(_, dst, _)| hijack.matches(dst))
+}
+
+/// Reply to the query in `packet` as if from the server it was sent to,
+/// `None` without a DNS section or for another kind of packet
+pub async fn answer(context: &Context, packet: &[u8]) -> Option<Vec<u8>> {
+    let (src, _, range) = packet::udp_datagram(packet)?;
+    let resolver = context.dns()?;
+    let response = crate::dns::answer(&resolver, &packet[range], Some(src.ip())).await?;
+    packet::udp_reply(packet, &response)
+}
+
+/// Answer the length prefixed queries `client` sends over `stream`, a TCP
+/// connection to a hijacked server, until it closes or idles
+pub async fn serve<S>(context: &Context, client: IpAddr, mut stream: S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let resolver = match context.dns() {
+        Some(resolver) => resolver,
+        None => return Ok(()),
+    };
+    let mut len = [0; 2];
+    loop {
+        match rt::timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut len)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Ok(()),
+        }
+        let mut query = vec![0; usize::from(u16::from_be_bytes(len))];
+        stream.read_exact(&mut query).await?;
+        let response = match crate::dns::answer(&resolver, &query, Some(client)).await {
+            Some(response) => response,
+            None => return Ok(()),
+        };
+        let len = u16::try_from(response.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "response too long"))?;
+        stream
+            .write_all(&[&len.to_be_bytes()[..], &response].concat())
+            .await?;
+    }
+}
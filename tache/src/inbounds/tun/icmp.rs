@@ -0,0 +1,83 @@
+//! Echo requests routed into the TUN device
+//!
+//! Nothing carries ICMP through a proxy, so pings are answered here instead
+//! of vanishing. With `check` a reply means the destination takes TCP
+//! connections through the outbound rules pick for it, which is what the
+//! user pinging wants to know.
+
+use std::net::SocketAddr;
+
+use log::debug;
+
+use super::packet;
+use crate::{
+    config::IcmpMode,
+    context::Context,
+    engine::{run_rule, ConnectionMeta},
+    outbound::{probe::PROBE_TIMEOUT, TcpDialer},
+    rt,
+    utils::Address,
+};
+
+/// Ports tried in turn by `check`
+const CHECK_PORTS: [u16; 2] = [443, 80];
+
+/// Reply to the echo request in `packet`, `None` for no answer or another
+/// kind of packet
+pub async fn answer(
+    context: &Context,
+    inbound: &str,
+    mode: IcmpMode,
+    mut packet: Vec<u8>,
+) -> Option<Vec<u8>> {
+    let dst = packet::echo_request(&packet)?;
+    match mode {
+        IcmpMode::Reply => {}
+        IcmpMode::Drop => return None,
+        IcmpMode::Check => {
+            let mut reachable = false;
+            for &port in CHECK_PORTS.iter() {
+                if connects(context, inbound, SocketAddr::new(dst, port)).await {
+                    reachable = true;
+                    break;
+                }
+            }
+            if !reachable {
+                debug!("[{}] ping to {} unanswered, not reachable", inbound, dst);
+                return None;
+            }
+        }
+    }
+    if packet::echo_reply(&mut packet) {
+        Some(packet)
+    } else {
+        None
+    }
+}
+
+/// Whether a TCP connection to `dst` opens through the outbound picked for it
+async fn connects(context: &Context, inbound: &str, dst: SocketAddr) -> bool {
+    let meta = ConnectionMeta {
+        udp: false,
+        inbound: inbound.to_owned(),
+        user: None,
+        uid: None,
+        host: dst.ip().to_string(),
+        dst_port: dst.port(),
+        src_addr: None,
+        dst_addr: Some(dst),
+        protocol: None,
+        sni: None,
+        tls_version: None,
+        fingerprint: None,
+    };
+    let matched = match run_rule(context, &meta).await {
+        Ok(matched) => matched,
+        Err(_) => return false,
+    };
+    let target = Address::SocketAddr(dst);
+    let dial = matched.outbound.dial(&target, &TcpDialer);
+    rt::timeout(PROBE_TIMEOUT, dial)
+        .await
+        .is_ok_and(|stream| stream.is_ok())
+}
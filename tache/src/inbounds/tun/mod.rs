@@ -0,0 +1,15 @@
+//! TUN inbound, connections taken from the packets of a virtual interface
+//!
+//! TCP connections are handed to the system's stack by `nat` and relayed
+//! from its listener like those of other inbounds, or answered by `dns` when
+//! they go to a hijacked DNS server. Pings are answered by `icmp`, UDP queries
+//! to hijacked servers by `dns`, other packets are dropped by the workers of
+//! the device queues.
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod device;
+pub mod dns;
+pub mod icmp;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod nat;
+pub mod packet;
@@ -0,0 +1,212 @@
+//! TCP connections of the device handed to a listener of the system's stack
+//!
+//! A SYN from `client` to `dst` is readdressed to come from `dst` at a port
+//! of its own and to go to the listener, so the system terminates the
+//! connection and the port of the accepted peer tells what it was meant for.
+//! Segments of the listener to that port are readdressed back to come from
+//! `dst` and go to `client`. One table serves one address family.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::packet;
+
+/// Ports readdressed connections come from
+const FIRST_PORT: u16 = 10000;
+const PORTS: usize = (u16::MAX - FIRST_PORT) as usize + 1;
+/// Time sessions not accepted or closed are kept for their last segments
+const LINGER: Duration = Duration::from_secs(60);
+
+struct Session {
+    client: SocketAddr,
+    dst: SocketAddr,
+    /// Held by an `Entry` of the accepted connection
+    accepted: bool,
+    last: Instant,
+}
+
+struct Table {
+    sessions: HashMap<u16, Session>,
+    ports: HashMap<(SocketAddr, SocketAddr), u16>,
+    next: u16,
+    swept: Instant,
+}
+
+impl Table {
+    /// Port of a new session from `client` to `dst`, `None` when all are
+    /// taken
+    fn open(&mut self, client: SocketAddr, dst: SocketAddr) -> Option<u16> {
+        let now = Instant::now();
+        if now.duration_since(self.swept) >= LINGER {
+            self.sweep(now);
+        }
+        if self.sessions.len() >= PORTS {
+            return None;
+        }
+        let mut port = self.next;
+        while self.sessions.contains_key(&port) {
+            port = port.checked_add(1).unwrap_or(FIRST_PORT);
+        }
+        self.next = port.checked_add(1).unwrap_or(FIRST_PORT);
+        self.sessions.insert(
+            port,
+            Session {
+                client,
+                dst,
+                accepted: false,
+                last: now,
+            },
+        );
+        self.ports.insert((client, dst), port);
+        Some(port)
+    }
+
+    /// Drop sessions idle for `LINGER` that no connection holds
+    fn sweep(&mut self, now: Instant) {
+        let ports = &mut self.ports;
+        self.sessions.retain(|_, session| {
+            let keep = session.accepted || now.duration_since(session.last) < LINGER;
+            if !keep {
+                ports.remove(&(session.client, session.dst));
+            }
+            keep
+        });
+        self.swept = now;
+    }
+}
+
+pub struct Nat {
+    listener: SocketAddr,
+    table: Mutex<Table>,
+}
+
+impl Nat {
+    /// Table of connections handed to `listener`
+    pub fn new(listener: SocketAddr) -> Nat {
+        Nat {
+            listener,
+            table: Mutex::new(Table {
+                sessions: HashMap::new(),
+                ports: HashMap::new(),
+                next: FIRST_PORT,
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// Readdress the TCP segment in `packet` read from the device, false
+    /// when it belongs to no connection and is dropped
+    pub fn translate(&self, packet: &mut [u8]) -> bool {
+        let (src, dst, opening) = match packet::tcp_ends(packet) {
+            Some(ends) => ends,
+            None => return false,
+        };
+        let mut table = self.table.lock().unwrap();
+        if src == self.listener {
+            return match table.sessions.get_mut(&dst.port()) {
+                Some(session) if session.dst.ip() == dst.ip() => {
+                    session.last = Instant::now();
+                    packet::rewrite_tcp(packet, session.dst, session.client)
+                }
+                _ => false,
+            };
+        }
+        let port = match table.ports.get(&(src, dst)) {
+            Some(&port) => port,
+            None if opening => match table.open(src, dst) {
+                Some(port) => port,
+                None => return false,
+            },
+            None => return false,
+        };
+        if let Some(session) = table.sessions.get_mut(&port) {
+            session.last = Instant::now();
+        }
+        packet::rewrite_tcp(packet, SocketAddr::new(dst.ip(), port), self.listener)
+    }
+
+    /// Session of the connection the listener accepted from `peer`, kept
+    /// while the entry lives
+    pub fn accept(self: &Arc<Self>, peer: SocketAddr) -> Option<Entry> {
+        let mut table = self.table.lock().unwrap();
+        let session = table.sessions.get_mut(&peer.port())?;
+        if session.dst.ip() != peer.ip() {
+            return None;
+        }
+        session.accepted = true;
+        Some(Entry {
+            nat: self.clone(),
+            port: peer.port(),
+            client: session.client,
+            dst: session.dst,
+        })
+    }
+}
+
+/// Session of an accepted connection, left to linger once dropped
+pub struct Entry {
+    nat: Arc<Nat>,
+    port: u16,
+    pub client: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let mut table = self.nat.table.lock().unwrap();
+        if let Some(session) = table.sessions.get_mut(&self.port) {
+            session.accepted = false;
+            session.last = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// IPv4 TCP segment with `flags` and no options
+    fn segment(src: SocketAddr, dst: SocketAddr, flags: u8) -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0, 0, 40, 0, 0, 0x40, 0, 64, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        packet.extend_from_slice(&[0; 20]);
+        packet[32] = 5 << 4;
+        packet[33] = flags;
+        packet::rewrite_tcp(&mut packet, src, dst);
+        packet
+    }
+
+    #[test]
+    fn hands_connections_to_the_listener() {
+        let listener: SocketAddr = "198.18.0.1:7000".parse().unwrap();
+        let client: SocketAddr = "198.18.0.1:50000".parse().unwrap();
+        let dst: SocketAddr = "198.18.5.6:443".parse().unwrap();
+        let nat = Arc::new(Nat::new(listener));
+
+        // Only SYNs open sessions
+        assert!(!nat.translate(&mut segment(client, dst, 0x10)));
+        let mut syn = segment(client, dst, 0x02);
+        assert!(nat.translate(&mut syn));
+        let (peer, to, _) = packet::tcp_ends(&syn).unwrap();
+        assert_eq!((peer.ip(), to), (dst.ip(), listener));
+        assert!(nat.translate(&mut segment(client, dst, 0x10)));
+
+        let mut reply = segment(listener, peer, 0x12);
+        assert!(nat.translate(&mut reply));
+        assert_eq!(packet::tcp_ends(&reply).unwrap(), (dst, client, false));
+
+        let entry = nat.accept(peer).unwrap();
+        assert_eq!((entry.client, entry.dst), (client, dst));
+        assert!(nat.accept("198.18.9.9:1".parse().unwrap()).is_none());
+
+        // Another client gets a port of its own
+        let mut other = segment("198.18.0.1:50001".parse().unwrap(), dst, 0x02);
+        assert!(nat.translate(&mut other));
+        assert_ne!(packet::tcp_ends(&other).unwrap().0, peer);
+    }
+}
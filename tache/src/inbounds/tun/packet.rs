@@ -0,0 +1,591 @@
+//! IP packets as read from and written to the TUN device
+//!
+//! Edits happen in place and patch the checksums they invalidate, packets
+//! are never reassembled or copied. IPv4 and IPv6 are both handled, fragments
+//! after the first are left alone.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    convert::TryFrom,
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+};
+
+const ICMP: u8 = 1;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ICMPV6: u8 = 58;
+
+/// IPv6 extension headers skipped to reach the payload
+const HOP_BY_HOP: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const DESTINATION: u8 = 60;
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// Internet checksum of `chunks` taken as one run of bytes
+pub fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut odd = None;
+    for &b in chunks.iter().flat_map(|c| c.iter()) {
+        match odd.take() {
+            Some(high) => sum += u32::from(high) << 8 | u32::from(b),
+            None => odd = Some(b),
+        }
+    }
+    if let Some(high) = odd {
+        sum += u32::from(high) << 8;
+    }
+    !fold(sum)
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Patch `check` for a 16-bit word changing from `old` to `new`, RFC 1624
+fn update_checksum(check: &mut [u8], old: u16, new: u16) {
+    let current = u16::from_be_bytes([check[0], check[1]]);
+    let sum = u32::from(!current) + u32::from(!old) + u32::from(new);
+    check.copy_from_slice(&(!fold(sum)).to_be_bytes());
+}
+
+/// Whether `packet` is IPv6 rather than IPv4
+pub fn is_ipv6(packet: &[u8]) -> bool {
+    packet.first().is_some_and(|b| b >> 4 == 6)
+}
+
+/// Where the payload of a packet carrying `protocol` is, only for the first
+/// fragment
+fn payload(packet: &[u8], protocol: u8) -> Option<Range<usize>> {
+    if is_ipv6(packet) {
+        ipv6_payload(packet, protocol)
+    } else {
+        ipv4_payload(packet, protocol)
+    }
+}
+
+fn ipv4_payload(packet: &[u8], protocol: u8) -> Option<Range<usize>> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 || packet[9] != protocol {
+        return None;
+    }
+    // Only the first fragment holds the header
+    if u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0 {
+        return None;
+    }
+    let header_len = usize::from(packet[0] & 0x0f) * 4;
+    let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    if header_len < 20 || total_len < header_len || total_len > packet.len() {
+        return None;
+    }
+    Some(header_len..total_len)
+}
+
+fn ipv6_payload(packet: &[u8], protocol: u8) -> Option<Range<usize>> {
+    if packet.len() < 40 {
+        return None;
+    }
+    let end = 40 + usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+    if end > packet.len() {
+        return None;
+    }
+    let (mut next, mut at) = (packet[6], 40);
+    loop {
+        match next {
+            HOP_BY_HOP | ROUTING | DESTINATION if at + 8 <= end => {
+                next = packet[at];
+                at += (usize::from(packet[at + 1]) + 1) * 8;
+            }
+            FRAGMENT if at + 8 <= end => {
+                // Offset in 8 byte units, the header only comes first
+                if u16::from_be_bytes([packet[at + 2], packet[at + 3]]) >> 3 != 0 {
+                    return None;
+                }
+                next = packet[at];
+                at += 8;
+            }
+            next if next == protocol && at <= end => return Some(at..end),
+            _ => return None,
+        }
+    }
+}
+
+/// Source and destination addresses, swapped for a reply
+fn addresses(packet: &mut [u8]) -> (&mut [u8], &mut [u8]) {
+    if is_ipv6(packet) {
+        packet[8..40].split_at_mut(16)
+    } else {
+        packet[12..20].split_at_mut(4)
+    }
+}
+
+/// Which of `shards` workers handles the flow of `packet`, the same one for
+/// both directions. Fragments after the first have no ports and only go by
+/// their addresses.
+pub fn shard(packet: &[u8], shards: usize) -> usize {
+    if shards <= 1 {
+        return 0;
+    }
+    let (src, dst) = match packet.len() {
+        len if is_ipv6(packet) && len >= 40 => (&packet[8..24], &packet[24..40]),
+        len if !is_ipv6(packet) && len >= 20 => (&packet[12..16], &packet[16..20]),
+        _ => return 0,
+    };
+    let (protocol, src_port, dst_port) = [TCP, UDP]
+        .iter()
+        .find_map(|&protocol| {
+            let range = payload(packet, protocol).filter(|range| range.len() >= 4)?;
+            let ports = &packet[range];
+            Some((protocol, [ports[0], ports[1]], [ports[2], ports[3]]))
+        })
+        .unwrap_or_default();
+    let mut ends = [(src, src_port), (dst, dst_port)];
+    ends.sort();
+    let mut hasher = DefaultHasher::new();
+    (protocol, ends).hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// TCP header and payload of the first fragment of a packet
+fn tcp_segment(packet: &mut [u8]) -> Option<&mut [u8]> {
+    let range = payload(packet, TCP).filter(|range| range.len() >= 20)?;
+    Some(&mut packet[range])
+}
+
+/// ICMP protocol number and echo request and reply types of `packet`
+fn echo_kinds(packet: &[u8]) -> (u8, u8, u8) {
+    if is_ipv6(packet) {
+        (ICMPV6, ECHO_REQUEST_V6, ECHO_REPLY_V6)
+    } else {
+        (ICMP, ECHO_REQUEST, ECHO_REPLY)
+    }
+}
+
+/// Destination of an ICMP or ICMPv6 echo request
+pub fn echo_request(packet: &[u8]) -> Option<IpAddr> {
+    let (protocol, request, _) = echo_kinds(packet);
+    let message = &packet[payload(packet, protocol)?];
+    if message.len() < 8 || message[0] != request {
+        return None;
+    }
+    if is_ipv6(packet) {
+        let dst = <[u8; 16]>::try_from(&packet[24..40]).ok()?;
+        Some(IpAddr::V6(Ipv6Addr::from(dst)))
+    } else {
+        let dst = <[u8; 4]>::try_from(&packet[16..20]).ok()?;
+        Some(IpAddr::V4(Ipv4Addr::from(dst)))
+    }
+}
+
+/// Turn the echo request in `packet` into its reply, false for other
+/// packets
+pub fn echo_reply(packet: &mut [u8]) -> bool {
+    if echo_request(packet).is_none() {
+        return false;
+    }
+    // Swapped addresses leave the header and pseudo-header checksums as is
+    let (src, dst) = addresses(packet);
+    src.swap_with_slice(dst);
+    let (protocol, request, reply) = echo_kinds(packet);
+    let message = match payload(packet, protocol) {
+        Some(range) => &mut packet[range],
+        None => return false,
+    };
+    let code = message[1];
+    message[0] = reply;
+    let word = |kind: u8| u16::from(kind) << 8 | u16::from(code);
+    update_checksum(&mut message[2..4], word(request), word(reply));
+    true
+}
+
+/// Source and destination addresses
+fn ips(packet: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    if is_ipv6(packet) {
+        let src = <[u8; 16]>::try_from(packet.get(8..24)?).ok()?;
+        let dst = <[u8; 16]>::try_from(packet.get(24..40)?).ok()?;
+        Some((Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into()))
+    } else {
+        let src = <[u8; 4]>::try_from(packet.get(12..16)?).ok()?;
+        let dst = <[u8; 4]>::try_from(packet.get(16..20)?).ok()?;
+        Some((Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into()))
+    }
+}
+
+/// Source and destination of a UDP datagram and where its payload is
+pub fn udp_datagram(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, Range<usize>)> {
+    let range = payload(packet, UDP).filter(|range| range.len() >= 8)?;
+    let (src, dst) = ips(packet)?;
+    let udp = &packet[range.clone()];
+    let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+    let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+    Some((
+        SocketAddr::new(src, src_port),
+        SocketAddr::new(dst, dst_port),
+        range.start + 8..range.end,
+    ))
+}
+
+/// UDP packet answering the datagram in `request` with `payload`, from the
+/// address and port it was sent to
+pub fn udp_reply(request: &[u8], payload: &[u8]) -> Option<Vec<u8>> {
+    let (to, from, _) = udp_datagram(request)?;
+    let udp_len = u16::try_from(8 + payload.len()).ok()?;
+    let mut packet = match (from.ip(), to.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let total_len = 20u16.checked_add(udp_len)?;
+            let mut header = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, UDP, 0, 0];
+            header[2..4].copy_from_slice(&total_len.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            let check = checksum(&[&header]);
+            header[10..12].copy_from_slice(&check.to_be_bytes());
+            header
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut header = vec![0x60, 0, 0, 0, 0, 0, UDP, 64];
+            header[4..6].copy_from_slice(&udp_len.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            header
+        }
+        _ => return None,
+    };
+    let at = packet.len();
+    packet.extend_from_slice(&from.port().to_be_bytes());
+    packet.extend_from_slice(&to.port().to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+
+    let mut pseudo = match (from.ip(), to.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => [&src.octets()[..], &dst.octets()[..]].concat(),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => [&src.octets()[..], &dst.octets()[..]].concat(),
+        _ => return None,
+    };
+    // Adds up the same as the longer IPv6 pseudo-header
+    pseudo.extend_from_slice(&[0, UDP]);
+    pseudo.extend_from_slice(&udp_len.to_be_bytes());
+    // Zero means no checksum in UDP, it is sent as all ones instead
+    let check = match checksum(&[&pseudo, &packet[at..]]) {
+        0 => 0xffff,
+        check => check,
+    };
+    packet[at + 6..at + 8].copy_from_slice(&check.to_be_bytes());
+    Some(packet)
+}
+
+/// Source and destination of the TCP segment in `packet`, and whether it is
+/// a SYN opening a connection
+pub fn tcp_ends(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, bool)> {
+    let range = payload(packet, TCP).filter(|range| range.len() >= 20)?;
+    let (src, dst) = ips(packet)?;
+    let tcp = &packet[range];
+    let src_port = u16::from_be_bytes([tcp[0], tcp[1]]);
+    let dst_port = u16::from_be_bytes([tcp[2], tcp[3]]);
+    let opening = tcp[13] & (TCP_SYN | TCP_ACK) == TCP_SYN;
+    Some((
+        SocketAddr::new(src, src_port),
+        SocketAddr::new(dst, dst_port),
+        opening,
+    ))
+}
+
+/// Readdress the TCP segment in `packet` from `src` to `dst`, false when the
+/// packet is of another kind or family
+pub fn rewrite_tcp(packet: &mut [u8], src: SocketAddr, dst: SocketAddr) -> bool {
+    let range = match payload(packet, TCP).filter(|range| range.len() >= 20) {
+        Some(range) => range,
+        None => return false,
+    };
+    let tcp_check = range.start + 16;
+    let (src_ip, dst_ip, at) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) if !is_ipv6(packet) => {
+            (s.octets().to_vec(), d.octets().to_vec(), 12)
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) if is_ipv6(packet) => {
+            (s.octets().to_vec(), d.octets().to_vec(), 8)
+        }
+        _ => return false,
+    };
+    // Addresses are in the IPv4 header checksum and in the pseudo-header of
+    // the TCP one, ports only in the latter
+    let v4_check = [10, tcp_check];
+    let addr_checks = if is_ipv6(packet) {
+        &v4_check[1..]
+    } else {
+        &v4_check[..]
+    };
+    patch(packet, at, &src_ip, addr_checks);
+    patch(packet, at + src_ip.len(), &dst_ip, addr_checks);
+    patch(packet, range.start, &src.port().to_be_bytes(), &[tcp_check]);
+    patch(
+        packet,
+        range.start + 2,
+        &dst.port().to_be_bytes(),
+        &[tcp_check],
+    );
+    true
+}
+
+/// Overwrite the words at `at` with `new`, patching the checksums at
+/// `checks` they count in
+fn patch(packet: &mut [u8], at: usize, new: &[u8], checks: &[usize]) {
+    for (i, word) in new.chunks(2).enumerate() {
+        let at = at + 2 * i;
+        let old = u16::from_be_bytes([packet[at], packet[at + 1]]);
+        let new = u16::from_be_bytes([word[0], word[1]]);
+        for &check in checks {
+            update_checksum(&mut packet[check..check + 2], old, new);
+        }
+        packet[at..at + 2].copy_from_slice(word);
+    }
+}
+
+/// Lower the MSS option of a TCP SYN in `packet` to `mss`, true when the
+/// packet was changed
+pub fn clamp_mss(packet: &mut [u8], mss: u16) -> bool {
+    let segment = match tcp_segment(packet) {
+        Some(segment) => segment,
+        None => return false,
+    };
+    let header_len = usize::from(segment[12] >> 4) * 4;
+    if segment[13] & TCP_SYN == 0 || header_len < 20 || header_len > segment.len() {
+        return false;
+    }
+    let mut i = 20;
+    while i < header_len {
+        match segment[i] {
+            OPTION_END => break,
+            OPTION_NOP => i += 1,
+            kind => {
+                let len = match segment.get(i + 1) {
+                    Some(&len) if len >= 2 && i + usize::from(len) <= header_len => len,
+                    _ => return false,
+                };
+                if kind == OPTION_MSS && len == 4 {
+                    let at = i + 2;
+                    let old = u16::from_be_bytes([segment[at], segment[at + 1]]);
+                    if old <= mss {
+                        return false;
+                    }
+                    segment[at..at + 2].copy_from_slice(&mss.to_be_bytes());
+                    // Words at odd offsets add to the checksum byte swapped
+                    let (old, new) = if at % 2 == 0 {
+                        (old, mss)
+                    } else {
+                        (old.swap_bytes(), mss.swap_bytes())
+                    };
+                    update_checksum(&mut segment[16..18], old, new);
+                    return true;
+                }
+                i += usize::from(len);
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// IPv4 TCP SYN from 10.0.0.2 to 10.0.0.1 with `options`
+    fn syn(options: &[u8]) -> Vec<u8> {
+        let tcp_len = 20 + options.len();
+        let mut packet = vec![
+            0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, TCP, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1,
+        ];
+        packet[2..4].copy_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+        let mut tcp = vec![0x30, 0x39, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0];
+        tcp.push(((tcp_len / 4) as u8) << 4);
+        tcp.extend_from_slice(&[TCP_SYN, 0xff, 0xff, 0, 0, 0, 0]);
+        tcp.extend_from_slice(options);
+        packet.extend_from_slice(&tcp);
+        let check = tcp_checksum(&packet);
+        packet[36..38].copy_from_slice(&check.to_be_bytes());
+        packet
+    }
+
+    fn tcp_checksum(packet: &[u8]) -> u16 {
+        let tcp = &packet[20..];
+        let mut pseudo = packet[12..20].to_vec();
+        pseudo.extend_from_slice(&[0, TCP]);
+        pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+        checksum(&[&pseudo, tcp])
+    }
+
+    #[test]
+    fn clamps_mss_of_syns() {
+        // MSS 1460 first, then behind a NOP at an odd offset
+        for options in &[
+            &[OPTION_MSS, 4, 0x05, 0xb4][..],
+            &[
+                OPTION_NOP, OPTION_MSS, 4, 0x05, 0xb4, OPTION_NOP, OPTION_NOP, OPTION_END,
+            ][..],
+        ] {
+            let mut packet = syn(options);
+            assert!(clamp_mss(&mut packet, 1400));
+            let at = options.iter().position(|&b| b == OPTION_MSS).unwrap() + 42;
+            assert_eq!(&packet[at..at + 2], &1400u16.to_be_bytes());
+            // A valid checksum sums to zero
+            assert_eq!(tcp_checksum(&packet), 0);
+            assert!(!clamp_mss(&mut packet, 1400));
+        }
+
+        let mut packet = syn(&[OPTION_MSS, 4, 0x05, 0xb4]);
+        packet[33] = 0x10; // ACK
+        assert!(!clamp_mss(&mut packet, 1400));
+    }
+
+    #[test]
+    fn rewrites_tcp_ends() {
+        let mut packet = syn(&[OPTION_MSS, 4, 0x05, 0xb4]);
+        let check = checksum(&[&packet[..20]]);
+        packet[10..12].copy_from_slice(&check.to_be_bytes());
+        assert_eq!(
+            tcp_ends(&packet),
+            Some((
+                "10.0.0.2:12345".parse().unwrap(),
+                "10.0.0.1:80".parse().unwrap(),
+                true
+            ))
+        );
+        let (src, dst) = (
+            "198.18.3.4:40000".parse().unwrap(),
+            "198.18.0.1:8080".parse().unwrap(),
+        );
+        assert!(rewrite_tcp(&mut packet, src, dst));
+        assert_eq!(tcp_ends(&packet), Some((src, dst, true)));
+        assert_eq!(checksum(&[&packet[..20]]), 0);
+        assert_eq!(tcp_checksum(&packet), 0);
+        assert!(!rewrite_tcp(&mut packet, "[::1]:1".parse().unwrap(), dst));
+
+        packet[33] |= TCP_ACK;
+        assert!(!tcp_ends(&packet).unwrap().2);
+    }
+
+    #[test]
+    fn replies_to_echo_requests() {
+        let mut packet = vec![
+            0x45, 0, 0, 32, 0, 0, 0x40, 0, 64, ICMP, 0, 0, 10, 0, 0, 2, 1, 1, 1, 1,
+        ];
+        let check = checksum(&[&packet]);
+        packet[10..12].copy_from_slice(&check.to_be_bytes());
+        let mut message = vec![ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0, 1, 1, 2, 3, 4];
+        let check = checksum(&[&message]);
+        message[2..4].copy_from_slice(&check.to_be_bytes());
+        packet.extend_from_slice(&message);
+
+        assert_eq!(echo_request(&packet), Some("1.1.1.1".parse().unwrap()));
+        assert!(echo_reply(&mut packet));
+        assert_eq!(&packet[12..20], &[1, 1, 1, 1, 10, 0, 0, 2]);
+        assert_eq!(packet[20], ECHO_REPLY);
+        assert_eq!(checksum(&[&packet[..20]]), 0);
+        assert_eq!(checksum(&[&packet[20..]]), 0);
+        assert!(!echo_reply(&mut packet));
+    }
+
+    #[test]
+    fn shards_flows_both_ways() {
+        let packet = syn(&[]);
+        let mut reverse = packet.clone();
+        reverse[12..16].copy_from_slice(&packet[16..20]);
+        reverse[16..20].copy_from_slice(&packet[12..16]);
+        reverse[20..22].copy_from_slice(&packet[22..24]);
+        reverse[22..24].copy_from_slice(&packet[20..22]);
+        for &shards in &[1, 2, 8] {
+            assert!(shard(&packet, shards) < shards);
+            assert_eq!(shard(&packet, shards), shard(&reverse, shards));
+        }
+        assert_eq!(shard(&packet[..10], 8), 0);
+    }
+
+    #[test]
+    fn handles_ipv6() {
+        let src = "fd00::2".parse::<Ipv6Addr>().unwrap().octets();
+        let dst = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets();
+        // Echo request behind an empty hop-by-hop options header
+        let mut message = vec![ECHO_REQUEST_V6, 0, 0, 0, 0x12, 0x34, 0, 1, 9];
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, HOP_BY_HOP, 64];
+        packet[4..6].copy_from_slice(&(8 + message.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        packet.extend_from_slice(&[ICMPV6, 0, 1, 4, 0, 0, 0, 0]);
+        let pseudo = |packet: &[u8], len: usize| {
+            let mut pseudo = packet[8..40].to_vec();
+            pseudo.extend_from_slice(&(len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, ICMPV6]);
+            pseudo
+        };
+        let check = checksum(&[&pseudo(&packet, message.len()), &message]);
+        message[2..4].copy_from_slice(&check.to_be_bytes());
+        packet.extend_from_slice(&message);
+
+        assert_eq!(echo_request(&packet), Some(IpAddr::V6(Ipv6Addr::from(dst))));
+        assert!(echo_reply(&mut packet));
+        assert_eq!(&packet[8..24], &dst);
+        assert_eq!(packet[48], ECHO_REPLY_V6);
+        assert_eq!(
+            checksum(&[&pseudo(&packet, message.len()), &packet[48..]]),
+            0
+        );
+
+        // Later fragments carry no header to look at
+        packet[40] = ICMPV6;
+        packet[6] = FRAGMENT;
+        packet[43] = 8;
+        assert!(echo_request(&packet).is_none());
+    }
+
+    #[test]
+    fn replies_to_udp() {
+        let query = [0x12, 0x34, 1, 0];
+        let mut request = vec![
+            0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, UDP, 0, 0, 10, 0, 0, 2, 8, 8, 8, 8,
+        ];
+        request[2..4].copy_from_slice(&((28 + query.len()) as u16).to_be_bytes());
+        request.extend_from_slice(&[0xc3, 0x50, 0, 53, 0, 12, 0, 0]);
+        request.extend_from_slice(&query);
+        let (src, dst, range) = udp_datagram(&request).unwrap();
+        assert_eq!(src, "10.0.0.2:50000".parse().unwrap());
+        assert_eq!(dst, "8.8.8.8:53".parse().unwrap());
+        assert_eq!(&request[range], &query);
+
+        let reply = udp_reply(&request, b"answer").unwrap();
+        assert_eq!(checksum(&[&reply[..20]]), 0);
+        let (from, to, range) = udp_datagram(&reply).unwrap();
+        assert_eq!((from, to), (dst, src));
+        assert_eq!(&reply[range], b"answer");
+        let mut pseudo = reply[12..20].to_vec();
+        pseudo.extend_from_slice(&[0, UDP, 0, 14]);
+        assert_eq!(checksum(&[&pseudo, &reply[20..]]), 0);
+
+        // Over IPv6 the source of the request becomes the destination
+        let mut request6 = vec![0x60, 0, 0, 0, 0, 12, UDP, 64];
+        request6.extend_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        request6.extend_from_slice(&"2001:db8::53".parse::<Ipv6Addr>().unwrap().octets());
+        request6.extend_from_slice(&[0xc3, 0x50, 0, 53, 0, 12, 0, 0]);
+        request6.extend_from_slice(&query);
+        let reply6 = udp_reply(&request6, b"answer").unwrap();
+        assert_eq!(&reply6[8..24], &request6[24..40]);
+        assert_eq!(&reply6[24..40], &request6[8..24]);
+        let mut pseudo = reply6[8..40].to_vec();
+        pseudo.extend_from_slice(&[0, UDP, 0, 14]);
+        assert_eq!(checksum(&[&pseudo, &reply6[40..]]), 0);
+        assert!(udp_reply(&request6[..40], b"").is_none());
+    }
+}
@@ -0,0 +1,192 @@
+//! CIDR lookups by longest prefix match
+//!
+//! A binary trie per address family, one node per prefix bit, so a lookup
+//! costs at most 32 or 128 steps however many networks are stored.
+
+use std::net::IpAddr;
+
+/// Parse `a.b.c.d/len`, a bare address is a host route
+pub fn parse_cidr(s: &str) -> Option<(IpAddr, u8)> {
+    let mut sp = s.splitn(2, '/');
+    let ip = sp.next()?.trim().parse::<IpAddr>().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let len = match sp.next() {
+        Some(len) => len.trim().parse::<u8>().ok().filter(|l| *l <= max)?,
+        None => max,
+    };
+    Some((ip, len))
+}
+
+struct Node<T> {
+    children: [Option<u32>; 2],
+    value: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            children: [None, None],
+            value: None,
+        }
+    }
+}
+
+/// Networks mapped to values
+pub struct IpTrie<T> {
+    /// Roots of the IPv4 and IPv6 tries come first
+    nodes: Vec<Node<T>>,
+    len: usize,
+}
+
+const V4_ROOT: usize = 0;
+const V6_ROOT: usize = 1;
+
+/// Root and address bits, most significant first
+fn key(ip: IpAddr) -> (usize, u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => (V4_ROOT, u128::from(u32::from(ip)) << 96, 32),
+        IpAddr::V6(ip) => (V6_ROOT, u128::from(ip), 128),
+    }
+}
+
+fn bit(bits: u128, i: u8) -> usize {
+    ((bits >> (127 - i)) & 1) as usize
+}
+
+impl<T> Default for IpTrie<T> {
+    fn default() -> IpTrie<T> {
+        IpTrie::new()
+    }
+}
+
+impl<T> IpTrie<T> {
+    pub fn new() -> IpTrie<T> {
+        IpTrie {
+            nodes: vec![Node::new(), Node::new()],
+            len: 0,
+        }
+    }
+
+    /// Networks stored
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Map the network `ip/prefix` to `value`, returning the value it replaced
+    ///
+    /// Host bits of `ip` are ignored, prefixes longer than the address are
+    /// cut to its length.
+    pub fn insert(&mut self, ip: IpAddr, prefix: u8, value: T) -> Option<T> {
+        let (mut node, bits, max) = key(ip);
+        for i in 0..prefix.min(max) {
+            let b = bit(bits, i);
+            node = match self.nodes[node].children[b] {
+                Some(child) => child as usize,
+                None => {
+                    self.nodes.push(Node::new());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[b] = Some(child as u32);
+                    child
+                }
+            };
+        }
+        let old = self.nodes[node].value.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Value of the most specific network containing `ip`, with its prefix length
+    pub fn longest_match(&self, ip: IpAddr) -> Option<(u8, &T)> {
+        let (mut node, bits, max) = key(ip);
+        let mut best = self.nodes[node].value.as_ref().map(|v| (0, v));
+        for i in 0..max {
+            node = match self.nodes[node].children[bit(bits, i)] {
+                Some(child) => child as usize,
+                None => break,
+            };
+            if let Some(ref v) = self.nodes[node].value {
+                best = Some((i + 1, v));
+            }
+        }
+        best
+    }
+
+    /// Value of the most specific network containing `ip`
+    pub fn get(&self, ip: IpAddr) -> Option<&T> {
+        self.longest_match(ip).map(|(_, v)| v)
+    }
+
+    /// Whether any network contains `ip`
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.longest_match(ip).is_some()
+    }
+}
+
+/// Set of networks
+pub type IpSet = IpTrie<()>;
+
+impl IpTrie<()> {
+    /// Build a set from `a.b.c.d/len` strings, handing invalid ones to `invalid`
+    pub fn from_cidrs<'a, I, F>(cidrs: I, mut invalid: F) -> IpSet
+    where
+        I: IntoIterator<Item = &'a String>,
+        F: FnMut(&str),
+    {
+        let mut set = IpTrie::new();
+        for s in cidrs {
+            match parse_cidr(s) {
+                Some((ip, len)) => {
+                    set.insert(ip, len, ());
+                }
+                None => invalid(s),
+            }
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut trie = IpTrie::new();
+        trie.insert(ip("10.0.0.0"), 8, "wide");
+        trie.insert(ip("10.1.0.0"), 16, "narrow");
+        trie.insert(ip("10.1.2.3"), 32, "host");
+        trie.insert(ip("fd00::"), 8, "ula");
+
+        assert_eq!(trie.longest_match(ip("10.9.9.9")), Some((8, &"wide")));
+        assert_eq!(trie.get(ip("10.1.9.9")), Some(&"narrow"));
+        assert_eq!(trie.get(ip("10.1.2.3")), Some(&"host"));
+        assert_eq!(trie.get(ip("11.0.0.1")), None);
+        assert_eq!(trie.get(ip("fd12::1")), Some(&"ula"));
+        // Families don't mix, 10.0.0.0/8 is not ::/8
+        assert_eq!(trie.get(ip("a00::1")), None);
+
+        assert_eq!(trie.insert(ip("10.255.0.0"), 8, "again"), Some("wide"));
+        assert_eq!(trie.len(), 4);
+    }
+
+    #[test]
+    fn default_route_and_parse() {
+        let set = IpTrie::from_cidrs(&["0.0.0.0/0".to_owned(), "bogus".to_owned()], |s| {
+            assert_eq!(s, "bogus")
+        });
+        assert!(set.contains(ip("8.8.8.8")));
+        assert!(!set.contains(ip("::1")));
+        assert_eq!(parse_cidr("192.168.1.1"), Some((ip("192.168.1.1"), 32)));
+        assert_eq!(parse_cidr("::/129"), None);
+    }
+}
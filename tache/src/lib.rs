@@ -1,24 +1,45 @@
 #![crate_type = "lib"]
 #![crate_name = "tache"]
 #![recursion_limit = "128"]
-#![feature(async_await)]
 
 /// ShadowSocks version
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub use self::{
     config::{Config, Mode},
-    engine::run,
+    engine::{run, run_profile, Engine, EngineBuilder, EngineError},
+    event::Event,
+    utils::{Address, ListenAddress, ListenAddresses},
 };
 
 // relay::{dns::run as run_dns},
 
+pub mod api;
+pub mod buffer;
 pub mod config;
 mod context;
+pub mod convert;
+pub mod crypto;
+pub mod dns;
+pub mod domain_trie;
 pub(crate) mod dns_resolver;
 pub mod engine;
+pub mod event;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod geodb;
+pub mod geoip;
+mod http_client;
 pub mod inbounds;
+pub mod ip_trie;
+mod listener;
 mod local;
 pub mod outbound;
+pub mod profile;
 pub mod protocol;
+pub mod provider;
+pub mod rt;
+mod socket_owner;
+pub mod state;
+pub(crate) mod tls;
 mod utils;
@@ -0,0 +1,348 @@
+//! Listeners on TCP addresses or Unix domain sockets
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+use futures::{
+    future,
+    stream::{self, Stream, StreamExt},
+};
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+#[cfg(unix)]
+use crate::rt::{UnixListener, UnixStream};
+use crate::{
+    outbound::dialer::set_keepalive,
+    protocol::proxy_protocol,
+    rt::{TcpListener, TcpStream},
+    socket_owner,
+    tls::{self, ServerStream},
+    utils::ListenAddress,
+};
+
+/// An accepted connection
+pub enum InboundStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// TLS terminated by the inbound, over a TCP or Unix connection
+    Tls(Box<ServerStream<InboundStream>>),
+    /// Forwarded by a load balancer that sent the client's addresses in a
+    /// PROXY protocol header
+    Proxied {
+        stream: Box<InboundStream>,
+        source: SocketAddr,
+        destination: SocketAddr,
+    },
+}
+
+impl InboundStream {
+    /// Address of the client, `None` on a Unix socket
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            InboundStream::Tcp(ref s) => s.peer_addr().ok(),
+            #[cfg(unix)]
+            InboundStream::Unix(..) => None,
+            InboundStream::Tls(ref s) => tls::inner(s).peer_addr(),
+            InboundStream::Proxied { source, .. } => Some(source),
+        }
+    }
+
+    /// Address the client connected to, `None` on a Unix socket
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            InboundStream::Tcp(ref s) => s.local_addr().ok(),
+            #[cfg(unix)]
+            InboundStream::Unix(..) => None,
+            InboundStream::Tls(ref s) => tls::inner(s).local_addr(),
+            InboundStream::Proxied { destination, .. } => Some(destination),
+        }
+    }
+
+    /// User running the client, `None` when it is not on this host
+    pub fn owner_uid(&self) -> Option<u32> {
+        match *self {
+            InboundStream::Tcp(ref s) => s.peer_addr().ok().and_then(socket_owner::tcp_uid),
+            #[cfg(unix)]
+            InboundStream::Unix(ref s) => socket_owner::unix_peer_uid(s.as_raw_fd()),
+            InboundStream::Tls(ref s) => tls::inner(s).owner_uid(),
+            // The load balancer's host, not the client's
+            InboundStream::Proxied { .. } => None,
+        }
+    }
+
+    /// The TCP connection underneath, `None` on a Unix socket
+    fn tcp_mut(&mut self) -> Option<&mut TcpStream> {
+        match *self {
+            InboundStream::Tcp(ref mut s) => Some(s),
+            #[cfg(unix)]
+            InboundStream::Unix(..) => None,
+            InboundStream::Tls(ref mut s) => tls::inner_mut(s).tcp_mut(),
+            InboundStream::Proxied { ref mut stream, .. } => stream.tcp_mut(),
+        }
+    }
+
+    /// Probe the client with aggressive TCP keepalives, no-op on Unix sockets
+    pub fn set_keepalive(&mut self) -> io::Result<()> {
+        match self.tcp_mut() {
+            Some(s) => set_keepalive(s),
+            None => Ok(()),
+        }
+    }
+
+    /// Reset the connection instead of closing it once dropped, so the client
+    /// sees at once that it has to reconnect
+    pub fn reset_on_close(&mut self) -> io::Result<()> {
+        match self.tcp_mut() {
+            Some(s) => linger_zero(s),
+            None => Ok(()),
+        }
+    }
+
+    /// Resolves once the client hangs up, without consuming what it sent
+    ///
+    /// Stays pending once the client sent data, and on Unix sockets which
+    /// can't be peeked.
+    pub async fn closed(&mut self) {
+        if let Some(s) = self.tcp_mut() {
+            let mut buf = [0; 1];
+            match s.peek(&mut buf).await {
+                Ok(n) if n > 0 => {}
+                _ => return,
+            }
+        }
+        future::pending::<()>().await
+    }
+
+    /// Take the client addresses from the PROXY protocol header the
+    /// connection starts with, a header without addresses keeps the
+    /// connection as it is
+    pub async fn accept_proxy_protocol(mut self) -> io::Result<InboundStream> {
+        match proxy_protocol::read_header(&mut self).await? {
+            Some((source, destination)) => Ok(InboundStream::Proxied {
+                stream: Box::new(self),
+                source,
+                destination,
+            }),
+            None => Ok(self),
+        }
+    }
+
+    /// Whether the client runs on this host
+    pub fn is_local(&self) -> bool {
+        match *self {
+            InboundStream::Tcp(ref s) => s.peer_addr().is_ok_and(|a| is_loopback(a.ip())),
+            #[cfg(unix)]
+            InboundStream::Unix(..) => true,
+            InboundStream::Tls(ref s) => tls::inner(s).is_local(),
+            InboundStream::Proxied { source, .. } => is_loopback(source.ip()),
+        }
+    }
+}
+
+impl AsyncRead for InboundStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            InboundStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            InboundStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            InboundStream::Tls(s) => Pin::new(&mut **s).poll_read(cx, buf),
+            InboundStream::Proxied { stream, .. } => Pin::new(&mut **stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for InboundStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            InboundStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            InboundStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            InboundStream::Tls(s) => Pin::new(&mut **s).poll_write(cx, buf),
+            InboundStream::Proxied { stream, .. } => Pin::new(&mut **stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            InboundStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            InboundStream::Unix(s) => Pin::new(s).poll_flush(cx),
+            InboundStream::Tls(s) => Pin::new(&mut **s).poll_flush(cx),
+            InboundStream::Proxied { stream, .. } => Pin::new(&mut **stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            InboundStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            InboundStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            InboundStream::Tls(s) => Pin::new(&mut **s).poll_shutdown(cx),
+            InboundStream::Proxied { stream, .. } => Pin::new(&mut **stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Closing with a zero linger time sends RST right away
+#[cfg(unix)]
+fn linger_zero(stream: &TcpStream) -> io::Result<()> {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn linger_zero(_stream: &TcpStream) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "resetting connections is not supported on this platform",
+    ))
+}
+
+/// Destination of a connection redirected here by netfilter, the `REDIRECT`
+/// target of iptables or nftables
+#[cfg(target_os = "linux")]
+pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let (level, name) = match stream.local_addr()? {
+        SocketAddr::V4(..) => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+        SocketAddr::V6(..) => (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST),
+    };
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { *(&addr as *const _ as *const libc::sockaddr_in) };
+            let ip = std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::new(ip.into(), u16::from_be(addr.sin_port)))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { *(&addr as *const _ as *const libc::sockaddr_in6) };
+            let ip = std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::new(ip.into(), u16::from_be(addr.sin6_port)))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown original destination family",
+        )),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "redirected connections are only supported on Linux",
+    ))
+}
+
+/// IPv4-mapped loopback addresses count as well
+pub fn is_loopback(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback(),
+        IpAddr::V6(v6) => v6.is_loopback() || v6.to_ipv4().is_some_and(|v4| v4.is_loopback()),
+    }
+}
+
+pub type Incoming = Pin<Box<dyn Stream<Item = io::Result<InboundStream>> + Send>>;
+
+/// Listen on every address of `listens`, accepting from all of them as one
+pub async fn bind_all(listens: &[ListenAddress]) -> io::Result<Incoming> {
+    let mut incomings = Vec::with_capacity(listens.len());
+    for listen in listens {
+        incomings.push(bind(listen).await?);
+    }
+    Ok(Box::pin(stream::select_all(incomings)))
+}
+
+/// Listen on every address `listen` resolves to
+pub async fn bind(listen: &ListenAddress) -> io::Result<Incoming> {
+    match *listen {
+        ListenAddress::Tcp(ref addr) => {
+            let mut incomings = Vec::new();
+            for addr in addr.to_socket_addrs()? {
+                let listener = TcpListener::bind(&addr).await?;
+                info!("Listening on: {}", addr);
+                incomings.push(listener.map(|s| s.map(InboundStream::Tcp)));
+            }
+            if incomings.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} resolved to no address", addr),
+                ));
+            }
+            Ok(Box::pin(stream::select_all(incomings)))
+        }
+        #[cfg(unix)]
+        ListenAddress::Unix(ref path) => {
+            remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path)?;
+            info!("Listening on: {}", listen);
+            Ok(Box::pin(listener.map(|s| s.map(InboundStream::Unix))))
+        }
+        #[cfg(not(unix))]
+        ListenAddress::Unix(..) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "unix sockets are not supported on this platform",
+        )),
+    }
+}
+
+/// A socket file left by a previous run would make bind fail
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(..) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,246 @@
+//! Circuit breaker failing dials through a dead proxy fast
+//!
+//! After `threshold` dials in a row failed the circuit opens: for `cooldown`
+//! dials fail at once instead of each new connection waiting out the
+//! connect timeout, and groups see the proxy as down and pick other
+//! members. Then one dial is let through as a trial, a success closes the
+//! circuit and a failure opens it for another cooldown.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use log::{info, warn};
+use serde::Serialize;
+
+use super::{BoxStream, Datagrams, Dialer, Outbound};
+use crate::{config::CircuitBreakerConfig, utils::Address};
+
+/// Failed dials in a row opening the circuit
+pub const DEFAULT_THRESHOLD: u32 = 5;
+/// Seconds the circuit stays open
+pub const DEFAULT_COOLDOWN: u64 = 30;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    Closed,
+    /// Failing fast
+    Open,
+    /// Cooled down, the next dial is a trial
+    HalfOpen,
+}
+
+/// State of a circuit as shown by the API
+#[derive(Serialize, Clone, Debug)]
+pub struct Circuit {
+    pub state: State,
+    /// Failed dials in a row
+    pub failures: u32,
+    /// Seconds until a trial dial is let through, while open
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_in: Option<u64>,
+}
+
+#[derive(Default)]
+struct Inner {
+    failures: u32,
+    opened: Option<Instant>,
+    /// Start of the trial dial in flight, one left unfinished for a whole
+    /// cooldown was dropped
+    trial: Option<Instant>,
+}
+
+/// `outbound` behind a circuit breaker
+pub struct Breaker {
+    outbound: Arc<dyn Outbound>,
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl Breaker {
+    /// `None` when the config turns breakers off
+    pub fn wrap(
+        outbound: Arc<dyn Outbound>,
+        config: Option<&CircuitBreakerConfig>,
+    ) -> Option<Breaker> {
+        let threshold = config
+            .and_then(|c| c.threshold)
+            .unwrap_or(DEFAULT_THRESHOLD);
+        if threshold == 0 {
+            return None;
+        }
+        let cooldown = config.and_then(|c| c.cooldown).unwrap_or(DEFAULT_COOLDOWN);
+        Some(Breaker {
+            outbound,
+            threshold,
+            cooldown: Duration::from_secs(cooldown),
+            inner: Mutex::new(Inner::default()),
+        })
+    }
+
+    /// Whether a dial may go out and if it is the trial, the time left
+    /// until one may otherwise
+    fn permit(&self, now: Instant) -> Result<bool, Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        let opened = match inner.opened {
+            Some(opened) => opened,
+            None => return Ok(false),
+        };
+        let waited = now.duration_since(opened);
+        if waited < self.cooldown {
+            return Err(self.cooldown - waited);
+        }
+        if let Some(trial) = inner.trial {
+            let running = now.duration_since(trial);
+            if running < self.cooldown {
+                return Err(self.cooldown - running);
+            }
+        }
+        inner.trial = Some(now);
+        Ok(true)
+    }
+
+    fn record(&self, ok: bool, trial: bool, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        if ok {
+            if inner.opened.is_some() {
+                info!("Circuit of {} closed", self.outbound.name());
+            }
+            *inner = Inner::default();
+            return;
+        }
+        inner.failures = inner.failures.saturating_add(1);
+        if trial {
+            inner.opened = Some(now);
+            inner.trial = None;
+        } else if inner.opened.is_none() && inner.failures >= self.threshold {
+            warn!(
+                "Circuit of {} opened after {} failed dials, failing fast for {}s",
+                self.outbound.name(),
+                inner.failures,
+                self.cooldown.as_secs()
+            );
+            inner.opened = Some(now);
+        }
+    }
+
+    fn state(&self, now: Instant) -> Circuit {
+        let inner = self.inner.lock().unwrap();
+        let (state, retry_in) = match inner.opened {
+            None => (State::Closed, None),
+            Some(opened) => {
+                let waited = now.duration_since(opened);
+                if waited < self.cooldown {
+                    (State::Open, Some((self.cooldown - waited).as_secs()))
+                } else {
+                    (State::HalfOpen, None)
+                }
+            }
+        };
+        Circuit {
+            state,
+            failures: inner.failures,
+            retry_in,
+        }
+    }
+}
+
+impl Outbound for Breaker {
+    fn name(&self) -> String {
+        self.outbound.name()
+    }
+
+    fn udp(&self) -> bool {
+        self.outbound.udp()
+    }
+
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let trial = self.permit(Instant::now()).map_err(|left| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!(
+                        "circuit of {} is open, retrying in {}s",
+                        self.name(),
+                        left.as_secs()
+                    ),
+                )
+            })?;
+            let result = self.outbound.dial(target, dialer).await;
+            self.record(result.is_ok(), trial, Instant::now());
+            result
+        })
+    }
+
+    fn bind(&self) -> BoxFuture<'_, io::Result<Datagrams>> {
+        if self.state(Instant::now()).state == State::Open {
+            let name = self.name();
+            return Box::pin(async move {
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("circuit of {} is open", name),
+                ))
+            });
+        }
+        self.outbound.bind()
+    }
+
+    fn alive(&self) -> bool {
+        self.state(Instant::now()).state != State::Open && self.outbound.alive()
+    }
+
+    fn circuit(&self) -> Option<Circuit> {
+        Some(self.state(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::outbound::Direct;
+
+    #[test]
+    fn opens_and_closes() {
+        let config = CircuitBreakerConfig {
+            threshold: Some(2),
+            cooldown: Some(10),
+        };
+        let breaker = Breaker::wrap(Arc::new(Direct::new("hk")), Some(&config)).unwrap();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(breaker.permit(at(0)), Ok(false));
+        breaker.record(false, false, at(0));
+        assert_eq!(breaker.state(at(0)).state, State::Closed);
+        breaker.record(false, false, at(1));
+        assert_eq!(breaker.state(at(1)).state, State::Open);
+        assert_eq!(breaker.permit(at(5)), Err(Duration::from_secs(6)));
+
+        // One trial once cooled down, failing it opens again
+        assert_eq!(breaker.state(at(11)).state, State::HalfOpen);
+        assert_eq!(breaker.permit(at(11)), Ok(true));
+        assert!(breaker.permit(at(12)).is_err());
+        breaker.record(false, true, at(13));
+        assert_eq!(breaker.state(at(13)).retry_in, Some(10));
+
+        assert_eq!(breaker.permit(at(23)), Ok(true));
+        breaker.record(true, true, at(24));
+        let circuit = breaker.state(at(24));
+        assert_eq!((circuit.state, circuit.failures), (State::Closed, 0));
+
+        let off = CircuitBreakerConfig {
+            threshold: Some(0),
+            cooldown: None,
+        };
+        assert!(Breaker::wrap(Arc::new(Direct::new("hk")), Some(&off)).is_none());
+    }
+}
@@ -0,0 +1,287 @@
+use std::{io, sync::Arc};
+
+use futures::future::BoxFuture;
+use log::warn;
+
+use super::{direct, BoxStream, Outbound};
+use crate::{rt::TcpStream, utils::Address};
+
+/// Opens the connections an outbound runs its protocol over
+pub trait Dialer: Send + Sync {
+    fn connect<'a>(&'a self, target: &'a Address) -> BoxFuture<'a, io::Result<BoxStream>>;
+}
+
+/// Plain TCP connections
+pub struct TcpDialer;
+
+impl Dialer for TcpDialer {
+    fn connect<'a>(&'a self, target: &'a Address) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move { Ok(Box::new(direct::connect(target).await?) as BoxStream) })
+    }
+}
+
+/// Plain TCP connections with their packets marked for QoS or limited in
+/// size, or probed by keepalives
+pub struct MarkedDialer {
+    /// Differentiated services code point, 0 to 63
+    pub dscp: Option<u8>,
+    /// Largest TCP segment sent, for paths through links with a reduced MTU
+    pub mss: Option<u16>,
+    /// Probe the connection with `set_keepalive`
+    pub keepalive: bool,
+}
+
+impl Dialer for MarkedDialer {
+    fn connect<'a>(&'a self, target: &'a Address) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let stream = direct::connect(target).await?;
+            // Unmarked packets still get through, only their priority is lost
+            if let Some(dscp) = self.dscp {
+                if let Err(e) = set_dscp(&stream, dscp) {
+                    warn!("Failed to set DSCP {} towards {}, err: {}", dscp, target, e);
+                }
+            }
+            // Without it full sized segments may vanish on the reduced link
+            if let Some(mss) = self.mss {
+                if let Err(e) = set_mss(&stream, mss) {
+                    warn!("Failed to set MSS {} towards {}, err: {}", mss, target, e);
+                }
+            }
+            if self.keepalive {
+                if let Err(e) = set_keepalive(&stream) {
+                    warn!("Failed to set keepalive towards {}, err: {}", target, e);
+                }
+            }
+            Ok(Box::new(stream) as BoxStream)
+        })
+    }
+}
+
+/// Mark the packets `stream` sends from now on, the handshake is already
+/// done by then
+#[cfg(unix)]
+fn set_dscp(stream: &TcpStream, dscp: u8) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // DSCP is the upper six bits of the TOS and traffic class bytes
+    let tos = libc::c_int::from(dscp << 2);
+    let (level, name) = if stream.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    };
+    setsockopt(stream.as_raw_fd(), level, name, tos)
+}
+
+/// Limit the segments `stream` sends, the MSS its SYN announced stays
+#[cfg(unix)]
+fn set_mss(stream: &TcpStream, mss: u16) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    setsockopt(
+        stream.as_raw_fd(),
+        libc::IPPROTO_TCP,
+        libc::TCP_MAXSEG,
+        libc::c_int::from(mss),
+    )
+}
+
+/// Seconds idle before the first keepalive probe
+#[cfg(unix)]
+const KEEPALIVE_IDLE: libc::c_int = 10;
+/// Seconds between unanswered probes
+#[cfg(unix)]
+const KEEPALIVE_INTERVAL: libc::c_int = 5;
+/// Unanswered probes until the connection is dead
+#[cfg(unix)]
+const KEEPALIVE_PROBES: libc::c_int = 3;
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPALIVE;
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPIDLE;
+
+/// Probe `stream` once idle, so a peer gone without a word is noticed within
+/// 25 seconds and reads and writes fail with `TimedOut`
+#[cfg(unix)]
+pub fn set_keepalive(stream: &TcpStream) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    setsockopt(fd, libc::IPPROTO_TCP, TCP_KEEPIDLE, KEEPALIVE_IDLE)?;
+    setsockopt(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPINTVL,
+        KEEPALIVE_INTERVAL,
+    )?;
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, KEEPALIVE_PROBES)
+}
+
+#[cfg(unix)]
+fn setsockopt(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_dscp(_stream: &TcpStream, _dscp: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "DSCP marking is not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn set_keepalive(_stream: &TcpStream) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "keepalive tuning is not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+fn set_mss(_stream: &TcpStream, _mss: u16) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "MSS clamping is not supported on this platform",
+    ))
+}
+
+/// Connections through `outbound`, which itself dials through `parent`
+pub struct Via<'p> {
+    pub outbound: &'p dyn Outbound,
+    pub parent: &'p dyn Dialer,
+}
+
+impl<'p> Dialer for Via<'p> {
+    fn connect<'a>(&'a self, target: &'a Address) -> BoxFuture<'a, io::Result<BoxStream>> {
+        self.outbound.dial(target, self.parent)
+    }
+}
+
+/// `outbound` reaching its server through `parent`, from `dialer-proxy`
+pub struct Chained {
+    outbound: Arc<dyn Outbound>,
+    parent: Arc<dyn Outbound>,
+}
+
+impl Chained {
+    pub fn new(outbound: Arc<dyn Outbound>, parent: Arc<dyn Outbound>) -> Chained {
+        Chained { outbound, parent }
+    }
+}
+
+impl Outbound for Chained {
+    fn name(&self) -> String {
+        self.outbound.name()
+    }
+
+    /// Datagrams can't follow a stream chain
+    fn udp(&self) -> bool {
+        false
+    }
+
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let via = Via {
+                outbound: &*self.parent,
+                parent: dialer,
+            };
+            self.outbound.dial(target, &via).await
+        })
+    }
+
+    fn alive(&self) -> bool {
+        self.outbound.alive() && self.parent.alive()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::os::unix::io::AsRawFd;
+
+    use super::*;
+    use crate::rt::{Runtime, TcpListener};
+
+    fn getsockopt(stream: &TcpStream, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        value
+    }
+
+    #[test]
+    fn marks_packets_with_dscp() {
+        Runtime::new().unwrap().block_on(async {
+            for local in &["127.0.0.1:0", "[::1]:0"] {
+                let listener = match TcpListener::bind(local).await {
+                    Ok(listener) => listener,
+                    // No IPv6 on this host
+                    Err(..) => continue,
+                };
+                let target = Address::SocketAddr(listener.local_addr().unwrap());
+                let stream = direct::connect(&target).await.unwrap();
+                let (level, name) = if stream.local_addr().unwrap().is_ipv4() {
+                    (libc::IPPROTO_IP, libc::IP_TOS)
+                } else {
+                    (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+                };
+                set_dscp(&stream, 46).unwrap();
+                // Expedited forwarding, in the upper six bits
+                assert_eq!(getsockopt(&stream, level, name), 46 << 2);
+            }
+        });
+    }
+
+    #[test]
+    fn probes_idle_connections() {
+        Runtime::new().unwrap().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target = Address::SocketAddr(listener.local_addr().unwrap());
+            let stream = direct::connect(&target).await.unwrap();
+            assert_eq!(getsockopt(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+            set_keepalive(&stream).unwrap();
+            assert_ne!(getsockopt(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+            assert_eq!(
+                getsockopt(&stream, libc::IPPROTO_TCP, TCP_KEEPIDLE),
+                KEEPALIVE_IDLE
+            );
+            assert_eq!(
+                getsockopt(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPCNT),
+                KEEPALIVE_PROBES
+            );
+        });
+    }
+}
@@ -0,0 +1,55 @@
+use std::io;
+
+use futures::future::BoxFuture;
+
+use super::{BoxStream, Dialer, Outbound};
+use crate::{rt::TcpStream, utils::Address};
+
+/// Connect to the destination without any proxy
+pub struct Direct {
+    name: String,
+}
+
+impl Direct {
+    pub fn new(name: &str) -> Direct {
+        Direct {
+            name: name.to_owned(),
+        }
+    }
+}
+
+/// Connect to the first reachable address of `target`
+pub async fn connect(target: &Address) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in target.lookup().await? {
+        match TcpStream::connect(&addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "resolved to empty address")))
+}
+
+impl Outbound for Direct {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn udp(&self) -> bool {
+        true
+    }
+
+    /// Chained behind a proxy this is the tunnel of that proxy
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        dialer.connect(target)
+    }
+
+    fn alive(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,159 @@
+//! Address and country traffic leaves a proxy from
+//!
+//! Found by fetching an echo endpoint through the proxy. JSON answers of the
+//! common services (ifconfig.co, ipinfo.io, ip-api.com) and plain text
+//! addresses are understood; without a country in the answer it comes from
+//! the GeoIP database. Results are kept for `ttl`.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use log::debug;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::Outbound;
+use crate::{
+    config::ExitCheckConfig, geoip::GeoIP, http_client, provider::unix_now, rt::delay_for,
+};
+
+pub const DEFAULT_URL: &str = "https://ifconfig.co/json";
+/// Seconds a result is kept
+pub const DEFAULT_TTL: u64 = 3600;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Exit {
+    pub ip: Option<IpAddr>,
+    /// ISO 3166 code
+    pub country: Option<String>,
+    /// Unix seconds of the check
+    pub checked_at: u64,
+    pub error: Option<String>,
+}
+
+pub struct ExitChecker {
+    url: String,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, Exit)>>,
+}
+
+impl ExitChecker {
+    pub fn new(config: Option<&ExitCheckConfig>) -> ExitChecker {
+        ExitChecker {
+            url: config
+                .and_then(|c| c.url.clone())
+                .unwrap_or_else(|| DEFAULT_URL.to_owned()),
+            ttl: Duration::from_secs(config.and_then(|c| c.ttl).unwrap_or(DEFAULT_TTL)),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Exit of `name` checked within `ttl`, checked now otherwise
+    pub async fn exit(&self, name: &str, outbound: &dyn Outbound, geoip: Option<&GeoIP>) -> Exit {
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(name)
+            .filter(|(at, _)| at.elapsed() < self.ttl)
+            .map(|(_, exit)| exit.clone());
+        if let Some(exit) = cached {
+            return exit;
+        }
+        self.check(name, outbound, geoip).await
+    }
+
+    /// Check `outbound` now and keep the result
+    pub async fn check(&self, name: &str, outbound: &dyn Outbound, geoip: Option<&GeoIP>) -> Exit {
+        let mut exit = Exit {
+            ip: None,
+            country: None,
+            checked_at: unix_now(),
+            error: None,
+        };
+        match http_client::get_via(&self.url, CHECK_TIMEOUT, Some(outbound)).await {
+            Ok(ref resp) if resp.status == 200 => match parse(&resp.body) {
+                Some((ip, country)) => {
+                    exit.ip = Some(ip);
+                    exit.country = country.or_else(|| geoip?.country(ip));
+                }
+                None => exit.error = Some("unrecognized answer".to_owned()),
+            },
+            Ok(resp) => exit.error = Some(format!("status {}", resp.status)),
+            Err(e) => exit.error = Some(e.to_string()),
+        }
+        debug!("Exit of {}: {:?}", name, exit);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), (Instant::now(), exit.clone()));
+        exit
+    }
+
+    /// Check `outbounds` now and every `ttl`
+    pub async fn run(
+        checker: Arc<ExitChecker>,
+        outbounds: Vec<(String, Arc<dyn Outbound>)>,
+        geoip: Option<Arc<GeoIP>>,
+    ) {
+        let geoip = geoip.as_deref();
+        loop {
+            join_all(
+                outbounds
+                    .iter()
+                    .map(|(name, outbound)| checker.check(name, &**outbound, geoip)),
+            )
+            .await;
+            delay_for(checker.ttl).await;
+        }
+    }
+}
+
+/// Address and country in an echo answer
+fn parse(body: &[u8]) -> Option<(IpAddr, Option<String>)> {
+    let text = String::from_utf8_lossy(body);
+    if let Ok(ip) = text.trim().parse() {
+        return Some((ip, None));
+    }
+    let json: Value = serde_json::from_str(&text).ok()?;
+    let ip = ["ip", "query"]
+        .iter()
+        .find_map(|key| json.get(key)?.as_str()?.parse().ok())?;
+    let country = ["country_iso", "countryCode", "country_code", "country"]
+        .iter()
+        .filter_map(|key| json.get(key)?.as_str())
+        .find(|code| code.len() == 2)
+        .map(str::to_ascii_uppercase);
+    Some((ip, country))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_echo_answers() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(parse(b"203.0.113.7\n"), Some((ip, None)));
+        assert_eq!(
+            parse(br#"{"ip":"203.0.113.7","country":"Japan","country_iso":"JP"}"#),
+            Some((ip, Some("JP".to_owned())))
+        );
+        assert_eq!(
+            parse(br#"{"status":"success","countryCode":"DE","query":"203.0.113.7"}"#),
+            Some((ip, Some("DE".to_owned())))
+        );
+        assert_eq!(
+            parse(br#"{"ip":"203.0.113.7","country":"nl"}"#),
+            Some((ip, Some("NL".to_owned())))
+        );
+        assert_eq!(parse(b"<html>"), None);
+    }
+}
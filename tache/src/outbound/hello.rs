@@ -0,0 +1,375 @@
+//! ClientHellos shaped like the ones of common browsers
+//!
+//! Middleboxes flag TLS clients by the JA3/JA4 of their ClientHello, and a
+//! proxy client offering what no browser does stands out. A profile lists
+//! what a browser offers in its order; `GREASE` entries become random
+//! reserved values per connection and Chrome's extension order is shuffled
+//! like Chrome does since version 110.
+//!
+//! Hand-built hellos, like the one of shadow-tls, follow a profile fully.
+//! rustls builds its own, so only the cipher suite order follows there.
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{config::ClientFingerprint, engine::fingerprint::is_grease};
+
+/// Placeholder for a random GREASE value
+const GREASE: u16 = 0x0a0a;
+
+const SERVER_NAME: u16 = 0x0000;
+const STATUS_REQUEST: u16 = 0x0005;
+const SUPPORTED_GROUPS: u16 = 0x000a;
+const EC_POINT_FORMATS: u16 = 0x000b;
+const SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const ALPN: u16 = 0x0010;
+const PADDING: u16 = 0x0015;
+const COMPRESS_CERTIFICATE: u16 = 0x001b;
+const RECORD_SIZE_LIMIT: u16 = 0x001c;
+const DELEGATED_CREDENTIALS: u16 = 0x0022;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+const PSK_KEY_EXCHANGE_MODES: u16 = 0x002d;
+const KEY_SHARE: u16 = 0x0033;
+const APPLICATION_SETTINGS: u16 = 0x4469;
+const RENEGOTIATION_INFO: u16 = 0xff01;
+
+const X25519: u16 = 0x001d;
+
+/// What a client offers, in the order it does
+pub struct Profile {
+    pub ciphers: &'static [u16],
+    /// Extension types, those without a body known here are sent empty
+    pub extensions: &'static [u16],
+    pub groups: &'static [u16],
+    pub signature_algorithms: &'static [u16],
+    pub versions: &'static [u16],
+    pub alpn: &'static [&'static str],
+    /// Shuffle the extensions between the GREASE ones
+    pub shuffle: bool,
+}
+
+/// What shadow-tls sent before profiles, a lean TLS 1.3 client
+pub static DEFAULT: Profile = Profile {
+    ciphers: &[
+        0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8,
+    ],
+    extensions: &[
+        SERVER_NAME,
+        0x0017,
+        RENEGOTIATION_INFO,
+        SUPPORTED_GROUPS,
+        EC_POINT_FORMATS,
+        SIGNATURE_ALGORITHMS,
+        ALPN,
+        SUPPORTED_VERSIONS,
+        PSK_KEY_EXCHANGE_MODES,
+        KEY_SHARE,
+    ],
+    groups: &[X25519, 0x0017, 0x0018],
+    signature_algorithms: &[
+        0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+    ],
+    versions: &[0x0304, 0x0303],
+    alpn: &["h2", "http/1.1"],
+    shuffle: false,
+};
+
+pub static CHROME: Profile = Profile {
+    ciphers: &[
+        GREASE, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+        0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+    ],
+    extensions: &[
+        GREASE,
+        SERVER_NAME,
+        0x0017,
+        RENEGOTIATION_INFO,
+        SUPPORTED_GROUPS,
+        EC_POINT_FORMATS,
+        0x0023,
+        ALPN,
+        STATUS_REQUEST,
+        SIGNATURE_ALGORITHMS,
+        0x0012,
+        KEY_SHARE,
+        PSK_KEY_EXCHANGE_MODES,
+        SUPPORTED_VERSIONS,
+        COMPRESS_CERTIFICATE,
+        APPLICATION_SETTINGS,
+        GREASE,
+        PADDING,
+    ],
+    groups: &[GREASE, X25519, 0x0017, 0x0018],
+    signature_algorithms: &[
+        0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+    ],
+    versions: &[GREASE, 0x0304, 0x0303],
+    alpn: &["h2", "http/1.1"],
+    shuffle: true,
+};
+
+pub static FIREFOX: Profile = Profile {
+    ciphers: &[
+        0x1301, 0x1303, 0x1302, 0xc02b, 0xc02f, 0xcca9, 0xcca8, 0xc02c, 0xc030, 0xc00a, 0xc009,
+        0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+    ],
+    extensions: &[
+        SERVER_NAME,
+        0x0017,
+        RENEGOTIATION_INFO,
+        SUPPORTED_GROUPS,
+        EC_POINT_FORMATS,
+        0x0023,
+        ALPN,
+        STATUS_REQUEST,
+        DELEGATED_CREDENTIALS,
+        KEY_SHARE,
+        SUPPORTED_VERSIONS,
+        SIGNATURE_ALGORITHMS,
+        PSK_KEY_EXCHANGE_MODES,
+        RECORD_SIZE_LIMIT,
+    ],
+    groups: &[X25519, 0x0017, 0x0018, 0x0019, 0x0100, 0x0101],
+    signature_algorithms: &[
+        0x0403, 0x0503, 0x0603, 0x0804, 0x0805, 0x0806, 0x0401, 0x0501, 0x0601, 0x0203, 0x0201,
+    ],
+    versions: &[0x0304, 0x0303],
+    alpn: &["h2", "http/1.1"],
+    shuffle: false,
+};
+
+pub static SAFARI: Profile = Profile {
+    ciphers: &[
+        GREASE, 0x1301, 0x1302, 0x1303, 0xc02c, 0xc02b, 0xcca9, 0xc030, 0xc02f, 0xcca8, 0xc00a,
+        0xc009, 0xc014, 0xc013, 0x009d, 0x009c, 0x0035, 0x002f, 0xc008, 0xc012, 0x000a,
+    ],
+    extensions: &[
+        GREASE,
+        SERVER_NAME,
+        0x0017,
+        RENEGOTIATION_INFO,
+        SUPPORTED_GROUPS,
+        EC_POINT_FORMATS,
+        ALPN,
+        STATUS_REQUEST,
+        SIGNATURE_ALGORITHMS,
+        0x0012,
+        KEY_SHARE,
+        PSK_KEY_EXCHANGE_MODES,
+        SUPPORTED_VERSIONS,
+        COMPRESS_CERTIFICATE,
+        GREASE,
+        PADDING,
+    ],
+    groups: &[GREASE, X25519, 0x0017, 0x0018, 0x0019],
+    signature_algorithms: &[
+        0x0403, 0x0804, 0x0401, 0x0503, 0x0203, 0x0805, 0x0501, 0x0806, 0x0601, 0x0201,
+    ],
+    versions: &[GREASE, 0x0304, 0x0303, 0x0302, 0x0301],
+    alpn: &["h2", "http/1.1"],
+    shuffle: false,
+};
+
+/// Profile to shape a connection like, `random` picks one per call
+pub fn profile(fingerprint: ClientFingerprint) -> &'static Profile {
+    match fingerprint {
+        ClientFingerprint::Chrome => &CHROME,
+        ClientFingerprint::Firefox => &FIREFOX,
+        ClientFingerprint::Safari => &SAFARI,
+        ClientFingerprint::Random => [&CHROME, &FIREFOX, &SAFARI]
+            .choose(&mut rand::thread_rng())
+            .unwrap(),
+    }
+}
+
+/// A random GREASE value, `0x?a?a` with equal bytes
+fn grease() -> u16 {
+    0x0a0a | (rand::thread_rng().gen_range(0, 16) * 0x1010)
+}
+
+/// `values` with the GREASE placeholder replaced by `grease`
+fn degrease(values: &[u16], grease: u16) -> Vec<u16> {
+    values
+        .iter()
+        .map(|&v| if v == GREASE { grease } else { v })
+        .collect()
+}
+
+fn push_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn push_u16s(buf: &mut Vec<u8>, values: &[u16]) {
+    push_u16(buf, values.len() as u16 * 2);
+    for &v in values {
+        push_u16(buf, v);
+    }
+}
+
+/// ClientHello handshake message for `host`, offering an X25519 `key_share`
+pub fn client_hello(
+    profile: &Profile,
+    host: &str,
+    hello_random: &[u8; 32],
+    session_id: &[u8],
+    key_share: &[u8; 32],
+) -> Vec<u8> {
+    let group_grease = grease();
+    let groups = degrease(profile.groups, group_grease);
+
+    // The two GREASE extensions of Chrome differ, the second carries a byte
+    let first_grease = grease();
+    let mut kinds = profile.extensions.to_vec();
+    for (greased, kind) in (0u16..).zip(kinds.iter_mut().filter(|k| **k == GREASE)) {
+        *kind = first_grease ^ (greased * 0x1010);
+    }
+    if profile.shuffle {
+        let mut middle: Vec<u16> = kinds
+            .iter()
+            .cloned()
+            .filter(|&k| !is_grease(k) && k != PADDING)
+            .collect();
+        middle.shuffle(&mut rand::thread_rng());
+        let mut middle = middle.into_iter();
+        for kind in kinds.iter_mut() {
+            if !is_grease(*kind) && *kind != PADDING {
+                *kind = middle.next().unwrap();
+            }
+        }
+    }
+
+    let mut extensions = Vec::new();
+    for &kind in kinds.iter() {
+        let body = match kind {
+            PADDING => continue,
+            SERVER_NAME => {
+                let mut body = Vec::new();
+                push_u16(&mut body, host.len() as u16 + 3);
+                body.push(0);
+                push_u16(&mut body, host.len() as u16);
+                body.extend_from_slice(host.as_bytes());
+                body
+            }
+            RENEGOTIATION_INFO => vec![0],
+            SUPPORTED_GROUPS => {
+                let mut body = Vec::new();
+                push_u16s(&mut body, &groups);
+                body
+            }
+            EC_POINT_FORMATS => vec![1, 0],
+            SIGNATURE_ALGORITHMS => {
+                let mut body = Vec::new();
+                push_u16s(&mut body, profile.signature_algorithms);
+                body
+            }
+            DELEGATED_CREDENTIALS => {
+                let mut body = Vec::new();
+                push_u16s(&mut body, &[0x0403, 0x0503, 0x0603, 0x0203]);
+                body
+            }
+            ALPN | APPLICATION_SETTINGS => {
+                // ALPS lists the protocols it has settings for, h2 only
+                let alpn: &[&str] = if kind == ALPN { profile.alpn } else { &["h2"] };
+                let mut list = Vec::new();
+                for protocol in alpn {
+                    list.push(protocol.len() as u8);
+                    list.extend_from_slice(protocol.as_bytes());
+                }
+                let mut body = Vec::new();
+                push_u16(&mut body, list.len() as u16);
+                body.extend_from_slice(&list);
+                body
+            }
+            STATUS_REQUEST => vec![1, 0, 0, 0, 0],
+            SUPPORTED_VERSIONS => {
+                let versions = degrease(profile.versions, grease());
+                let mut body = vec![versions.len() as u8 * 2];
+                for v in versions {
+                    push_u16(&mut body, v);
+                }
+                body
+            }
+            PSK_KEY_EXCHANGE_MODES => vec![1, 1],
+            KEY_SHARE => {
+                let mut shares = Vec::new();
+                if profile.groups.contains(&GREASE) {
+                    push_u16(&mut shares, group_grease);
+                    push_u16(&mut shares, 1);
+                    shares.push(0);
+                }
+                push_u16(&mut shares, X25519);
+                push_u16(&mut shares, 32);
+                shares.extend_from_slice(key_share);
+                let mut body = Vec::new();
+                push_u16(&mut body, shares.len() as u16);
+                body.extend_from_slice(&shares);
+                body
+            }
+            // brotli
+            COMPRESS_CERTIFICATE => vec![2, 0, 2],
+            RECORD_SIZE_LIMIT => vec![0x40, 0x01],
+            kind if is_grease(kind) && kind != first_grease => vec![0],
+            _ => Vec::new(),
+        };
+        push_u16(&mut extensions, kind);
+        push_u16(&mut extensions, body.len() as u16);
+        extensions.extend_from_slice(&body);
+    }
+
+    let mut body = vec![3, 3];
+    body.extend_from_slice(hello_random);
+    body.push(session_id.len() as u8);
+    body.extend_from_slice(session_id);
+    push_u16s(&mut body, &degrease(profile.ciphers, grease()));
+    body.extend_from_slice(&[1, 0]);
+
+    // Like BoringSSL, pad hellos of 256 to 511 bytes to 512 for servers
+    // choking on those lengths
+    if profile.extensions.contains(&PADDING) {
+        let len = 4 + body.len() + 2 + extensions.len();
+        if len > 0xff && len < 0x200 {
+            let padding = (0x200 - len).max(4 + 1) - 4;
+            push_u16(&mut extensions, PADDING);
+            push_u16(&mut extensions, padding as u16);
+            extensions.resize(extensions.len() + padding, 0);
+        }
+    }
+    push_u16(&mut body, extensions.len() as u16);
+    body.extend_from_slice(&extensions);
+
+    let mut message = vec![0x01];
+    message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    message.extend_from_slice(&body);
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::{fingerprint::Fingerprint, sniff};
+
+    fn fingerprint(profile: &Profile) -> Fingerprint {
+        let message = client_hello(profile, "example.com", &[0; 32], &[0; 32], &[1; 32]);
+        let mut record = vec![0x16, 3, 1];
+        record.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        record.extend_from_slice(&message);
+        match sniff::client_hello(&record) {
+            sniff::Sniff::Found(hello) => {
+                assert_eq!(hello.sni.as_deref(), Some("example.com"));
+                assert_eq!(hello.version, 0x0304);
+                hello.fingerprint
+            }
+            other => panic!("expected a ClientHello, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hellos_look_like_browsers() {
+        // JA4 sorts what Chrome shuffles, GREASE is left out of both
+        let chrome = fingerprint(&CHROME);
+        assert!(chrome.ja4.starts_with("t13d1516h2_"), "{}", chrome.ja4);
+        assert_eq!(fingerprint(&CHROME).ja4, chrome.ja4);
+        assert_eq!(fingerprint(&FIREFOX).ja3, fingerprint(&FIREFOX).ja3);
+        assert!(fingerprint(&FIREFOX).ja4.starts_with("t13d1714h2_"));
+        assert!(fingerprint(&SAFARI).ja4.starts_with("t13d2014h2_"));
+    }
+}
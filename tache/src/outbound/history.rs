@@ -0,0 +1,136 @@
+//! Recent health check delays of every proxy
+//!
+//! Url tests of groups and provider health checks add their results to a
+//! ring buffer per proxy name. Groups rank members by the median of it
+//! rather than the last delay, which one slow test would swing, and the API
+//! shows the percentiles and jitter for trends. Buffers are kept across
+//! reloads as the names usually stay.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use super::probe;
+use crate::provider::unix_now;
+
+/// Results kept per proxy
+pub const CAPACITY: usize = 32;
+
+lazy_static! {
+    static ref HISTORY: Mutex<HashMap<String, VecDeque<Sample>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Unix seconds
+    pub at: u64,
+    /// Milliseconds, `None` when the check failed
+    pub delay: Option<u64>,
+}
+
+/// Summary of the samples kept, delays in milliseconds
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Stats {
+    pub p50: Option<u64>,
+    pub p95: Option<u64>,
+    /// Mean change between consecutive successful checks
+    pub jitter: Option<u64>,
+    /// Percentage of checks that failed
+    pub loss: u64,
+    pub samples: usize,
+}
+
+/// Add the result of a check of `name`
+pub fn record(name: &str, delay: Option<u64>) {
+    let mut history = HISTORY.lock().unwrap();
+    let samples = history
+        .entry(name.to_owned())
+        .or_insert_with(|| VecDeque::with_capacity(CAPACITY));
+    if samples.len() == CAPACITY {
+        samples.pop_front();
+    }
+    samples.push_back(Sample {
+        at: unix_now(),
+        delay,
+    });
+}
+
+/// Samples of `name`, oldest first
+pub fn samples(name: &str) -> Vec<Sample> {
+    let history = HISTORY.lock().unwrap();
+    history
+        .get(name)
+        .map_or_else(Vec::new, |s| s.iter().cloned().collect())
+}
+
+pub fn stats(name: &str) -> Option<Stats> {
+    let history = HISTORY.lock().unwrap();
+    history.get(name).map(|s| summarize(s.iter()))
+}
+
+fn summarize<'a, I: Iterator<Item = &'a Sample>>(samples: I) -> Stats {
+    let mut count = 0;
+    let delays: Vec<u64> = samples
+        .inspect(|_| count += 1)
+        .filter_map(|s| s.delay)
+        .collect();
+    let jitter = probe::jitter(&delays);
+    let mut sorted = delays.clone();
+    sorted.sort();
+    Stats {
+        p50: percentile(&sorted, 50),
+        p95: percentile(&sorted, 95),
+        jitter,
+        loss: ((count - delays.len()) * 100)
+            .checked_div(count)
+            .unwrap_or(0) as u64,
+        samples: count,
+    }
+}
+
+/// Nearest rank percentile of `sorted`
+fn percentile(sorted: &[u64], p: usize) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len()).div_ceil(100);
+    Some(sorted[rank.max(1) - 1])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summarizes_samples() {
+        let samples: Vec<_> = [Some(40), Some(60), None, Some(50), Some(400)]
+            .iter()
+            .map(|&delay| Sample { at: 0, delay })
+            .collect();
+        assert_eq!(
+            summarize(samples.iter()),
+            Stats {
+                p50: Some(50),
+                p95: Some(400),
+                jitter: Some(126),
+                loss: 20,
+                samples: 5,
+            }
+        );
+        assert_eq!(summarize(samples[..0].iter()).p50, None);
+    }
+
+    #[test]
+    fn keeps_latest_samples() {
+        for delay in 0..CAPACITY as u64 + 3 {
+            record("history-test", Some(delay));
+        }
+        let samples = samples("history-test");
+        assert_eq!(samples.len(), CAPACITY);
+        assert_eq!(samples[0].delay, Some(3));
+    }
+}
@@ -0,0 +1,119 @@
+use std::io;
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{connect_tls, other, BoxStream, Dialer, Outbound};
+use crate::{tls::TlsConnector, utils::Address};
+
+/// Longest CONNECT response head accepted from the proxy
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+/// Upstream HTTP proxy tunneling through CONNECT
+pub struct Http {
+    name: String,
+    server: Address,
+    username: Option<String>,
+    password: Option<String>,
+    /// Talk to the server over TLS
+    tls: Option<TlsConnector>,
+}
+
+impl Http {
+    pub fn new(
+        name: &str,
+        server: Address,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> Http {
+        Http {
+            name: name.to_owned(),
+            server,
+            username,
+            password,
+            tls: None,
+        }
+    }
+
+    pub fn tls(mut self, connector: Option<TlsConnector>) -> Http {
+        self.tls = connector;
+        self
+    }
+}
+
+/// Issue a CONNECT on an established stream and wait for the tunnel
+pub async fn handshake<S>(
+    stream: &mut S,
+    target: &Address,
+    auth: Option<(&str, &str)>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let host = match *target {
+        Address::SocketAddr(ref addr) => addr.to_string(),
+        Address::DomainName(ref dn) => dn.to_string(),
+    };
+    let mut req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", host);
+    if let Some((username, password)) = auth {
+        req.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode(&format!("{}:{}", username, password))
+        ));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    // Read byte by byte so nothing past the response head is consumed
+    let mut head = Vec::with_capacity(128);
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(other("http proxy response head too long"));
+        }
+        stream.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut resp = httparse::Response::new(&mut headers);
+    resp.parse(&head).map_err(other)?;
+    match resp.code {
+        Some(200) => Ok(()),
+        Some(code) => Err(other(format!("http proxy CONNECT returned {}", code))),
+        None => Err(other("invalid http proxy response")),
+    }
+}
+
+impl Outbound for Http {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn udp(&self) -> bool {
+        false
+    }
+
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let mut stream = dialer.connect(&self.server).await?;
+            if let Some(ref connector) = self.tls {
+                stream = connect_tls(connector, &self.server, stream).await?;
+            }
+            let auth = match (&self.username, &self.password) {
+                (Some(u), Some(p)) => Some((u.as_str(), p.as_str())),
+                _ => None,
+            };
+            handshake(&mut stream, target, auth).await?;
+            Ok(stream)
+        })
+    }
+
+    fn alive(&self) -> bool {
+        true
+    }
+}
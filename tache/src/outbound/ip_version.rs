@@ -0,0 +1,171 @@
+//! Address family of connections made through a proxy, from `ip-version`
+//!
+//! The family applies to what the outbound dials directly: the server of a
+//! proxy, the destination itself for direct dials. Destination domains are
+//! passed to proxies untouched, their servers resolve them, only destination
+//! addresses of a family not allowed are refused. It's for exits whose IPv6
+//! or IPv4 is broken while DNS still answers for it. UDP relays are left
+//! alone.
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use futures::future::BoxFuture;
+
+use super::{BoxStream, Datagrams, Dialer, Outbound};
+use crate::{config::IpVersion, utils::Address};
+
+/// Addresses to try in order, the first of each family allowed
+fn candidates(addrs: &[SocketAddr], version: IpVersion) -> Vec<SocketAddr> {
+    let v4 = addrs.iter().find(|a| a.is_ipv4()).cloned();
+    let v6 = addrs.iter().find(|a| a.is_ipv6()).cloned();
+    let order = match version {
+        IpVersion::V4Only => vec![v4],
+        IpVersion::V6Only => vec![v6],
+        IpVersion::PreferV4 => vec![v4, v6],
+        IpVersion::PreferV6 => vec![v6, v4],
+    };
+    order.into_iter().flatten().collect()
+}
+
+/// `dialer` connecting to addresses of the families `version` allows
+struct Family<'d> {
+    dialer: &'d dyn Dialer,
+    version: IpVersion,
+}
+
+impl<'d> Dialer for Family<'d> {
+    fn connect<'a>(&'a self, target: &'a Address) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let addrs = target.lookup().await?;
+            let mut last_err = io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no address of the family ip-version allows", target),
+            );
+            for addr in candidates(&addrs, self.version) {
+                match self.dialer.connect(&Address::SocketAddr(addr)).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => last_err = e,
+                }
+            }
+            Err(last_err)
+        })
+    }
+}
+
+/// `outbound` dialing addresses of one address family
+pub struct IpVersioned {
+    outbound: Arc<dyn Outbound>,
+    version: IpVersion,
+}
+
+impl IpVersioned {
+    pub fn new(outbound: Arc<dyn Outbound>, version: IpVersion) -> IpVersioned {
+        IpVersioned { outbound, version }
+    }
+}
+
+impl Outbound for IpVersioned {
+    fn name(&self) -> String {
+        self.outbound.name()
+    }
+
+    fn udp(&self) -> bool {
+        self.outbound.udp()
+    }
+
+    /// `dialer` connects directly, it's handed the server of a proxy or the
+    /// destination of a direct dial
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            if let Address::SocketAddr(addr) = *target {
+                if candidates(&[addr], self.version).is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} is of a family ip-version doesn't allow", addr),
+                    ));
+                }
+            }
+            let family = Family {
+                dialer,
+                version: self.version,
+            };
+            self.outbound.dial(target, &family).await
+        })
+    }
+
+    fn bind(&self) -> BoxFuture<'_, io::Result<Datagrams>> {
+        self.outbound.bind()
+    }
+
+    fn alive(&self) -> bool {
+        self.outbound.alive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        outbound::{Socks5, TcpDialer},
+        rt::{Runtime, TcpListener},
+        utils::DomainName,
+    };
+
+    #[test]
+    fn orders_candidates() {
+        let addrs: Vec<SocketAddr> = ["[2001:db8::1]:443", "192.0.2.1:443", "192.0.2.2:443"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let (v6, v4) = (addrs[0], addrs[1]);
+        assert_eq!(candidates(&addrs, IpVersion::V4Only), vec![v4]);
+        assert_eq!(candidates(&addrs, IpVersion::V6Only), vec![v6]);
+        assert_eq!(candidates(&addrs, IpVersion::PreferV4), vec![v4, v6]);
+        assert_eq!(candidates(&addrs, IpVersion::PreferV6), vec![v6, v4]);
+        assert!(candidates(&addrs[1..], IpVersion::V6Only).is_empty());
+    }
+
+    #[test]
+    fn proxies_get_domains_untouched() {
+        Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut greeting = [0; 3];
+                stream.read_exact(&mut greeting).await.unwrap();
+                stream.write_all(&[5, 0]).await.unwrap();
+                let mut head = [0; 5];
+                stream.read_exact(&mut head).await.unwrap();
+                let mut domain = vec![0; usize::from(head[4]) + 2];
+                stream.read_exact(&mut domain).await.unwrap();
+                stream
+                    .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                (head[3], domain)
+            };
+
+            // Only the server, named `localhost`, is resolved here
+            let server_address = Address::DomainName(DomainName("localhost".to_owned(), port));
+            let socks = Arc::new(Socks5::new("socks", server_address, None, None).tls(None));
+            let outbound = IpVersioned::new(socks, IpVersion::V4Only);
+            let target = Address::DomainName(DomainName("example.com".to_owned(), 443));
+            let (dialed, (kind, domain)) =
+                futures::join!(outbound.dial(&target, &TcpDialer), server);
+            dialed.unwrap();
+            assert_eq!(kind, 3);
+            assert_eq!(&domain[..11], b"example.com");
+
+            let v6 = Address::SocketAddr("[2001:db8::1]:443".parse().unwrap());
+            let err = outbound.dial(&v6, &TcpDialer).await.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+}
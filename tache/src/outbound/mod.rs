@@ -1,10 +1,463 @@
+use std::{collections::HashMap, io, sync::Arc};
+
+use futures::{channel::mpsc, future::BoxFuture};
+use log::{error, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    config::{CircuitBreakerConfig, ProxyConfig, ProxyGroupConfig},
+    crypto::CipherKind,
+    tls::{self, TlsConnector},
+    utils::Address,
+};
+
+pub mod breaker;
+pub mod dialer;
 mod direct;
+pub mod exit;
 mod fallback;
+pub mod hello;
+pub mod history;
+mod ip_version;
+mod http;
+pub mod pool;
+pub mod pre_dial;
+pub mod probe;
+#[cfg(feature = "ring-crypto")]
+mod quic;
+pub mod shadow_tls;
+pub mod shadowsocks;
+mod smart;
 mod socks5;
+pub mod speedtest;
+pub mod system;
+mod tor;
+pub mod transport;
+mod trojan;
+pub mod uot;
+mod vmess;
+
+pub use self::{
+    breaker::Breaker,
+    dialer::{Chained, Dialer, MarkedDialer, TcpDialer, Via},
+    direct::Direct,
+    http::{handshake as http_handshake, Http},
+    ip_version::IpVersioned,
+    pool::{Pool, Pooled},
+    pre_dial::PreDial,
+    shadowsocks::Shadowsocks,
+    smart::Smart,
+    socks5::{handshake as socks5_handshake, Socks5},
+    system::System,
+    tor::Tor,
+    transport::Transport,
+    trojan::Trojan,
+    vmess::Vmess,
+};
+
+#[cfg(feature = "ring-crypto")]
+pub use self::quic::Quic;
+
+/// Name of the built-in outbound connecting without any proxy
+pub const DIRECT: &str = "DIRECT";
+
+/// Byte stream produced by an outbound
+pub trait ProxyStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ProxyStream for T {}
 
-pub trait Outbound {
+pub type BoxStream = Box<dyn ProxyStream>;
+
+/// UDP relayed through an outbound, datagrams go in with the peer they are
+/// for and come out with the peer they are from
+pub struct Datagrams {
+    pub send: mpsc::UnboundedSender<(Address, Vec<u8>)>,
+    pub recv: mpsc::UnboundedReceiver<(Address, Vec<u8>)>,
+}
+
+pub trait Outbound: Send + Sync {
     fn name(&self) -> String;
     fn udp(&self) -> bool;
-    fn dial(&self) -> Result<(), String>;
+    /// Open a stream to `target` through this outbound, connecting to its
+    /// server with `dialer`
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>>;
+    /// Open a UDP relay through this outbound, closed once `Datagrams` is
+    /// dropped or the relay goes quiet
+    fn bind(&self) -> BoxFuture<'_, io::Result<Datagrams>> {
+        Box::pin(async move { Err(other(format!("{} relays no UDP", self.name()))) })
+    }
     fn alive(&self) -> bool;
+    /// State of the circuit breaker in front of a proxy
+    fn circuit(&self) -> Option<breaker::Circuit> {
+        None
+    }
+}
+
+pub type Outbounds = HashMap<String, Arc<dyn Outbound>>;
+
+/// Build the outbounds of every supported proxy and group plus `DIRECT`,
+/// aliases map to the outbound of their proxy. Proxies are put behind a
+/// circuit breaker unless `breaker` turns them off. Ciphers set to `auto`
+/// are `preferred_cipher`.
+pub fn build_outbounds(
+    proxies: &[ProxyConfig],
+    groups: &[ProxyGroupConfig],
+    breaker: Option<&CircuitBreakerConfig>,
+    preferred_cipher: CipherKind,
+) -> Outbounds {
+    let mut outbounds: Outbounds = HashMap::new();
+    outbounds.insert(DIRECT.to_owned(), Arc::new(Direct::new(DIRECT)));
+    for proxy in proxies {
+        let outbound: Arc<dyn Outbound> = match *proxy {
+            ProxyConfig::Socks5 {
+                ref name,
+                ref address,
+                ref username,
+                ref password,
+                tls,
+                ..
+            } => Arc::new(
+                Socks5::new(name, address.clone(), username.clone(), password.clone())
+                    .tls(proxy_tls(proxy, tls)),
+            ),
+            ProxyConfig::HTTP {
+                ref name,
+                ref address,
+                ref username,
+                ref password,
+                tls,
+                ..
+            } => Arc::new(
+                Http::new(name, address.clone(), username.clone(), password.clone())
+                    .tls(proxy_tls(proxy, tls)),
+            ),
+            ProxyConfig::Shadowsocks {
+                ref name,
+                ref address,
+                ref cipher,
+                ref password,
+                udp,
+                ref shadow_tls,
+                ..
+            } => match Shadowsocks::new(
+                name,
+                address.clone(),
+                cipher,
+                password,
+                udp,
+                preferred_cipher,
+            ) {
+                Ok(outbound) => Arc::new(
+                    outbound.shadow_tls(shadow_tls.clone(), proxy.options().client_fingerprint),
+                ),
+                Err(e) => {
+                    error!("Skip proxy {}, err: {}", name, e);
+                    continue;
+                }
+            },
+            ProxyConfig::VMESS {
+                ref name,
+                ref address,
+                ref uuid,
+                alter_id,
+                ref cipher,
+                tls,
+                ref shadow_tls,
+                ref transport,
+                ..
+            } => {
+                let fingerprint = proxy.options().client_fingerprint;
+                let outbound = Vmess::new(
+                    name,
+                    address.clone(),
+                    uuid,
+                    alter_id,
+                    cipher,
+                    preferred_cipher,
+                )
+                .and_then(|outbound| {
+                    Ok(outbound
+                        .tls(proxy_tls(proxy, tls))
+                        .shadow_tls(shadow_tls.clone(), fingerprint)
+                        .transport(Transport::from_config(transport, fingerprint)?))
+                });
+                match outbound {
+                    Ok(outbound) => Arc::new(outbound),
+                    Err(e) => {
+                        error!("Skip proxy {}, err: {}", name, e);
+                        continue;
+                    }
+                }
+            }
+            ProxyConfig::Trojan {
+                ref name,
+                ref address,
+                ref password,
+                ref sni,
+                skip_cert_verify,
+                ref transport,
+                ..
+            } => {
+                if skip_cert_verify == Some(true) {
+                    warn!(
+                        "Proxy {} sets skip-cert-verify, its certificate is verified anyway",
+                        name
+                    );
+                }
+                let fingerprint = proxy.options().client_fingerprint;
+                match Transport::from_config(transport, fingerprint) {
+                    Ok(transport) => Arc::new(
+                        Trojan::new(name, address.clone(), password, sni.clone(), fingerprint)
+                            .transport(transport),
+                    ),
+                    Err(e) => {
+                        error!("Skip proxy {}, err: {}", name, e);
+                        continue;
+                    }
+                }
+            }
+            #[cfg(feature = "ring-crypto")]
+            ProxyConfig::Quic {
+                ref name,
+                ref address,
+                ref password,
+                ref sni,
+                ref alpn,
+                congestion,
+                up_mbps,
+                zero_rtt,
+                udp,
+                ..
+            } => match Quic::new(
+                name,
+                address.clone(),
+                password,
+                sni.clone(),
+                alpn,
+                congestion,
+                up_mbps,
+                zero_rtt.unwrap_or(false),
+                udp.unwrap_or(false),
+            ) {
+                Ok(outbound) => Arc::new(outbound),
+                Err(e) => {
+                    error!("Skip proxy {}, err: {}", name, e);
+                    continue;
+                }
+            },
+            #[cfg(not(feature = "ring-crypto"))]
+            ProxyConfig::Quic { ref name, .. } => {
+                error!(
+                    "Skip proxy {}, QUIC needs tache built with the ring-crypto feature",
+                    name
+                );
+                continue;
+            }
+            ProxyConfig::Tor {
+                ref name,
+                ref address,
+                isolation,
+                onion_only,
+                ..
+            } => Arc::new(Tor::new(
+                name,
+                address.clone(),
+                isolation.unwrap_or(true),
+                onion_only.unwrap_or(false),
+            )),
+            ProxyConfig::System { ref name, .. } => match System::from_env(name) {
+                Ok(outbound) => Arc::new(outbound),
+                Err(e) => {
+                    error!("Skip proxy {}, err: {}", name, e);
+                    continue;
+                }
+            },
+        };
+        let outbound = PreDial::wrap(outbound, proxy.options().pre_dial.as_ref());
+        outbounds.insert(proxy.name().to_owned(), outbound);
+    }
+
+    // Wrap chained proxies once all of them exist, parents first
+    let mut chained = HashMap::new();
+    for proxy in proxies {
+        if proxy.dialer_proxy().is_some() {
+            if let Err(e) = chain(
+                proxy.name(),
+                proxies,
+                &outbounds,
+                &mut chained,
+                &mut Vec::new(),
+            ) {
+                error!("Skip proxy {}, err: {}", proxy.name(), e);
+            }
+        }
+    }
+    for proxy in proxies {
+        if proxy.dialer_proxy().is_some() {
+            match chained.remove(proxy.name()) {
+                Some(outbound) => outbounds.insert(proxy.name().to_owned(), outbound),
+                None => outbounds.remove(proxy.name()),
+            };
+        }
+    }
+
+    for proxy in proxies {
+        if let (Some(outbound), Some(version)) = (
+            outbounds.get(proxy.name()).cloned(),
+            proxy.options().ip_version,
+        ) {
+            outbounds.insert(
+                proxy.name().to_owned(),
+                Arc::new(IpVersioned::new(outbound, version)),
+            );
+        }
+    }
+
+    for proxy in proxies {
+        if let Some(outbound) = outbounds.get(proxy.name()).cloned() {
+            if let Some(breaker) = Breaker::wrap(outbound, breaker) {
+                outbounds.insert(proxy.name().to_owned(), Arc::new(breaker));
+            }
+        }
+    }
+
+    for proxy in proxies {
+        if let Some(outbound) = outbounds.get(proxy.name()).cloned() {
+            for alias in proxy.options().alias.iter() {
+                outbounds.insert(alias.clone(), outbound.clone());
+            }
+        }
+    }
+
+    // A group may contain the groups defined before it
+    for group in groups {
+        let members = group
+            .proxies
+            .iter()
+            .filter_map(|name| {
+                let member = outbounds.get(name).cloned();
+                if member.is_none() {
+                    error!("Proxy {} of group {} not found", name, group.name);
+                }
+                member
+            })
+            .collect::<Vec<_>>();
+        let outbound: Arc<dyn Outbound> = match &group.kind[..] {
+            "smart" => Arc::new(Smart::new(
+                &group.name,
+                members,
+                group
+                    .url
+                    .clone()
+                    .unwrap_or_else(|| probe::DEFAULT_TEST_URL.to_owned()),
+                group.interval.unwrap_or(smart::DEFAULT_INTERVAL),
+                group.retries.unwrap_or(smart::DEFAULT_RETRIES),
+            )),
+            kind => {
+                error!(
+                    "Proxy group {} of kind {} is not supported yet",
+                    group.name, kind
+                );
+                continue;
+            }
+        };
+        outbounds.insert(group.name.clone(), outbound);
+    }
+    outbounds
+}
+
+/// Connector of a proxy served over TLS, offering cipher suites like its
+/// `client-fingerprint`
+fn proxy_tls(proxy: &ProxyConfig, tls: Option<bool>) -> Option<TlsConnector> {
+    if !tls.unwrap_or(false) {
+        return None;
+    }
+    if let ProxyConfig::Socks5 {
+        skip_cert_verify: Some(true),
+        ..
+    }
+    | ProxyConfig::HTTP {
+        skip_cert_verify: Some(true),
+        ..
+    } = *proxy
+    {
+        warn!(
+            "Proxy {} sets skip-cert-verify, its certificate is verified anyway",
+            proxy.name()
+        );
+    }
+    Some(tls::client_connector_like(
+        &[],
+        proxy.options().client_fingerprint,
+    ))
+}
+
+/// Domain of a proxy server, the name its certificate is checked against
+fn server_name(server: &Address) -> io::Result<&str> {
+    match *server {
+        Address::DomainName(ref dn) => Ok(&dn.0),
+        Address::SocketAddr(_) => Err(other("tls to a proxy needs its domain name")),
+    }
+}
+
+/// TLS to a proxy server over `stream`, its domain is the server name
+pub(crate) async fn connect_tls(
+    connector: &TlsConnector,
+    server: &Address,
+    stream: BoxStream,
+) -> io::Result<BoxStream> {
+    let host = server_name(server)?;
+    Ok(Box::new(tls::connect(connector, host, stream).await?))
+}
+
+/// Name of the proxy `name` is an alias of, or `name` itself
+fn canonical<'a>(name: &'a str, proxies: &'a [ProxyConfig]) -> &'a str {
+    proxies
+        .iter()
+        .find(|p| p.options().alias.iter().any(|a| a == name))
+        .map_or(name, ProxyConfig::name)
+}
+
+/// Resolve the `dialer-proxy` chain of `name`, `visiting` catches loops
+fn chain(
+    name: &str,
+    proxies: &[ProxyConfig],
+    outbounds: &Outbounds,
+    chained: &mut Outbounds,
+    visiting: &mut Vec<String>,
+) -> io::Result<Arc<dyn Outbound>> {
+    let name = canonical(name, proxies);
+    if let Some(outbound) = chained.get(name) {
+        return Ok(outbound.clone());
+    }
+    let outbound = outbounds
+        .get(name)
+        .cloned()
+        .ok_or_else(|| other(format!("proxy {} not available", name)))?;
+    let parent = match proxies
+        .iter()
+        .find(|p| p.name() == name)
+        .and_then(ProxyConfig::dialer_proxy)
+    {
+        Some(parent) => parent,
+        None => return Ok(outbound),
+    };
+    if visiting.iter().any(|v| v == name) {
+        return Err(other(format!("dialer-proxy loop at {}", name)));
+    }
+    visiting.push(name.to_owned());
+    let parent = chain(parent, proxies, outbounds, chained, visiting)?;
+    visiting.pop();
+
+    let outbound: Arc<dyn Outbound> = Arc::new(Chained::new(outbound, parent));
+    chained.insert(name.to_owned(), outbound.clone());
+    Ok(outbound)
+}
+
+pub(crate) fn other<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
 }
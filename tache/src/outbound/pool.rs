@@ -0,0 +1,308 @@
+//! Warm connections to proxy servers, `keep-alive` of the config
+//!
+//! A proxy server dialed through the plain dialer gets a spare connection
+//! opened in the background, which the next dial to it checks out instead
+//! of waiting for the TCP handshake. Spares of servers left unused for
+//! `idle-timeout` are closed, spares older than `session-lifetime` are
+//! replaced by a fresh one before the server times them out, so the first
+//! request after a quiet period doesn't stall on a dead connection.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, StreamExt};
+use log::{debug, trace};
+
+use super::{BoxStream, Dialer, TcpDialer};
+use crate::{
+    config::{KeepAliveConfig, ProxyConfig},
+    rt::{self, interval},
+    utils::Address,
+};
+
+/// Default time a server may go unused before its spare is closed
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+/// Default age after which a spare is replaced by a fresh one
+pub const DEFAULT_SESSION_LIFETIME: Duration = Duration::from_secs(600);
+
+/// Reap at least this often, whatever the limits
+const MAX_REAP_PERIOD: Duration = Duration::from_secs(30);
+
+struct Spare {
+    conn: BoxStream,
+    created: Instant,
+}
+
+/// A proxy server and its spare connection
+struct Server {
+    address: Address,
+    spare: Option<Spare>,
+    /// Last dial to the server, `None` before the first one
+    last_used: Option<Instant>,
+    /// A spare is being dialed
+    refilling: bool,
+}
+
+/// Spare connections, keyed by the proxy server address
+pub struct Pool {
+    idle_timeout: Duration,
+    session_lifetime: Duration,
+    servers: Mutex<HashMap<String, Server>>,
+}
+
+impl Pool {
+    /// Pool for the servers at `addresses`, dials to other addresses pass
+    /// through
+    pub fn new<'a, I>(idle_timeout: Duration, session_lifetime: Duration, addresses: I) -> Pool
+    where
+        I: IntoIterator<Item = &'a Address>,
+    {
+        let servers = addresses
+            .into_iter()
+            .map(|address| {
+                let server = Server {
+                    address: address.clone(),
+                    spare: None,
+                    last_used: None,
+                    refilling: false,
+                };
+                (address.to_string(), server)
+            })
+            .collect();
+        Pool {
+            idle_timeout,
+            session_lifetime,
+            servers: Mutex::new(servers),
+        }
+    }
+
+    /// Pool for the servers of `proxies`, none without `keep-alive`
+    pub fn from_config(config: Option<&KeepAliveConfig>, proxies: &[ProxyConfig]) -> Pool {
+        let idle_timeout = config
+            .and_then(|c| c.idle_timeout)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+        let session_lifetime = config
+            .and_then(|c| c.session_lifetime)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SESSION_LIFETIME);
+        let addresses = match config {
+            Some(_) => proxies.iter().filter_map(ProxyConfig::address).collect(),
+            None => Vec::new(),
+        };
+        Pool::new(idle_timeout, session_lifetime, addresses)
+    }
+
+    /// Spare connections currently open
+    pub fn spares(&self) -> usize {
+        let servers = self.servers.lock().unwrap();
+        servers.values().filter(|s| s.spare.is_some()).count()
+    }
+
+    /// Close every spare, returning how many there were
+    ///
+    /// Servers get new ones on their next dial.
+    pub fn clear(&self) -> usize {
+        let mut servers = self.servers.lock().unwrap();
+        let spares = servers.values_mut().filter_map(|s| s.spare.take());
+        spares.count()
+    }
+
+    /// Spare connection to `target`, `Err` when it isn't a pooled server
+    ///
+    /// Counts as a use of the server, so it gets a new spare either way.
+    fn take(&self, target: &Address) -> Result<Option<BoxStream>, ()> {
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        let server = servers.get_mut(&target.to_string()).ok_or(())?;
+        server.last_used = Some(now);
+        let spare = server.spare.take();
+        Ok(spare
+            .filter(|spare| now.duration_since(spare.created) < self.session_lifetime)
+            .map(|spare| spare.conn))
+    }
+
+    /// Close spares of unused servers, returning the servers whose spare
+    /// reached its lifetime, or couldn't be dialed, to refill them
+    fn reap(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut refill = Vec::new();
+        let mut servers = self.servers.lock().unwrap();
+        for (key, server) in servers.iter_mut() {
+            let used = server.last_used.map(|at| now.duration_since(at));
+            if used.is_none_or(|idle| idle >= self.idle_timeout) {
+                if server.spare.take().is_some() {
+                    trace!("Closed the idle spare connection to {}", key);
+                }
+                continue;
+            }
+            let aged = match server.spare {
+                Some(ref spare) => now.duration_since(spare.created) >= self.session_lifetime,
+                None => !server.refilling,
+            };
+            if aged {
+                server.spare = None;
+                refill.push(key.clone());
+            }
+        }
+        refill
+    }
+
+    /// Dial a spare to the server under `key` in the background, unless it
+    /// has one or one is on its way
+    fn refill(self: &Arc<Pool>, key: &str) {
+        let address = {
+            let mut servers = self.servers.lock().unwrap();
+            let server = match servers.get_mut(key) {
+                Some(server) if server.spare.is_none() && !server.refilling => server,
+                _ => return,
+            };
+            server.refilling = true;
+            server.address.clone()
+        };
+        let pool = self.clone();
+        let key = key.to_owned();
+        rt::spawn(async move {
+            let dialed = TcpDialer.connect(&address).await;
+            let mut servers = pool.servers.lock().unwrap();
+            let server = match servers.get_mut(&key) {
+                Some(server) => server,
+                None => return,
+            };
+            server.refilling = false;
+            match dialed {
+                Ok(conn) => {
+                    trace!("Opened a spare connection to {}", key);
+                    server.spare = Some(Spare {
+                        conn,
+                        created: Instant::now(),
+                    });
+                }
+                // Tried again by the next reap or dial
+                Err(e) => debug!("Failed to open a spare connection to {}, err: {}", key, e),
+            }
+        });
+    }
+
+    /// Reap and refill spares until dropped, never returns
+    pub async fn run_reaper(self: Arc<Pool>) {
+        let period = self
+            .idle_timeout
+            .min(self.session_lifetime)
+            .min(MAX_REAP_PERIOD * 2)
+            .max(Duration::from_secs(2))
+            / 2;
+        let mut interval = interval(period);
+        while interval.next().await.is_some() {
+            for key in self.reap() {
+                debug!("Re-handshaking the spare connection to {}", key);
+                self.refill(&key);
+            }
+        }
+    }
+}
+
+/// Plain TCP connections, checked out of `pool` for its servers
+pub struct Pooled(pub Arc<Pool>);
+
+impl Dialer for Pooled {
+    fn connect<'a>(&'a self, target: &'a Address) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let spare = match self.0.take(target) {
+                Ok(spare) => spare,
+                Err(()) => return TcpDialer.connect(target).await,
+            };
+            self.0.refill(&target.to_string());
+            match spare {
+                Some(conn) => {
+                    trace!("Checked out a spare connection to {}", target);
+                    Ok(conn)
+                }
+                None => TcpDialer.connect(target).await,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::rt::{delay_for, Runtime, TcpListener};
+
+    fn pool_of(address: &Address, idle_timeout: u64, session_lifetime: u64) -> Arc<Pool> {
+        Arc::new(Pool::new(
+            Duration::from_millis(idle_timeout),
+            Duration::from_millis(session_lifetime),
+            vec![address],
+        ))
+    }
+
+    async fn wait_for_spare(pool: &Pool) {
+        while pool.spares() == 0 {
+            delay_for(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn dials_check_out_spares() {
+        Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = Address::SocketAddr(listener.local_addr().unwrap());
+            let pool = pool_of(&server, 60_000, 60_000);
+            let dialer = Pooled(pool.clone());
+
+            // The first dial opens its own connection and a spare
+            let _first = dialer.connect(&server).await.unwrap();
+            let _ = listener.accept().await.unwrap();
+            let (mut spare, _) = listener.accept().await.unwrap();
+            wait_for_spare(&pool).await;
+
+            let mut second = dialer.connect(&server).await.unwrap();
+            second.write_all(b"warm").await.unwrap();
+            let mut buf = [0; 4];
+            spare.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"warm");
+
+            // And a new spare takes its place
+            let _ = listener.accept().await.unwrap();
+            wait_for_spare(&pool).await;
+            assert_eq!(pool.clear(), 1);
+            assert_eq!(pool.spares(), 0);
+
+            // Other addresses are dialed as they are
+            let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let other = Address::SocketAddr(other.local_addr().unwrap());
+            assert!(pool.take(&other).is_err());
+        });
+    }
+
+    #[test]
+    fn reaps_idle_and_refreshes_aged_spares() {
+        Runtime::new().unwrap().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = Address::SocketAddr(listener.local_addr().unwrap());
+
+            // Used within the idle timeout but older than the lifetime
+            let pool = pool_of(&server, 60_000, 50);
+            let _ = Pooled(pool.clone()).connect(&server).await.unwrap();
+            wait_for_spare(&pool).await;
+            delay_for(Duration::from_millis(100)).await;
+            assert_eq!(pool.reap(), vec![server.to_string()]);
+            assert_eq!(pool.spares(), 0);
+
+            // Unused for the idle timeout
+            let pool = pool_of(&server, 50, 60_000);
+            let _ = Pooled(pool.clone()).connect(&server).await.unwrap();
+            wait_for_spare(&pool).await;
+            delay_for(Duration::from_millis(100)).await;
+            assert!(pool.reap().is_empty());
+            assert_eq!(pool.spares(), 0);
+        });
+    }
+}
@@ -0,0 +1,183 @@
+//! Hooks run before dialing a proxy, `pre-dial` of its options
+//!
+//! For servers whose port only opens after a knock sequence or a
+//! single-packet authorization. The hook runs before the first dial and
+//! again on the first dial after `cooldown`, dials meanwhile wait for the
+//! running hook rather than starting their own. A failed hook fails the
+//! dial and is tried again on the next one.
+
+use std::{
+    io,
+    process::Command,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures::{channel::oneshot, future::BoxFuture, lock::Mutex};
+use log::{debug, warn};
+
+use super::{BoxStream, Datagrams, Dialer, Outbound};
+use crate::{config::PreDialConfig, http_client, rt, utils::Address};
+
+/// A minute, long enough for a burst of connections to share one knock
+pub const DEFAULT_COOLDOWN: u64 = 60;
+
+/// Hooks taking longer fail the dial
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Hook {
+    Url(String),
+    Command(Vec<String>),
+}
+
+impl Hook {
+    async fn run(&self) -> io::Result<()> {
+        match *self {
+            Hook::Url(ref url) => {
+                let resp = http_client::get(url, HOOK_TIMEOUT).await?;
+                if resp.status / 100 != 2 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("pre-dial {} returned status {}", url, resp.status),
+                    ));
+                }
+                Ok(())
+            }
+            Hook::Command(ref command) => {
+                rt::timeout(HOOK_TIMEOUT, run_command(command.clone())).await?
+            }
+        }
+    }
+}
+
+/// Run `command` on its own thread, the runtime has no process support
+async fn run_command(command: Vec<String>) -> io::Result<()> {
+    let (tx, rx) = oneshot::channel();
+    let program = command[0].clone();
+    thread::spawn(move || {
+        let _ = tx.send(Command::new(&command[0]).args(&command[1..]).status());
+    });
+    let status = rx
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "pre-dial command vanished"))??;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("pre-dial {} exited with {}", program, status),
+        ));
+    }
+    Ok(())
+}
+
+/// `outbound` running its hook before dials
+pub struct PreDial {
+    outbound: Arc<dyn Outbound>,
+    hook: Hook,
+    cooldown: Duration,
+    /// When the hook last succeeded, held while it runs
+    last_run: Mutex<Option<Instant>>,
+}
+
+impl PreDial {
+    /// `outbound` itself without a hook
+    pub fn wrap(outbound: Arc<dyn Outbound>, config: Option<&PreDialConfig>) -> Arc<dyn Outbound> {
+        let config = match config {
+            Some(config) => config,
+            None => return outbound,
+        };
+        let hook = match (config.url.as_ref(), config.command.as_ref()) {
+            (Some(url), _) => Hook::Url(url.clone()),
+            (None, Some(command)) if !command.is_empty() => Hook::Command(command.clone()),
+            _ => return outbound,
+        };
+        Arc::new(PreDial {
+            outbound,
+            hook,
+            cooldown: Duration::from_secs(config.cooldown.unwrap_or(DEFAULT_COOLDOWN)),
+            last_run: Mutex::new(None),
+        })
+    }
+
+    /// Run the hook unless it ran within the cooldown
+    async fn ready(&self) -> io::Result<()> {
+        let mut last_run = self.last_run.lock().await;
+        if let Some(at) = *last_run {
+            if at.elapsed() < self.cooldown {
+                return Ok(());
+            }
+        }
+        match self.hook.run().await {
+            Ok(()) => {
+                debug!("Ran pre-dial of {}", self.outbound.name());
+                *last_run = Some(Instant::now());
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Pre-dial of {} failed, err: {}", self.outbound.name(), e);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Outbound for PreDial {
+    fn name(&self) -> String {
+        self.outbound.name()
+    }
+
+    fn udp(&self) -> bool {
+        self.outbound.udp()
+    }
+
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            self.ready().await?;
+            self.outbound.dial(target, dialer).await
+        })
+    }
+
+    fn bind(&self) -> BoxFuture<'_, io::Result<Datagrams>> {
+        Box::pin(async move {
+            self.ready().await?;
+            self.outbound.bind().await
+        })
+    }
+
+    fn alive(&self) -> bool {
+        self.outbound.alive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{outbound::Direct, rt::Runtime};
+
+    #[cfg(unix)]
+    #[test]
+    fn hook_runs_once_per_cooldown() {
+        let marker = std::env::temp_dir().join(format!("tache-pre-dial-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let pre_dial = PreDial {
+            outbound: Arc::new(Direct::new("knocked")),
+            hook: Hook::Command(vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                format!("echo >> {}", marker.display()),
+            ]),
+            cooldown: Duration::from_secs(DEFAULT_COOLDOWN),
+            last_run: Mutex::new(None),
+        };
+        Runtime::new().unwrap().block_on(async {
+            pre_dial.ready().await.unwrap();
+            pre_dial.ready().await.unwrap();
+        });
+        assert_eq!(std::fs::read_to_string(&marker).unwrap().lines().count(), 1);
+        std::fs::remove_file(&marker).unwrap();
+    }
+}
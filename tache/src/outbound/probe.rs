@@ -0,0 +1,250 @@
+//! Measurements of proxies, shared by health checks, url tests, speed tests
+//! and the `test-proxies` report
+
+use std::{
+    cmp::Ordering,
+    fmt::Write,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use serde::Serialize;
+
+use super::{Outbound, Outbounds};
+use crate::{
+    config::ProxyConfig,
+    http_client,
+    rt::{self, TcpStream},
+    utils::Address,
+};
+
+/// Answers quickly with an empty body
+pub const DEFAULT_TEST_URL: &str = "http://www.gstatic.com/generate_204";
+
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time allowed for the throughput download
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn millis(d: Duration) -> u64 {
+    d.as_millis() as u64
+}
+
+/// Milliseconds to open a TCP connection to the server at `address`
+pub async fn connect_delay(address: &Address) -> Option<u64> {
+    let addr = address.lookup().await.ok()?.into_iter().next()?;
+    let start = Instant::now();
+    match rt::timeout(PROBE_TIMEOUT, TcpStream::connect(&addr)).await {
+        Ok(Ok(_)) => Some(millis(start.elapsed())),
+        _ => None,
+    }
+}
+
+/// Milliseconds until `url` was fetched through `outbound`
+pub async fn url_delay(outbound: &dyn Outbound, url: &str) -> io::Result<u64> {
+    let start = Instant::now();
+    http_client::get_via(url, PROBE_TIMEOUT, Some(outbound)).await?;
+    Ok(millis(start.elapsed()))
+}
+
+/// Delays of `count` url tests one after another, failed ones left out
+pub async fn url_delays(outbound: &dyn Outbound, url: &str, count: usize) -> Vec<u64> {
+    let mut delays = Vec::with_capacity(count);
+    for _ in 0..count {
+        if let Ok(delay) = url_delay(outbound, url).await {
+            delays.push(delay);
+        }
+    }
+    delays
+}
+
+/// Mean difference between consecutive delays, `None` for less than two
+pub fn jitter(delays: &[u64]) -> Option<u64> {
+    if delays.len() < 2 {
+        return None;
+    }
+    let sum: u64 = delays
+        .windows(2)
+        .map(|w| (w[0] as i64 - w[1] as i64).unsigned_abs())
+        .sum();
+    Some(sum / (delays.len() - 1) as u64)
+}
+
+/// Bytes of `url` downloaded through `outbound` and the time it took
+pub async fn download(outbound: &dyn Outbound, url: &str) -> io::Result<(u64, Duration)> {
+    let start = Instant::now();
+    let resp = http_client::get_via(url, DOWNLOAD_TIMEOUT, Some(outbound)).await?;
+    if resp.status != 200 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("status {}", resp.status),
+        ));
+    }
+    Ok((resp.body.len() as u64, start.elapsed()))
+}
+
+/// Bytes per second of a download
+pub fn per_second(bytes: u64, elapsed: Duration) -> u64 {
+    (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64
+}
+
+/// Outbounds of the proxies listed in the API, groups left out
+pub fn proxy_outbounds(
+    proxies: &[ProxyConfig],
+    outbounds: &Outbounds,
+) -> Vec<(String, Arc<dyn Outbound>)> {
+    proxies
+        .iter()
+        .filter(|proxy| !proxy.hidden())
+        .filter_map(|proxy| {
+            let outbound = outbounds.get(proxy.name())?;
+            Some((proxy.name().to_owned(), outbound.clone()))
+        })
+        .collect()
+}
+
+/// Timings of one outbound, in milliseconds
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub name: String,
+    /// Until the stream to the test url was ready, proxy and TLS handshakes
+    /// included
+    pub handshake: Option<u64>,
+    /// From sending the request to the first byte of the response
+    pub ttfb: Option<u64>,
+    /// Bytes per second downloading the throughput url
+    pub throughput: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Fetch `url` through `outbound`, then `download` when given
+pub async fn measure(
+    name: &str,
+    outbound: &dyn Outbound,
+    url: &str,
+    download: Option<&str>,
+) -> Report {
+    let mut report = Report {
+        name: name.to_owned(),
+        handshake: None,
+        ttfb: None,
+        throughput: None,
+        error: None,
+    };
+    match http_client::get_via(url, PROBE_TIMEOUT, Some(outbound)).await {
+        Ok(resp) => {
+            report.handshake = Some(millis(resp.handshake));
+            report.ttfb = Some(millis(resp.ttfb));
+        }
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    }
+    if let Some(download) = download {
+        match self::download(outbound, download).await {
+            Ok((bytes, elapsed)) => report.throughput = Some(per_second(bytes, elapsed)),
+            Err(e) => report.error = Some(format!("download: {}", e)),
+        }
+    }
+    report
+}
+
+/// Measure every outbound at once, fastest first and failures last
+pub async fn measure_all(
+    outbounds: &[(String, Arc<dyn Outbound>)],
+    url: &str,
+    download: Option<&str>,
+) -> Vec<Report> {
+    let mut reports = join_all(
+        outbounds
+            .iter()
+            .map(|(name, outbound)| measure(name, &**outbound, url, download)),
+    )
+    .await;
+    reports.sort_by(|a, b| match (a.ttfb, b.ttfb) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.name.cmp(&b.name),
+    });
+    reports
+}
+
+/// Reports as a text table
+pub fn table(reports: &[Report]) -> String {
+    fn cell(value: Option<u64>, unit: &str) -> String {
+        value.map_or_else(|| "-".to_owned(), |v| format!("{}{}", v, unit))
+    }
+
+    let width = reports
+        .iter()
+        .map(|r| r.name.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<w$}  {:>10}  {:>10}  {:>12}  ERROR",
+        "NAME",
+        "HANDSHAKE",
+        "TTFB",
+        "THROUGHPUT",
+        w = width
+    );
+    for r in reports {
+        let _ = writeln!(
+            out,
+            "{:<w$}  {:>10}  {:>10}  {:>12}  {}",
+            r.name,
+            cell(r.handshake, "ms"),
+            cell(r.ttfb, "ms"),
+            cell(r.throughput.map(|t| t / 1024), "KiB/s"),
+            r.error.as_ref().map_or("", String::as_str),
+            w = width
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn table_aligns_columns() {
+        let reports = vec![
+            Report {
+                name: "fast".to_owned(),
+                handshake: Some(20),
+                ttfb: Some(35),
+                throughput: Some(2048 * 1024),
+                error: None,
+            },
+            Report {
+                name: "down-proxy".to_owned(),
+                handshake: None,
+                ttfb: None,
+                throughput: None,
+                error: Some("timed out".to_owned()),
+            },
+        ];
+        let table = table(&reports);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("fast        "));
+        assert!(lines[1].contains("2048KiB/s"));
+        assert!(lines[2].ends_with("timed out"));
+    }
+
+    #[test]
+    fn measures_jitter() {
+        assert_eq!(jitter(&[]), None);
+        assert_eq!(jitter(&[40]), None);
+        assert_eq!(jitter(&[40, 40, 40]), Some(0));
+        assert_eq!(jitter(&[40, 50, 30, 30]), Some(10));
+    }
+}
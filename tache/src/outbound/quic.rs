@@ -0,0 +1,662 @@
+//! QUIC proxy client speaking TUIC v4
+//!
+//! Every stream and UDP relay to a server runs over one QUIC connection,
+//! opened on first use and again once it closes. The connection is
+//! authenticated on a unidirectional stream with the BLAKE3 hash of the
+//! password. A stream is a bidirectional QUIC stream starting with a
+//! `Connect` command the server answers, UDP packets are QUIC datagrams
+//! tagged with the association they belong to. With `zero-rtt` a resumed
+//! connection sends its first commands in 0-RTT data, which an observer
+//! could replay.
+//!
+//! quinn's congestion controller is NewReno. `brutal` keeps it from
+//! backing off, with a window that never shrinks, and paces what is sent at
+//! `up-mbps` instead, whatever the loss.
+
+use std::{
+    cmp,
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::{
+    channel::mpsc,
+    future::{BoxFuture, Future},
+    lock::Mutex as AsyncMutex,
+    ready, StreamExt,
+};
+use log::{debug, warn};
+use quinn_proto::{ClientConfig, TransportConfig};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::Delay,
+};
+
+use super::{other, server_name, BoxStream, Datagrams, Dialer, Outbound};
+use crate::{
+    config::Congestion,
+    protocol::quic::{Connection, RecvStream, SendStream},
+    rt::{self, delay_for},
+    utils::{Address, DomainName},
+};
+
+const VERSION: u8 = 4;
+const COMMAND_AUTHENTICATE: u8 = 0x00;
+const COMMAND_CONNECT: u8 = 0x01;
+const COMMAND_PACKET: u8 = 0x02;
+const COMMAND_DISSOCIATE: u8 = 0x03;
+const COMMAND_RESPONSE: u8 = 0xff;
+const RESPONSE_SUCCEEDED: u8 = 0x00;
+
+const ADDRESS_DOMAIN: u8 = 0x00;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_IPV6: u8 = 0x02;
+
+/// Connections close after this long without packets
+const IDLE_TIMEOUT: u64 = 30_000;
+/// PINGs keep quiet connections below the idle timeout of either side
+const KEEP_ALIVE_INTERVAL: u32 = 10_000;
+/// Round trip time the window of `brutal` has room for
+const BRUTAL_MAX_RTT: u64 = 500;
+/// Sends `brutal` lets through ahead of its rate
+const BURST: Duration = Duration::from_millis(10);
+
+/// Send rate of `brutal`, shared by the streams and datagrams of a proxy
+struct Pacer {
+    bytes_per_sec: u64,
+    /// When what was sent so far is paid for
+    next: Mutex<Instant>,
+}
+
+impl Pacer {
+    fn new(mbps: u64) -> Pacer {
+        Pacer {
+            bytes_per_sec: cmp::max(mbps * 1_000_000 / 8, 1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Account for `len` bytes sent, returning how long to hold off the next
+    /// send
+    fn sent(&self, len: usize) -> Duration {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        let cost = Duration::from_nanos(len as u64 * 1_000_000_000 / self.bytes_per_sec);
+        *next = cmp::max(*next, now) + cost;
+        next.saturating_duration_since(now)
+            .checked_sub(BURST)
+            .unwrap_or_default()
+    }
+}
+
+/// UDP relays of a connection, by association ID
+type Associations = Arc<Mutex<HashMap<u32, mpsc::UnboundedSender<(Address, Vec<u8>)>>>>;
+
+/// An open connection to the server
+#[derive(Clone)]
+struct Session {
+    connection: Connection,
+    associations: Associations,
+}
+
+pub struct Quic {
+    name: String,
+    server: Address,
+    sni: Option<String>,
+    /// BLAKE3 hash of the password
+    token: [u8; 32],
+    config: ClientConfig,
+    zero_rtt: bool,
+    udp: bool,
+    pacer: Option<Arc<Pacer>>,
+    session: AsyncMutex<Option<Session>>,
+    next_association: AtomicU32,
+}
+
+impl Quic {
+    /// Proxy at `server`, offering the ALPN protocols `alpn`
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: &str,
+        server: Address,
+        password: &str,
+        sni: Option<String>,
+        alpn: &[String],
+        congestion: Option<Congestion>,
+        up_mbps: Option<u64>,
+        zero_rtt: bool,
+        udp: bool,
+    ) -> io::Result<Quic> {
+        let pacer = match (congestion, up_mbps) {
+            (Some(Congestion::Brutal), Some(mbps)) => Some(Arc::new(Pacer::new(mbps))),
+            (Some(Congestion::Brutal), None) => {
+                return Err(other("brutal congestion control requires up-mbps"))
+            }
+            _ => None,
+        };
+
+        let mut transport = TransportConfig {
+            idle_timeout: IDLE_TIMEOUT,
+            keep_alive_interval: KEEP_ALIVE_INTERVAL,
+            ..TransportConfig::default()
+        };
+        if let Some(ref pacer) = pacer {
+            // Never below what the rate needs at the longest round trip, loss
+            // doesn't shrink it
+            let window = cmp::max(
+                pacer.bytes_per_sec * BRUTAL_MAX_RTT / 1000,
+                transport.initial_window,
+            );
+            transport.initial_window = window;
+            transport.minimum_window = window;
+            transport.loss_reduction_factor = u16::MAX;
+            transport.send_window = cmp::max(transport.send_window, window);
+        }
+
+        let mut crypto = rustls::ClientConfig::new();
+        crypto.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+        crypto.enable_early_data = zero_rtt;
+        crypto
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        crypto.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+        Ok(Quic {
+            name: name.to_owned(),
+            server,
+            sni,
+            token: *blake3::hash(password.as_bytes()).as_bytes(),
+            config: ClientConfig {
+                transport: Arc::new(transport),
+                crypto: Arc::new(crypto),
+            },
+            zero_rtt,
+            udp,
+            pacer,
+            session: AsyncMutex::new(None),
+            next_association: AtomicU32::new(0),
+        })
+    }
+
+    fn server_name(&self) -> io::Result<&str> {
+        match self.sni {
+            Some(ref sni) => Ok(sni),
+            None => server_name(&self.server),
+        }
+    }
+
+    /// The open connection, a new one if there is none
+    async fn session(&self) -> io::Result<Session> {
+        let mut session = self.session.lock().await;
+        if let Some(ref session) = *session {
+            if !session.connection.is_closed() {
+                return Ok(session.clone());
+            }
+        }
+        let opened = self.connect().await?;
+        *session = Some(opened.clone());
+        Ok(opened)
+    }
+
+    async fn connect(&self) -> io::Result<Session> {
+        let server_name = self.server_name()?;
+        let addr = self
+            .server
+            .lookup()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| other("resolved to empty address"))?;
+        let connection =
+            Connection::connect(self.config.clone(), addr, server_name, self.zero_rtt).await?;
+        if connection.is_0rtt() {
+            debug!("Resumed the QUIC session of proxy {} with 0-RTT", self.name);
+        }
+
+        let mut auth = connection.open_uni().await?;
+        let mut command = vec![VERSION, COMMAND_AUTHENTICATE];
+        command.extend_from_slice(&self.token);
+        auth.write_all(&command).await?;
+        auth.shutdown().await?;
+
+        let associations: Associations = Arc::default();
+        let incoming = associations.clone();
+        let datagrams = connection.clone();
+        rt::spawn(async move {
+            while let Some(datagram) = datagrams.recv_datagram().await {
+                let (association, peer, payload) = match read_packet(&datagram) {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        debug!("Dropped a datagram from the server, err: {}", e);
+                        continue;
+                    }
+                };
+                let relay = incoming.lock().unwrap().get(&association).cloned();
+                if let Some(relay) = relay {
+                    let _ = relay.unbounded_send((peer, payload.to_vec()));
+                }
+            }
+        });
+
+        Ok(Session {
+            connection,
+            associations,
+        })
+    }
+}
+
+fn write_address(buf: &mut Vec<u8>, target: &Address) -> io::Result<()> {
+    match *target {
+        Address::SocketAddr(SocketAddr::V4(ref addr)) => {
+            buf.push(ADDRESS_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        Address::SocketAddr(SocketAddr::V6(ref addr)) => {
+            buf.push(ADDRESS_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        Address::DomainName(ref dn) => {
+            if dn.0.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "domain name too long",
+                ));
+            }
+            buf.push(ADDRESS_DOMAIN);
+            buf.push(dn.0.len() as u8);
+            buf.extend_from_slice(dn.0.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&target.port().to_be_bytes());
+    Ok(())
+}
+
+/// Address at the start of `buf` and the bytes it takes
+fn read_address(buf: &[u8]) -> io::Result<(Address, usize)> {
+    let short = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated address");
+    let (ip, len): (IpAddr, usize) = match buf.first() {
+        Some(&ADDRESS_IPV4) if buf.len() >= 1 + 4 + 2 => {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(&buf[1..5]);
+            (octets.into(), 1 + 4)
+        }
+        Some(&ADDRESS_IPV6) if buf.len() >= 1 + 16 + 2 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&buf[1..17]);
+            (octets.into(), 1 + 16)
+        }
+        Some(&ADDRESS_DOMAIN) => {
+            let len = *buf.get(1).ok_or_else(short)? as usize;
+            if buf.len() < 2 + len + 2 {
+                return Err(short());
+            }
+            let domain = String::from_utf8(buf[2..2 + len].to_vec())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid domain"))?;
+            let port = u16::from_be_bytes([buf[2 + len], buf[3 + len]]);
+            return Ok((Address::DomainName(DomainName(domain, port)), 2 + len + 2));
+        }
+        Some(&ADDRESS_IPV4) | Some(&ADDRESS_IPV6) | None => return Err(short()),
+        Some(kind) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown address type {}", kind),
+            ))
+        }
+    };
+    let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
+    Ok((Address::SocketAddr(SocketAddr::new(ip, port)), len + 2))
+}
+
+/// `Packet` command of `payload` from or to `peer`
+fn packet(association: u32, peer: &Address, payload: &[u8]) -> io::Result<Vec<u8>> {
+    if payload.len() > u16::MAX as usize {
+        return Err(other("datagram too large"));
+    }
+    let mut buf = Vec::with_capacity(2 + 4 + 2 + 1 + 255 + 2 + payload.len());
+    buf.push(VERSION);
+    buf.push(COMMAND_PACKET);
+    buf.extend_from_slice(&association.to_be_bytes());
+    buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    write_address(&mut buf, peer)?;
+    buf.extend_from_slice(payload);
+    Ok(buf)
+}
+
+/// Association, peer and payload of a `Packet` command
+fn read_packet(buf: &[u8]) -> io::Result<(u32, Address, &[u8])> {
+    if buf.len() < 2 + 4 + 2 || buf[0] != VERSION || buf[1] != COMMAND_PACKET {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a packet command",
+        ));
+    }
+    let association = u32::from_be_bytes([buf[2], buf[3], buf[4], buf[5]]);
+    let len = u16::from_be_bytes([buf[6], buf[7]]) as usize;
+    let (peer, n) = read_address(&buf[8..])?;
+    let payload = &buf[8 + n..];
+    if payload.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "packet length mismatch",
+        ));
+    }
+    Ok((association, peer, payload))
+}
+
+/// Stream to a target over a bidirectional QUIC stream
+struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    pacer: Option<Arc<Pacer>>,
+    /// Holds writes back to the rate of `brutal`
+    delay: Option<Delay>,
+    /// Keeps the connection open
+    _session: Session,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(ref mut delay) = this.delay {
+            ready!(Pin::new(delay).poll(cx));
+            this.delay = None;
+        }
+        let n = ready!(Pin::new(&mut this.send).poll_write(cx, buf))?;
+        if let Some(ref pacer) = this.pacer {
+            let wait = pacer.sent(n);
+            if wait > Duration::from_secs(0) {
+                this.delay = Some(delay_for(wait));
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+impl Drop for Quic {
+    /// The datagram reader would keep the connection open otherwise
+    fn drop(&mut self) {
+        if let Some(session) = self.session.get_mut().take() {
+            session.connection.close();
+        }
+    }
+}
+
+impl Outbound for Quic {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn udp(&self) -> bool {
+        self.udp
+    }
+
+    /// QUIC goes out on a UDP socket of its own, `dialer` isn't used
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        _dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let session = self.session().await?;
+            let (mut send, mut recv) = session.connection.open_bi().await?;
+            let mut command = vec![VERSION, COMMAND_CONNECT];
+            write_address(&mut command, target)?;
+            send.write_all(&command).await?;
+
+            let mut response = [0; 3];
+            recv.read_exact(&mut response).await?;
+            if response[..2] != [VERSION, COMMAND_RESPONSE] {
+                return Err(other("invalid response from the server"));
+            }
+            if response[2] != RESPONSE_SUCCEEDED {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("proxy {} failed to connect to {}", self.name, target),
+                ));
+            }
+            Ok(Box::new(QuicStream {
+                send,
+                recv,
+                pacer: self.pacer.clone(),
+                delay: None,
+                _session: session,
+            }) as BoxStream)
+        })
+    }
+
+    /// An association of its own, dissociated once `Datagrams` is dropped
+    fn bind(&self) -> BoxFuture<'_, io::Result<Datagrams>> {
+        Box::pin(async move {
+            if !self.udp {
+                return Err(other(format!("proxy {} has no UDP relay", self.name)));
+            }
+            let session = self.session().await?;
+            let association = self.next_association.fetch_add(1, Ordering::Relaxed);
+            let (incoming, recv) = mpsc::unbounded();
+            session
+                .associations
+                .lock()
+                .unwrap()
+                .insert(association, incoming);
+
+            let (send, mut outgoing) = mpsc::unbounded::<(Address, Vec<u8>)>();
+            let pacer = self.pacer.clone();
+            rt::spawn(async move {
+                while let Some((peer, payload)) = outgoing.next().await {
+                    let datagram = match packet(association, &peer, &payload) {
+                        Ok(datagram) => datagram,
+                        Err(e) => {
+                            debug!("Dropped datagram to {}, err: {}", peer, e);
+                            continue;
+                        }
+                    };
+                    if let Some(ref pacer) = pacer {
+                        let wait = pacer.sent(datagram.len());
+                        if wait > Duration::from_secs(0) {
+                            delay_for(wait).await;
+                        }
+                    }
+                    let sent = session.connection.send_datagram(Bytes::from(datagram));
+                    if let Err(e) = sent {
+                        debug!("Dropped datagram to {}, err: {}", peer, e);
+                    }
+                }
+
+                session.associations.lock().unwrap().remove(&association);
+                let mut command = vec![VERSION, COMMAND_DISSOCIATE];
+                command.extend_from_slice(&association.to_be_bytes());
+                let dissociated = async {
+                    let mut stream = session.connection.open_uni().await?;
+                    stream.write_all(&command).await?;
+                    stream.shutdown().await
+                };
+                if let Err(e) = dissociated.await {
+                    warn!("Failed to end UDP association {}, err: {}", association, e);
+                }
+            });
+            Ok(Datagrams { send, recv })
+        })
+    }
+
+    fn alive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quinn_proto::ServerConfig;
+
+    use super::*;
+    use crate::{
+        outbound::TcpDialer,
+        rt::{Runtime, UdpSocket},
+    };
+
+    impl Quic {
+        /// Trust the self-signed certificate `der` of a test server
+        fn trust(&mut self, der: &[u8]) {
+            let crypto = Arc::make_mut(&mut self.config.crypto);
+            crypto
+                .root_store
+                .add(&rustls::Certificate(der.to_vec()))
+                .unwrap();
+        }
+    }
+
+    /// Answer the `Connect` of one stream and echo it, and echo one
+    /// datagram, checking the token first
+    async fn serve(conn: Connection, token: [u8; 32]) {
+        let mut auth = Vec::new();
+        let mut uni = conn.accept_uni().await.unwrap();
+        uni.read_to_end(&mut auth).await.unwrap();
+        assert_eq!(auth[..2], [VERSION, COMMAND_AUTHENTICATE]);
+        assert_eq!(auth[2..], token);
+
+        let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+        let mut connect = [0; 2 + 1 + 4 + 2];
+        recv.read_exact(&mut connect).await.unwrap();
+        assert_eq!(connect, [VERSION, COMMAND_CONNECT, 1, 127, 0, 0, 1, 0, 80]);
+        send.write_all(&[VERSION, COMMAND_RESPONSE, RESPONSE_SUCCEEDED])
+            .await
+            .unwrap();
+        let mut ping = [0; 4];
+        recv.read_exact(&mut ping).await.unwrap();
+        send.write_all(&ping).await.unwrap();
+
+        let datagram = conn.recv_datagram().await.unwrap();
+        let (association, peer, payload) = read_packet(&datagram).unwrap();
+        assert_eq!(peer.to_string(), "example.com:53");
+        let echo = packet(association, &peer, payload).unwrap();
+        conn.send_datagram(Bytes::from(echo)).unwrap();
+
+        // Until the client dissociates
+        let mut dissociate = Vec::new();
+        let mut uni = conn.accept_uni().await.unwrap();
+        uni.read_to_end(&mut dissociate).await.unwrap();
+        assert_eq!(dissociate[..2], [VERSION, COMMAND_DISSOCIATE]);
+        assert_eq!(dissociate[2..], association.to_be_bytes());
+    }
+
+    #[test]
+    fn packet_roundtrip() {
+        for peer in &[
+            Address::SocketAddr(([1, 2, 3, 4], 53).into()),
+            Address::SocketAddr(("::1".parse::<IpAddr>().unwrap(), 443).into()),
+            Address::DomainName(DomainName("example.com".to_owned(), 8080)),
+        ] {
+            let buf = packet(7, peer, b"payload").unwrap();
+            let (association, read, payload) = read_packet(&buf).unwrap();
+            assert_eq!(association, 7);
+            assert_eq!(read.to_string(), peer.to_string());
+            assert_eq!(payload, b"payload");
+            assert!(read_packet(&buf[..buf.len() - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn relays_streams_and_datagrams() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let key = cert.serialize_private_key_der();
+        let mut server = ServerConfig::default();
+        Arc::make_mut(&mut server.crypto)
+            .set_single_cert(
+                vec![rustls::Certificate(der.clone())],
+                rustls::PrivateKey(key),
+            )
+            .unwrap();
+
+        Runtime::new().unwrap().block_on(async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let mut quic = Quic::new(
+                "quic",
+                Address::SocketAddr(addr),
+                "password",
+                Some("localhost".to_owned()),
+                &[],
+                Some(Congestion::Brutal),
+                Some(100),
+                true,
+                true,
+            )
+            .unwrap();
+            quic.trust(&der);
+
+            let token = quic.token;
+            let server = async {
+                let conn = Connection::accept(socket, server).await.unwrap();
+                serve(conn, token).await;
+            };
+            let client = async {
+                let target = Address::SocketAddr(([127, 0, 0, 1], 80).into());
+                let mut stream = quic.dial(&target, &TcpDialer).await.unwrap();
+                stream.write_all(b"ping").await.unwrap();
+                let mut pong = [0; 4];
+                stream.read_exact(&mut pong).await.unwrap();
+                assert_eq!(&pong, b"ping");
+
+                let Datagrams { send, mut recv } = quic.bind().await.unwrap();
+                let peer = Address::DomainName(DomainName("example.com".to_owned(), 53));
+                send.unbounded_send((peer.clone(), b"query".to_vec()))
+                    .unwrap();
+                let (from, payload) = recv.next().await.unwrap();
+                assert_eq!(from.to_string(), peer.to_string());
+                assert_eq!(payload, b"query");
+            };
+            futures::join!(server, client);
+
+            // The ticket of the first connection lets the next one send
+            // right away
+            let resumed = Connection::connect(quic.config.clone(), addr, "localhost", true)
+                .await
+                .unwrap();
+            assert!(resumed.is_0rtt());
+        });
+
+        assert!(Quic::new(
+            "quic",
+            Address::SocketAddr(([127, 0, 0, 1], 443).into()),
+            "password",
+            None,
+            &[],
+            Some(Congestion::Brutal),
+            None,
+            false,
+            false,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,569 @@
+//! Shadow TLS v3 client transport
+//!
+//! The shadow-tls server relays our handshake to a real TLS server, so the
+//! connection carries that server's certificate. The server recognises us by
+//! an HMAC hidden in the ClientHello session id and from then on both sides
+//! exchange application data records tagged with HMACs keyed by the password
+//! and the ServerRandom of the borrowed handshake.
+//!
+//! The borrowed handshake is not verified, the record HMACs authenticate the
+//! shadow-tls server instead. The inner proxy protocol provides the privacy.
+
+use std::{
+    cmp, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use futures::ready;
+use hmac::Mac;
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::hello::{self, Profile};
+use crate::{
+    config::{ClientFingerprint, ShadowTlsConfig},
+    crypto::HmacSha1,
+};
+
+const CHANGE_CIPHER_SPEC: u8 = 0x14;
+const ALERT: u8 = 0x15;
+const HANDSHAKE: u8 = 0x16;
+const APPLICATION_DATA: u8 = 0x17;
+
+const HEADER_LEN: usize = 5;
+const TAG_LEN: usize = 4;
+/// Largest TLS 1.3 ciphertext record
+const MAX_RECORD_LEN: usize = 16384 + 256;
+/// Data per record we send, the tag included it stays below a plaintext record
+const MAX_DATA_LEN: usize = 16384 - TAG_LEN;
+
+/// Offset of the session id inside the ClientHello handshake message
+const SESSION_ID_OFFSET: usize = 4 + 2 + 32 + 1;
+const SESSION_ID_LEN: usize = 32;
+
+/// ServerRandom of a HelloRetryRequest, RFC 8446 4.1.3
+const HELLO_RETRY_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+fn invalid(desc: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, desc)
+}
+
+fn hmac(password: &str) -> HmacSha1 {
+    HmacSha1::new_varkey(password.as_bytes()).expect("HMAC takes keys of any length")
+}
+
+/// HMAC chain tagging the data records of one direction
+#[derive(Clone)]
+struct RecordMac(HmacSha1);
+
+impl RecordMac {
+    fn new(password: &str, server_random: &[u8], direction: &[u8]) -> RecordMac {
+        let mut mac = hmac(password);
+        mac.input(server_random);
+        mac.input(direction);
+        RecordMac(mac)
+    }
+
+    /// Tag of `data` without advancing the chain
+    fn peek(&self, data: &[u8]) -> [u8; TAG_LEN] {
+        let mut mac = self.0.clone();
+        mac.input(data);
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&mac.result().code()[..TAG_LEN]);
+        tag
+    }
+
+    /// Tag of `data`, chaining it into the following tags
+    fn next(&mut self, data: &[u8]) -> [u8; TAG_LEN] {
+        let tag = self.peek(data);
+        self.0.input(data);
+        self.0.input(&tag);
+        tag
+    }
+}
+
+/// ClientHello for `host` shaped by `profile`, with the authentication tag
+/// in its session id
+fn client_hello(host: &str, password: &str, profile: &Profile) -> io::Result<Vec<u8>> {
+    if host.is_empty() || host.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid shadow-tls host",
+        ));
+    }
+
+    let mut hello_random = [0u8; 32];
+    let mut session_id = [0u8; SESSION_ID_LEN];
+    let mut key_share = [0u8; 32];
+    let mut rng = rand::thread_rng();
+    rng.fill_bytes(&mut hello_random);
+    rng.fill_bytes(&mut session_id[..SESSION_ID_LEN - TAG_LEN]);
+    rng.fill_bytes(&mut key_share);
+    let mut message = hello::client_hello(profile, host, &hello_random, &session_id, &key_share);
+
+    // Tagged while the tag bytes are still zero
+    let mut mac = hmac(password);
+    mac.input(&message);
+    let end = SESSION_ID_OFFSET + SESSION_ID_LEN;
+    message[end - TAG_LEN..end].copy_from_slice(&mac.result().code()[..TAG_LEN]);
+
+    let mut record = vec![HANDSHAKE, 3, 1];
+    record.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    record.extend_from_slice(&message);
+    Ok(record)
+}
+
+/// ServerRandom of a TLS 1.3 ServerHello
+fn server_random(payload: &[u8]) -> io::Result<[u8; 32]> {
+    let short = || invalid("truncated ServerHello");
+    if payload.first() != Some(&0x02) {
+        return Err(invalid("expect ServerHello"));
+    }
+    let body = payload.get(4..).ok_or_else(short)?;
+    let mut random = [0u8; 32];
+    random.copy_from_slice(body.get(2..34).ok_or_else(short)?);
+    if random == HELLO_RETRY_RANDOM {
+        return Err(invalid("handshake server asked to retry the handshake"));
+    }
+
+    let session_len = *body.get(34).ok_or_else(short)? as usize;
+    // session id, cipher suite and compression method
+    let mut pos = 35 + session_len + 3;
+    let ext_len = body.get(pos..pos + 2).ok_or_else(short)?;
+    let end = pos + 2 + u16::from_be_bytes([ext_len[0], ext_len[1]]) as usize;
+    pos += 2;
+    while pos + 4 <= end {
+        let ext = body.get(pos..pos + 4).ok_or_else(short)?;
+        let kind = u16::from_be_bytes([ext[0], ext[1]]);
+        let len = u16::from_be_bytes([ext[2], ext[3]]) as usize;
+        pos += 4;
+        if kind == 0x002b && body.get(pos..pos + len) == Some(&[3, 4][..]) {
+            return Ok(random);
+        }
+        pos += len;
+    }
+    Err(invalid("handshake server does not support TLS 1.3"))
+}
+
+async fn read_record<S>(stream: &mut S) -> io::Result<(u8, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if len > MAX_RECORD_LEN {
+        return Err(invalid("TLS record too long"));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    Ok((header[0], payload))
+}
+
+/// Run the borrowed handshake over `stream`, connected to the shadow-tls
+/// server, with a ClientHello like the browser of `fingerprint`
+pub async fn connect<S>(
+    mut stream: S,
+    config: &ShadowTlsConfig,
+    fingerprint: Option<ClientFingerprint>,
+) -> io::Result<ShadowTlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let profile = fingerprint.map_or(&hello::DEFAULT, hello::profile);
+    stream
+        .write_all(&client_hello(&config.host, &config.password, profile)?)
+        .await?;
+
+    let server_random = match read_record(&mut stream).await? {
+        (HANDSHAKE, payload) => server_random(&payload)?,
+        (ALERT, _) => return Err(invalid("handshake server rejected the ClientHello")),
+        _ => return Err(invalid("expect ServerHello")),
+    };
+
+    // The shadow-tls server tags the handshake server's encrypted records,
+    // an untagged one means it did not accept our ClientHello. Their masked
+    // content is the handshake server's flight, which is not verified.
+    let handshake_mac = RecordMac::new(&config.password, &server_random, b"");
+    loop {
+        match read_record(&mut stream).await? {
+            (CHANGE_CIPHER_SPEC, _) => continue,
+            (APPLICATION_DATA, payload) => {
+                if payload.len() < TAG_LEN
+                    || handshake_mac.peek(&payload[TAG_LEN..]) != payload[..TAG_LEN]
+                {
+                    return Err(invalid(
+                        "shadow-tls server authentication failed, check the password",
+                    ));
+                }
+                break;
+            }
+            (ALERT, _) => return Err(invalid("handshake server aborted the handshake")),
+            _ => return Err(invalid("unexpected TLS record in handshake")),
+        }
+    }
+
+    // Finish like a TLS 1.3 client would, a Finished message of SHA-256
+    // suites is 53 bytes encrypted
+    let mut finished = vec![
+        CHANGE_CIPHER_SPEC,
+        3,
+        3,
+        0,
+        1,
+        1,
+        APPLICATION_DATA,
+        3,
+        3,
+        0,
+        53,
+    ];
+    finished.resize(finished.len() + 53, 0);
+    let len = finished.len();
+    rand::thread_rng().fill_bytes(&mut finished[len - 53..]);
+    stream.write_all(&finished).await?;
+
+    Ok(ShadowTlsStream {
+        stream,
+        read_mac: RecordMac::new(&config.password, &server_random, b"S"),
+        write_mac: RecordMac::new(&config.password, &server_random, b"C"),
+        handshake_mac,
+        read_buf: BytesMut::new(),
+        plain: BytesMut::new(),
+        write_buf: Vec::new(),
+        write_pos: 0,
+    })
+}
+
+/// Data records of an established shadow-tls connection
+pub struct ShadowTlsStream<S> {
+    stream: S,
+    read_mac: RecordMac,
+    write_mac: RecordMac,
+    /// Remaining records of the borrowed handshake carry this tag
+    handshake_mac: RecordMac,
+    read_buf: BytesMut,
+    /// Data of the current record not yet returned
+    plain: BytesMut,
+    /// A record being written
+    write_buf: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ShadowTlsStream<S> {
+    /// Move the next data record into `plain`, `false` on EOF
+    fn poll_record(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            if self.read_buf.len() >= HEADER_LEN {
+                let len = u16::from_be_bytes([self.read_buf[3], self.read_buf[4]]) as usize;
+                if len > MAX_RECORD_LEN {
+                    return Poll::Ready(Err(invalid("TLS record too long")));
+                }
+                if self.read_buf.len() >= HEADER_LEN + len {
+                    let kind = self.read_buf[0];
+                    self.read_buf.advance(HEADER_LEN);
+                    let mut payload = self.read_buf.split_to(len);
+                    match kind {
+                        APPLICATION_DATA if len >= TAG_LEN => {
+                            let data = &payload[TAG_LEN..];
+                            if self.read_mac.peek(data) == payload[..TAG_LEN] {
+                                self.read_mac.next(data);
+                                payload.advance(TAG_LEN);
+                                self.plain = payload;
+                                return Poll::Ready(Ok(true));
+                            }
+                            if self.handshake_mac.peek(data) != payload[..TAG_LEN] {
+                                return Poll::Ready(Err(invalid("shadow-tls record tag mismatch")));
+                            }
+                        }
+                        // The server closes like a TLS server would
+                        ALERT => return Poll::Ready(Ok(false)),
+                        CHANGE_CIPHER_SPEC | HANDSHAKE => {}
+                        _ => return Poll::Ready(Err(invalid("unexpected TLS record"))),
+                    }
+                    continue;
+                }
+            }
+
+            let mut buf = [0u8; 4096];
+            let n = ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+            if n == 0 {
+                return if self.read_buf.is_empty() {
+                    Poll::Ready(Ok(false))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            self.read_buf.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Write out the pending record
+    fn poll_write_record(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for ShadowTlsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.plain.is_empty() {
+            if !ready!(this.poll_record(cx))? {
+                return Poll::Ready(Ok(0));
+            }
+        }
+        let n = cmp::min(buf.len(), this.plain.len());
+        buf[..n].copy_from_slice(&this.plain[..n]);
+        this.plain.advance(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ShadowTlsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_record(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let data = &buf[..cmp::min(buf.len(), MAX_DATA_LEN)];
+        let tag = this.write_mac.next(data);
+        this.write_buf.extend_from_slice(&[APPLICATION_DATA, 3, 3]);
+        this.write_buf
+            .extend_from_slice(&((TAG_LEN + data.len()) as u16).to_be_bytes());
+        this.write_buf.extend_from_slice(&tag);
+        this.write_buf.extend_from_slice(data);
+        // The record is accepted once buffered, later calls finish writing it
+        if let Poll::Ready(Err(e)) = this.poll_write_record(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_record(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_record(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use std::net::SocketAddr;
+
+    use futures::future::join;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{
+        crypto::CipherKind,
+        outbound::{
+            shadowsocks::{master_key, AeadStream},
+            socks5::read_address,
+            Outbound, Shadowsocks, TcpDialer,
+        },
+        rt::{self, Runtime},
+        utils::{Address, DomainName},
+    };
+
+    /// TLS 1.3 ServerHello record with `random`
+    fn server_hello(random: &[u8; 32]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(random);
+        // No session id, TLS_AES_128_GCM_SHA256, no compression
+        body.extend_from_slice(&[0, 0x13, 0x01, 0]);
+        // supported_versions of TLS 1.3
+        body.extend_from_slice(&[0, 6, 0, 0x2b, 0, 2, 3, 4]);
+        let mut message = vec![0x02, 0, 0, body.len() as u8];
+        message.extend_from_slice(&body);
+        record(HANDSHAKE, &message)
+    }
+
+    fn record(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut record = vec![kind, 3, 3];
+        record.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        record.extend_from_slice(payload);
+        record
+    }
+
+    /// Shadow-tls server of `password`, checking the client's tags and
+    /// relaying its data to `upstream`
+    pub(in crate::outbound) async fn serve_shadow_tls(
+        mut client: TcpStream,
+        password: &str,
+        upstream: SocketAddr,
+    ) {
+        let (kind, mut hello) = read_record(&mut client).await.unwrap();
+        assert_eq!(kind, HANDSHAKE);
+        let end = SESSION_ID_OFFSET + SESSION_ID_LEN;
+        let tag = hello[end - TAG_LEN..end].to_vec();
+        for b in &mut hello[end - TAG_LEN..end] {
+            *b = 0;
+        }
+        let mut mac = hmac(password);
+        mac.input(&hello);
+        assert_eq!(&mac.result().code()[..TAG_LEN], &tag[..]);
+
+        // The handshake server's flight, tagged by the shadow-tls server
+        let random = [9u8; 32];
+        client.write_all(&server_hello(&random)).await.unwrap();
+        let flight = b"encrypted extensions and certificate";
+        let mut payload = RecordMac::new(password, &random, b"").peek(flight).to_vec();
+        payload.extend_from_slice(flight);
+        client
+            .write_all(&record(APPLICATION_DATA, &payload))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_record(&mut client).await.unwrap().0,
+            CHANGE_CIPHER_SPEC
+        );
+        assert_eq!(read_record(&mut client).await.unwrap().0, APPLICATION_DATA);
+
+        let mut upstream = TcpStream::connect(upstream).await.unwrap();
+        let (mut client_read, mut client_write) = client.split();
+        let (mut upstream_read, mut upstream_write) = upstream.split();
+        let mut read_mac = RecordMac::new(password, &random, b"C");
+        let mut write_mac = RecordMac::new(password, &random, b"S");
+        let up = async {
+            while let Ok((APPLICATION_DATA, payload)) = read_record(&mut client_read).await {
+                let data = &payload[TAG_LEN..];
+                assert_eq!(read_mac.next(data), payload[..TAG_LEN]);
+                upstream_write.write_all(data).await.unwrap();
+            }
+        };
+        let down = async {
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = upstream_read.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                let mut payload = write_mac.next(&buf[..n]).to_vec();
+                payload.extend_from_slice(&buf[..n]);
+                client_write
+                    .write_all(&record(APPLICATION_DATA, &payload))
+                    .await
+                    .unwrap();
+            }
+        };
+        join(up, down).await;
+    }
+
+    #[test]
+    fn shadowsocks_dials_through_shadow_tls() {
+        Runtime::new().unwrap().block_on(async {
+            let kind = CipherKind::ChaCha20Poly1305;
+            let mut shadowsocks = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let upstream = shadowsocks.local_addr().unwrap();
+            rt::spawn(async move {
+                let (stream, _) = shadowsocks.accept().await.unwrap();
+                let key = master_key("ss-secret", kind.key_len());
+                let mut stream = AeadStream::new(stream, kind, &key).unwrap();
+                let mut head = [0u8; 1 + 1 + 18 + 2];
+                stream.read_exact(&mut head).await.unwrap();
+                let (target, _) = read_address(&head).unwrap();
+                assert_eq!(target.to_string(), "origin.example.com:80");
+                let mut ping = [0u8; 4];
+                stream.read_exact(&mut ping).await.unwrap();
+                assert_eq!(&ping, b"ping");
+                stream.write_all(b"pong").await.unwrap();
+            });
+
+            let mut shadow_tls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = shadow_tls.local_addr().unwrap();
+            rt::spawn(async move {
+                let (client, _) = shadow_tls.accept().await.unwrap();
+                serve_shadow_tls(client, "stls-secret", upstream).await;
+            });
+
+            let outbound = Shadowsocks::new(
+                "ss",
+                Address::SocketAddr(server),
+                "chacha20-ietf-poly1305",
+                "ss-secret",
+                false,
+                CipherKind::Aes128Gcm,
+            )
+            .unwrap()
+            .shadow_tls(
+                Some(ShadowTlsConfig {
+                    password: "stls-secret".to_owned(),
+                    host: "www.example.com".to_owned(),
+                }),
+                Some(ClientFingerprint::Chrome),
+            );
+            let target = Address::DomainName(DomainName("origin.example.com".to_owned(), 80));
+            let mut stream = outbound.dial(&target, &TcpDialer).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut pong = [0u8; 4];
+            stream.read_exact(&mut pong).await.unwrap();
+            assert_eq!(&pong, b"pong");
+        });
+    }
+
+    #[test]
+    fn client_hello_carries_tag() {
+        for profile in &[&hello::DEFAULT, &hello::CHROME] {
+            let record = client_hello("www.example.com", "secret", profile).unwrap();
+            assert_eq!(record[0], HANDSHAKE);
+            let mut message = record[HEADER_LEN..].to_vec();
+            let end = SESSION_ID_OFFSET + SESSION_ID_LEN;
+            let tag = message[end - TAG_LEN..end].to_vec();
+            for b in &mut message[end - TAG_LEN..end] {
+                *b = 0;
+            }
+            let mut mac = hmac("secret");
+            mac.input(&message);
+            assert_eq!(&mac.result().code()[..TAG_LEN], &tag[..]);
+        }
+    }
+
+    #[test]
+    fn record_tags_chain() {
+        let random = [7u8; 32];
+        let mut writer = RecordMac::new("secret", &random, b"C");
+        let mut reader = RecordMac::new("secret", &random, b"C");
+        let first = writer.next(b"hello");
+        let second = writer.next(b"hello");
+        assert_ne!(first, second);
+        assert_eq!(reader.peek(b"hello"), first);
+        reader.next(b"hello");
+        assert_eq!(reader.peek(b"hello"), second);
+        assert_ne!(
+            RecordMac::new("secret", &random, b"S").peek(b"hello"),
+            first
+        );
+    }
+}
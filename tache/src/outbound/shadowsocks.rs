@@ -0,0 +1,504 @@
+//! Shadowsocks client of AEAD streams and the UDP relay
+//!
+//! Every datagram is sealed on its own with a fresh random salt, a subkey
+//! derived from it and the password and a zero nonce, so no nonce or session
+//! state is shared between packets or peers. The SOCKS5 address of the peer
+//! goes ahead of the payload.
+//!
+//! Each direction of a stream starts with its own salt, followed by chunks
+//! of a sealed length and a sealed payload under a nonce counting up. The
+//! client's first chunk is the target address.
+
+use std::{
+    cmp, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BytesMut};
+use futures::{channel::mpsc, future::BoxFuture, ready, StreamExt};
+use hkdf::Hkdf;
+use log::debug;
+use md5::{Digest, Md5};
+use rand::RngCore;
+use sha1::Sha1;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use super::{
+    other, shadow_tls,
+    socks5::{read_address, write_address},
+    BoxStream, Datagrams, Dialer, Outbound,
+};
+use crate::{
+    config::{ClientFingerprint, ShadowTlsConfig},
+    crypto::{Cipher, CipherKind, NONCE_LEN, TAG_LEN},
+    rt::{self, UdpSocket},
+    utils::Address,
+};
+
+/// Relays without replies for this long are closed
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+
+/// Largest payload of a stream chunk
+const MAX_CHUNK_LEN: usize = 0x3fff;
+
+pub struct Shadowsocks {
+    name: String,
+    server: Address,
+    kind: CipherKind,
+    key: Arc<Vec<u8>>,
+    udp: bool,
+    /// Streams run inside shadow-tls, `server` is then the shadow-tls server
+    shadow_tls: Option<ShadowTlsConfig>,
+    fingerprint: Option<ClientFingerprint>,
+}
+
+impl Shadowsocks {
+    /// Proxy at `server`, `preferred` is the cipher of `auto`
+    pub fn new(
+        name: &str,
+        server: Address,
+        cipher: &str,
+        password: &str,
+        udp: bool,
+        preferred: CipherKind,
+    ) -> io::Result<Shadowsocks> {
+        let kind = CipherKind::select(cipher, preferred)
+            .ok_or_else(|| other(format!("cipher {} is not supported", cipher)))?;
+        Ok(Shadowsocks {
+            name: name.to_owned(),
+            server,
+            kind,
+            key: Arc::new(master_key(password, kind.key_len())),
+            udp,
+            shadow_tls: None,
+            fingerprint: None,
+        })
+    }
+
+    /// Borrow a handshake with a ClientHello like the browser of
+    /// `fingerprint` before each stream
+    pub fn shadow_tls(
+        mut self,
+        config: Option<ShadowTlsConfig>,
+        fingerprint: Option<ClientFingerprint>,
+    ) -> Shadowsocks {
+        self.shadow_tls = config;
+        self.fingerprint = fingerprint;
+        self
+    }
+}
+
+/// Key of `password`, OpenSSL's `EVP_BytesToKey` with MD5 and one round
+pub fn master_key(password: &str, len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(len + 16);
+    let mut last: Vec<u8> = Vec::new();
+    while key.len() < len {
+        let mut md5 = Md5::new();
+        md5.input(&last);
+        md5.input(password.as_bytes());
+        last = md5.result().to_vec();
+        key.extend_from_slice(&last);
+    }
+    key.truncate(len);
+    key
+}
+
+/// Cipher of one salt, HKDF-SHA1 of the master key
+fn session(kind: CipherKind, key: &[u8], salt: &[u8]) -> io::Result<Cipher> {
+    let mut subkey = vec![0; kind.key_len()];
+    Hkdf::<Sha1>::new(Some(salt), key)
+        .expand(SUBKEY_INFO, &mut subkey)
+        .map_err(|_| other("invalid subkey length"))?;
+    Cipher::new(kind, &subkey)
+}
+
+/// Datagram of `payload` to `peer` as sent to the server
+pub fn seal_packet(
+    kind: CipherKind,
+    key: &[u8],
+    peer: &Address,
+    payload: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut salt = vec![0; kind.key_len()];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut data = Vec::with_capacity(payload.len() + 24);
+    write_address(&mut data, peer)?;
+    data.extend_from_slice(payload);
+    session(kind, key, &salt)?.seal(&[0; NONCE_LEN], &mut data)?;
+    salt.extend_from_slice(&data);
+    Ok(salt)
+}
+
+/// Peer and payload of a datagram received from the server
+pub fn open_packet(kind: CipherKind, key: &[u8], packet: &[u8]) -> io::Result<(Address, Vec<u8>)> {
+    if packet.len() < kind.key_len() {
+        return Err(other("shadowsocks packet too short"));
+    }
+    let (salt, sealed) = packet.split_at(kind.key_len());
+    let mut data = sealed.to_vec();
+    session(kind, key, salt)?.open(&[0; NONCE_LEN], &mut data)?;
+    let (peer, len) = read_address(&data)?;
+    Ok((peer, data.split_off(len)))
+}
+
+/// Subkey of one direction of a stream and the nonce of its next message
+struct Session {
+    cipher: Cipher,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl Session {
+    fn new(kind: CipherKind, key: &[u8], salt: &[u8]) -> io::Result<Session> {
+        Ok(Session {
+            cipher: session(kind, key, salt)?,
+            nonce: [0; NONCE_LEN],
+        })
+    }
+
+    fn seal(&mut self, data: &mut Vec<u8>) -> io::Result<()> {
+        self.cipher.seal(&self.nonce, data)?;
+        self.advance();
+        Ok(())
+    }
+
+    fn open(&mut self, data: &mut Vec<u8>) -> io::Result<()> {
+        self.cipher.open(&self.nonce, data)?;
+        self.advance();
+        Ok(())
+    }
+
+    /// Nonces are little endian counters
+    fn advance(&mut self) {
+        for b in self.nonce.iter_mut() {
+            *b = b.wrapping_add(1);
+            if *b != 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// AEAD stream over `stream`, servers speak it the same way as clients
+pub struct AeadStream<S> {
+    stream: S,
+    kind: CipherKind,
+    key: Vec<u8>,
+    /// Set once the peer's salt arrived
+    reader: Option<Session>,
+    writer: Session,
+    /// Payload length of the chunk being read, once its length is opened
+    pending: Option<usize>,
+    read_buf: BytesMut,
+    /// Payload of the current chunk not yet returned
+    plain: BytesMut,
+    /// Our salt, then a chunk being written
+    write_buf: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AeadStream<S> {
+    /// Stream keyed by the master `key`, our salt goes out with the first
+    /// chunk
+    pub fn new(stream: S, kind: CipherKind, key: &[u8]) -> io::Result<AeadStream<S>> {
+        let mut salt = vec![0; kind.key_len()];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Ok(AeadStream {
+            stream,
+            kind,
+            key: key.to_vec(),
+            reader: None,
+            writer: Session::new(kind, key, &salt)?,
+            pending: None,
+            read_buf: BytesMut::new(),
+            plain: BytesMut::new(),
+            write_buf: salt,
+            write_pos: 0,
+        })
+    }
+
+    /// Move the payload of the next chunk into `plain`, `false` on EOF
+    fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            match self.reader {
+                None if self.read_buf.len() >= self.kind.key_len() => {
+                    let salt = self.read_buf.split_to(self.kind.key_len());
+                    self.reader = Some(Session::new(self.kind, &self.key, &salt)?);
+                    continue;
+                }
+                None => {}
+                Some(ref mut reader) => {
+                    if self.pending.is_none() && self.read_buf.len() >= 2 + TAG_LEN {
+                        let mut len = self.read_buf.split_to(2 + TAG_LEN).to_vec();
+                        reader.open(&mut len)?;
+                        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                        if len > MAX_CHUNK_LEN {
+                            return Poll::Ready(Err(other("shadowsocks chunk too long")));
+                        }
+                        self.pending = Some(len);
+                    }
+                    if let Some(len) = self.pending {
+                        if self.read_buf.len() >= len + TAG_LEN {
+                            let mut payload = self.read_buf.split_to(len + TAG_LEN).to_vec();
+                            reader.open(&mut payload)?;
+                            self.pending = None;
+                            self.plain = BytesMut::from(&payload[..]);
+                            return Poll::Ready(Ok(true));
+                        }
+                    }
+                }
+            }
+
+            let mut buf = [0u8; 4096];
+            let n = ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+            if n == 0 {
+                return if self.read_buf.is_empty() && self.pending.is_none() {
+                    Poll::Ready(Ok(false))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            self.read_buf.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Write out our salt or the pending chunk
+    fn poll_write_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for AeadStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.plain.is_empty() {
+            if !ready!(this.poll_chunk(cx))? {
+                return Poll::Ready(Ok(0));
+            }
+        }
+        let n = cmp::min(buf.len(), this.plain.len());
+        buf[..n].copy_from_slice(&this.plain[..n]);
+        this.plain.advance(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for AeadStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let data = &buf[..cmp::min(buf.len(), MAX_CHUNK_LEN)];
+        let mut len = (data.len() as u16).to_be_bytes().to_vec();
+        this.writer.seal(&mut len)?;
+        let mut payload = data.to_vec();
+        this.writer.seal(&mut payload)?;
+        this.write_buf.extend_from_slice(&len);
+        this.write_buf.extend_from_slice(&payload);
+        // The chunk is accepted once buffered, later calls finish writing it
+        if let Poll::Ready(Err(e)) = this.poll_write_chunk(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+impl Outbound for Shadowsocks {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// The shadow-tls server takes no datagrams
+    fn udp(&self) -> bool {
+        self.udp && self.shadow_tls.is_none()
+    }
+
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let stream = dialer.connect(&self.server).await?;
+            let stream: BoxStream = match self.shadow_tls {
+                Some(ref config) => {
+                    Box::new(shadow_tls::connect(stream, config, self.fingerprint).await?)
+                }
+                None => stream,
+            };
+            let mut stream = AeadStream::new(stream, self.kind, &self.key)?;
+            let mut head = Vec::new();
+            write_address(&mut head, target)?;
+            stream.write_all(&head).await?;
+            Ok(Box::new(stream) as BoxStream)
+        })
+    }
+
+    /// A socket of its own, callers bind once per peer they relay for
+    fn bind(&self) -> BoxFuture<'_, io::Result<Datagrams>> {
+        Box::pin(async move {
+            if !self.udp() {
+                return Err(other(format!("proxy {} has no UDP relay", self.name)));
+            }
+            let server = self
+                .server
+                .lookup()
+                .await?
+                .into_iter()
+                .next()
+                .ok_or_else(|| other("resolved to empty address"))?;
+            let local: SocketAddr = if server.is_ipv4() {
+                "0.0.0.0:0".parse().unwrap()
+            } else {
+                "[::]:0".parse().unwrap()
+            };
+            let (mut recv, mut send) = UdpSocket::bind(&local).await?.split();
+
+            let (datagrams, mut outgoing) = mpsc::unbounded::<(Address, Vec<u8>)>();
+            let kind = self.kind;
+            let key = self.key.clone();
+            rt::spawn(async move {
+                while let Some((peer, payload)) = outgoing.next().await {
+                    let packet = match seal_packet(kind, &key, &peer, &payload) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            debug!("Dropped datagram to {}, err: {}", peer, e);
+                            continue;
+                        }
+                    };
+                    if send.send_to(&packet, &server).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let (replies, incoming) = mpsc::unbounded();
+            let key = self.key.clone();
+            rt::spawn(async move {
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    let (n, from) =
+                        match rt::timeout(UDP_IDLE_TIMEOUT, recv.recv_from(&mut buf)).await {
+                            Ok(Ok(received)) => received,
+                            _ => break,
+                        };
+                    // Drop stray packets from anyone but the server
+                    if from != server {
+                        continue;
+                    }
+                    match open_packet(kind, &key, &buf[..n]) {
+                        Ok(reply) => {
+                            if replies.unbounded_send(reply).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => debug!("Dropped datagram from {}, err: {}", server, e),
+                    }
+                }
+            });
+            Ok(Datagrams {
+                send: datagrams,
+                recv: incoming,
+            })
+        })
+    }
+
+    fn alive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::DomainName;
+
+    #[test]
+    fn seals_packets_apart() {
+        // Key of shadowsocks-libev for "foobar"
+        assert_eq!(
+            master_key("foobar", 16),
+            [
+                0x38, 0x58, 0xf6, 0x22, 0x30, 0xac, 0x3c, 0x91, 0x5f, 0x30, 0x0c, 0x66, 0x43, 0x12,
+                0xc6, 0x3f
+            ]
+        );
+
+        let kind = CipherKind::ChaCha20Poly1305;
+        let key = master_key("secret", kind.key_len());
+        let peer = Address::DomainName(DomainName("dns.example.com".to_owned(), 53));
+        let first = seal_packet(kind, &key, &peer, b"query").unwrap();
+        let second = seal_packet(kind, &key, &peer, b"query").unwrap();
+        // Fresh salt, and so fresh subkey, for each
+        assert_ne!(first[..32], second[..32]);
+        assert_eq!(first.len(), 32 + 1 + 1 + 15 + 2 + 5 + 16);
+
+        let (from, payload) = open_packet(kind, &key, &first).unwrap();
+        assert_eq!(from.to_string(), peer.to_string());
+        assert_eq!(payload, b"query");
+        assert!(open_packet(kind, &master_key("other", 32), &second).is_err());
+        assert!(open_packet(kind, &key, &second[..40]).is_err());
+    }
+
+    #[test]
+    fn auto_cipher_is_the_preferred_one() {
+        let server = Address::DomainName(DomainName("ss.example.com".to_owned(), 8388));
+        for &preferred in &[CipherKind::Aes128Gcm, CipherKind::ChaCha20Poly1305] {
+            let outbound =
+                Shadowsocks::new("ss", server.clone(), "auto", "secret", false, preferred).unwrap();
+            assert_eq!(outbound.kind, preferred);
+            assert_eq!(outbound.key.len(), preferred.key_len());
+        }
+        let outbound = Shadowsocks::new(
+            "ss",
+            server.clone(),
+            "aes-256-gcm",
+            "secret",
+            false,
+            CipherKind::ChaCha20Poly1305,
+        )
+        .unwrap();
+        assert_eq!(outbound.kind, CipherKind::Aes256Gcm);
+        let preferred = CipherKind::Aes128Gcm;
+        assert!(Shadowsocks::new("ss", server, "rc4-md5", "secret", false, preferred).is_err());
+    }
+}
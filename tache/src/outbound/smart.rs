@@ -0,0 +1,388 @@
+use std::{
+    cmp::Ordering,
+    io,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use futures::future::{join_all, BoxFuture};
+use log::debug;
+use lru_cache::LruCache;
+
+use super::{history, probe, BoxStream, Datagrams, Dialer, Outbound};
+use crate::{domain_trie, rt, utils::Address};
+
+/// Default seconds between url tests
+pub const DEFAULT_INTERVAL: u64 = 300;
+/// Default members tried after the chosen one failed
+pub const DEFAULT_RETRIES: usize = 2;
+/// Destinations whose history is kept
+const MAX_DESTINATIONS: usize = 4096;
+/// Age after which a result counts half
+const HALF_LIFE: Duration = Duration::from_secs(30 * 60);
+/// Weight of the newest result in the moving averages
+const ALPHA: f64 = 0.3;
+/// Success rate assumed without history
+const PRIOR: f64 = 0.5;
+
+/// How well one member did for one destination
+#[derive(Clone, Copy)]
+struct Score {
+    success: f64,
+    /// Connect time in milliseconds
+    latency: f64,
+    updated: Instant,
+}
+
+impl Score {
+    /// Success rate fading back to the prior as the history ages
+    fn success(&self, now: Instant) -> f64 {
+        let age = now.duration_since(self.updated).as_secs_f64();
+        let weight = 0.5f64.powf(age / HALF_LIFE.as_secs_f64());
+        PRIOR + (self.success - PRIOR) * weight
+    }
+
+    fn record(&mut self, ok: bool, latency: f64, now: Instant) {
+        self.success = self.success(now) * (1.0 - ALPHA) + if ok { ALPHA } else { 0.0 };
+        if ok {
+            self.latency = self.latency * (1.0 - ALPHA) + latency * ALPHA;
+        }
+        self.updated = now;
+    }
+}
+
+/// Order members by their history for a destination, members without
+/// history and ties follow the url test delays
+fn rank(scores: &[Option<Score>], delays: &[Option<u64>], now: Instant) -> Vec<usize> {
+    let success = |i: usize| scores[i].map_or(PRIOR, |s| s.success(now));
+    let latency = |i: usize| scores[i].map(|s| s.latency);
+    let mut order = (0..delays.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        // Rates this close are noise
+        if (success(a) - success(b)).abs() > 0.05 {
+            return success(b)
+                .partial_cmp(&success(a))
+                .unwrap_or(Ordering::Equal);
+        }
+        match (latency(a), latency(b)) {
+            (Some(la), Some(lb)) if (la - lb).abs() > 0.2 * la.min(lb) => {
+                return la.partial_cmp(&lb).unwrap_or(Ordering::Equal);
+            }
+            _ => {}
+        }
+        match (delays[a], delays[b]) {
+            (Some(da), Some(db)) => da.cmp(&db),
+            (Some(..), None) => Ordering::Less,
+            (None, Some(..)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    });
+    order
+}
+
+fn destination(target: &Address) -> String {
+    match *target {
+        Address::SocketAddr(ref addr) => addr.ip().to_string(),
+        Address::DomainName(ref dn) => {
+            domain_trie::normalize(&dn.0).unwrap_or_else(|| dn.0.clone())
+        }
+    }
+}
+
+/// Group routing each destination to the member that worked best for it
+pub struct Smart {
+    name: String,
+    members: Arc<Vec<Arc<dyn Outbound>>>,
+    url: String,
+    interval: Duration,
+    retries: usize,
+    /// Median of the recent url test delays per member in milliseconds,
+    /// `None` when the last test failed
+    delays: Arc<RwLock<Vec<Option<u64>>>>,
+    tested_at: Mutex<Option<Instant>>,
+    scores: Mutex<LruCache<String, Vec<Option<Score>>>>,
+}
+
+impl Smart {
+    pub fn new(
+        name: &str,
+        members: Vec<Arc<dyn Outbound>>,
+        url: String,
+        interval: u64,
+        retries: usize,
+    ) -> Smart {
+        let len = members.len();
+        Smart {
+            name: name.to_owned(),
+            members: Arc::new(members),
+            url,
+            interval: Duration::from_secs(interval),
+            retries,
+            delays: Arc::new(RwLock::new(vec![None; len])),
+            tested_at: Mutex::new(None),
+            scores: Mutex::new(LruCache::new(MAX_DESTINATIONS)),
+        }
+    }
+
+    /// Start a url test in the background when the last one is stale
+    fn refresh_delays(&self) {
+        let mut tested_at = self.tested_at.lock().unwrap();
+        if tested_at.is_some_and(|t| t.elapsed() < self.interval) {
+            return;
+        }
+        *tested_at = Some(Instant::now());
+
+        let members = self.members.clone();
+        let delays = self.delays.clone();
+        let url = self.url.clone();
+        rt::spawn(async move {
+            let results = join_all(members.iter().map(|member| {
+                let url = &url;
+                async move {
+                    let name = member.name();
+                    let delay = match probe::url_delay(&**member, url).await {
+                        Ok(delay) => Some(delay),
+                        Err(e) => {
+                            debug!("Url test of {} failed, err: {}", name, e);
+                            None
+                        }
+                    };
+                    history::record(&name, delay);
+                    delay.and_then(|_| history::stats(&name)?.p50)
+                }
+            }))
+            .await;
+            *delays.write().unwrap() = results;
+        });
+    }
+
+    fn ranked(&self, key: &str) -> Vec<usize> {
+        let delays = self.delays.read().unwrap().clone();
+        let mut scores = self.scores.lock().unwrap();
+        match scores.get_mut(key) {
+            Some(scores) => rank(scores, &delays, Instant::now()),
+            None => rank(&vec![None; delays.len()], &delays, Instant::now()),
+        }
+    }
+
+    fn record(&self, key: String, member: usize, ok: bool, latency: Duration) {
+        let now = Instant::now();
+        let latency = latency.as_millis() as f64;
+        let mut scores = self.scores.lock().unwrap();
+        if !scores.contains_key(&key) {
+            scores.insert(key.clone(), vec![None; self.members.len()]);
+        }
+        if let Some(scores) = scores.get_mut(&key) {
+            match scores[member] {
+                Some(ref mut score) => score.record(ok, latency, now),
+                None => {
+                    scores[member] = Some(Score {
+                        success: if ok { 1.0 } else { 0.0 },
+                        latency,
+                        updated: now,
+                    })
+                }
+            }
+        }
+    }
+}
+
+impl Outbound for Smart {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn udp(&self) -> bool {
+        self.members.iter().all(|m| m.udp())
+    }
+
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            self.refresh_delays();
+            let key = destination(target);
+            let mut order = self.ranked(&key);
+            // Members known to be down are only tried after the others
+            order.sort_by_key(|&member| !self.members[member].alive());
+
+            let mut last_err = io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("group {} has no member", self.name),
+            );
+            for &member in order.iter().take(1 + self.retries) {
+                let start = Instant::now();
+                let result = self.members[member].dial(target, dialer).await;
+                debug!(
+                    "{} dialed {} via {}, ok: {}",
+                    self.name,
+                    key,
+                    self.members[member].name(),
+                    result.is_ok()
+                );
+                self.record(key.clone(), member, result.is_ok(), start.elapsed());
+                match result {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => last_err = e,
+                }
+            }
+            Err(last_err)
+        })
+    }
+
+    /// The first member opening a relay, live ones first
+    fn bind(&self) -> BoxFuture<'_, io::Result<Datagrams>> {
+        Box::pin(async move {
+            let mut order: Vec<_> = self.members.iter().collect();
+            order.sort_by_key(|member| !member.alive());
+            let mut last_err = io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("group {} has no member", self.name),
+            );
+            for member in order {
+                match member.bind().await {
+                    Ok(datagrams) => return Ok(datagrams),
+                    Err(e) => last_err = e,
+                }
+            }
+            Err(last_err)
+        })
+    }
+
+    fn alive(&self) -> bool {
+        self.members.iter().any(|m| m.alive())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{outbound::TcpDialer, rt::Runtime, utils::DomainName};
+
+    fn score(success: f64, latency: f64, now: Instant) -> Option<Score> {
+        Some(Score {
+            success,
+            latency,
+            updated: now,
+        })
+    }
+
+    #[test]
+    fn history_beats_url_test() {
+        let now = Instant::now();
+        let delays = [Some(50), Some(300), None];
+        assert_eq!(rank(&[None, None, None], &delays, now), vec![0, 1, 2]);
+
+        // The fastest member is blocked by this destination
+        let scores = [
+            score(0.1, 0.0, now),
+            score(0.9, 200.0, now),
+            score(0.0, 0.0, now),
+        ];
+        assert_eq!(rank(&scores, &delays, now), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn history_decays_to_prior() {
+        let now = Instant::now();
+        let old = Score {
+            success: 0.0,
+            latency: 0.0,
+            updated: now,
+        };
+        let later = now + HALF_LIFE * 10;
+        assert!((old.success(later) - PRIOR).abs() < 0.01);
+
+        let mut s = old;
+        s.record(true, 100.0, now);
+        assert!(s.success > old.success);
+    }
+
+    /// Member whose dials to example.com succeed or fail, noting each
+    struct Member {
+        name: &'static str,
+        alive: bool,
+        works: bool,
+        dialed: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Outbound for Member {
+        fn name(&self) -> String {
+            self.name.to_owned()
+        }
+
+        fn udp(&self) -> bool {
+            false
+        }
+
+        fn dial<'a>(
+            &'a self,
+            target: &'a Address,
+            _: &'a dyn Dialer,
+        ) -> BoxFuture<'a, io::Result<BoxStream>> {
+            // Url tests dial their own host
+            if target.host() == "example.com" {
+                self.dialed.lock().unwrap().push(self.name);
+            }
+            let works = self.works;
+            Box::pin(async move {
+                if works {
+                    let stream: BoxStream = Box::new(io::Cursor::new(Vec::new()));
+                    Ok(stream)
+                } else {
+                    Err(io::Error::new(io::ErrorKind::ConnectionRefused, "down"))
+                }
+            })
+        }
+
+        fn alive(&self) -> bool {
+            self.alive
+        }
+    }
+
+    fn smart(retries: usize, dialed: &Arc<Mutex<Vec<&'static str>>>) -> Smart {
+        let member = |name, alive, works| {
+            Arc::new(Member {
+                name,
+                alive,
+                works,
+                dialed: dialed.clone(),
+            }) as Arc<dyn Outbound>
+        };
+        Smart::new(
+            "smart",
+            vec![
+                member("a", true, false),
+                member("dead", false, true),
+                member("b", true, true),
+            ],
+            "http://probe.invalid/".to_owned(),
+            DEFAULT_INTERVAL,
+            retries,
+        )
+    }
+
+    #[test]
+    fn retries_the_next_healthy_member() {
+        let target = Address::DomainName(DomainName("example.com".to_owned(), 443));
+        let dialed = Arc::new(Mutex::new(Vec::new()));
+        Runtime::new().unwrap().block_on(async {
+            let group = smart(DEFAULT_RETRIES, &dialed);
+            assert!(group.dial(&target, &TcpDialer).await.is_ok());
+            assert_eq!(*dialed.lock().unwrap(), ["a", "b"]);
+
+            // The failure counts against the member for this destination
+            dialed.lock().unwrap().clear();
+            assert!(group.dial(&target, &TcpDialer).await.is_ok());
+            assert_eq!(*dialed.lock().unwrap(), ["b"]);
+
+            dialed.lock().unwrap().clear();
+            let group = smart(0, &dialed);
+            let e = group.dial(&target, &TcpDialer).await.err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+            assert_eq!(*dialed.lock().unwrap(), ["a"]);
+        });
+    }
+}
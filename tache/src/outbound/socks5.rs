@@ -0,0 +1,203 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{connect_tls, other, BoxStream, Dialer, Outbound};
+use crate::{
+    tls::TlsConnector,
+    utils::{Address, DomainName},
+};
+
+/// Upstream SOCKS5 proxy
+pub struct Socks5 {
+    name: String,
+    server: Address,
+    username: Option<String>,
+    password: Option<String>,
+    /// Talk to the server over TLS
+    tls: Option<TlsConnector>,
+}
+
+impl Socks5 {
+    pub fn new(
+        name: &str,
+        server: Address,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> Socks5 {
+        Socks5 {
+            name: name.to_owned(),
+            server,
+            username,
+            password,
+            tls: None,
+        }
+    }
+
+    pub fn tls(mut self, connector: Option<TlsConnector>) -> Socks5 {
+        self.tls = connector;
+        self
+    }
+}
+
+pub(super) fn write_address(buf: &mut Vec<u8>, target: &Address) -> io::Result<()> {
+    match *target {
+        Address::SocketAddr(SocketAddr::V4(ref addr)) => {
+            buf.push(0x01);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        Address::SocketAddr(SocketAddr::V6(ref addr)) => {
+            buf.push(0x04);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        Address::DomainName(ref dn) => {
+            if dn.0.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "domain name too long",
+                ));
+            }
+            buf.push(0x03);
+            buf.push(dn.0.len() as u8);
+            buf.extend_from_slice(dn.0.as_bytes());
+        }
+    }
+    let port = target.port();
+    buf.push((port >> 8) as u8);
+    buf.push(port as u8);
+    Ok(())
+}
+
+/// Address at the start of `buf` and the bytes it takes
+pub(super) fn read_address(buf: &[u8]) -> io::Result<(Address, usize)> {
+    let short = || other("truncated socks address");
+    let (address, len) = match buf.first() {
+        Some(0x01) => {
+            let addr = buf.get(1..5).ok_or_else(short)?;
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            (Address::SocketAddr(SocketAddr::new(ip.into(), 0)), 5)
+        }
+        Some(0x04) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(buf.get(1..17).ok_or_else(short)?);
+            let ip = Ipv6Addr::from(octets);
+            (Address::SocketAddr(SocketAddr::new(ip.into(), 0)), 17)
+        }
+        Some(0x03) => {
+            let len = usize::from(*buf.get(1).ok_or_else(short)?);
+            let name = buf.get(2..2 + len).ok_or_else(short)?;
+            let name =
+                String::from_utf8(name.to_vec()).map_err(|_| other("invalid domain name"))?;
+            (Address::DomainName(DomainName(name, 0)), 2 + len)
+        }
+        _ => return Err(other("invalid socks address type")),
+    };
+    let port = buf.get(len..len + 2).ok_or_else(short)?;
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let address = match address {
+        Address::SocketAddr(addr) => Address::SocketAddr(SocketAddr::new(addr.ip(), port)),
+        Address::DomainName(DomainName(name, _)) => Address::DomainName(DomainName(name, port)),
+    };
+    Ok((address, len + 2))
+}
+
+/// Run the client side of a SOCKS5 CONNECT on an established stream
+pub async fn handshake<S>(
+    stream: &mut S,
+    target: &Address,
+    auth: Option<(&str, &str)>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let methods: &[u8] = if auth.is_some() {
+        &[5, 2, 0, 2]
+    } else {
+        &[5, 1, 0]
+    };
+    stream.write_all(methods).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err(other("invalid socks version in reply"));
+    }
+    match (reply[1], auth) {
+        (0, _) => {}
+        (2, Some((username, password))) => {
+            let mut req = vec![1, username.len() as u8];
+            req.extend_from_slice(username.as_bytes());
+            req.push(password.len() as u8);
+            req.extend_from_slice(password.as_bytes());
+            stream.write_all(&req).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(other("socks authentication failed"));
+            }
+        }
+        _ => return Err(other("no acceptable socks authentication method")),
+    }
+
+    let mut req = vec![5, 1, 0];
+    write_address(&mut req, target)?;
+    stream.write_all(&req).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0 {
+        return Err(other(format!(
+            "socks connect failed with reply {}",
+            head[1]
+        )));
+    }
+    // Skip the bound address
+    let skip = match head[3] {
+        0x01 => 4 + 2,
+        0x04 => 16 + 2,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize + 2
+        }
+        _ => return Err(other("invalid socks address type in reply")),
+    };
+    let mut bound = vec![0u8; skip];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+impl Outbound for Socks5 {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn udp(&self) -> bool {
+        false
+    }
+
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let mut stream = dialer.connect(&self.server).await?;
+            if let Some(ref connector) = self.tls {
+                stream = connect_tls(connector, &self.server, stream).await?;
+            }
+            let auth = match (&self.username, &self.password) {
+                (Some(u), Some(p)) => Some((u.as_str(), p.as_str())),
+                _ => None,
+            };
+            handshake(&mut stream, target, auth).await?;
+            Ok(stream)
+        })
+    }
+
+    fn alive(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,177 @@
+//! Speed tests of single proxies, requested through the API or the CLI
+//!
+//! A test runs a few url tests in a row for latency and jitter, then
+//! downloads the payload for throughput. Payloads are large and count
+//! against the proxy's traffic, so the API runs one test at a time and a
+//! proxy can't be tested again within `cooldown`.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::{probe, Outbound};
+use crate::config::SpeedtestConfig;
+
+/// 10 MB, served by Cloudflare
+pub const DEFAULT_URL: &str = "https://speed.cloudflare.com/__down?bytes=10000000";
+/// Seconds before a proxy can be tested again
+pub const DEFAULT_COOLDOWN: u64 = 60;
+
+/// Url tests timed for latency and jitter
+const SAMPLES: usize = 5;
+
+#[derive(Serialize, Debug)]
+pub struct SpeedTest {
+    pub name: String,
+    /// Median url test delay in milliseconds
+    pub latency: Option<u64>,
+    /// Mean change between consecutive url test delays in milliseconds
+    pub jitter: Option<u64>,
+    /// Size of the payload downloaded
+    pub bytes: u64,
+    /// Bytes per second downloading the payload
+    pub throughput: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Measure `outbound` against test url `url`, then download `download`
+pub async fn run(name: &str, outbound: &dyn Outbound, url: &str, download: &str) -> SpeedTest {
+    let mut delays = probe::url_delays(outbound, url, SAMPLES).await;
+    let mut test = SpeedTest {
+        name: name.to_owned(),
+        latency: None,
+        jitter: probe::jitter(&delays),
+        bytes: 0,
+        throughput: None,
+        error: None,
+    };
+    if delays.is_empty() {
+        test.error = Some(format!("url test of {} failed", url));
+        return test;
+    }
+    delays.sort();
+    test.latency = Some(delays[delays.len() / 2]);
+    match probe::download(outbound, download).await {
+        Ok((bytes, elapsed)) => {
+            test.bytes = bytes;
+            test.throughput = Some(probe::per_second(bytes, elapsed));
+        }
+        Err(e) => test.error = Some(format!("download: {}", e)),
+    }
+    test
+}
+
+/// Why a test wasn't started
+#[derive(Debug)]
+pub enum Refused {
+    /// Another test is running
+    Busy,
+    /// Seconds until the proxy can be tested again
+    Cooldown(u64),
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Refused::Busy => write!(f, "another speed test is running"),
+            Refused::Cooldown(secs) => write!(f, "tested recently, retry in {}s", secs),
+        }
+    }
+}
+
+/// Speed tests on demand, one at a time
+pub struct SpeedTester {
+    url: String,
+    cooldown: Duration,
+    running: AtomicBool,
+    tested: Mutex<HashMap<String, Instant>>,
+}
+
+/// Clears `running` when the test ends or is dropped
+struct Running<'a>(&'a AtomicBool);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl SpeedTester {
+    pub fn new(config: Option<&SpeedtestConfig>) -> SpeedTester {
+        SpeedTester {
+            url: config
+                .and_then(|c| c.url.clone())
+                .unwrap_or_else(|| DEFAULT_URL.to_owned()),
+            cooldown: Duration::from_secs(
+                config.and_then(|c| c.cooldown).unwrap_or(DEFAULT_COOLDOWN),
+            ),
+            running: AtomicBool::new(false),
+            tested: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Payload downloaded unless a test names another
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Test `outbound` now unless refused, downloading `download` or the
+    /// configured payload
+    pub async fn test(
+        &self,
+        name: &str,
+        outbound: &dyn Outbound,
+        url: &str,
+        download: Option<&str>,
+    ) -> Result<SpeedTest, Refused> {
+        {
+            let mut tested = self.tested.lock().unwrap();
+            if let Some(at) = tested.get(name) {
+                let elapsed = at.elapsed();
+                if elapsed < self.cooldown {
+                    let left = self.cooldown - elapsed;
+                    return Err(Refused::Cooldown(left.as_secs().max(1)));
+                }
+            }
+            if self.running.swap(true, Ordering::Acquire) {
+                return Err(Refused::Busy);
+            }
+            let cooldown = self.cooldown;
+            tested.retain(|_, at| at.elapsed() < cooldown);
+            tested.insert(name.to_owned(), Instant::now());
+        }
+        let _running = Running(&self.running);
+        Ok(run(name, outbound, url, download.unwrap_or(&self.url)).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::outbound::Direct;
+
+    #[test]
+    fn refuses_within_cooldown() {
+        let tester = SpeedTester::new(None);
+        tester
+            .tested
+            .lock()
+            .unwrap()
+            .insert("hk".to_owned(), Instant::now());
+        let direct = Direct::new("hk");
+        let test = tester.test("hk", &direct, probe::DEFAULT_TEST_URL, None);
+        match futures::executor::block_on(test) {
+            Err(Refused::Cooldown(secs)) => assert!(secs > 0 && secs <= DEFAULT_COOLDOWN),
+            other => panic!("expected a cooldown, got {:?}", other),
+        }
+        assert!(!tester.running.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,91 @@
+use std::io;
+
+use futures::future::BoxFuture;
+use rand::RngCore;
+
+use super::{socks5, BoxStream, Dialer, Outbound};
+use crate::{domain_trie, utils::Address};
+
+/// Local Tor client
+///
+/// Tor keeps streams with different SOCKS credentials on different circuits
+/// (IsolateSOCKSAuth, on by default), so isolation sends the destination
+/// host as username. The password is random per instance to keep our
+/// circuits apart from other applications using the same Tor.
+pub struct Tor {
+    name: String,
+    server: Address,
+    isolation: bool,
+    onion_only: bool,
+    nonce: String,
+}
+
+impl Tor {
+    pub fn new(name: &str, server: Address, isolation: bool, onion_only: bool) -> Tor {
+        let mut nonce = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        Tor {
+            name: name.to_owned(),
+            server,
+            isolation,
+            onion_only,
+            nonce: nonce.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+}
+
+fn host(target: &Address) -> String {
+    match *target {
+        Address::SocketAddr(ref addr) => addr.ip().to_string(),
+        Address::DomainName(ref dn) => {
+            domain_trie::normalize(&dn.0).unwrap_or_else(|| dn.0.clone())
+        }
+    }
+}
+
+fn is_onion(target: &Address) -> bool {
+    match *target {
+        Address::DomainName(ref dn) => {
+            domain_trie::normalize(&dn.0).is_some_and(|d| d.ends_with(".onion"))
+        }
+        Address::SocketAddr(..) => false,
+    }
+}
+
+impl Outbound for Tor {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn udp(&self) -> bool {
+        false
+    }
+
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            if self.onion_only && !is_onion(target) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} only carries .onion hosts", self.name),
+                ));
+            }
+            let mut stream = dialer.connect(&self.server).await?;
+            let username = host(target);
+            let auth = if self.isolation && username.len() <= 255 {
+                Some((username.as_str(), self.nonce.as_str()))
+            } else {
+                None
+            };
+            socks5::handshake(&mut stream, target, auth).await?;
+            Ok(stream)
+        })
+    }
+
+    fn alive(&self) -> bool {
+        true
+    }
+}
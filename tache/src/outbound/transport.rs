@@ -0,0 +1,393 @@
+//! Stream transports below VMess and Trojan
+//!
+//! `h2` sends the proxy stream as the body of a long lived PUT request like
+//! v2ray does, `grpc` wraps it in gRPC messages of the `Tun` method (the
+//! "gun" protocol). Both run over TLS with ALPN h2.
+
+use std::{
+    cmp, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::ready;
+use h2::{client, RecvStream, SendStream};
+use http::{Method, Request};
+use log::debug;
+use rand::seq::SliceRandom;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{other, BoxStream};
+use crate::{
+    config::{ClientFingerprint, Network, TransportConfig},
+    rt,
+    tls::{self, TlsConnector},
+};
+
+/// gRPC message header and protobuf field header of the largest write
+const GRPC_OVERHEAD: usize = 5 + 1 + 3;
+/// Largest write sent as one DATA frame or gRPC message
+const MAX_WRITE_LEN: usize = 16384;
+
+enum Mode {
+    H2 { host: Vec<String>, path: String },
+    Grpc { service_name: String },
+}
+
+/// `h2` or `grpc` transport, `tcp` has none
+pub struct Transport {
+    mode: Mode,
+    connector: TlsConnector,
+}
+
+impl Transport {
+    /// Transport of `config`, `None` for plain streams, its TLS looks like
+    /// the browser of `fingerprint`
+    pub fn from_config(
+        config: &TransportConfig,
+        fingerprint: Option<ClientFingerprint>,
+    ) -> io::Result<Option<Transport>> {
+        let mode = match config.network {
+            None | Some(Network::Tcp) => return Ok(None),
+            Some(Network::H2) => {
+                let opts = config.h2_opts.as_ref();
+                Mode::H2 {
+                    host: opts.map(|o| o.host.clone()).unwrap_or_default(),
+                    path: opts.map_or_else(|| "/".to_owned(), |o| o.path.clone()),
+                }
+            }
+            Some(Network::Grpc) => match config.grpc_opts {
+                Some(ref opts) => Mode::Grpc {
+                    service_name: opts.grpc_service_name.clone(),
+                },
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "grpc transport requires grpc-opts",
+                    ))
+                }
+            },
+            Some(Network::Ws) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "ws transport is not supported yet",
+                ))
+            }
+        };
+        Ok(Some(Transport {
+            mode,
+            connector: tls::client_connector_like(&["h2"], fingerprint),
+        }))
+    }
+
+    /// Open the transport on `stream` connected to the proxy server, its
+    /// certificate has to be valid for `server_name`
+    pub async fn connect(&self, stream: BoxStream, server_name: &str) -> io::Result<BoxStream> {
+        let (authority, path, grpc) = match self.mode {
+            Mode::H2 { ref host, ref path } => {
+                let authority = host
+                    .choose(&mut rand::thread_rng())
+                    .map_or(server_name, String::as_str);
+                (authority, path.clone(), false)
+            }
+            Mode::Grpc { ref service_name } => {
+                (server_name, format!("/{}/Tun", service_name), true)
+            }
+        };
+        let stream = tls::connect(&self.connector, server_name, stream).await?;
+        open(stream, authority, &path, grpc).await
+    }
+}
+
+/// Send the request of the proxy stream over the h2 connection on `stream`
+async fn open<S>(stream: S, authority: &str, path: &str, grpc: bool) -> io::Result<BoxStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (client, connection) = client::handshake(stream).await.map_err(other)?;
+    rt::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("h2 transport connection closed, err: {}", e);
+        }
+    });
+
+    let builder = Request::builder().uri(format!("https://{}{}", authority, path));
+    let builder = if grpc {
+        builder
+            .method(Method::POST)
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+    } else {
+        builder.method(Method::PUT)
+    };
+    let request = builder.body(()).map_err(other)?;
+
+    let mut client = client.ready().await.map_err(other)?;
+    let (response, send) = client.send_request(request, false).map_err(other)?;
+    let response = response.await.map_err(other)?;
+    if !response.status().is_success() {
+        return Err(other(format!(
+            "transport request rejected with {}",
+            response.status()
+        )));
+    }
+
+    Ok(Box::new(H2Stream {
+        send,
+        recv: response.into_body(),
+        grpc,
+        read_buf: BytesMut::new(),
+        plain: Bytes::new(),
+        shutdown: false,
+    }))
+}
+
+/// Proxy stream carried by one h2 stream
+struct H2Stream {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    grpc: bool,
+    /// Received gRPC messages not yet decoded
+    read_buf: BytesMut,
+    /// Data not yet returned
+    plain: Bytes,
+    shutdown: bool,
+}
+
+fn put_varint(buf: &mut BytesMut, mut v: usize) {
+    while v >= 0x80 {
+        buf.put_u8(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.put_u8(v as u8);
+}
+
+/// Decode a varint, `None` while incomplete
+fn get_varint(buf: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    let mut v = 0usize;
+    for (i, b) in buf.iter().enumerate().take(5) {
+        v |= ((b & 0x7f) as usize) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok(Some((v, i + 1)));
+        }
+    }
+    if buf.len() >= 5 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "varint too long",
+        ));
+    }
+    Ok(None)
+}
+
+/// `Hunk { bytes data = 1; }` framed as a gRPC message
+fn encode_hunk(data: &[u8]) -> Bytes {
+    let mut proto = BytesMut::with_capacity(data.len() + 4);
+    proto.put_u8(0x0a);
+    put_varint(&mut proto, data.len());
+    proto.put_slice(data);
+
+    let mut buf = BytesMut::with_capacity(proto.len() + 5);
+    buf.put_u8(0);
+    buf.put_u32(proto.len() as u32);
+    buf.put_slice(&proto);
+    buf.freeze()
+}
+
+/// Take the data of the next complete gRPC message out of `buf`
+fn decode_hunk(buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+    if buf.len() < 5 {
+        return Ok(None);
+    }
+    if buf[0] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "compressed gRPC message",
+        ));
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if buf.len() < 5 + len {
+        return Ok(None);
+    }
+    buf.advance(5);
+    let mut message = buf.split_to(len);
+    if message.is_empty() {
+        return Ok(Some(Bytes::new()));
+    }
+    if message[0] != 0x0a {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected field in gRPC message",
+        ));
+    }
+    let (data_len, n) = get_varint(&message[1..])?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated gRPC message"))?;
+    message.advance(1 + n);
+    if message.len() < data_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated gRPC message",
+        ));
+    }
+    message.truncate(data_len);
+    Ok(Some(message.freeze()))
+}
+
+impl AsyncRead for H2Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.plain.is_empty() {
+            if this.grpc {
+                if let Some(data) = decode_hunk(&mut this.read_buf)? {
+                    this.plain = data;
+                    continue;
+                }
+            }
+            match ready!(this.recv.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    let _ = this.recv.flow_control().release_capacity(data.len());
+                    if this.grpc {
+                        this.read_buf.extend_from_slice(&data);
+                    } else {
+                        this.plain = data;
+                    }
+                }
+                Some(Err(e)) => return Poll::Ready(Err(other(e))),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let n = cmp::min(buf.len(), this.plain.len());
+        buf[..n].copy_from_slice(&this.plain[..n]);
+        this.plain.advance(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for H2Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let overhead = if this.grpc { GRPC_OVERHEAD } else { 0 };
+
+        // Wait for flow control instead of queueing without bound
+        this.send
+            .reserve_capacity(cmp::min(buf.len(), MAX_WRITE_LEN) + overhead);
+        let capacity = loop {
+            match ready!(this.send.poll_capacity(cx)) {
+                Some(Ok(capacity)) if capacity > overhead => break capacity,
+                Some(Ok(..)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(other(e))),
+                None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            }
+        };
+
+        let data = &buf[..cmp::min(buf.len(), capacity - overhead)];
+        let frame = if this.grpc {
+            encode_hunk(data)
+        } else {
+            Bytes::copy_from_slice(data)
+        };
+        this.send.send_data(frame, false).map_err(other)?;
+        Poll::Ready(Ok(data.len()))
+    }
+
+    /// The h2 connection task writes out queued data
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.shutdown {
+            this.shutdown = true;
+            this.send.send_data(Bytes::new(), true).map_err(other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::Response;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::rt::{Runtime, TcpListener, TcpStream};
+
+    #[test]
+    fn grpc_hunk_roundtrip() {
+        let payload = vec![0x5au8; 300];
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&encode_hunk(b"hello"));
+        buf.extend_from_slice(&encode_hunk(&payload));
+        let tail = encode_hunk(b"partial");
+        buf.extend_from_slice(&tail[..4]);
+
+        assert_eq!(&decode_hunk(&mut buf).unwrap().unwrap()[..], b"hello");
+        assert_eq!(&decode_hunk(&mut buf).unwrap().unwrap()[..], &payload[..]);
+        assert!(decode_hunk(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&tail[4..]);
+        assert_eq!(&decode_hunk(&mut buf).unwrap().unwrap()[..], b"partial");
+    }
+
+    /// Answer the one request on `stream` by echoing its body, checking it
+    /// is the transport request of `grpc`
+    async fn echo(stream: TcpStream, grpc: bool) {
+        let mut connection = h2::server::handshake(stream).await.unwrap();
+        let (request, mut respond) = connection.accept().await.unwrap().unwrap();
+        rt::spawn(async move { while connection.accept().await.is_some() {} });
+        if grpc {
+            assert_eq!(request.method(), Method::POST);
+            assert_eq!(request.uri().path(), "/example/Tun");
+            assert_eq!(request.headers()["content-type"], "application/grpc");
+        } else {
+            assert_eq!(request.method(), Method::PUT);
+            assert_eq!(request.uri().path(), "/path");
+        }
+        assert_eq!(request.uri().host(), Some("example.com"));
+        let mut body = request.into_body();
+        let mut send = respond.send_response(Response::new(()), false).unwrap();
+        while let Some(data) = body.data().await {
+            let data = data.unwrap();
+            let _ = body.flow_control().release_capacity(data.len());
+            send.send_data(data, false).unwrap();
+        }
+        send.send_data(Bytes::new(), true).unwrap();
+    }
+
+    #[test]
+    fn carries_a_stream() {
+        Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            for &(path, grpc) in &[("/path", false), ("/example/Tun", true)] {
+                let serve = async {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    echo(stream, grpc).await;
+                };
+                let client = async {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    let mut stream = open(stream, "example.com", path, grpc).await.unwrap();
+                    let payload = vec![0x5au8; 40_000];
+                    stream.write_all(&payload).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                    let mut echoed = Vec::new();
+                    stream.read_to_end(&mut echoed).await.unwrap();
+                    assert_eq!(echoed, payload);
+                };
+                futures::join!(serve, client);
+            }
+        });
+    }
+}
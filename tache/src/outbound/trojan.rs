@@ -0,0 +1,180 @@
+//! Trojan client
+//!
+//! The request is the hex SHA-224 of the password, the command and the
+//! destination in SOCKS5 form, each ended by CRLF, followed by the data of
+//! the stream. It runs over TLS to the server, or over an `h2` or `grpc`
+//! transport bringing its own.
+
+use std::{fmt::Write as _, io};
+
+use futures::future::BoxFuture;
+use sha2::{Digest, Sha224};
+use tokio::io::AsyncWriteExt;
+
+use super::{
+    server_name, socks5::write_address, transport::Transport, BoxStream, Dialer, Outbound,
+};
+use crate::{
+    config::ClientFingerprint,
+    tls::{self, TlsConnector},
+    utils::Address,
+};
+
+const CRLF: &[u8] = b"\r\n";
+const COMMAND_CONNECT: u8 = 1;
+
+pub struct Trojan {
+    name: String,
+    server: Address,
+    /// Hex SHA-224 of the password
+    key: String,
+    /// Name the certificate of the server has to be valid for
+    sni: Option<String>,
+    connector: TlsConnector,
+    transport: Option<Transport>,
+}
+
+impl Trojan {
+    /// Proxy at `server`, its TLS looks like the browser of `fingerprint`
+    pub fn new(
+        name: &str,
+        server: Address,
+        password: &str,
+        sni: Option<String>,
+        fingerprint: Option<ClientFingerprint>,
+    ) -> Trojan {
+        let mut key = String::with_capacity(56);
+        for b in Sha224::digest(password.as_bytes()) {
+            let _ = write!(key, "{:02x}", b);
+        }
+        Trojan {
+            name: name.to_owned(),
+            server,
+            key,
+            sni,
+            connector: tls::client_connector_like(&[], fingerprint),
+            transport: None,
+        }
+    }
+
+    /// Carry streams over `transport` instead of plain TLS
+    pub fn transport(mut self, transport: Option<Transport>) -> Trojan {
+        self.transport = transport;
+        self
+    }
+
+    fn server_name(&self) -> io::Result<&str> {
+        match self.sni {
+            Some(ref sni) => Ok(sni),
+            None => server_name(&self.server),
+        }
+    }
+}
+
+/// Request header of a stream to `target`
+fn request(key: &str, target: &Address) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(key.len() + 2 + 1 + 1 + 255 + 2 + 2);
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(CRLF);
+    buf.push(COMMAND_CONNECT);
+    write_address(&mut buf, target)?;
+    buf.extend_from_slice(CRLF);
+    Ok(buf)
+}
+
+/// Send the request of a stream to `target` over `stream`
+async fn handshake(mut stream: BoxStream, key: &str, target: &Address) -> io::Result<BoxStream> {
+    stream.write_all(&request(key, target)?).await?;
+    stream.flush().await?;
+    Ok(stream)
+}
+
+impl Outbound for Trojan {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn udp(&self) -> bool {
+        false
+    }
+
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let stream = dialer.connect(&self.server).await?;
+            let server_name = self.server_name()?;
+            let stream: BoxStream = match self.transport {
+                Some(ref transport) => transport.connect(stream, server_name).await?,
+                None => Box::new(tls::connect(&self.connector, server_name, stream).await?),
+            };
+            handshake(stream, &self.key, target).await
+        })
+    }
+
+    fn alive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{
+        rt::{Runtime, TcpListener, TcpStream},
+        utils::DomainName,
+    };
+
+    #[test]
+    fn sends_the_request() {
+        let trojan = Trojan::new(
+            "trojan",
+            Address::DomainName(DomainName("trojan.example.com".to_owned(), 443)),
+            "password",
+            None,
+            None,
+        );
+        assert_eq!(
+            trojan.key,
+            "d63dc919e201d7bc4c825630d2cf25fdc93d4b2f0d46706d29038d01"
+        );
+        assert_eq!(trojan.server_name().unwrap(), "trojan.example.com");
+
+        Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let serve = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = vec![0; 56 + 2 + 1 + 1 + 1 + 11 + 2 + 2 + 4];
+                stream.read_exact(&mut head).await.unwrap();
+                let mut expected = trojan.key.as_bytes().to_vec();
+                expected.extend_from_slice(b"\r\n\x01\x03\x0bexample.com\x01\xbb\r\nping");
+                assert_eq!(head, expected);
+                stream.write_all(b"pong").await.unwrap();
+            };
+            let client = async {
+                let stream = Box::new(TcpStream::connect(addr).await.unwrap());
+                let target = Address::DomainName(DomainName("example.com".to_owned(), 443));
+                let mut stream = handshake(stream, &trojan.key, &target).await.unwrap();
+                stream.write_all(b"ping").await.unwrap();
+                let mut pong = [0; 4];
+                stream.read_exact(&mut pong).await.unwrap();
+                assert_eq!(&pong, b"pong");
+            };
+            futures::join!(serve, client);
+        });
+
+        let trojan = Trojan::new(
+            "trojan",
+            Address::SocketAddr(([127, 0, 0, 1], 443).into()),
+            "password",
+            None,
+            None,
+        );
+        assert!(trojan.server_name().is_err());
+    }
+}
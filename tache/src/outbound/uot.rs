@@ -0,0 +1,106 @@
+//! UDP over TCP, datagrams framed on a proxied stream
+//!
+//! For servers without a UDP relay. The stream is dialed to `MAGIC_HOST`,
+//! which servers supporting it (the sing-box protocol, version 1) take as a
+//! request to relay datagrams. Each datagram in either direction is the
+//! SOCKS5 address of the remote peer, a big-endian length and the payload.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{other, socks5::write_address};
+use crate::utils::{Address, DomainName};
+
+pub const MAGIC_HOST: &str = "sp.udp-over-tcp.arpa";
+
+/// Address the stream carrying datagrams is dialed to
+pub fn target() -> Address {
+    Address::DomainName(DomainName(MAGIC_HOST.to_owned(), 0))
+}
+
+/// Frame of `payload` sent to or received from `peer`
+pub fn encode(peer: &Address, payload: &[u8]) -> io::Result<Vec<u8>> {
+    if payload.len() > usize::from(u16::MAX) {
+        return Err(other("datagram too large"));
+    }
+    let mut frame = Vec::with_capacity(payload.len() + 24);
+    write_address(&mut frame, peer)?;
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Next datagram on `stream` with the peer it came from
+pub async fn read_datagram<R>(stream: &mut R) -> io::Result<(Address, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut kind = [0u8; 1];
+    stream.read_exact(&mut kind).await?;
+    let peer = match kind[0] {
+        0x01 => {
+            let mut addr = [0u8; 6];
+            stream.read_exact(&mut addr).await?;
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            Address::SocketAddr(SocketAddr::new(ip.into(), port(&addr[4..])))
+        }
+        0x04 => {
+            let mut addr = [0u8; 18];
+            stream.read_exact(&mut addr).await?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr[..16]);
+            let ip = Ipv6Addr::from(octets);
+            Address::SocketAddr(SocketAddr::new(ip.into(), port(&addr[16..])))
+        }
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut addr = vec![0u8; len[0] as usize + 2];
+            stream.read_exact(&mut addr).await?;
+            let port = port(&addr[len[0] as usize..]);
+            addr.truncate(len[0] as usize);
+            let host = String::from_utf8(addr).map_err(|_| other("invalid domain name"))?;
+            Address::DomainName(DomainName(host, port))
+        }
+        _ => return Err(other("invalid address type of datagram")),
+    };
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut payload = vec![0u8; port(&len) as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok((peer, payload))
+}
+
+fn port(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) << 8 | u16::from(bytes[1])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let peers = [
+            Address::SocketAddr("1.2.3.4:53".parse().unwrap()),
+            Address::SocketAddr("[2001:db8::1]:443".parse().unwrap()),
+            Address::DomainName(DomainName("example.com".to_owned(), 3478)),
+        ];
+        let mut stream = Vec::new();
+        for (i, peer) in peers.iter().enumerate() {
+            stream.extend(encode(peer, &vec![i as u8; i * 100]).unwrap());
+        }
+
+        let mut reader = &stream[..];
+        for (i, peer) in peers.iter().enumerate() {
+            let (from, payload) = futures::executor::block_on(read_datagram(&mut reader)).unwrap();
+            assert_eq!(from.to_string(), peer.to_string());
+            assert_eq!(payload, vec![i as u8; i * 100]);
+        }
+        assert!(reader.is_empty());
+    }
+}
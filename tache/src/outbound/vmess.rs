@@ -0,0 +1,923 @@
+//! VMess client of AEAD headers, the ones of alterId 0
+//!
+//! The request header is sealed under keys derived from the user's command
+//! key, a random nonce and an auth ID, the encrypted timestamp servers check
+//! against their own clock, corrected by the offset of `clock-check`. The
+//! body key and IV of the request pick the keys of the response. Both
+//! directions of the body are chunks of a masked length and a payload sealed
+//! under a nonce counting up, the masks are read from SHAKE128 of the
+//! direction's IV. Legacy headers, of an alterId above 0, are not supported.
+
+use std::{
+    cmp, io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use aes::{cipher::generic_array::GenericArray, Aes128, BlockEncrypt, NewBlockCipher};
+use bytes::{Buf, BytesMut};
+use futures::{future::BoxFuture, ready};
+use md5::{Digest, Md5};
+use rand::RngCore;
+use sha2::{
+    digest::{ExtendableOutput, Input, XofReader},
+    Sha256,
+};
+use sha3::{Sha3XofReader, Shake128};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use super::{
+    connect_tls, other, server_name, shadow_tls, transport::Transport, BoxStream, Dialer, Outbound,
+};
+use crate::{
+    config::{ClientFingerprint, ShadowTlsConfig},
+    crypto::{Cipher, CipherKind, NONCE_LEN, TAG_LEN},
+    protocol::vmess::clock,
+    tls::TlsConnector,
+    utils::Address,
+};
+
+const CMD_KEY_SALT: &[u8] = b"c48619fe-8f02-49e0-b9e9-edf763e17e21";
+const KDF_SALT: &[u8] = b"VMess AEAD KDF";
+const AUTH_ID_KEY: &[u8] = b"AES Auth ID Encryption";
+const HEADER_LEN_KEY: &[u8] = b"VMess Header AEAD Key_Length";
+const HEADER_LEN_NONCE: &[u8] = b"VMess Header AEAD Nonce_Length";
+const HEADER_KEY: &[u8] = b"VMess Header AEAD Key";
+const HEADER_NONCE: &[u8] = b"VMess Header AEAD Nonce";
+const RESPONSE_LEN_KEY: &[u8] = b"AEAD Resp Header Len Key";
+const RESPONSE_LEN_NONCE: &[u8] = b"AEAD Resp Header Len IV";
+const RESPONSE_KEY: &[u8] = b"AEAD Resp Header Key";
+const RESPONSE_NONCE: &[u8] = b"AEAD Resp Header IV";
+
+const VERSION: u8 = 1;
+/// Chunked body with masked lengths
+const OPTIONS: u8 = 0x01 | 0x04;
+const SECURITY_AES_128_GCM: u8 = 3;
+const SECURITY_CHACHA20_POLY1305: u8 = 4;
+const COMMAND_TCP: u8 = 1;
+
+/// Largest payload of a body chunk, as v2ray writes them
+const MAX_CHUNK_LEN: usize = 8192 - TAG_LEN - 2;
+/// Sealed length of a header
+const SEALED_LEN: usize = 2 + TAG_LEN;
+
+pub struct Vmess {
+    name: String,
+    server: Address,
+    cmd_key: [u8; 16],
+    kind: CipherKind,
+    tls: Option<TlsConnector>,
+    /// Streams run inside shadow-tls, `server` is then the shadow-tls server
+    shadow_tls: Option<ShadowTlsConfig>,
+    fingerprint: Option<ClientFingerprint>,
+    /// `h2` or `grpc`, bringing its own TLS
+    transport: Option<Transport>,
+}
+
+impl Vmess {
+    /// Proxy at `server` for the user `uuid`, `preferred` is the cipher of
+    /// `auto`
+    pub fn new(
+        name: &str,
+        server: Address,
+        uuid: &str,
+        alter_id: i64,
+        cipher: &str,
+        preferred: CipherKind,
+    ) -> io::Result<Vmess> {
+        if alter_id != 0 {
+            return Err(other(
+                "alterId must be 0, legacy VMess headers are not supported",
+            ));
+        }
+        let kind = match CipherKind::select(cipher, preferred) {
+            Some(kind @ CipherKind::Aes128Gcm) | Some(kind @ CipherKind::ChaCha20Poly1305) => kind,
+            _ => return Err(other(format!("cipher {} is not supported", cipher))),
+        };
+        Ok(Vmess {
+            name: name.to_owned(),
+            server,
+            cmd_key: cmd_key(&parse_uuid(uuid)?),
+            kind,
+            tls: None,
+            shadow_tls: None,
+            fingerprint: None,
+            transport: None,
+        })
+    }
+
+    /// Run streams over TLS to the server
+    pub fn tls(mut self, tls: Option<TlsConnector>) -> Vmess {
+        self.tls = tls;
+        self
+    }
+
+    /// Borrow a handshake with a ClientHello like the browser of
+    /// `fingerprint` before each stream, in place of TLS
+    pub fn shadow_tls(
+        mut self,
+        config: Option<ShadowTlsConfig>,
+        fingerprint: Option<ClientFingerprint>,
+    ) -> Vmess {
+        self.shadow_tls = config;
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// Carry streams over `transport` instead of TLS
+    pub fn transport(mut self, transport: Option<Transport>) -> Vmess {
+        self.transport = transport;
+        self
+    }
+}
+
+/// The 16 bytes of a UUID in its hyphenated or plain form
+fn parse_uuid(uuid: &str) -> io::Result<[u8; 16]> {
+    let hex: Vec<u8> = uuid.bytes().filter(|&b| b != b'-').collect();
+    let invalid = || other(format!("invalid uuid {}", uuid));
+    if hex.len() != 32 {
+        return Err(invalid());
+    }
+    let mut id = [0; 16];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        id[i] = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(id)
+}
+
+fn cmd_key(uuid: &[u8; 16]) -> [u8; 16] {
+    let mut data = uuid.to_vec();
+    data.extend_from_slice(CMD_KEY_SALT);
+    let mut key = [0; 16];
+    key.copy_from_slice(&Md5::digest(&data));
+    key
+}
+
+/// The key derivation of VMess, HMAC-SHA256 keyed by the salt and then
+/// nested once more for each element of `path`, the last one outermost
+fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
+    let mut keys = vec![KDF_SALT];
+    keys.extend_from_slice(path);
+    nested_hmac(&keys, key)
+}
+
+/// HMAC keyed by the last of `keys` over the hash of the ones before it,
+/// SHA-256 at the bottom
+fn nested_hmac(keys: &[&[u8]], data: &[u8]) -> [u8; 32] {
+    let (key, inner) = match keys.split_last() {
+        Some(split) => split,
+        None => {
+            let mut digest = [0; 32];
+            digest.copy_from_slice(&Sha256::digest(data));
+            return digest;
+        }
+    };
+    // Every key of the protocol fits a SHA-256 block
+    let mut block = [0u8; 64];
+    block[..key.len()].copy_from_slice(key);
+    let mut message: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    message.extend_from_slice(data);
+    let digest = nested_hmac(inner, &message);
+    let mut message: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    message.extend_from_slice(&digest);
+    nested_hmac(inner, &message)
+}
+
+/// AES-128-GCM keyed by `kdf` of `key` along `key_path`, and its nonce,
+/// `kdf` of `iv` along `nonce_path`
+fn header_cipher(
+    key: &[u8],
+    key_path: &[&[u8]],
+    iv: &[u8],
+    nonce_path: &[&[u8]],
+) -> io::Result<(Cipher, [u8; NONCE_LEN])> {
+    let cipher = Cipher::new(CipherKind::Aes128Gcm, &kdf(key, key_path)[..16])?;
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&kdf(iv, nonce_path)[..NONCE_LEN]);
+    Ok((cipher, nonce))
+}
+
+/// `time` and a checksum, encrypted with the command key
+fn auth_id(cmd_key: &[u8; 16], time: u64) -> [u8; 16] {
+    let mut id = [0; 16];
+    id[..8].copy_from_slice(&time.to_be_bytes());
+    rand::thread_rng().fill_bytes(&mut id[8..12]);
+    let crc = crc32fast::hash(&id[..12]);
+    id[12..].copy_from_slice(&crc.to_be_bytes());
+    let key = kdf(cmd_key, &[AUTH_ID_KEY]);
+    let mut block = GenericArray::clone_from_slice(&id);
+    Aes128::new(GenericArray::from_slice(&key[..16])).encrypt_block(&mut block);
+    id.copy_from_slice(&block);
+    id
+}
+
+/// FNV-1a, 32 bits, the checksum ending the request header
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash: u32, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Port ahead of the address, unlike SOCKS
+fn write_address(buf: &mut Vec<u8>, target: &Address) -> io::Result<()> {
+    buf.extend_from_slice(&target.port().to_be_bytes());
+    match *target {
+        Address::SocketAddr(SocketAddr::V4(ref addr)) => {
+            buf.push(0x01);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        Address::SocketAddr(SocketAddr::V6(ref addr)) => {
+            buf.push(0x03);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        Address::DomainName(ref dn) => {
+            if dn.0.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "domain name too long",
+                ));
+            }
+            buf.push(0x02);
+            buf.push(dn.0.len() as u8);
+            buf.extend_from_slice(dn.0.as_bytes());
+        }
+    }
+    Ok(())
+}
+
+/// Body key and IV of one direction, the response ones hash the request ones
+#[derive(Clone, Copy)]
+struct Keys {
+    key: [u8; 16],
+    iv: [u8; 16],
+}
+
+impl Keys {
+    fn random() -> Keys {
+        let mut keys = Keys {
+            key: [0; 16],
+            iv: [0; 16],
+        };
+        rand::thread_rng().fill_bytes(&mut keys.key);
+        rand::thread_rng().fill_bytes(&mut keys.iv);
+        keys
+    }
+
+    fn response(&self) -> Keys {
+        let mut keys = Keys {
+            key: [0; 16],
+            iv: [0; 16],
+        };
+        keys.key.copy_from_slice(&Sha256::digest(&self.key)[..16]);
+        keys.iv.copy_from_slice(&Sha256::digest(&self.iv)[..16]);
+        keys
+    }
+}
+
+/// Request header for `target`, sealed with the command key at `time`
+fn seal_request(
+    cmd_key: &[u8; 16],
+    kind: CipherKind,
+    keys: &Keys,
+    check: u8,
+    target: &Address,
+    time: u64,
+) -> io::Result<Vec<u8>> {
+    let security = match kind {
+        CipherKind::ChaCha20Poly1305 => SECURITY_CHACHA20_POLY1305,
+        _ => SECURITY_AES_128_GCM,
+    };
+    let mut header = vec![VERSION];
+    header.extend_from_slice(&keys.iv);
+    header.extend_from_slice(&keys.key);
+    header.extend_from_slice(&[check, OPTIONS, security, 0, COMMAND_TCP]);
+    write_address(&mut header, target)?;
+    let checksum = fnv1a(&header);
+    header.extend_from_slice(&checksum.to_be_bytes());
+
+    let auth_id = auth_id(cmd_key, time);
+    let mut nonce = [0; 8];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut len = (header.len() as u16).to_be_bytes().to_vec();
+    let (cipher, iv) = header_cipher(
+        cmd_key,
+        &[HEADER_LEN_KEY, &auth_id, &nonce],
+        cmd_key,
+        &[HEADER_LEN_NONCE, &auth_id, &nonce],
+    )?;
+    cipher.seal_with(&iv, &auth_id, &mut len)?;
+    let (cipher, iv) = header_cipher(
+        cmd_key,
+        &[HEADER_KEY, &auth_id, &nonce],
+        cmd_key,
+        &[HEADER_NONCE, &auth_id, &nonce],
+    )?;
+    cipher.seal_with(&iv, &auth_id, &mut header)?;
+
+    let mut sealed = auth_id.to_vec();
+    sealed.extend_from_slice(&len);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&header);
+    Ok(sealed)
+}
+
+/// Cipher of one direction of the body, with its nonce count and length
+/// masks
+struct Body {
+    cipher: Cipher,
+    iv: [u8; 16],
+    count: u16,
+    masks: Sha3XofReader,
+}
+
+impl Body {
+    fn new(kind: CipherKind, keys: &Keys) -> io::Result<Body> {
+        let cipher = match kind {
+            CipherKind::ChaCha20Poly1305 => {
+                // MD5 of the key and MD5 of that
+                let first = Md5::digest(&keys.key);
+                let mut key = first.to_vec();
+                key.extend_from_slice(&Md5::digest(&first));
+                Cipher::new(kind, &key)?
+            }
+            _ => Cipher::new(kind, &keys.key)?,
+        };
+        let mut shake = Shake128::default();
+        shake.input(keys.iv);
+        Ok(Body {
+            cipher,
+            iv: keys.iv,
+            count: 0,
+            masks: shake.xof_result(),
+        })
+    }
+
+    fn nonce(&mut self) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        nonce[2..].copy_from_slice(&self.iv[2..NONCE_LEN]);
+        self.count = self.count.wrapping_add(1);
+        nonce
+    }
+
+    fn mask(&mut self) -> u16 {
+        let mut mask = [0; 2];
+        self.masks.read(&mut mask);
+        u16::from_be_bytes(mask)
+    }
+
+    /// Chunk of `data`, empty ends the body
+    fn seal(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut payload = data.to_vec();
+        let nonce = self.nonce();
+        self.cipher.seal(&nonce, &mut payload)?;
+        let len = payload.len() as u16 ^ self.mask();
+        let mut chunk = len.to_be_bytes().to_vec();
+        chunk.extend_from_slice(&payload);
+        Ok(chunk)
+    }
+
+    /// Length of the sealed payload of the chunk its masked `len` starts
+    fn open_len(&mut self, len: [u8; 2]) -> usize {
+        (u16::from_be_bytes(len) ^ self.mask()) as usize
+    }
+
+    fn open(&mut self, payload: &mut Vec<u8>) -> io::Result<()> {
+        let nonce = self.nonce();
+        self.cipher.open(&nonce, payload)
+    }
+}
+
+/// Response header still to read, with what the request expects of it
+struct Pending {
+    keys: Keys,
+    check: u8,
+    /// Length of the header, once opened
+    len: Option<usize>,
+}
+
+/// VMess stream over `stream`, the request header goes out with the first
+/// write or flush
+pub struct VmessStream<S> {
+    stream: S,
+    kind: CipherKind,
+    writer: Body,
+    /// Set once the response header arrived
+    reader: Option<Body>,
+    response: Pending,
+    /// Sealed length of the chunk being read, once its length is read
+    pending: Option<usize>,
+    /// The server ended its body
+    eof: bool,
+    read_buf: BytesMut,
+    /// Payload of the current chunk not yet returned
+    plain: BytesMut,
+    /// The request header, then a chunk being written
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    /// The chunk ending our body is queued
+    ended: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> VmessStream<S> {
+    /// Stream to `target` for the user of `cmd_key`, timestamped `time`
+    pub fn new(
+        stream: S,
+        cmd_key: &[u8; 16],
+        kind: CipherKind,
+        target: &Address,
+        time: u64,
+    ) -> io::Result<VmessStream<S>> {
+        let keys = Keys::random();
+        let check = rand::random();
+        let header = seal_request(cmd_key, kind, &keys, check, target, time)?;
+        Ok(VmessStream {
+            stream,
+            kind,
+            writer: Body::new(kind, &keys)?,
+            reader: None,
+            response: Pending {
+                keys: keys.response(),
+                check,
+                len: None,
+            },
+            pending: None,
+            eof: false,
+            read_buf: BytesMut::new(),
+            plain: BytesMut::new(),
+            write_buf: header,
+            write_pos: 0,
+            ended: false,
+        })
+    }
+
+    /// Open the response header at the start of `read_buf`, `false` while
+    /// incomplete
+    fn open_response(&mut self) -> io::Result<bool> {
+        let keys = self.response.keys;
+        if self.response.len.is_none() {
+            if self.read_buf.len() < SEALED_LEN {
+                return Ok(false);
+            }
+            let mut len = self.read_buf.split_to(SEALED_LEN).to_vec();
+            let (cipher, nonce) = header_cipher(
+                &keys.key,
+                &[RESPONSE_LEN_KEY],
+                &keys.iv,
+                &[RESPONSE_LEN_NONCE],
+            )?;
+            cipher.open(&nonce, &mut len)?;
+            self.response.len = Some(u16::from_be_bytes([len[0], len[1]]) as usize);
+        }
+        let len = self.response.len.unwrap_or(0);
+        if self.read_buf.len() < len + TAG_LEN {
+            return Ok(false);
+        }
+        let mut header = self.read_buf.split_to(len + TAG_LEN).to_vec();
+        let (cipher, nonce) =
+            header_cipher(&keys.key, &[RESPONSE_KEY], &keys.iv, &[RESPONSE_NONCE])?;
+        cipher.open(&nonce, &mut header)?;
+        // Check byte, options, a command and its length
+        if header.len() < 4 || header[0] != self.response.check {
+            return Err(other("vmess response doesn't match the request"));
+        }
+        self.reader = Some(Body::new(self.kind, &keys)?);
+        Ok(true)
+    }
+
+    /// Move the payload of the next chunk into `plain`, `false` on EOF
+    fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            if self.eof {
+                return Poll::Ready(Ok(false));
+            }
+            match self.reader {
+                None => {
+                    if self.open_response()? {
+                        continue;
+                    }
+                }
+                Some(ref mut reader) => {
+                    if self.pending.is_none() && self.read_buf.len() >= 2 {
+                        let len = self.read_buf.split_to(2);
+                        let len = reader.open_len([len[0], len[1]]);
+                        if len < TAG_LEN {
+                            return Poll::Ready(Err(other("vmess chunk too short")));
+                        }
+                        // A chunk of only a tag ends the body
+                        if len == TAG_LEN {
+                            self.eof = true;
+                            return Poll::Ready(Ok(false));
+                        }
+                        self.pending = Some(len);
+                    }
+                    if let Some(len) = self.pending {
+                        if self.read_buf.len() >= len {
+                            let mut payload = self.read_buf.split_to(len).to_vec();
+                            reader.open(&mut payload)?;
+                            self.pending = None;
+                            self.plain = BytesMut::from(&payload[..]);
+                            return Poll::Ready(Ok(true));
+                        }
+                    }
+                }
+            }
+
+            let mut buf = [0u8; 4096];
+            let n = ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+            if n == 0 {
+                // Servers may close without ending the body
+                let clean = self.reader.is_some() && self.pending.is_none();
+                return if clean && self.read_buf.is_empty() {
+                    Poll::Ready(Ok(false))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            self.read_buf.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Write out the request header or the pending chunk
+    fn poll_write_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for VmessStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.plain.is_empty() {
+            if !ready!(this.poll_chunk(cx))? {
+                return Poll::Ready(Ok(0));
+            }
+        }
+        let n = cmp::min(buf.len(), this.plain.len());
+        buf[..n].copy_from_slice(&this.plain[..n]);
+        this.plain.advance(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for VmessStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+        // An empty chunk would end the body
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let data = &buf[..cmp::min(buf.len(), MAX_CHUNK_LEN)];
+        this.write_buf = this.writer.seal(data)?;
+        // The chunk is accepted once buffered, later calls finish writing it
+        if let Poll::Ready(Err(e)) = this.poll_write_chunk(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+        if !this.ended {
+            this.ended = true;
+            this.write_buf = this.writer.seal(&[])?;
+            ready!(this.poll_write_chunk(cx))?;
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+impl Outbound for Vmess {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn udp(&self) -> bool {
+        false
+    }
+
+    fn dial<'a>(
+        &'a self,
+        target: &'a Address,
+        dialer: &'a dyn Dialer,
+    ) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let stream = dialer.connect(&self.server).await?;
+            let stream: BoxStream = match (&self.transport, &self.shadow_tls, &self.tls) {
+                (Some(transport), ..) => {
+                    transport
+                        .connect(stream, server_name(&self.server)?)
+                        .await?
+                }
+                (None, Some(config), _) => {
+                    Box::new(shadow_tls::connect(stream, config, self.fingerprint).await?)
+                }
+                (None, None, Some(connector)) => {
+                    connect_tls(connector, &self.server, stream).await?
+                }
+                (None, None, None) => stream,
+            };
+            // Corrected by the offset of `clock-check`, if any
+            let time = clock::timestamp();
+            let mut stream = VmessStream::new(stream, &self.cmd_key, self.kind, target, time)?;
+            stream.flush().await?;
+            Ok(Box::new(stream) as BoxStream)
+        })
+    }
+
+    fn alive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use aes::BlockDecrypt;
+    use hmac::{Hmac, Mac};
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{
+        outbound::{shadow_tls::test::serve_shadow_tls, TcpDialer},
+        rt::{self, Runtime, TcpListener, TcpStream},
+        utils::DomainName,
+    };
+
+    const UUID: &str = "b831381d-6324-4d53-ad4f-8cda48b30811";
+
+    #[test]
+    fn derives_keys() {
+        // Without a path it is plain HMAC-SHA256 keyed by the salt
+        let mut mac = Hmac::<Sha256>::new_varkey(KDF_SALT).unwrap();
+        mac.input(b"key");
+        assert_eq!(kdf(b"key", &[])[..], mac.result().code()[..]);
+        // The value v2ray tests its KDF with
+        let path: &[&[u8]] = &[
+            b"Demo Path for KDF Value Test",
+            b"Demo Path for KDF Value Test2",
+            b"Demo Path for KDF Value Test3",
+        ];
+        assert_eq!(
+            kdf(b"Demo Key for KDF Value Test", path),
+            [
+                0x53, 0xe9, 0xd7, 0xe1, 0xbd, 0x7b, 0xd2, 0x50, 0x22, 0xb7, 0x1e, 0xad, 0x07, 0xd8,
+                0xa5, 0x96, 0xef, 0xc8, 0xa8, 0x45, 0xc7, 0x88, 0x86, 0x52, 0xfd, 0x68, 0x4b, 0x49,
+                0x03, 0xdc, 0x88, 0x92
+            ]
+        );
+
+        assert_eq!(
+            parse_uuid(UUID).unwrap(),
+            parse_uuid(&UUID.replace('-', "")).unwrap()
+        );
+        assert!(parse_uuid("b831381d-6324").is_err());
+        assert!(parse_uuid("x831381d-6324-4d53-ad4f-8cda48b30811").is_err());
+        let server = Address::DomainName(DomainName("vmess.example.com".to_owned(), 443));
+        let preferred = CipherKind::Aes128Gcm;
+        assert!(Vmess::new("vmess", server.clone(), UUID, 32, "auto", preferred).is_err());
+        assert!(Vmess::new("vmess", server.clone(), UUID, 0, "none", preferred).is_err());
+        let vmess = Vmess::new("vmess", server, UUID, 0, "chacha20-poly1305", preferred).unwrap();
+        assert_eq!(vmess.kind, CipherKind::ChaCha20Poly1305);
+    }
+
+    /// Read and check a request, as a server would
+    async fn accept(
+        stream: &mut TcpStream,
+        cmd_key: &[u8; 16],
+        time: u64,
+    ) -> (Keys, u8, CipherKind) {
+        let mut auth_id = [0; 16];
+        stream.read_exact(&mut auth_id).await.unwrap();
+        let key = kdf(cmd_key, &[AUTH_ID_KEY]);
+        let mut block = GenericArray::clone_from_slice(&auth_id);
+        Aes128::new(GenericArray::from_slice(&key[..16])).decrypt_block(&mut block);
+        // Sent within a second of `time`
+        let mut sent = [0; 8];
+        sent.copy_from_slice(&block[..8]);
+        let sent = u64::from_be_bytes(sent);
+        assert!(sent.abs_diff(time) <= 1);
+        assert_eq!(block[12..], crc32fast::hash(&block[..12]).to_be_bytes());
+
+        let mut len = vec![0; SEALED_LEN];
+        stream.read_exact(&mut len).await.unwrap();
+        let mut nonce = [0; 8];
+        stream.read_exact(&mut nonce).await.unwrap();
+        let (cipher, iv) = header_cipher(
+            cmd_key,
+            &[HEADER_LEN_KEY, &auth_id, &nonce],
+            cmd_key,
+            &[HEADER_LEN_NONCE, &auth_id, &nonce],
+        )
+        .unwrap();
+        cipher.open_with(&iv, &auth_id, &mut len).unwrap();
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let mut header = vec![0; len + TAG_LEN];
+        stream.read_exact(&mut header).await.unwrap();
+        let (cipher, iv) = header_cipher(
+            cmd_key,
+            &[HEADER_KEY, &auth_id, &nonce],
+            cmd_key,
+            &[HEADER_NONCE, &auth_id, &nonce],
+        )
+        .unwrap();
+        cipher.open_with(&iv, &auth_id, &mut header).unwrap();
+
+        let (header, checksum) = header.split_at(header.len() - 4);
+        assert_eq!(checksum, fnv1a(header).to_be_bytes());
+        assert_eq!(header[0], VERSION);
+        let mut keys = Keys::random();
+        keys.iv.copy_from_slice(&header[1..17]);
+        keys.key.copy_from_slice(&header[17..33]);
+        assert_eq!(header[34], OPTIONS);
+        let kind = match header[35] {
+            SECURITY_AES_128_GCM => CipherKind::Aes128Gcm,
+            SECURITY_CHACHA20_POLY1305 => CipherKind::ChaCha20Poly1305,
+            security => panic!("security {}", security),
+        };
+        assert_eq!(header[37], COMMAND_TCP);
+        // Port 443, then a domain
+        assert_eq!(header[38..41], [1, 187, 2]);
+        assert_eq!(&header[42..], b"example.com");
+        (keys, header[33], kind)
+    }
+
+    #[test]
+    fn carries_a_stream() {
+        Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = Address::SocketAddr(listener.local_addr().unwrap());
+            let target = Address::DomainName(DomainName("example.com".to_owned(), 443));
+            let cmd_key = cmd_key(&parse_uuid(UUID).unwrap());
+            let time = clock::timestamp();
+
+            let serve = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (keys, check, kind) = accept(&mut stream, &cmd_key, time).await;
+                let mut request = Body::new(kind, &keys).unwrap();
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).await.unwrap();
+                let mut payload = vec![0; request.open_len(len)];
+                stream.read_exact(&mut payload).await.unwrap();
+                request.open(&mut payload).unwrap();
+                assert_eq!(payload, b"ping");
+
+                let response = keys.response();
+                let mut len = (4u16).to_be_bytes().to_vec();
+                let (cipher, nonce) = header_cipher(
+                    &response.key,
+                    &[RESPONSE_LEN_KEY],
+                    &response.iv,
+                    &[RESPONSE_LEN_NONCE],
+                )
+                .unwrap();
+                cipher.seal(&nonce, &mut len).unwrap();
+                let mut header = vec![check, 0, 0, 0];
+                let (cipher, nonce) = header_cipher(
+                    &response.key,
+                    &[RESPONSE_KEY],
+                    &response.iv,
+                    &[RESPONSE_NONCE],
+                )
+                .unwrap();
+                cipher.seal(&nonce, &mut header).unwrap();
+                let mut body = Body::new(kind, &response).unwrap();
+                stream.write_all(&len).await.unwrap();
+                stream.write_all(&header).await.unwrap();
+                stream
+                    .write_all(&body.seal(b"pong").unwrap())
+                    .await
+                    .unwrap();
+                stream.write_all(&body.seal(&[]).unwrap()).await.unwrap();
+
+                // The client ends its body the same way
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).await.unwrap();
+                assert_eq!(request.open_len(len), TAG_LEN);
+            };
+            let client = async {
+                let vmess =
+                    Vmess::new("vmess", server, UUID, 0, "auto", CipherKind::Aes128Gcm).unwrap();
+                let stream = TcpDialer.connect(&vmess.server).await.unwrap();
+                let mut stream =
+                    VmessStream::new(stream, &vmess.cmd_key, vmess.kind, &target, time).unwrap();
+                stream.write_all(b"ping").await.unwrap();
+                let mut reply = Vec::new();
+                stream.read_to_end(&mut reply).await.unwrap();
+                assert_eq!(reply, b"pong");
+                stream.shutdown().await.unwrap();
+            };
+            futures::join!(serve, client);
+        });
+    }
+
+    #[test]
+    fn rejects_a_response_to_another_request() {
+        Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = Address::SocketAddr(listener.local_addr().unwrap());
+            let serve = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                // A header sealed under keys of no request
+                let response = Keys::random();
+                let mut len = (4u16).to_be_bytes().to_vec();
+                let (cipher, nonce) = header_cipher(
+                    &response.key,
+                    &[RESPONSE_LEN_KEY],
+                    &response.iv,
+                    &[RESPONSE_LEN_NONCE],
+                )
+                .unwrap();
+                cipher.seal(&nonce, &mut len).unwrap();
+                stream.write_all(&len).await.unwrap();
+                stream.write_all(&[0; 4 + TAG_LEN]).await.unwrap();
+            };
+            let client = async {
+                let vmess = Vmess::new(
+                    "vmess",
+                    server.clone(),
+                    UUID,
+                    0,
+                    "auto",
+                    CipherKind::Aes128Gcm,
+                )
+                .unwrap();
+                let target = Address::DomainName(DomainName("example.com".to_owned(), 443));
+                let mut stream = vmess.dial(&target, &TcpDialer).await.unwrap();
+                let mut buf = [0; 16];
+                assert!(stream.read(&mut buf).await.is_err());
+            };
+            futures::join!(serve, client);
+        });
+    }
+
+    #[test]
+    fn dials_through_shadow_tls() {
+        Runtime::new().unwrap().block_on(async {
+            let mut vmess = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let upstream = vmess.local_addr().unwrap();
+            let cmd_key = cmd_key(&parse_uuid(UUID).unwrap());
+            let serve = async move {
+                let (mut stream, _) = vmess.accept().await.unwrap();
+                let (_, _, kind) = accept(&mut stream, &cmd_key, clock::timestamp()).await;
+                assert_eq!(kind, CipherKind::ChaCha20Poly1305);
+            };
+
+            let mut shadow_tls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = shadow_tls.local_addr().unwrap();
+            rt::spawn(async move {
+                let (client, _) = shadow_tls.accept().await.unwrap();
+                serve_shadow_tls(client, "stls-secret", upstream).await;
+            });
+
+            let client = async {
+                let vmess = Vmess::new(
+                    "vmess",
+                    Address::SocketAddr(server),
+                    UUID,
+                    0,
+                    "chacha20-poly1305",
+                    CipherKind::Aes128Gcm,
+                )
+                .unwrap()
+                .shadow_tls(
+                    Some(ShadowTlsConfig {
+                        password: "stls-secret".to_owned(),
+                        host: "www.example.com".to_owned(),
+                    }),
+                    Some(ClientFingerprint::Chrome),
+                );
+                let target = Address::DomainName(DomainName("example.com".to_owned(), 443));
+                vmess.dial(&target, &TcpDialer).await.unwrap();
+            };
+            futures::join!(serve, client);
+        });
+    }
+}
@@ -0,0 +1,126 @@
+//! Named configs in a directory, switched while running
+//!
+//! A profile is a full config file `<name>.yaml` (or `.yml`) in the profiles
+//! directory. Activating one hands its config to `engine::run_profile`,
+//! which stops serving the current one and serves the new one in its place.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::config::Config;
+
+pub struct Profiles {
+    dir: PathBuf,
+    active: Mutex<Option<String>>,
+    switch: UnboundedSender<Config>,
+}
+
+impl Profiles {
+    /// Profiles in `dir`, with the receiver of activated configs
+    pub fn new<P: AsRef<Path>>(dir: P) -> (Profiles, UnboundedReceiver<Config>) {
+        let (switch, switches) = mpsc::unbounded();
+        let profiles = Profiles {
+            dir: dir.as_ref().to_owned(),
+            active: Mutex::new(None),
+            switch,
+        };
+        (profiles, switches)
+    }
+
+    /// Names of the profiles found, sorted
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let yaml = path
+                .extension()
+                .is_some_and(|ext| ext == "yaml" || ext == "yml");
+            if let (true, Some(name)) = (yaml, path.file_stem().and_then(|s| s.to_str())) {
+                if valid_name(name) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    pub fn active(&self) -> Option<String> {
+        self.active.lock().unwrap().clone()
+    }
+
+    /// Load and check the config of `name` without switching to it
+    pub fn load(&self, name: &str) -> io::Result<Config> {
+        let path = self.path(name)?;
+        Config::load_from_file(&path.to_string_lossy()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("profile {}: {:?}", name, e),
+            )
+        })
+    }
+
+    /// Switch to `name`, failing when its config doesn't load
+    pub fn activate(&self, name: &str) -> io::Result<()> {
+        let config = self.load(name)?;
+        self.switch
+            .unbounded_send(config)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "profiles are not served"))?;
+        self.set_active(name);
+        Ok(())
+    }
+
+    /// Record `name` as served, set when starting with one of the profiles
+    pub fn set_active(&self, name: &str) {
+        *self.active.lock().unwrap() = Some(name.to_owned());
+    }
+
+    fn path(&self, name: &str) -> io::Result<PathBuf> {
+        if !valid_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid profile name \"{}\"", name),
+            ));
+        }
+        ["yaml", "yml"]
+            .iter()
+            .map(|ext| self.dir.join(format!("{}.{}", name, ext)))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("profile \"{}\" not found", name),
+                )
+            })
+    }
+}
+
+/// Plain file names only, API callers must not reach outside the directory
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names_stay_inside_the_directory() {
+        assert!(valid_name("work"));
+        assert!(valid_name("home-2.v6"));
+        assert!(!valid_name(""));
+        assert!(!valid_name(".."));
+        assert!(!valid_name("../etc/passwd"));
+        assert!(!valid_name("a/b"));
+    }
+}
@@ -1,20 +1,180 @@
-use bytes::BytesMut;
-use futures::StreamExt;
-use http::{header::HeaderValue, Request, Response};
+use bytes::{Buf, Bytes, BytesMut};
+use http::{
+    header::{self, HeaderValue},
+    Request, Response, StatusCode,
+};
 use std::{fmt, io};
-use tokio::codec::{Decoder, Encoder};
+use tokio_util::codec::{Decoder, Encoder};
 
-pub struct Http;
+/// Most headers accepted in one request
+const MAX_HEADERS: usize = 64;
+/// Default longest request head, or trailer section, buffered before giving up
+pub const MAX_HEAD_LEN: usize = 64 * 1024;
+/// Longest chunk size line, extensions included
+const MAX_CHUNK_LINE_LEN: usize = 4096;
+
+/// A request is decoded as its head, any number of body parts and an end
+/// marker, so bodies are streamed and the next pipelined request starts
+/// right after the end of the previous body.
+#[derive(Debug)]
+pub enum Message {
+    Request(Request<()>),
+    /// Part of the body of the last request, chunked framing removed
+    Body(Bytes),
+    /// The last request is complete, sent for requests without body as well
+    End,
+}
+
+/// Framing of the body being decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Head,
+    /// Remaining bytes of a Content-Length body
+    Length(u64),
+    ChunkSize,
+    /// Remaining bytes of the current chunk
+    ChunkData(u64),
+    /// CRLF closing a chunk
+    ChunkEnd,
+    /// Trailer lines until the empty one, with bytes read so far
+    Trailer(usize),
+}
+
+pub struct Http {
+    state: State,
+    max_head_len: usize,
+}
+
+impl Http {
+    pub fn new() -> Http {
+        Http::with_max_head_len(MAX_HEAD_LEN)
+    }
+
+    /// Refuse request heads and trailer sections longer than `max_head_len`
+    pub fn with_max_head_len(max_head_len: usize) -> Http {
+        Http {
+            state: State::Head,
+            max_head_len,
+        }
+    }
+}
+
+impl Default for Http {
+    fn default() -> Http {
+        Http::new()
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Whether the client waits for `100 Continue` before sending the body
+pub fn expects_continue(request: &Request<()>) -> bool {
+    request
+        .headers()
+        .get(header::EXPECT)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
+/// Interim response telling the client to go on with the body
+pub fn continue_response() -> Response<String> {
+    Response::builder()
+        .status(StatusCode::CONTINUE)
+        .body(String::new())
+        .unwrap()
+}
+
+/// Body framing of a request per RFC 7230 section 3.3.3
+///
+/// Requests carrying both headers or conflicting lengths are refused, they
+/// are the usual vehicle of request smuggling.
+fn body_state(request: &Request<()>) -> io::Result<State> {
+    let headers = request.headers();
+    let mut encodings = headers.get_all(header::TRANSFER_ENCODING).iter().peekable();
+    if encodings.peek().is_some() {
+        if headers.contains_key(header::CONTENT_LENGTH) {
+            return Err(invalid("both Transfer-Encoding and Content-Length present"));
+        }
+        let last = encodings
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .last();
+        return match last {
+            Some(last) if last.eq_ignore_ascii_case("chunked") => Ok(State::ChunkSize),
+            _ => Err(invalid("request body not chunked")),
+        };
+    }
+
+    let mut length = None;
+    for value in headers.get_all(header::CONTENT_LENGTH) {
+        for part in value
+            .to_str()
+            .map_err(|_| invalid("invalid Content-Length"))?
+            .split(',')
+        {
+            let n = part
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| invalid("invalid Content-Length"))?;
+            if length.is_some_and(|l| l != n) {
+                return Err(invalid("conflicting Content-Length"));
+            }
+            length = Some(n);
+        }
+    }
+    Ok(State::Length(length.unwrap_or(0)))
+}
+
+/// Split off one CRLF terminated line, without the CRLF
+fn take_line(src: &mut BytesMut, max: usize) -> io::Result<Option<BytesMut>> {
+    match src.windows(2).position(|w| w == b"\r\n") {
+        Some(pos) if pos <= max => {
+            let line = src.split_to(pos);
+            src.advance(2);
+            Ok(Some(line))
+        }
+        Some(_) => Err(invalid("http line too long")),
+        None if src.len() > max + 1 => Err(invalid("http line too long")),
+        None => Ok(None),
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> io::Result<u64> {
+    // Extensions after `;` are ignored
+    let size = line.split(|b| *b == b';').next().unwrap_or(line);
+    let size = std::str::from_utf8(size)
+        .map_err(|_| invalid("invalid chunk size"))?
+        .trim();
+    if size.is_empty() {
+        return Err(invalid("invalid chunk size"));
+    }
+    u64::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))
+}
 
 /// Implementation of encoding an HTTP response into a `BytesMut`, basically
 /// just writing out an HTTP/1.1 response.
-impl Encoder for Http {
-    type Item = Response<String>;
+impl Encoder<Response<String>> for Http {
     type Error = io::Error;
 
     fn encode(&mut self, item: Response<String>, dst: &mut BytesMut) -> io::Result<()> {
         use std::fmt::Write;
 
+        // Interim responses carry neither body nor Content-Length
+        if item.status().is_informational() {
+            write!(BytesWrite(dst), "HTTP/1.1 {}\r\n", item.status()).unwrap();
+            for (k, v) in item.headers() {
+                dst.extend_from_slice(k.as_str().as_bytes());
+                dst.extend_from_slice(b": ");
+                dst.extend_from_slice(v.as_bytes());
+                dst.extend_from_slice(b"\r\n");
+            }
+            dst.extend_from_slice(b"\r\n");
+            return Ok(());
+        }
+
         write!(
             BytesWrite(dst),
             "\
@@ -64,29 +224,102 @@ impl Encoder for Http {
 /// that information to construct an instance of a `http::Request` object,
 /// trying to avoid allocations where possible.
 impl Decoder for Http {
-    type Item = Request<()>;
+    type Item = Message;
     type Error = io::Error;
 
-    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Request<()>>> {
-        // TODO: we should grow this headers array if parsing fails and asks
-        //       for more headers
-        let mut headers = [None; 16];
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        loop {
+            match self.state {
+                State::Head => {
+                    return match self.decode_head(src)? {
+                        Some(request) => {
+                            self.state = body_state(&request)?;
+                            Ok(Some(Message::Request(request)))
+                        }
+                        None => Ok(None),
+                    };
+                }
+                State::Length(0) => {
+                    self.state = State::Head;
+                    return Ok(Some(Message::End));
+                }
+                State::Length(remaining) | State::ChunkData(remaining) => {
+                    if src.is_empty() {
+                        return Ok(None);
+                    }
+                    let n = remaining.min(src.len() as u64);
+                    let data = src.split_to(n as usize).freeze();
+                    self.state = match self.state {
+                        State::Length(_) => State::Length(remaining - n),
+                        _ if remaining == n => State::ChunkEnd,
+                        _ => State::ChunkData(remaining - n),
+                    };
+                    return Ok(Some(Message::Body(data)));
+                }
+                State::ChunkSize => match take_line(src, MAX_CHUNK_LINE_LEN)? {
+                    Some(line) => {
+                        self.state = match parse_chunk_size(&line)? {
+                            0 => State::Trailer(0),
+                            size => State::ChunkData(size),
+                        };
+                    }
+                    None => return Ok(None),
+                },
+                State::ChunkEnd => {
+                    if src.len() < 2 {
+                        return Ok(None);
+                    }
+                    if &src[..2] != b"\r\n" {
+                        return Err(invalid("missing CRLF after chunk"));
+                    }
+                    src.advance(2);
+                    self.state = State::ChunkSize;
+                }
+                State::Trailer(read) => {
+                    let max = self.max_head_len.saturating_sub(read);
+                    match take_line(src, max)? {
+                        // Trailer fields are dropped, nothing downstream uses them
+                        Some(ref line) if !line.is_empty() => {
+                            self.state = State::Trailer(read + line.len() + 2);
+                        }
+                        Some(_) => {
+                            self.state = State::Head;
+                            return Ok(Some(Message::End));
+                        }
+                        None => return Ok(None),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Http {
+    fn decode_head(&mut self, src: &mut BytesMut) -> io::Result<Option<Request<()>>> {
+        let mut headers = [None; MAX_HEADERS];
         let (method, path, version, amt) = {
-            let mut parsed_headers = [httparse::EMPTY_HEADER; 16];
+            let mut parsed_headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
             let mut r = httparse::Request::new(&mut parsed_headers);
             let status = r.parse(src).map_err(|e| {
                 let msg = format!("failed to parse http request: {:?}", e);
-                io::Error::new(io::ErrorKind::Other, msg)
+                io::Error::new(io::ErrorKind::InvalidData, msg)
             })?;
 
             let amt = match status {
                 httparse::Status::Complete(amt) => amt,
+                // Don't buffer forever waiting for the end of the head
+                httparse::Status::Partial if src.len() >= self.max_head_len => {
+                    return Err(invalid("http request head too large"));
+                }
                 httparse::Status::Partial => return Ok(None),
             };
 
+            // Every parsed slice borrows from `src`, except possibly empty ones
             let to_slice = |a: &[u8]| {
+                if a.is_empty() {
+                    return (0, 0);
+                }
                 let start = a.as_ptr() as usize - src.as_ptr() as usize;
-                assert!(start < src.len());
                 (start, start + a.len())
             };
 
@@ -96,12 +329,15 @@ impl Decoder for Http {
                 headers[i] = Some((k, v));
             }
 
-            (
-                to_slice(r.method.unwrap().as_bytes()),
-                to_slice(r.path.unwrap().as_bytes()),
-                r.version.unwrap(),
-                amt,
-            )
+            match (r.method, r.path, r.version) {
+                (Some(method), Some(path), Some(version)) => (
+                    to_slice(method.as_bytes()),
+                    to_slice(path.as_bytes()),
+                    version,
+                    amt,
+                ),
+                _ => return Err(invalid("incomplete http request line")),
+            }
         };
         if version != 1 {
             return Err(io::Error::new(
@@ -110,17 +346,18 @@ impl Decoder for Http {
             ));
         }
         let data = src.split_to(amt).freeze();
-        let mut ret = Request::builder();
-        ret.method(&data[method.0..method.1]);
-        ret.uri(data.slice(path.0, path.1));
-        ret.version(http::Version::HTTP_11);
+        let mut ret = Request::builder()
+            .method(&data[method.0..method.1])
+            .uri(&data[path.0..path.1])
+            .version(http::Version::HTTP_11);
         for header in headers.iter() {
             let (k, v) = match *header {
                 Some((ref k, ref v)) => (k, v),
                 None => break,
             };
-            let value = unsafe { HeaderValue::from_shared_unchecked(data.slice(v.0, v.1)) };
-            ret.header(&data[k.0..k.1], value);
+            let value = HeaderValue::from_maybe_shared(data.slice(v.0..v.1))
+                .map_err(|_| invalid("invalid http header value"))?;
+            ret = ret.header(&data[k.0..k.1], value);
         }
 
         let req = ret
@@ -212,3 +449,155 @@ mod date {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_all(data: &[u8]) -> io::Result<Vec<Message>> {
+        let mut codec = Http::new();
+        let mut src = BytesMut::from(data);
+        let mut messages = Vec::new();
+        while let Some(message) = codec.decode(&mut src)? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    fn body(messages: &[Message]) -> Vec<u8> {
+        messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Body(data) => Some(&data[..]),
+                _ => None,
+            })
+            .flat_map(|d| d.iter().cloned())
+            .collect()
+    }
+
+    #[test]
+    fn pipelined_requests_with_content_length() {
+        let messages = decode_all(
+            b"POST /a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello\
+              GET /b HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .unwrap();
+        let paths: Vec<&str> = messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Request(r) => Some(r.uri().path()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(paths, ["/a", "/b"]);
+        assert_eq!(body(&messages), b"hello");
+        match messages.last() {
+            Some(Message::End) => {}
+            other => panic!("expected end, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chunked_body_with_trailer() {
+        let messages = decode_all(
+            b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Sum: 1\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(body(&messages), b"hello world");
+        match messages.last() {
+            Some(Message::End) => {}
+            other => panic!("expected end, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn partial_body_waits_for_more() {
+        let mut codec = Http::new();
+        let mut src = BytesMut::from(&b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nab"[..]);
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(Message::Request(..))
+        ));
+        assert!(match codec.decode(&mut src).unwrap() {
+            Some(Message::Body(ref d)) => &d[..] == b"ab",
+            _ => false,
+        });
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"cd");
+        assert!(match codec.decode(&mut src).unwrap() {
+            Some(Message::Body(ref d)) => &d[..] == b"cd",
+            _ => false,
+        });
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(Message::End)
+        ));
+    }
+
+    #[test]
+    fn ambiguous_framing_is_refused() {
+        assert!(decode_all(
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n"
+        )
+        .is_err());
+        assert!(
+            decode_all(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n")
+                .is_err()
+        );
+        assert!(decode_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn expect_continue() {
+        let messages =
+            decode_all(b"PUT / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 1\r\n\r\n")
+                .unwrap();
+        match messages.first() {
+            Some(Message::Request(r)) => assert!(expects_continue(r)),
+            other => panic!("expected request, got {:?}", other),
+        }
+
+        let mut dst = BytesMut::new();
+        Http::new().encode(continue_response(), &mut dst).unwrap();
+        assert_eq!(&dst[..], b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    #[test]
+    fn head_over_limit_is_refused() {
+        let mut codec = Http::with_max_head_len(32);
+        let mut src = BytesMut::from(&b"GET / HTTP/1.1\r\nHost: a\r\n"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"X-Padding: aaaaaaaaaa\r\n");
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn malformed_heads_are_errors() {
+        for data in [
+            &b"\x00\xff\r\n\r\n"[..],
+            b"GET\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost x\r\n\r\n",
+            b"GET / HTTP/1.0\r\nHost: x\r\n\r\n",
+        ] {
+            assert!(decode_all(data).is_err(), "{:?}", data);
+        }
+
+        // Every prefix of a request only asks for more
+        let request = b"GET /a HTTP/1.1\r\nHost: x\r\n\r\n";
+        for len in 0..request.len() {
+            assert!(decode_all(&request[..len]).unwrap().is_empty());
+        }
+
+        // More headers than the old fixed array of 16
+        let mut many = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..40 {
+            many.extend_from_slice(format!("X-H{}: {}\r\n", i, i).as_bytes());
+        }
+        many.extend_from_slice(b"\r\n");
+        match decode_all(&many).unwrap().first() {
+            Some(Message::Request(r)) => assert_eq!(r.headers().len(), 40),
+            other => panic!("expected request, got {:?}", other),
+        }
+    }
+}
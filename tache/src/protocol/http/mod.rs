@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 mod http;
 
-pub use self::http::Http;
+pub use self::http::{continue_response, expects_continue, Http, Message, MAX_HEAD_LEN};
@@ -1,6 +1,9 @@
 mod http;
+pub mod proxy_protocol;
+#[cfg(feature = "ring-crypto")]
+pub mod quic;
 mod shadowsocks;
-mod socks;
-mod vmess;
+pub mod socks;
+pub mod vmess;
 
-pub use self::http::Http;
+pub use self::http::{continue_response, expects_continue, Http, Message, MAX_HEAD_LEN};
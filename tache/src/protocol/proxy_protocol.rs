@@ -0,0 +1,194 @@
+//! HAProxy PROXY protocol, the client address a load balancer sends ahead of
+//! the connection it forwards
+//!
+//! Version 1 is a text line like `PROXY TCP4 192.0.2.1 198.51.100.1 56324
+//! 443\r\n`, version 2 a binary header starting with a fixed signature.
+//! Headers are read exactly, the client's own bytes stay in the stream.
+//! `LOCAL` and `UNKNOWN` headers, sent by health checks, carry no addresses.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 line, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+/// Source and destination of the proxied connection
+pub type Addresses = (SocketAddr, SocketAddr);
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header, {}", msg),
+    )
+}
+
+/// Read the header of either version off `stream`
+pub async fn read_header<R>(stream: &mut R) -> io::Result<Option<Addresses>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut head = [0u8; 16];
+    stream.read_exact(&mut head[..6]).await?;
+    if &head[..6] == b"PROXY " {
+        let mut line = head[..6].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                return Err(invalid("line too long"));
+            }
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await?;
+            line.push(byte[0]);
+        }
+        return parse_v1(&line);
+    }
+    stream.read_exact(&mut head[6..]).await?;
+    if head[..12] != V2_SIGNATURE {
+        return Err(invalid("missing"));
+    }
+    let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+    let mut rest = vec![0u8; len];
+    stream.read_exact(&mut rest).await?;
+    parse_v2(&head, &rest)
+}
+
+/// `line` from `PROXY` to the CRLF
+fn parse_v1(line: &[u8]) -> io::Result<Option<Addresses>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("not text"))?;
+    let fields = line.trim_end().split(' ').collect::<Vec<_>>();
+    match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {}
+        _ => return Err(invalid(line.trim_end())),
+    }
+    let ip = |s: &str| s.parse::<IpAddr>().map_err(|_| invalid("bad address"));
+    let port = |s: &str| s.parse::<u16>().map_err(|_| invalid("bad port"));
+    let source = SocketAddr::new(ip(fields[2])?, port(fields[4])?);
+    let destination = SocketAddr::new(ip(fields[3])?, port(fields[5])?);
+    if source.is_ipv4() != (fields[1] == "TCP4") || destination.is_ipv4() != source.is_ipv4() {
+        return Err(invalid("addresses don't match the protocol"));
+    }
+    Ok(Some((source, destination)))
+}
+
+/// The 16 bytes of `head` up to the length and the `rest` it counts
+fn parse_v2(head: &[u8], rest: &[u8]) -> io::Result<Option<Addresses>> {
+    if head[12] >> 4 != 2 {
+        return Err(invalid("unknown version"));
+    }
+    match head[12] & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unknown command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([rest[at], rest[at + 1]]);
+    match head[13] >> 4 {
+        1 if rest.len() >= 12 => {
+            let ip = |at: usize| Ipv4Addr::new(rest[at], rest[at + 1], rest[at + 2], rest[at + 3]);
+            Ok(Some((
+                SocketAddr::new(ip(0).into(), port(8)),
+                SocketAddr::new(ip(4).into(), port(10)),
+            )))
+        }
+        2 if rest.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&rest[at..at + 16]);
+                Ipv6Addr::from(octets)
+            };
+            Ok(Some((
+                SocketAddr::new(ip(0).into(), port(32)),
+                SocketAddr::new(ip(16).into(), port(34)),
+            )))
+        }
+        1 | 2 => Err(invalid("addresses cut short")),
+        // Unspecified or Unix sockets
+        _ => Ok(None),
+    }
+}
+
+/// Header of `version` 1 or 2 for `addresses`, unknown ones or ones of
+/// different families are sent as `UNKNOWN` or `LOCAL`
+pub fn encode(version: u8, addresses: Option<Addresses>) -> Vec<u8> {
+    let addresses = addresses.filter(|(src, dst)| src.is_ipv4() == dst.is_ipv4());
+    if version == 1 {
+        return match addresses {
+            Some((src, dst)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if src.is_ipv4() { "TCP4" } else { "TCP6" },
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        };
+    }
+    let mut header = V2_SIGNATURE.to_vec();
+    let (src, dst) = match addresses {
+        Some(addresses) => addresses,
+        None => {
+            header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+            return header;
+        }
+    };
+    let mut body = Vec::with_capacity(36);
+    let family = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            body.extend_from_slice(&s.octets());
+            body.extend_from_slice(&d.octets());
+            0x11
+        }
+        (s, d) => {
+            body.extend_from_slice(&to_v6(s).octets());
+            body.extend_from_slice(&to_v6(d).octets());
+            0x21
+        }
+    };
+    body.extend_from_slice(&src.port().to_be_bytes());
+    body.extend_from_slice(&dst.port().to_be_bytes());
+    header.extend_from_slice(&[0x21, family]);
+    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    header.extend_from_slice(&body);
+    header
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_both_versions() {
+        let src: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        let dst: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let v1 = encode(1, Some((src, dst)));
+        assert_eq!(
+            v1,
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n".to_vec()
+        );
+        assert_eq!(parse_v1(&v1).unwrap(), Some((src, dst)));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1(b"PROXY TCP6 192.0.2.1 198.51.100.1 1 2\r\n").is_err());
+
+        let src: SocketAddr = "[2001:db8::1]:56324".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        let v2 = encode(2, Some((src, dst)));
+        assert_eq!(v2.len(), 16 + 36);
+        assert_eq!(parse_v2(&v2[..16], &v2[16..]).unwrap(), Some((src, dst)));
+        let local = encode(2, None);
+        assert_eq!(parse_v2(&local[..16], &local[16..]).unwrap(), None);
+    }
+}
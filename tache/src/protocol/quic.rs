@@ -0,0 +1,633 @@
+//! QUIC connections over a UDP socket of their own
+//!
+//! quinn-proto runs the protocol, this drives it: a task per connection
+//! reads the socket, fires the timers and sends what the connection queues,
+//! waking the streams and datagram readers that wait on it. quinn's own
+//! endpoint isn't used, it decodes the address of received packets by
+//! reinterpreting a `sockaddr` as a std `SocketAddr`, whose layout is no
+//! longer that.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use futures::future::poll_fn;
+use log::debug;
+use quinn_proto::{
+    ClientConfig, ConnectionError, ConnectionHandle, DatagramEvent, Dir, Endpoint, EndpointConfig,
+    Event, ReadError, StreamId, TimerSetting, TimerTable, Transmit, WriteError,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{delay_until, Delay},
+};
+
+use crate::rt::{self, UdpSocket};
+
+/// Largest UDP payload read off the socket
+const MAX_DATAGRAM: usize = 64 * 1024;
+/// Packets read per wakeup before giving other tasks a turn
+const RECV_BOUND: usize = 32;
+
+fn lost(e: &ConnectionError) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string())
+}
+
+struct State {
+    endpoint: Endpoint,
+    handle: ConnectionHandle,
+    conn: quinn_proto::Connection,
+    timers: TimerTable<Option<Instant>>,
+    connected: bool,
+    /// Why the connection is gone, `None` while it is open
+    error: Option<ConnectionError>,
+    driver: Option<Waker>,
+    on_connected: Option<Waker>,
+    blocked_readers: HashMap<StreamId, Waker>,
+    blocked_writers: HashMap<StreamId, Waker>,
+    /// Waiting for the peer to allow more streams
+    opening: Vec<Waker>,
+    /// Waiting for the peer to open a stream
+    accepting: Vec<Waker>,
+    datagram_reader: Option<Waker>,
+}
+
+impl State {
+    fn wake_driver(&mut self) {
+        if let Some(driver) = self.driver.take() {
+            driver.wake();
+        }
+    }
+
+    /// Pass the events of the connection to whoever waits for them
+    fn forward_events(&mut self) {
+        while let Some(event) = self.conn.poll() {
+            match event {
+                Event::Connected => {
+                    self.connected = true;
+                    if let Some(waker) = self.on_connected.take() {
+                        waker.wake();
+                    }
+                }
+                Event::ConnectionLost { reason } => self.terminate(reason),
+                Event::StreamReadable { stream } => {
+                    if let Some(waker) = self.blocked_readers.remove(&stream) {
+                        waker.wake();
+                    }
+                }
+                Event::StreamWritable { stream } => {
+                    if let Some(waker) = self.blocked_writers.remove(&stream) {
+                        waker.wake();
+                    }
+                }
+                Event::StreamAvailable { .. } => self.opening.drain(..).for_each(Waker::wake),
+                Event::StreamOpened { .. } => self.accepting.drain(..).for_each(Waker::wake),
+                Event::DatagramReceived => {
+                    if let Some(waker) = self.datagram_reader.take() {
+                        waker.wake();
+                    }
+                }
+                Event::StreamFinished { .. } | Event::DatagramSendUnblocked => {}
+            }
+        }
+    }
+
+    /// Fail everything waiting on the connection with `reason`
+    fn terminate(&mut self, reason: ConnectionError) {
+        if self.error.is_none() {
+            self.error = Some(reason);
+        }
+        self.blocked_readers.drain().for_each(|(_, w)| w.wake());
+        self.blocked_writers.drain().for_each(|(_, w)| w.wake());
+        self.opening.drain(..).for_each(Waker::wake);
+        self.accepting.drain(..).for_each(Waker::wake);
+        if let Some(waker) = self.datagram_reader.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.on_connected.take() {
+            waker.wake();
+        }
+    }
+
+    fn close(&mut self) {
+        self.conn.close(Instant::now(), 0u32.into(), Bytes::new());
+        self.terminate(ConnectionError::LocallyClosed);
+        self.wake_driver();
+    }
+}
+
+/// Runs a connection until it is drained
+struct Driver {
+    state: Arc<Mutex<State>>,
+    socket: UdpSocket,
+    buf: Vec<u8>,
+    outgoing: VecDeque<Transmit>,
+    /// Fires at the earliest timer of the connection
+    timer: Option<Delay>,
+}
+
+impl Driver {
+    /// Read what arrived, returning whether there may be more
+    fn recv(&mut self, cx: &mut Context<'_>, state: &mut State) -> io::Result<bool> {
+        for _ in 0..RECV_BOUND {
+            let (n, remote) = match self.socket.poll_recv_from(cx, &mut self.buf) {
+                Poll::Ready(Ok(recvd)) => recvd,
+                Poll::Pending => return Ok(false),
+                // Not part of QUIC, an attacker may forge them
+                Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Poll::Ready(Err(e)) => return Err(e),
+            };
+            let now = Instant::now();
+            let data = self.buf[..n].into();
+            match state.endpoint.handle(now, remote, None, data) {
+                Some((handle, DatagramEvent::ConnectionEvent(event))) if handle == state.handle => {
+                    state.conn.handle_event(event)
+                }
+                // Only the one connection is expected here
+                Some(_) | None => {}
+            }
+        }
+        Ok(true)
+    }
+
+    /// Fire expired timers and apply new ones, returning whether any fired
+    fn timers(&mut self, cx: &mut Context<'_>, state: &mut State) -> bool {
+        let now = Instant::now();
+        let mut fired = false;
+        for (timer, slot) in &mut state.timers {
+            if slot.is_some_and(|at| at <= now) {
+                *slot = None;
+                state.conn.handle_timeout(now, timer);
+                fired = true;
+            }
+        }
+        while let Some(update) = state.conn.poll_timers() {
+            state.timers[update.timer] = match update.update {
+                TimerSetting::Start(at) => Some(at),
+                TimerSetting::Stop => None,
+            };
+        }
+
+        let next = state.timers.iter().filter_map(|(_, at)| *at).min();
+        match next {
+            Some(at) => {
+                let at = tokio::time::Instant::from_std(at);
+                match self.timer {
+                    Some(ref mut delay) => delay.reset(at),
+                    None => self.timer = Some(delay_until(at)),
+                }
+                // Registers the wakeup, or fires on the next pass
+                if let Poll::Ready(()) = Pin::new(self.timer.as_mut().unwrap()).poll(cx) {
+                    fired = true;
+                }
+            }
+            None => self.timer = None,
+        }
+        fired
+    }
+
+    /// Send what the connection and the endpoint queued
+    fn send(&mut self, cx: &mut Context<'_>, state: &mut State) {
+        let now = Instant::now();
+        while let Some(event) = state.conn.poll_endpoint_events() {
+            if let Some(event) = state.endpoint.handle_event(state.handle, event) {
+                state.conn.handle_event(event);
+            }
+        }
+        while let Some(transmit) = state.conn.poll_transmit(now) {
+            self.outgoing.push_back(transmit);
+        }
+        while let Some(transmit) = state.endpoint.poll_transmit() {
+            self.outgoing.push_back(transmit);
+        }
+        while let Some(transmit) = self.outgoing.front() {
+            match self
+                .socket
+                .poll_send_to(cx, &transmit.contents, &transmit.destination)
+            {
+                Poll::Ready(Ok(_)) => {}
+                // Lost like any other packet, QUIC sends it again
+                Poll::Ready(Err(e)) => debug!("Failed to send a QUIC packet, err: {}", e),
+                Poll::Pending => break,
+            }
+            self.outgoing.pop_front();
+        }
+    }
+}
+
+impl Future for Driver {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let shared = this.state.clone();
+        let mut guard = shared.lock().unwrap();
+        let state = &mut *guard;
+        loop {
+            let more = match this.recv(cx, state) {
+                Ok(more) => more,
+                Err(e) => {
+                    debug!("QUIC socket failed, err: {}", e);
+                    state.terminate(ConnectionError::Reset);
+                    return Poll::Ready(());
+                }
+            };
+            this.send(cx, state);
+            state.forward_events();
+            // Last, sends and events set timers too
+            let fired = this.timers(cx, state);
+            if state.conn.is_drained() {
+                state.terminate(ConnectionError::LocallyClosed);
+                return Poll::Ready(());
+            }
+            if !more && !fired {
+                break;
+            }
+        }
+        state.driver = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// The shared state, closing the connection once the last handle is gone
+struct Handle(Arc<Mutex<State>>);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        Connection::close_state(&mut self.0.lock().unwrap());
+    }
+}
+
+/// An open QUIC connection, cloned handles share it
+#[derive(Clone)]
+pub struct Connection(Arc<Handle>);
+
+impl Connection {
+    /// Connect to `remote` from a socket bound for it, returning before the
+    /// handshake completes when `zero_rtt` is set and the TLS session cache
+    /// has a ticket for `server_name`
+    pub async fn connect(
+        config: ClientConfig,
+        remote: SocketAddr,
+        server_name: &str,
+        zero_rtt: bool,
+    ) -> io::Result<Connection> {
+        let local = match remote {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        let mut endpoint = Endpoint::new(Arc::new(EndpointConfig::default()), None)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let (handle, conn) = endpoint
+            .connect(config, remote, server_name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let early = zero_rtt && conn.has_0rtt();
+        let connection = Connection::spawn(socket, endpoint, handle, conn);
+        if !early {
+            connection.connected().await?;
+        }
+        Ok(connection)
+    }
+
+    fn spawn(
+        socket: UdpSocket,
+        endpoint: Endpoint,
+        handle: ConnectionHandle,
+        conn: quinn_proto::Connection,
+    ) -> Connection {
+        let state = Arc::new(Mutex::new(State {
+            endpoint,
+            handle,
+            conn,
+            timers: TimerTable::default(),
+            connected: false,
+            error: None,
+            driver: None,
+            on_connected: None,
+            blocked_readers: HashMap::new(),
+            blocked_writers: HashMap::new(),
+            opening: Vec::new(),
+            accepting: Vec::new(),
+            datagram_reader: None,
+        }));
+        rt::spawn(Driver {
+            state: state.clone(),
+            socket,
+            buf: vec![0; MAX_DATAGRAM],
+            outgoing: VecDeque::new(),
+            timer: None,
+        });
+        Connection(Arc::new(Handle(state)))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        (self.0).0.lock().unwrap()
+    }
+
+    /// Wait for the handshake to complete
+    async fn connected(&self) -> io::Result<()> {
+        poll_fn(|cx| {
+            let mut state = self.state();
+            if let Some(ref e) = state.error {
+                return Poll::Ready(Err(lost(e)));
+            }
+            if state.connected {
+                return Poll::Ready(Ok(()));
+            }
+            state.on_connected = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Whether the connection is gone, new streams fail on it
+    pub fn is_closed(&self) -> bool {
+        self.state().error.is_some()
+    }
+
+    /// Close the connection, failing its streams
+    pub fn close(&self) {
+        Connection::close_state(&mut self.state());
+    }
+
+    fn close_state(state: &mut State) {
+        if state.error.is_none() {
+            state.close();
+        }
+    }
+
+    /// Whether the handshake resumed a session and sent 0-RTT data, which
+    /// the server may still refuse
+    pub fn is_0rtt(&self) -> bool {
+        let state = self.state();
+        state.conn.has_0rtt() && (state.conn.is_handshaking() || state.conn.accepted_0rtt())
+    }
+
+    async fn open(&self, dir: Dir) -> io::Result<StreamId> {
+        poll_fn(|cx| {
+            let mut state = self.state();
+            if let Some(ref e) = state.error {
+                return Poll::Ready(Err(lost(e)));
+            }
+            match state.conn.open(dir) {
+                Some(id) => Poll::Ready(Ok(id)),
+                None => {
+                    state.opening.push(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn open_uni(&self) -> io::Result<SendStream> {
+        let id = self.open(Dir::Uni).await?;
+        Ok(SendStream::new(self.clone(), id))
+    }
+
+    pub async fn open_bi(&self) -> io::Result<(SendStream, RecvStream)> {
+        let id = self.open(Dir::Bi).await?;
+        Ok((
+            SendStream::new(self.clone(), id),
+            RecvStream::new(self.clone(), id),
+        ))
+    }
+
+    /// Queue an unreliable datagram, an error when it is too large or the
+    /// send buffer is full
+    pub fn send_datagram(&self, data: Bytes) -> io::Result<()> {
+        let mut state = self.state();
+        if let Some(ref e) = state.error {
+            return Err(lost(e));
+        }
+        state
+            .conn
+            .send_datagram()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+            .send(data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        state.wake_driver();
+        Ok(())
+    }
+
+    /// Next datagram from the peer, `None` once the connection is gone
+    pub async fn recv_datagram(&self) -> Option<Bytes> {
+        poll_fn(|cx| {
+            let mut state = self.state();
+            if let Some(datagram) = state.conn.recv_datagram() {
+                return Poll::Ready(Some(datagram));
+            }
+            if state.error.is_some() {
+                return Poll::Ready(None);
+            }
+            state.datagram_reader = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// Sending side of a stream, finished on shutdown or drop
+pub struct SendStream {
+    conn: Connection,
+    id: StreamId,
+    finished: bool,
+}
+
+impl SendStream {
+    fn new(conn: Connection, id: StreamId) -> SendStream {
+        SendStream {
+            conn,
+            id,
+            finished: false,
+        }
+    }
+
+    fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            let mut state = self.conn.state();
+            // Gone along with the connection otherwise
+            let _ = state.conn.finish(self.id);
+            state.wake_driver();
+        }
+    }
+}
+
+impl AsyncWrite for SendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut state = this.conn.state();
+        if let Some(ref e) = state.error {
+            return Poll::Ready(Err(lost(e)));
+        }
+        match state.conn.write(this.id, buf) {
+            Ok(n) => {
+                state.wake_driver();
+                Poll::Ready(Ok(n))
+            }
+            Err(WriteError::Blocked) => {
+                state.blocked_writers.insert(this.id, cx.waker().clone());
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                e.to_string(),
+            ))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().finish();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SendStream {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Receiving side of a stream, stopped when dropped before its end
+pub struct RecvStream {
+    conn: Connection,
+    id: StreamId,
+    ended: bool,
+}
+
+impl RecvStream {
+    fn new(conn: Connection, id: StreamId) -> RecvStream {
+        RecvStream {
+            conn,
+            id,
+            ended: false,
+        }
+    }
+}
+
+impl AsyncRead for RecvStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ended {
+            return Poll::Ready(Ok(0));
+        }
+        let mut state = this.conn.state();
+        let read = match state.conn.read(this.id, buf) {
+            Ok(Some(n)) => {
+                // Reading hands the peer more credit
+                state.wake_driver();
+                Ok(n)
+            }
+            Ok(None) | Err(ReadError::UnknownStream) => {
+                this.ended = true;
+                Ok(0)
+            }
+            Err(ReadError::Blocked) => match state.error {
+                Some(ref e) => Err(lost(e)),
+                None => {
+                    state.blocked_readers.insert(this.id, cx.waker().clone());
+                    return Poll::Pending;
+                }
+            },
+            Err(e @ ReadError::Reset { .. }) => {
+                this.ended = true;
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    e.to_string(),
+                ))
+            }
+        };
+        Poll::Ready(read)
+    }
+}
+
+impl Drop for RecvStream {
+    fn drop(&mut self) {
+        if !self.ended {
+            let mut state = self.conn.state();
+            let _ = state.conn.stop_sending(self.id, 0u32.into());
+            state.wake_driver();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    //! The server side, for tests of the protocols over QUIC
+
+    use quinn_proto::ServerConfig;
+
+    use super::*;
+
+    impl Connection {
+        /// Accept the first connection arriving at `socket`
+        pub async fn accept(mut socket: UdpSocket, config: ServerConfig) -> io::Result<Connection> {
+            let mut endpoint =
+                Endpoint::new(Arc::new(EndpointConfig::default()), Some(Arc::new(config)))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            let mut buf = vec![0; MAX_DATAGRAM];
+            loop {
+                let (n, remote) = socket.recv_from(&mut buf).await?;
+                let data = buf[..n].into();
+                if let Some((handle, DatagramEvent::NewConnection(conn))) =
+                    endpoint.handle(Instant::now(), remote, None, data)
+                {
+                    let connection = Connection::spawn(socket, endpoint, handle, conn);
+                    connection.connected().await?;
+                    return Ok(connection);
+                }
+            }
+        }
+
+        async fn accept_stream(&self, dir: Dir) -> io::Result<StreamId> {
+            poll_fn(|cx| {
+                let mut state = self.state();
+                if let Some(id) = state.conn.accept(dir) {
+                    state.wake_driver();
+                    return Poll::Ready(Ok(id));
+                }
+                if let Some(ref e) = state.error {
+                    return Poll::Ready(Err(lost(e)));
+                }
+                state.accepting.push(cx.waker().clone());
+                Poll::Pending
+            })
+            .await
+        }
+
+        pub async fn accept_uni(&self) -> io::Result<RecvStream> {
+            let id = self.accept_stream(Dir::Uni).await?;
+            Ok(RecvStream::new(self.clone(), id))
+        }
+
+        pub async fn accept_bi(&self) -> io::Result<(SendStream, RecvStream)> {
+            let id = self.accept_stream(Dir::Bi).await?;
+            Ok((
+                SendStream::new(self.clone(), id),
+                RecvStream::new(self.clone(), id),
+            ))
+        }
+    }
+}
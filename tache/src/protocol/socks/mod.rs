@@ -1 +1,5 @@
 mod v5;
+
+pub use self::v5::{
+    accept, read_addr, reply, reply_code, REPLY_GENERAL_FAILURE, REPLY_NOT_ALLOWED, REPLY_SUCCEEDED,
+};
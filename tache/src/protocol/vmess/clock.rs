@@ -0,0 +1,136 @@
+//! Clock offset for VMess request timestamps
+//!
+//! VMess servers drop requests whose timestamp is more than two minutes
+//! off their own clock, and they fail like a wrong UUID would. Without NTP
+//! the offset is estimated from the `Date` header of a direct HTTP request,
+//! taken at the midpoint of the round trip, and added to every timestamp.
+
+use std::{
+    io,
+    sync::atomic::{AtomicI64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+
+use crate::{config::ClockCheckConfig, http_client, outbound::probe, rt::delay_for};
+
+/// Largest skew VMess servers accept, in seconds
+pub const TOLERANCE: i64 = 120;
+/// Seconds between estimates
+pub const DEFAULT_INTERVAL: u64 = 3600;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Seconds to add to the local clock, 0 until estimated
+static OFFSET: AtomicI64 = AtomicI64::new(0);
+
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+pub fn offset() -> i64 {
+    OFFSET.load(Ordering::Relaxed)
+}
+
+/// Unix seconds to put in VMess requests, corrected by the last estimate
+pub fn timestamp() -> u64 {
+    (unix_millis() / 1000 + offset()).max(0) as u64
+}
+
+/// Offset of a server answering with `date` between `sent` and `received`,
+/// in local Unix milliseconds
+pub fn offset_from(date: &str, sent: i64, received: i64) -> Option<i64> {
+    let server = time::strptime(date.trim(), "%a, %d %b %Y %H:%M:%S GMT")
+        .ok()?
+        .to_timespec()
+        .sec;
+    // Dates are truncated to the second, take the middle of it
+    let server = server * 1000 + 500;
+    let local = sent + (received - sent) / 2;
+    Some((server - local) / 1000)
+}
+
+/// Estimate the offset against `url`, fetched directly
+pub async fn estimate(url: &str) -> io::Result<i64> {
+    let sent = unix_millis();
+    let resp = http_client::get(url, CHECK_TIMEOUT).await?;
+    let received = unix_millis();
+    resp.header("date")
+        .and_then(|date| offset_from(date, sent, received))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no valid Date header"))
+}
+
+/// Estimate now and every `interval`, keeping the last offset on failures
+pub async fn run(config: ClockCheckConfig) {
+    let url = config
+        .url
+        .unwrap_or_else(|| probe::DEFAULT_TEST_URL.to_owned());
+    let interval = Duration::from_secs(config.interval.unwrap_or(DEFAULT_INTERVAL));
+    loop {
+        match estimate(&url).await {
+            Ok(skew) => {
+                if skew.abs() > TOLERANCE {
+                    warn!(
+                        "Local clock is {}s off {}, VMess servers reject more than {}s, \
+                         correcting request timestamps",
+                        skew, url, TOLERANCE
+                    );
+                } else {
+                    debug!("Local clock is {}s off {}", skew, url);
+                }
+                OFFSET.store(skew, Ordering::Relaxed);
+            }
+            Err(e) => debug!("Clock check against {} failed, err: {}", url, e),
+        }
+        delay_for(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::rt::{self, Runtime, TcpListener};
+
+    #[test]
+    fn estimates_offset_from_date() {
+        // 1994-11-06 08:49:37 UTC
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let at = 784_111_777_000;
+        assert_eq!(offset_from(date, at, at + 400), Some(0));
+        assert_eq!(offset_from(date, at - 300_000, at - 299_000), Some(300));
+        assert_eq!(offset_from(date, at + 200_000, at + 200_200), Some(-199));
+        assert_eq!(offset_from("yesterday", at, at), None);
+    }
+
+    #[test]
+    fn estimates_against_a_server() {
+        Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/generate_204", listener.local_addr().unwrap());
+            rt::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                // Five minutes ahead
+                let ahead = time::Timespec::new(unix_millis() / 1000 + 300, 0);
+                let date = time::at_utc(ahead)
+                    .strftime("%a, %d %b %Y %H:%M:%S GMT")
+                    .unwrap()
+                    .to_string();
+                let resp = format!(
+                    "HTTP/1.1 204 No Content\r\nDate: {}\r\nContent-Length: 0\r\n\r\n",
+                    date
+                );
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            });
+            let skew = estimate(&url).await.unwrap();
+            assert!((299..=300).contains(&skew), "skew {}", skew);
+            assert!(skew > TOLERANCE);
+        });
+    }
+}
@@ -0,0 +1,299 @@
+//! Sorted string lists in one buffer, for rule sets of many thousand entries
+//!
+//! Entries are stored reversed so domains next to each other share their
+//! suffix, and every entry keeps only what differs from the one before it.
+//! Each 16th entry is stored whole, lookups binary search those and decode
+//! at most a run of 16.
+//!
+//! Lists are saved as is by `tachelocal compile-ruleset`, loading one only
+//! checks its bounds instead of sorting every entry again.
+
+use std::{cmp::Ordering, io};
+
+/// Entries between two stored whole
+const RESTART_INTERVAL: usize = 16;
+
+/// Start of a saved list, followed by the entry and restart counts as
+/// varints, the restarts as u32 LE and the data
+const MAGIC: &[u8] = b"\x7fTRS1";
+
+pub struct CompactList {
+    data: Vec<u8>,
+    /// Offsets of the entries stored whole
+    restarts: Vec<u32>,
+    len: usize,
+}
+
+impl CompactList {
+    pub fn new(entries: Vec<String>) -> CompactList {
+        let mut keys: Vec<Vec<u8>> = entries
+            .into_iter()
+            .map(|entry| entry.into_bytes().into_iter().rev().collect())
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut data = Vec::new();
+        let mut restarts = Vec::with_capacity(keys.len() / RESTART_INTERVAL + 1);
+        let mut previous: &[u8] = &[];
+        for (i, key) in keys.iter().enumerate() {
+            let shared = if i % RESTART_INTERVAL == 0 {
+                restarts.push(data.len() as u32);
+                0
+            } else {
+                previous
+                    .iter()
+                    .zip(key.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            };
+            put_varint(&mut data, shared);
+            put_varint(&mut data, key.len() - shared);
+            data.extend_from_slice(&key[shared..]);
+            previous = key;
+        }
+        data.shrink_to_fit();
+        CompactList {
+            data,
+            restarts,
+            len: keys.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes held by the entries
+    pub fn size(&self) -> usize {
+        self.data.len() + self.restarts.len() * 4
+    }
+
+    pub fn contains(&self, entry: &str) -> bool {
+        let key: Vec<u8> = entry.bytes().rev().collect();
+        // Last run starting at or before the key
+        let run = match self
+            .restarts
+            .binary_search_by(|&offset| self.whole(offset).cmp(&key[..]))
+        {
+            Ok(_) => return true,
+            Err(0) => return false,
+            Err(i) => i - 1,
+        };
+        let mut entries = Entries {
+            list: self,
+            pos: self.restarts[run] as usize,
+            key: Vec::new(),
+        };
+        for _ in 0..RESTART_INTERVAL {
+            match entries.next_key() {
+                Some(found) => match found.cmp(&key[..]) {
+                    Ordering::Less => continue,
+                    Ordering::Equal => return true,
+                    Ordering::Greater => return false,
+                },
+                None => return false,
+            }
+        }
+        false
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = String> + '_ {
+        let mut entries = Entries {
+            list: self,
+            pos: 0,
+            key: Vec::new(),
+        };
+        std::iter::from_fn(move || {
+            let key = entries.next_key()?;
+            let bytes: Vec<u8> = key.iter().rev().cloned().collect();
+            Some(String::from_utf8_lossy(&bytes).into_owned())
+        })
+    }
+
+    /// Whether `bytes` is a saved list
+    pub fn is_saved(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 10 + self.size());
+        bytes.extend_from_slice(MAGIC);
+        put_varint(&mut bytes, self.len);
+        put_varint(&mut bytes, self.restarts.len());
+        for offset in &self.restarts {
+            bytes.extend_from_slice(&offset.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Load a saved list, walking its entries once so lookups never read
+    /// out of bounds
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<CompactList> {
+        let malformed =
+            || io::Error::new(io::ErrorKind::InvalidData, "malformed compiled rule set");
+        if !CompactList::is_saved(bytes) {
+            return Err(malformed());
+        }
+        let mut pos = MAGIC.len();
+        let len = read_varint(bytes, &mut pos).ok_or_else(malformed)?;
+        let count = read_varint(bytes, &mut pos).ok_or_else(malformed)?;
+        let mut restarts = Vec::with_capacity(count.min(bytes.len() / 4));
+        for _ in 0..count {
+            let offset = bytes.get(pos..pos + 4).ok_or_else(malformed)?;
+            restarts.push(u32::from_le_bytes([
+                offset[0], offset[1], offset[2], offset[3],
+            ]));
+            pos += 4;
+        }
+        let data = bytes[pos..].to_vec();
+
+        let (mut pos, mut entries, mut previous) = (0, 0, 0);
+        while pos < data.len() {
+            let whole = entries % RESTART_INTERVAL == 0;
+            if whole && restarts.get(entries / RESTART_INTERVAL) != Some(&(pos as u32)) {
+                return Err(malformed());
+            }
+            let shared = read_varint(&data, &mut pos).ok_or_else(malformed)?;
+            let rest = read_varint(&data, &mut pos).ok_or_else(malformed)?;
+            if (whole && shared != 0) || shared > previous || rest > data.len() - pos {
+                return Err(malformed());
+            }
+            pos += rest;
+            previous = shared + rest;
+            entries += 1;
+        }
+        if entries != len || len.div_ceil(RESTART_INTERVAL) != restarts.len() {
+            return Err(malformed());
+        }
+        Ok(CompactList {
+            data,
+            restarts,
+            len,
+        })
+    }
+
+    /// Key of the entry stored whole at `offset`
+    fn whole(&self, offset: u32) -> &[u8] {
+        let mut pos = offset as usize;
+        let _shared = get_varint(&self.data, &mut pos);
+        let len = get_varint(&self.data, &mut pos);
+        &self.data[pos..pos + len]
+    }
+}
+
+struct Entries<'a> {
+    list: &'a CompactList,
+    pos: usize,
+    key: Vec<u8>,
+}
+
+impl<'a> Entries<'a> {
+    fn next_key(&mut self) -> Option<&[u8]> {
+        let data = &self.list.data;
+        if self.pos >= data.len() {
+            return None;
+        }
+        let shared = get_varint(data, &mut self.pos);
+        let len = get_varint(data, &mut self.pos);
+        self.key.truncate(shared);
+        self.key.extend_from_slice(&data[self.pos..self.pos + len]);
+        self.pos += len;
+        Some(&self.key)
+    }
+}
+
+fn put_varint(data: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+/// `get_varint` of untrusted data, `None` past its end or on overflow
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        if shift >= 64 {
+            return None;
+        }
+        value |= usize::from(byte & 0x7f).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+fn get_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_entries_in_less_space() {
+        let mut entries: Vec<String> = (0..1000)
+            .map(|i| format!("+.host{}.example.com", i))
+            .collect();
+        entries.push("+.host7.example.com".to_owned());
+        entries.push("IP-CIDR,10.0.0.0/8".to_owned());
+        let total: usize = entries.iter().map(String::len).sum();
+
+        let list = CompactList::new(entries);
+        assert_eq!(list.len(), 1001);
+        assert!(list.size() < total / 2);
+        for entry in &[
+            "+.host0.example.com",
+            "+.host999.example.com",
+            "IP-CIDR,10.0.0.0/8",
+        ] {
+            assert!(list.contains(entry), "{}", entry);
+        }
+        for entry in &["", "+.host1000.example.com", "example.com", "zzz"] {
+            assert!(!list.contains(entry), "{}", entry);
+        }
+        let all: Vec<String> = list.iter().collect();
+        assert_eq!(all.len(), 1001);
+        assert!(all.iter().all(|entry| list.contains(entry)));
+    }
+
+    #[test]
+    fn loads_saved_lists() {
+        let entries: Vec<String> = (0..100)
+            .map(|i| format!("+.host{}.example.com", i))
+            .collect();
+        let bytes = CompactList::new(entries).to_bytes();
+        assert!(CompactList::is_saved(&bytes));
+
+        let list = CompactList::from_bytes(&bytes).unwrap();
+        assert_eq!(list.len(), 100);
+        assert!(list.contains("+.host42.example.com"));
+        assert!(!list.contains("+.host100.example.com"));
+
+        for end in MAGIC.len()..bytes.len() - 1 {
+            assert!(CompactList::from_bytes(&bytes[..end]).is_err(), "{}", end);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Proxies and rules maintained outside of the main config
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::StreamExt;
+use log::{error, info};
+
+use crate::{
+    config::ProviderConfig,
+    http_client,
+    rt::interval,
+    state::{self, Dirs},
+};
+
+mod compact;
+mod proxy;
+mod rule;
+
+pub use self::{
+    compact::CompactList,
+    proxy::ProxyProvider,
+    rule::{compile, RuleProvider},
+};
+
+/// Where provider content comes from
+pub enum Vehicle {
+    HTTP { url: String, path: PathBuf },
+    File { path: PathBuf },
+}
+
+impl Vehicle {
+    /// Downloads without a path are kept under `cache` by provider name
+    fn new(config: &ProviderConfig, cache: &Path) -> Vehicle {
+        match *config {
+            ProviderConfig::HTTP {
+                ref name,
+                ref url,
+                ref path,
+                ..
+            } => Vehicle::HTTP {
+                url: url.clone(),
+                path: path
+                    .as_ref()
+                    .map_or_else(|| cache.join(name), PathBuf::from),
+            },
+            ProviderConfig::File { ref path, .. } => Vehicle::File {
+                path: PathBuf::from(path),
+            },
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Vehicle::HTTP { .. } => "HTTP",
+            Vehicle::File { .. } => "File",
+        }
+    }
+
+    /// Load the cached copy, falling back to a download if there is none
+    async fn initial(&self) -> io::Result<Vec<u8>> {
+        match *self {
+            Vehicle::HTTP { ref path, .. } if path.exists() => fs::read(path),
+            _ => self.fetch().await,
+        }
+    }
+
+    /// Modification time of the copy on disk, `None` without one
+    fn cached(&self) -> Option<u64> {
+        let path = match *self {
+            Vehicle::HTTP { ref path, .. } | Vehicle::File { ref path } => path,
+        };
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+        modified
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs())
+    }
+
+    /// Read the copy on disk
+    fn read_cached(&self) -> io::Result<Vec<u8>> {
+        match *self {
+            Vehicle::HTTP { ref path, .. } | Vehicle::File { ref path } => fs::read(path),
+        }
+    }
+
+    /// Read the latest content, downloads are cached at `path`
+    async fn fetch(&self) -> io::Result<Vec<u8>> {
+        match *self {
+            Vehicle::HTTP { ref url, ref path } => {
+                let resp = http_client::get(url, http_client::DEFAULT_TIMEOUT).await?;
+                if resp.status != 200 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("fetching {} returned status {}", url, resp.status),
+                    ));
+                }
+                state::write_atomic(path, &resp.body)?;
+                Ok(resp.body)
+            }
+            Vehicle::File { ref path } => fs::read(path),
+        }
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// RFC 3339 timestamp as used by the API
+pub(crate) fn format_time(secs: u64) -> String {
+    time::at_utc(time::Timespec::new(secs as i64, 0))
+        .rfc3339()
+        .to_string()
+}
+
+/// All providers declared in the config
+#[derive(Default)]
+pub struct Providers {
+    pub proxies: HashMap<String, Arc<ProxyProvider>>,
+    pub rules: HashMap<String, Arc<RuleProvider>>,
+}
+
+impl Providers {
+    pub fn new(proxies: &[ProviderConfig], rules: &[ProviderConfig], dirs: &Dirs) -> Providers {
+        let proxy_cache = dirs.cache().join("proxies");
+        let rule_cache = dirs.cache().join("rules");
+        Providers {
+            proxies: proxies
+                .iter()
+                .map(|c| {
+                    let provider = ProxyProvider::new(c, &proxy_cache);
+                    (c.name().to_owned(), Arc::new(provider))
+                })
+                .collect(),
+            rules: rules
+                .iter()
+                .map(|c| {
+                    let provider = RuleProvider::new(c, &rule_cache);
+                    (c.name().to_owned(), Arc::new(provider))
+                })
+                .collect(),
+        }
+    }
+
+    /// Load every provider once, failures are logged and leave the provider empty
+    pub async fn initialize(&self) {
+        for provider in self.proxies.values() {
+            if let Err(e) = provider.initialize().await {
+                error!(
+                    "Failed to load proxy provider {}, err: {}",
+                    provider.name(),
+                    e
+                );
+            }
+        }
+        for provider in self.rules.values() {
+            if let Err(e) = provider.initialize().await {
+                error!(
+                    "Failed to load rule provider {}, err: {}",
+                    provider.name(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Refresh HTTP providers whose interval elapsed, checking every minute
+    pub async fn run_updater(providers: Arc<Providers>) {
+        let mut interval = interval(Duration::from_secs(60));
+        while interval.next().await.is_some() {
+            let now = unix_now();
+            for provider in providers.proxies.values() {
+                if provider.due(now) {
+                    match provider.update().await {
+                        Ok(()) => info!("Proxy provider {} updated", provider.name()),
+                        Err(e) => error!(
+                            "Failed to update proxy provider {}, err: {}",
+                            provider.name(),
+                            e
+                        ),
+                    }
+                }
+            }
+            for provider in providers.rules.values() {
+                if provider.due(now) {
+                    match provider.update().await {
+                        Ok(()) => info!("Rule provider {} updated", provider.name()),
+                        Err(e) => error!(
+                            "Failed to update rule provider {}, err: {}",
+                            provider.name(),
+                            e
+                        ),
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use futures::future::join_all;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{format_time, unix_now, Vehicle};
+use crate::{
+    config::{ProviderConfig, ProxyConfig},
+    event::{Event, EventBus},
+    outbound::{history, probe, system},
+};
+
+#[derive(Deserialize)]
+struct ProviderFile {
+    #[serde(deserialize_with = "crate::config::deserialize_proxies")]
+    proxies: Vec<ProxyConfig>,
+}
+
+/// Provider content is either a yaml file with a `proxies` list or a subscription
+fn parse(content: &[u8]) -> io::Result<Vec<ProxyConfig>> {
+    let text = String::from_utf8_lossy(content);
+    let proxies = match serde_yaml::from_str::<ProviderFile>(&text) {
+        Ok(file) => file.proxies,
+        Err(..) => ProxyConfig::from_subscription(&text),
+    };
+    if proxies.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "provider contains no proxy",
+        ));
+    }
+    Ok(proxies)
+}
+
+pub struct ProxyProvider {
+    name: String,
+    vehicle: Vehicle,
+    interval: Option<u64>,
+    updated_at: AtomicU64,
+    proxies: RwLock<Vec<ProxyConfig>>,
+    /// Last measured delay in milliseconds, `None` when unreachable
+    health: RwLock<HashMap<String, Option<u64>>>,
+}
+
+impl ProxyProvider {
+    /// Downloads are kept in `cache` unless the config has a path
+    pub fn new(config: &ProviderConfig, cache: &Path) -> ProxyProvider {
+        let interval = match *config {
+            ProviderConfig::HTTP { interval, .. } => interval,
+            ProviderConfig::File { .. } => None,
+        };
+        ProxyProvider {
+            name: config.name().to_owned(),
+            vehicle: Vehicle::new(config, cache),
+            interval,
+            updated_at: AtomicU64::new(0),
+            proxies: RwLock::new(Vec::new()),
+            health: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn proxies(&self) -> Vec<ProxyConfig> {
+        self.proxies.read().unwrap().clone()
+    }
+
+    pub(super) fn due(&self, now: u64) -> bool {
+        match self.interval {
+            Some(interval) => now >= self.updated_at.load(Ordering::Relaxed) + interval,
+            None => false,
+        }
+    }
+
+    fn replace(&self, proxies: Vec<ProxyConfig>) {
+        *self.proxies.write().unwrap() = proxies;
+        self.updated_at.store(unix_now(), Ordering::Relaxed);
+    }
+
+    pub async fn initialize(&self) -> io::Result<()> {
+        let content = self.vehicle.initial().await?;
+        self.replace(parse(&content)?);
+        Ok(())
+    }
+
+    /// Force a re-download (or re-read for file providers)
+    pub async fn update(&self) -> io::Result<()> {
+        let content = self.vehicle.fetch().await?;
+        self.replace(parse(&content)?);
+        Ok(())
+    }
+
+    /// Measure the connect time to every proxy server
+    ///
+    /// Unreachable proxies are published as `ProxyUnhealthy`, `system` ones
+    /// are probed at the upstream of the environment.
+    pub async fn health_check(&self, events: &EventBus) {
+        let proxies = self.proxies();
+        let results = join_all(proxies.iter().map(|proxy| async move {
+            let address = match proxy.address() {
+                Some(address) => address.clone(),
+                None => system::upstream().ok()??.address,
+            };
+            probe::connect_delay(&address).await
+        }))
+        .await;
+        let mut health = self.health.write().unwrap();
+        health.clear();
+        for (proxy, delay) in proxies.iter().zip(results) {
+            if delay.is_none() {
+                events.publish(Event::ProxyUnhealthy {
+                    provider: self.name.clone(),
+                    proxy: proxy.name().to_owned(),
+                });
+            }
+            history::record(proxy.name(), delay);
+            health.insert(proxy.name().to_owned(), delay);
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let health = self.health.read().unwrap();
+        let proxies: Vec<Value> = self
+            .proxies
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|proxy| !proxy.hidden())
+            .map(|proxy| {
+                let delay = health.get(proxy.name()).cloned();
+                json!({
+                    "name": proxy.name(),
+                    "type": proxy.kind(),
+                    "alive": delay.map(|d| d.is_some()),
+                    "delay": delay.and_then(|d| d),
+                    "history": history::stats(proxy.name()),
+                })
+            })
+            .collect();
+        json!({
+            "name": self.name,
+            "type": "Proxy",
+            "vehicleType": self.vehicle.kind(),
+            "updatedAt": format_time(self.updated_at.load(Ordering::Relaxed)),
+            "proxies": proxies,
+        })
+    }
+}
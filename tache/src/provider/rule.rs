@@ -0,0 +1,162 @@
+use std::{
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{format_time, unix_now, CompactList, Vehicle};
+use crate::config::{ProviderConfig, RuleSetFormat};
+
+#[derive(Deserialize)]
+struct RuleSetFile {
+    payload: Vec<String>,
+}
+
+fn parse_text(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Read a rule set in `format`. Without one compiled sets are told apart by
+/// their header, and yaml without a `payload` list is read as text.
+fn parse(content: &[u8], format: Option<RuleSetFormat>) -> io::Result<CompactList> {
+    let format = match format {
+        Some(format) => format,
+        None if CompactList::is_saved(content) => RuleSetFormat::Binary,
+        None => {
+            let text = String::from_utf8_lossy(content);
+            let entries = match serde_yaml::from_str::<RuleSetFile>(&text) {
+                Ok(file) => file.payload,
+                Err(..) => parse_text(&text),
+            };
+            return Ok(CompactList::new(entries));
+        }
+    };
+    match format {
+        RuleSetFormat::Yaml => {
+            let file = serde_yaml::from_slice::<RuleSetFile>(content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(CompactList::new(file.payload))
+        }
+        RuleSetFormat::Text => Ok(CompactList::new(parse_text(&String::from_utf8_lossy(
+            content,
+        )))),
+        RuleSetFormat::Binary => CompactList::from_bytes(content),
+    }
+}
+
+/// A yaml or text rule set compiled to the binary format
+pub fn compile(content: &[u8], format: Option<RuleSetFormat>) -> io::Result<Vec<u8>> {
+    Ok(parse(content, format)?.to_bytes())
+}
+
+pub struct RuleProvider {
+    name: String,
+    vehicle: Vehicle,
+    interval: Option<u64>,
+    format: Option<RuleSetFormat>,
+    updated_at: AtomicU64,
+    /// Parsed on first use when there is a copy on disk
+    rules: RwLock<Option<Arc<CompactList>>>,
+}
+
+impl RuleProvider {
+    /// Downloads are kept in `cache` unless the config has a path
+    pub fn new(config: &ProviderConfig, cache: &Path) -> RuleProvider {
+        let interval = match *config {
+            ProviderConfig::HTTP { interval, .. } => interval,
+            ProviderConfig::File { .. } => None,
+        };
+        RuleProvider {
+            name: config.name().to_owned(),
+            vehicle: Vehicle::new(config, cache),
+            interval,
+            format: config.format(),
+            updated_at: AtomicU64::new(0),
+            rules: RwLock::new(None),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Entries of the set, read from the copy on disk the first time
+    pub fn rules(&self) -> io::Result<Arc<CompactList>> {
+        if let Some(ref rules) = *self.rules.read().unwrap() {
+            return Ok(rules.clone());
+        }
+        let rules = Arc::new(parse(&self.vehicle.read_cached()?, self.format)?);
+        *self.rules.write().unwrap() = Some(rules.clone());
+        Ok(rules)
+    }
+
+    pub(super) fn due(&self, now: u64) -> bool {
+        match self.interval {
+            Some(interval) => now >= self.updated_at.load(Ordering::Relaxed) + interval,
+            None => false,
+        }
+    }
+
+    fn replace(&self, rules: CompactList) {
+        *self.rules.write().unwrap() = Some(Arc::new(rules));
+        self.updated_at.store(unix_now(), Ordering::Relaxed);
+    }
+
+    pub async fn initialize(&self) -> io::Result<()> {
+        // Large sets stay on disk until a rule needs them
+        if let Some(modified) = self.vehicle.cached() {
+            self.updated_at.store(modified, Ordering::Relaxed);
+            return Ok(());
+        }
+        let content = self.vehicle.initial().await?;
+        self.replace(parse(&content, self.format)?);
+        Ok(())
+    }
+
+    /// Force a re-download (or re-read for file providers)
+    pub async fn update(&self) -> io::Result<()> {
+        let content = self.vehicle.fetch().await?;
+        self.replace(parse(&content, self.format)?);
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "type": "Rule",
+            "vehicleType": self.vehicle.kind(),
+            // Unknown until loaded
+            "ruleCount": self.rules.read().unwrap().as_ref().map(|r| r.len()),
+            "updatedAt": format_time(self.updated_at.load(Ordering::Relaxed)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detects_formats() {
+        let yaml = b"payload:\n  - '+.example.com'\n  - 'IP-CIDR,10.0.0.0/8'\n";
+        let text = b"# blocked\n+.example.com\nIP-CIDR,10.0.0.0/8\n";
+        let binary = compile(text, Some(RuleSetFormat::Text)).unwrap();
+        for content in &[&yaml[..], &text[..], &binary[..]] {
+            let rules = parse(content, None).unwrap();
+            assert_eq!(rules.len(), 2);
+            assert!(rules.contains("+.example.com"));
+        }
+        assert!(parse(text, Some(RuleSetFormat::Yaml)).is_err());
+        assert!(parse(text, Some(RuleSetFormat::Binary)).is_err());
+    }
+}
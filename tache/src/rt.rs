@@ -0,0 +1,184 @@
+//! The one async runtime tache runs on
+//!
+//! Everything spawns, sleeps and opens sockets through here, so the library,
+//! the binary and the C API share one executor and switching runtimes stays
+//! a change to this file.
+
+use std::{
+    future::Future,
+    io,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{
+    future::{select, Either},
+    pin_mut,
+};
+use log::{info, warn};
+
+use crate::config::RuntimeConfig;
+
+#[cfg(unix)]
+pub use tokio::net::{UnixListener, UnixStream};
+pub use tokio::{
+    net::{lookup_host, TcpListener, TcpStream, UdpSocket},
+    runtime::Runtime,
+    time::{delay_for, interval, Interval},
+};
+
+/// Time `shutdown` waits for blocking calls
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Tasks spawned through `spawn` that haven't finished
+static TASKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Unix time serving first began at, 0 before
+static STARTED: AtomicU64 = AtomicU64::new(0);
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Record that serving began, later calls keep the first time
+pub fn mark_started() {
+    let _ = STARTED.compare_exchange(0, unix_now(), Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Time since serving first began, reloads and profile switches included
+pub fn uptime() -> Duration {
+    match STARTED.load(Ordering::Relaxed) {
+        0 => Duration::from_secs(0),
+        started => Duration::from_secs(unix_now().saturating_sub(started)),
+    }
+}
+
+/// Number of tasks spawned through `spawn` still running
+pub fn tasks() -> usize {
+    TASKS.load(Ordering::Relaxed)
+}
+
+/// Counts a spawned task until dropped with it, however it ends
+struct Running;
+
+impl Running {
+    fn new() -> Running {
+        TASKS.fetch_add(1, Ordering::Relaxed);
+        Running
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        TASKS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Build the multi-threaded runtime used by the engine and the binary
+///
+/// The open file limit is raised first, every connection holds one or two.
+pub fn runtime(config: Option<&RuntimeConfig>) -> io::Result<Runtime> {
+    raise_open_files_limit(config.and_then(|c| c.max_open_files));
+
+    let workers = config
+        .and_then(|c| c.worker_threads)
+        .map_or_else(num_cpus::get, |n| n.max(1));
+    let mut builder = tokio::runtime::Builder::new();
+    builder
+        .threaded_scheduler()
+        .enable_all()
+        .thread_name("tache-worker")
+        .core_threads(workers);
+    // The limit counts the workers as well
+    if let Some(n) = config.and_then(|c| c.blocking_threads) {
+        builder.max_threads(workers + n.max(1));
+    }
+    builder.build()
+}
+
+/// Tear down `runtime`, dropping the tasks still running on it
+///
+/// Blocking calls in flight are waited for only briefly.
+pub fn shutdown(runtime: Runtime) {
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+}
+
+/// Raise the soft RLIMIT_NOFILE to `wanted`, or to the hard limit
+#[cfg(unix)]
+fn raise_open_files_limit(wanted: Option<u64>) {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        warn!(
+            "Failed to read the open file limit, err: {}",
+            io::Error::last_os_error()
+        );
+        return;
+    }
+    let max = limit.rlim_max;
+    let target = match wanted {
+        Some(wanted) if wanted > max => {
+            warn!(
+                "Open file limit {} is above the hard limit, using {}",
+                wanted, max
+            );
+            max
+        }
+        Some(wanted) => wanted,
+        None => max,
+    };
+    // macOS refuses soft limits above OPEN_MAX even when the hard limit is unlimited
+    #[cfg(target_os = "macos")]
+    let target = target.min(libc::OPEN_MAX as u64);
+    if target <= limit.rlim_cur {
+        return;
+    }
+
+    let raised = libc::rlimit {
+        rlim_cur: target as libc::rlim_t,
+        rlim_max: limit.rlim_max,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+        info!(
+            "Raised open file limit from {} to {}",
+            limit.rlim_cur, target
+        );
+    } else {
+        warn!(
+            "Failed to raise the open file limit to {}, err: {}",
+            target,
+            io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn raise_open_files_limit(_wanted: Option<u64>) {}
+
+/// Run `fut` in the background on the current runtime
+pub fn spawn<F>(fut: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let running = Running::new();
+    tokio::spawn(async move {
+        let _running = running;
+        fut.await
+    });
+}
+
+/// Run `fut` unless `duration` passes first, which fails with `TimedOut`
+pub async fn timeout<F>(duration: Duration, fut: F) -> io::Result<F::Output>
+where
+    F: Future,
+{
+    pin_mut!(fut);
+    match select(fut, delay_for(duration)).await {
+        Either::Left((v, _)) => Ok(v),
+        Either::Right(..) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+    }
+}
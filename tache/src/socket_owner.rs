@@ -0,0 +1,134 @@
+//! User owning the client end of a local connection
+//!
+//! Linux only: TCP sockets are looked up in `/proc/net/tcp` and `tcp6` by
+//! the client address, which for redirected connections is still the
+//! address of the local app. Unix socket peers come from `SO_PEERCRED`.
+//! Everywhere else, and for clients on other hosts, there is no owner.
+
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+#[cfg(target_os = "linux")]
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// Owner of the TCP socket bound to `client` on this host
+#[cfg(target_os = "linux")]
+pub fn tcp_uid(client: SocketAddr) -> Option<u32> {
+    ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| File::open(path).ok())
+        .find_map(|file| {
+            BufReader::new(file)
+                .lines()
+                .skip(1)
+                .filter_map(Result::ok)
+                .find_map(|line| parse_entry(&line, client))
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_uid(_client: SocketAddr) -> Option<u32> {
+    None
+}
+
+/// Uid of an entry in `/proc/net/tcp` whose local address is `client`
+///
+/// Columns are `sl local_address rem_address st queues timers retrnsmt uid`.
+#[cfg(target_os = "linux")]
+fn parse_entry(line: &str, client: SocketAddr) -> Option<u32> {
+    let mut columns = line.split_whitespace();
+    let local = parse_address(columns.nth(1)?)?;
+    if !same_address(local, client) {
+        return None;
+    }
+    columns.nth(5)?.parse().ok()
+}
+
+/// `0100007F:1F90`, the address in words of host byte order, the port in hex
+#[cfg(target_os = "linux")]
+fn parse_address(s: &str) -> Option<SocketAddr> {
+    let mut sp = s.splitn(2, ':');
+    let ip = sp.next()?;
+    let port = u16::from_str_radix(sp.next()?, 16).ok()?;
+    let word = |i: usize| {
+        ip.get(i * 8..i * 8 + 8)
+            .and_then(|w| u32::from_str_radix(w, 16).ok())
+            .map(u32::to_ne_bytes)
+    };
+    let ip = match ip.len() {
+        8 => IpAddr::V4(Ipv4Addr::from(word(0)?)),
+        32 => {
+            let mut octets = [0u8; 16];
+            for i in 0..4 {
+                octets[i * 4..i * 4 + 4].copy_from_slice(&word(i)?);
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Equal addresses, an IPv4 client matches its mapped form on a v6 socket
+#[cfg(target_os = "linux")]
+fn same_address(local: SocketAddr, client: SocketAddr) -> bool {
+    let v4 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V6(ip) => ip.to_ipv4().map(IpAddr::V4).unwrap_or(addr.ip()),
+        ip => ip,
+    };
+    local.port() == client.port() && v4(local) == v4(client)
+}
+
+/// Uid of the process on the other end of a Unix socket
+#[cfg(target_os = "linux")]
+pub fn unix_peer_uid(fd: RawFd) -> Option<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == 0 {
+        Some(cred.uid)
+    } else {
+        None
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn unix_peer_uid(_fd: RawFd) -> Option<u32> {
+    None
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn proc_net_tcp_entries() {
+        let client = "127.0.0.1:8080".parse().unwrap();
+        let line = "   0: 0100007F:1F90 0100007F:22B8 01 00000000:00000000 \
+                    00:00000000 00000000  1000        0 12345 1";
+        assert_eq!(parse_entry(line, client), Some(1000));
+        assert_eq!(parse_entry(line, "127.0.0.1:8081".parse().unwrap()), None);
+
+        let mapped = "0000000000000000FFFF00000100007F:1F90";
+        assert_eq!(
+            parse_address(mapped).map(|a| same_address(a, client)),
+            Some(true)
+        );
+    }
+}
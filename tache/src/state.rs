@@ -0,0 +1,150 @@
+//! Directories files are kept in, and writing them crash-safe
+//!
+//! State that should survive, like usage counters, goes to the state
+//! directory, downloads that can be fetched again go to the cache
+//! directory. Both follow the platform: XDG on Linux, `~/Library` on macOS
+//! and `%LOCALAPPDATA%` on Windows, unless `state-dir` puts them in one
+//! place. Files are replaced through a renamed temporary file, so a crash
+//! or power loss leaves either the old or the new content.
+
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Tells apart temporary files of concurrent writes
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone)]
+pub struct Dirs {
+    state: PathBuf,
+    cache: PathBuf,
+}
+
+impl Dirs {
+    /// Platform directories, or both under `dir` when set. The working
+    /// directory is used when the platform has none, like without `HOME`.
+    pub fn new(dir: Option<&str>) -> Dirs {
+        let (state, cache) = match dir {
+            Some(dir) => (PathBuf::from(dir), Path::new(dir).join("cache")),
+            None => platform_dirs().unwrap_or_else(|| (PathBuf::from("."), PathBuf::from("cache"))),
+        };
+        Dirs { state, cache }
+    }
+
+    pub fn state(&self) -> &Path {
+        &self.state
+    }
+
+    pub fn cache(&self) -> &Path {
+        &self.cache
+    }
+}
+
+#[cfg(windows)]
+fn platform_dirs() -> Option<(PathBuf, PathBuf)> {
+    let base = PathBuf::from(env::var_os("LOCALAPPDATA")?).join("tache");
+    let cache = base.join("cache");
+    Some((base, cache))
+}
+
+#[cfg(target_os = "macos")]
+fn platform_dirs() -> Option<(PathBuf, PathBuf)> {
+    let home = PathBuf::from(env::var_os("HOME")?);
+    Some((
+        home.join("Library/Application Support/tache"),
+        home.join("Library/Caches/tache"),
+    ))
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn platform_dirs() -> Option<(PathBuf, PathBuf)> {
+    let xdg = |var: &str, default: &str| -> Option<PathBuf> {
+        let base = match env::var_os(var) {
+            Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(default),
+        };
+        Some(base.join("tache"))
+    };
+    Some((
+        xdg("XDG_STATE_HOME", ".local/state")?,
+        xdg("XDG_CACHE_HOME", ".cache")?,
+    ))
+}
+
+/// Replace `path` with `contents`, creating its directory
+///
+/// The content is synced before it's renamed over `path`, a temporary file
+/// left by a crash is ignored.
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        )
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(
+        ".{}-{}.tmp",
+        process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = dir.join(tmp_name);
+    let written = fs::File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    sync_dir(dir);
+    Ok(())
+}
+
+/// Persist the rename itself, best effort
+#[cfg(unix)]
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replaces_files() {
+        let dir = env::temp_dir().join(format!("tache-state-{}", process::id()));
+        let dirs = Dirs::new(dir.to_str());
+        assert_eq!(dirs.cache(), dir.join("cache").as_path());
+
+        let path = dirs.cache().join("providers").join("hk");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        let entries = fs::read_dir(path.parent().unwrap()).unwrap().count();
+        assert_eq!(entries, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
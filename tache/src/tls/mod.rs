@@ -0,0 +1,26 @@
+//! TLS of listeners, outbounds, DNS upstreams and HTTPS interception
+//!
+//! rustls needs ring, so TLS is only built in with the `ring-crypto`
+//! feature. Builds with just `rust-crypto` or `openssl-crypto` keep ring out
+//! and fail whatever uses TLS with an error saying so.
+
+use serde::Serialize;
+
+#[cfg(feature = "ring-crypto")]
+mod rustls_backend;
+#[cfg(feature = "ring-crypto")]
+pub use self::rustls_backend::*;
+
+#[cfg(not(feature = "ring-crypto"))]
+mod unsupported;
+#[cfg(not(feature = "ring-crypto"))]
+pub use self::unsupported::*;
+
+#[derive(Serialize, Debug, Default)]
+pub struct SessionStats {
+    pub entries: usize,
+    /// Handshakes offering a cached session
+    pub resumed: u64,
+    /// Handshakes without one
+    pub full: u64,
+}
@@ -0,0 +1,373 @@
+//! TLS by rustls, which needs ring
+//!
+//! Client connections share one session cache, so reconnecting to a proxy
+//! or origin host resumes its last session with a ticket or session ID and
+//! skips the full handshake. Sessions are kept by server name. 0-RTT stays
+//! off, early data of proxy handshakes could be replayed by anyone on path.
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use lazy_static::lazy_static;
+use lru_cache::LruCache;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair,
+    RcgenError, SanType, PKCS_ECDSA_P256_SHA256,
+};
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    ClientConfig, NoClientAuth, PrivateKey, ServerConfig, StoresClientSessions,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::webpki::DNSNameRef;
+pub use tokio_rustls::{
+    client::TlsStream as ClientStream, server::TlsStream as ServerStream, TlsAcceptor, TlsConnector,
+};
+
+use super::SessionStats;
+use crate::{
+    config::{ClientFingerprint, TlsServerConfig},
+    outbound::hello,
+};
+
+/// Sessions and key exchange hints kept, two entries for each host
+const SESSION_CACHE_SIZE: usize = 512;
+
+lazy_static! {
+    static ref SESSIONS: Arc<SessionCache> = Arc::new(SessionCache::new(SESSION_CACHE_SIZE));
+}
+
+/// Least recently used client sessions of every host
+struct SessionCache {
+    entries: Mutex<LruCache<Vec<u8>, Vec<u8>>>,
+    resumed: AtomicU64,
+    full: AtomicU64,
+}
+
+impl SessionCache {
+    fn new(size: usize) -> SessionCache {
+        SessionCache {
+            entries: Mutex::new(LruCache::new(size)),
+            resumed: AtomicU64::new(0),
+            full: AtomicU64::new(0),
+        }
+    }
+}
+
+impl StoresClientSessions for SessionCache {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.entries.lock().unwrap().insert(key, value);
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.entries.lock().unwrap().get_mut(key).cloned();
+        // Also asked for key exchange hints, only session lookups count
+        if key.starts_with(b"session") {
+            let counter = if value.is_some() {
+                &self.resumed
+            } else {
+                &self.full
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        value
+    }
+}
+
+pub fn session_stats() -> SessionStats {
+    SessionStats {
+        entries: SESSIONS.entries.lock().unwrap().len(),
+        resumed: SESSIONS.resumed.load(Ordering::Relaxed),
+        full: SESSIONS.full.load(Ordering::Relaxed),
+    }
+}
+
+fn invalid(desc: &str, path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{} {}", desc, path))
+}
+
+/// Acceptor serving the PEM encoded certificate chain and private key of
+/// `config`
+pub fn server_acceptor(config: &TlsServerConfig) -> io::Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(load_server_config(config)?))
+}
+
+/// Build a rustls server config from PEM encoded certificate chain and private key
+fn load_server_config(config: &TlsServerConfig) -> io::Result<Arc<ServerConfig>> {
+    let chain = certs(&mut BufReader::new(File::open(&config.cert)?))
+        .map_err(|_| invalid("invalid certificate in", &config.cert))?;
+    if chain.is_empty() {
+        return Err(invalid("no certificate found in", &config.cert));
+    }
+
+    // Accept both PKCS#8 and traditional RSA keys
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(&config.key)?))
+        .map_err(|_| invalid("invalid private key in", &config.key))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(&config.key)?))
+            .map_err(|_| invalid("invalid private key in", &config.key))?;
+    }
+    let key = keys
+        .pop()
+        .ok_or_else(|| invalid("no private key found in", &config.key))?;
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config
+        .set_single_cert(chain, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    server_config.set_protocols(
+        &config
+            .alpn
+            .iter()
+            .map(|p| p.as_bytes().to_vec())
+            .collect::<Vec<_>>(),
+    );
+    Ok(Arc::new(server_config))
+}
+
+/// Client trusting the web PKI roots, offering `alpn` and resuming cached
+/// sessions
+pub fn client_connector(alpn: &[&str]) -> TlsConnector {
+    TlsConnector::from(Arc::new(client_config(alpn)))
+}
+
+/// Like `client_connector`, also trusting the CA certificates in the PEM
+/// file `path`
+pub fn client_connector_trusting(alpn: &[&str], path: &str) -> io::Result<TlsConnector> {
+    let mut config = client_config(alpn);
+    let (added, _) = config
+        .root_store
+        .add_pem_file(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| invalid("invalid certificate in", path))?;
+    if added == 0 {
+        return Err(invalid("no certificate found in", path));
+    }
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Like `client_connector`, offering cipher suites in the order of the
+/// browser of `fingerprint`
+///
+/// rustls decides the rest of the ClientHello, so this doesn't make it look
+/// like the browser's, only less like a default rustls one.
+pub fn client_connector_like(
+    alpn: &[&str],
+    fingerprint: Option<ClientFingerprint>,
+) -> TlsConnector {
+    let mut config = client_config(alpn);
+    if let Some(fingerprint) = fingerprint {
+        let order = hello::profile(fingerprint).ciphers;
+        config.ciphersuites.sort_by_key(|suite| {
+            let suite = suite.suite.get_u16();
+            order
+                .iter()
+                .position(|&c| c == suite)
+                .unwrap_or(order.len())
+        });
+    }
+    TlsConnector::from(Arc::new(config))
+}
+
+fn client_config(alpn: &[&str]) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config.session_persistence = SESSIONS.clone();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    config.set_protocols(
+        &alpn
+            .iter()
+            .map(|p| p.as_bytes().to_vec())
+            .collect::<Vec<_>>(),
+    );
+    config
+}
+
+/// TLS to `host` over `stream`, its certificate has to be valid for `host`
+pub async fn connect<S>(
+    connector: &TlsConnector,
+    host: &str,
+    stream: S,
+) -> io::Result<ClientStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let name =
+        DNSNameRef::try_from_ascii_str(host).map_err(|_| invalid("invalid tls name", host))?;
+    connector.connect(name, stream).await
+}
+
+/// Finish the server side handshake over `stream`
+pub async fn accept<S>(acceptor: &TlsAcceptor, stream: S) -> io::Result<ServerStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    acceptor.accept(stream).await
+}
+
+/// Connection under the TLS of an inbound
+pub fn inner<S>(stream: &ServerStream<S>) -> &S {
+    stream.get_ref().0
+}
+
+pub fn inner_mut<S>(stream: &mut ServerStream<S>) -> &mut S {
+    stream.get_mut().0
+}
+
+fn rcgen_error(e: RcgenError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// CA signing certificates for hosts on the fly
+pub struct Authority {
+    ca: Certificate,
+    /// As read, leaves chain up to it rather than a re-serialization
+    der: Vec<u8>,
+}
+
+impl Authority {
+    /// PEM encoded certificate and private key of a new CA named `name`
+    pub fn generate(name: &str) -> io::Result<(String, String)> {
+        let mut params = CertificateParams::default();
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let mut dn = DistinguishedName::new();
+        dn.push(DnType::CommonName, name);
+        dn.push(DnType::OrganizationName, "tache");
+        params.distinguished_name = dn;
+        let ca = Certificate::from_params(params).map_err(rcgen_error)?;
+        Ok((
+            ca.serialize_pem().map_err(rcgen_error)?,
+            ca.serialize_private_key_pem(),
+        ))
+    }
+
+    /// CA of a PEM encoded certificate and private key
+    pub fn from_pem(cert: &str, key: &str) -> io::Result<Authority> {
+        let key = KeyPair::from_pem(key).map_err(rcgen_error)?;
+        let params = CertificateParams::from_ca_cert_pem(cert, key).map_err(rcgen_error)?;
+        let ca = Certificate::from_params(params).map_err(rcgen_error)?;
+        let der = certs(&mut cert.as_bytes())
+            .ok()
+            .and_then(|chain| chain.into_iter().next())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no CA certificate found"))?
+            .0;
+        Ok(Authority { ca, der })
+    }
+
+    /// DER encoded certificate of the CA
+    #[cfg(test)]
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// Acceptor presenting a new certificate for `host`, offering `alpn`
+    pub fn acceptor(&self, host: &str, alpn: &[&str]) -> io::Result<TlsAcceptor> {
+        let mut params = CertificateParams::default();
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.serial_number = Some(rand::random());
+        params.subject_alt_names = vec![match host.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(host.to_owned()),
+        }];
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, host);
+        params.distinguished_name = name;
+
+        // Clients reject leaves valid for longer than about a year
+        let now = time::now_utc();
+        let (year, month) = (now.tm_year + 1900, now.tm_mon as u32 + 1);
+        params.not_before = rcgen::date_time_ymd(year, month, 1);
+        params.not_after = rcgen::date_time_ymd(year + 1, month, 1);
+
+        let leaf = Certificate::from_params(params).map_err(rcgen_error)?;
+        let chain = vec![
+            rustls::Certificate(
+                leaf.serialize_der_with_signer(&self.ca)
+                    .map_err(rcgen_error)?,
+            ),
+            rustls::Certificate(self.der.clone()),
+        ];
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(chain, PrivateKey(leaf.serialize_private_key_der()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        config.set_protocols(
+            &alpn
+                .iter()
+                .map(|p| p.as_bytes().to_vec())
+                .collect::<Vec<_>>(),
+        );
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::rt::{Runtime, TcpListener, TcpStream};
+
+    #[test]
+    fn resumes_cached_sessions() {
+        let config = client_config(&[]);
+        let shared = Arc::as_ptr(&config.session_persistence) as *const u8;
+        assert_eq!(shared, Arc::as_ptr(&*SESSIONS) as *const u8);
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let mut server = ServerConfig::new(NoClientAuth::new());
+        server
+            .set_single_cert(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server));
+
+        // A cache of its own, other tests handshake through the shared one
+        let cache = Arc::new(SessionCache::new(SESSION_CACHE_SIZE));
+        let mut client = config;
+        client.session_persistence = cache.clone();
+        client
+            .root_store
+            .add(&rustls::Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let connector = TlsConnector::from(Arc::new(client));
+
+        Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let serve = async {
+                for _ in 0..2 {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let mut stream = accept(&acceptor, stream).await.unwrap();
+                    stream.write_all(b"pong").await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+            };
+            let dial = async {
+                for _ in 0..2 {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    let mut stream = connect(&connector, "localhost", stream).await.unwrap();
+                    // Tickets come after the handshake
+                    let mut pong = [0; 4];
+                    stream.read_exact(&mut pong).await.unwrap();
+                    assert_eq!(&pong, b"pong");
+                }
+            };
+            futures::join!(serve, dial);
+        });
+        assert_eq!(cache.full.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.resumed.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,123 @@
+//! Stand-ins of a build without TLS, connectors fail on use and everything
+//! serving TLS fails to be set up
+
+use std::{
+    convert::Infallible,
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::SessionStats;
+use crate::config::{ClientFingerprint, TlsServerConfig};
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "TLS needs tache built with the ring-crypto feature",
+    )
+}
+
+#[derive(Clone)]
+pub struct TlsConnector(());
+
+/// Never built
+#[derive(Clone)]
+pub struct TlsAcceptor(Infallible);
+
+/// Never built
+pub struct ClientStream<S>(Infallible, PhantomData<S>);
+
+/// Never built
+pub struct ServerStream<S>(Infallible, PhantomData<S>);
+
+pub fn session_stats() -> SessionStats {
+    SessionStats::default()
+}
+
+pub fn server_acceptor(_: &TlsServerConfig) -> io::Result<TlsAcceptor> {
+    Err(unsupported())
+}
+
+pub fn client_connector(_: &[&str]) -> TlsConnector {
+    TlsConnector(())
+}
+
+pub fn client_connector_trusting(_: &[&str], _: &str) -> io::Result<TlsConnector> {
+    Err(unsupported())
+}
+
+pub fn client_connector_like(_: &[&str], _: Option<ClientFingerprint>) -> TlsConnector {
+    TlsConnector(())
+}
+
+pub async fn connect<S>(_: &TlsConnector, _: &str, _: S) -> io::Result<ClientStream<S>> {
+    Err(unsupported())
+}
+
+pub async fn accept<S>(acceptor: &TlsAcceptor, _: S) -> io::Result<ServerStream<S>> {
+    match acceptor.0 {}
+}
+
+pub fn inner<S>(stream: &ServerStream<S>) -> &S {
+    match stream.0 {}
+}
+
+pub fn inner_mut<S>(stream: &mut ServerStream<S>) -> &mut S {
+    match stream.0 {}
+}
+
+/// Never built
+pub struct Authority(Infallible);
+
+impl Authority {
+    pub fn generate(_: &str) -> io::Result<(String, String)> {
+        Err(unsupported())
+    }
+
+    pub fn from_pem(_: &str, _: &str) -> io::Result<Authority> {
+        Err(unsupported())
+    }
+
+    pub fn acceptor(&self, _: &str, _: &[&str]) -> io::Result<TlsAcceptor> {
+        match self.0 {}
+    }
+}
+
+macro_rules! impl_io {
+    ($stream:ident) => {
+        impl<S> AsyncRead for $stream<S> {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                _: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                match self.0 {}
+            }
+        }
+
+        impl<S> AsyncWrite for $stream<S> {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                _: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                match self.0 {}
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+                match self.0 {}
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+                match self.0 {}
+            }
+        }
+    };
+}
+
+impl_io!(ClientStream);
+impl_io!(ServerStream);
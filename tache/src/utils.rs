@@ -1,33 +1,23 @@
 use std::{
-    collections::HashSet,
     convert::From,
     default::Default,
-    error,
     fmt::Write,
     fmt::{self, Debug, Display, Formatter},
-    fs::OpenOptions,
-    io::{self, Read},
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+    io,
+    net::{SocketAddr, ToSocketAddrs},
     option::Option,
-    path::Path,
+    path::PathBuf,
     str::FromStr,
     string::ToString,
-    time::Duration,
     vec,
 };
 
-use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
-use bytes::Bytes;
-use json5;
-use log::{error, trace};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::{
     de::{self, Deserialize, Deserializer, Visitor},
-    ser::{self, Serialize, Serializer},
+    ser::{Serialize, Serializer},
     *,
 };
-use serde_urlencoded;
-use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig};
-use url::{self, Url};
 
 #[derive(Clone, Debug)]
 pub struct DomainName(pub String, pub u16);
@@ -108,8 +98,18 @@ impl Serialize for DomainName {
     }
 }
 
+impl JsonSchema for DomainName {
+    fn schema_name() -> String {
+        "DomainName".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
 /// Address
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum Address {
     /// IP Address
@@ -134,6 +134,17 @@ impl Address {
             Address::DomainName(ref p) => p.1,
         }
     }
+
+    /// Resolve to socket addresses without blocking the runtime
+    pub async fn lookup(&self) -> io::Result<Vec<SocketAddr>> {
+        match *self {
+            Address::SocketAddr(addr) => Ok(vec![addr]),
+            Address::DomainName(ref dm) => {
+                let addrs = crate::rt::lookup_host((dm.0.as_str(), dm.1)).await?;
+                Ok(addrs.collect())
+            }
+        }
+    }
 }
 
 /// Parse `Address` error
@@ -162,7 +173,10 @@ impl FromStr for Address {
 
 impl Display for Address {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{}", self)
+        match *self {
+            Address::SocketAddr(ref s) => write!(f, "{}", s),
+            Address::DomainName(ref dm) => write!(f, "{}", dm),
+        }
     }
 }
 
@@ -196,3 +210,174 @@ impl Iterator for Iter {
         }
     }
 }
+
+/// Where a server listens, a TCP address or `unix:///path/to.sock`
+#[derive(Clone, Debug)]
+pub enum ListenAddress {
+    Tcp(Address),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<ListenAddress, AddressError> {
+        if let Some(path) = s.strip_prefix("unix://") {
+            if path.is_empty() {
+                return Err(AddressError);
+            }
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+        s.parse().map(ListenAddress::Tcp)
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ListenAddress::Tcp(ref addr) => write!(f, "{}", addr),
+            ListenAddress::Unix(ref path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddress {
+    fn deserialize<D>(deserializer: D) -> Result<ListenAddress, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| de::Error::custom(format!("invalid listen address \"{}\"", s)))
+    }
+}
+
+impl JsonSchema for ListenAddress {
+    fn schema_name() -> String {
+        "ListenAddress".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+impl Serialize for ListenAddress {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// Ports one range may span, more is likely a typo
+const MAX_PORT_RANGE: u32 = 1024;
+
+/// One or more listen addresses, TCP ports may be ranges like
+/// `0.0.0.0:10000-10010`
+///
+/// Kept as written for serializing, every expanded address for binding.
+#[derive(Clone, Debug)]
+pub struct ListenAddresses {
+    specs: Vec<String>,
+    addresses: Vec<ListenAddress>,
+}
+
+impl ListenAddresses {
+    pub fn new(specs: Vec<String>) -> Result<ListenAddresses, String> {
+        let mut addresses = Vec::new();
+        for spec in specs.iter() {
+            expand_listen(spec, &mut addresses)?;
+        }
+        if addresses.is_empty() {
+            return Err("no listen address".to_owned());
+        }
+        Ok(ListenAddresses { specs, addresses })
+    }
+}
+
+fn expand_listen(spec: &str, addresses: &mut Vec<ListenAddress>) -> Result<(), String> {
+    let invalid = || format!("invalid listen address \"{}\"", spec);
+    if let Ok(listen) = spec.parse() {
+        addresses.push(listen);
+        return Ok(());
+    }
+    let colon = spec.rfind(':').ok_or_else(invalid)?;
+    let (host, range) = (&spec[..colon], &spec[colon + 1..]);
+    let mut ends = range.splitn(2, '-').map(|p| p.trim().parse::<u16>());
+    let (first, last) = match (ends.next(), ends.next()) {
+        (Some(Ok(first)), Some(Ok(last))) if first <= last => (first, last),
+        _ => return Err(invalid()),
+    };
+    if u32::from(last - first) >= MAX_PORT_RANGE {
+        return Err(format!(
+            "port range of \"{}\" spans over {} ports",
+            spec, MAX_PORT_RANGE
+        ));
+    }
+    for port in first..=last {
+        let listen = format!("{}:{}", host, port)
+            .parse()
+            .map_err(|_| invalid())?;
+        addresses.push(listen);
+    }
+    Ok(())
+}
+
+impl std::ops::Deref for ListenAddresses {
+    type Target = [ListenAddress];
+
+    fn deref(&self) -> &[ListenAddress] {
+        &self.addresses
+    }
+}
+
+impl Display for ListenAddresses {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.specs.join(", "))
+    }
+}
+
+/// As written in configs, one string or a list of them
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum ListenSpecs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl<'de> Deserialize<'de> for ListenAddresses {
+    fn deserialize<D>(deserializer: D) -> Result<ListenAddresses, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let specs = match ListenSpecs::deserialize(deserializer)? {
+            ListenSpecs::One(spec) => vec![spec],
+            ListenSpecs::Many(specs) => specs,
+        };
+        ListenAddresses::new(specs).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ListenAddresses {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.specs[..] {
+            [ref spec] => serializer.serialize_str(spec),
+            ref specs => specs.serialize(serializer),
+        }
+    }
+}
+
+impl JsonSchema for ListenAddresses {
+    fn schema_name() -> String {
+        "ListenAddresses".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        ListenSpecs::json_schema(gen)
+    }
+}
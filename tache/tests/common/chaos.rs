@@ -0,0 +1,160 @@
+//! Dialer injecting network faults, so tests can check that retries,
+//! fallbacks and timeouts actually engage
+//!
+//! Faults apply to connections towards the servers they are set for, every
+//! other connection goes through the wrapped dialer untouched.
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use tache::{
+    outbound::{BoxStream, Dialer},
+    Address,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::delay_for,
+};
+
+/// What goes wrong on connections to one server
+#[derive(Clone, Copy, Default)]
+pub struct Faults {
+    /// Added before the connection is established
+    pub latency: Duration,
+    /// Largest write accepted at once, callers have to handle the rest
+    pub max_write: Option<usize>,
+    /// Bytes read and written in total before the connection is reset
+    pub reset_after: Option<usize>,
+    /// Bytes read before the server seems to close, cutting handshakes short
+    pub truncate_after: Option<usize>,
+}
+
+pub struct Chaos<D> {
+    inner: D,
+    servers: Vec<(SocketAddr, Faults)>,
+}
+
+impl<D: Dialer> Chaos<D> {
+    pub fn new(inner: D) -> Chaos<D> {
+        Chaos {
+            inner,
+            servers: Vec::new(),
+        }
+    }
+
+    /// Inject `faults` into connections to `server`
+    pub fn on(mut self, server: SocketAddr, faults: Faults) -> Chaos<D> {
+        self.servers.push((server, faults));
+        self
+    }
+
+    fn faults(&self, target: &Address) -> Option<Faults> {
+        let target = target.to_string();
+        self.servers
+            .iter()
+            .find(|(server, _)| server.to_string() == target)
+            .map(|(_, faults)| *faults)
+    }
+}
+
+impl<D: Dialer> Dialer for Chaos<D> {
+    fn connect<'a>(&'a self, target: &'a Address) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let faults = match self.faults(target) {
+                Some(faults) => faults,
+                None => return self.inner.connect(target).await,
+            };
+            delay_for(faults.latency).await;
+            let inner = self.inner.connect(target).await?;
+            Ok(Box::new(ChaosStream {
+                inner,
+                faults,
+                read: 0,
+                written: 0,
+            }) as BoxStream)
+        })
+    }
+}
+
+struct ChaosStream {
+    inner: BoxStream,
+    faults: Faults,
+    read: usize,
+    written: usize,
+}
+
+impl ChaosStream {
+    fn reset(&self) -> bool {
+        self.faults
+            .reset_after
+            .is_some_and(|after| self.read + self.written >= after)
+    }
+}
+
+fn reset() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "chaos reset")
+}
+
+impl AsyncRead for ChaosStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.reset() {
+            return Poll::Ready(Err(reset()));
+        }
+        let left = match self.faults.truncate_after {
+            Some(after) => after.saturating_sub(self.read),
+            None => buf.len(),
+        };
+        if left == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        let len = buf.len().min(left);
+        match Pin::new(&mut self.inner).poll_read(cx, &mut buf[..len]) {
+            Poll::Ready(Ok(n)) => {
+                self.read += n;
+                Poll::Ready(Ok(n))
+            }
+            other => other,
+        }
+    }
+}
+
+impl AsyncWrite for ChaosStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.reset() {
+            return Poll::Ready(Err(reset()));
+        }
+        let len = self
+            .faults
+            .max_write
+            .map_or(buf.len(), |max| buf.len().min(max));
+        match Pin::new(&mut self.inner).poll_write(cx, &buf[..len]) {
+            Poll::Ready(Ok(n)) => {
+                self.written += n;
+                Poll::Ready(Ok(n))
+            }
+            other => other,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,509 @@
+//! In-process servers and config helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+pub mod chaos;
+
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures::future::try_join;
+use tache::{
+    crypto::CipherKind,
+    outbound::shadowsocks::{master_key, AeadStream},
+    protocol::socks::read_addr,
+    Config,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Runtime,
+};
+#[cfg(feature = "ring-crypto")]
+use tokio_rustls::TlsAcceptor;
+pub use trust_dns_proto::rr::RecordType;
+use trust_dns_proto::{
+    op::{Message, Query},
+    rr::{Name, RData},
+};
+
+/// Body served by [`spawn_http_origin`]
+pub const ORIGIN_BODY: &str = "hello from origin";
+
+/// Reserve a loopback port, it is free again once this returns
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Accept forever on a background thread, each connection on its own thread
+fn serve<F>(handler: F) -> SocketAddr
+where
+    F: Fn(TcpStream) -> io::Result<()> + Send + Sync + Copy + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            thread::spawn(move || {
+                let _ = handler(stream);
+            });
+        }
+    });
+    addr
+}
+
+/// Echo every byte back until the client closes
+pub fn spawn_echo_server() -> SocketAddr {
+    serve(|mut stream| {
+        let mut reader = stream.try_clone()?;
+        io::copy(&mut reader, &mut stream)?;
+        stream.shutdown(Shutdown::Write)
+    })
+}
+
+/// Answer every request with [`ORIGIN_BODY`] and close
+pub fn spawn_http_origin() -> SocketAddr {
+    serve(|mut stream| {
+        read_head(&mut stream)?;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            ORIGIN_BODY.len(),
+            ORIGIN_BODY
+        )
+    })
+}
+
+/// Answer every request with [`ORIGIN_BODY`] fresh for a minute, counting
+/// the requests
+pub fn spawn_cacheable_origin() -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            if read_head(&mut stream).is_err() {
+                continue;
+            }
+            counter.fetch_add(1, Ordering::SeqCst);
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                ORIGIN_BODY.len(),
+                ORIGIN_BODY
+            );
+        }
+    });
+    (addr, requests)
+}
+
+/// Answer every request over TLS with [`ORIGIN_BODY`] and close, presenting
+/// the certificate of `acceptor`
+#[cfg(feature = "ring-crypto")]
+pub fn spawn_https_origin(acceptor: TlsAcceptor) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let mut listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = acceptor.accept(stream).await?;
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(stream.read_u8().await?);
+                    }
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        ORIGIN_BODY.len(),
+                        ORIGIN_BODY
+                    );
+                    stream.write_all(response.as_bytes()).await?;
+                    stream.shutdown().await
+                });
+            }
+        });
+    });
+    addr
+}
+
+/// Answer every request with its Content-Length body and close
+pub fn spawn_http_echo_origin() -> SocketAddr {
+    serve(|mut stream| {
+        let head = read_head(&mut stream)?;
+        let mut body = vec![0u8; content_length(&head)];
+        stream.read_exact(&mut body)?;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )?;
+        stream.write_all(&body)
+    })
+}
+
+/// SOCKS5 server without authentication supporting CONNECT only
+pub fn spawn_socks5_server() -> SocketAddr {
+    serve(|mut stream| {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head)?;
+        let mut methods = vec![0u8; head[1] as usize];
+        stream.read_exact(&mut methods)?;
+        stream.write_all(&[5, 0])?;
+
+        let mut req = [0u8; 4];
+        stream.read_exact(&mut req)?;
+        let target = match req[3] {
+            0x01 => {
+                let mut ip = [0u8; 4];
+                stream.read_exact(&mut ip)?;
+                format!("{}", IpAddr::from(Ipv4Addr::from(ip)))
+            }
+            0x04 => {
+                let mut ip = [0u8; 16];
+                stream.read_exact(&mut ip)?;
+                format!("[{}]", Ipv6Addr::from(ip))
+            }
+            _ => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                let mut name = vec![0u8; len[0] as usize];
+                stream.read_exact(&mut name)?;
+                String::from_utf8_lossy(&name).into_owned()
+            }
+        };
+        let mut port = [0u8; 2];
+        stream.read_exact(&mut port)?;
+        let port = u16::from_be_bytes(port);
+
+        let remote = match TcpStream::connect(format!("{}:{}", target, port)) {
+            Ok(remote) => remote,
+            Err(e) => {
+                stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])?;
+                return Err(e);
+            }
+        };
+        stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])?;
+        pipe(stream, remote)
+    })
+}
+
+/// Shadowsocks AEAD server of `cipher` and `password` connecting to any
+/// target, recording the targets it relayed to
+pub fn spawn_shadowsocks_server(
+    cipher: &str,
+    password: &str,
+) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+    let kind = CipherKind::from_name(cipher).expect("known cipher");
+    let key = master_key(password, kind.key_len());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let served = Arc::new(Mutex::new(Vec::new()));
+    let targets = served.clone();
+    thread::spawn(move || {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            let mut listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            while let Ok((stream, _)) = listener.accept().await {
+                let key = key.clone();
+                let targets = targets.clone();
+                tokio::spawn(async move {
+                    let _ = relay_shadowsocks(stream, kind, &key, &targets).await;
+                });
+            }
+        });
+    });
+    (addr, served)
+}
+
+async fn relay_shadowsocks(
+    stream: tokio::net::TcpStream,
+    kind: CipherKind,
+    key: &[u8],
+    served: &Mutex<Vec<String>>,
+) -> io::Result<()> {
+    let mut client = AeadStream::new(stream, kind, key)?;
+    // Address type, the address and its port
+    let mut addr = vec![client.read_u8().await?];
+    let len = match addr[0] {
+        0x01 => 4 + 2,
+        0x04 => 16 + 2,
+        _ => {
+            let len = client.read_u8().await?;
+            addr.push(len);
+            len as usize + 2
+        }
+    };
+    let start = addr.len();
+    addr.resize(start + len, 0);
+    client.read_exact(&mut addr[start..]).await?;
+    let target = read_addr(&mut &addr[..])?;
+    served.lock().unwrap().push(target.to_string());
+
+    let remote = tokio::net::TcpStream::connect(target.to_string()).await?;
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+    let up = async {
+        tokio::io::copy(&mut client_read, &mut remote_write).await?;
+        remote_write.shutdown().await
+    };
+    let down = async {
+        tokio::io::copy(&mut remote_read, &mut client_write).await?;
+        client_write.shutdown().await
+    };
+    try_join(up, down).await.map(|_| ())
+}
+
+/// HTTP proxy supporting CONNECT only
+pub fn spawn_http_connect_proxy() -> SocketAddr {
+    serve(|mut stream| {
+        let head = read_head(&mut stream)?;
+        let target = head
+            .lines()
+            .next()
+            .and_then(|line| {
+                let mut parts = line.split(' ');
+                match (parts.next(), parts.next()) {
+                    (Some("CONNECT"), Some(target)) => Some(target.to_owned()),
+                    _ => None,
+                }
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a CONNECT"))?;
+        let remote = match TcpStream::connect(target) {
+            Ok(remote) => remote,
+            Err(e) => {
+                stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
+                return Err(e);
+            }
+        };
+        stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+        pipe(stream, remote)
+    })
+}
+
+/// Read up to and including the blank line ending a request or response head
+pub fn read_head<R: Read>(stream: &mut R) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Copy both ways until either side closes
+fn pipe(a: TcpStream, b: TcpStream) -> io::Result<()> {
+    let (mut a_read, mut b_write) = (a.try_clone()?, b.try_clone()?);
+    let forward = thread::spawn(move || {
+        let _ = io::copy(&mut a_read, &mut b_write);
+        let _ = b_write.shutdown(Shutdown::Write);
+    });
+    let (mut b_read, mut a_write) = (b, a);
+    let _ = io::copy(&mut b_read, &mut a_write);
+    let _ = a_write.shutdown(Shutdown::Write);
+    let _ = forward.join();
+    Ok(())
+}
+
+/// Connect, retrying while a listener is still starting up
+pub fn connect_retry(addr: SocketAddr) -> TcpStream {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => {
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                return stream;
+            }
+            Err(e) if Instant::now() >= deadline => panic!("connect {}: {}", addr, e),
+            Err(_) => thread::sleep(Duration::from_millis(20)),
+        }
+    }
+}
+
+/// Whether TUN devices can be created, which takes root
+pub fn tun_available() -> bool {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")
+        .is_ok()
+}
+
+fn dns_query(name: &str, kind: RecordType) -> Vec<u8> {
+    let mut query = Message::new();
+    query
+        .set_id(1)
+        .set_recursion_desired(true)
+        .add_query(Query::query(Name::from_ascii(name).unwrap(), kind));
+    query.to_vec().unwrap()
+}
+
+fn first_address(response: &[u8]) -> IpAddr {
+    let response = Message::from_vec(response).unwrap();
+    for answer in response.answers() {
+        match *answer.rdata() {
+            RData::A(ip) => return ip.into(),
+            RData::AAAA(ip) => return ip.into(),
+            _ => {}
+        }
+    }
+    panic!("no address in {:?}", response);
+}
+
+/// Address `server` answers a `kind` query for `name` with, asking again
+/// while it is still starting up
+pub fn resolve(server: SocketAddr, name: &str, kind: RecordType) -> IpAddr {
+    let query = dns_query(name, kind);
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let mut buf = [0; 512];
+    for _ in 0..25 {
+        socket.send_to(&query, server).unwrap();
+        if let Ok(len) = socket.recv(&mut buf) {
+            return first_address(&buf[..len]);
+        }
+    }
+    panic!("no answer from {}", server);
+}
+
+/// Like `resolve`, over a TCP connection asking twice
+pub fn resolve_tcp(server: SocketAddr, name: &str, kind: RecordType) -> IpAddr {
+    let query = dns_query(name, kind);
+    let mut stream = connect_retry(server);
+    let mut answers = (0..2).map(|_| {
+        stream
+            .write_all(&(query.len() as u16).to_be_bytes())
+            .unwrap();
+        stream.write_all(&query).unwrap();
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut response = vec![0; usize::from(u16::from_be_bytes(len))];
+        stream.read_exact(&mut response).unwrap();
+        first_address(&response)
+    });
+    let first = answers.next().unwrap();
+    assert_eq!(answers.next(), Some(first));
+    first
+}
+
+/// Whether `dst` answers an ICMP echo request within a second, over a raw
+/// socket as there may be no `ping` around
+pub fn ping(dst: Ipv4Addr) -> bool {
+    const ID: u16 = 0x7461;
+    let mut request = [8, 0, 0, 0, (ID >> 8) as u8, ID as u8, 0, 1, b't', b'a'];
+    let sum = request.chunks(2).fold(0u32, |sum, word| {
+        sum + u32::from(u16::from_be_bytes([word[0], word[1]]))
+    });
+    let sum = !((sum & 0xffff) + (sum >> 16)) as u16;
+    request[2..4].copy_from_slice(&sum.to_be_bytes());
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_ICMP);
+        assert!(fd >= 0, "raw socket: {}", io::Error::last_os_error());
+        let timeout = libc::timeval {
+            tv_sec: 0,
+            tv_usec: 100_000,
+        };
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        );
+        let mut addr: libc::sockaddr_in = std::mem::zeroed();
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_addr.s_addr = u32::from(dst).to_be();
+        libc::sendto(
+            fd,
+            request.as_ptr() as *const libc::c_void,
+            request.len(),
+            0,
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        );
+        // Raw sockets see every ICMP packet, the reply is the one from `dst`
+        // with our identifier
+        let mut buf = [0u8; 1500];
+        let mut answered = false;
+        for _ in 0..10 {
+            let len = libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0);
+            if len < 28 {
+                continue;
+            }
+            let icmp = &buf[usize::from(buf[0] & 0xf) * 4..];
+            if buf[12..16] == dst.octets() && icmp[0] == 0 && icmp[4..6] == ID.to_be_bytes() {
+                answered = true;
+                break;
+            }
+        }
+        libc::close(fd);
+        answered
+    }
+}
+
+/// Minimal config, `extra` is appended as further top level YAML
+pub fn config(extra: &str) -> Config {
+    let mut yaml = String::from("mode: rule\nlog-level: silent\n");
+    for section in &["inbounds", "proxies", "proxy-groups", "rules"] {
+        if !extra.contains(&format!("{}:", section)) {
+            yaml.push_str(&format!("{}: []\n", section));
+        }
+    }
+    yaml.push_str(extra);
+    Config::load_from_str(&yaml).expect("valid test config")
+}
+
+/// Content-Length of a request or response head, 0 without one
+pub fn content_length(head: &str) -> usize {
+    head.lines()
+        .filter_map(|line| {
+            let mut sp = line.splitn(2, ':');
+            match (sp.next(), sp.next()) {
+                (Some(name), Some(value)) if name.eq_ignore_ascii_case("content-length") => {
+                    value.trim().parse::<usize>().ok()
+                }
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or(0)
+}
+
+/// Send a request and return the response head and its Content-Length body
+pub fn http_request(addr: SocketAddr, request: &str) -> (String, String) {
+    let mut stream = connect_retry(addr);
+    stream.write_all(request.as_bytes()).unwrap();
+    read_response(&mut stream)
+}
+
+/// Read a response head and its Content-Length body
+pub fn read_response<R: Read>(stream: &mut R) -> (String, String) {
+    let head = read_head(stream).unwrap();
+    let mut body = vec![0u8; content_length(&head)];
+    stream.read_exact(&mut body).unwrap();
+    (head, String::from_utf8_lossy(&body).into_owned())
+}
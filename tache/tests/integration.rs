@@ -0,0 +1,830 @@
+//! End-to-end tests running the engine against in-process servers
+
+mod common;
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::io::AsRawFd,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use tache::{
+    buffer::BufferPool,
+    engine::relay::relay,
+    outbound::{Chained, Dialer, Http, Outbound, Smart, Socks5, TcpDialer},
+    Address, Engine, EngineError, Event,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    runtime::Runtime,
+};
+
+use common::{
+    chaos::{Chaos, Faults},
+    *,
+};
+
+fn address(addr: SocketAddr) -> Address {
+    addr.to_string().parse().unwrap()
+}
+
+/// Dial `target` through `outbound` and read the origin response
+fn fetch_via(outbound: &dyn Outbound, target: SocketAddr) -> String {
+    fetch_with(outbound, &TcpDialer, target).unwrap()
+}
+
+/// Like `fetch_via`, reaching the proxy server with `dialer` and failing
+/// with `TimedOut` when the dial hangs
+fn fetch_with(
+    outbound: &dyn Outbound,
+    dialer: &dyn Dialer,
+    target: SocketAddr,
+) -> io::Result<String> {
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(async move {
+        let target = address(target);
+        let dial = outbound.dial(&target, dialer);
+        let mut stream = tache::rt::timeout(Duration::from_secs(5), dial).await??;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: origin\r\n\r\n")
+            .await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok(String::from_utf8_lossy(&response).into_owned())
+    })
+}
+
+fn smart_group(members: &[SocketAddr], retries: usize) -> Smart {
+    let members = members
+        .iter()
+        .enumerate()
+        .map(|(i, server)| {
+            let name = format!("socks{}", i);
+            Arc::new(Socks5::new(&name, address(*server), None, None)) as Arc<dyn Outbound>
+        })
+        .collect();
+    // Nothing listens there, url tests leave members unranked
+    Smart::new(
+        "smart",
+        members,
+        "http://127.0.0.1:9/".to_owned(),
+        300,
+        retries,
+    )
+}
+
+#[test]
+fn socks5_outbound_reaches_origin() {
+    let origin = spawn_http_origin();
+    let server = spawn_socks5_server();
+    let outbound = Socks5::new("socks", address(server), None, None);
+    assert!(fetch_via(&outbound, origin).ends_with(ORIGIN_BODY));
+}
+
+#[test]
+fn http_outbound_tunnels_with_connect() {
+    let origin = spawn_http_origin();
+    let server = spawn_http_connect_proxy();
+    let outbound = Http::new("http", address(server), None, None);
+    assert!(fetch_via(&outbound, origin).ends_with(ORIGIN_BODY));
+}
+
+#[test]
+fn chained_outbound_dials_through_parent() {
+    let origin = spawn_http_origin();
+    let socks = spawn_socks5_server();
+    let http = spawn_http_connect_proxy();
+    // The HTTP proxy is reached through the SOCKS5 server
+    let outbound = Chained::new(
+        Arc::new(Http::new("http", address(http), None, None)),
+        Arc::new(Socks5::new("socks", address(socks), None, None)),
+    );
+    assert!(fetch_via(&outbound, origin).ends_with(ORIGIN_BODY));
+}
+
+#[test]
+fn socks5_outbound_reports_unreachable_target() {
+    let server = spawn_socks5_server();
+    let outbound = Socks5::new("socks", address(server), None, None);
+    let target = address(format!("127.0.0.1:{}", free_port()).parse().unwrap());
+    let mut rt = Runtime::new().unwrap();
+    assert!(rt.block_on(outbound.dial(&target, &TcpDialer)).is_err());
+}
+
+#[test]
+fn smart_group_retries_past_reset_member() {
+    let origin = spawn_http_origin();
+    let (broken, working) = (spawn_socks5_server(), spawn_socks5_server());
+    let reset = Faults {
+        reset_after: Some(0),
+        ..Faults::default()
+    };
+    let dialer = Chaos::new(TcpDialer).on(broken, reset);
+
+    let alone = smart_group(&[broken, working], 0);
+    assert!(fetch_with(&alone, &dialer, origin).is_err());
+    let group = smart_group(&[broken, working], 1);
+    assert!(fetch_with(&group, &dialer, origin)
+        .unwrap()
+        .ends_with(ORIGIN_BODY));
+}
+
+#[test]
+fn outbound_handles_latency_and_partial_writes() {
+    let origin = spawn_http_origin();
+    let server = spawn_socks5_server();
+    let slow = Faults {
+        latency: Duration::from_millis(200),
+        max_write: Some(1),
+        ..Faults::default()
+    };
+    let dialer = Chaos::new(TcpDialer).on(server, slow);
+    let outbound = Socks5::new("socks", address(server), None, None);
+
+    let start = std::time::Instant::now();
+    assert!(fetch_with(&outbound, &dialer, origin)
+        .unwrap()
+        .ends_with(ORIGIN_BODY));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn outbound_fails_on_truncated_handshake() {
+    let origin = spawn_http_origin();
+    let server = spawn_http_connect_proxy();
+    // The server goes away in the middle of its status line
+    let truncated = Faults {
+        truncate_after: Some(12),
+        ..Faults::default()
+    };
+    let dialer = Chaos::new(TcpDialer).on(server, truncated);
+    let outbound = Http::new("http", address(server), None, None);
+
+    let err = fetch_with(&outbound, &dialer, origin).unwrap_err();
+    assert_ne!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn relay_copies_both_directions_under_backpressure() {
+    let echo = spawn_echo_server();
+    // Buffers much smaller than the payload so reads pause at the ceiling
+    let pool = Arc::new(BufferPool::new(1024, 4, 2048));
+    let rt = Runtime::new().unwrap();
+    let listen: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+
+    let relay_pool = pool.clone();
+    rt.spawn(async move {
+        let mut listener = TcpListener::bind(&listen).await.unwrap();
+        let (mut local, _) = listener.accept().await.unwrap();
+        let mut remote = tokio::net::TcpStream::connect(&echo).await.unwrap();
+        relay(&mut local, &mut remote, &relay_pool, None)
+            .await
+            .unwrap();
+    });
+
+    let payload: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+    let mut client = connect_retry(listen);
+    let mut writer = client.try_clone().unwrap();
+    let sent = payload.clone();
+    let sender = std::thread::spawn(move || {
+        writer.write_all(&sent).unwrap();
+        writer.shutdown(std::net::Shutdown::Write).unwrap();
+    });
+    let mut echoed = Vec::new();
+    client.read_to_end(&mut echoed).unwrap();
+    sender.join().unwrap();
+
+    assert_eq!(echoed, payload);
+    // Every buffer went back to the pool, up to its idle limit
+    assert!(pool.idle() <= 4);
+}
+
+#[test]
+fn engine_starts_reloads_and_shuts_down() {
+    let port = free_port();
+    let config = config(&format!(
+        "inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{} }}\n",
+        port
+    ));
+    let engine = Engine::builder().config(config.clone()).build().unwrap();
+    let mut events = engine.subscribe();
+
+    engine.start().unwrap();
+    assert!(engine.is_running());
+    assert!(engine.start().is_err());
+    connect_retry(([127, 0, 0, 1], port).into());
+
+    engine.reload(config).unwrap();
+    assert!(engine.is_running());
+    engine.shutdown().unwrap();
+    assert!(!engine.is_running());
+    assert!(engine.start().is_err());
+
+    let mut rt = Runtime::new().unwrap();
+    let received: Vec<Event> = rt.block_on(async move {
+        let mut received = Vec::new();
+        while let Some(event) = events.next().await {
+            let stopped = matches!(event, Event::Stopped);
+            received.push(event);
+            if stopped {
+                break;
+            }
+        }
+        received
+    });
+    let kinds: Vec<&str> = received
+        .iter()
+        .filter_map(|e| match e {
+            Event::Started => Some("started"),
+            Event::Reloaded => Some("reloaded"),
+            Event::Stopped => Some("stopped"),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(kinds, ["started", "started", "reloaded", "stopped"]);
+}
+
+#[test]
+fn engine_reports_listen_failure() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let config = config(&format!(
+        "inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{} }}\n",
+        port
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    let mut events = engine.subscribe();
+    engine.start().unwrap();
+
+    let mut rt = Runtime::new().unwrap();
+    let failed = rt.block_on(async move {
+        loop {
+            if let Event::Failed { error } = events.next().await.unwrap() {
+                return error;
+            }
+        }
+    });
+    assert!(!failed.is_empty());
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!engine.is_running());
+}
+
+#[test]
+fn failed_reload_keeps_the_old_engine_serving() {
+    let port = free_port();
+    let running = config(&format!(
+        "inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{} }}\n",
+        port
+    ));
+    let engine = Engine::builder().config(running.clone()).build().unwrap();
+    engine.start().unwrap();
+    connect_retry(([127, 0, 0, 1], port).into());
+
+    // Rejected before anything is stopped
+    let mut invalid = running.clone();
+    invalid
+        .rules
+        .push(serde_yaml::from_str("{ kind: MATCH }").unwrap());
+    assert!(matches!(
+        engine.reload(invalid),
+        Err(EngineError::Config(_))
+    ));
+
+    // Valid, but its inbound can't listen
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let unbindable = config(&format!(
+        "inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{} }}\n",
+        taken.local_addr().unwrap().port()
+    ));
+    assert!(matches!(
+        engine.reload(unbindable),
+        Err(EngineError::Failed(_))
+    ));
+
+    assert!(engine.is_running());
+    assert_eq!(
+        serde_yaml::to_string(&engine.config()).unwrap(),
+        serde_yaml::to_string(&running).unwrap()
+    );
+    connect_retry(([127, 0, 0, 1], port).into());
+    engine.shutdown().unwrap();
+}
+
+#[test]
+fn api_serves_version() {
+    let port = free_port();
+    let config = config(&format!("api:\n  listen: 127.0.0.1:{}\n", port));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    let (head, body) = http_request(
+        ([127, 0, 0, 1], port).into(),
+        "GET /version HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    );
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert!(body.contains(tache::VERSION));
+}
+
+#[cfg(unix)]
+#[test]
+fn api_serves_on_unix_socket() {
+    use std::os::unix::net::UnixStream;
+
+    let path = std::env::temp_dir().join(format!("tache-api-{}.sock", free_port()));
+    let config = config(&format!("api:\n  listen: unix://{}\n", path.display()));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    let mut stream = (0..250)
+        .find_map(|_| {
+            UnixStream::connect(&path).ok().or_else(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                None
+            })
+        })
+        .expect("API socket not created");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(b"GET /version HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let head = read_head(&mut stream).unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    engine.shutdown().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn http_inbound_continues_uploads_and_keeps_alive() {
+    let origin = spawn_http_echo_origin();
+    let port = free_port();
+    let config = config(&format!(
+        "inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{} }}\n",
+        port
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    let mut stream = connect_retry(([127, 0, 0, 1], port).into());
+    write!(
+        stream,
+        "POST http://{0}/upload HTTP/1.1\r\nHost: {0}\r\n\
+         Expect: 100-continue\r\nContent-Length: 6\r\n\r\n",
+        origin
+    )
+    .unwrap();
+    assert!(read_head(&mut stream).unwrap().starts_with("HTTP/1.1 100"));
+    stream.write_all(b"upload").unwrap();
+    let (head, body) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert!(!head.to_ascii_lowercase().contains("connection: close"));
+    assert_eq!(body, "upload");
+
+    // The next request reuses the connection
+    write!(
+        stream,
+        "POST http://{0}/ HTTP/1.1\r\nHost: {0}\r\nContent-Length: 5\r\n\r\nagain",
+        origin
+    )
+    .unwrap();
+    let (head, body) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, "again");
+}
+
+#[test]
+fn http_inbound_forwards_plain_requests() {
+    let origin = spawn_http_origin();
+    let port = free_port();
+    let config = config(&format!(
+        "inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{} }}\n",
+        port
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    let (head, body) = http_request(
+        ([127, 0, 0, 1], port).into(),
+        &format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", origin),
+    );
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, ORIGIN_BODY);
+}
+
+#[test]
+fn http_inbound_tunnels_connect_through_socks5_rule() {
+    let origin = spawn_http_origin();
+    let upstream = spawn_socks5_server();
+    let port = free_port();
+    let config = config(&format!(
+        "inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{port} }}\n\
+         proxies:\n  - {{ name: socks, kind: socks5, address: {upstream} }}\n\
+         rules:\n  - {{ kind: MATCH, source: [http1], params: [], target: socks }}\n",
+        port = port,
+        upstream = upstream
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    let mut stream = connect_retry(([127, 0, 0, 1], port).into());
+    write!(stream, "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", origin).unwrap();
+    assert!(read_head(&mut stream).unwrap().starts_with("HTTP/1.1 200"));
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: origin\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with(ORIGIN_BODY));
+}
+
+#[test]
+fn socks5_inbound_falls_back_to_direct() {
+    let origin = spawn_http_origin();
+    let port = free_port();
+    let config = config(&format!(
+        "inbounds:\n  - {{ name: socks1, kind: socks5, listen: 127.0.0.1:{} }}\n",
+        port
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    // Wait for the listener
+    drop(connect_retry(([127, 0, 0, 1], port).into()));
+    let outbound = Socks5::new("socks", address(([127, 0, 0, 1], port).into()), None, None);
+    assert!(fetch_via(&outbound, origin).ends_with(ORIGIN_BODY));
+}
+
+#[test]
+fn http_inbound_serves_cached_responses() {
+    let (origin, requests) = spawn_cacheable_origin();
+    let port = free_port();
+    let config = config(&format!(
+        "http-cache: {{ memory-size: 65536 }}\n\
+         inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{} }}\n",
+        port
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    let request = format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", origin);
+    let (head, body) = http_request(([127, 0, 0, 1], port).into(), &request);
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, ORIGIN_BODY);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // Answered from the cache without asking the origin
+    let (head, body) = http_request(([127, 0, 0, 1], port).into(), &request);
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert!(head.contains("Age: "));
+    assert_eq!(body, ORIGIN_BODY);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn http_inbound_rewrites_response_headers() {
+    let origin = spawn_http_origin();
+    let port = free_port();
+    let config = config(&format!(
+        "rewrites:\n  - {{ url: \"^http://{origin}/\", \
+         response-headers: {{ add: {{ X-Rewritten: \"1\" }} }} }}\n\
+         inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{port} }}\n",
+        origin = origin,
+        port = port
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    let (head, body) = http_request(
+        ([127, 0, 0, 1], port).into(),
+        &format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", origin),
+    );
+    assert!(head.to_ascii_lowercase().contains("x-rewritten: 1\r\n"));
+    assert_eq!(body, ORIGIN_BODY);
+}
+
+// TLS comes with ring only
+#[cfg(feature = "ring-crypto")]
+#[test]
+fn http_inbound_proxies_intercepted_tunnels() {
+    use tache::{config::MitmConfig, engine::mitm::Mitm};
+    use tokio_rustls::webpki::DNSNameRef;
+
+    let dir = std::env::temp_dir().join(format!("tache-mitm-test-{}", free_port()));
+    fs::create_dir_all(&dir).unwrap();
+    let ca = dir.join("ca.pem").to_string_lossy().into_owned();
+    let key = dir.join("ca.key").to_string_lossy().into_owned();
+    let mitm = MitmConfig {
+        ca_cert: ca.clone(),
+        ca_key: key.clone(),
+        hosts: vec!["localhost".to_owned()],
+        origin_ca: Some(ca.clone()),
+    };
+    // Generates the CA, which issues the certificate of the origin as well
+    let origin = spawn_https_origin(Mitm::new(&mitm).unwrap().acceptor("localhost").unwrap());
+    let port = free_port();
+    let config = config(&format!(
+        "mitm: {{ ca-cert: \"{ca}\", ca-key: \"{key}\", hosts: [localhost], \
+         origin-ca: \"{ca}\" }}\n\
+         inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{port} }}\n",
+        ca = ca,
+        key = key,
+        port = port
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    let mut stream = connect_retry(([127, 0, 0, 1], port).into());
+    write!(
+        stream,
+        "CONNECT localhost:{0} HTTP/1.1\r\nHost: localhost:{0}\r\n\r\n",
+        origin.port()
+    )
+    .unwrap();
+    assert!(read_head(&mut stream).unwrap().starts_with("HTTP/1.1 200"));
+
+    // The client trusts the CA the leaf of the tunnel is minted by
+    let mut tls = rustls::ClientConfig::new();
+    tls.root_store
+        .add_pem_file(&mut io::BufReader::new(fs::File::open(&ca).unwrap()))
+        .unwrap();
+    let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut session = rustls::ClientSession::new(&Arc::new(tls), name);
+    let mut tls = rustls::Stream::new(&mut session, &mut stream);
+    tls.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let (head, body) = read_response(&mut tls);
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, ORIGIN_BODY);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tun_inbound_relays_connections_to_fake_addresses() {
+    if !tun_available() {
+        eprintln!("skipped, creating TUN devices takes root");
+        return;
+    }
+    let origin = spawn_http_origin();
+    let dns = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let config = config(&format!(
+        "dns:\n  listen: {dns}\n  mode: fake-ip\n  servers: [127.0.0.1]\n  \
+         fake-ip-range: 198.18.1.0/24\n\
+         inbounds:\n  - {{ name: tun1, kind: tun, device: tachetest1, \
+         inet4-address: 198.18.1.1/24, mss-clamp: 1200 }}\n",
+        dns = dns
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    // The fake address stands for localhost, where DIRECT finds the origin
+    let ip = resolve(dns, "localhost.", RecordType::A);
+    let mut stream = connect_retry(SocketAddr::new(ip, origin.port()));
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let (head, body) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(body, ORIGIN_BODY);
+
+    // The SYN-ACK of the device announced the clamped segment size
+    let mut mss: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let got = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &mut mss as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(got, 0);
+    assert!(mss > 0 && mss <= 1200, "mss {}", mss);
+}
+
+#[test]
+fn tun_inbound_relays_ipv6_connections() {
+    if !tun_available() {
+        eprintln!("skipped, creating TUN devices takes root");
+        return;
+    }
+    let origin = spawn_http_origin();
+    let dns = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let config = config(&format!(
+        "dns:\n  listen: {dns}\n  mode: fake-ip\n  servers: [127.0.0.1]\n  \
+         fake-ip-range: 198.18.3.0/24\n  fake-ip6-range: fd00:7461:6368:3::/64\n\
+         inbounds:\n  - {{ name: tun3, kind: tun, device: tachetest3, \
+         inet4-address: 198.18.3.1/24, inet6-address: 'fd00:7461:6368:3::1/64' }}\n",
+        dns = dns
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    let ip = resolve(dns, "localhost.", RecordType::AAAA);
+    assert!(ip.is_ipv6(), "{}", ip);
+    let mut stream = connect_retry(SocketAddr::new(ip, origin.port()));
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let (head, body) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(body, ORIGIN_BODY);
+}
+
+#[test]
+fn tun_inbound_shards_flows_over_queues() {
+    if !tun_available() {
+        eprintln!("skipped, creating TUN devices takes root");
+        return;
+    }
+    let origin = spawn_http_origin();
+    let dns = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let config = config(&format!(
+        "dns:\n  listen: {dns}\n  mode: fake-ip\n  servers: [127.0.0.1]\n  \
+         fake-ip-range: 198.18.4.0/24\n\
+         inbounds:\n  - {{ name: tun4, kind: tun, device: tachetest4, \
+         inet4-address: 198.18.4.1/24, queues: 4 }}\n",
+        dns = dns
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    // Flows of different client ports land on different workers, whichever
+    // queue the kernel picks for them
+    let ip = resolve(dns, "localhost.", RecordType::A);
+    let clients = (0..8)
+        .map(|_| {
+            std::thread::spawn(move || {
+                let mut stream = connect_retry(SocketAddr::new(ip, origin.port()));
+                stream
+                    .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                    .unwrap();
+                read_response(&mut stream)
+            })
+        })
+        .collect::<Vec<_>>();
+    for client in clients {
+        let (head, body) = client.join().unwrap();
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(body, ORIGIN_BODY);
+    }
+}
+
+#[test]
+fn api_captures_tun_packets() {
+    if !tun_available() {
+        eprintln!("skipped, creating TUN devices takes root");
+        return;
+    }
+    let api = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let config = config(&format!(
+        "api:\n  listen: {api}\n\
+         inbounds:\n  - {{ name: tun5, kind: tun, device: tachetest5, \
+         inet4-address: 198.18.5.1/24 }}\n",
+        api = api
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+    let dst = Ipv4Addr::new(198, 18, 5, 9);
+    assert!((0..25).any(|_| ping(dst)), "no reply from {}", dst);
+
+    let filter = r#"{"tun": true, "inbound": "tun5"}"#;
+    let (head, _) = http_request(
+        api,
+        &format!(
+            "POST /debug/capture HTTP/1.1\r\nHost: localhost\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            filter.len(),
+            filter
+        ),
+    );
+    assert!(head.starts_with("HTTP/1.1 201"), "{}", head);
+    assert!(ping(dst));
+    let (_, body) = http_request(
+        api,
+        "DELETE /debug/capture HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    );
+    let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+    // The request as read and the reply as written
+    assert!(status["packets"].as_u64().unwrap() >= 2, "{}", body);
+    let _ = fs::remove_file(status["path"].as_str().unwrap());
+}
+
+#[test]
+fn tun_inbound_answers_hijacked_dns_queries() {
+    if !tun_available() {
+        eprintln!("skipped, creating TUN devices takes root");
+        return;
+    }
+    let dns = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let config = config(&format!(
+        "dns:\n  listen: {dns}\n  mode: fake-ip\n  servers: [127.0.0.1]\n  \
+         fake-ip-range: 198.18.7.0/24\n\
+         inbounds:\n  - {{ name: tun6, kind: tun, device: tachetest6, \
+         inet4-address: 198.18.6.1/24, dns-hijack: ['198.18.6.53:53'] }}\n",
+        dns = dns
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    // Until the device routes them, queries go out to whatever answers
+    let up = Ipv4Addr::new(198, 18, 6, 9);
+    assert!((0..25).any(|_| ping(up)), "no reply from {}", up);
+
+    // Nothing listens there, the built-in resolver answers with fake-ip
+    let hijacked = SocketAddr::from(([198, 18, 6, 53], 53));
+    let ip = resolve(hijacked, "localhost.", RecordType::A);
+    assert_eq!(resolve(dns, "localhost.", RecordType::A), ip);
+    assert_eq!(resolve_tcp(hijacked, "localhost.", RecordType::A), ip);
+}
+
+#[test]
+fn tun_inbound_answers_pings() {
+    if !tun_available() {
+        eprintln!("skipped, creating TUN devices takes root");
+        return;
+    }
+    let config = config(
+        "inbounds:\n  - { name: tun2, kind: tun, device: tachetest2, \
+         inet4-address: 198.18.2.1/24 }\n",
+    );
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    // Nothing is there, `reply` answers in its place
+    let dst = Ipv4Addr::new(198, 18, 2, 9);
+    assert!((0..25).any(|_| ping(dst)), "no reply from {}", dst);
+}
+
+#[test]
+fn http_inbound_routes_by_rule_through_shadowsocks() {
+    let proxied = spawn_http_origin();
+    let direct = spawn_http_origin();
+    let (server, served) = spawn_shadowsocks_server("chacha20-ietf-poly1305", "secret");
+    let port = free_port();
+    let config = config(&format!(
+        "inbounds:\n  - {{ name: http1, kind: http, listen: 127.0.0.1:{port} }}\n\
+         proxies:\n  - {{ name: ss, kind: shadowsocks, address: {server}, \
+         cipher: chacha20-ietf-poly1305, password: secret, udp: false }}\n\
+         rules:\n  - {{ kind: DST-PORT, params: [\"{proxied}\"], target: ss }}\n",
+        port = port,
+        server = server,
+        proxied = proxied.port()
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    let inbound = ([127, 0, 0, 1], port).into();
+    let (head, body) = http_request(
+        inbound,
+        &format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", proxied),
+    );
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, ORIGIN_BODY);
+    assert_eq!(*served.lock().unwrap(), [proxied.to_string()]);
+
+    // Nothing matches, the request goes out directly
+    let (head, body) = http_request(
+        inbound,
+        &format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", direct),
+    );
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, ORIGIN_BODY);
+    assert_eq!(*served.lock().unwrap(), [proxied.to_string()]);
+}
+
+#[test]
+fn socks5_inbound_falls_back_past_dead_group_member() {
+    let origin = spawn_http_origin();
+    let (server, served) = spawn_shadowsocks_server("aes-128-gcm", "secret");
+    let port = free_port();
+    // Nothing listens there, dialing the first member fails
+    let dead = free_port();
+    let config = config(&format!(
+        "inbounds:\n  - {{ name: socks1, kind: socks5, listen: 127.0.0.1:{port} }}\n\
+         proxies:\n  - {{ name: dead, kind: socks5, address: 127.0.0.1:{dead} }}\n  \
+         - {{ name: ss, kind: shadowsocks, address: {server}, \
+         cipher: aes-128-gcm, password: secret, udp: false }}\n\
+         proxy-groups:\n  - {{ name: smart, kind: smart, proxies: [dead, ss], \
+         url: \"http://127.0.0.1:9/\", interval: 300, retries: 1 }}\n\
+         rules:\n  - {{ kind: MATCH, params: [], target: smart }}\n",
+        port = port,
+        dead = dead,
+        server = server
+    ));
+    let engine = Engine::builder().config(config).build().unwrap();
+    engine.start().unwrap();
+
+    drop(connect_retry(([127, 0, 0, 1], port).into()));
+    let outbound = Socks5::new("socks", address(([127, 0, 0, 1], port).into()), None, None);
+    assert!(fetch_via(&outbound, origin).ends_with(ORIGIN_BODY));
+    assert!(served.lock().unwrap().contains(&origin.to_string()));
+}
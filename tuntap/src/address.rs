@@ -0,0 +1,65 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use crate::error::*;
+
+/// Anything naming an IPv4 address.
+pub trait IntoAddress {
+    fn into_address(&self) -> Result<Ipv4Addr>;
+}
+
+impl IntoAddress for u32 {
+    fn into_address(&self) -> Result<Ipv4Addr> {
+        Ok(Ipv4Addr::from(*self))
+    }
+}
+
+impl IntoAddress for [u8; 4] {
+    fn into_address(&self) -> Result<Ipv4Addr> {
+        Ok(Ipv4Addr::from(*self))
+    }
+}
+
+impl IntoAddress for (u8, u8, u8, u8) {
+    fn into_address(&self) -> Result<Ipv4Addr> {
+        Ok(Ipv4Addr::new(self.0, self.1, self.2, self.3))
+    }
+}
+
+impl IntoAddress for str {
+    fn into_address(&self) -> Result<Ipv4Addr> {
+        self.parse().map_err(|_| ErrorKind::InvalidAddress.into())
+    }
+}
+
+impl IntoAddress for &str {
+    fn into_address(&self) -> Result<Ipv4Addr> {
+        (*self).into_address()
+    }
+}
+
+impl IntoAddress for String {
+    fn into_address(&self) -> Result<Ipv4Addr> {
+        self.as_str().into_address()
+    }
+}
+
+impl IntoAddress for Ipv4Addr {
+    fn into_address(&self) -> Result<Ipv4Addr> {
+        Ok(*self)
+    }
+}
+
+impl IntoAddress for SocketAddrV4 {
+    fn into_address(&self) -> Result<Ipv4Addr> {
+        Ok(*self.ip())
+    }
+}
+
+impl IntoAddress for SocketAddr {
+    fn into_address(&self) -> Result<Ipv4Addr> {
+        match *self {
+            SocketAddr::V4(ref addr) => Ok(*addr.ip()),
+            SocketAddr::V6(..) => Err(ErrorKind::InvalidAddress.into()),
+        }
+    }
+}
@@ -1,7 +1,7 @@
-use std::net::{Ipv4Addr};
+use std::net::{Ipv4Addr, Ipv6Addr};
 
-use address::IntoAddress;
-use platform;
+use crate::address::IntoAddress;
+use crate::platform;
 
 /// Configuration builder for a TUN interface.
 #[derive(Clone, Default, Debug)]
@@ -13,6 +13,7 @@ pub struct Configuration {
     pub(crate) destination: Option<Ipv4Addr>,
     pub(crate) broadcast:   Option<Ipv4Addr>,
     pub(crate) netmask:     Option<Ipv4Addr>,
+    pub(crate) address6:    Option<(Ipv6Addr, u8)>,
     pub(crate) mtu:         Option<i32>,
     pub(crate) enabled:     Option<bool>,
 }
@@ -26,7 +27,7 @@ impl Configuration {
     }
 
     /// Set the name.
-    pub fn interfaceName<S: AsRef<str>>(&mut self, name: S) -> &mut Self {
+    pub fn name<S: AsRef<str>>(&mut self, name: S) -> &mut Self {
         self.name = Some(name.as_ref().into());
         self
     }
@@ -55,6 +56,12 @@ impl Configuration {
         self
     }
 
+    /// Set the IPv6 address and its prefix length.
+    pub fn address6(&mut self, value: Ipv6Addr, prefix: u8) -> &mut Self {
+        self.address6 = Some((value, prefix));
+        self
+    }
+
     /// Set the MTU.
     pub fn mtu(&mut self, value: i32) -> &mut Self {
         self.mtu = Some(value);
@@ -0,0 +1,80 @@
+use std::{error, fmt, io};
+use std::ffi::NulError;
+use std::num::ParseIntError;
+
+/// Failures of the crate itself, the others come from the system.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    NameTooLong,
+    InvalidName,
+    InvalidAddress,
+    InvalidDescriptor,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Tun(ErrorKind),
+    Nul(NulError),
+    ParseNum(ParseIntError),
+    Io(io::Error),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Tun(ErrorKind::NameTooLong) => f.write_str("device name too long"),
+            Error::Tun(ErrorKind::InvalidName) => f.write_str("invalid device name"),
+            Error::Tun(ErrorKind::InvalidAddress) => f.write_str("invalid address"),
+            Error::Tun(ErrorKind::InvalidDescriptor) => f.write_str("invalid file descriptor"),
+            Error::Nul(ref e) => e.fmt(f),
+            Error::ParseNum(ref e) => e.fmt(f),
+            Error::Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Tun(..) => None,
+            Error::Nul(ref e) => Some(e),
+            Error::ParseNum(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error::Tun(kind)
+    }
+}
+
+impl From<NulError> for Error {
+    fn from(e: NulError) -> Error {
+        Error::Nul(e)
+    }
+}
+
+impl From<ParseIntError> for Error {
+    fn from(e: ParseIntError) -> Error {
+        Error::ParseNum(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidInput, e),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Declarations of `ioctl` requests as typed functions.
+
+#[cfg(target_os = "linux")]
+pub mod dir {
+    pub const WRITE: u32 = 1 << 30;
+    // Requests of Linux read back through `bad read` only
+    #[allow(dead_code)]
+    pub const READ: u32 = 2 << 30;
+    pub const SIZE_MASK: u32 = 0x3fff;
+}
+
+#[cfg(not(target_os = "linux"))]
+pub mod dir {
+    pub const WRITE: u32 = 0x8000_0000;
+    pub const READ: u32 = 0x4000_0000;
+    pub const SIZE_MASK: u32 = 0x1fff;
+}
+
+/// Number of a request carrying a `size` byte argument in direction `dir`.
+pub const fn request(dir: u32, ty: u32, nr: u32, size: usize) -> u32 {
+    dir | ((size as u32 & dir::SIZE_MASK) << 16) | (ty << 8) | nr
+}
+
+macro_rules! ioctl {
+    (bad read $name:ident with $nr:expr; $ty:ty) => {
+        pub unsafe fn $name(fd: ::libc::c_int, data: *mut $ty) -> ::libc::c_int {
+            ::libc::ioctl(fd, $nr as _, data)
+        }
+    };
+    (bad write $name:ident with $nr:expr; $ty:ty) => {
+        pub unsafe fn $name(fd: ::libc::c_int, data: *const $ty) -> ::libc::c_int {
+            ::libc::ioctl(fd, $nr as _, data)
+        }
+    };
+    (write $name:ident with $ioty:expr, $nr:expr; $ty:ty) => {
+        pub unsafe fn $name(fd: ::libc::c_int, data: *const $ty) -> ::libc::c_int {
+            let request = $crate::ioctl::request(
+                $crate::ioctl::dir::WRITE, $ioty as u32, $nr, ::std::mem::size_of::<$ty>());
+            ::libc::ioctl(fd, request as _, data)
+        }
+    };
+    (readwrite $name:ident with $ioty:expr, $nr:expr; $ty:ty) => {
+        pub unsafe fn $name(fd: ::libc::c_int, data: *mut $ty) -> ::libc::c_int {
+            let request = $crate::ioctl::request(
+                $crate::ioctl::dir::READ | $crate::ioctl::dir::WRITE, $ioty as u32, $nr,
+                ::std::mem::size_of::<$ty>());
+            ::libc::ioctl(fd, request as _, data)
+        }
+    };
+}
@@ -1,3 +1,26 @@
+//! TUN devices of Linux and macOS, read and written one IP packet at a time.
+
+#[macro_use]
+mod ioctl;
+
+mod error;
+pub use crate::error::*;
+
+mod address;
+pub use crate::address::IntoAddress;
+
+mod tuntap;
+pub use crate::tuntap::Tuntap;
+
+mod configuration;
+pub use crate::configuration::Configuration;
+
+pub mod platform;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub use crate::platform::{create, Device};
+#[cfg(all(unix, feature = "async"))]
+pub use crate::platform::AsyncDevice;
+
 #[cfg(test)]
 mod tests {
     #[test]
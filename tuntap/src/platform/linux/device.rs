@@ -17,24 +17,24 @@ use std::ptr;
 use std::io::{self, Read, Write};
 use std::os::unix::io::{RawFd, AsRawFd, IntoRawFd};
 use std::ffi::{CString, CStr};
-use std::net::{Ipv4Addr};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 
-use libc;
-use libc::{c_char};
-use libc::{AF_INET, SOCK_DGRAM, O_RDWR};
+use libc::{c_char, c_uint};
+use libc::{AF_INET, AF_INET6, SOCK_DGRAM, O_RDWR};
 
-use error::*;
-use device::Device as D;
-use platform::posix::{self, SockAddr, Fd};
-use platform::linux::sys::*;
-use configuration::Configuration;
+use crate::error::*;
+use crate::tuntap::Tuntap as D;
+use crate::platform::posix::{self, SockAddr, Fd};
+use crate::platform::linux::sys::*;
+use crate::configuration::Configuration;
 
 /// A TUN device using the TUN/TAP Linux driver.
 pub struct Device {
-	name: String,
-	tun:  Fd,
-	ctl:  Fd,
+	name:   String,
+	tun:    Fd,
+	ctl:    Fd,
+	queues: Vec<Fd>,
 }
 
 impl Device {
@@ -56,33 +56,43 @@ impl Device {
 					None
 			};
 
-			let tun = Fd::new(libc::open(b"/dev/net/tun\0".as_ptr() as *const _, O_RDWR))
-				.map_err(|_| io::Error::last_os_error())?;
-
 			let mut req: ifreq = mem::zeroed();
 
 			if let Some(dev) = dev.as_ref() {
 				ptr::copy_nonoverlapping(dev.as_ptr() as *const c_char, req.ifrn.name.as_mut_ptr(), dev.as_bytes().len());
 			}
 
+			let queues = config.platform.queues.max(1);
+
 			req.ifru.flags = IFF_TUN |
-				if config.platform.packet_information { 0 } else { IFF_NO_PI };
+				if config.platform.packet_information { 0 } else { IFF_NO_PI } |
+				if queues > 1 { IFF_MULTI_QUEUE } else { 0 };
 
-			if tunsetiff(tun.0, &mut req as *mut _ as *mut _) < 0 {
-				return Err(io::Error::last_os_error().into());
+			let tun = attach(&mut req)?;
+
+			// The first attach filled in the name, the others join the same device.
+			let mut extra = Vec::with_capacity(queues - 1);
+
+			for _ in 1 .. queues {
+				extra.push(attach(&mut req)?);
 			}
 
 			let ctl = Fd::new(libc::socket(AF_INET, SOCK_DGRAM, 0))
 				.map_err(|_| io::Error::last_os_error())?;
 
 			Device {
-				name: CStr::from_ptr(req.ifrn.name.as_ptr()).to_string_lossy().into(),
-				tun:  tun,
-				ctl:  ctl,
+				name:   CStr::from_ptr(req.ifrn.name.as_ptr()).to_string_lossy().into(),
+				tun,
+				ctl,
+				queues: extra,
 			}
 		};
 
-		device.configure(&config)?;
+		device.configure(config)?;
+
+		if let Some((address, prefix)) = config.address6 {
+			device.set_address6(address, prefix)?;
+		}
 
 		Ok(device)
 	}
@@ -95,6 +105,32 @@ impl Device {
 		req
 	}
 
+	/// Add an IPv6 address with the given prefix length.
+	pub fn set_address6(&mut self, value: Ipv6Addr, prefix: u8) -> Result<()> {
+		unsafe {
+			let mut req = self.request();
+
+			if siocgifindex(self.ctl.as_raw_fd(), &mut req) < 0 {
+				return Err(io::Error::last_os_error().into());
+			}
+
+			// IPv6 addresses go through a socket of their own family.
+			let ctl6 = Fd::new(libc::socket(AF_INET6, SOCK_DGRAM, 0))
+				.map_err(|_| io::Error::last_os_error())?;
+
+			let mut req6: in6_ifreq = mem::zeroed();
+			req6.ifr6_addr.s6_addr = value.octets();
+			req6.ifr6_prefixlen    = prefix as c_uint;
+			req6.ifr6_ifindex      = req.ifru.ivalue;
+
+			if siocsifaddr6(ctl6.as_raw_fd(), &req6) < 0 {
+				return Err(io::Error::last_os_error().into());
+			}
+
+			Ok(())
+		}
+	}
+
 	/// Make the device persistent.
 	pub fn persist(&mut self) -> Result<()> {
 		unsafe {
@@ -131,6 +167,12 @@ impl Device {
 		}
 	}
 
+	/// Take the descriptors of the queues beyond the first, which stays with
+	/// the device; each one reads and writes packets of its own flows.
+	pub fn take_queues(&mut self) -> Vec<Fd> {
+		mem::take(&mut self.queues)
+	}
+
 	/// Split the interface into a `Reader` and `Writer`.
 	pub fn split(self) -> (posix::Reader, posix::Writer) {
 		let fd = Arc::new(self.tun);
@@ -138,6 +180,18 @@ impl Device {
 	}
 }
 
+/// Open a TUN descriptor and attach it to the device `req` describes.
+unsafe fn attach(req: &mut ifreq) -> Result<Fd> {
+	let tun = Fd::new(libc::open(b"/dev/net/tun\0".as_ptr() as *const _, O_RDWR))
+		.map_err(|_| io::Error::last_os_error())?;
+
+	if tunsetiff(tun.0, req as *mut _ as *mut _) < 0 {
+		return Err(io::Error::last_os_error().into());
+	}
+
+	Ok(tun)
+}
+
 impl Read for Device {
 	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
 		self.tun.read(buf)
@@ -195,7 +249,7 @@ impl D for Device {
 				req.ifru.flags &= !IFF_UP;
 			}
 
-			if siocsifflags(self.ctl.as_raw_fd(), &mut req) < 0 {
+			if siocsifflags(self.ctl.as_raw_fd(), &req) < 0 {
 				return Err(io::Error::last_os_error().into());
 			}
 
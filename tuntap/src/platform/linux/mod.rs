@@ -19,13 +19,14 @@ pub mod sys;
 mod device;
 pub use self::device::Device;
 
-use error::*;
-use configuration::Configuration as C;
+use crate::error::*;
+use crate::configuration::Configuration as C;
 
 /// Linux-only interface configuration.
 #[derive(Copy, Clone, Default, Debug)]
 pub struct Configuration {
 	pub(crate) packet_information: bool,
+	pub(crate) queues:             usize,
 }
 
 impl Configuration {
@@ -35,9 +36,16 @@ impl Configuration {
 		self.packet_information = value;
 		self
 	}
+
+	/// Set the number of queues, with more than one the device is created
+	/// with `IFF_MULTI_QUEUE` and every queue gets its own descriptor.
+	pub fn queues(&mut self, value: usize) -> &mut Self {
+		self.queues = value;
+		self
+	}
 }
 
 /// Create a TUN device with the given name.
 pub fn create(configuration: &C) -> Result<Device> {
-	Device::new(&configuration)
+	Device::new(configuration)
 }
@@ -15,7 +15,7 @@
 //! Bindings to internal Linux stuff.
 
 use libc::{c_void, c_char, c_uchar, c_short, c_ushort, c_int, c_uint, c_ulong};
-use libc::{sockaddr};
+use libc::{sockaddr, in6_addr};
 
 pub const IFNAMSIZ: usize = 16;
 
@@ -25,6 +25,8 @@ pub const IFF_RUNNING: c_short = 0x40;
 pub const IFF_TUN:   c_short = 0x0001;
 pub const IFF_NO_PI: c_short = 0x1000;
 
+pub const IFF_MULTI_QUEUE: c_short = 0x0100;
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct ifmap {
@@ -88,6 +90,15 @@ pub struct ifreq {
 	pub ifru: ifru,
 }
 
+/// Address request of `AF_INET6` sockets.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct in6_ifreq {
+	pub ifr6_addr:      in6_addr,
+	pub ifr6_prefixlen: c_uint,
+	pub ifr6_ifindex:   c_int,
+}
+
 ioctl!(bad read siocgifflags with 0x8913; ifreq);
 ioctl!(bad write siocsifflags with 0x8914; ifreq);
 ioctl!(bad read siocgifaddr with 0x8915; ifreq);
@@ -101,6 +112,8 @@ ioctl!(bad write siocsifnetmask with 0x891c; ifreq);
 ioctl!(bad read siocgifmtu with 0x8921; ifreq);
 ioctl!(bad write siocsifmtu with 0x8922; ifreq);
 ioctl!(bad write siocsifname with 0x8923; ifreq);
+ioctl!(bad read siocgifindex with 0x8933; ifreq);
+ioctl!(bad write siocsifaddr6 with 0x8916; in6_ifreq);
 
 ioctl!(write tunsetiff with b'T', 202; c_int);
 ioctl!(write tunsetpersist with b'T', 203; c_int);
@@ -16,6 +16,8 @@
 
 #[cfg(unix)]
 pub mod posix;
+#[cfg(all(unix, feature = "async"))]
+pub use self::posix::AsyncDevice;
 
 #[cfg(target_os = "linux")]
 pub mod linux;
@@ -30,8 +32,8 @@ pub use self::macos::{Device, Configuration, create};
 #[cfg(test)]
 mod test {
 	use std::net::Ipv4Addr;
-	use configuration::Configuration;
-	use device::Device;
+	use crate::configuration::Configuration;
+	use crate::tuntap::Tuntap;
 
 	#[test]
 	fn create() {
@@ -0,0 +1,118 @@
+//            DO WHAT THE FUCK YOU WANT TO PUBLIC LICENSE
+//                    Version 2, December 2004
+//
+// Copyleft (ↄ) meh. <meh@schizofreni.co> | http://meh.schizofreni.co
+//
+// Everyone is permitted to copy and distribute verbatim or modified
+// copies of this license document, and changing it is allowed as long
+// as the name is changed.
+//
+//            DO WHAT THE FUCK YOU WANT TO PUBLIC LICENSE
+//   TERMS AND CONDITIONS FOR COPYING, DISTRIBUTION AND MODIFICATION
+//
+//  0. You just DO WHAT THE FUCK YOU WANT TO.
+
+//! Non-blocking device I/O driven by the tokio reactor.
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use mio::event::Evented;
+use tokio::io::{AsyncRead, AsyncWrite, PollEvented};
+
+use crate::platform::Device;
+
+/// A device, or one of its queues, whose reads and writes wait for readiness
+/// instead of blocking.
+pub struct AsyncDevice<T: Evented = Device> {
+	inner: PollEvented<T>,
+}
+
+impl<T> AsyncDevice<T> where T: Read + Write + AsRawFd + Evented + Unpin {
+	/// Switch the device to non-blocking mode and register it with the
+	/// reactor of the current runtime.
+	pub fn new(device: T) -> io::Result<Self> {
+		set_nonblocking(device.as_raw_fd())?;
+
+		Ok(AsyncDevice {
+			inner: PollEvented::new(device)?,
+		})
+	}
+
+	/// Access the underlying device.
+	pub fn get_ref(&self) -> &T {
+		self.inner.get_ref()
+	}
+
+	/// Access the underlying device mutably.
+	pub fn get_mut(&mut self) -> &mut T {
+		self.inner.get_mut()
+	}
+
+	/// Read the packets already queued, one per buffer, waiting only for the
+	/// first one; the length of the packet in `bufs[i]` goes to `sizes[i]`
+	/// and the number of packets read is returned.
+	pub fn poll_recv_batch(&mut self, cx: &mut Context<'_>, bufs: &mut [&mut [u8]], sizes: &mut [usize]) -> Poll<io::Result<usize>> {
+		let count = bufs.len().min(sizes.len());
+
+		if count == 0 {
+			return Poll::Ready(Ok(0));
+		}
+
+		sizes[0] = match Pin::new(&mut self.inner).poll_read(cx, bufs[0]) {
+			Poll::Ready(Ok(size)) => size,
+			Poll::Ready(Err(e))   => return Poll::Ready(Err(e)),
+			Poll::Pending         => return Poll::Pending,
+		};
+
+		let mut read = 1;
+
+		while read < count {
+			match self.inner.get_mut().read(bufs[read]) {
+				Ok(size) => {
+					sizes[read] = size;
+					read += 1;
+				}
+
+				// Would block, or fails again on the next poll which reports it.
+				Err(_) => break,
+			}
+		}
+
+		Poll::Ready(Ok(read))
+	}
+}
+
+impl<T> AsyncRead for AsyncDevice<T> where T: Read + Write + AsRawFd + Evented + Unpin {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.inner).poll_read(cx, buf)
+	}
+}
+
+impl<T> AsyncWrite for AsyncDevice<T> where T: Read + Write + AsRawFd + Evented + Unpin {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.inner).poll_write(cx, buf)
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+	unsafe {
+		let flags = libc::fcntl(fd, libc::F_GETFL);
+
+		if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+			return Err(io::Error::last_os_error());
+		}
+	}
+
+	Ok(())
+}
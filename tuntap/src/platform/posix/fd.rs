@@ -15,8 +15,7 @@
 use std::io::{self, Read, Write};
 use std::os::unix::io::{RawFd, AsRawFd, IntoRawFd};
 
-use libc;
-use error::*;
+use crate::error::*;
 
 /// POSIX file descriptor support for `io` traits and optionally for `mio`.
 pub struct Fd(pub RawFd);
@@ -37,7 +36,7 @@ impl Read for Fd {
 			let amount = libc::read(self.0, buf.as_mut_ptr() as *mut _, buf.len());
 
 			if amount < 0 {
-				return Err(io::Error::last_os_error().into());
+				return Err(io::Error::last_os_error());
 			}
 
 			Ok(amount as usize)
@@ -51,7 +50,7 @@ impl Write for Fd {
 			let amount = libc::write(self.0, buf.as_ptr() as *const _, buf.len());
 
 			if amount < 0 {
-				return Err(io::Error::last_os_error().into());
+				return Err(io::Error::last_os_error());
 			}
 
 			Ok(amount as usize)
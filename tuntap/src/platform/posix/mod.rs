@@ -22,3 +22,8 @@ pub use self::fd::Fd;
 
 mod split;
 pub use self::split::{Reader, Writer};
+
+#[cfg(feature = "async")]
+mod async_device;
+#[cfg(feature = "async")]
+pub use self::async_device::AsyncDevice;
@@ -24,7 +24,7 @@ use libc::{c_uint, c_uchar};
 use libc::{sockaddr, sockaddr_in, in_addr};
 use libc::AF_INET as _AF_INET;
 
-use error::*;
+use crate::error::*;
 
 /// A wrapper for `sockaddr_in`.
 #[derive(Copy, Clone)]
@@ -68,7 +68,7 @@ impl From<Ipv4Addr> for SockAddr {
 			((parts[3] as c_uint) << 24) |
 			((parts[2] as c_uint) << 16) |
 			((parts[1] as c_uint) <<  8) |
-			((parts[0] as c_uint))
+			(parts[0] as c_uint)
 		};
 
 		SockAddr(addr)
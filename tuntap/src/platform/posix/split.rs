@@ -16,8 +16,7 @@ use std::io::{self, Read, Write};
 use std::sync::Arc;
 use std::os::unix::io::{RawFd, AsRawFd};
 
-use libc;
-use platform::posix::Fd;
+use crate::platform::posix::Fd;
 
 /// Read-only end for a file descriptor.
 pub struct Reader(pub(crate) Arc<Fd>);
@@ -31,7 +30,7 @@ impl Read for Reader {
 			let amount = libc::read(self.0.as_raw_fd(), buf.as_mut_ptr() as *mut _, buf.len());
 
 			if amount < 0 {
-				return Err(io::Error::last_os_error().into());
+				return Err(io::Error::last_os_error());
 			}
 
 			Ok(amount as usize)
@@ -45,7 +44,7 @@ impl Write for Writer {
 			let amount = libc::write(self.0.as_raw_fd(), buf.as_ptr() as *const _, buf.len());
 
 			if amount < 0 {
-				return Err(io::Error::last_os_error().into());
+				return Err(io::Error::last_os_error());
 			}
 
 			Ok(amount as usize)
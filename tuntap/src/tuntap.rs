@@ -1,7 +1,39 @@
 use std::io::{Read, Write};
 use std::net::Ipv4Addr;
 
+use crate::configuration::Configuration;
+use crate::error::*;
+
 pub trait Tuntap: Read + Write {
+    /// Reconfigure the device.
+    fn configure(&mut self, config: &Configuration) -> Result<()> {
+        if let Some(ip) = config.address {
+            self.set_address(ip)?;
+        }
+
+        if let Some(ip) = config.destination {
+            self.set_destination(ip)?;
+        }
+
+        if let Some(ip) = config.broadcast {
+            self.set_broadcast(ip)?;
+        }
+
+        if let Some(ip) = config.netmask {
+            self.set_netmask(ip)?;
+        }
+
+        if let Some(mtu) = config.mtu {
+            self.set_mtu(mtu)?;
+        }
+
+        if let Some(enabled) = config.enabled {
+            self.enabled(enabled)?;
+        }
+
+        Ok(())
+    }
+
     /// Turn on or off the interface.
     fn enabled(&mut self, value: bool) -> Result<()>;
 